@@ -0,0 +1 @@
+DROP INDEX key_tree_parent_id_node_id;
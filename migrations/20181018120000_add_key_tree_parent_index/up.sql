@@ -0,0 +1 @@
+CREATE INDEX key_tree_parent_id_node_id ON key_tree(parent_id, node_id);
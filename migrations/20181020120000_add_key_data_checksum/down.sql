@@ -0,0 +1 @@
+ALTER TABLE key_data DROP COLUMN checksum;
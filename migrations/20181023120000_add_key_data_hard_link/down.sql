@@ -0,0 +1 @@
+ALTER TABLE key_data DROP COLUMN hard_link;
@@ -0,0 +1 @@
+ALTER TABLE key_data ADD COLUMN xattrs BLOB;
@@ -0,0 +1,2 @@
+DROP TABLE chunk_ref_snapshots;
+DROP TABLE chunk_refs;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS chunk_refs (
+    hash_id       INTEGER PRIMARY KEY,
+    bytes         INTEGER NOT NULL,
+    snapshots     INTEGER NOT NULL,
+    snapshot_sum  INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS chunk_ref_snapshots (
+    snapshot_id    INTEGER PRIMARY KEY,
+    chunks         INTEGER NOT NULL,
+    bytes          INTEGER NOT NULL,
+    unique_chunks  INTEGER NOT NULL,
+    unique_bytes   INTEGER NOT NULL
+);
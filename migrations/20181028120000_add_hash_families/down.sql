@@ -0,0 +1 @@
+DROP TABLE hash_families;
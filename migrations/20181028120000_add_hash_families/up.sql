@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS hash_families (
+    hash_id  INTEGER PRIMARY KEY,
+    family   VARCHAR NOT NULL
+);
@@ -0,0 +1,436 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{slice_range, StorageClass, StoreBackend};
+use crypto::CipherText;
+use filetime::{self, FileTime};
+use hex::{self, FromHex};
+use lru_cache::LruCache;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use util::FnBox;
+
+/// Name of the blob cache directory in the state directory.
+pub const BLOB_CACHE_DIRNAME: &str = "blob-cache";
+
+/// Bytes of blobs kept, unless configured otherwise.
+pub const DEFAULT_BLOB_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Names of control objects, which are rewritten in place and so never cached.
+const CONTROL_PREFIX: &[u8] = b"hat-";
+
+/// Names temporary files uniquely within this process.
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// The blobs in the cache, least recently used first, with their sizes.
+struct Entries {
+    lru: LruCache<Vec<u8>, u64>,
+    bytes: u64,
+}
+
+/// A repository backend that keeps the blobs it reads in a directory, by default
+/// `BLOB_CACHE_DIRNAME` in the state directory, so reading them again does not download them.
+///
+/// The least recently used blobs are removed once the cache holds more than its size. Each blob
+/// is a file named by its hex name, whose modification time records its last use, so the order
+/// survives restarts. Processes sharing the directory each enforce the size on their own view
+/// of it. Control objects are never cached, as they change; blobs are authenticated when read
+/// from the cache like when read from the backend. Failing to cache a blob only costs a later
+/// download.
+pub struct CachingBackend<B> {
+    inner: Arc<B>,
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+}
+
+impl<B: StoreBackend> CachingBackend<B> {
+    /// Cache blobs of `inner` in `dir`, up to `max_bytes`. The blobs found in `dir` are kept,
+    /// as far as they fit.
+    pub fn new(inner: Arc<B>, dir: PathBuf, max_bytes: u64) -> CachingBackend<B> {
+        let mut found = vec![];
+        if let Ok(listing) = fs::read_dir(&dir) {
+            for entry in listing.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_str().and_then(|n| Vec::from_hex(n).ok());
+                let meta = entry.metadata().ok().filter(|m| m.is_file());
+                if let (Some(name), Some(meta)) = (name, meta) {
+                    found.push((FileTime::from_last_modification_time(&meta), name, meta.len()));
+                } else if entry.file_name().to_string_lossy().starts_with(".tmp-") {
+                    // Left by a process that stopped while writing it.
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        found.sort();
+
+        let cache = CachingBackend {
+            inner: inner,
+            dir: dir,
+            max_bytes: max_bytes,
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(usize::MAX),
+                bytes: 0,
+            }),
+        };
+        {
+            let mut entries = cache.entries.lock().unwrap();
+            for (_, name, len) in found {
+                entries.lru.insert(name, len);
+                entries.bytes += len;
+            }
+            cache.evict(&mut entries);
+        }
+        cache
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Bytes of blobs in the cache.
+    pub fn cached_bytes(&self) -> u64 {
+        self.entries.lock().unwrap().bytes
+    }
+
+    fn path(&self, name: &[u8]) -> PathBuf {
+        self.dir.join(hex::encode(name))
+    }
+
+    /// Remove the least recently used blobs until the rest fit.
+    fn evict(&self, entries: &mut Entries) {
+        while entries.bytes > self.max_bytes {
+            match entries.lru.remove_lru() {
+                Some((name, len)) => {
+                    entries.bytes -= len;
+                    let _ = fs::remove_file(self.path(&name));
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn forget(&self, name: &[u8]) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(len) = entries.lru.remove(name) {
+            entries.bytes -= len;
+        }
+        let _ = fs::remove_file(self.path(name));
+    }
+
+    /// The blob `name`, if it is in the cache.
+    fn get(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let path = self.path(name);
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.lru.get_mut(name)?;
+        }
+        match fs::read(&path) {
+            Ok(data) => {
+                let now = FileTime::from_system_time(SystemTime::now());
+                let _ = filetime::set_file_times(&path, now, now);
+                Some(data)
+            }
+            Err(_) => {
+                // Evicted by another process sharing the cache.
+                self.forget(name);
+                None
+            }
+        }
+    }
+
+    fn write(&self, name: &[u8], data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Readers must never see a partial entry.
+        let tmp = self.dir.join(format!(
+            ".tmp-{}-{}",
+            process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, self.path(name))
+    }
+
+    fn put(&self, name: &[u8], data: &[u8]) {
+        let len = data.len() as u64;
+        if len > self.max_bytes || name.starts_with(CONTROL_PREFIX) {
+            return;
+        }
+        if let Err(e) = self.write(name, data) {
+            warn!("Could not cache blob in {}: {}", self.dir.display(), e);
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.lru.insert(name.to_vec(), len) {
+            entries.bytes -= old;
+        }
+        entries.bytes += len;
+        self.evict(&mut entries);
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for CachingBackend<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.inner.store_with_class(name, data, class, done)
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.forget(name);
+        self.inner.replace(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if let Some(data) = self.get(name) {
+            return Ok(Some(data));
+        }
+        let res = self.inner.retrieve(name)?;
+        if let Some(ref data) = res {
+            self.put(name, data);
+        }
+        Ok(res)
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_uncached(name)
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        // Ranges are only served from cached blobs; they do not fill the cache.
+        match self.get(name) {
+            Some(data) => Ok(Some(slice_range(&data[..], offset, len).to_vec())),
+            None => self.inner.retrieve_range(name, offset, len),
+        }
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_range_uncached(name, offset, len)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.forget(name);
+        self.inner.delete(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        if self.entries.lock().unwrap().lru.contains_key(name) {
+            return Ok(true);
+        }
+        self.inner.request_restore(name)
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.checksum(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+/// Reads all blobs of the backend it wraps with `retrieve_uncached` and
+/// `retrieve_range_uncached`, e.g. to check what the storage itself holds. Everything else is
+/// passed on as is.
+pub struct UncachedBackend<B> {
+    inner: Arc<B>,
+}
+
+impl<B: StoreBackend> UncachedBackend<B> {
+    pub fn new(inner: Arc<B>) -> UncachedBackend<B> {
+        UncachedBackend { inner: inner }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for UncachedBackend<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.inner.store(name, data, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.inner.store_with_class(name, data, class, done)
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.inner.replace(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_uncached(name)
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_uncached(name)
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_range_uncached(name, offset, len)
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.inner.retrieve_range_uncached(name, offset, len)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        self.inner.request_restore(name)
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.checksum(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use std::env;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hat-blob-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn store(backend: &MemoryBackend, name: &[u8], len: usize) {
+        backend
+            .store(name, CipherText::new(vec![name[0]; len]), Box::new(|()| ()))
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_are_cached() {
+        let dir = cache_dir("reads");
+        let memory = Arc::new(MemoryBackend::new());
+        store(&memory, b"a", 100);
+        store(&memory, b"hat-control", 100);
+
+        let cache = CachingBackend::new(memory.clone(), dir.clone(), 1000);
+        assert_eq!(cache.retrieve(b"a").unwrap(), Some(vec![b'a'; 100]));
+        assert_eq!(cache.retrieve(b"hat-control").unwrap(), Some(vec![b'h'; 100]));
+        assert_eq!(cache.retrieve(b"missing").unwrap(), None);
+        assert_eq!(cache.cached_bytes(), 100);
+
+        // Served from the cache, also in a new process.
+        memory.delete(b"a").unwrap();
+        assert_eq!(cache.retrieve(b"a").unwrap(), Some(vec![b'a'; 100]));
+        let cache = CachingBackend::new(memory.clone(), dir.clone(), 1000);
+        assert_eq!(cache.retrieve_range(b"a", 90, 20).unwrap(), Some(vec![b'a'; 10]));
+
+        // Reads that must reach the storage pass the cache by.
+        let cache = Arc::new(cache);
+        assert_eq!(cache.retrieve_uncached(b"a").unwrap(), None);
+        assert_eq!(UncachedBackend::new(cache.clone()).retrieve(b"a").unwrap(), None);
+        assert_eq!(cache.retrieve_range_uncached(b"a", 90, 20).unwrap(), None);
+        let uncached = UncachedBackend::new(cache.clone());
+        assert_eq!(uncached.retrieve_range(b"a", 90, 20).unwrap(), None);
+
+        // Deleting through the cache removes the cached copy.
+        cache.delete(b"a").unwrap();
+        assert_eq!(cache.retrieve(b"a").unwrap(), None);
+        assert_eq!(cache.cached_bytes(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_blobs_are_evicted() {
+        let dir = cache_dir("evict");
+        let memory = Arc::new(MemoryBackend::new());
+        for name in &[b"a", b"b", b"c", b"d"] {
+            store(&memory, &name[..], 400);
+        }
+
+        let cache = CachingBackend::new(memory.clone(), dir.clone(), 1000);
+        cache.retrieve(b"a").unwrap();
+        cache.retrieve(b"b").unwrap();
+        cache.retrieve(b"a").unwrap();
+        // Makes room by evicting "b", which was used least recently.
+        cache.retrieve(b"c").unwrap();
+        assert_eq!(cache.cached_bytes(), 800);
+        assert!(dir.join(hex::encode(b"a")).exists());
+        assert!(!dir.join(hex::encode(b"b")).exists());
+
+        // A smaller cache keeps what fits of the most recently used blobs.
+        let cache = CachingBackend::new(memory.clone(), dir.clone(), 500);
+        assert_eq!(cache.cached_bytes(), 400);
+        assert!(dir.join(hex::encode(b"c")).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,12 +10,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use backend::StoreBackend;
+use backend::{slice_range, StorageClass, StoreBackend, DELETE_REFUSED,
+              RESTORE_PENDING};
+use crypto::keys;
 use crypto::CipherText;
 use hex::{self, FromHex};
 use std::collections::BTreeMap;
+use std::io;
 use std::mem;
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Mutex, MutexGuard};
 use std::thread;
 use std::time::Duration;
@@ -23,18 +28,126 @@ use util::FnBox;
 
 const HAT_CMD_PUT: &str = "hat-backup-put";
 const HAT_CMD_GET: &str = "hat-backup-get";
+const HAT_CMD_GET_RANGE: &str = "hat-backup-get-range";
 const HAT_CMD_DELETE: &str = "hat-backup-delete";
 const HAT_CMD_LIST: &str = "hat-backup-list";
+const HAT_CMD_CHECKSUM: &str = "hat-backup-checksum";
+const HAT_CMD_RESTORE: &str = "hat-backup-restore";
+
+const HAT_CMD_PARENT_GET: &str = "hat-backup-parent-get";
+const HAT_CMD_PARENT_GET_RANGE: &str = "hat-backup-parent-get-range";
+const HAT_CMD_PARENT_LIST: &str = "hat-backup-parent-list";
+const HAT_CMD_PARENT_CHECKSUM: &str = "hat-backup-parent-checksum";
+const HAT_CMD_PARENT_RESTORE: &str = "hat-backup-parent-restore";
+
+/// Version of the command protocol described in `backends/PROTOCOL.md`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Environment variable holding `PROTOCOL_VERSION` for every command, so that commands can
+/// refuse a protocol they do not implement.
+const HAT_ENV_PROTOCOL: &str = "HAT_BACKUP_PROTOCOL";
+
+/// Environment variable holding the checksum of the blob given to `hat-backup-put`, so the
+/// storage can check what it received.
+const HAT_ENV_CHECKSUM: &str = "HAT_BACKUP_CHECKSUM";
+
+/// Environment variable holding the storage class suggested for the blob given to
+/// `hat-backup-put`: `STANDARD` or `COLD`.
+const HAT_ENV_STORAGE_CLASS: &str = "HAT_BACKUP_STORAGE_CLASS";
+
+/// Environment variable set to `1` when `hat-backup-put` replaces a blob that was stored
+/// before, e.g. sealed again with new keys.
+const HAT_ENV_REPLACE: &str = "HAT_BACKUP_REPLACE";
+
+/// Tries to replace a blob before giving up; replacing is rare, and must not hang.
+const REPLACE_ATTEMPTS: usize = 3;
+
+/// Environment variable holding the storage of the parent repository for the
+/// `hat-backup-parent-*` commands.
+const HAT_ENV_PARENT_STORAGE_DIR: &str = "HAT_BACKUP_PARENT_STORAGE_DIR";
+
+/// Exit code of `hat-backup-delete` when the blob is inside the immutability window.
+const EXIT_DELETE_REFUSED: i32 = 77;
+
+/// Exit code of `hat-backup-put` when it would overwrite a blob inside the immutability window.
+/// Trying again cannot help, so such uploads fail at once.
+const EXIT_PUT_REFUSED: i32 = 77;
+
+/// Exit code of `hat-backup-get`, `hat-backup-get-range` and `hat-backup-restore` when the blob
+/// is in a cold storage tier and is not restored yet.
+const EXIT_RESTORE_PENDING: i32 = 75;
+
+/// Exit code of `hat-backup-get`, `hat-backup-get-range` and `hat-backup-checksum` when the blob
+/// does not exist.
+const EXIT_NOT_FOUND: i32 = 66;
+
+/// Length of the BLAKE2b-512 checksums printed by `hat-backup-checksum`, in hex.
+const CHECKSUM_HEX_LEN: usize = 128;
+
+/// Where the commands are found and what environment they run with.
+#[derive(Clone, Default)]
+struct Commands {
+    /// Directory holding the commands, instead of searching `PATH`.
+    dir: Option<PathBuf>,
+    /// Extra environment variables for every command.
+    envs: Vec<(String, String)>,
+}
+
+impl Commands {
+    fn command(&self, name: &str) -> process::Command {
+        let mut cmd = match self.dir {
+            Some(ref dir) => process::Command::new(dir.join(name)),
+            None => process::Command::new(name),
+        };
+        cmd.env(HAT_ENV_PROTOCOL, PROTOCOL_VERSION.to_string());
+        cmd.envs(self.envs.iter().cloned());
+        cmd
+    }
+}
+
+/// The error for a command that exited with a status the protocol does not allow.
+fn failed(cmd: &str, what: &str, out: &process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    match stderr.trim() {
+        "" => format!("{} failed {}: {}", cmd, what, out.status),
+        msg => format!("{} failed {}: {}: {}", cmd, what, out.status, msg),
+    }
+}
 
 pub struct CmdBackend {
-    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
+    read_cache: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
     max_cache_size: usize,
     max_concurrent: usize,
     queue: Mutex<Vec<CmdPut>>,
+    commands: Commands,
+    cmd_put: &'static str,
+    cmd_get: &'static str,
+    cmd_get_range: &'static str,
+    cmd_list: &'static str,
+    cmd_checksum: &'static str,
+    cmd_restore: &'static str,
+    cmd_delete: &'static str,
+    read_only: bool,
+    // Uploads are retried until they succeed, unless this limits the number of tries.
+    max_put_attempts: Option<usize>,
+    // Errors of uploads that were given up on, reported by the next flush.
+    failed_puts: Mutex<Vec<String>>,
+    // Set when the range command is not installed; we then fall back to whole reads.
+    no_range_cmd: AtomicBool,
+    // Set when the checksum command is not installed; checksums are then unknown.
+    no_checksum_cmd: AtomicBool,
+    // Set when the restore command is not installed; all blobs are then ready to read.
+    no_restore_cmd: AtomicBool,
 }
 
 struct CmdPutContext {
+    commands: Commands,
+    cmd_put: &'static str,
+    attempts: usize,
     hex_key: String,
+    hex_checksum: String,
+    class: StorageClass,
+    replace: bool,
     text: CipherText,
     done_callback: Box<FnBox<(), ()>>,
 }
@@ -43,11 +156,17 @@ impl CmdPutContext {
     fn start_child(&self) -> Result<process::Child, String> {
         use std::io::Write;
 
-        let mut child = process::Command::new(HAT_CMD_PUT)
+        let mut cmd = self.commands.command(self.cmd_put);
+        if self.replace {
+            cmd.env(HAT_ENV_REPLACE, "1");
+        }
+        let mut child = cmd
             .arg(&self.hex_key[..])
+            .env(HAT_ENV_CHECKSUM, &self.hex_checksum)
+            .env(HAT_ENV_STORAGE_CLASS, self.class.name())
             .stdin(process::Stdio::piped())
             .spawn()
-            .map_err(|err| format!("failed to spawn sub-process {}: {}", HAT_CMD_PUT, err))?;
+            .map_err(|err| format!("failed to spawn sub-process {}: {}", self.cmd_put, err))?;
 
         {
             let mut stdin = mem::replace(&mut child.stdin, None).expect("failed to get stdin");
@@ -68,7 +187,8 @@ struct CmdPut {
 }
 
 impl CmdPut {
-    fn new(context: CmdPutContext) -> Result<Self, String> {
+    fn new(mut context: CmdPutContext) -> Result<Self, String> {
+        context.attempts += 1;
         let child = context.start_child()?;
 
         Ok(CmdPut {
@@ -81,23 +201,25 @@ impl CmdPut {
         self.child.try_wait().map_err(|err| {
             format!(
                 "failed to query sub-process {}: {}",
-                HAT_CMD_PUT,
+                self.context.cmd_put,
                 err.to_string()
             )
         })
     }
 
-    fn wait(mut self) -> Result<(), (String, CmdPutContext)> {
+    /// Wait for the upload. On failure, the context is returned to try again, unless the
+    /// storage refused the upload.
+    fn wait(mut self) -> Result<(), (String, Option<Box<CmdPutContext>>)> {
         let status = match self.child.wait() {
             Ok(status) => status,
             Err(err) => {
                 return Err((
                     format!(
                         "failed to query sub-process {}: {}",
-                        HAT_CMD_PUT,
+                        self.context.cmd_put,
                         err.to_string()
                     ),
-                    self.context,
+                    Some(Box::new(self.context)),
                 ))
             }
         };
@@ -105,14 +227,20 @@ impl CmdPut {
         if status.success() {
             self.context.done_callback.call(());
             Ok(())
+        } else if status.code() == Some(EXIT_PUT_REFUSED) {
+            let err = format!(
+                "sub-process {} refused to overwrite {} inside the storage immutability window",
+                self.context.cmd_put, self.context.hex_key
+            );
+            Err((err, None))
         } else {
             let why = status
                 .code()
                 .map(|c| format!("failed with exit code: {}", c))
                 .unwrap_or_else(|| "killed by signal".into());
 
-            let err = format!("sub-process {} {}", HAT_CMD_PUT, why);
-            Err((err, self.context))
+            let err = format!("sub-process {} {}", self.context.cmd_put, why);
+            Err((err, Some(Box::new(self.context))))
         }
     }
 }
@@ -124,13 +252,87 @@ impl CmdBackend {
             max_cache_size: 10,
             max_concurrent: 5,
             queue: Mutex::new(vec![]),
+            commands: Commands::default(),
+            cmd_put: HAT_CMD_PUT,
+            cmd_get: HAT_CMD_GET,
+            cmd_get_range: HAT_CMD_GET_RANGE,
+            cmd_list: HAT_CMD_LIST,
+            cmd_checksum: HAT_CMD_CHECKSUM,
+            cmd_restore: HAT_CMD_RESTORE,
+            cmd_delete: HAT_CMD_DELETE,
+            read_only: false,
+            max_put_attempts: None,
+            failed_puts: Mutex::new(vec![]),
+            no_range_cmd: AtomicBool::new(false),
+            no_checksum_cmd: AtomicBool::new(false),
+            no_restore_cmd: AtomicBool::new(false),
         }
     }
 
+    /// Read-only access to a parent repository through `hat-backup-parent-get`,
+    /// `hat-backup-parent-get-range`, `hat-backup-parent-list`, `hat-backup-parent-checksum` and
+    /// `hat-backup-parent-restore`, which find the parent in `$HAT_BACKUP_PARENT_STORAGE_DIR`,
+    /// set to `storage`.
+    pub fn new_parent(storage: &str) -> CmdBackend {
+        let parent = CmdBackend {
+            cmd_get: HAT_CMD_PARENT_GET,
+            cmd_get_range: HAT_CMD_PARENT_GET_RANGE,
+            cmd_list: HAT_CMD_PARENT_LIST,
+            cmd_checksum: HAT_CMD_PARENT_CHECKSUM,
+            cmd_restore: HAT_CMD_PARENT_RESTORE,
+            read_only: true,
+            ..CmdBackend::new()
+        };
+        parent.with_env(HAT_ENV_PARENT_STORAGE_DIR, storage)
+    }
+
+    /// Run the commands in `dir`, instead of the ones found in `PATH`.
+    pub fn with_command_dir(mut self, dir: PathBuf) -> CmdBackend {
+        self.commands.dir = Some(dir);
+        self
+    }
+
+    /// Run the commands with the environment variable `key` set to `value`.
+    pub fn with_env(mut self, key: &str, value: &str) -> CmdBackend {
+        self.commands.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Give up on an upload after `attempts` tries and fail the next `flush`, instead of
+    /// retrying it until it succeeds.
+    pub fn with_put_attempts(mut self, attempts: usize) -> CmdBackend {
+        self.max_put_attempts = Some(attempts);
+        self
+    }
+
+    fn put_context(
+        &self,
+        name: &[u8],
+        text: CipherText,
+        class: StorageClass,
+        replace: bool,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<CmdPutContext, String> {
+        if self.read_only {
+            return Err("cannot store in a read-only parent repository".into());
+        }
+        Ok(CmdPutContext {
+            commands: self.commands.clone(),
+            cmd_put: self.cmd_put,
+            attempts: 0,
+            hex_key: hex::encode(name),
+            hex_checksum: hex::encode(keys::checksum(&text.slices())),
+            class: class,
+            replace: replace,
+            text: text,
+            done_callback: done,
+        })
+    }
+
     fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
         match self.read_cache.lock() {
             Err(e) => Some(Err(e.to_string())),
-            Ok(cache) => cache.get(name).cloned(),
+            Ok(cache) => cache.get(name).cloned().map(Ok),
         }
     }
 
@@ -138,11 +340,18 @@ impl CmdBackend {
         // Read key:
         let hex_key = hex::encode(&name);
 
-        match process::Command::new(HAT_CMD_GET)
+        match self.commands
+            .command(self.cmd_get)
             .arg(&hex_key[..])
-            .stdout(process::Stdio::piped())
             .output()
         {
+            Ok(ref out) if out.status.code() == Some(EXIT_RESTORE_PENDING) => {
+                Err(format!("{}: {}", RESTORE_PENDING, hex_key))
+            }
+            Ok(ref out) if out.status.code() == Some(EXIT_NOT_FOUND) => Ok(None),
+            Ok(ref out) if !out.status.success() => {
+                Err(failed(self.cmd_get, &format!("while getting file {}", hex_key), out))
+            }
             Ok(out) => {
                 if out.stdout.is_empty() {
                     Ok(None)
@@ -152,23 +361,126 @@ impl CmdBackend {
             }
             Err(err) => Err(format!(
                 "{} failed while getting file {}: {}",
-                HAT_CMD_GET,
+                self.cmd_get,
                 hex_key,
                 err.to_string()
             )),
         }
     }
 
+    /// Read part of a blob with the range command. Returns `None` if it is not installed.
+    fn get_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Option<Result<Option<Vec<u8>>, String>> {
+        let hex_key = hex::encode(name);
+
+        match self.commands
+            .command(self.cmd_get_range)
+            .arg(&hex_key[..])
+            .arg(offset.to_string())
+            .arg(len.to_string())
+            .output()
+        {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => Some(Err(format!(
+                "{} failed while getting file {}: {}",
+                self.cmd_get_range, hex_key, err
+            ))),
+            Ok(ref out) if out.status.code() == Some(EXIT_RESTORE_PENDING) => {
+                Some(Err(format!("{}: {}", RESTORE_PENDING, hex_key)))
+            }
+            Ok(ref out) if out.status.code() == Some(EXIT_NOT_FOUND) => Some(Ok(None)),
+            Ok(ref out) if !out.status.success() => Some(Err(failed(
+                self.cmd_get_range,
+                &format!("while getting file {}", hex_key),
+                out,
+            ))),
+            Ok(ref out) if out.stdout.len() > len => Some(Err(format!(
+                "{} returned {} bytes of file {} when asked for {}",
+                self.cmd_get_range,
+                out.stdout.len(),
+                hex_key,
+                len
+            ))),
+            Ok(out) => Some(Ok(Some(out.stdout))),
+        }
+    }
+
+    /// Ask the storage for the checksum of a blob. Returns `None` if the command is not
+    /// installed.
+    fn get_checksum(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
+        let hex_key = hex::encode(name);
+
+        match self.commands
+            .command(self.cmd_checksum)
+            .arg(&hex_key[..])
+            .output()
+        {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => Some(Err(format!(
+                "{} failed while checking file {}: {}",
+                self.cmd_checksum, hex_key, err
+            ))),
+            Ok(ref out) if out.status.code() == Some(EXIT_NOT_FOUND) => Some(Ok(None)),
+            Ok(ref out) if !out.status.success() => Some(Err(failed(
+                self.cmd_checksum,
+                &format!("while checking file {}", hex_key),
+                out,
+            ))),
+            Ok(out) => {
+                let text = String::from_utf8_lossy(&out.stdout);
+                match text.trim() {
+                    "" => Some(Ok(None)),
+                    sum if sum.len() == CHECKSUM_HEX_LEN => {
+                        Some(Vec::from_hex(sum).map(Some).map_err(|_| {
+                            format!(
+                                "{} returned an invalid checksum for {}",
+                                self.cmd_checksum, hex_key
+                            )
+                        }))
+                    }
+                    _ => Some(Err(format!(
+                        "{} returned an invalid checksum for {}",
+                        self.cmd_checksum, hex_key
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Ask the storage to restore a blob from its cold tier. Returns `None` if the restore
+    /// command is not installed.
+    fn get_restore(&self, name: &[u8]) -> Option<Result<bool, String>> {
+        let hex_key = hex::encode(name);
+
+        match self.commands.command(self.cmd_restore).arg(&hex_key[..]).output() {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => Some(Err(format!(
+                "{} failed while restoring file {}: {}",
+                self.cmd_restore, hex_key, err
+            ))),
+            Ok(ref out) if out.status.code() == Some(EXIT_RESTORE_PENDING) => Some(Ok(false)),
+            Ok(ref out) if !out.status.success() => Some(Err(format!(
+                "{} failed while restoring file {}: {}",
+                self.cmd_restore, hex_key, out.status
+            ))),
+            Ok(..) => Some(Ok(true)),
+        }
+    }
+
     fn guarded_cache_delete(&self, name: &[u8]) {
         self.read_cache.lock().unwrap().remove(name);
     }
 
-    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
+    fn guarded_cache_put(&self, name: Vec<u8>, data: Option<Vec<u8>>) {
         let mut cache = self.read_cache.lock().unwrap();
         if cache.len() >= self.max_cache_size {
             cache.clear();
         }
-        cache.insert(name, result);
+        cache.insert(name, data);
     }
 
     fn new_put(&self, ctx: CmdPutContext) -> Result<(), String> {
@@ -200,33 +512,71 @@ impl CmdBackend {
                     // Process seems ready.
                     if let Err((err, ctx)) = c.wait() {
                         eprintln!("error: {}", err);
-                        restart.push(ctx);
+                        match (ctx, self.max_put_attempts) {
+                            (Some(ref ctx), Some(max)) if ctx.attempts >= max => {
+                                self.failed_puts.lock().unwrap().push(err)
+                            }
+                            (Some(ctx), _) => restart.push(*ctx),
+                            (None, _) => self.failed_puts.lock().unwrap().push(err),
+                        }
                     }
                 }
             }
         }
 
         for ctx in restart {
-            queue.push(CmdPut::new(ctx).expect("failed to restart failed sub-process"));
+            match CmdPut::new(ctx) {
+                Ok(put) => queue.push(put),
+                Err(err) if self.max_put_attempts.is_some() => {
+                    self.failed_puts.lock().unwrap().push(err)
+                }
+                Err(err) => panic!("failed to restart failed sub-process: {}", err),
+            }
         }
     }
 }
 
 impl StoreBackend for CmdBackend {
     fn store(&self, name: &[u8], text: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
-        let hex_key = hex::encode(&name);
-
-        let context = CmdPutContext {
-            hex_key,
-            text,
-            done_callback: done,
-        };
+        self.store_with_class(name, text, StorageClass::Standard, done)
+    }
 
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        text: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        let context = self.put_context(name, text, class, false, done)?;
         self.new_put(context)?;
 
         Ok(())
     }
 
+    fn replace(
+        &self,
+        name: &[u8],
+        text: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        let context = self.put_context(name, text, StorageClass::Standard, true, done)?;
+        self.guarded_cache_delete(name);
+
+        // Replaced at once, so a refusal or failure is reported to the caller.
+        let mut put = CmdPut::new(context)?;
+        loop {
+            match put.wait() {
+                Ok(()) => return Ok(()),
+                Err((err, Some(ctx))) if ctx.attempts < REPLACE_ATTEMPTS => {
+                    eprintln!("error: {}", err);
+                    put = CmdPut::new(*ctx)?;
+                }
+                Err((err, _)) => return Err(err),
+            }
+        }
+    }
+
     fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
         // Check for key in cache:
         let value_opt = self.guarded_cache_get(name);
@@ -235,53 +585,128 @@ impl StoreBackend for CmdBackend {
         } else {
             let res = self.get(name);
 
-            // Update cache to contain key:
-            self.guarded_cache_put(name.to_vec(), res.clone());
+            // Only cache what we read; a failed read is tried again next time.
+            if let Ok(ref data) = res {
+                self.guarded_cache_put(name.to_vec(), data.clone());
+            }
             res
         }
     }
 
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.get(name)
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if !self.no_range_cmd.load(Ordering::Relaxed) {
+            match self.get_range(name, offset, len) {
+                Some(res) => return res,
+                None => self.no_range_cmd.store(true, Ordering::Relaxed),
+            }
+        }
+        Ok(self.retrieve(name)?
+            .map(|data| slice_range(&data[..], offset, len).to_vec()))
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if !self.no_range_cmd.load(Ordering::Relaxed) {
+            match self.get_range(name, offset, len) {
+                Some(res) => return res,
+                None => self.no_range_cmd.store(true, Ordering::Relaxed),
+            }
+        }
+        Ok(self.get(name)?
+            .map(|data| slice_range(&data[..], offset, len).to_vec()))
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String> {
+        if self.read_only {
+            return Err("cannot delete from a read-only parent repository".into());
+        }
         let name = name.to_vec();
         self.guarded_cache_delete(&name);
 
         let hex_key = hex::encode(&name);
 
-        match process::Command::new(HAT_CMD_DELETE).arg(&hex_key).output() {
+        match self.commands.command(self.cmd_delete).arg(&hex_key).output() {
+            Ok(ref out) if out.status.code() == Some(EXIT_DELETE_REFUSED) => {
+                Err(format!("{}: {}", DELETE_REFUSED, hex_key))
+            }
+            Ok(ref out) if !out.status.success() => Err(failed(
+                self.cmd_delete,
+                &format!("while deleting file {}", hex_key),
+                out,
+            )),
             Ok(..) => Ok(()),
             Err(err) => Err(format!(
                 "{} failed while deleting file {}: {}",
-                HAT_CMD_DELETE,
+                self.cmd_delete,
                 hex_key,
                 err.to_string()
             )),
         }
     }
 
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        if self.no_restore_cmd.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        match self.get_restore(name) {
+            Some(res) => res,
+            None => {
+                self.no_restore_cmd.store(true, Ordering::Relaxed);
+                Ok(true)
+            }
+        }
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.no_checksum_cmd.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        match self.get_checksum(name) {
+            Some(res) => res,
+            None => {
+                self.no_checksum_cmd.store(true, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
     fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
-        let listing = match process::Command::new(HAT_CMD_LIST)
-            .stdout(process::Stdio::piped())
-            .output()
-        {
+        let listing = match self.commands.command(self.cmd_list).output() {
+            Ok(ref out) if !out.status.success() => {
+                return Err(failed(self.cmd_list, "while listing files", out))
+            }
             Ok(out) => match String::from_utf8(out.stdout) {
                 Ok(utf8) => utf8,
                 Err(err) => {
                     return Err(format!(
                         "{} result encoding is not valid utf8: {}",
-                        HAT_CMD_LIST,
-                        err.to_string()
+                        self.cmd_list, err
                     ));
                 }
             },
-            Err(err) => return Err(format!("{} failed: {}", HAT_CMD_LIST, err.to_string())),
+            Err(err) => return Err(format!("{} failed: {}", self.cmd_list, err)),
         };
 
         let mut out = vec![];
-        for f in listing.lines() {
-            if let Ok(bytes) = Vec::from_hex(&f) {
-                out.push(bytes.into_boxed_slice());
-            } else {
-                eprintln!("WARNING: ignoring unexpected files name: {}", f);
+        for f in listing.lines().filter(|f| !f.is_empty()) {
+            match Vec::from_hex(f) {
+                Ok(bytes) => out.push(bytes.into_boxed_slice()),
+                Err(_) => {
+                    return Err(format!("{} returned an invalid name: {:?}", self.cmd_list, f))
+                }
             }
         }
 
@@ -301,6 +726,11 @@ impl StoreBackend for CmdBackend {
             thread::sleep(Duration::from_millis(100));
         }
 
+        let failed = mem::take(&mut *self.failed_puts.lock().unwrap());
+        if !failed.is_empty() {
+            return Err(failed.join("; "));
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,349 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conformance checks for backends, run by `hat backend check`.
+//!
+//! A throwaway blob is stored, read back whole and in part, listed, stored again and deleted
+//! twice, and blobs that do not exist are read. Each step is checked against the protocol in
+//! `backends/PROTOCOL.md`, as interpreted by `CmdBackend`. The blob is named like a control
+//! object, so that storage with an immutability window still lets it be deleted.
+
+use backend::StoreBackend;
+use crypto::keys;
+use crypto::CipherText;
+use hex;
+use rand::{self, Rng};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Names of the blobs stored by the checks start with this.
+pub const CONFORMANCE_PREFIX: &str = "hat-conformance-";
+
+/// Size of the blob stored by the checks.
+const BLOB_LEN: usize = 100 * 1024 + 7;
+
+/// The outcome of one check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+    /// The check needs an optional command that is not installed.
+    Skipped(String),
+}
+
+impl From<Result<(), String>> for CheckOutcome {
+    fn from(res: Result<(), String>) -> CheckOutcome {
+        match res {
+            Ok(()) => CheckOutcome::Passed,
+            Err(e) => CheckOutcome::Failed(e),
+        }
+    }
+}
+
+/// One of the checks of `check_conformance`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolCheck {
+    pub name: &'static str,
+    pub outcome: CheckOutcome,
+}
+
+impl ProtocolCheck {
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, CheckOutcome::Failed(..))
+    }
+}
+
+fn expect_eq<T: PartialEq>(res: Result<T, String>, expected: T, what: &str) -> CheckOutcome {
+    match res {
+        Ok(ref found) if *found == expected => CheckOutcome::Passed,
+        Ok(..) => CheckOutcome::Failed(what.into()),
+        Err(e) => CheckOutcome::Failed(e),
+    }
+}
+
+fn random_name() -> Vec<u8> {
+    let mut suffix = [0u8; 8];
+    rand::thread_rng().fill(&mut suffix[..]);
+    format!("{}{}", CONFORMANCE_PREFIX, hex::encode(suffix)).into_bytes()
+}
+
+/// Store `data` as `name` and wait until the backend confirms it.
+fn store<B: StoreBackend>(backend: &B, name: &[u8], data: &[u8]) -> CheckOutcome {
+    let done = Arc::new(AtomicBool::new(false));
+    let done_ = done.clone();
+    let res = backend
+        .store(
+            name,
+            CipherText::new(data.to_vec()),
+            Box::new(move |()| done_.store(true, Ordering::SeqCst)),
+        )
+        .and_then(|()| backend.flush());
+    match res {
+        Err(e) => CheckOutcome::Failed(e),
+        Ok(()) if !done.load(Ordering::SeqCst) => {
+            CheckOutcome::Failed("the upload was never confirmed".into())
+        }
+        Ok(()) => CheckOutcome::Passed,
+    }
+}
+
+/// Run the checks against `backend`, in order. The blob stored by the checks is deleted again,
+/// unless deleting is what fails.
+pub fn check_conformance<B: StoreBackend>(backend: &B) -> Vec<ProtocolCheck> {
+    let name = random_name();
+    let missing = random_name();
+    let mut data = vec![0u8; BLOB_LEN];
+    rand::thread_rng().fill(&mut data[..]);
+
+    let listed = |name: &[u8]| {
+        backend
+            .list()
+            .map(|names| names.iter().any(|n| &n[..] == name))
+    };
+
+    let mut checks = vec![];
+    let mut check = |name: &'static str, outcome: CheckOutcome| {
+        checks.push(ProtocolCheck { name, outcome })
+    };
+
+    check("list", backend.list().map(|_| ()).into());
+    check(
+        "get of a missing blob",
+        expect_eq(backend.retrieve(&missing), None, "returned data"),
+    );
+    check(
+        "get-range of a missing blob",
+        expect_eq(
+            backend.retrieve_range(&missing, 0, 10),
+            None,
+            "returned data",
+        ),
+    );
+    check(
+        "checksum of a missing blob",
+        expect_eq(backend.checksum(&missing), None, "returned a checksum"),
+    );
+
+    check("put", store(backend, &name, &data));
+    check(
+        "get",
+        expect_eq(
+            backend.retrieve(&name),
+            Some(data.clone()),
+            "returned other data than was stored",
+        ),
+    );
+    check(
+        "get-range",
+        expect_eq(
+            backend.retrieve_range(&name, 10, 1000),
+            Some(data[10..1010].to_vec()),
+            "returned other data than was stored",
+        ),
+    );
+    check(
+        "get-range past the end",
+        expect_eq(
+            backend.retrieve_range(&name, BLOB_LEN - 10, 1000),
+            Some(data[BLOB_LEN - 10..].to_vec()),
+            "returned other data than the end of the blob",
+        ),
+    );
+    check(
+        "checksum",
+        match backend.checksum(&name) {
+            Ok(None) => CheckOutcome::Skipped("no checksum returned; it is optional".into()),
+            res => expect_eq(
+                res,
+                Some(keys::checksum(&[&data[..]])),
+                "returned the wrong checksum",
+            ),
+        },
+    );
+    check(
+        "list of a stored blob",
+        expect_eq(listed(&name), true, "the blob is not listed"),
+    );
+    check("put again", store(backend, &name, &data));
+
+    check("delete", backend.delete(&name).into());
+    check(
+        "get of a deleted blob",
+        expect_eq(backend.retrieve(&name), None, "returned data"),
+    );
+    check(
+        "list of a deleted blob",
+        expect_eq(listed(&name), false, "the blob is still listed"),
+    );
+    check("delete again", backend.delete(&name).into());
+
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::CmdBackend;
+    use filetime;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    fn backend_dir(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("backends")
+            .join(name)
+    }
+
+    fn storage_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("hat-conformance-{}-{}", name, process::id()))
+    }
+
+    fn faulty(storage: &Path, fault: &str) -> CmdBackend {
+        CmdBackend::new()
+            .with_command_dir(backend_dir("faulty"))
+            .with_env("HAT_BACKUP_STORAGE_DIR", &storage.display().to_string())
+            .with_env("HAT_BACKUP_FAULT", fault)
+            .with_put_attempts(2)
+    }
+
+    fn failed(checks: &[ProtocolCheck]) -> Vec<&'static str> {
+        checks.iter().filter(|c| c.failed()).map(|c| c.name).collect()
+    }
+
+    #[test]
+    fn localdir_conforms() {
+        let storage = storage_dir("localdir");
+        let backend = CmdBackend::new()
+            .with_command_dir(backend_dir("localdir"))
+            .with_env("HAT_BACKUP_STORAGE_DIR", &storage.display().to_string());
+
+        let checks = check_conformance(&backend);
+        fs::remove_dir_all(&storage).unwrap();
+
+        for c in &checks {
+            assert_eq!(c.outcome, CheckOutcome::Passed, "{}", c.name);
+        }
+    }
+
+    #[test]
+    fn injected_faults_fail_checks() {
+        let cases: &[(&str, &[&str])] = &[
+            ("hat-backup-put:fail", &["put", "get", "get-range", "get-range past the end",
+                                       "list of a stored blob", "put again"]),
+            ("hat-backup-get:fail", &["get of a missing blob", "get", "get of a deleted blob"]),
+            ("hat-backup-get:garbage", &["get of a missing blob", "get",
+                                         "get of a deleted blob"]),
+            ("hat-backup-get-range:garbage", &["get-range", "get-range past the end"]),
+            ("hat-backup-list:fail", &["list", "list of a stored blob",
+                                       "list of a deleted blob"]),
+            ("hat-backup-list:garbage", &["list", "list of a stored blob",
+                                          "list of a deleted blob"]),
+            ("hat-backup-delete:fail", &["delete", "get of a deleted blob",
+                                         "list of a deleted blob", "delete again"]),
+            ("hat-backup-checksum:fail", &["checksum of a missing blob", "checksum"]),
+            ("hat-backup-checksum:garbage", &["checksum"]),
+        ];
+        for (i, &(fault, expected)) in cases.iter().enumerate() {
+            let storage = storage_dir(&format!("faulty-{}", i));
+            let checks = check_conformance(&faulty(&storage, fault));
+            let _ = fs::remove_dir_all(&storage);
+
+            assert_eq!(failed(&checks), expected.to_vec(), "{}", fault);
+        }
+    }
+
+    #[test]
+    fn failing_commands_are_errors() {
+        let storage = storage_dir("errors");
+
+        let get = faulty(&storage, "hat-backup-get:fail");
+        assert!(get.retrieve(b"missing").is_err());
+
+        let delete = faulty(&storage, "hat-backup-delete:fail");
+        assert!(delete.delete(b"missing").is_err());
+
+        let list = faulty(&storage, "hat-backup-list:garbage");
+        assert!(list.list().is_err());
+
+        let _ = fs::remove_dir_all(&storage);
+    }
+
+    #[test]
+    fn localdir_keeps_young_blobs() {
+        let storage = storage_dir("immutable");
+        let backend = CmdBackend::new()
+            .with_command_dir(backend_dir("localdir"))
+            .with_env("HAT_BACKUP_STORAGE_DIR", &storage.display().to_string())
+            .with_env("HAT_BACKUP_IMMUTABLE_DAYS", "1");
+        let put = |data: &[u8]| {
+            backend
+                .store(b"blob", CipherText::new(data.to_vec()), Box::new(|()| ()))
+                .and_then(|()| backend.flush())
+        };
+        let replace = |data: &[u8]| {
+            backend.replace(b"blob", CipherText::new(data.to_vec()), Box::new(|()| ()))
+        };
+        assert_eq!(put(b"old"), Ok(()));
+        let retried = put(b"old");
+
+        // Refused without retrying, which would never end.
+        let refused = put(b"new");
+        let refused_replace = replace(b"new");
+        let file = storage.join("blobs").join(hex::encode(b"blob"));
+        let young = fs::read(&file);
+
+        // Once the window has passed, the blob may be overwritten.
+        let old = filetime::FileTime::from_unix_time(0, 0);
+        filetime::set_file_times(&file, old, old).unwrap();
+        let overwritten = put(b"new");
+        filetime::set_file_times(&file, old, old).unwrap();
+        let replaced = replace(b"newer");
+        let aged = fs::read(&file);
+        fs::remove_dir_all(&storage).unwrap();
+
+        assert_eq!(retried, Ok(()));
+        assert!(refused.unwrap_err().contains("immutability window"));
+        assert!(refused_replace.unwrap_err().contains("immutability window"));
+        assert_eq!(young.unwrap(), b"old");
+        assert_eq!(overwritten, Ok(()));
+        assert_eq!(replaced, Ok(()));
+        assert_eq!(aged.unwrap(), b"newer");
+    }
+
+    #[test]
+    fn localdir_parent_reads_parent_storage() {
+        let storage = storage_dir("parent");
+        let own = CmdBackend::new()
+            .with_command_dir(backend_dir("localdir"))
+            .with_env("HAT_BACKUP_STORAGE_DIR", &storage.display().to_string());
+        own.store(b"blob", CipherText::new(b"data".to_vec()), Box::new(|()| ()))
+            .unwrap();
+        own.flush().unwrap();
+
+        let parent = CmdBackend::new_parent(&storage.display().to_string())
+            .with_command_dir(backend_dir("localdir"));
+        let listed = parent.list();
+        let retrieved = parent.retrieve(b"blob");
+        let ranged = parent.retrieve_range(b"blob", 1, 2);
+        let checksum = parent.checksum(b"blob");
+        fs::remove_dir_all(&storage).unwrap();
+
+        assert_eq!(listed.unwrap(), vec![b"blob".to_vec().into_boxed_slice()]);
+        assert_eq!(retrieved.unwrap(), Some(b"data".to_vec()));
+        assert_eq!(ranged.unwrap(), Some(b"at".to_vec()));
+        assert!(checksum.is_ok());
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use backend::StoreBackend;
+use crypto::keys;
 use crypto::CipherText;
 use hex::{self, FromHex};
 use std::collections::BTreeMap;
@@ -66,6 +67,22 @@ impl FileBackend {
         }
     }
 
+    fn get_range(&self, name: &[u8], offset: usize, len: usize) -> Result<Option<Vec<u8>>, String> {
+        use self::io::{Read, Seek, SeekFrom};
+
+        let path = self.root.join(hex::encode(name));
+        let mut fd = match fs::File::open(&path) {
+            Err(_) => return Ok(None),
+            Ok(fd) => fd,
+        };
+
+        let mut buf = Vec::with_capacity(len);
+        fd.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| fd.take(len as u64).read_to_end(&mut buf))
+            .map_err(|e| e.to_string())?;
+        Ok(Some(buf))
+    }
+
     fn guarded_cache_delete(&self, name: &[u8]) {
         self.read_cache.lock().unwrap().remove(name);
     }
@@ -116,6 +133,20 @@ impl StoreBackend for FileBackend {
         res
     }
 
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        // Range reads are for streaming through large blobs, so they bypass the cache.
+        self.get_range(name, offset, len)
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.get(name)?.map(|data| keys::checksum(&[&data])))
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String> {
         let name = name.to_vec();
         self.guarded_cache_delete(&name);
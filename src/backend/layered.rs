@@ -0,0 +1,181 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{shared, StorageClass, StoreBackend};
+use crypto::CipherText;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+use util::FnBox;
+
+/// A repository backend layered on top of a read-only parent repository.
+///
+/// The parent (e.g. a golden OS image) must be encrypted under the same key, so that its hashes
+/// match ours and its chunks are reused instead of uploaded again. New blobs always go to `own`.
+/// Reads prefer `own` and fall back to the parent. The parent is never modified: deleting a blob
+/// that only exists in the parent is a no-op.
+///
+/// Blob names are derived from blob ids. Recovering from this backend registers the parent's
+/// blobs first, so our own blobs get higher ids and never shadow a parent blob we reference.
+pub struct LayeredBackend<B, P> {
+    own: Arc<B>,
+    parent: Option<Arc<P>>,
+    /// The blobs of `own`, listed by the first delete since the last flush.
+    own_names: Mutex<Option<HashSet<Box<[u8]>>>>,
+}
+
+impl<B: StoreBackend, P: StoreBackend> LayeredBackend<B, P> {
+    pub fn new(own: Arc<B>, parent: Option<Arc<P>>) -> LayeredBackend<B, P> {
+        LayeredBackend {
+            own: own,
+            parent: parent,
+            own_names: Mutex::new(None),
+        }
+    }
+
+    /// Keep the listing of `own` up to date with a blob stored since it was taken.
+    fn stored(&self, name: &[u8]) {
+        if let Some(ref mut names) = *self.own_names.lock().unwrap() {
+            names.insert(name.to_vec().into_boxed_slice());
+        }
+    }
+
+    /// Whether `own` has blob `name`, listing it only once between flushes.
+    fn own_has(&self, name: &[u8]) -> Result<bool, String> {
+        let mut own_names = self.own_names.lock().unwrap();
+        if own_names.is_none() {
+            *own_names = Some(self.own.list()?.into_iter().collect());
+        }
+        Ok(own_names.as_ref().unwrap().contains(name))
+    }
+}
+
+impl<B: StoreBackend, P: StoreBackend> StoreBackend for LayeredBackend<B, P> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.stored(name);
+        self.own.store(name, data, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.stored(name);
+        self.own.store_with_class(name, data, class, done)
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.stored(name);
+        self.own.replace(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match (self.own.retrieve(name)?, self.parent.as_ref()) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(parent)) => parent.retrieve(name),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match (self.own.retrieve_uncached(name)?, self.parent.as_ref()) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(parent)) => parent.retrieve_uncached(name),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        match (self.own.retrieve_range(name, offset, len)?, self.parent.as_ref()) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(parent)) => parent.retrieve_range(name, offset, len),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        match (self.own.retrieve_range_uncached(name, offset, len)?, self.parent.as_ref()) {
+            (Some(data), _) => Ok(Some(data)),
+            (None, Some(parent)) => parent.retrieve_range_uncached(name, offset, len),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        if self.parent.is_some() && !self.own_has(name)? {
+            // Belongs to the parent, which we must not modify.
+            return Ok(());
+        }
+        self.own.delete(name)?;
+        if let Some(ref mut names) = *self.own_names.lock().unwrap() {
+            names.remove(name);
+        }
+        Ok(())
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        // The blob is read from whichever layer has it; both must have it ready.
+        let own = self.own.request_restore(name)?;
+        match self.parent {
+            Some(ref parent) => Ok(parent.request_restore(name)? && own),
+            None => Ok(own),
+        }
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match (self.own.checksum(name)?, self.parent.as_ref()) {
+            (Some(sum), _) => Ok(Some(sum)),
+            (None, Some(parent)) => parent.checksum(name),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        let mut names = BTreeSet::new();
+        if let Some(ref parent) = self.parent {
+            // The parent's locks and generation belong to its own writers.
+            names.extend(
+                parent
+                    .list()?
+                    .into_iter()
+                    .filter(|name| !shared::is_control_name(name)),
+            );
+        }
+        names.extend(self.own.list()?);
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        // Others may have changed `own` by the next delete.
+        *self.own_names.lock().unwrap() = None;
+        self.own.flush()
+    }
+}
@@ -12,28 +12,62 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use backend::StoreBackend;
+use backend::{shared, slice_range, StoreBackend, DELETE_REFUSED};
+use crypto::keys;
 use crypto::CipherText;
 use std::collections::BTreeMap;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use util::FnBox;
 
 pub struct MemoryBackend {
     files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    stored_at: Mutex<BTreeMap<Vec<u8>, Instant>>,
+    immutable_for: Option<Duration>,
+    /// How far the clock of the immutability window is ahead of the real one.
+    clock_offset: Mutex<Duration>,
 }
 
 impl MemoryBackend {
     pub fn new() -> MemoryBackend {
         MemoryBackend {
             files: Mutex::new(BTreeMap::new()),
+            stored_at: Mutex::new(BTreeMap::new()),
+            immutable_for: None,
+            clock_offset: Mutex::new(Duration::from_secs(0)),
         }
     }
 
+    /// A backend that, like a storage server with an immutability window, refuses to delete
+    /// blobs stored less than `window` ago.
+    pub fn with_immutability(window: Duration) -> MemoryBackend {
+        MemoryBackend {
+            immutable_for: Some(window),
+            ..MemoryBackend::new()
+        }
+    }
+
+    /// Move the clock of the immutability window `by` ahead, as if that much time had passed.
+    pub fn advance_clock(&self, by: Duration) {
+        *self.clock_offset.lock().unwrap() += by;
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now() + *self.clock_offset.lock().unwrap()
+    }
+
+    /// Whether `at` is less than the immutability window ago.
+    fn is_immutable(&self, at: Instant) -> bool {
+        self.immutable_for
+            .is_some_and(|window| self.now().duration_since(at) < window)
+    }
+
     fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
         let mut guarded_files = self.files.lock().unwrap();
         if guarded_files.contains_key(&key) {
             return Err(format!("Key already exists: '{:?}'", key));
         }
+        self.stored_at.lock().unwrap().insert(key.clone(), self.now());
         guarded_files.insert(key, value);
         Ok(())
     }
@@ -47,6 +81,13 @@ impl MemoryBackend {
 
     fn guarded_delete(&self, key: &[u8]) -> Result<(), String> {
         let mut guarded_files = self.files.lock().unwrap();
+        let mut stored_at = self.stored_at.lock().unwrap();
+        if let Some(&at) = stored_at.get(key) {
+            if self.is_immutable(at) && !shared::is_control_name(key) {
+                return Err(DELETE_REFUSED.to_string());
+            }
+        }
+        stored_at.remove(key);
         guarded_files.remove(key);
         Ok(())
     }
@@ -68,14 +109,54 @@ impl StoreBackend for MemoryBackend {
         res
     }
 
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        let mut guarded_files = self.files.lock().unwrap();
+        let stored_at = self.stored_at.lock().unwrap();
+        if let Some(&at) = stored_at.get(name) {
+            if self.is_immutable(at) && !shared::is_control_name(name) {
+                return Err(format!(
+                    "Refusing to replace '{:?}' inside the immutability window",
+                    name
+                ));
+            }
+        }
+        guarded_files.insert(name.to_vec(), data.to_vec());
+        done.call(());
+        Ok(())
+    }
+
     fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
         self.guarded_retrieve(name)
     }
 
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        match self.files.lock() {
+            Err(e) => Err(e.to_string()),
+            Ok(map) => Ok(map.get(name).map(|data| slice_range(data, offset, len).to_vec())),
+        }
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String> {
         self.guarded_delete(name)
     }
 
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.files.lock() {
+            Err(e) => Err(e.to_string()),
+            Ok(map) => Ok(map.get(name).map(|data| keys::checksum(&[data]))),
+        }
+    }
+
     fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
         self.guarded_list()
     }
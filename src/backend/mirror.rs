@@ -0,0 +1,489 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{StorageClass, StoreBackend};
+use crypto::CipherText;
+use hex;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use util::FnBox;
+
+/// Start a read on the next replica when the current ones have not answered in this time.
+pub const DEFAULT_HEDGE_AFTER: Duration = Duration::from_millis(500);
+
+/// Consecutive failures after which a replica is only read when the healthy ones fail.
+const MAX_FAILURES: u32 = 3;
+
+/// How a replica has been doing on reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplicaHealth {
+    /// Moving average of the time successful reads took; `None` before the first one.
+    pub latency: Option<Duration>,
+    pub consecutive_failures: u32,
+}
+
+impl ReplicaHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_FAILURES
+    }
+
+    fn record(&mut self, ok: bool, elapsed: Duration) {
+        if !ok {
+            self.consecutive_failures += 1;
+            return;
+        }
+        self.consecutive_failures = 0;
+        self.latency = Some(match self.latency {
+            None => elapsed,
+            // Weigh the new sample by a quarter.
+            Some(avg) => (avg * 3 + elapsed) / 4,
+        });
+    }
+}
+
+/// A repository backend that keeps a full copy of every blob in each of several replicas, e.g.
+/// in different failure domains.
+///
+/// Writes, deletes and flushes go to every replica and fail if any of them fails. Reads go to
+/// the fastest healthy replica first. If it has not answered within the hedge threshold, the
+/// next replica is asked too and the first answer wins; a replica that fails or lacks the blob
+/// is skipped right away. Replicas that keep failing are only tried after the healthy ones.
+pub struct MirrorBackend {
+    replicas: Vec<Arc<StoreBackend>>,
+    health: Arc<Mutex<Vec<ReplicaHealth>>>,
+    hedge_after: Duration,
+}
+
+/// The outcome of a read from the replica with this index.
+type ReadResult = (usize, Result<Option<Vec<u8>>, String>);
+
+impl MirrorBackend {
+    pub fn new(replicas: Vec<Arc<StoreBackend>>) -> MirrorBackend {
+        assert!(!replicas.is_empty(), "a mirror needs at least one replica");
+        MirrorBackend {
+            health: Arc::new(Mutex::new(vec![ReplicaHealth::default(); replicas.len()])),
+            replicas: replicas,
+            hedge_after: DEFAULT_HEDGE_AFTER,
+        }
+    }
+
+    /// Ask another replica when a read has not finished after `hedge_after`.
+    pub fn with_hedge_after(mut self, hedge_after: Duration) -> MirrorBackend {
+        self.hedge_after = hedge_after;
+        self
+    }
+
+    /// Read health of each replica, in the order they were given.
+    pub fn health(&self) -> Vec<ReplicaHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Replicas in the order to read from them: healthy before unhealthy, then fastest first.
+    /// Replicas without a measured latency are tried early, so they get measured.
+    fn read_order(&self) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.replicas.len()).collect();
+        order.sort_by_key(|&i| {
+            (
+                !health[i].is_healthy(),
+                health[i].latency.unwrap_or_default(),
+            )
+        });
+        order
+    }
+
+    /// Write `data` to every replica through `store`, calling `done` once every replica is done.
+    fn store_each<F>(
+        &self,
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+        store: F,
+    ) -> Result<(), String>
+    where
+        F: Fn(&StoreBackend, CipherText, Box<FnBox<(), ()>>) -> Result<(), String>,
+    {
+        let remaining = Arc::new(AtomicUsize::new(self.replicas.len()));
+        let done = Arc::new(Mutex::new(Some(done)));
+        let bytes = data.to_vec();
+        for replica in &self.replicas {
+            let remaining = remaining.clone();
+            let done = done.clone();
+            store(
+                &**replica,
+                CipherText::new(bytes.clone()),
+                Box::new(move |()| {
+                    if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        if let Some(done) = done.lock().unwrap().take() {
+                            done.call(());
+                        }
+                    }
+                }),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Read through `read`, hedging and failing over between replicas as described above.
+    fn read<F>(&self, read: F) -> Result<Option<Vec<u8>>, String>
+    where
+        F: Fn(&StoreBackend) -> Result<Option<Vec<u8>>, String> + Send + Sync + 'static,
+    {
+        let read = Arc::new(read);
+        let order = self.read_order();
+        let (tx, rx) = mpsc::channel::<ReadResult>();
+
+        let start = |i: usize| {
+            let replica = self.replicas[i].clone();
+            let health = self.health.clone();
+            let read = read.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                let res = read(&*replica);
+                // Record even if the read was overtaken; it still tells us about the replica.
+                health.lock().unwrap()[i].record(res.is_ok(), started.elapsed());
+                let _ = tx.send((i, res));
+            });
+        };
+
+        let mut next = 1;
+        let mut running = 1;
+        start(order[0]);
+
+        let mut missing = false;
+        let mut last_err = None;
+        while running > 0 {
+            let answer = if next < order.len() {
+                match rx.recv_timeout(self.hedge_after) {
+                    Ok(answer) => Some(answer),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(answer) => Some(answer),
+                    Err(_) => break,
+                }
+            };
+            if let Some((_, res)) = answer {
+                running -= 1;
+                match res {
+                    Ok(Some(data)) => return Ok(Some(data)),
+                    Ok(None) => missing = true,
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            // Hedge on a timeout, fail over on a miss.
+            if next < order.len() {
+                start(order[next]);
+                next += 1;
+                running += 1;
+            }
+        }
+
+        // A blob is only missing if no replica failed to tell us whether it has it.
+        match last_err {
+            Some(e) => Err(e),
+            None => {
+                assert!(missing);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl StoreBackend for MirrorBackend {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store_each(data, done, |replica, data, done| {
+            replica.store_with_class(name, data, class, done)
+        })
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store_each(data, done, |replica, data, done| replica.replace(name, data, done))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let name = name.to_vec();
+        self.read(move |replica| replica.retrieve(&name))
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let name = name.to_vec();
+        self.read(move |replica| replica.retrieve_uncached(&name))
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let name = name.to_vec();
+        self.read(move |replica| replica.retrieve_range(&name, offset, len))
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let name = name.to_vec();
+        self.read(move |replica| replica.retrieve_range_uncached(&name, offset, len))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        for replica in &self.replicas {
+            replica.delete(name)?;
+        }
+        Ok(())
+    }
+
+    /// Ready once a replica has the blob ready, as reads can be served by any replica.
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        let mut ready = false;
+        for replica in &self.replicas {
+            ready |= replica.request_restore(name)?;
+        }
+        Ok(ready)
+    }
+
+    /// The checksum the replicas agree on. Replicas without one are skipped; differing
+    /// checksums are an error, as at least one replica holds a damaged copy.
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut agreed: Option<Vec<u8>> = None;
+        for replica in &self.replicas {
+            let sum = match replica.checksum(name)? {
+                Some(sum) => sum,
+                None => continue,
+            };
+            match agreed {
+                Some(ref other) if *other != sum => {
+                    return Err(format!(
+                        "Replicas disagree on the checksum of {}",
+                        hex::encode(name)
+                    ))
+                }
+                Some(_) => (),
+                None => agreed = Some(sum),
+            }
+        }
+        Ok(agreed)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        // A blob missing from some replicas is still stored.
+        let mut names = BTreeSet::new();
+        for replica in &self.replicas {
+            names.extend(replica.list()?);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        for replica in &self.replicas {
+            replica.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use crypto::keys;
+    use std::sync::atomic::AtomicBool;
+
+    /// A replica that can be made slow or broken.
+    struct TestReplica {
+        blobs: MemoryBackend,
+        delay: Duration,
+        broken: AtomicBool,
+    }
+
+    impl TestReplica {
+        fn new(delay: Duration) -> Arc<TestReplica> {
+            Arc::new(TestReplica {
+                blobs: MemoryBackend::new(),
+                delay: delay,
+                broken: AtomicBool::new(false),
+            })
+        }
+    }
+
+    impl StoreBackend for TestReplica {
+        fn store(
+            &self,
+            name: &[u8],
+            data: CipherText,
+            done: Box<FnBox<(), ()>>,
+        ) -> Result<(), String> {
+            self.blobs.store(name, data, done)
+        }
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            thread::sleep(self.delay);
+            if self.broken.load(Ordering::SeqCst) {
+                return Err("replica is down".to_owned());
+            }
+            self.blobs.retrieve(name)
+        }
+        fn delete(&self, name: &[u8]) -> Result<(), String> {
+            self.blobs.delete(name)
+        }
+        fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.blobs.checksum(name)
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            self.blobs.list()
+        }
+        fn flush(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn mirror(replicas: &[Arc<TestReplica>]) -> MirrorBackend {
+        MirrorBackend::new(
+            replicas
+                .iter()
+                .map(|r| r.clone() as Arc<StoreBackend>)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn stores_on_every_replica() {
+        let replicas = [
+            TestReplica::new(Duration::from_millis(0)),
+            TestReplica::new(Duration::from_millis(0)),
+        ];
+        let backend = mirror(&replicas);
+
+        let done = Arc::new(AtomicUsize::new(0));
+        let done_in_callback = done.clone();
+        backend
+            .store(
+                b"name",
+                CipherText::new(vec![1, 2, 3]),
+                Box::new(move |()| {
+                    done_in_callback.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+            .unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        for r in &replicas {
+            assert_eq!(r.blobs.retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+        }
+
+        backend.delete(b"name").unwrap();
+        assert_eq!(backend.retrieve(b"name").unwrap(), None);
+    }
+
+    #[test]
+    fn fails_over_to_healthy_replicas() {
+        let replicas = [
+            TestReplica::new(Duration::from_millis(0)),
+            TestReplica::new(Duration::from_millis(0)),
+        ];
+        let backend = mirror(&replicas);
+        backend
+            .store(b"name", CipherText::new(vec![7]), Box::new(|()| ()))
+            .unwrap();
+
+        replicas[0].broken.store(true, Ordering::SeqCst);
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![7]));
+        }
+        assert!(!backend.health()[0].is_healthy());
+        assert_eq!(backend.read_order(), vec![1, 0]);
+
+        // The broken replica is still asked when the others miss the blob.
+        replicas[1].blobs.delete(b"name").unwrap();
+        assert!(backend.retrieve(b"name").is_err());
+
+        // Missing everywhere.
+        replicas[0].broken.store(false, Ordering::SeqCst);
+        replicas[0].blobs.delete(b"name").unwrap();
+        assert_eq!(backend.retrieve(b"name").unwrap(), None);
+        assert!(backend.health()[0].is_healthy());
+    }
+
+    #[test]
+    fn checksums_must_agree() {
+        let replicas = [
+            TestReplica::new(Duration::from_millis(0)),
+            TestReplica::new(Duration::from_millis(0)),
+        ];
+        let backend = mirror(&replicas);
+        backend
+            .store(b"name", CipherText::new(vec![7]), Box::new(|()| ()))
+            .unwrap();
+        assert_eq!(
+            backend.checksum(b"name").unwrap(),
+            Some(keys::checksum(&[&[7]]))
+        );
+
+        replicas[1].blobs.delete(b"name").unwrap();
+        assert_eq!(
+            backend.checksum(b"name").unwrap(),
+            Some(keys::checksum(&[&[7]]))
+        );
+
+        replicas[1]
+            .blobs
+            .store(b"name", CipherText::new(vec![8]), Box::new(|()| ()))
+            .unwrap();
+        assert!(backend.checksum(b"name").is_err());
+    }
+
+    #[test]
+    fn hedges_slow_reads() {
+        let replicas = [
+            TestReplica::new(Duration::from_millis(500)),
+            TestReplica::new(Duration::from_millis(0)),
+        ];
+        let backend = mirror(&replicas).with_hedge_after(Duration::from_millis(10));
+        backend
+            .store(b"name", CipherText::new(vec![7]), Box::new(|()| ()))
+            .unwrap();
+
+        // Both are unmeasured, so the slow replica is asked first, but the fast one answers.
+        let started = Instant::now();
+        assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![7]));
+        assert!(started.elapsed() < Duration::from_millis(400));
+
+        // Once the slow read finishes and both are measured, the fast replica is preferred.
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(backend.read_order()[0], 1);
+    }
+}
@@ -12,18 +12,75 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod caching;
 mod cmd;
+mod conformance;
 mod devnull;
 mod file;
+mod layered;
 mod memory;
+mod mirror;
+mod retry;
+mod sftp;
+mod throttled;
+mod traced;
+pub mod shared;
 
 use crypto::CipherText;
 use util::FnBox;
 
-pub use self::cmd::CmdBackend;
+pub use self::caching::{CachingBackend, UncachedBackend, BLOB_CACHE_DIRNAME,
+                         DEFAULT_BLOB_CACHE_SIZE};
+pub use self::cmd::{CmdBackend, PROTOCOL_VERSION};
+pub use self::conformance::{check_conformance, CheckOutcome, ProtocolCheck,
+                            CONFORMANCE_PREFIX};
 pub use self::devnull::DevNullBackend;
 pub use self::file::FileBackend;
+pub use self::layered::LayeredBackend;
 pub use self::memory::MemoryBackend;
+pub use self::mirror::{MirrorBackend, ReplicaHealth, DEFAULT_HEDGE_AFTER};
+pub use self::retry::{RetryBackend, DEFAULT_RETRY_ATTEMPTS};
+pub use self::sftp::{SftpBackend, DEFAULT_SFTP_CONNECTIONS};
+pub use self::throttled::{BandwidthSchedule, ThrottledBackend};
+pub use self::traced::{BackendOp, CallStats, SlowCall, TracedBackend, DEFAULT_SLOW_AFTER,
+                       SLOW_LOG_FILENAME};
+
+/// Start of the error returned when the storage refuses to delete a blob that is still inside
+/// its immutability window. Such blobs are kept, and deleted by a GC after the window.
+pub const DELETE_REFUSED: &str = "Deletion refused inside the storage immutability window";
+
+pub fn is_delete_refused(err: &str) -> bool {
+    err.starts_with(DELETE_REFUSED)
+}
+
+/// Start of the error returned when reading a blob that is kept in a cold storage tier and is
+/// not restored yet. Reading it again succeeds once the storage has restored it.
+pub const RESTORE_PENDING: &str = "Blob is being restored from cold storage";
+
+pub fn is_restore_pending(err: &str) -> bool {
+    err.starts_with(RESTORE_PENDING)
+}
+
+/// How soon a stored blob is expected to be read back, for storage with cheaper tiers for data
+/// that is rarely read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageClass {
+    /// Blobs with metadata, such as directory listings and snapshot lists, which most commands
+    /// read.
+    Standard,
+    /// Blobs with nothing but file contents, which are only read to restore files.
+    Cold,
+}
+
+impl StorageClass {
+    /// The name given to the storage, e.g. to `hat-backup-put`.
+    pub fn name(self) -> &'static str {
+        match self {
+            StorageClass::Standard => "STANDARD",
+            StorageClass::Cold => "COLD",
+        }
+    }
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
     fn store(
@@ -32,8 +89,144 @@ pub trait StoreBackend: Sync + Send + 'static {
         data: CipherText,
         done_callback: Box<FnBox<(), ()>>,
     ) -> Result<(), String>;
+
+    /// Like `store`, with a hint of the storage class that suits the blob. Backends with
+    /// storage tiers should override this; the default ignores the hint.
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        _class: StorageClass,
+        done_callback: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store(name, data, done_callback)
+    }
+
+    /// Store `data` in place of blob `name`, which was stored before, e.g. sealed again with new
+    /// keys. Readers see the old data until the new data is complete. Backends whose `store`
+    /// refuses existing names, or that wrap another backend, should override this.
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store(name, data, done)
+    }
+
     fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    /// Like `retrieve`, but from the storage itself: caches on the way must not answer it.
+    /// Backends that cache reads, or wrap another backend, should override this.
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retrieve(name)
+    }
+
+    /// Retrieve at most `len` bytes of a blob, starting at `offset`. Fewer bytes are returned
+    /// only at the end of the blob. Backends that can read parts of a blob should override this;
+    /// the default fetches the whole blob.
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.retrieve(name)?.map(|data| slice_range(&data[..], offset, len).to_vec()))
+    }
+
+    /// Like `retrieve_range`, but from the storage itself, as `retrieve_uncached`.
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.retrieve_range(name, offset, len)
+    }
     fn delete(&self, name: &[u8]) -> Result<(), String>;
+
+    /// Ask the storage to make blob `name` readable, if it is kept in a cold tier. Returns false
+    /// while the blob is being restored; reads then fail with `RESTORE_PENDING`. Backends
+    /// without storage tiers have every blob ready.
+    fn request_restore(&self, _name: &[u8]) -> Result<bool, String> {
+        Ok(true)
+    }
+
+    /// Checksum of blob `name` as computed by `crypto::keys::checksum`, computed where the blob
+    /// is stored, so checking it does not download the blob. `None` if the backend cannot tell,
+    /// or does not have the blob. Backends that can ask their storage should override this.
+    fn checksum(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(None)
+    }
+
     fn list(&self) -> Result<Vec<Box<[u8]>>, String>;
     fn flush(&self) -> Result<(), String>;
 }
+
+/// Lets the backend be picked at runtime, e.g. from the configuration.
+impl StoreBackend for Box<StoreBackend> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        (**self).store(name, data, done)
+    }
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        (**self).store_with_class(name, data, class, done)
+    }
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        (**self).replace(name, data, done)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve(name)
+    }
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve_uncached(name)
+    }
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve_range(name, offset, len)
+    }
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve_range_uncached(name, offset, len)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        (**self).delete(name)
+    }
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        (**self).request_restore(name)
+    }
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        (**self).checksum(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        (**self).list()
+    }
+    fn flush(&self) -> Result<(), String> {
+        (**self).flush()
+    }
+}
+
+/// The part of `data` covered by a range read of `len` bytes from `offset`.
+pub fn slice_range(data: &[u8], offset: usize, len: usize) -> &[u8] {
+    let from = offset.min(data.len());
+    let to = offset.saturating_add(len).min(data.len());
+    &data[from..to]
+}
@@ -0,0 +1,427 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{is_delete_refused, is_restore_pending, StorageClass, StoreBackend};
+use crypto::CipherText;
+use rand::{self, Rng};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use util::FnBox;
+
+/// Calls made at most, unless configured otherwise.
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled for each one after it.
+const DEFAULT_FIRST_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two tries.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Errors that say something about the blob, not the connection: trying again gives the same.
+fn is_permanent(err: &str) -> bool {
+    is_delete_refused(err) || is_restore_pending(err)
+}
+
+/// The callback of a store, taken by whichever try completes first.
+type DoneSlot = Arc<Mutex<Option<Box<FnBox<(), ()>>>>>;
+
+/// A store whose callback has not been called yet, kept to be stored again if it fails later.
+struct PendingStore {
+    name: Vec<u8>,
+    data: Arc<Vec<u8>>,
+    class: StorageClass,
+    // Whether the blob replaces one stored before.
+    replace: bool,
+    done: DoneSlot,
+}
+
+impl PendingStore {
+    fn is_done(&self) -> bool {
+        self.done.lock().unwrap().is_none()
+    }
+
+    /// Hand a copy of the data to `backend`, with a callback that calls the original one once.
+    fn store_into<B: StoreBackend>(&self, backend: &B) -> Result<(), String> {
+        let slot = self.done.clone();
+        let done: Box<FnBox<(), ()>> = Box::new(move |()| {
+            let done = slot.lock().unwrap().take();
+            if let Some(done) = done {
+                done.call(());
+            }
+        });
+        let data = CipherText::new((*self.data).clone());
+        if self.replace {
+            backend.replace(&self.name, data, done)
+        } else {
+            backend.store_with_class(&self.name, data, self.class, done)
+        }
+    }
+}
+
+/// A repository backend that tries failed calls again, waiting longer after each failure.
+///
+/// A single call failing, e.g. on a flaky network, then does not abort a long commit. Each wait
+/// is drawn at random from the upper half of its exponential backoff, so clients that failed
+/// together do not retry together. Stores that fail in the background are stored again when the
+/// flush reporting them fails. Refused deletes and reads of blobs being restored from cold
+/// storage are not retried.
+pub struct RetryBackend<B> {
+    inner: Arc<B>,
+    pending: Mutex<Vec<PendingStore>>,
+    attempts: u32,
+    first_delay: Duration,
+    max_delay: Duration,
+}
+
+impl<B: StoreBackend> RetryBackend<B> {
+    pub fn new(inner: Arc<B>) -> RetryBackend<B> {
+        RetryBackend {
+            inner: inner,
+            pending: Mutex::new(vec![]),
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            first_delay: DEFAULT_FIRST_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Make each call at most `attempts` times, instead of `DEFAULT_RETRY_ATTEMPTS`.
+    pub fn with_attempts(mut self, attempts: u32) -> RetryBackend<B> {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `first` before the first retry, and never longer than `max`.
+    pub fn with_delays(mut self, first: Duration, max: Duration) -> RetryBackend<B> {
+        self.first_delay = first;
+        self.max_delay = max.max(first);
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// How long to wait after `failures` failed tries.
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        let delay = self
+            .first_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+    }
+
+    /// Make `store`, trying again as needed, and keep it until its callback is called. A failed
+    /// store takes the data and callback along, so each try gets its own copy.
+    fn store_pending(&self, store: PendingStore) -> Result<(), String> {
+        self.retry("store", || store.store_into(&*self.inner))?;
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|p| !p.is_done());
+        if !store.is_done() {
+            pending.push(store);
+        }
+        Ok(())
+    }
+
+    fn retry<T, F>(&self, what: &str, mut call: F) -> Result<T, String>
+    where
+        F: FnMut() -> Result<T, String>,
+    {
+        let mut failures = 0;
+        loop {
+            let last = failures + 1 >= self.attempts;
+            match call() {
+                Err(ref e) if !last && !is_permanent(e) => {
+                    failures += 1;
+                    let delay = self.delay(failures);
+                    warn!(
+                        "{} failed, trying again in {} ms: {}",
+                        what,
+                        delay.as_millis(),
+                        e
+                    );
+                    thread::sleep(delay);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for RetryBackend<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store_pending(PendingStore {
+            name: name.to_vec(),
+            data: Arc::new(data.into_vec()),
+            class: class,
+            replace: false,
+            done: Arc::new(Mutex::new(Some(done))),
+        })
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store_pending(PendingStore {
+            name: name.to_vec(),
+            data: Arc::new(data.into_vec()),
+            class: StorageClass::Standard,
+            replace: true,
+            done: Arc::new(Mutex::new(Some(done))),
+        })
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retry("retrieve", || self.inner.retrieve(name))
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retry("retrieve", || self.inner.retrieve_uncached(name))
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.retry("retrieve", || self.inner.retrieve_range(name, offset, len))
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.retry("retrieve", || self.inner.retrieve_range_uncached(name, offset, len))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.retry("delete", || self.inner.delete(name))
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        self.retry("restore", || self.inner.request_restore(name))
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retry("checksum", || self.inner.checksum(name))
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.retry("list", || self.inner.list())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let mut failures = 0;
+        loop {
+            let res = self.inner.flush();
+            // Once flushed, a store whose callback was not called has failed.
+            let mut failed = mem::take(&mut *self.pending.lock().unwrap());
+            failed.retain(|p| !p.is_done());
+            match res {
+                Err(ref e) if !failed.is_empty() && failures + 1 < self.attempts => {
+                    failures += 1;
+                    let delay = self.delay(failures);
+                    warn!(
+                        "{} stores failed, trying again in {} ms: {}",
+                        failed.len(),
+                        delay.as_millis(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    for store in &failed {
+                        self.retry("store", || store.store_into(&*self.inner))?;
+                    }
+                    self.pending.lock().unwrap().extend(failed);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{MemoryBackend, DELETE_REFUSED};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A backend whose calls fail while `failures` is above zero, counting down. While `lost`
+    /// is above zero, stores are accepted but dropped, and reported by the next flush.
+    struct Flaky {
+        blobs: MemoryBackend,
+        failures: AtomicUsize,
+        calls: AtomicUsize,
+        lost: AtomicUsize,
+        dropped: AtomicUsize,
+        error: &'static str,
+    }
+
+    impl Flaky {
+        fn new(failures: usize, error: &'static str) -> Flaky {
+            Flaky {
+                blobs: MemoryBackend::new(),
+                failures: AtomicUsize::new(failures),
+                calls: AtomicUsize::new(0),
+                lost: AtomicUsize::new(0),
+                dropped: AtomicUsize::new(0),
+                error: error,
+            }
+        }
+
+        fn check(&self) -> Result<(), String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let left = self.failures.load(Ordering::SeqCst);
+            if left > 0 {
+                self.failures.store(left - 1, Ordering::SeqCst);
+                return Err(self.error.into());
+            }
+            Ok(())
+        }
+    }
+
+    impl StoreBackend for Flaky {
+        fn store(
+            &self,
+            name: &[u8],
+            data: CipherText,
+            done: Box<FnBox<(), ()>>,
+        ) -> Result<(), String> {
+            self.check()?;
+            let lost = self.lost.load(Ordering::SeqCst);
+            if lost > 0 {
+                self.lost.store(lost - 1, Ordering::SeqCst);
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            }
+            self.blobs.store(name, data, done)
+        }
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.check()?;
+            self.blobs.retrieve(name)
+        }
+        fn delete(&self, name: &[u8]) -> Result<(), String> {
+            self.check()?;
+            self.blobs.delete(name)
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            self.check()?;
+            self.blobs.list()
+        }
+        fn flush(&self) -> Result<(), String> {
+            if self.dropped.swap(0, Ordering::SeqCst) > 0 {
+                return Err("upload failed".into());
+            }
+            self.blobs.flush()
+        }
+    }
+
+    fn retrying(flaky: Flaky) -> RetryBackend<Flaky> {
+        RetryBackend::new(Arc::new(flaky))
+            .with_attempts(3)
+            .with_delays(Duration::from_millis(1), Duration::from_millis(2))
+    }
+
+    #[test]
+    fn failed_calls_are_retried() {
+        let backend = retrying(Flaky::new(2, "connection reset"));
+        let done = Arc::new(AtomicUsize::new(0));
+        let done_ = done.clone();
+        backend
+            .store(
+                b"name",
+                CipherText::new(vec![1, 2, 3]),
+                Box::new(move |()| {
+                    done_.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+            .unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.inner().calls.load(Ordering::SeqCst), 3);
+
+        backend.inner().failures.store(2, Ordering::SeqCst);
+        assert_eq!(backend.retrieve(b"name").unwrap(), Some(vec![1, 2, 3]));
+
+        backend.inner().failures.store(2, Ordering::SeqCst);
+        backend.delete(b"name").unwrap();
+        assert_eq!(backend.list().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn stores_failed_in_flush_are_retried() {
+        let backend = retrying(Flaky::new(0, ""));
+        backend.inner().lost.store(2, Ordering::SeqCst);
+        let done = Arc::new(AtomicUsize::new(0));
+        for name in &[b"a", b"b"] {
+            let done = done.clone();
+            let callback = Box::new(move |()| {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+            backend.store(&name[..], CipherText::new(name.to_vec()), callback).unwrap();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+
+        backend.flush().unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+        assert_eq!(backend.retrieve(b"b").unwrap(), Some(b"b".to_vec()));
+
+        backend.inner().lost.store(3, Ordering::SeqCst);
+        backend.store(b"c", CipherText::new(vec![3]), Box::new(|()| ())).unwrap();
+        assert_eq!(backend.flush(), Err("upload failed".into()));
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let backend = retrying(Flaky::new(3, "connection reset"));
+        assert_eq!(backend.retrieve(b"name"), Err("connection reset".into()));
+        assert_eq!(backend.inner().calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let backend = retrying(Flaky::new(1, DELETE_REFUSED));
+        assert!(backend.delete(b"name").is_err());
+        assert_eq!(backend.inner().calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let backend = RetryBackend::new(Arc::new(MemoryBackend::new()))
+            .with_delays(Duration::from_millis(100), Duration::from_millis(1000));
+        for &(failures, max) in &[(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let delay = backend.delay(failures);
+            assert!(delay >= Duration::from_millis(max / 2), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(max), "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,684 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blob storage over SFTP, without the `hat-backup-*` commands.
+//!
+//! The backend drives the OpenSSH client, so hosts, ports, users and keys come from the usual
+//! `~/.ssh/config`. Each pooled connection is an `ssh` control master; every operation is a
+//! short `sftp` batch multiplexed over one of them, so it does not pay for a new SSH handshake.
+//! Connections that drop are replaced, and the operation is tried again.
+//!
+//! Blobs are stored as `<dir>/blobs/<name in hex>`. Uploads go to a `.part` file first and are
+//! renamed into place, so a dropped upload never leaves a truncated blob behind.
+
+use backend::StoreBackend;
+use crypto::CipherText;
+use hex::{self, FromHex};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand;
+use util::FnBox;
+
+/// Connections opened at most, and so uploads running at once, unless configured otherwise.
+pub const DEFAULT_SFTP_CONNECTIONS: usize = 4;
+
+/// Tries of an operation that fails because its connection dropped.
+const ATTEMPTS: u32 = 4;
+
+/// Wait before trying again, multiplied by the number of tries so far.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a new connection may take to come up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Names local files and control sockets uniquely within this process.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory only we can use, for local files and control sockets, so other users can
+/// neither read them nor plant files or links in their place. It is removed when dropped.
+struct PrivateDir {
+    path: PathBuf,
+}
+
+impl PrivateDir {
+    fn create() -> Result<PrivateDir, String> {
+        // Creating the directory fails if the name is taken, so nobody can have prepared it.
+        let path = env::temp_dir().join(format!(
+            "hat-sftp-{}-{:016x}",
+            process::id(),
+            rand::random::<u64>()
+        ));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .map_err(|e| format!("could not create {}: {}", path.display(), e))?;
+        Ok(PrivateDir { path: path })
+    }
+
+    /// A new path in the directory, nothing yet.
+    fn temp_path(&self, kind: &str) -> PathBuf {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        self.path.join(format!("{}-{}", kind, id))
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Write `data` to a new file at `path`, failing if anything is there already.
+fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+/// Check that `path` can be quoted for an `sftp` batch file.
+fn check_path(path: &str) -> Result<(), String> {
+    if path.is_empty() || path.contains('"') || path.contains('\n') {
+        return Err(format!("Invalid SFTP directory: {:?}", path));
+    }
+    Ok(())
+}
+
+/// Quote `path` for an `sftp` batch file.
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path)
+}
+
+/// True if `sftp` failed because a file or directory does not exist.
+fn is_not_found(stderr: &str) -> bool {
+    stderr.contains("not found") || stderr.contains("No such file")
+}
+
+/// True if `sftp` failed because of the connection, rather than the operation.
+fn is_transient(out: &process::Output) -> bool {
+    // ssh exits with 255 when the connection fails.
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    out.status.code() == Some(255) || stderr.contains("Connection closed")
+        || stderr.contains("Connection reset") || stderr.contains("Broken pipe")
+}
+
+fn describe(out: &process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    match stderr.trim() {
+        "" => out.status.to_string(),
+        msg => format!("{}: {}", out.status, msg),
+    }
+}
+
+/// A persistent SSH connection: a control master that `sftp` sessions are multiplexed over.
+struct Connection {
+    master: process::Child,
+    socket: PathBuf,
+}
+
+impl Connection {
+    /// Connect to `host`, with the control socket at `socket`.
+    fn open(ssh: &Path, host: &str, socket: PathBuf) -> Result<Connection, String> {
+        let mut master = process::Command::new(ssh)
+            .arg("-M")
+            .arg("-N")
+            .arg("-S")
+            .arg(&socket)
+            .args(["-o", "ControlPersist=no", "-o", "BatchMode=yes"])
+            .args(["-o", "ServerAliveInterval=15", "-o", "ServerAliveCountMax=3"])
+            .arg(host)
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", ssh.display(), e))?;
+
+        // The master creates its control socket once the connection is up.
+        let started = Instant::now();
+        while !socket.exists() {
+            match master.try_wait() {
+                Ok(None) if started.elapsed() < CONNECT_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                Ok(None) => {
+                    let _ = master.kill();
+                    let _ = master.wait();
+                    return Err(format!("timed out connecting to {}", host));
+                }
+                Ok(Some(..)) | Err(..) => {
+                    let out = master.wait_with_output().map_err(|e| e.to_string())?;
+                    return Err(format!("could not connect to {}: {}", host, describe(&out)));
+                }
+            }
+        }
+
+        Ok(Connection { master, socket })
+    }
+
+    fn alive(&mut self) -> bool {
+        matches!(self.master.try_wait(), Ok(None))
+    }
+
+    /// Run the `sftp` commands in `batch` over this connection.
+    fn sftp(&self, sftp: &Path, host: &str, batch: &str) -> Result<process::Output, String> {
+        let mut child = process::Command::new(sftp)
+            .args(["-q", "-b", "-"])
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.socket.display()))
+            .args(["-o", "ControlMaster=no", "-o", "BatchMode=yes"])
+            .arg(host)
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not run {}: {}", sftp.display(), e))?;
+        {
+            let mut stdin = child.stdin.take().expect("failed to get stdin");
+            stdin.write_all(batch.as_bytes()).map_err(|e| e.to_string())?;
+        }
+        child.wait_with_output().map_err(|e| e.to_string())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.master.kill();
+        let _ = self.master.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    idle: Vec<Connection>,
+    /// Connections open, whether idle or in use.
+    open: usize,
+}
+
+#[derive(Default)]
+struct Uploads {
+    running: usize,
+    /// Errors of uploads that failed, reported by the next flush.
+    errors: Vec<String>,
+}
+
+struct Inner {
+    host: String,
+    dir: String,
+    ssh: PathBuf,
+    sftp: PathBuf,
+    max_connections: usize,
+    pool: Mutex<Pool>,
+    pool_changed: Condvar,
+    uploads: Mutex<Uploads>,
+    uploads_changed: Condvar,
+    /// Last, so it is removed after the connections' control sockets.
+    tmp: PrivateDir,
+}
+
+impl Inner {
+    fn blobs_dir(&self) -> String {
+        format!("{}/blobs", self.dir)
+    }
+
+    fn blob_path(&self, name: &[u8]) -> String {
+        format!("{}/{}", self.blobs_dir(), hex::encode(name))
+    }
+
+    /// Take an idle connection, or open one if there are fewer than `max_connections`.
+    fn take_connection(&self) -> Result<Connection, String> {
+        let mut pool = self.pool.lock().unwrap();
+        loop {
+            if let Some(mut conn) = pool.idle.pop() {
+                if conn.alive() {
+                    return Ok(conn);
+                }
+                pool.open -= 1;
+                continue;
+            }
+            if pool.open < self.max_connections {
+                pool.open += 1;
+                drop(pool);
+                let res = Connection::open(&self.ssh, &self.host, self.tmp.temp_path("control"));
+                if res.is_err() {
+                    self.drop_connection(None);
+                }
+                return res;
+            }
+            pool = self.pool_changed.wait(pool).unwrap();
+        }
+    }
+
+    fn return_connection(&self, mut conn: Connection) {
+        if conn.alive() {
+            self.pool.lock().unwrap().idle.push(conn);
+            self.pool_changed.notify_one();
+        } else {
+            self.drop_connection(Some(conn));
+        }
+    }
+
+    fn drop_connection(&self, conn: Option<Connection>) {
+        drop(conn);
+        self.pool.lock().unwrap().open -= 1;
+        self.pool_changed.notify_one();
+    }
+
+    /// Run `batch` on a pooled connection. Operations that fail because their connection
+    /// dropped are tried again on a new one; other failures are left to the caller.
+    fn run(&self, batch: &str) -> Result<process::Output, String> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match self.take_connection() {
+                Err(e) => e,
+                Ok(mut conn) => match conn.sftp(&self.sftp, &self.host, batch) {
+                    Ok(out) => {
+                        if out.status.success() || (!is_transient(&out) && conn.alive()) {
+                            self.return_connection(conn);
+                            return Ok(out);
+                        }
+                        self.drop_connection(Some(conn));
+                        describe(&out)
+                    }
+                    Err(e) => {
+                        self.drop_connection(Some(conn));
+                        e
+                    }
+                },
+            };
+            if attempt >= ATTEMPTS {
+                return Err(format!(
+                    "sftp to {} failed {} times: {}",
+                    self.host, attempt, err
+                ));
+            }
+            warn!("sftp to {} failed, trying again: {}", self.host, err);
+            thread::sleep(RETRY_DELAY * attempt);
+        }
+    }
+
+    fn put(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        let local = self.tmp.temp_path("put");
+        write_new(&local, data).map_err(|e| e.to_string())?;
+
+        let path = self.blob_path(name);
+        let part = format!("{}.part", path);
+        // Failing to create a directory that exists is fine, hence the leading '-'.
+        let batch = format!(
+            "-mkdir {}\n-mkdir {}\nput {} {}\nrename {} {}\n",
+            quote(&self.dir),
+            quote(&self.blobs_dir()),
+            quote(&local.display().to_string()),
+            quote(&part),
+            quote(&part),
+            quote(&path)
+        );
+        let res = self.run(&batch);
+        let _ = fs::remove_file(&local);
+
+        let out = res?;
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(format!("sftp could not store {}: {}", path, describe(&out)))
+        }
+    }
+}
+
+/// Stores blobs on an SFTP server, e.g. a NAS, over a pool of persistent SSH connections.
+pub struct SftpBackend {
+    inner: Arc<Inner>,
+}
+
+impl SftpBackend {
+    /// Store blobs in `dir` on `host`, which is anything `ssh` accepts, e.g. `user@nas` or a
+    /// host alias from `~/.ssh/config`. The directory is created, but not its parents.
+    pub fn new(host: &str, dir: &str) -> Result<SftpBackend, String> {
+        check_path(dir)?;
+        Ok(SftpBackend {
+            inner: Arc::new(Inner {
+                host: host.into(),
+                dir: dir.trim_end_matches('/').into(),
+                ssh: "ssh".into(),
+                sftp: "sftp".into(),
+                max_connections: DEFAULT_SFTP_CONNECTIONS,
+                pool: Mutex::new(Pool::default()),
+                pool_changed: Condvar::new(),
+                uploads: Mutex::new(Uploads::default()),
+                uploads_changed: Condvar::new(),
+                tmp: PrivateDir::create()?,
+            }),
+        })
+    }
+
+    /// Parse `[user@]host:dir`, as understood by `scp`.
+    pub fn parse(location: &str) -> Result<SftpBackend, String> {
+        match location.find(':') {
+            Some(i) if i > 0 => SftpBackend::new(&location[..i], &location[i + 1..]),
+            _ => Err(format!(
+                "Invalid SFTP location '{}': expected [user@]host:dir",
+                location
+            )),
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("SftpBackend is configured before use")
+    }
+
+    /// Keep at most `connections` connections open, instead of `DEFAULT_SFTP_CONNECTIONS`.
+    pub fn with_connections(mut self, connections: usize) -> SftpBackend {
+        self.inner_mut().max_connections = connections.max(1);
+        self
+    }
+
+    /// Store the blobs of repository `name` in their own subdirectory.
+    pub fn with_repo(mut self, name: &str) -> Result<SftpBackend, String> {
+        check_path(name)?;
+        let dir = format!("{}/{}", self.inner.dir, name);
+        self.inner_mut().dir = dir;
+        Ok(self)
+    }
+
+    /// Run these programs instead of `ssh` and `sftp` from `PATH`.
+    pub fn with_programs(mut self, ssh: PathBuf, sftp: PathBuf) -> SftpBackend {
+        {
+            let inner = self.inner_mut();
+            inner.ssh = ssh;
+            inner.sftp = sftp;
+        }
+        self
+    }
+}
+
+impl StoreBackend for SftpBackend {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        // Wait for a free connection, so uploads queue here instead of piling up in memory.
+        {
+            let mut uploads = self.inner.uploads.lock().unwrap();
+            while uploads.running >= self.inner.max_connections {
+                uploads = self.inner.uploads_changed.wait(uploads).unwrap();
+            }
+            uploads.running += 1;
+        }
+
+        let inner = self.inner.clone();
+        let name = name.to_vec();
+        thread::spawn(move || {
+            let res = inner.put(&name, &data.to_vec());
+            let mut uploads = inner.uploads.lock().unwrap();
+            uploads.running -= 1;
+            match res {
+                Ok(()) => done.call(()),
+                Err(e) => uploads.errors.push(e),
+            }
+            inner.uploads_changed.notify_all();
+        });
+        Ok(())
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let local = self.inner.tmp.temp_path("get");
+        let path = self.inner.blob_path(name);
+        let batch = format!(
+            "get {} {}\n",
+            quote(&path),
+            quote(&local.display().to_string())
+        );
+        let out = self.inner.run(&batch)?;
+
+        let res = if out.status.success() {
+            fs::read(&local).map(Some).map_err(|e| e.to_string())
+        } else if is_not_found(&String::from_utf8_lossy(&out.stderr)) {
+            Ok(None)
+        } else {
+            Err(format!("sftp could not get {}: {}", path, describe(&out)))
+        };
+        let _ = fs::remove_file(&local);
+        res
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        let path = self.inner.blob_path(name);
+        let out = self.inner.run(&format!("rm {}\n", quote(&path)))?;
+        if out.status.success() || is_not_found(&String::from_utf8_lossy(&out.stderr)) {
+            Ok(())
+        } else {
+            Err(format!("sftp could not delete {}: {}", path, describe(&out)))
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        let dir = self.inner.blobs_dir();
+        let out = self.inner.run(&format!("ls -1 {}\n", quote(&dir)))?;
+        if !out.status.success() {
+            if is_not_found(&String::from_utf8_lossy(&out.stderr)) {
+                return Ok(vec![]);
+            }
+            return Err(format!("sftp could not list {}: {}", dir, describe(&out)));
+        }
+
+        // Lines are the listed paths, after the echo of the command itself. Unfinished uploads
+        // end in ".part" and are left out.
+        let listing = String::from_utf8_lossy(&out.stdout);
+        Ok(listing
+            .lines()
+            .filter(|line| !line.starts_with("sftp>"))
+            .filter_map(|line| line.trim().rsplit('/').next())
+            .filter_map(|name| Vec::from_hex(name).ok())
+            .map(|name| name.into_boxed_slice())
+            .collect())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let mut uploads = self.inner.uploads.lock().unwrap();
+        while uploads.running > 0 {
+            uploads = self.inner.uploads_changed.wait(uploads).unwrap();
+        }
+        let errors = mem::take(&mut uploads.errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Stands in for the `ssh` control master: it creates the control socket and waits.
+    const FAKE_SSH: &str = r#"#!/bin/bash
+while [ "$1" != "-S" ]; do shift; done
+touch "$2"
+exec sleep 600
+"#;
+
+    /// Stands in for `sftp` in batch mode, on the local filesystem. It fails as if the
+    /// connection dropped while a file named `drop` is next to it, removing the file.
+    const FAKE_SFTP: &str = r#"#!/bin/bash
+HERE="$(dirname "$0")"
+if [ -e "${HERE}/drop" ]; then
+  rm "${HERE}/drop"
+  echo "Connection closed" >&2
+  exit 255
+fi
+while read -r LINE; do
+  echo "sftp> ${LINE}"
+  IGNORE=""
+  if [[ "${LINE}" == -* ]]; then IGNORE=1; LINE="${LINE:1}"; fi
+  eval "set -- ${LINE}"
+  OK=1
+  case "$1" in
+    mkdir) mkdir "$2" 2>/dev/null || OK="" ;;
+    put) cp "$2" "$3" || OK="" ;;
+    get) if [ -f "$2" ]; then cp "$2" "$3"; else echo "File \"$2\" not found." >&2; OK=""; fi ;;
+    rename) mv "$2" "$3" || OK="" ;;
+    rm)
+      if [ -f "$2" ]; then
+        rm "$2"
+      else
+        echo "Couldn't delete file: No such file or directory" >&2; OK=""
+      fi ;;
+    ls)
+      if [ -d "$3" ]; then
+        for F in "$3"/*; do [ -e "$F" ] && echo "$F"; done
+      else
+        echo "Can't ls: \"$3\" not found" >&2; OK=""
+      fi ;;
+  esac
+  if [ -z "${OK}" ] && [ -z "${IGNORE}" ]; then exit 1; fi
+done
+"#;
+
+    struct Fake {
+        dir: PathBuf,
+    }
+
+    impl Fake {
+        fn new(name: &str) -> Fake {
+            let dir = env::temp_dir().join(format!("hat-sftp-test-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            fs::create_dir_all(dir.join("remote")).unwrap();
+            for &(name, script) in &[("ssh", FAKE_SSH), ("sftp", FAKE_SFTP)] {
+                let path = dir.join("bin").join(name);
+                fs::write(&path, script).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            Fake { dir }
+        }
+
+        fn backend(&self) -> SftpBackend {
+            self.backend_in("remote")
+        }
+
+        fn backend_in(&self, remote: &str) -> SftpBackend {
+            let remote = self.dir.join(remote).display().to_string();
+            SftpBackend::new("nas", &remote)
+                .unwrap()
+                .with_programs(self.dir.join("bin/ssh"), self.dir.join("bin/sftp"))
+        }
+
+        fn drop_next_connection(&self) {
+            fs::write(self.dir.join("bin/drop"), b"").unwrap();
+        }
+    }
+
+    impl Drop for Fake {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn store(backend: &SftpBackend, name: &[u8], data: &[u8]) {
+        backend
+            .store(name, CipherText::new(data.to_vec()), Box::new(|()| ()))
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_location() {
+        assert_eq!(SftpBackend::parse("me@nas:/backup").unwrap().inner.host, "me@nas");
+        assert_eq!(SftpBackend::parse("nas:backup/").unwrap().inner.dir, "backup");
+        assert!(SftpBackend::parse("/backup").is_err());
+        assert!(SftpBackend::parse("nas:").is_err());
+
+        let backend = SftpBackend::parse("nas:backup").unwrap();
+        assert!(backend.with_repo("a\"\nrm /").is_err());
+        let backend = SftpBackend::parse("nas:backup").unwrap();
+        assert_eq!(backend.with_repo("laptop").unwrap().inner.dir, "backup/laptop");
+    }
+
+    #[test]
+    fn local_files_are_private() {
+        let fake = Fake::new("private");
+        let backend = fake.backend();
+        let tmp = backend.inner.tmp.path.clone();
+        let mode = fs::metadata(&tmp).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // A path that is taken is not written through.
+        let local = backend.inner.tmp.temp_path("put");
+        fs::write(&local, b"planted").unwrap();
+        assert!(write_new(&local, b"data").is_err());
+        assert_eq!(fs::read(&local).unwrap(), b"planted");
+
+        drop(backend);
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn store_retrieve_list_delete() {
+        let fake = Fake::new("roundtrip");
+        let backend = fake.backend().with_connections(2);
+
+        assert_eq!(backend.list().unwrap().len(), 0);
+        assert_eq!(backend.retrieve(b"missing").unwrap(), None);
+
+        for i in 0..5u8 {
+            store(&backend, &[i], &vec![i; 1000 + i as usize]);
+        }
+        backend.flush().unwrap();
+        assert!(backend.inner.pool.lock().unwrap().open <= 2);
+
+        let mut names = backend.list().unwrap();
+        names.sort();
+        assert_eq!(names, (0..5u8).map(|i| vec![i].into_boxed_slice()).collect::<Vec<_>>());
+        assert_eq!(backend.retrieve(&[3]).unwrap(), Some(vec![3; 1003]));
+
+        backend.delete(&[3]).unwrap();
+        backend.delete(&[3]).unwrap();
+        assert_eq!(backend.retrieve(&[3]).unwrap(), None);
+        assert_eq!(backend.list().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn dropped_connections_are_retried() {
+        let fake = Fake::new("retry");
+        let backend = fake.backend();
+
+        fake.drop_next_connection();
+        store(&backend, b"name", b"data");
+        backend.flush().unwrap();
+
+        fake.drop_next_connection();
+        assert_eq!(backend.retrieve(b"name").unwrap(), Some(b"data".to_vec()));
+    }
+
+    #[test]
+    fn failed_uploads_fail_flush() {
+        let fake = Fake::new("failing");
+        // The parent of the directory must exist.
+        let backend = fake.backend_in("missing/remote");
+
+        store(&backend, b"name", b"data");
+        assert!(backend.flush().is_err());
+        backend.flush().unwrap();
+    }
+}
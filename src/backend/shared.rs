@@ -0,0 +1,277 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coordination between state directories that write to the same backend.
+//!
+//! Each such state directory has a writer id. Writing operations hold an exclusive lock,
+//! stored as an object in the backend, so commits and GC on different machines never overlap.
+//! When releasing the lock, a writer publishes the names of all blobs it knows of; GC on
+//! another writer keeps those blobs, even if its own snapshots do not reference them.
+//!
+//! Every state directory, shared or not, counts the changes it makes to the backend in a
+//! generation object. One that finds a generation other than the one it last saw knows that
+//! its local indexes may be stale. Likewise, a key version object tells the others when the
+//! keys were rotated.
+
+use backend::StoreBackend;
+use crypto::{self, CipherText};
+use hex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str;
+use util::FnBox;
+
+/// Name of the file holding the writer id in a shared state directory.
+pub const WRITER_ID_FILENAME: &str = "writer-id";
+
+/// A lock this old is stale: its writer likely crashed. It no longer blocks other writers,
+/// but GC waits until the writer has resumed, as it may have unpublished blobs.
+pub const LOCK_TTL_SECS: i64 = 24 * 3600;
+
+const CONTROL_PREFIX: &str = "hat-";
+const LOCK_PREFIX: &str = "hat-lock-";
+const REFS_PREFIX: &str = "hat-refs-";
+const GENERATION_PREFIX: &str = "hat-generation-";
+const KEY_VERSION_PREFIX: &str = "hat-key-version-";
+
+/// Whether `name` is a lock or reference list rather than a blob.
+pub fn is_control_name(name: &[u8]) -> bool {
+    name.starts_with(CONTROL_PREFIX.as_bytes()) && name.iter().all(|b| b.is_ascii_graphic())
+}
+
+/// The writer id of the state directory `dir`, if it shares its backend.
+pub fn read_writer_id(dir: &Path) -> Result<Option<String>, String> {
+    match fs::read_to_string(dir.join(WRITER_ID_FILENAME)) {
+        Ok(id) => Ok(Some(id.trim().to_owned())),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Give the state directory `dir` a new random writer id, unless it has one.
+pub fn create_writer_id(dir: &Path) -> Result<String, String> {
+    if let Some(id) = read_writer_id(dir)? {
+        return Ok(id);
+    }
+    let id = hex::encode(crypto::keys::random_bytes(8).unsecure());
+    fs::write(dir.join(WRITER_ID_FILENAME), format!("{}\n", id)).map_err(|e| e.to_string())?;
+    Ok(id)
+}
+
+/// Split a control object name `<prefix><writer>-<timestamp>`.
+fn parse_name(prefix: &str, name: &[u8]) -> Option<(String, i64)> {
+    if !is_control_name(name) {
+        return None;
+    }
+    let rest = str::from_utf8(name).ok()?.trim_start_matches(prefix);
+    if rest.len() + prefix.len() != name.len() {
+        return None;
+    }
+    let mut parts = rest.rsplitn(2, '-');
+    let ts = parts.next()?.parse().ok()?;
+    let writer = parts.next()?;
+    Some((writer.to_owned(), ts))
+}
+
+fn store<B: StoreBackend>(backend: &B, name: &[u8], data: Vec<u8>) -> Result<(), String> {
+    backend.store(name, CipherText::new(data), Box::new(|()| ()) as Box<FnBox<(), ()>>)?;
+    backend.flush()
+}
+
+/// Parse a control object name `<prefix><counter>`.
+fn parse_counter(prefix: &str, name: &[u8]) -> Option<u64> {
+    if !is_control_name(name) {
+        return None;
+    }
+    let name = str::from_utf8(name).ok()?;
+    if !name.starts_with(prefix) {
+        return None;
+    }
+    name[prefix.len()..].parse().ok()
+}
+
+/// The highest counter with `prefix` in `backend`, if any.
+fn read_counter<B: StoreBackend>(backend: &B, prefix: &str) -> Result<Option<u64>, String> {
+    Ok(backend
+        .list()?
+        .iter()
+        .filter_map(|name| parse_counter(prefix, name))
+        .max())
+}
+
+/// Store counter `value` with `prefix` in `backend`. The new object is stored before the old
+/// ones are deleted, so the counter never appears to go back.
+fn write_counter<B: StoreBackend>(backend: &B, prefix: &str, value: u64) -> Result<(), String> {
+    let names = backend.list()?;
+    store(backend, format!("{}{}", prefix, value).as_bytes(), vec![])?;
+    for name in names.iter() {
+        match parse_counter(prefix, name) {
+            Some(old) if old < value => backend.delete(name)?,
+            _ => (),
+        }
+    }
+    backend.flush()
+}
+
+/// The number of changes state directories have recorded in `backend`; 0 if none has.
+pub fn read_generation<B: StoreBackend>(backend: &B) -> Result<u64, String> {
+    Ok(read_counter(backend, GENERATION_PREFIX)?.unwrap_or(0))
+}
+
+/// Record that `backend` reached `generation`.
+pub fn write_generation<B: StoreBackend>(backend: &B, generation: u64) -> Result<(), String> {
+    write_counter(backend, GENERATION_PREFIX, generation)
+}
+
+/// The version of the keys new blobs in `backend` are written with, as last rotated.
+pub fn read_key_version<B: StoreBackend>(backend: &B) -> Result<u64, String> {
+    match read_counter(backend, KEY_VERSION_PREFIX)? {
+        None => Ok(crypto::keys::KEY_VERSION),
+        Some(version) if version < crypto::keys::KEY_VERSION => {
+            Err(format!("Invalid key version in the backend: {}", version))
+        }
+        Some(version) => Ok(version),
+    }
+}
+
+/// Record that the keys of `backend` were rotated to version `version`.
+pub fn write_key_version<B: StoreBackend>(backend: &B, version: u64) -> Result<(), String> {
+    write_counter(backend, KEY_VERSION_PREFIX, version)
+}
+
+/// The locks held on `backend`, as (writer, acquired timestamp).
+pub fn list_locks<B: StoreBackend>(backend: &B) -> Result<Vec<(String, i64)>, String> {
+    Ok(backend
+        .list()?
+        .iter()
+        .filter_map(|name| parse_name(LOCK_PREFIX, name))
+        .collect())
+}
+
+/// Writers other than `writer` whose locks went stale before `now`.
+pub fn stale_writers<B: StoreBackend>(
+    backend: &B,
+    writer: &str,
+    now: i64,
+) -> Result<Vec<String>, String> {
+    Ok(list_locks(backend)?
+        .into_iter()
+        .filter(|&(ref w, ts)| w != writer && ts + LOCK_TTL_SECS <= now)
+        .map(|(w, _)| w)
+        .collect())
+}
+
+/// The exclusive lock of one writer on a shared backend.
+pub struct RemoteLock {
+    name: Vec<u8>,
+}
+
+impl RemoteLock {
+    /// Lock `backend` for `writer` at time `now`, failing if another writer holds the lock.
+    /// The lock is announced before checking for others, so of two writers racing for it,
+    /// at least one sees the other and backs off.
+    pub fn acquire<B: StoreBackend>(
+        backend: &B,
+        writer: &str,
+        now: i64,
+    ) -> Result<RemoteLock, String> {
+        let held_by_other = |locks: &[(String, i64)]| {
+            locks
+                .iter()
+                .find(|&&(ref w, ts)| w != writer && ts + LOCK_TTL_SECS > now)
+                .map(|(w, _)| w.clone())
+        };
+        let busy =
+            |other: String| format!("Backend is locked by writer {}; try again later", other);
+
+        let locks = list_locks(backend)?;
+        if let Some(other) = held_by_other(&locks) {
+            return Err(busy(other));
+        }
+
+        // Our own locks were left by a crash; the caller recovers from it while holding ours.
+        for (w, ts) in locks {
+            if w == writer {
+                backend.delete(format!("{}{}-{}", LOCK_PREFIX, w, ts).as_bytes())?;
+            }
+        }
+
+        let lock = RemoteLock {
+            name: format!("{}{}-{}", LOCK_PREFIX, writer, now).into_bytes(),
+        };
+        store(backend, &lock.name, vec![])?;
+
+        if let Some(other) = held_by_other(&list_locks(backend)?) {
+            backend.delete(&lock.name)?;
+            return Err(busy(other));
+        }
+        Ok(lock)
+    }
+
+    pub fn release<B: StoreBackend>(self, backend: &B) -> Result<(), String> {
+        backend.delete(&self.name)?;
+        backend.flush()
+    }
+}
+
+/// Publish the blobs known to `writer`, replacing its earlier list. Requires the lock.
+pub fn publish_refs<B: StoreBackend>(
+    backend: &B,
+    writer: &str,
+    now: i64,
+    blob_names: &[Vec<u8>],
+) -> Result<(), String> {
+    let mut text = String::new();
+    for name in blob_names {
+        text.push_str(&hex::encode(name));
+        text.push('\n');
+    }
+
+    // Holding the lock, no other writer runs GC while our list is missing.
+    for old in backend.list()?.iter() {
+        match parse_name(REFS_PREFIX, old) {
+            Some((ref w, _)) if w == writer => backend.delete(old)?,
+            _ => (),
+        }
+    }
+    let name = format!("{}{}-{}", REFS_PREFIX, writer, now);
+    store(backend, name.as_bytes(), text.into_bytes())
+}
+
+/// The blobs published by writers other than `writer`.
+pub fn foreign_refs<B: StoreBackend>(backend: &B, writer: &str) -> Result<Vec<Vec<u8>>, String> {
+    // Only the newest list of each writer is current.
+    let mut newest = BTreeMap::new();
+    for name in backend.list()?.iter() {
+        if let Some((w, ts)) = parse_name(REFS_PREFIX, name) {
+            if w != writer && newest.get(&w).is_none_or(|&(t, _)| t < ts) {
+                newest.insert(w, (ts, name.to_vec()));
+            }
+        }
+    }
+
+    let mut refs = vec![];
+    for (w, (_, name)) in newest {
+        let text = backend
+            .retrieve(&name)?
+            .ok_or_else(|| format!("Reference list of writer {} disappeared", w))?;
+        for line in String::from_utf8_lossy(&text).lines() {
+            let name = hex::decode(line)
+                .map_err(|e| format!("Invalid reference list of writer {}: {}", w, e))?;
+            refs.push(name);
+        }
+    }
+    Ok(refs)
+}
@@ -0,0 +1,378 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{StorageClass, StoreBackend};
+use chrono::{self, Timelike};
+use crypto::CipherText;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use util::{parse_size, FnBox};
+
+/// Bandwidth by local time of day.
+///
+/// Written as comma-separated rules: `HH:MM-HH:MM=RATE` applies between two times of day, and
+/// may wrap around midnight; a plain `RATE` applies at other times. The first rule that matches
+/// applies; traffic is not limited when none does. Rates are bytes per second, with an optional
+/// `K`, `M` or `G` suffix, e.g. `08:00-18:00=256K,4M`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    /// Start and end minute of the day, and bytes per second.
+    windows: Vec<(u32, u32, u64)>,
+    default: Option<u64>,
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match parse_size(s) {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid rate '{}': expected bytes per second, e.g. 512K", s)),
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Result<u32, String> {
+    let mut parts = s.splitn(2, ':');
+    let hours = parts.next().and_then(|h| h.parse::<u32>().ok());
+    let minutes = parts.next().and_then(|m| m.parse::<u32>().ok());
+    match (hours, minutes) {
+        (Some(h), Some(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        _ => Err(format!("Invalid time of day '{}': expected HH:MM", s)),
+    }
+}
+
+impl BandwidthSchedule {
+    pub fn parse(s: &str) -> Result<BandwidthSchedule, String> {
+        let mut schedule = BandwidthSchedule {
+            windows: vec![],
+            default: None,
+        };
+        for rule in s.split(',').map(|r| r.trim()) {
+            let mut parts = rule.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(window), Some(rate)) => {
+                    let mut times = window.splitn(2, '-');
+                    let (start, end) = match (times.next(), times.next()) {
+                        (Some(start), Some(end)) => (start, end),
+                        _ => {
+                            return Err(format!("Invalid window '{}': expected HH:MM-HH:MM", window))
+                        }
+                    };
+                    schedule.windows.push((
+                        parse_time_of_day(start)?,
+                        parse_time_of_day(end)?,
+                        parse_rate(rate)?,
+                    ));
+                }
+                (Some(rate), None) if schedule.default.is_none() => {
+                    schedule.default = Some(parse_rate(rate)?);
+                }
+                _ => return Err(format!("Invalid bandwidth rule '{}'", rule)),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Bytes per second allowed at `minute` of the day, if limited.
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        for &(start, end, rate) in &self.windows {
+            let inside = if start <= end {
+                start <= minute && minute < end
+            } else {
+                minute >= start || minute < end
+            };
+            if inside {
+                return Some(rate);
+            }
+        }
+        self.default
+    }
+
+    /// Bytes per second allowed now, if limited.
+    pub fn rate_now(&self) -> Option<u64> {
+        let now = chrono::Local::now();
+        self.rate_at(now.hour() * 60 + now.minute())
+    }
+}
+
+impl fmt::Display for BandwidthSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rules: Vec<String> = self.windows
+            .iter()
+            .map(|&(start, end, rate)| {
+                format!(
+                    "{:02}:{:02}-{:02}:{:02}={}",
+                    start / 60,
+                    start % 60,
+                    end / 60,
+                    end % 60,
+                    rate
+                )
+            })
+            .collect();
+        rules.extend(self.default.map(|rate| rate.to_string()));
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+/// The part of a second of traffic a bucket holds, which may be sent at once after a pause.
+const BURST_SECS: f64 = 0.1;
+
+/// A token bucket: bytes may be sent while it holds tokens, which it gains at the rate its
+/// schedule allows now, up to `BURST_SECS` of them. Traffic larger than what the bucket holds
+/// goes into debt, which later traffic waits to pay off.
+struct TokenBucket {
+    schedule: Option<BandwidthSchedule>,
+    /// Bytes that may be sent now; negative while in debt.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> TokenBucket {
+        TokenBucket {
+            schedule: None,
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn set_schedule(&mut self, schedule: Option<BandwidthSchedule>) {
+        // A new limit starts with a full bucket.
+        self.tokens = schedule
+            .as_ref()
+            .and_then(|s| s.rate_now())
+            .map_or(0.0, |rate| rate as f64 * BURST_SECS);
+        self.refilled = Instant::now();
+        self.schedule = schedule;
+    }
+
+    /// Take `bytes` tokens. Returns how long to wait before sending them, if at all.
+    fn take(&mut self, bytes: usize) -> Option<Duration> {
+        let rate = self.schedule.as_ref().and_then(|s| s.rate_now())? as f64;
+        let now = Instant::now();
+        let gained = now.duration_since(self.refilled).as_secs_f64() * rate;
+        self.tokens = (self.tokens + gained).min(rate * BURST_SECS) - bytes as f64;
+        self.refilled = now;
+        if self.tokens < 0.0 {
+            Some(Duration::from_secs_f64(-self.tokens / rate))
+        } else {
+            None
+        }
+    }
+}
+
+/// A repository backend that limits the bandwidth used to upload and download blobs.
+///
+/// Each direction has a token bucket, so short bursts go out at once while the rate holds on
+/// average over a few blobs. A store waits for its allowance before sending the blob; a read
+/// waits after receiving it, as its size is known only then. Deletes and listings are not
+/// limited.
+pub struct ThrottledBackend<B> {
+    inner: Arc<B>,
+    upload: Mutex<TokenBucket>,
+    download: Mutex<TokenBucket>,
+}
+
+impl<B: StoreBackend> ThrottledBackend<B> {
+    pub fn new(inner: Arc<B>) -> ThrottledBackend<B> {
+        ThrottledBackend {
+            inner: inner,
+            upload: Mutex::new(TokenBucket::new()),
+            download: Mutex::new(TokenBucket::new()),
+        }
+    }
+
+    /// Limit uploads from now on by `schedule`, or not at all.
+    pub fn set_upload_schedule(&self, schedule: Option<BandwidthSchedule>) {
+        self.upload.lock().unwrap().set_schedule(schedule);
+    }
+
+    /// Limit downloads from now on by `schedule`, or not at all.
+    pub fn set_download_schedule(&self, schedule: Option<BandwidthSchedule>) {
+        self.download.lock().unwrap().set_schedule(schedule);
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Wait for the allowance of `bucket` to send `bytes` bytes.
+    fn wait_for(bucket: &Mutex<TokenBucket>, bytes: usize) {
+        let wait = bucket.lock().unwrap().take(bytes);
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Wait for the allowance of `bucket` to have received `data`, if any.
+    fn received(
+        bucket: &Mutex<TokenBucket>,
+        data: Result<Option<Vec<u8>>, String>,
+    ) -> Result<Option<Vec<u8>>, String> {
+        if let Ok(Some(ref data)) = data {
+            Self::wait_for(bucket, data.len());
+        }
+        data
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for ThrottledBackend<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        Self::wait_for(&self.upload, data.len());
+        self.inner.store_with_class(name, data, class, done)
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        Self::wait_for(&self.upload, data.len());
+        self.inner.replace(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Self::received(&self.download, self.inner.retrieve(name))
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Self::received(&self.download, self.inner.retrieve_uncached(name))
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        Self::received(&self.download, self.inner.retrieve_range(name, offset, len))
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        Self::received(&self.download, self.inner.retrieve_range_uncached(name, offset, len))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.inner.delete(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        self.inner.request_restore(name)
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.checksum(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.inner.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+
+    #[test]
+    fn parse_schedule() {
+        let schedule = BandwidthSchedule::parse("08:00-18:00=256K, 22:30-06:00=4M,1M").unwrap();
+        assert_eq!(schedule.rate_at(7 * 60 + 59), Some(1024 * 1024));
+        assert_eq!(schedule.rate_at(8 * 60), Some(256 * 1024));
+        assert_eq!(schedule.rate_at(18 * 60), Some(1024 * 1024));
+        assert_eq!(schedule.rate_at(23 * 60), Some(4 * 1024 * 1024));
+        assert_eq!(schedule.rate_at(3 * 60), Some(4 * 1024 * 1024));
+        assert_eq!(
+            schedule.to_string(),
+            "08:00-18:00=262144,22:30-06:00=4194304,1048576"
+        );
+
+        let evenings = BandwidthSchedule::parse("18:00-23:00=100").unwrap();
+        assert_eq!(evenings.rate_at(12 * 60), None);
+        assert_eq!(BandwidthSchedule::parse("2G").unwrap().rate_at(0), Some(2 << 30));
+
+        for bad in &["", "0", "5X", "8:00=1M", "08:00-25:00=1M", "1M,2M", "08:00-09:00"] {
+            assert!(BandwidthSchedule::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn stores_wait_for_bandwidth() {
+        let backend = ThrottledBackend::new(Arc::new(MemoryBackend::new()));
+        let store = |name: &[u8]| {
+            backend
+                .store(name, CipherText::new(vec![0; 1000]), Box::new(|()| ()))
+                .unwrap()
+        };
+
+        let started = Instant::now();
+        store(b"a");
+        store(b"b");
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        backend.set_upload_schedule(Some(BandwidthSchedule::parse("10K").unwrap()));
+        let started = Instant::now();
+        for name in &[b"c", b"d", b"e", b"f"] {
+            store(*name);
+        }
+        // The first store goes out at once; each of the others waits for the one before it.
+        assert!(started.elapsed() >= Duration::from_millis(290));
+        assert_eq!(backend.list().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn reads_wait_for_bandwidth() {
+        let backend = ThrottledBackend::new(Arc::new(MemoryBackend::new()));
+        backend
+            .store(b"a", CipherText::new(vec![0; 1000]), Box::new(|()| ()))
+            .unwrap();
+        // Uploads and downloads are limited separately.
+        backend.set_upload_schedule(Some(BandwidthSchedule::parse("1").unwrap()));
+        backend.set_download_schedule(Some(BandwidthSchedule::parse("10K").unwrap()));
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(backend.retrieve(b"a").unwrap(), Some(vec![0; 1000]));
+        }
+        assert_eq!(backend.retrieve_range(b"a", 0, 1000).unwrap(), Some(vec![0; 1000]));
+        // A tenth of a second goes out at once, and the rest at the rate.
+        assert!(started.elapsed() >= Duration::from_millis(290));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Blobs that are not there use no bandwidth.
+        let started = Instant::now();
+        assert_eq!(backend.retrieve(b"missing").unwrap(), None);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+}
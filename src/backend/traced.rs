@@ -0,0 +1,398 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{StorageClass, StoreBackend};
+use chrono;
+use crypto::CipherText;
+use hex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use util::FnBox;
+
+/// Calls that take at least this long are logged as slow.
+pub const DEFAULT_SLOW_AFTER: Duration = Duration::from_secs(10);
+
+/// Name of the slow call log in the state directory.
+pub const SLOW_LOG_FILENAME: &str = "backend-slow.log";
+
+/// The slow call log is moved aside to `<log>.1` when it grows beyond this size.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+/// Number of slow calls to remember in memory.
+const MAX_SLOW_CALLS: usize = 100;
+
+/// The backend operations that are traced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BackendOp {
+    Store,
+    Retrieve,
+    Delete,
+    List,
+}
+
+impl fmt::Display for BackendOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BackendOp::Store => "store",
+            BackendOp::Retrieve => "retrieve",
+            BackendOp::Delete => "delete",
+            BackendOp::List => "list",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Latency of the calls of one operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallStats {
+    pub calls: u64,
+    pub failures: u64,
+    pub slow: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl CallStats {
+    pub fn average(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::default();
+        }
+        self.total / self.calls as u32
+    }
+}
+
+/// A call that took at least the slow threshold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowCall {
+    pub op: BackendOp,
+    /// Name of the blob; empty for `list`.
+    pub name: Vec<u8>,
+    pub elapsed: Duration,
+    pub failed: bool,
+}
+
+impl fmt::Display for SlowCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        if !self.name.is_empty() {
+            write!(f, " of {}", hex::encode(&self.name))?;
+        }
+        write!(f, " took {} ms", self.elapsed.as_millis())?;
+        if self.failed {
+            write!(f, " and failed")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Trace {
+    stats: BTreeMap<BackendOp, CallStats>,
+    slow_calls: Vec<SlowCall>,
+}
+
+/// Where a `TracedBackend` records its calls; shared with the callbacks of pending stores.
+struct Tracer {
+    slow_after: Duration,
+    log: Option<PathBuf>,
+    trace: Mutex<Trace>,
+}
+
+impl Tracer {
+    fn record(&self, op: BackendOp, name: &[u8], elapsed: Duration, ok: bool) {
+        let call = SlowCall {
+            op: op,
+            name: name.to_vec(),
+            elapsed: elapsed,
+            failed: !ok,
+        };
+        let slow = elapsed >= self.slow_after;
+        {
+            let mut trace = self.trace.lock().unwrap();
+            {
+                let stats = trace.stats.entry(op).or_default();
+                stats.calls += 1;
+                stats.total += elapsed;
+                stats.max = stats.max.max(elapsed);
+                stats.failures += (!ok) as u64;
+                stats.slow += slow as u64;
+            }
+            if !slow {
+                return;
+            }
+            if trace.slow_calls.len() == MAX_SLOW_CALLS {
+                trace.slow_calls.remove(0);
+            }
+            trace.slow_calls.push(call.clone());
+        }
+
+        warn!("Slow backend call: {}", call);
+        if let Err(e) = self.append_to_log(&call) {
+            warn!("Could not log slow backend call: {}", e);
+        }
+    }
+
+    fn append_to_log(&self, call: &SlowCall) -> Result<(), String> {
+        let path = match self.log {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if fs::metadata(path).map(|m| m.len() > MAX_LOG_BYTES).unwrap_or(false) {
+            let mut old = path.clone().into_os_string();
+            old.push(".1");
+            fs::rename(path, old).map_err(|e| e.to_string())?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{} {}", chrono::Utc::now().to_rfc3339(), call).map_err(|e| e.to_string())
+    }
+}
+
+/// A repository backend that measures how long each store, retrieve, delete and list takes.
+///
+/// Calls that take at least the slow threshold are logged with their blob name, to tell which
+/// blobs a misbehaving storage is stalling on. A store is timed until the backend reports it
+/// done, so waiting for a free upload slot counts too. Range reads count as retrieves.
+pub struct TracedBackend<B> {
+    inner: Arc<B>,
+    tracer: Arc<Tracer>,
+}
+
+impl<B: StoreBackend> TracedBackend<B> {
+    pub fn new(inner: Arc<B>) -> TracedBackend<B> {
+        TracedBackend {
+            inner: inner,
+            tracer: Arc::new(Tracer {
+                slow_after: DEFAULT_SLOW_AFTER,
+                log: None,
+                trace: Mutex::new(Trace::default()),
+            }),
+        }
+    }
+
+    /// Log calls that take at least `slow_after`.
+    pub fn with_slow_after(self, slow_after: Duration) -> TracedBackend<B> {
+        self.with_tracer(|t| t.slow_after = slow_after)
+    }
+
+    /// Append slow calls to the file at `path`, besides logging them.
+    pub fn with_log(self, path: PathBuf) -> TracedBackend<B> {
+        self.with_tracer(|t| t.log = Some(path))
+    }
+
+    fn with_tracer<F: FnOnce(&mut Tracer)>(mut self, f: F) -> TracedBackend<B> {
+        f(Arc::get_mut(&mut self.tracer).expect("configured while in use"));
+        self
+    }
+
+    pub fn slow_after(&self) -> Duration {
+        self.tracer.slow_after
+    }
+
+    /// Latency of the calls of each operation that has been called.
+    pub fn stats(&self) -> Vec<(BackendOp, CallStats)> {
+        let trace = self.tracer.trace.lock().unwrap();
+        trace.stats.iter().map(|(&op, &stats)| (op, stats)).collect()
+    }
+
+    /// The most recent slow calls, oldest first.
+    pub fn slow_calls(&self) -> Vec<SlowCall> {
+        self.tracer.trace.lock().unwrap().slow_calls.clone()
+    }
+
+    fn timed<T, F>(&self, op: BackendOp, name: &[u8], f: F) -> Result<T, String>
+    where
+        F: FnOnce(&B) -> Result<T, String>,
+    {
+        let started = Instant::now();
+        let res = f(&self.inner);
+        self.tracer.record(op, name, started.elapsed(), res.is_ok());
+        res
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for TracedBackend<B> {
+    fn store(&self, name: &[u8], data: CipherText, done: Box<FnBox<(), ()>>) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        let tracer = self.tracer.clone();
+        let blob_name = name.to_vec();
+        let res = self.inner.store_with_class(
+            name,
+            data,
+            class,
+            Box::new(move |()| {
+                tracer.record(BackendOp::Store, &blob_name, started.elapsed(), true);
+                done.call(());
+            }),
+        );
+        if res.is_err() {
+            // The callback is not called for stores that fail.
+            self.tracer
+                .record(BackendOp::Store, name, started.elapsed(), false);
+        }
+        res
+    }
+
+    fn replace(
+        &self,
+        name: &[u8],
+        data: CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.inner.replace(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.timed(BackendOp::Retrieve, name, |b| b.retrieve(name))
+    }
+
+    fn retrieve_uncached(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.timed(BackendOp::Retrieve, name, |b| b.retrieve_uncached(name))
+    }
+
+    fn retrieve_range(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.timed(BackendOp::Retrieve, name, |b| {
+            b.retrieve_range(name, offset, len)
+        })
+    }
+
+    fn retrieve_range_uncached(
+        &self,
+        name: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.timed(BackendOp::Retrieve, name, |b| {
+            b.retrieve_range_uncached(name, offset, len)
+        })
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.timed(BackendOp::Delete, name, |b| b.delete(name))
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        self.inner.request_restore(name)
+    }
+
+    fn checksum(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.inner.checksum(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.timed(BackendOp::List, &[], |b| b.list())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::MemoryBackend;
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    /// A backend whose retrieves take a while.
+    struct SlowReads {
+        blobs: MemoryBackend,
+        delay: Duration,
+    }
+
+    impl StoreBackend for SlowReads {
+        fn store(
+            &self,
+            name: &[u8],
+            data: CipherText,
+            done: Box<FnBox<(), ()>>,
+        ) -> Result<(), String> {
+            self.blobs.store(name, data, done)
+        }
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            thread::sleep(self.delay);
+            self.blobs.retrieve(name)
+        }
+        fn delete(&self, name: &[u8]) -> Result<(), String> {
+            self.blobs.delete(name)
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+            self.blobs.list()
+        }
+        fn flush(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_slow_calls() {
+        let log = env::temp_dir().join(format!("hat-slow-log-{}", process::id()));
+        let _ = fs::remove_file(&log);
+        let backend = TracedBackend::new(Arc::new(SlowReads {
+            blobs: MemoryBackend::new(),
+            delay: Duration::from_millis(20),
+        })).with_slow_after(Duration::from_millis(10))
+            .with_log(log.clone());
+
+        backend
+            .store(b"\x01\x02", CipherText::new(vec![7]), Box::new(|()| ()))
+            .unwrap();
+        assert_eq!(backend.retrieve(b"\x01\x02").unwrap(), Some(vec![7]));
+        assert!(backend.delete(b"\x03").is_ok());
+        assert_eq!(backend.list().unwrap().len(), 1);
+
+        let stats: BTreeMap<_, _> = backend.stats().into_iter().collect();
+        assert_eq!(stats.len(), 4);
+        assert!(stats.values().all(|s| s.calls == 1));
+        let retrieve = stats[&BackendOp::Retrieve];
+        assert_eq!(retrieve.slow, 1);
+        assert!(retrieve.max >= Duration::from_millis(20));
+        assert_eq!(stats[&BackendOp::Store].slow, 0);
+
+        let slow = backend.slow_calls();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].op, BackendOp::Retrieve);
+        assert_eq!(slow[0].name, b"\x01\x02".to_vec());
+
+        let logged = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        assert_eq!(logged.lines().count(), 1);
+        assert!(logged.contains("retrieve of 0102 took "));
+    }
+}
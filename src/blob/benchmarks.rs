@@ -40,6 +40,7 @@ fn dummy_hashref(keys: &crypto::keys::Keeper) -> HashRef {
             key: None,
         },
         info: None,
+        range: None,
     }
 }
 
@@ -19,7 +19,7 @@ use hash::tree::HashRef;
 use std::mem;
 use std::sync::Arc;
 
-use super::BlobError;
+use super::{BlobError, Compression};
 
 pub struct Blob {
     keys: Arc<crypto::keys::Keeper>,
@@ -28,6 +28,7 @@ pub struct Blob {
     footer: Vec<u8>,
     overhead: usize,
     max_len: usize,
+    compression: Compression,
 }
 
 impl Blob {
@@ -37,11 +38,26 @@ impl Blob {
             access_key: crypto::FixedKey::new_access_partial_key(),
             chunks: CipherText::empty(),
             footer: Vec::with_capacity(max_len / 2),
-            overhead: crypto::sealed::desc::overhead() + crypto::authed::hash::DIGESTBYTES as usize,
+            overhead: crypto::sealed::desc::overhead() + crypto::authed::hash::DIGESTBYTES,
             max_len: max_len,
+            compression: Compression::default(),
         }
     }
 
+    /// Compress the chunks appended from now on with `compression`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Seal this blob, and those after it, with `keys`.
+    pub fn set_keys(&mut self, keys: Arc<crypto::keys::Keeper>) {
+        self.keys = keys;
+    }
+
     pub fn upperbound_len(&self) -> usize {
         if self.chunks.len() == 0 {
             0
@@ -52,7 +68,12 @@ impl Blob {
 
     #[cfg_attr(feature = "flame_it", flame)]
     pub fn try_append(&mut self, chunk: &[u8], mut href: &mut HashRef) -> Result<(), ()> {
-        let ct = crypto::RefKey::seal(&mut href, &self.access_key, PlainTextRef::new(chunk));
+        let ct = crypto::RefKey::seal(
+            href,
+            &self.access_key,
+            PlainTextRef::new(chunk),
+            self.compression,
+        );
 
         href.persistent_ref.offset = self.chunks.len();
         let mut href_bytes = href.as_bytes();
@@ -113,8 +134,23 @@ impl Blob {
     }
 }
 
+/// Parse the chunk references stored in an unsealed blob footer.
+pub fn parse_footer(mut footer_pos: &[u8]) -> Result<Vec<HashRef>, BlobError> {
+    let mut hrefs = Vec::new();
+    while !footer_pos.is_empty() {
+        let len = footer_pos[0] as usize + 256 * (footer_pos[1] as usize);
+        assert!(footer_pos.len() > len);
+
+        hrefs.push(HashRef::from_bytes(&footer_pos[2..2 + len])?);
+        footer_pos = &footer_pos[len + 2..];
+    }
+
+    Ok(hrefs)
+}
+
 pub struct BlobReader {
     keys: Arc<crypto::keys::Keeper>,
+    key_version: u64,
     access_key: crypto::authed::desc::Key,
     footer_ct: Vec<u8>,
     blob: CipherText,
@@ -125,14 +161,17 @@ impl BlobReader {
         keys: Arc<crypto::keys::Keeper>,
         blob: CipherTextRef<'b>,
     ) -> Result<BlobReader, crypto::CryptoError> {
-        let rest = blob.strip_authentication(&keys)?;
-        let (access_key, footer_ct, rest) = crypto::FixedKey::new(&keys).unseal_access_ctx(rest)?;
+        let (rest, key_version) = blob.strip_authentication(&keys)?;
+        let (access_key, footer_ct, rest) = crypto::FixedKey::new(&keys)
+            .with_key_version(key_version)
+            .unseal_access_ctx(rest)?;
 
         // TODO(jos): Figure out how to make the borrow checker happy without this.
         let rest_of_blob = blob.slice(0, rest.len());
 
         Ok(BlobReader {
             keys: keys,
+            key_version: key_version,
             access_key: access_key,
             footer_ct: footer_ct.to_vec(),
             blob: rest_of_blob.to_owned(),
@@ -140,22 +179,31 @@ impl BlobReader {
     }
 
     pub fn refs(&mut self) -> Result<Vec<HashRef>, BlobError> {
-        let (_rest, footer_vec) = crypto::FixedKey::new(&self.keys).unseal(
+        let fixed = crypto::FixedKey::new(&self.keys).with_key_version(self.key_version);
+        let (_rest, footer_vec) = fixed.unseal(
             CipherTextRef::new(&self.footer_ct[..]),
             self.blob.collapse(),
         )?;
-        let mut footer_pos = footer_vec.as_bytes();
-
-        let mut hrefs = Vec::new();
-        while footer_pos.len() > 0 {
-            let len = footer_pos[0] as usize + 256 * (footer_pos[1] as usize);
-            assert!(footer_pos.len() > len);
+        parse_footer(footer_vec.as_bytes())
+    }
 
-            hrefs.push(HashRef::from_bytes(&mut &footer_pos[2..2 + len])?);
-            footer_pos = &footer_pos[len + 2..];
-        }
+    /// The version of the keys this blob was sealed with.
+    pub fn key_version(&self) -> u64 {
+        self.key_version
+    }
 
-        Ok(hrefs)
+    /// The same blob sealed with the current keys of `keys`. Only the footer and the
+    /// authentication depend on the key version, so the chunks stay where they are.
+    pub fn reseal(&mut self, keys: &crypto::keys::Keeper) -> Result<CipherText, BlobError> {
+        let fixed = crypto::FixedKey::new(&self.keys).with_key_version(self.key_version);
+        let (rest, footer) = fixed.unseal(
+            CipherTextRef::new(&self.footer_ct[..]),
+            self.blob.collapse(),
+        )?;
+        let mut out = rest.to_owned();
+        out.append(crypto::FixedKey::new(keys).seal(&self.access_key, footer.as_ref()));
+        out.append_authentication(keys);
+        Ok(out)
     }
 
     pub fn read_chunk(&mut self, href: &HashRef) -> Result<Vec<u8>, BlobError> {
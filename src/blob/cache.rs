@@ -0,0 +1,216 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local caches of decoded metadata chunks.
+//!
+//! Deleting snapshots walks their directory trees, and a maintenance window often deletes
+//! several snapshots that share most of their tree. A `ChunkStore` keeps the chunks read so
+//! far; `ChunkCache` keeps them across processes in the state directory, each in its own file
+//! named by its hash, and `MemoryChunkStore` only for the life of the process. Embedders can
+//! implement `ChunkStore` on a database of their own.
+
+use hash::Hash;
+use hex;
+use lru_cache::LruCache;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Name of the chunk cache directory in the state directory.
+pub const CHUNK_CACHE_DIRNAME: &str = "chunk-cache";
+
+/// Entries are reused for this long: about one maintenance window.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(12 * 3600);
+
+/// Where metadata chunks read from the backend are kept for reuse.
+///
+/// Failures are not reported: a store that can not keep or find a chunk only costs a later
+/// download. Readers check every chunk they get against its hash.
+pub trait ChunkStore: Send {
+    /// The chunk stored under `hash`, if any.
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>>;
+
+    /// Store `chunk` under its hash `hash`.
+    fn put(&self, hash: &Hash, chunk: &[u8]);
+
+    /// Forget the chunk stored under `hash`, e.g. as it did not match its hash.
+    fn remove(&self, hash: &Hash);
+}
+
+/// Chunks kept in a directory, by default `CHUNK_CACHE_DIRNAME` in the state directory.
+pub struct ChunkCache {
+    dir: PathBuf,
+    max_age: Duration,
+}
+
+impl ChunkCache {
+    /// A cache keeping its entries in `dir`, which is created when needed.
+    pub fn new(dir: PathBuf) -> ChunkCache {
+        ChunkCache {
+            dir: dir,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> ChunkCache {
+        self.max_age = max_age;
+        self
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        self.dir.join(hex::encode(&hash.bytes))
+    }
+
+    fn is_fresh(&self, path: &Path) -> bool {
+        let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+        match SystemTime::now().duration_since(modified) {
+            Ok(age) => age < self.max_age,
+            // Written in the future: the clock moved back, so do not trust it.
+            Err(_) => false,
+        }
+    }
+
+    fn write(&self, hash: &Hash, chunk: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Readers must never see a partial entry.
+        let tmp = self.dir.join(format!(".tmp-{}", process::id()));
+        fs::write(&tmp, chunk)?;
+        fs::rename(&tmp, self.path(hash))
+    }
+
+    /// Remove the entries that are too old to be used. Returns how many were removed.
+    pub fn expire(&self) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if !self.is_fresh(&path) {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl ChunkStore for ChunkCache {
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        let path = self.path(hash);
+        if !self.is_fresh(&path) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        fs::read(&path).ok()
+    }
+
+    fn put(&self, hash: &Hash, chunk: &[u8]) {
+        if let Err(e) = self.write(hash, chunk) {
+            warn!("Could not cache chunk in {}: {}", self.dir.display(), e);
+        }
+    }
+
+    fn remove(&self, hash: &Hash) {
+        let _ = fs::remove_file(self.path(hash));
+    }
+}
+
+/// Chunks kept in memory, for processes that should leave nothing behind, such as a restore
+/// in a throwaway CI job. The least recently used chunks make room for new ones.
+pub struct MemoryChunkStore {
+    chunks: Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryChunkStore {
+    /// A store of at most `capacity` chunks.
+    pub fn new(capacity: usize) -> MemoryChunkStore {
+        MemoryChunkStore {
+            chunks: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl ChunkStore for MemoryChunkStore {
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        self.chunks.lock().unwrap().get_mut(&hash.bytes).cloned()
+    }
+
+    fn put(&self, hash: &Hash, chunk: &[u8]) {
+        self.chunks
+            .lock()
+            .unwrap()
+            .insert(hash.bytes.clone(), chunk.to_vec());
+    }
+
+    fn remove(&self, hash: &Hash) {
+        self.chunks.lock().unwrap().remove(&hash.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn entries_expire() {
+        let dir = env::temp_dir().join(format!("hat-chunk-cache-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let hash = Hash {
+            bytes: vec![1, 2, 3],
+        };
+
+        let cache = ChunkCache::new(dir.clone());
+        assert_eq!(cache.get(&hash), None);
+        cache.put(&hash, b"tree");
+        assert_eq!(cache.get(&hash), Some(b"tree".to_vec()));
+        assert_eq!(cache.expire().unwrap(), 0);
+
+        let expired = ChunkCache::new(dir.clone()).with_max_age(Duration::from_secs(0));
+        assert_eq!(expired.get(&hash), None);
+        assert_eq!(cache.get(&hash), None);
+
+        cache.put(&hash, b"tree");
+        assert_eq!(expired.expire().unwrap(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_store_keeps_recent_chunks() {
+        let hash = |b| Hash { bytes: vec![b] };
+        let store = MemoryChunkStore::new(2);
+        store.put(&hash(1), b"one");
+        store.put(&hash(2), b"two");
+        assert_eq!(store.get(&hash(1)), Some(b"one".to_vec()));
+
+        // The chunk read least recently makes room.
+        store.put(&hash(3), b"three");
+        assert_eq!(store.get(&hash(2)), None);
+        assert_eq!(store.get(&hash(1)), Some(b"one".to_vec()));
+
+        store.remove(&hash(1));
+        assert_eq!(store.get(&hash(1)), None);
+        assert_eq!(store.get(&hash(3)), Some(b"three".to_vec()));
+    }
+}
@@ -22,6 +22,7 @@ use serde_cbor;
 pub enum Packing {
     GZip,
     Snappy,
+    Zstd,
 }
 
 #[derive(Debug, Clone)]
@@ -59,6 +60,7 @@ impl From<u64> for LeafType {
             1 => LeafType::FileChunk,
             2 => LeafType::TreeList,
             3 => LeafType::SnapshotList,
+            4 => LeafType::Manifest,
             _ => unreachable!("Corrupt LeafType tag: {}", n),
         }
     }
@@ -70,6 +72,7 @@ impl From<LeafType> for u64 {
             LeafType::FileChunk => 1,
             LeafType::TreeList => 2,
             LeafType::SnapshotList => 3,
+            LeafType::Manifest => 4,
         }
     }
 }
@@ -95,6 +98,7 @@ impl From<models::ChunkRef> for ChunkRef {
                 models::Packing::Raw => None,
                 models::Packing::GZip => Some(Packing::GZip),
                 models::Packing::Snappy => Some(Packing::Snappy),
+                models::Packing::Zstd => Some(Packing::Zstd),
             },
             key: match chunk_ref.key {
                 models::Key::None => None,
@@ -116,6 +120,7 @@ impl ChunkRef {
                 None => models::Packing::Raw,
                 Some(Packing::GZip) => models::Packing::GZip,
                 Some(Packing::Snappy) => models::Packing::Snappy,
+                Some(Packing::Zstd) => models::Packing::Zstd,
             },
             key: match self.key {
                 None => models::Key::None,
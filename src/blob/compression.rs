@@ -0,0 +1,108 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression of chunks before they are sealed into a blob.
+//!
+//! Each chunk records its own packing, so changing the compression of a repository only affects
+//! chunks stored from then on; chunks stored before remain readable as they are.
+
+use super::Packing;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use zstd;
+
+/// Holds the compression new chunks of a state directory are stored with.
+pub const COMPRESSION_FILENAME: &str = "compression";
+
+/// Zstandard level used when none is given; a good trade between speed and size.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Store chunks as they are.
+    None,
+    /// Compress chunks with Zstandard at this level.
+    Zstd(i32),
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::Zstd(DEFAULT_ZSTD_LEVEL)
+    }
+}
+
+impl Compression {
+    /// Parse `none`, `zstd` or `zstd:LEVEL`.
+    pub fn parse(s: &str) -> Result<Compression, String> {
+        let s = s.trim();
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("none"), None) => Ok(Compression::None),
+            (Some("zstd"), None) => Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)),
+            (Some("zstd"), Some(level)) => {
+                let level = level
+                    .parse()
+                    .map_err(|_| format!("invalid zstd level: {}", level))?;
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(format!("zstd level out of range: {}", level));
+                }
+                Ok(Compression::Zstd(level))
+            }
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+
+    /// The compression of state directory `dir`; without a compression file, the default.
+    pub fn load(dir: &Path) -> Result<Compression, String> {
+        match fs::read_to_string(dir.join(COMPRESSION_FILENAME)) {
+            Ok(text) => Compression::parse(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Compression::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Compress `chunk`, if that makes it smaller. Returns the packing used and the packed data.
+    pub fn pack(&self, chunk: &[u8]) -> Option<(Packing, Vec<u8>)> {
+        match *self {
+            Compression::None => None,
+            Compression::Zstd(level) => match zstd::bulk::compress(chunk, level) {
+                Ok(ref packed) if packed.len() >= chunk.len() => None,
+                Ok(packed) => Some((Packing::Zstd, packed)),
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+/// The original contents of `data`, a chunk stored with `packing`.
+pub fn unpack(packing: Option<&Packing>, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    match packing {
+        None => Ok(data),
+        Some(&Packing::Zstd) => {
+            zstd::stream::decode_all(&data[..]).map_err(|e| format!("zstd: {}", e))
+        }
+        Some(p) => Err(format!("unsupported packing: {:?}", p)),
+    }
+}
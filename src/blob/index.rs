@@ -19,6 +19,7 @@ use db;
 
 use errors::DieselError;
 
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 use tags;
@@ -98,7 +99,8 @@ impl InternalBlobIndex {
             name: name,
             id: wanted_id,
         };
-        self.index.lock().blob_in_air(&blob);
+        // We cannot tell which keys an existing blob was written with.
+        self.index.lock().blob_in_air(&blob, None, None);
         self.index.lock().blob_commit(&blob);
 
         blob
@@ -125,8 +127,13 @@ impl BlobIndex {
     /// Report that this blob is in the process of being committed to persistent storage. If a
     /// blob is in this state when the system starts up, it may or may not exist in the persistent
     /// storage, but **should not** be referenced elsewhere, and is therefore safe to delete.
-    pub fn in_air(&self, blob: &BlobDesc) {
-        self.0.index.lock().blob_in_air(blob)
+    /// `key_version` is that of the keys the blob was written with, and `checksum` that of the
+    /// stored bytes, see `crypto::keys::checksum`.
+    pub fn in_air(&self, blob: &BlobDesc, key_version: u64, checksum: &[u8]) {
+        self.0
+            .index
+            .lock()
+            .blob_in_air(blob, Some(key_version), Some(checksum))
     }
 
     /// Report that this blob has been fully committed to persistent storage. We can now use its
@@ -135,12 +142,59 @@ impl BlobIndex {
         self.0.index.lock().blob_commit(blob)
     }
 
+    /// Report that this committed blob was sealed again with the keys of version `key_version`,
+    /// and stored with checksum `checksum`.
+    pub fn resealed(&self, blob: &BlobDesc, key_version: u64, checksum: &[u8]) {
+        self.0
+            .index
+            .lock()
+            .blob_set_key_version(blob, key_version, checksum)
+    }
+
     /// Reinstall blob recovered by from external storage.
     /// Creates a new blob by a known external name.
     pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
         self.0.recover(name)
     }
 
+    /// Continue numbering new blobs after the highest known id, e.g. after recovering blobs.
+    pub fn refresh_next_id(&self) {
+        self.0.refresh_next_id()
+    }
+
+    /// Number of blobs stored or being stored.
+    pub fn count(&self) -> u64 {
+        self.0.index.lock().blob_count() as u64
+    }
+
+    /// Names of all blobs stored or being stored.
+    pub fn names(&self) -> Vec<Vec<u8>> {
+        self.0.index.lock().blob_names()
+    }
+
+    /// Number of blobs by the version of the keys they were written with, oldest first.
+    /// Blobs recovered from the backend, or written before versions were recorded, have no
+    /// known version and come first.
+    pub fn key_usage(&self) -> Vec<(Option<u64>, u64)> {
+        let mut usage = BTreeMap::new();
+        for version in self.0.index.lock().blob_key_versions() {
+            *usage.entry(version).or_insert(0) += 1;
+        }
+        usage.into_iter().collect()
+    }
+
+    /// Up to `limit` committed blobs in the order they should be re-encrypted: unknown and
+    /// oldest key versions first.
+    pub fn oldest_key_blobs(&self, limit: usize) -> Vec<BlobDesc> {
+        self.0.index.lock().blob_list_by_key_age(limit as i64)
+    }
+
+    /// Committed blobs with the checksum recorded when they were stored. Blobs recovered from
+    /// the backend, or stored before checksums were recorded, have none.
+    pub fn checksums(&self) -> Vec<(BlobDesc, Option<Vec<u8>>)> {
+        self.0.index.lock().blob_list_checksums()
+    }
+
     pub fn find(&self, name: &[u8]) -> Option<BlobDesc> {
         if let Some(id) = self.0.index.lock().blob_id_from_name(&name) {
             Some(BlobDesc {
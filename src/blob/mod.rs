@@ -14,24 +14,32 @@
 
 //! Combines data chunks into larger blobs to be stored externally.
 
-use backend::StoreBackend;
+use backend::{self, shared, StorageClass, StoreBackend};
 use crypto;
 use errors;
 use hash::tree::HashRef;
 use hash::Hash;
+use hex;
 use key;
 use lru_cache;
 use serde_cbor;
 use std::borrow::Cow;
+use std::fmt;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::Duration;
 use tags;
-use util::FnBox;
+use util::{Deadline, FnBox, ProgressEvent, ProgressFn};
 
 mod blob;
+mod cache;
 mod chunk;
+mod compression;
 mod index;
+mod prefetch;
+mod restore;
+mod verify;
 #[cfg(test)]
 pub mod tests;
 
@@ -39,8 +47,13 @@ pub mod tests;
 mod benchmarks;
 
 pub use self::blob::{Blob, BlobReader};
+pub use self::cache::{ChunkCache, ChunkStore, MemoryChunkStore, CHUNK_CACHE_DIRNAME};
 pub use self::chunk::{ChunkRef, Key, LeafType, NodeType, Packing};
+pub use self::compression::{unpack, Compression, COMPRESSION_FILENAME};
 pub use self::index::{BlobDesc, BlobIndex};
+pub use self::prefetch::{Prefetcher, DEFAULT_FETCH_JOBS};
+pub use self::restore::{RestoreQueue, DEFAULT_RESTORE_POLL, DEFAULT_RESTORE_WAIT};
+pub use self::verify::BlobVerifier;
 
 error_type! {
     #[derive(Debug)]
@@ -55,6 +68,10 @@ error_type! {
         },
         Serde(serde_cbor::error::Error) {
             cause;
+        },
+        RestorePending(Duration) {
+            disp (d, fmt) write!(fmt, "blob is being restored, read again in {}s", d.as_secs());
+            desc (_d) "blob is being restored from cold storage";
         }
     }
 }
@@ -68,13 +85,135 @@ pub struct StoreInner<B> {
     blob_desc: BlobDesc,
     blob_refs: Vec<(Box<FnBox<(), ()>>)>,
     blob: Blob,
+    // Storage class of the current blob; it is cold until it holds more than file contents.
+    blob_class: StorageClass,
+    max_blob_size: usize,
+    quota: Option<u64>,
+    /// Set when the quota refused to store the current blob.
+    quota_refused: bool,
     read_cache: lru_cache::LruCache<Vec<u8>, BlobReader>,
+    chunk_cache: Option<Box<ChunkStore>>,
+    metrics: RetrieveMetrics,
+    failures: Vec<RetrieveFailure>,
+    store_metrics: StoreMetrics,
+    restores: RestoreQueue,
+    prefetch: Option<Prefetcher>,
+    progress: Option<ProgressFn>,
+}
+
+/// Outcome of the checks done on data read back through a blob store, and so from its backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetrieveMetrics {
+    /// Chunks that decrypted and matched their hash.
+    pub chunks_verified: u64,
+    /// Blobs whose authentication tag or footer did not check out.
+    pub blob_failures: u64,
+    /// Chunks that did not decrypt or did not match their hash.
+    pub chunk_failures: u64,
+    /// Metadata chunks read from the chunk cache instead of the backend.
+    pub cache_hits: u64,
+}
+
+impl RetrieveMetrics {
+    pub fn failures(&self) -> u64 {
+        self.blob_failures + self.chunk_failures
+    }
+}
+
+/// A chunk that failed its checks when read back, and where it is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetrieveFailure {
+    pub hash: Hash,
+    pub blob_name: Vec<u8>,
+    pub offset: usize,
+    pub length: usize,
+    /// The whole blob failed its authentication tag, so none of its chunks can be read.
+    pub whole_blob: bool,
+    pub error: String,
+}
+
+impl fmt::Display for RetrieveFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.whole_blob {
+            write!(f, "blob {}: {}", hex::encode(&self.blob_name), self.error)
+        } else {
+            write!(
+                f,
+                "chunk at offset {} ({} bytes) of blob {}: {}",
+                self.offset,
+                self.length,
+                hex::encode(&self.blob_name),
+                self.error
+            )
+        }
+    }
+}
+
+/// File data seen by a blob store while committing: what it stored, and what was already
+/// stored, by another family or an earlier snapshot, and so not uploaded again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreMetrics {
+    /// File data chunks stored, and their bytes before encryption.
+    pub chunks_stored: u64,
+    pub bytes_stored: u64,
+    /// File data chunks found already stored, and their bytes.
+    pub chunks_reused: u64,
+    pub bytes_reused: u64,
+    /// The part of the reused chunks that another family stored first, and their bytes.
+    pub chunks_shared: u64,
+    pub bytes_shared: u64,
+}
+
+impl StoreMetrics {
+    /// What was counted after `before` was taken.
+    pub fn since(&self, before: &StoreMetrics) -> StoreMetrics {
+        StoreMetrics {
+            chunks_stored: self.chunks_stored - before.chunks_stored,
+            bytes_stored: self.bytes_stored - before.bytes_stored,
+            chunks_reused: self.chunks_reused - before.chunks_reused,
+            bytes_reused: self.bytes_reused - before.bytes_reused,
+            chunks_shared: self.chunks_shared - before.chunks_shared,
+            bytes_shared: self.bytes_shared - before.bytes_shared,
+        }
+    }
+
+    /// What was counted here and in `other`.
+    pub fn add(&self, other: &StoreMetrics) -> StoreMetrics {
+        StoreMetrics {
+            chunks_stored: self.chunks_stored + other.chunks_stored,
+            bytes_stored: self.bytes_stored + other.bytes_stored,
+            chunks_reused: self.chunks_reused + other.chunks_reused,
+            bytes_reused: self.bytes_reused + other.bytes_reused,
+            chunks_shared: self.chunks_shared + other.chunks_shared,
+            bytes_shared: self.bytes_shared + other.bytes_shared,
+        }
+    }
+}
+
+/// Stored blobs written with one version of the keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// `None` for blobs whose key version is not known.
+    pub key_version: Option<u64>,
+    pub blobs: u64,
+    pub bytes: u64,
+}
+
+/// The error reported when storing another blob would exceed the storage quota.
+pub fn quota_exceeded_message(usage: u64, quota: u64) -> String {
+    format!(
+        "Storage quota exceeded: {} of {} bytes in use; raise the quota or use --force",
+        usage, quota
+    )
 }
 
 impl<B> Drop for StoreInner<B> {
     fn drop(&mut self) {
-        // Sanity check that we flushed this blob store before dropping it.
-        assert_eq!(0, self.blob.upperbound_len());
+        // Sanity check that we flushed this blob store before dropping it. A blob the quota
+        // refused is left unstored; none of its chunks were committed.
+        if !self.quota_refused {
+            assert_eq!(0, self.blob.upperbound_len());
+        }
     }
 }
 
@@ -92,7 +231,18 @@ impl<B: StoreBackend> StoreInner<B> {
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
             blob: Blob::new(keys, max_blob_size),
+            blob_class: StorageClass::Cold,
+            max_blob_size: max_blob_size,
+            quota: None,
+            quota_refused: false,
             read_cache: lru_cache::LruCache::new(10),
+            chunk_cache: None,
+            metrics: RetrieveMetrics::default(),
+            failures: vec![],
+            store_metrics: StoreMetrics::default(),
+            restores: RestoreQueue::new(),
+            prefetch: None,
+            progress: None,
         };
         bs.reserve_new_blob();
         bs
@@ -102,9 +252,21 @@ impl<B: StoreBackend> StoreInner<B> {
         mem::replace(&mut self.blob_desc, self.blob_index.reserve())
     }
 
-    fn flush(&mut self) {
+    fn flush(&mut self) -> Result<(), BlobError> {
+        if self.blob.upperbound_len() == 0 {
+            return Ok(());
+        }
+        if let Some(quota) = self.quota {
+            let usage = self.usage();
+            if usage + self.max_blob_size as u64 > quota {
+                // Nothing is stored, and the blob and its references are kept as they are.
+                self.quota_refused = true;
+                return Err(quota_exceeded_message(usage, quota).into());
+            }
+        }
+        self.quota_refused = false;
         let ct = match self.blob.to_ciphertext() {
-            None => return,
+            None => return Ok(()),
             Some(ct) => ct,
         };
 
@@ -112,16 +274,49 @@ impl<B: StoreBackend> StoreInner<B> {
         let old_blob_desc = self.reserve_new_blob();
 
         let callbacks = mem::replace(&mut self.blob_refs, vec![]);
+        let class = mem::replace(&mut self.blob_class, StorageClass::Cold);
+        let len = ct.len() as u64;
+        let progress = self.progress.clone();
+        if let Some(ref progress) = progress {
+            progress(ProgressEvent::BlobFlushed(len));
+        }
         let done_callback = Box::new(move |()| {
             callbacks.into_iter().for_each(|c| c.call(()));
+            if let Some(progress) = progress {
+                progress(ProgressEvent::BytesUploaded(len));
+            }
         });
 
-        self.blob_index.in_air(&old_blob_desc);
+        let checksum = crypto::keys::checksum(&ct.slices());
+        self.blob_index
+            .in_air(&old_blob_desc, self.keys.key_version(), &checksum);
         self.backend
-            .store(&old_blob_desc.name[..], ct, done_callback)
+            .store_with_class(&old_blob_desc.name[..], ct, class, done_callback)
             .expect("Store operation failed");
 
         self.blob_index.commit_done(&old_blob_desc);
+        Ok(())
+    }
+
+    /// Store a file data chunk compressed with `compression`, instead of the blob's.
+    fn store_file_chunk(
+        &mut self,
+        chunk: &[u8],
+        hash: Hash,
+        info: Option<&key::Info>,
+        compression: Compression,
+        callback: Box<FnBox<(), ()>>,
+    ) -> Result<HashRef, BlobError> {
+        let default = self.blob.compression();
+        self.blob.set_compression(compression);
+        let res = self.store(chunk, hash, NodeType::Leaf, LeafType::FileChunk, info, callback);
+        self.blob.set_compression(default);
+        res
+    }
+
+    /// Bytes used by stored blobs. Blobs are padded to the maximum size, so this is exact.
+    fn usage(&self) -> u64 {
+        self.blob_index.count() * self.max_blob_size as u64
     }
 
     fn store(
@@ -132,7 +327,7 @@ impl<B: StoreBackend> StoreInner<B> {
         leaf: LeafType,
         info: Option<&key::Info>,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
         let mut href = HashRef {
             hash: hash,
             node: node,
@@ -147,8 +342,14 @@ impl<B: StoreBackend> StoreInner<B> {
                 length: 0,
                 key: None,
             },
+            range: None,
         };
 
+        if node == NodeType::Leaf && leaf == LeafType::FileChunk {
+            self.store_metrics.chunks_stored += 1;
+            self.store_metrics.bytes_stored += chunk.len() as u64;
+        }
+
         if chunk.is_empty() {
             // We are not going to store an empty chunk, so commit it ASAP.
             thread::spawn(move || callback.call(()));
@@ -156,12 +357,16 @@ impl<B: StoreBackend> StoreInner<B> {
             href.persistent_ref.blob_id = Some(self.blob_desc.id);
             href.persistent_ref.blob_name = self.blob_desc.name.clone();
             if let Err(()) = self.blob.try_append(chunk, &mut href) {
-                self.flush();
+                self.flush()?;
                 href.persistent_ref.blob_id = Some(self.blob_desc.id);
                 href.persistent_ref.blob_name = self.blob_desc.name.clone();
 
                 self.blob.try_append(chunk, &mut href).unwrap();
             }
+            if leaf != LeafType::FileChunk {
+                // Metadata is read by most commands, so keep it out of the cold tier.
+                self.blob_class = StorageClass::Standard;
+            }
 
             // Queue the callback; we will trigger it when the blob has been pushed.
             self.blob_refs.push(callback);
@@ -170,39 +375,133 @@ impl<B: StoreBackend> StoreInner<B> {
         // Info is internal to the blob only.
         href.info = None;
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        href
+        Ok(href)
     }
 
     fn retrieve(&mut self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
         if href.persistent_ref.offset == 0 && href.persistent_ref.length == 0 {
-            return Ok(Some(Vec::new()));
+            return self.verify_chunk(href, Vec::new()).map(Some);
+        }
+
+        // File data is read once by a checkout; only the trees above it are read again.
+        let cacheable = !(href.node == NodeType::Leaf && href.leaf == LeafType::FileChunk);
+        if cacheable {
+            if let Some(chunk) = self.chunk_cache.as_ref().and_then(|c| c.get(&href.hash)) {
+                if Hash::new(&self.keys, href.node, href.leaf, &chunk[..]) == href.hash {
+                    self.metrics.cache_hits += 1;
+                    return Ok(Some(chunk));
+                }
+                self.chunk_cache.as_ref().unwrap().remove(&href.hash);
+            }
         }
 
         let name = &href.persistent_ref.blob_name[..];
-        if self.read_cache.get_mut(name).is_some() {
-            let reader = self.read_cache.get_mut(name).expect("is_some");
-            Ok(Some(reader.read_chunk(href)?))
-        } else {
-            match self.backend.retrieve(name) {
-                Ok(Some(blob)) => {
-                    let text = crypto::CipherTextRef::new(&blob[..]);
-                    let mut reader = BlobReader::new(self.keys.clone(), text)?;
-                    let chunk = reader.read_chunk(href)?;
-                    self.read_cache.insert(name.to_vec(), reader);
-                    Ok(Some(chunk))
+        if self.read_cache.get_mut(name).is_none() {
+            let blob = match self.fetch(name)? {
+                Some(blob) => blob,
+                None => return Ok(None),
+            };
+            // Creating the reader checks the authentication tag of the whole blob.
+            match BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&blob[..])) {
+                Ok(reader) => self.read_cache.insert(name.to_vec(), reader),
+                Err(e) => {
+                    self.metrics.blob_failures += 1;
+                    return Err(self.failure(href, true, e.into()));
                 }
-                Ok(None) => Ok(None),
-                Err(e) => Err(e.into()),
+            };
+        }
+
+        let res = self.read_cache
+            .get_mut(name)
+            .expect("reader is cached")
+            .read_chunk(href);
+        match res {
+            Ok(chunk) => {
+                let chunk = self.verify_chunk(href, chunk)?;
+                match self.chunk_cache {
+                    Some(ref cache) if cacheable => cache.put(&href.hash, &chunk),
+                    _ => (),
+                }
+                Ok(Some(chunk))
+            }
+            Err(e) => {
+                self.metrics.chunk_failures += 1;
+                Err(self.failure(href, false, e))
             }
         }
     }
 
+    /// Read blob `name` from the backend. Fails with `BlobError::RestorePending` if it is being
+    /// restored from a cold storage tier; `BlobStore` waits for it without holding the lock.
+    fn fetch(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, BlobError> {
+        // A failed fetch ahead is retried below, where it waits for cold storage if need be.
+        if let Some(Ok(blob)) = self.prefetch.as_ref().and_then(|p| p.take(name)) {
+            return Ok(blob);
+        }
+        match self.backend.retrieve(name) {
+            Err(ref e) if backend::is_restore_pending(e) => {
+                self.restores.add(name);
+                Err(BlobError::RestorePending(self.restores.next_poll(name)?))
+            }
+            res => {
+                self.restores.remove(name);
+                Ok(res?)
+            }
+        }
+    }
+
+    /// Ask the backend to restore the blobs in `names` that are kept in a cold storage tier, so
+    /// they are restored at the same time rather than one by one as they are read. Returns the
+    /// number of blobs being restored.
+    fn queue_restores(&mut self, names: &[Vec<u8>]) -> Result<usize, String> {
+        for name in names {
+            if !self.restores.contains(name) && !self.backend.request_restore(name)? {
+                self.restores.add(name);
+            }
+        }
+        Ok(self.restores.len())
+    }
+
+    /// Record that reading the chunk at `href` failed with `error`, and return the error with
+    /// where the chunk is stored.
+    fn failure(&mut self, href: &HashRef, whole_blob: bool, error: BlobError) -> BlobError {
+        let failure = RetrieveFailure {
+            hash: href.hash.clone(),
+            blob_name: href.persistent_ref.blob_name.clone(),
+            offset: href.persistent_ref.offset,
+            length: href.persistent_ref.length,
+            whole_blob: whole_blob,
+            error: error.to_string(),
+        };
+        let error = failure.to_string().into();
+        self.failures.push(failure);
+        error
+    }
+
+    /// Check that a decrypted chunk has the hash it is referenced by.
+    fn verify_chunk(&mut self, href: &HashRef, chunk: Vec<u8>) -> Result<Vec<u8>, BlobError> {
+        let actual = Hash::new(&self.keys, href.node, href.leaf, &chunk[..]);
+        if actual == href.hash {
+            self.metrics.chunks_verified += 1;
+            Ok(chunk)
+        } else {
+            self.metrics.chunk_failures += 1;
+            Err(self.failure(href, false, "chunk does not match its hash".into()))
+        }
+    }
+
     fn retrieve_refs(&mut self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
-        match self.backend.retrieve(&blob.name[..])? {
+        match self.fetch(&blob.name[..])? {
             None => Ok(None),
             Some(ct) => {
-                let hrefs = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?
-                    .refs()?;
+                let reader = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]));
+                let hrefs = match reader.map_err(BlobError::from).and_then(|mut r| r.refs()) {
+                    Ok(hrefs) => hrefs,
+                    Err(e) => {
+                        self.metrics.blob_failures += 1;
+                        return Err(e);
+                    }
+                };
                 if hrefs.len() == 0 {
                     Ok(None)
                 } else {
@@ -213,10 +512,49 @@ impl<B: StoreBackend> StoreInner<B> {
         }
     }
 
+    fn reseal(&mut self, blob: BlobDesc) -> Result<bool, BlobError> {
+        let ct = match self.fetch(&blob.name[..])? {
+            None => return Err(format!("blob {} is missing", hex::encode(&blob.name)).into()),
+            Some(ct) => ct,
+        };
+        let mut reader = BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?;
+        let version = self.keys.key_version();
+        if reader.key_version() == version {
+            // Record the version of a blob recovered without one, so it is not picked again.
+            let checksum = crypto::keys::checksum(&[&ct[..]]);
+            self.blob_index.resealed(&blob, version, &checksum);
+            return Ok(false);
+        }
+        let resealed = reader.reseal(&self.keys)?;
+        if resealed.len() != ct.len() {
+            return Err(format!(
+                "blob {} changed size from {} to {} bytes when sealed again",
+                hex::encode(&blob.name),
+                ct.len(),
+                resealed.len()
+            ).into());
+        }
+
+        // The blob keeps its name, so references to its chunks stay valid. Readers find the
+        // version from the authentication, so either copy can be read while it is replaced.
+        let checksum = crypto::keys::checksum(&resealed.slices());
+        let name = blob.name.clone();
+        let index = self.blob_index.clone();
+        let done = Box::new(move |()| index.resealed(&blob, version, &checksum));
+        self.backend.replace(&name[..], resealed, done)?;
+        Ok(true)
+    }
+
     fn recover(&mut self) -> Result<(), String> {
         self.backend.list()?.into_iter()
             .filter(|b| b.len() > 4)  // FIXME(jos): Remove when "root" is gone.
+            .filter(|b| !shared::is_control_name(b))
             .map(|b| self.blob_index.recover(b.into_vec())).last();
+        // New blobs must not reuse the name of a recovered one.
+        self.blob_index.refresh_next_id();
+        if self.blob.upperbound_len() == 0 {
+            self.reserve_new_blob();
+        }
         Ok(())
     }
 
@@ -234,13 +572,28 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.tag_all(tag);
     }
 
-    fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
+    /// Returns whether all blobs were tried before `deadline`, and how many of them the
+    /// backend refused to delete; those are left tagged `tag`.
+    fn delete_by_tag(&mut self, tag: tags::Tag, deadline: Deadline) -> Result<(bool, u64), String> {
         let blobs = self.blob_index.list_by_tag(tag);
+        let mut completed = true;
+        let mut refused = 0;
         for b in &blobs {
-            self.backend.delete(&b.name)?;
+            if deadline.is_past() {
+                completed = false;
+                break;
+            }
+            match self.backend.delete(&b.name) {
+                Ok(()) => self.blob_index.tag(b, tags::Tag::DeleteComplete),
+                Err(ref e) if backend::is_delete_refused(e) => refused += 1,
+                Err(e) => {
+                    self.blob_index.delete_by_tag(tags::Tag::DeleteComplete);
+                    return Err(e);
+                }
+            }
         }
-        self.blob_index.delete_by_tag(tag);
-        Ok(())
+        self.blob_index.delete_by_tag(tags::Tag::DeleteComplete);
+        Ok((completed, refused))
     }
 }
 
@@ -263,9 +616,25 @@ impl<B: StoreBackend> BlobStore<B> {
         self.0.lock().expect("Blob store was poisoned")
     }
 
+    /// Run `f` on the store until it no longer waits for a blob being restored from cold
+    /// storage, sleeping between tries without holding the lock.
+    fn until_restored<T, F>(&self, mut f: F) -> Result<T, BlobError>
+    where
+        F: FnMut(&mut StoreInner<B>) -> Result<T, BlobError>,
+    {
+        loop {
+            let res = f(&mut self.lock());
+            match res {
+                Err(BlobError::RestorePending(delay)) => thread::sleep(delay),
+                res => return res,
+            }
+        }
+    }
+
     /// Store a new data chunk into the current blob. The callback is triggered after the blob
     /// containing the chunk has been committed to persistent storage (it is then safe to use the
-    /// `ChunkRef` as persistent reference).
+    /// `ChunkRef` as persistent reference). Fails if the blob it fills up can not be stored
+    /// within the quota.
     pub fn store(
         &self,
         chunk: &[u8],
@@ -274,19 +643,67 @@ impl<B: StoreBackend> BlobStore<B> {
         leaf: LeafType,
         info: Option<&key::Info>,
         callback: Box<FnBox<(), ()>>,
-    ) -> HashRef {
+    ) -> Result<HashRef, BlobError> {
         let mut guard = self.lock();
         guard.store(chunk, hash, node, leaf, info, callback)
     }
 
-    /// Retrieve the data chunk identified by `ChunkRef`.
+    /// Store a file data chunk as `store` does, but compressed with `compression` instead of the
+    /// store's compression.
+    pub fn store_file_chunk(
+        &self,
+        chunk: &[u8],
+        hash: Hash,
+        info: Option<&key::Info>,
+        compression: Compression,
+        callback: Box<FnBox<(), ()>>,
+    ) -> Result<HashRef, BlobError> {
+        self.lock()
+            .store_file_chunk(chunk, hash, info, compression, callback)
+    }
+
+    /// Retrieve the data chunk identified by `ChunkRef`, after checking the blob it is in and its
+    /// hash.
     pub fn retrieve(&self, href: &HashRef) -> Result<Option<Vec<u8>>, BlobError> {
-        self.lock().retrieve(href)
+        self.until_restored(|inner| inner.retrieve(href))
+    }
+
+    /// Verification counts for everything retrieved through this store so far.
+    pub fn retrieve_metrics(&self) -> RetrieveMetrics {
+        self.lock().metrics
+    }
+
+    /// The chunks that failed verification when retrieved through this store so far.
+    pub fn retrieve_failures(&self) -> Vec<RetrieveFailure> {
+        self.lock().failures.clone()
+    }
+
+    /// Count a file data chunk of `len` bytes that was found already stored, and whether
+    /// another family stored it first.
+    pub fn count_reused(&self, len: usize, shared: bool) {
+        let mut guard = self.lock();
+        guard.store_metrics.chunks_reused += 1;
+        guard.store_metrics.bytes_reused += len as u64;
+        if shared {
+            guard.store_metrics.chunks_shared += 1;
+            guard.store_metrics.bytes_shared += len as u64;
+        }
+    }
+
+    /// File data stored, and found already stored, through this store so far.
+    pub fn store_metrics(&self) -> StoreMetrics {
+        self.lock().store_metrics
     }
 
     /// Fetch a blob and recover the HashRefs for its contents.
     pub fn retrieve_refs(&self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
-        self.lock().retrieve_refs(blob)
+        self.until_restored(|inner| inner.retrieve_refs(blob.clone()))
+    }
+
+    /// Store `blob` again, sealed with the current keys, if it was sealed with older ones.
+    /// Returns whether it was.
+    pub fn reseal(&self, blob: BlobDesc) -> Result<bool, BlobError> {
+        self.until_restored(|inner| inner.reseal(blob.clone()))
     }
 
     /// Reinstall a blob recovered from external storage.
@@ -298,12 +715,26 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().tag(chunk, tag)
     }
 
+    pub fn tag_by_name(&self, name: Vec<u8>, tag: tags::Tag) {
+        self.lock().blob_index.tag(&BlobDesc { id: 0, name: name }, tag)
+    }
+
     pub fn tag_all(&self, tag: tags::Tag) {
         self.lock().tag_all(tag)
     }
 
     pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), String> {
-        self.lock().delete_by_tag(tag)
+        self.lock().delete_by_tag(tag, Deadline::none()).map(|_| ())
+    }
+
+    /// Like `delete_by_tag`, but stops when `deadline` passes. Returns false if some blobs
+    /// were not tried, and the number of blobs the backend refused to delete.
+    pub fn delete_by_tag_until(
+        &self,
+        tag: tags::Tag,
+        deadline: Deadline,
+    ) -> Result<(bool, u64), String> {
+        self.lock().delete_by_tag(tag, deadline)
     }
 
     pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
@@ -321,11 +752,101 @@ impl<B: StoreBackend> BlobStore<B> {
         }
     }
 
-    /// Flush the current blob, independent of its size.
+    /// Refuse to store blobs that would take the storage usage above `quota` bytes.
+    pub fn set_quota(&self, quota: Option<u64>) {
+        self.lock().quota = quota;
+    }
+
+    /// Report the blobs flushed and uploaded from now on to `progress`.
+    pub fn set_progress(&self, progress: Option<ProgressFn>) {
+        self.lock().progress = progress;
+    }
+
+    /// Compress the chunks stored from now on with `compression`.
+    pub fn set_compression(&self, compression: Compression) {
+        self.lock().blob.set_compression(compression);
+    }
+
+    /// Seal the blob being filled, and those after it, with `keys`, and read blobs with them.
+    pub fn set_keys(&self, keys: Arc<crypto::keys::Keeper>) {
+        let mut inner = self.lock();
+        inner.blob.set_keys(keys.clone());
+        inner.keys = keys;
+    }
+
+    /// Keep metadata chunks read from the backend in `cache`, and read them from there while
+    /// they last.
+    pub fn set_chunk_cache(&self, cache: Option<Box<ChunkStore>>) {
+        self.lock().chunk_cache = cache;
+    }
+
+    /// Wait at most `max_wait` for a blob to be restored from a cold storage tier, reading it
+    /// again after `poll` at first, and less often the longer it takes.
+    pub fn set_restore_wait(&self, poll: Duration, max_wait: Duration) {
+        self.lock().restores.set_wait(poll, max_wait);
+    }
+
+    /// Ask the backend to restore the blobs in `names` from a cold storage tier if they are kept
+    /// there. Returns the number of blobs that are being restored.
+    pub fn queue_restores(&self, names: &[Vec<u8>]) -> Result<usize, String> {
+        self.lock().queue_restores(names)
+    }
+
+    /// Fetch the blobs `names` ahead of their reads, `jobs` at a time, in the order given; see
+    /// `Prefetcher`. Replaces any earlier prefetch; no names, or no jobs, stops it.
+    pub fn prefetch(&self, names: Vec<Vec<u8>>, jobs: usize) {
+        let mut guard = self.lock();
+        // Stop the earlier workers before starting new ones.
+        guard.prefetch = None;
+        if !names.is_empty() && jobs > 0 {
+            guard.prefetch = Some(Prefetcher::new(guard.backend.clone(), names, jobs));
+        }
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.lock().quota
+    }
+
+    /// Number of blobs stored, according to the blob index.
+    pub fn blob_count(&self) -> u64 {
+        self.lock().blob_index.count()
+    }
+
+    /// Bytes of storage used by blobs, according to the blob index.
+    pub fn usage(&self) -> u64 {
+        self.lock().usage()
+    }
+
+    /// Storage used by blobs, by the version of the keys they were written with, oldest first.
+    pub fn key_usage(&self) -> Vec<KeyUsage> {
+        let inner = self.lock();
+        inner
+            .blob_index
+            .key_usage()
+            .into_iter()
+            .map(|(version, blobs)| KeyUsage {
+                key_version: version,
+                blobs: blobs,
+                bytes: blobs * inner.max_blob_size as u64,
+            })
+            .collect()
+    }
+
+    /// Flush the current blob, independent of its size. Fails, storing nothing, if the blob
+    /// would take the storage usage above the quota.
     #[cfg_attr(feature = "flame_it", flame)]
-    pub fn flush(&self) {
+    pub fn flush(&self) -> Result<(), BlobError> {
         let mut guard = self.lock();
-        guard.flush();
+        let res = guard.flush();
         guard.blob_index.flush();
+        res
+    }
+
+    /// Forget the chunks of the current blob without storing them, as a crash would.
+    #[cfg(test)]
+    pub fn crash(&self) {
+        let mut guard = self.lock();
+        guard.blob = Blob::new(guard.keys.clone(), guard.max_blob_size);
+        guard.blob_refs.clear();
     }
 }
@@ -0,0 +1,151 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blobs fetched ahead of a restore, several at a time.
+//!
+//! A restore reads its blobs one after the other, so with a remote backend it mostly waits for
+//! the network. Given the blobs in the order the restore reads them, worker threads fetch the
+//! next few while the restore writes out the current one; whatever order they complete in, the
+//! blobs are handed out in the order they are read. Only a window of blobs ahead of the restore
+//! is fetched, so memory use stays bounded.
+
+use backend::StoreBackend;
+use hex;
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Number of blobs fetched at the same time during a restore, by default.
+pub const DEFAULT_FETCH_JOBS: usize = 4;
+
+type Fetched = Result<Option<Vec<u8>>, String>;
+
+struct State {
+    names: Vec<Vec<u8>>,
+    positions: HashMap<Vec<u8>, usize>,
+    /// Position of the next blob to hand to a worker.
+    next_fetch: usize,
+    /// Position of the next blob the restore is expected to read.
+    next_read: usize,
+    /// Blobs fetched at or after `next_read`, by position.
+    fetched: BTreeMap<usize, Fetched>,
+    stopped: bool,
+}
+
+pub struct Prefetcher {
+    state: Arc<(Mutex<State>, Condvar)>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    /// Fetch the blobs `names`, listed in the order they will be read, from `backend` with `jobs`
+    /// workers, keeping at most `2 * jobs` blobs ahead of the one being read.
+    pub fn new<B: StoreBackend>(backend: Arc<B>, names: Vec<Vec<u8>>, jobs: usize) -> Prefetcher {
+        let mut positions = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            positions.entry(name.clone()).or_insert(i);
+        }
+        let state = Arc::new((
+            Mutex::new(State {
+                names: names,
+                positions: positions,
+                next_fetch: 0,
+                next_read: 0,
+                fetched: BTreeMap::new(),
+                stopped: false,
+            }),
+            Condvar::new(),
+        ));
+        let window = 2 * jobs;
+        let workers = (0..jobs)
+            .map(|_| {
+                let state = state.clone();
+                let backend = backend.clone();
+                thread::spawn(move || fetch_ahead(&*backend, &state, window))
+            })
+            .collect();
+        Prefetcher {
+            state: state,
+            workers: workers,
+        }
+    }
+
+    /// The blob `name`, once fetched. Blobs listed before it are taken to be skipped. Returns
+    /// `None` if the blob is not listed, or was listed before a blob taken earlier.
+    pub fn take(&self, name: &[u8]) -> Option<Fetched> {
+        let (ref lock, ref changed) = *self.state;
+        let mut state = lock.lock().unwrap();
+        let i = match state.positions.get(name) {
+            Some(&i) if i >= state.next_read => i,
+            _ => return None,
+        };
+        if i > state.next_read {
+            state.fetched = state.fetched.split_off(&i);
+            state.next_read = i;
+            state.next_fetch = state.next_fetch.max(i);
+            changed.notify_all();
+        }
+        loop {
+            if let Some(blob) = state.fetched.remove(&i) {
+                state.next_read = i + 1;
+                changed.notify_all();
+                return Some(blob);
+            }
+            state = changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        {
+            let (ref lock, ref changed) = *self.state;
+            lock.lock().unwrap().stopped = true;
+            changed.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().expect("prefetch worker panicked");
+        }
+    }
+}
+
+/// Fetch the blobs of `state` in order, staying within `window` blobs of the one being read.
+fn fetch_ahead<B: StoreBackend>(backend: &B, state: &(Mutex<State>, Condvar), window: usize) {
+    let (ref lock, ref changed) = *state;
+    let mut guard = lock.lock().unwrap();
+    loop {
+        if guard.stopped || guard.next_fetch >= guard.names.len() {
+            return;
+        }
+        if guard.next_fetch >= guard.next_read + window {
+            guard = changed.wait(guard).unwrap();
+            continue;
+        }
+        let i = guard.next_fetch;
+        guard.next_fetch += 1;
+        let name = guard.names[i].clone();
+        drop(guard);
+
+        // A panic is handed out as a failed fetch, so the restore does not wait for it forever.
+        let blob = panic::catch_unwind(AssertUnwindSafe(|| backend.retrieve(&name)))
+            .unwrap_or_else(|_| Err(format!("fetching blob {} panicked", hex::encode(&name))));
+
+        guard = lock.lock().unwrap();
+        if i >= guard.next_read {
+            guard.fetched.insert(i, blob);
+            changed.notify_all();
+        }
+    }
+}
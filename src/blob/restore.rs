@@ -0,0 +1,117 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blobs that are being restored from a cold storage tier, and how long to wait for them.
+//!
+//! Restoring from a cold tier can take hours. Restores are requested for all blobs a checkout
+//! needs before it starts, so the storage works on them at the same time, and a read of a blob
+//! that is not restored yet polls the storage, less often the longer it waits.
+
+use hex;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a blob to be restored before giving up.
+pub const DEFAULT_RESTORE_WAIT: Duration = Duration::from_secs(12 * 3600);
+
+/// How long to wait before reading a blob that is being restored again, at first.
+pub const DEFAULT_RESTORE_POLL: Duration = Duration::from_secs(60);
+
+/// The wait between reads of a blob that is being restored does not grow beyond this.
+const MAX_RESTORE_POLL: Duration = Duration::from_secs(15 * 60);
+
+struct RestoreJob {
+    queued: Instant,
+    polls: u32,
+}
+
+pub struct RestoreQueue {
+    jobs: BTreeMap<Vec<u8>, RestoreJob>,
+    poll: Duration,
+    max_wait: Duration,
+}
+
+impl RestoreQueue {
+    pub fn new() -> RestoreQueue {
+        RestoreQueue {
+            jobs: BTreeMap::new(),
+            poll: DEFAULT_RESTORE_POLL,
+            max_wait: DEFAULT_RESTORE_WAIT,
+        }
+    }
+
+    /// Poll every `poll` at first, doubling up to a limit, and give up after `max_wait`.
+    pub fn set_wait(&mut self, poll: Duration, max_wait: Duration) {
+        self.poll = poll;
+        self.max_wait = max_wait;
+    }
+
+    /// Record that the storage is restoring blob `name`, unless it is recorded already.
+    pub fn add(&mut self, name: &[u8]) {
+        self.jobs.entry(name.to_vec()).or_insert_with(|| RestoreJob {
+            queued: Instant::now(),
+            polls: 0,
+        });
+    }
+
+    /// Record that blob `name` can be read.
+    pub fn remove(&mut self, name: &[u8]) {
+        self.jobs.remove(name);
+    }
+
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.jobs.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// How long to wait before blob `name` should be read again, or fail if it has been waited
+    /// on for too long. The blob must have been added.
+    pub fn next_poll(&mut self, name: &[u8]) -> Result<Duration, String> {
+        let (poll, max_wait, waiting) = (self.poll, self.max_wait, self.jobs.len());
+        let job = self.jobs.get_mut(name).expect("blob is queued for restore");
+        let waited = job.queued.elapsed();
+        if waited >= max_wait {
+            return Err(format!(
+                "Blob {} is still being restored from cold storage after {} seconds; {} blobs \
+                 are waiting for restore, run again later",
+                hex::encode(name),
+                waited.as_secs(),
+                waiting
+            ));
+        }
+        if job.polls == 0 {
+            eprintln!(
+                "Waiting for blob {} to be restored from cold storage ({} blobs waiting)",
+                hex::encode(name),
+                waiting
+            );
+        }
+        let delay = poll
+            .checked_mul(1 << job.polls.min(16))
+            .unwrap_or(MAX_RESTORE_POLL)
+            .min(MAX_RESTORE_POLL)
+            .min(max_wait - waited);
+        job.polls += 1;
+        Ok(delay)
+    }
+}
+
+impl Default for RestoreQueue {
+    fn default() -> RestoreQueue {
+        RestoreQueue::new()
+    }
+}
@@ -12,15 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
-use backend::{MemoryBackend, StoreBackend};
-use blob::{Blob, BlobError, BlobIndex, BlobReader, BlobStore, ChunkRef, LeafType, NodeType};
+use backend::{MemoryBackend, StorageClass, StoreBackend, RESTORE_PENDING};
+use blob::{Blob, BlobError, BlobIndex, BlobReader, BlobStore, BlobVerifier, ChunkRef, Compression,
+           KeyUsage, LeafType, NodeType, Packing, Prefetcher};
 use crypto;
 use db;
 use hash;
 use quickcheck;
+use util::FnBox;
 
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[test]
 fn identity() {
@@ -44,12 +49,12 @@ fn identity() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -93,10 +98,10 @@ fn identity_with_excessive_flushing() {
                     leaf,
                     None,
                     Box::new(move |_| {}),
-                ),
+                ).unwrap(),
                 chunk,
             ));
-            bs_p.flush();
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
             assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), &chunk[..]);
         }
@@ -165,6 +170,7 @@ fn blob_reuse() {
             packing: None,
             key: None,
         },
+        range: None,
     };
     let mut c2 = c1.clone();
 
@@ -190,6 +196,67 @@ fn blob_reuse() {
     assert_eq!(vec![1, 2], reader.read_chunk(&c3).unwrap());
 }
 
+#[test]
+fn compressed_chunks() {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let mut zeros = hash::tree::HashRef {
+        hash: hash::Hash::new(&keys, node, leaf, &[0; 500]),
+        node: node,
+        leaf: leaf,
+        info: None,
+        persistent_ref: ChunkRef {
+            blob_id: None,
+            blob_name: Vec::new(),
+            offset: 0,
+            length: 0,
+            packing: None,
+            key: None,
+        },
+        range: None,
+    };
+    let mut small = zeros.clone();
+    let mut raw = zeros.clone();
+
+    // Chunks are compressed by default, unless that does not make them smaller.
+    let mut b = Blob::new(keys.clone(), 2000);
+    b.try_append(&[0; 500], &mut zeros).unwrap();
+    b.try_append(&[1, 2, 3], &mut small).unwrap();
+    assert_eq!(Some(Packing::Zstd), zeros.persistent_ref.packing);
+    assert!(zeros.persistent_ref.length < 500);
+    assert_eq!(None, small.persistent_ref.packing);
+
+    // Chunks stored without compression are read back alongside compressed ones.
+    b.set_compression(Compression::None);
+    b.try_append(&[0; 500], &mut raw).unwrap();
+    assert_eq!(None, raw.persistent_ref.packing);
+    assert!(raw.persistent_ref.length > 500);
+
+    let out = b.to_ciphertext().unwrap().to_vec();
+    let mut reader = BlobReader::new(keys.clone(), crypto::CipherTextRef::new(&out[..])).unwrap();
+    assert_eq!(vec![0; 500], reader.read_chunk(&zeros).unwrap());
+    assert_eq!(vec![1, 2, 3], reader.read_chunk(&small).unwrap());
+    assert_eq!(vec![0; 500], reader.read_chunk(&raw).unwrap());
+
+    // The packing is kept in the footer.
+    let packings: Vec<_> = reader
+        .refs()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.persistent_ref.packing)
+        .collect();
+    assert_eq!(vec![Some(Packing::Zstd), None, None], packings);
+
+    assert_eq!(Ok(Compression::Zstd(3)), Compression::parse("zstd"));
+    assert_eq!(Ok(Compression::Zstd(19)), Compression::parse("zstd:19\n"));
+    assert_eq!(Ok(Compression::None), Compression::parse("none"));
+    assert!(Compression::parse("zstd:100").is_err());
+    assert!(Compression::parse("gzip").is_err());
+    assert_eq!("zstd:19", Compression::Zstd(19).to_string());
+}
+
 #[test]
 fn blob_identity() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
@@ -215,6 +282,7 @@ fn blob_identity() {
                     packing: None,
                     key: None,
                 },
+                range: None,
             };
             if let Err(_) = b.try_append(&chunk[..], &mut cref) {
                 assert!(b.upperbound_len() + chunk.len() + cref.as_bytes().len() + 50 >= max_size);
@@ -280,6 +348,7 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
                 packing: None,
                 key: None,
             },
+            range: None,
         };
         match blob.try_append(&block[..], &mut cref) {
             Ok(()) => continue,
@@ -343,3 +412,542 @@ fn blob_ciphertext_authed_allbytes() {
     // We did not corrupt the blob.
     assert_eq!(vs, verify(&keys, &bytes[..]).unwrap());
 }
+
+fn store_chunks(chunks: &[Vec<u8>]) -> (Arc<crypto::keys::Keeper>, Arc<MemoryBackend>) {
+    let backend = Arc::new(MemoryBackend::new());
+
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs_p = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    for chunk in chunks {
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        bs_p.store(
+            &chunk[..],
+            hash::Hash::new(&keys, node, leaf, chunk),
+            node,
+            leaf,
+            None,
+            Box::new(move |_| {}),
+        ).unwrap();
+    }
+    bs_p.flush().unwrap();
+
+    (keys, backend)
+}
+
+#[test]
+fn verify_streaming() {
+    fn prop(chunks: Vec<Vec<u8>>, range_bytes: u8) -> bool {
+        let chunks: Vec<Vec<u8>> = chunks.into_iter().take(16).map(|mut c| {
+            c.truncate(512);
+            c
+        }).collect();
+        let (keys, backend) = store_chunks(&chunks);
+
+        // Odd range sizes exercise the held back authentication tag; tiny ones are only slow.
+        let sizes = [16, 17, 31, 64, 257, 4096];
+        let range_bytes = sizes[range_bytes as usize % sizes.len()];
+        let verifier = BlobVerifier::with_range_bytes(keys, backend.clone(), range_bytes);
+        let mut verified = 0;
+        for name in backend.list().unwrap() {
+            verified += verifier.verify(&name).unwrap();
+        }
+        verified == chunks.iter().filter(|c| !c.is_empty()).count()
+    }
+    quickcheck::QuickCheck::new()
+        .tests(30)
+        .quickcheck(prop as fn(Vec<Vec<u8>>, u8) -> bool);
+}
+
+#[test]
+fn verify_detects_corruption() {
+    let (keys, backend) = store_chunks(&[vec![1; 10], vec![2; 20]]);
+    let name = backend.list().unwrap().pop().unwrap();
+    let verifier = BlobVerifier::with_range_bytes(keys, backend.clone(), 64);
+    assert_eq!(verifier.verify(&name).unwrap(), 2);
+
+    let mut data = backend.retrieve(&name).unwrap().unwrap();
+    data[15] ^= 1;
+    backend.delete(&name).unwrap();
+    backend
+        .store(&name, crypto::CipherText::new(data), Box::new(move |_| {}))
+        .unwrap();
+    assert!(verifier.verify(&name).is_err());
+
+    backend.delete(&name).unwrap();
+    assert!(verifier.verify(&name).is_err());
+}
+
+#[test]
+fn quota_limits_stored_blobs() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let committed = Arc::new(AtomicUsize::new(0));
+    let store = |chunk: &[u8]| {
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let hash = hash::Hash::new(&keys, node, leaf, chunk);
+        let committed = committed.clone();
+        let callback = Box::new(move |_| {
+            committed.fetch_add(1, Ordering::SeqCst);
+        });
+        bs.store(chunk, hash, node, leaf, None, callback).unwrap();
+        bs.flush()
+    };
+
+    // Room for exactly one blob.
+    bs.set_quota(Some(1500));
+    store(b"first").unwrap();
+    assert_eq!(bs.usage(), 1024);
+    assert_eq!(backend.list().unwrap().len(), 1);
+    assert_eq!(committed.load(Ordering::SeqCst), 1);
+
+    // The second blob is refused, and kept with its pending reference.
+    let err = store(b"second").unwrap_err();
+    assert!(err.to_string().contains("Storage quota exceeded"), "{}", err);
+    assert_eq!(backend.list().unwrap().len(), 1);
+    assert_eq!(committed.load(Ordering::SeqCst), 1);
+
+    // Once the quota allows it, the same blob is stored.
+    bs.set_quota(Some(2048));
+    bs.flush().unwrap();
+    assert_eq!(backend.list().unwrap().len(), 2);
+    assert_eq!(committed.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn blobs_record_key_version() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+
+    // A blob found in the backend; we do not know which keys wrote it.
+    let other = BlobIndex::new(keys.clone(), Arc::new(db::Index::new_for_testing())).unwrap();
+    let recovered = blob_index.recover(other.reserve().name);
+    blob_index.refresh_next_id();
+
+    let bs = BlobStore::new(keys.clone(), blob_index.clone(), backend, 1024);
+    for chunk in &[&b"first"[..], &b"second"[..]] {
+        let node = NodeType::Leaf;
+        let leaf = LeafType::FileChunk;
+        let hash = hash::Hash::new(&keys, node, leaf, chunk);
+        bs.store(chunk, hash, node, leaf, None, Box::new(move |_| {})).unwrap();
+        bs.flush().unwrap();
+    }
+
+    assert_eq!(
+        bs.key_usage(),
+        vec![
+            KeyUsage {
+                key_version: None,
+                blobs: 1,
+                bytes: 1024,
+            },
+            KeyUsage {
+                key_version: Some(crypto::keys::KEY_VERSION),
+                blobs: 2,
+                bytes: 2048,
+            },
+        ]
+    );
+
+    // Blobs of unknown version are the first to re-encrypt.
+    let oldest = blob_index.oldest_key_blobs(2);
+    assert_eq!(oldest.len(), 2);
+    assert_eq!(oldest[0].name, recovered.name);
+}
+
+#[test]
+fn rotated_keys_read_blobs_of_older_versions() {
+    let backend = Arc::new(MemoryBackend::new());
+    let old_keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let new_keys = Arc::new(old_keys.at_key_version(2));
+    assert_eq!(new_keys.key_version(), 2);
+    assert_eq!(new_keys.key_versions(), vec![2, 1]);
+
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(old_keys.clone(), db).unwrap());
+    let store = |keys: &Arc<crypto::keys::Keeper>, chunk: &[u8]| {
+        let bs = BlobStore::new(keys.clone(), blob_index.clone(), backend.clone(), 1024);
+        let (node, leaf) = (NodeType::Leaf, LeafType::FileChunk);
+        let hash = hash::Hash::new(keys, node, leaf, chunk);
+        let href = bs.store(chunk, hash, node, leaf, None, Box::new(move |_| {})).unwrap();
+        bs.flush().unwrap();
+        href
+    };
+    let old = store(&old_keys, b"before rotating");
+    let new = store(&new_keys, b"after rotating");
+
+    // Rotating does not change the hashes, so chunks stored before are still deduplicated.
+    assert_eq!(
+        old.hash,
+        hash::Hash::new(&new_keys, NodeType::Leaf, LeafType::FileChunk, b"before rotating")
+    );
+
+    let bs = BlobStore::new(new_keys.clone(), blob_index.clone(), backend.clone(), 1024);
+    assert_eq!(bs.retrieve(&old).unwrap().unwrap(), b"before rotating");
+    assert_eq!(bs.retrieve(&new).unwrap().unwrap(), b"after rotating");
+    let versions: Vec<_> = bs.key_usage().into_iter().map(|u| u.key_version).collect();
+    assert_eq!(versions, vec![Some(1), Some(2)]);
+
+    let verifier = BlobVerifier::new(new_keys.clone(), backend.clone());
+    assert_eq!(verifier.verify(&old.persistent_ref.blob_name).unwrap(), 1);
+    assert_eq!(verifier.verify(&new.persistent_ref.blob_name).unwrap(), 1);
+
+    // The old keys cannot read what the new ones wrote.
+    let bs = BlobStore::new(old_keys.clone(), blob_index, backend.clone(), 1024);
+    assert!(bs.retrieve(&new).is_err());
+    let verifier = BlobVerifier::new(old_keys, backend);
+    assert!(verifier.verify(&new.persistent_ref.blob_name).is_err());
+}
+
+#[test]
+fn retrieve_verifies_blob_and_chunk_hash() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let node = NodeType::Leaf;
+    let leaf = LeafType::FileChunk;
+    let chunk = vec![3; 30];
+    let href = bs.store(
+        &chunk[..],
+        hash::Hash::new(&keys, node, leaf, &chunk[..]),
+        node,
+        leaf,
+        None,
+        Box::new(move |_| {}),
+    ).unwrap();
+    bs.flush().unwrap();
+    assert_eq!(bs.retrieve(&href).unwrap().unwrap(), chunk);
+    assert_eq!(bs.retrieve_metrics().chunks_verified, 1);
+    assert_eq!(bs.retrieve_metrics().failures(), 0);
+
+    // A reference whose hash does not match the stored chunk is refused.
+    let mut wrong = href.clone();
+    wrong.hash = hash::Hash::new(&keys, node, leaf, &[4; 30]);
+    assert!(bs.retrieve(&wrong).is_err());
+    assert_eq!(bs.retrieve_metrics().chunk_failures, 1);
+
+    // Failures tell where the chunk is stored.
+    let failures = bs.retrieve_failures();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].hash, wrong.hash);
+    assert!(!failures[0].whole_blob);
+    assert_eq!(failures[0].blob_name, href.persistent_ref.blob_name);
+    assert_eq!(
+        (failures[0].offset, failures[0].length),
+        (href.persistent_ref.offset, href.persistent_ref.length)
+    );
+
+    // A corrupted blob is refused before any chunk is decrypted.
+    let name = href.persistent_ref.blob_name.clone();
+    let mut data = backend.retrieve(&name).unwrap().unwrap();
+    data[100] ^= 1;
+    backend.delete(&name).unwrap();
+    backend
+        .store(&name, crypto::CipherText::new(data), Box::new(move |_| {}))
+        .unwrap();
+
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let fresh = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+    assert!(fresh.retrieve(&href).is_err());
+    let metrics = fresh.retrieve_metrics();
+    assert_eq!((metrics.blob_failures, metrics.chunk_failures), (1, 0));
+    assert_eq!(fresh.retrieve_failures()[0].hash, href.hash);
+    assert!(fresh.retrieve_failures()[0].whole_blob);
+}
+
+/// A backend that keeps blobs hinted `Cold` in a cold tier. A cold blob is readable after it
+/// has been asked for `reads_to_restore` more times once its restore was requested.
+struct TieredBackend {
+    blobs: MemoryBackend,
+    classes: Mutex<BTreeMap<Vec<u8>, StorageClass>>,
+    // Reads left until a blob being restored is readable.
+    restoring: Mutex<BTreeMap<Vec<u8>, usize>>,
+    reads_to_restore: usize,
+    restores_requested: Mutex<usize>,
+}
+
+impl TieredBackend {
+    fn new(reads_to_restore: usize) -> Arc<TieredBackend> {
+        Arc::new(TieredBackend {
+            blobs: MemoryBackend::new(),
+            classes: Mutex::new(BTreeMap::new()),
+            restoring: Mutex::new(BTreeMap::new()),
+            reads_to_restore: reads_to_restore,
+            restores_requested: Mutex::new(0),
+        })
+    }
+
+    /// Whether the blob can be read, starting its restore if it is cold.
+    fn readable(&self, name: &[u8]) -> bool {
+        if self.classes.lock().unwrap().get(name) != Some(&StorageClass::Cold) {
+            return true;
+        }
+        let mut restoring = self.restoring.lock().unwrap();
+        let left = restoring.entry(name.to_vec()).or_insert_with(|| {
+            *self.restores_requested.lock().unwrap() += 1;
+            self.reads_to_restore
+        });
+        *left == 0
+    }
+}
+
+impl StoreBackend for TieredBackend {
+    fn store(
+        &self,
+        name: &[u8],
+        data: crypto::CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.store_with_class(name, data, StorageClass::Standard, done)
+    }
+
+    fn store_with_class(
+        &self,
+        name: &[u8],
+        data: crypto::CipherText,
+        class: StorageClass,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.classes.lock().unwrap().insert(name.to_vec(), class);
+        self.blobs.store(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if !self.readable(name) {
+            let mut restoring = self.restoring.lock().unwrap();
+            let left = restoring.get_mut(name).unwrap();
+            *left -= 1;
+            return Err(RESTORE_PENDING.to_owned());
+        }
+        self.blobs.retrieve(name)
+    }
+
+    fn request_restore(&self, name: &[u8]) -> Result<bool, String> {
+        Ok(self.readable(name))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.blobs.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.blobs.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn tiered_store(
+    backend: Arc<TieredBackend>,
+) -> (Arc<crypto::keys::Keeper>, BlobStore<TieredBackend>) {
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs = BlobStore::new(keys.clone(), blob_index, backend, 1024);
+    (keys, bs)
+}
+
+fn store_leaf(
+    keys: &crypto::keys::Keeper,
+    bs: &BlobStore<TieredBackend>,
+    leaf: LeafType,
+    chunk: &[u8],
+) -> hash::tree::HashRef {
+    let node = NodeType::Leaf;
+    let hash = hash::Hash::new(keys, node, leaf, chunk);
+    bs.store(chunk, hash, node, leaf, None, Box::new(move |_| {})).unwrap()
+}
+
+#[test]
+fn blobs_get_storage_class_hints() {
+    let backend = TieredBackend::new(0);
+    let (keys, bs) = tiered_store(backend.clone());
+
+    let data = store_leaf(&keys, &bs, LeafType::FileChunk, b"file contents");
+    bs.flush().unwrap();
+    let listing = store_leaf(&keys, &bs, LeafType::TreeList, b"a directory");
+    bs.flush().unwrap();
+    let mixed = store_leaf(&keys, &bs, LeafType::FileChunk, b"more file contents");
+    store_leaf(&keys, &bs, LeafType::TreeList, b"another directory");
+    bs.flush().unwrap();
+
+    let classes = backend.classes.lock().unwrap().clone();
+    let class = |href: &hash::tree::HashRef| classes[&href.persistent_ref.blob_name];
+    assert_eq!(class(&data), StorageClass::Cold);
+    assert_eq!(class(&listing), StorageClass::Standard);
+    // Blobs with any metadata stay out of the cold tier.
+    assert_eq!(class(&mixed), StorageClass::Standard);
+}
+
+#[test]
+fn retrieve_waits_for_cold_restore() {
+    let backend = TieredBackend::new(3);
+    let (keys, bs) = tiered_store(backend.clone());
+    bs.set_restore_wait(Duration::from_millis(1), Duration::from_secs(60));
+
+    let first = store_leaf(&keys, &bs, LeafType::FileChunk, b"first");
+    bs.flush().unwrap();
+    let second = store_leaf(&keys, &bs, LeafType::FileChunk, b"second");
+    bs.flush().unwrap();
+    let names = vec![
+        first.persistent_ref.blob_name.clone(),
+        second.persistent_ref.blob_name.clone(),
+    ];
+
+    // Both restores are requested up front, and not again when read.
+    assert_eq!(bs.queue_restores(&names).unwrap(), 2);
+    assert_eq!(bs.retrieve(&first).unwrap().unwrap(), b"first");
+    assert_eq!(bs.retrieve(&second).unwrap().unwrap(), b"second");
+    assert_eq!(*backend.restores_requested.lock().unwrap(), 2);
+    assert_eq!(bs.queue_restores(&names).unwrap(), 0);
+}
+
+#[test]
+fn retrieve_gives_up_on_slow_cold_restore() {
+    let backend = TieredBackend::new(1000);
+    let (keys, bs) = tiered_store(backend.clone());
+    bs.set_restore_wait(Duration::from_millis(1), Duration::from_millis(20));
+
+    let href = store_leaf(&keys, &bs, LeafType::FileChunk, b"frozen");
+    bs.flush().unwrap();
+    let err = bs.retrieve(&href).unwrap_err().to_string();
+    assert!(err.contains("still being restored from cold storage"), "{}", err);
+    // The restore stays queued for the next attempt.
+    assert_eq!(bs.queue_restores(&[href.persistent_ref.blob_name]).unwrap(), 1);
+}
+
+#[test]
+fn prefetcher_hands_out_blobs_in_read_order() {
+    let backend = Arc::new(MemoryBackend::new());
+    let names: Vec<Vec<u8>> = (0..10u8).map(|i| vec![b'b', i]).collect();
+    for name in &names {
+        let data = crypto::CipherText::new(name.repeat(100));
+        backend.store(name, data, Box::new(move |_| {})).unwrap();
+    }
+
+    let prefetcher = Prefetcher::new(backend.clone(), names.clone(), 3);
+    for name in &names[..3] {
+        assert_eq!(prefetcher.take(name).unwrap().unwrap().unwrap(), name.repeat(100));
+    }
+    // Blobs may be skipped, but not read again.
+    assert_eq!(prefetcher.take(&names[7]).unwrap().unwrap().unwrap(), names[7].repeat(100));
+    assert!(prefetcher.take(&names[5]).is_none());
+    assert!(prefetcher.take(b"unlisted").is_none());
+    assert_eq!(prefetcher.take(&names[9]).unwrap().unwrap().unwrap(), names[9].repeat(100));
+
+    // A missing blob is handed out as such.
+    let prefetcher = Prefetcher::new(backend, vec![b"missing".to_vec()], 1);
+    assert_eq!(prefetcher.take(b"missing").unwrap().unwrap(), None);
+}
+
+/// A backend that records which threads read its blobs, and panics reading blob `panics_on`.
+struct ThreadedBackend {
+    blobs: MemoryBackend,
+    readers: Mutex<Vec<thread::ThreadId>>,
+    panics_on: Vec<u8>,
+}
+
+impl ThreadedBackend {
+    fn new(panics_on: &[u8]) -> Arc<ThreadedBackend> {
+        Arc::new(ThreadedBackend {
+            blobs: MemoryBackend::new(),
+            readers: Mutex::new(vec![]),
+            panics_on: panics_on.to_vec(),
+        })
+    }
+}
+
+impl StoreBackend for ThreadedBackend {
+    fn store(
+        &self,
+        name: &[u8],
+        data: crypto::CipherText,
+        done: Box<FnBox<(), ()>>,
+    ) -> Result<(), String> {
+        self.blobs.store(name, data, done)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        assert!(name != &self.panics_on[..], "backend failed");
+        self.readers.lock().unwrap().push(thread::current().id());
+        self.blobs.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.blobs.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+        self.blobs.list()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+fn prefetcher_hands_out_panicked_fetches_as_failed() {
+    let backend = ThreadedBackend::new(b"panics");
+    let names = vec![b"panics".to_vec(), b"missing".to_vec()];
+    let prefetcher = Prefetcher::new(backend, names, 2);
+    let err = prefetcher.take(b"panics").unwrap().unwrap_err();
+    assert!(err.contains("panicked"), "{}", err);
+    assert_eq!(prefetcher.take(b"missing").unwrap().unwrap(), None);
+}
+
+#[test]
+fn retrieve_reads_prefetched_blobs() {
+    let backend = ThreadedBackend::new(b"");
+    let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(keys.clone(), db).unwrap());
+    let bs = BlobStore::new(keys.clone(), blob_index, backend.clone(), 1024);
+
+    let (node, leaf) = (NodeType::Leaf, LeafType::FileChunk);
+    let chunks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 600]).collect();
+    let hrefs: Vec<_> = chunks
+        .iter()
+        .map(|chunk| {
+            let hash = hash::Hash::new(&keys, node, leaf, &chunk[..]);
+            bs.store(&chunk[..], hash, node, leaf, None, Box::new(move |_| {})).unwrap()
+        })
+        .collect();
+    bs.flush().unwrap();
+
+    let mut names: Vec<Vec<u8>> = vec![];
+    for href in &hrefs {
+        if names.last() != Some(&href.persistent_ref.blob_name) {
+            names.push(href.persistent_ref.blob_name.clone());
+        }
+    }
+    assert!(names.len() > 1);
+
+    bs.prefetch(names.clone(), 2);
+    for (href, chunk) in hrefs.iter().zip(chunks.iter()) {
+        assert_eq!(&bs.retrieve(href).unwrap().unwrap(), chunk);
+    }
+    bs.prefetch(vec![], 0);
+    // Every blob was read once, and by the prefetch workers rather than by this thread.
+    let readers = backend.readers.lock().unwrap().clone();
+    assert_eq!(readers.len(), names.len());
+    assert!(!readers.contains(&thread::current().id()));
+    assert_eq!(bs.retrieve_metrics().chunks_verified, chunks.len() as u64);
+    assert_eq!(bs.retrieve_metrics().failures(), 0);
+}
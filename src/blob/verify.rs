@@ -0,0 +1,175 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of stored blobs using ranged reads, so memory use does not grow with blob size.
+
+use backend::StoreBackend;
+use crypto;
+use crypto::CipherTextRef;
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use super::blob::parse_footer;
+use super::BlobError;
+
+/// Bytes requested per ranged read.
+pub const VERIFY_RANGE_BYTES: usize = 1024 * 1024;
+
+pub struct BlobVerifier<B> {
+    keys: Arc<crypto::keys::Keeper>,
+    backend: Arc<B>,
+    range_bytes: usize,
+}
+
+impl<B: StoreBackend> BlobVerifier<B> {
+    pub fn new(keys: Arc<crypto::keys::Keeper>, backend: Arc<B>) -> BlobVerifier<B> {
+        BlobVerifier::with_range_bytes(keys, backend, VERIFY_RANGE_BYTES)
+    }
+
+    pub fn with_range_bytes(
+        keys: Arc<crypto::keys::Keeper>,
+        backend: Arc<B>,
+        range_bytes: usize,
+    ) -> BlobVerifier<B> {
+        assert!(range_bytes > 0);
+        BlobVerifier {
+            keys: keys,
+            backend: backend,
+            range_bytes: range_bytes,
+        }
+    }
+
+    /// Check the authentication tag of blob `name`, its footer and every chunk it contains.
+    /// Returns the number of chunks checked.
+    pub fn verify(&self, name: &[u8]) -> Result<usize, BlobError> {
+        let (blob_len, key_version) = self.authenticate(name)?;
+        let body_len = blob_len - crypto::authed::hash::DIGESTBYTES;
+
+        // The access footer is at the very end, and tells us the size of the sealed footer.
+        let access_len = crypto::sealed::desc::access_cipher_bytes();
+        if body_len < access_len {
+            return Err("blob too short for its footer".into());
+        }
+        let access_ct = self.read(name, body_len - access_len, access_len)?;
+        let fixed = crypto::FixedKey::new(&self.keys).with_key_version(key_version);
+        let (access_key, footer_ct, _) = fixed.unseal_access_ctx(CipherTextRef::new(&access_ct))?;
+        let footer_ct = footer_ct.to_vec();
+
+        let sealed_len = fixed.sealed_len(CipherTextRef::new(&footer_ct))?;
+        if body_len - access_len < sealed_len {
+            return Err("blob too short for its footer".into());
+        }
+        let sealed_ct = self.read(name, body_len - access_len - sealed_len, sealed_len)?;
+        let (_, footer) = fixed.unseal(
+            CipherTextRef::new(&footer_ct),
+            CipherTextRef::new(&sealed_ct),
+        )?;
+
+        let hrefs = parse_footer(footer.as_bytes())?;
+        for href in &hrefs {
+            if &href.persistent_ref.blob_name[..] != name {
+                return Err("blob footer references another blob".into());
+            }
+            let ct = self.read(name, href.persistent_ref.offset, href.persistent_ref.length)?;
+            crypto::RefKey::unseal_chunk(&access_key, href, CipherTextRef::new(&ct))?;
+        }
+
+        Ok(hrefs.len())
+    }
+
+    /// Stream the whole blob through its authentication hash, under each version of the keys.
+    /// Returns the blob length and the version of the keys it was written with.
+    fn authenticate(&self, name: &[u8]) -> Result<(usize, u64), BlobError> {
+        let digest_len = crypto::authed::hash::DIGESTBYTES;
+
+        // Fetch the next range while hashing the current one.
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let backend = self.backend.clone();
+        let range_bytes = self.range_bytes;
+        let blob_name = name.to_vec();
+        thread::spawn(move || {
+            let mut offset = 0;
+            loop {
+                let res = backend.retrieve_range(&blob_name, offset, range_bytes);
+                let last = match res {
+                    Ok(Some(ref data)) => {
+                        offset += data.len();
+                        data.len() < range_bytes
+                    }
+                    _ => true,
+                };
+                if sender.send(res).is_err() || last {
+                    break;
+                }
+            }
+        });
+
+        let mut auths: Vec<_> = self
+            .keys
+            .key_versions()
+            .into_iter()
+            .map(|v| (v, self.keys.blob_authenticator(v, digest_len)))
+            .collect();
+        let mut update = |data: &[u8]| for &mut (_, ref mut auth) in auths.iter_mut() {
+            auth.update(data);
+        };
+        // The tag is the last bytes of the blob, so we hold back that many bytes.
+        let mut tail = Vec::with_capacity(digest_len);
+        let mut blob_len = 0;
+        for res in receiver.iter() {
+            let data = match res? {
+                Some(data) => data,
+                None if blob_len == 0 => return Err("blob not found".into()),
+                None => return Err("blob disappeared while reading".into()),
+            };
+            blob_len += data.len();
+
+            if data.len() >= digest_len {
+                let (body, rest) = data.split_at(data.len() - digest_len);
+                update(&tail[..]);
+                update(body);
+                tail.clear();
+                tail.extend_from_slice(rest);
+            } else {
+                tail.extend_from_slice(&data[..]);
+                let excess = tail.len().saturating_sub(digest_len);
+                update(&tail[..excess]);
+                tail.drain(..excess);
+            }
+        }
+
+        if blob_len < digest_len {
+            return Err("blob too short for its authentication tag".into());
+        }
+        for (key_version, auth) in auths {
+            let mut want = vec![0u8; digest_len];
+            auth.finalize(&mut want[..]);
+            if want == tail {
+                return Ok((blob_len, key_version));
+            }
+        }
+        Err("blob authentication failed".into())
+    }
+
+    fn read(&self, name: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, BlobError> {
+        match self.backend.retrieve_range(name, offset, len)? {
+            None => Err("blob disappeared while reading".into()),
+            Some(data) => if data.len() == len {
+                Ok(data)
+            } else {
+                Err("blob ended before expected".into())
+            },
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Debug bundles for bug reports, and read-only bundles for auditors.
+//!
+//! A debug bundle is a tar archive with a consistent copy of the local index, the operation
+//! status log and selected settings files from the state directory. Key material is never
+//! included: the key file is left out and chunk keys are removed from the copied index.
+//!
+//! A read-only bundle is a tar archive of a state directory that can list, verify and check out
+//! snapshots, but that hat refuses to commit or delete with: it holds a copy of the local index
+//! and the keys for reading, without the universal key they were derived from.
+
+use chrono;
+use crypto::keys::{Keeper, READ_ONLY_KEYS_FILENAME};
+use db;
+use errors::{DieselError, HatError};
+use hat;
+use status;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use util::{EntryHeader, EntryType, TarWriter};
+
+#[cfg(test)]
+mod tests;
+
+/// All files in a bundle are placed in this directory.
+pub const BUNDLE_DIR: &str = "hat-debug-bundle";
+
+/// All files in a read-only bundle are placed in this directory, which is a state directory.
+pub const READ_ONLY_BUNDLE_DIR: &str = "hat-read-only-bundle";
+
+/// Name of the bundled copy of the local index.
+pub const INDEX_FILENAME: &str = "hash_index.sqlite3";
+
+/// Name of the bundle's summary of the state directory.
+pub const MANIFEST_FILENAME: &str = "MANIFEST.txt";
+
+/// Append a consistent copy of the local index of `state_dir` to `tar` as `name`, written by
+/// `export` (e.g. `db::export`) to a temporary file.
+fn append_index<W, F>(
+    tar: &mut TarWriter<W>,
+    state_dir: &Path,
+    name: &str,
+    export: F,
+) -> Result<(), HatError>
+where
+    W: Write,
+    F: FnOnce(&str, &str) -> Result<(), DieselError>,
+{
+    let index_path = state_dir.join(format!("bundle-{}.sqlite3", process::id()));
+    let _ = fs::remove_file(&index_path);
+    let res = export(
+        &hat::hash_index_path(state_dir),
+        &index_path.to_string_lossy(),
+    ).map_err(HatError::from)
+        .and_then(|()| Ok(tar.append_file(name, &index_path)?));
+    let _ = fs::remove_file(&index_path);
+    res
+}
+
+/// Describe the files in `dir` (relative to `root`) by name and size.
+fn list_dir(root: &Path, dir: &Path, out: &mut String) -> Result<(), HatError> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let meta = entry.metadata()?;
+        let name = path.strip_prefix(root).unwrap_or(&path).display().to_string();
+        if meta.is_dir() {
+            writeln!(out, "  {}/", name).unwrap();
+            list_dir(root, &path, out)?;
+        } else {
+            writeln!(out, "  {} ({} bytes)", name, meta.len()).unwrap();
+        }
+    }
+    Ok(())
+}
+
+fn manifest(state_dir: &Path) -> Result<String, HatError> {
+    let mut out = String::new();
+    writeln!(out, "hat debug bundle").unwrap();
+    writeln!(out, "version: {}", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(out, "created: {}", chrono::Utc::now().to_rfc3339()).unwrap();
+
+    writeln!(out, "\nstate directory:").unwrap();
+    list_dir(state_dir, state_dir, &mut out)?;
+
+    writeln!(out, "\noperations:").unwrap();
+    for op in status::StatusLog::open(state_dir)?.operations() {
+        writeln!(out, "  {:?}", op).unwrap();
+    }
+    Ok(out)
+}
+
+/// Write a debug bundle of the state directory `state_dir` as a tar archive to `out`.
+/// `settings` names further files in `state_dir` to include when present.
+/// Returns the names of the files in the bundle.
+pub fn write_debug_bundle<W: Write>(
+    state_dir: &Path,
+    settings: &[&str],
+    out: W,
+) -> Result<Vec<String>, HatError> {
+    let mut tar = TarWriter::new(out);
+    let mut names = vec![];
+
+    let name = format!("{}/{}", BUNDLE_DIR, MANIFEST_FILENAME);
+    tar.append_bytes(&name, manifest(state_dir)?.as_bytes())?;
+    names.push(name);
+
+    // Export the index to a scrubbed copy; this is consistent even if the index is in use.
+    let name = format!("{}/{}", BUNDLE_DIR, INDEX_FILENAME);
+    append_index(&mut tar, state_dir, &name, db::export_scrubbed)?;
+    names.push(name);
+
+    for file in Some(status::STATUS_FILENAME).iter().chain(settings) {
+        let path = state_dir.join(file);
+        if path.is_file() {
+            let name = format!("{}/{}", BUNDLE_DIR, file);
+            tar.append_file(&name, &path)?;
+            names.push(name);
+        }
+    }
+
+    tar.finish()?;
+    Ok(names)
+}
+
+/// Write a read-only bundle of the state directory `state_dir` as a tar archive to `out`.
+/// `settings` names further files in `state_dir` to include when present. Extracted, the
+/// bundle's directory is a state directory for the same backend. Returns the names of the
+/// files in the bundle.
+pub fn write_read_only_bundle<W: Write>(
+    state_dir: &Path,
+    settings: &[&str],
+    out: W,
+) -> Result<Vec<String>, HatError> {
+    let keys = Keeper::load(state_dir)?.read_only_keys();
+    let mut tar = TarWriter::new(out);
+    let mut names = vec![];
+
+    let name = format!("{}/{}", READ_ONLY_BUNDLE_DIR, READ_ONLY_KEYS_FILENAME);
+    let header = EntryHeader {
+        name: name.as_bytes(),
+        entry_type: EntryType::File,
+        mode: 0o600,
+        uid: 0,
+        gid: 0,
+        mtime: chrono::Utc::now().timestamp() as u64,
+        size: keys.unsecure().len() as u64,
+    };
+    tar.append_entry(&header, keys.unsecure())?;
+    names.push(name);
+
+    let name = format!("{}/cache/{}", READ_ONLY_BUNDLE_DIR, INDEX_FILENAME);
+    append_index(&mut tar, state_dir, &name, db::export)?;
+    names.push(name);
+
+    for file in settings {
+        let path = state_dir.join(file);
+        if path.is_file() {
+            let name = format!("{}/{}", READ_ONLY_BUNDLE_DIR, file);
+            tar.append_file(&name, &path)?;
+            names.push(name);
+        }
+    }
+
+    tar.finish()?;
+    Ok(names)
+}
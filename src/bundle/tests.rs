@@ -0,0 +1,211 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use backend::{MemoryBackend, StoreBackend};
+use blob::ChunkRef;
+use bundle::*;
+use crypto;
+use diesel::prelude::*;
+use diesel::sql_types::Binary;
+use diesel::sqlite::SqliteConnection;
+use hash::tree::HashRef;
+use hat::tests::entry;
+use hat::{hash_index_path, HatRc};
+use status;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::Arc;
+use util::FileIterator;
+
+#[derive(QueryableByName)]
+struct Row {
+    #[sql_type = "Binary"]
+    bytes: Vec<u8>,
+}
+
+fn setup_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("hat-bundle-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("cache")).unwrap();
+    dir
+}
+
+/// The (name, contents) of each file in a tar archive.
+fn untar(mut archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut files = vec![];
+    while archive[0] != 0 {
+        let header = &archive[..512];
+        let name_len = header.iter().position(|&b| b == 0).unwrap();
+        let name = str::from_utf8(&header[..name_len]).unwrap().to_owned();
+        let size = str::from_utf8(&header[124..135]).unwrap();
+        let size = usize::from_str_radix(size, 8).unwrap();
+
+        let stored = header[148..154].to_vec();
+        let mut blank = header.to_vec();
+        blank[148..156].copy_from_slice(b"        ");
+        let sum: u64 = blank.iter().map(|&b| u64::from(b)).sum();
+        assert_eq!(format!("{:06o}", sum).as_bytes(), &stored[..]);
+
+        files.push((name, archive[512..512 + size].to_vec()));
+        archive = &archive[512 + size.div_ceil(512) * 512..];
+    }
+    assert_eq!(archive, &[0u8; 1024][..]);
+    files
+}
+
+fn query_refs(path: &Path, sql: &str) -> Vec<Vec<u8>> {
+    let conn = SqliteConnection::establish(&path.to_string_lossy()).unwrap();
+    diesel::sql_query(sql)
+        .load::<Row>(&conn)
+        .unwrap()
+        .into_iter()
+        .map(|r| r.bytes)
+        .collect()
+}
+
+const HASH_REFS: &str = "SELECT blob_ref AS bytes FROM hashes WHERE blob_ref IS NOT NULL";
+const SNAPSHOT_REFS: &str = "SELECT hash_ref AS bytes FROM snapshots WHERE hash_ref IS NOT NULL";
+
+#[test]
+fn bundle_without_keys() {
+    let dir = setup_dir("keys");
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+    fs::write(dir.join("quota"), "1000\n").unwrap();
+    {
+        let mut log = status::StatusLog::open(&dir).unwrap();
+        log.begin("commit", 1).unwrap();
+        log.finish().unwrap();
+    }
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::open_repository(dir.clone(), backend, 4 * 1024 * 1024).unwrap();
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    family
+        .snapshot_direct(entry("file".to_string()), false, Some(FileIterator::from_bytes(vec![7; 100])))
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // The live index has chunk keys.
+    let live = Path::new(&hash_index_path(&dir)).to_owned();
+    assert!(query_refs(&live, HASH_REFS)
+        .iter()
+        .any(|b| ChunkRef::from_bytes(&b[..]).unwrap().key.is_some()));
+
+    let mut archive = vec![];
+    let names = write_debug_bundle(&dir, &["quota", "parent"], &mut archive).unwrap();
+    let files = untar(&archive[..]);
+    assert_eq!(
+        names,
+        files.iter().map(|f| f.0.clone()).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        names,
+        vec![
+            format!("{}/{}", BUNDLE_DIR, MANIFEST_FILENAME),
+            format!("{}/{}", BUNDLE_DIR, INDEX_FILENAME),
+            format!("{}/{}", BUNDLE_DIR, status::STATUS_FILENAME),
+            format!("{}/quota", BUNDLE_DIR),
+        ]
+    );
+
+    let manifest = String::from_utf8(files[0].1.clone()).unwrap();
+    assert!(manifest.contains("secret-universal-key"));
+    assert!(manifest.contains("command: \"commit\""));
+    assert_eq!(&files[3].1[..], b"1000\n");
+
+    // The bundled index has the same references, without keys.
+    let copy = dir.join("bundled.sqlite3");
+    fs::write(&copy, &files[1].1[..]).unwrap();
+    let refs = query_refs(&copy, HASH_REFS);
+    assert_eq!(refs.len(), query_refs(&live, HASH_REFS).len());
+    assert!(refs.iter()
+        .all(|b| ChunkRef::from_bytes(&b[..]).unwrap().key.is_none()));
+    let snapshots = query_refs(&copy, SNAPSHOT_REFS);
+    assert!(!snapshots.is_empty());
+    assert!(snapshots
+        .iter()
+        .all(|b| HashRef::from_bytes(&b[..]).unwrap().persistent_ref.key.is_none()));
+
+    // No temporary files are left behind.
+    assert!(!fs::read_dir(&dir)
+        .unwrap()
+        .any(|e| e.unwrap().file_name().to_string_lossy().starts_with("bundle-")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_only_bundle_reads_but_does_not_write() {
+    let dir = setup_dir("read-only");
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    {
+        let mut hat = HatRc::open_repository(dir.clone(), backend.clone(), 4 * 1024 * 1024)
+            .unwrap();
+        let mut family = hat.open_family("family".to_string()).unwrap();
+        let contents = Some(FileIterator::from_bytes(vec![7; 100]));
+        family
+            .snapshot_direct(entry("file".to_string()), false, contents)
+            .unwrap();
+        family.flush().unwrap();
+        hat.commit(&mut family, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    }
+
+    let mut archive = vec![];
+    let names = write_read_only_bundle(&dir, &["parent"], &mut archive).unwrap();
+    let files = untar(&archive[..]);
+    assert_eq!(
+        names,
+        vec![
+            format!("{}/{}", READ_ONLY_BUNDLE_DIR, crypto::keys::READ_ONLY_KEYS_FILENAME),
+            format!("{}/cache/{}", READ_ONLY_BUNDLE_DIR, INDEX_FILENAME),
+        ]
+    );
+
+    // The extracted bundle is a state directory without the universal key.
+    let auditor = setup_dir("read-only-auditor");
+    for (name, contents) in files {
+        fs::write(auditor.join(name.replacen(READ_ONLY_BUNDLE_DIR, ".", 1)), contents).unwrap();
+    }
+    let blobs = backend.list().unwrap().len();
+    let mut hat = HatRc::open_repository(auditor.clone(), backend.clone(), 4 * 1024 * 1024)
+        .unwrap();
+    assert!(hat.is_read_only());
+
+    let snapshots = hat.list_snapshots();
+    assert_eq!(snapshots.iter().filter(|s| s.family_name == "family").count(), 1);
+    assert!(hat.verify_blobs().1.is_empty());
+    let out = auditor.join("out");
+    hat.checkout_in_dir("family".to_string(), out.clone()).unwrap();
+    assert_eq!(fs::read(out.join("file")).unwrap(), vec![7; 100]);
+
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    assert!(hat.commit(&mut family, None).is_err());
+    assert!(hat.meta_commit().is_err());
+    assert!(hat.deregister(&family, 1).is_err());
+    assert!(hat.gc().is_err());
+    assert_eq!(backend.list().unwrap().len(), blobs);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&auditor).unwrap();
+}
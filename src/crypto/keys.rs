@@ -12,25 +12,129 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use argon2;
 use blob;
-use libsodium_sys;
+use models;
+use crypto::provider::{self, CryptoProvider, KeyedHashState, Provider};
 use secstr;
+use serde_cbor;
 use std::path::Path;
 use std::fs;
 use std::io::{self, Write, Read};
+use std::sync::{Mutex, Once};
 
 
 const UNIVERSAL_KEY_FILENAME: &str = "secret-universal-key";
 
+/// The universal key encrypted with a passphrase, kept instead of `UNIVERSAL_KEY_FILENAME`.
+pub const LOCKED_UNIVERSAL_KEY_FILENAME: &str = "secret-universal-key-locked";
+
+/// Starts a passphrase-protected universal key, also when exported, and is authenticated with it.
+const LOCKED_KEY_MAGIC: &[u8] = b"hat-backup:locked-universal-key\n";
+
+/// Argon2id cost of deriving the key a universal key is locked with: 19 MiB, 2 passes, 1 lane.
+const LOCK_MEMORY_KIB: u32 = 19 * 1024;
+const LOCK_PASSES: u32 = 2;
+const LOCK_LANES: u32 = 1;
+
+/// Keys for reading the repository only, as serialized by `Keeper::read_only_keys`.
+pub const READ_ONLY_KEYS_FILENAME: &str = "secret-read-only-keys";
+
+/// Version of the keys a repository starts with. Rotating the keys derives the next version
+/// from the universal key; the blob index records the version of every blob written, so blobs
+/// under old keys can be found.
+pub const KEY_VERSION: u64 = 1;
+
+/// The version of the keys new blobs are written with, if not `KEY_VERSION`.
+pub const KEY_VERSION_FILENAME: &str = "key-version";
+
 // Crypto personalizations. Do not change these.
 const UNIVERSAL_KEY_MSG: &[u8] = b"hat-backup:universal-key:rust";
 
-const HAT_PERSONALIZATION:
-    &[u8; libsodium_sys::crypto_generichash_blake2b_PERSONALBYTES as usize] =
-    b"hat-backup~~rust";
+const HAT_PERSONALIZATION: &[u8; provider::HASH_PERSONALBYTES] = b"hat-backup~~rust";
+const BLOB_AUTHENTICATION_SALT: &[u8; provider::HASH_SALTBYTES] = b"blob~~~~blob~~~~";
+
+
+static PROVIDER_INIT: Once = Once::new();
 
+/// Asks for the passphrase of the universal key of the state directory it is given.
+pub type PassphrasePrompt = Box<Fn(&Path) -> Result<secstr::SecStr, String> + Send>;
 
+static PASSPHRASE_PROMPT: Mutex<Option<PassphrasePrompt>> = Mutex::new(None);
+
+/// Initialize the crypto provider. Safe to call any number of times; keys call it before first use.
+pub fn init() {
+    PROVIDER_INIT.call_once(Provider::init);
+}
+
+/// Ask `prompt` for the passphrase when loading the passphrase-protected universal key of the
+/// state directory it is given. Without a prompt, such keys cannot be loaded.
+pub fn set_passphrase_prompt(prompt: PassphrasePrompt) {
+    *PASSPHRASE_PROMPT.lock().unwrap() = Some(prompt);
+}
+
+fn ask_passphrase(dir: &Path) -> Result<secstr::SecStr, io::Error> {
+    match *PASSPHRASE_PROMPT.lock().unwrap() {
+        Some(ref prompt) => prompt(dir).map_err(io::Error::other),
+        None => Err(io::Error::other(
+            "the universal key is protected by a passphrase, and there is no way to ask for it",
+        )),
+    }
+}
+
+/// The key that locks a universal key, derived from `passphrase` with Argon2id.
+fn lock_key(
+    passphrase: &[u8],
+    salt: &[u8],
+    memory_kib: u32,
+    passes: u32,
+    lanes: u32,
+) -> Result<secstr::SecStr, io::Error> {
+    let invalid = |e: argon2::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let params = argon2::Params::new(memory_kib, passes, lanes, Some(provider::AEAD_KEYBYTES))
+        .map_err(invalid)?;
+    let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+    let mut key = vec![0; provider::AEAD_KEYBYTES];
+    argon2.hash_password_into(passphrase, salt, &mut key).map_err(invalid)?;
+    Ok(secstr::SecStr::new(key))
+}
+
+/// Encrypt the universal key `key` with `passphrase`, as stored in
+/// `LOCKED_UNIVERSAL_KEY_FILENAME`.
+fn lock_universal_key(key: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let salt = random_bytes(16).unsecure().to_vec();
+    let nonce = random_bytes(provider::AEAD_NONCEBYTES).unsecure().to_vec();
+    let lock = lock_key(passphrase, &salt, LOCK_MEMORY_KIB, LOCK_PASSES, LOCK_LANES)?;
+    let model = models::LockedKey {
+        sealed_key: Keeper::symmetric_lock(key, LOCKED_KEY_MAGIC, &nonce, lock.unsecure()),
+        salt: salt,
+        memory_kib: LOCK_MEMORY_KIB,
+        passes: LOCK_PASSES,
+        lanes: LOCK_LANES,
+        nonce: nonce,
+    };
+    let mut locked = LOCKED_KEY_MAGIC.to_vec();
+    serde_cbor::to_writer(&mut locked, &model).map_err(io::Error::other)?;
+    Ok(locked)
+}
+
+/// The universal key in `locked`, as written by `lock_universal_key`.
+fn unlock_universal_key(locked: &[u8], passphrase: &[u8]) -> Result<secstr::SecStr, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    if !locked.starts_with(LOCKED_KEY_MAGIC) {
+        return Err(invalid("not a passphrase-protected key"));
+    }
+    let model: models::LockedKey = serde_cbor::from_slice(&locked[LOCKED_KEY_MAGIC.len()..])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let lock = lock_key(passphrase, &model.salt, model.memory_kib, model.passes, model.lanes)?;
+    Keeper::symmetric_unlock(lock.unsecure(), &model.sealed_key, LOCKED_KEY_MAGIC, &model.nonce)
+        .map(secstr::SecStr::new)
+        .ok_or_else(|| invalid("wrong passphrase"))
+}
+
+#[derive(Clone)]
 struct PublicKey(secstr::SecStr);
+#[derive(Clone)]
 struct SecretKey(secstr::SecStr);
 
 #[cfg_attr(feature = "flame_it", flame)]
@@ -52,9 +156,8 @@ pub fn compute_salt(node_type: blob::NodeType, leaf_type: blob::LeafType) -> Box
 
 #[cfg_attr(feature = "flame_it", flame)]
 pub fn random_bytes(size: usize) -> secstr::SecStr {
-    use std::os::raw::c_void;
     let mut r = vec![0u8; size];
-    unsafe { libsodium_sys::randombytes_buf(r.as_mut_ptr() as *mut c_void, r.len()) };
+    Provider::random_bytes(&mut r[..]);
     secstr::SecStr::new(r)
 }
 
@@ -64,40 +167,109 @@ pub fn keyed_fingerprint_simple(sk: &[u8], msg: &[u8], out: &mut [u8]) {
 
 #[cfg_attr(feature = "flame_it", flame)]
 pub fn keyed_fingerprint(sk: &[u8], msg: &[u8], salt: &[u8], out: &mut [u8]) {
-    use libsodium_sys::{crypto_generichash_blake2b_PERSONALBYTES,
-                        crypto_generichash_blake2b_SALTBYTES};
-    assert_eq!(crypto_generichash_blake2b_SALTBYTES, salt.len() as u32);
+    assert_eq!(provider::HASH_SALTBYTES, salt.len());
+    Provider::keyed_hash(out, msg, sk, salt, &HAT_PERSONALIZATION[..]);
+}
 
-    let outlen = out.len();
+/// Plain BLAKE2b-512 of `slices` concatenated, without key, salt or personalization; the
+/// same digest as `b2sum`. Used to check stored blobs with the storage provider, which does not
+/// hold our keys.
+pub fn checksum(slices: &[&[u8]]) -> Vec<u8> {
+    let mut state = Checksum::new();
+    for slice in slices {
+        state.update(slice);
+    }
+    state.finalize()
+}
 
-    let ret = unsafe {
-        libsodium_sys::crypto_generichash_blake2b_salt_personal(
-            out.as_mut_ptr(),
-            outlen,
-            msg.as_ptr(),
-            msg.len() as u64,
-            sk.as_ptr(),
-            sk.len(),
-            salt.as_ptr(),
-            HAT_PERSONALIZATION.as_ptr(),
-        )
-    };
-    assert_eq!(ret, 0);
+/// Incremental form of `checksum`, for data read a piece at a time.
+pub struct Checksum(<Provider as CryptoProvider>::HashState);
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        init();
+        Checksum(Provider::keyed_hash_state(
+            provider::HASH_BYTES_MAX,
+            &[],
+            &[0; provider::HASH_SALTBYTES],
+            &[0; provider::HASH_PERSONALBYTES],
+        ))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        let mut out = vec![0; provider::HASH_BYTES_MAX];
+        self.0.finalize(&mut out);
+        out
+    }
+}
+
+impl Default for Checksum {
+    fn default() -> Checksum {
+        Checksum::new()
+    }
+}
+
+/// Write `key` to the new file `path`, readable by the owner only.
+fn write_private(path: &Path, key: &[u8]) -> Result<(), io::Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    f.write_all(key)
+}
+
+pub struct BlobAuthenticator(<Provider as CryptoProvider>::HashState);
+
+impl BlobAuthenticator {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    pub fn finalize(self, out: &mut [u8]) {
+        self.0.finalize(out)
+    }
+}
+
+/// The keys that change when the keys are rotated. Fingerprints, cut points and blob names
+/// stay the same, so data stored under old keys is still deduplicated against.
+struct Generation {
+    key_version: u64,
+    blob_authentication_key: secstr::SecStr,
+    data_key_pk: PublicKey,
+    data_key_sk: SecretKey,
+    access_key_pk: PublicKey,
+    access_key_sk: SecretKey,
 }
 
 pub struct Keeper {
-    universal_key: secstr::SecStr,
+    /// Missing for keepers loaded from read-only keys, which cannot derive new keys.
+    universal_key: Option<secstr::SecStr>,
     fingerprint_key: Option<secstr::SecStr>,
-    blob_authentication_key: Option<secstr::SecStr>,
-
-    data_key_pk: Option<PublicKey>,
-    data_key_sk: Option<SecretKey>,
 
     naming_key_pk: Option<PublicKey>,
     naming_key_sk: Option<SecretKey>,
 
-    access_key_pk: Option<PublicKey>,
-    access_key_sk: Option<SecretKey>,
+    chunking_key: Option<secstr::SecStr>,
+
+    /// The keys of every version up to the current one, oldest first. New blobs are written
+    /// with the last.
+    generations: Vec<Generation>,
+}
+
+/// The nonce of a key of version `key_version`. The first version uses the nonces from before
+/// keys could be rotated.
+fn versioned_nonce(nonce: &str, key_version: u64) -> Vec<u8> {
+    if key_version == KEY_VERSION {
+        nonce.as_bytes().to_vec()
+    } else {
+        format!("{}:v{}", nonce, key_version).into_bytes()
+    }
 }
 
 impl Keeper {
@@ -106,30 +278,198 @@ impl Keeper {
         let mut buf = Vec::new();
         f.read_to_end(&mut buf)?;
 
-        Ok(Keeper::new(secstr::SecStr::new(buf)))
+        let version = Keeper::read_key_version(dir)?;
+        Ok(Keeper::new(secstr::SecStr::new(buf)).at_key_version(version))
+    }
+
+    /// Load the universal key of the repository in `dir`, protected by `passphrase`.
+    pub fn load_with_passphrase(dir: &Path, passphrase: &[u8]) -> Result<Keeper, io::Error> {
+        let locked = fs::read(dir.join(LOCKED_UNIVERSAL_KEY_FILENAME))?;
+        let version = Keeper::read_key_version(dir)?;
+        Ok(Keeper::new(unlock_universal_key(&locked, passphrase)?).at_key_version(version))
+    }
+
+    /// The version of the keys the repository in `dir` writes new blobs with.
+    pub fn read_key_version(dir: &Path) -> Result<u64, io::Error> {
+        match fs::read_to_string(dir.join(KEY_VERSION_FILENAME)) {
+            Ok(text) => match text.trim().parse() {
+                Ok(version) if version >= KEY_VERSION => Ok(version),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid {}: {:?}", KEY_VERSION_FILENAME, text),
+                )),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(KEY_VERSION),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Make the repository in `dir` write new blobs with the keys of version `version`.
+    pub fn write_key_version(dir: &Path, version: u64) -> Result<(), io::Error> {
+        fs::write(dir.join(KEY_VERSION_FILENAME), format!("{}\n", version))
+    }
+
+    /// Load the keys of the repository in `dir`: the universal key if it is there, asking for
+    /// its passphrase if it has one (see `set_passphrase_prompt`), and the read-only keys
+    /// otherwise.
+    pub fn load(dir: &Path) -> Result<Keeper, io::Error> {
+        let read_only = dir.join(READ_ONLY_KEYS_FILENAME);
+        if dir.join(UNIVERSAL_KEY_FILENAME).exists() {
+            return Keeper::load_from_universal_key(dir);
+        }
+        if dir.join(LOCKED_UNIVERSAL_KEY_FILENAME).exists() {
+            let passphrase = ask_passphrase(dir)?;
+            return Keeper::load_with_passphrase(dir, passphrase.unsecure());
+        }
+        if !read_only.exists() {
+            return Keeper::load_from_universal_key(dir);
+        }
+        let bytes = secstr::SecStr::new(fs::read(read_only)?);
+        let model: models::ReadOnlyKeys = serde_cbor::from_slice(bytes.unsecure())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let sec = |v: Vec<u8>| Some(secstr::SecStr::new(v));
+        let generation = |g: models::KeyGeneration| Generation {
+            key_version: g.key_version,
+            blob_authentication_key: secstr::SecStr::new(g.blob_authentication),
+            data_key_pk: PublicKey(secstr::SecStr::new(g.data.0)),
+            data_key_sk: SecretKey(secstr::SecStr::new(g.data.1)),
+            access_key_pk: PublicKey(secstr::SecStr::new(g.access.0)),
+            access_key_sk: SecretKey(secstr::SecStr::new(g.access.1)),
+        };
+        let mut generations: Vec<Generation> = model.older.into_iter().map(&generation).collect();
+        generations.push(generation(models::KeyGeneration {
+            key_version: model.key_version,
+            blob_authentication: model.blob_authentication,
+            data: model.data,
+            access: model.access,
+        }));
+        Ok(Keeper {
+            universal_key: None,
+            fingerprint_key: sec(model.fingerprint),
+            naming_key_pk: Some(PublicKey(secstr::SecStr::new(model.naming.0))),
+            naming_key_sk: Some(SecretKey(secstr::SecStr::new(model.naming.1))),
+            chunking_key: sec(model.chunking),
+            generations: generations,
+        })
+    }
+
+    /// The keys needed to read the repository, but not the universal key, for `load` to find
+    /// in `READ_ONLY_KEYS_FILENAME`.
+    ///
+    /// The keys still let their holder write blobs the repository would accept; hat refuses to
+    /// write with them, so pair them with storage credentials that cannot write either.
+    pub fn read_only_keys(&self) -> secstr::SecStr {
+        let unsecure = |k: &Option<secstr::SecStr>| k.as_ref().unwrap().unsecure().to_vec();
+        let pair = |pk: &Option<PublicKey>, sk: &Option<SecretKey>| {
+            (
+                pk.as_ref().unwrap().0.unsecure().to_vec(),
+                sk.as_ref().unwrap().0.unsecure().to_vec(),
+            )
+        };
+        let generation = |g: &Generation| models::KeyGeneration {
+            key_version: g.key_version,
+            blob_authentication: g.blob_authentication_key.unsecure().to_vec(),
+            data: (
+                g.data_key_pk.0.unsecure().to_vec(),
+                g.data_key_sk.0.unsecure().to_vec(),
+            ),
+            access: (
+                g.access_key_pk.0.unsecure().to_vec(),
+                g.access_key_sk.0.unsecure().to_vec(),
+            ),
+        };
+        let (current, older) = self.generations.split_last().unwrap();
+        let current = generation(current);
+        let model = models::ReadOnlyKeys {
+            key_version: current.key_version,
+            fingerprint: unsecure(&self.fingerprint_key),
+            blob_authentication: current.blob_authentication,
+            chunking: unsecure(&self.chunking_key),
+            data: current.data,
+            access: current.access,
+            naming: pair(&self.naming_key_pk, &self.naming_key_sk),
+            older: older.iter().map(generation).collect(),
+        };
+        secstr::SecStr::new(serde_cbor::to_vec(&model).unwrap())
+    }
+
+    /// Whether these keys were loaded from read-only keys, without the universal key.
+    pub fn is_read_only(&self) -> bool {
+        self.universal_key.is_none()
     }
 
     pub fn write_new_universal_key(dir: &Path) -> Result<(), io::Error> {
         let mut f = fs::File::create(dir.join(UNIVERSAL_KEY_FILENAME))?;
         let keeper = Keeper::new(random_bytes(32));
-        f.write_all(keeper.universal_key.unsecure())?;
+        f.write_all(keeper.universal_key.as_ref().unwrap().unsecure())?;
         Ok(())
     }
 
+    /// Like `write_new_universal_key`, but keep the key encrypted with `passphrase`; opening
+    /// the repository then asks for it.
+    pub fn write_new_universal_key_with_passphrase(
+        dir: &Path,
+        passphrase: &[u8],
+    ) -> Result<(), io::Error> {
+        let keeper = Keeper::new(random_bytes(32));
+        let key = keeper.universal_key.as_ref().unwrap();
+        let locked = lock_universal_key(key.unsecure(), passphrase)?;
+        write_private(&dir.join(LOCKED_UNIVERSAL_KEY_FILENAME), &locked)
+    }
+
+    /// The file holding the universal key of the repository in `dir`, with or without a
+    /// passphrase.
+    fn universal_key_file(dir: &Path) -> ::std::path::PathBuf {
+        let plain = dir.join(UNIVERSAL_KEY_FILENAME);
+        let locked = dir.join(LOCKED_UNIVERSAL_KEY_FILENAME);
+        if !plain.exists() && locked.exists() {
+            locked
+        } else {
+            plain
+        }
+    }
+
+    /// Share the universal key of the repository in `from` with the one in `to`, along with
+    /// the version of the keys it writes with. A passphrase-protected key stays protected by
+    /// the same passphrase.
+    pub fn copy_universal_key(from: &Path, to: &Path) -> Result<(), io::Error> {
+        let file = Keeper::universal_key_file(from);
+        fs::copy(&file, to.join(file.file_name().unwrap()))?;
+        Keeper::write_key_version(to, Keeper::read_key_version(from)?)
+    }
+
+    /// Write the universal key of the repository in `dir` to the new file `file`, readable by
+    /// the owner only, to keep it safe for restoring without the state directory. A
+    /// passphrase-protected key is written as it is stored, still needing the passphrase.
+    pub fn export_universal_key(dir: &Path, file: &Path) -> Result<(), io::Error> {
+        let key = fs::read(Keeper::universal_key_file(dir))?;
+        write_private(file, &key)
+    }
+
+    /// Use the universal key exported to `file` for the repository in `dir`.
+    pub fn import_universal_key(file: &Path, dir: &Path) -> Result<(), io::Error> {
+        let key = fs::read(file)?;
+        let name = if key.starts_with(LOCKED_KEY_MAGIC) {
+            LOCKED_UNIVERSAL_KEY_FILENAME
+        } else {
+            UNIVERSAL_KEY_FILENAME
+        };
+        write_private(&dir.join(name), &key)
+    }
+
     pub fn new(key: secstr::SecStr) -> Keeper {
+        init();
+
         // Personalize key for Hat and make it 256-bit (32 bytes).
         let universal_key = Keeper::from_key_and_nonce(&key, &UNIVERSAL_KEY_MSG[..], 32);
 
         let mut keeper = Keeper {
-            universal_key: universal_key,
+            universal_key: Some(universal_key),
             fingerprint_key: None,
-            blob_authentication_key: None,
-            data_key_pk: None,
-            data_key_sk: None,
-            access_key_pk: None,
-            access_key_sk: None,
             naming_key_pk: None,
             naming_key_sk: None,
+            chunking_key: None,
+            generations: vec![],
         };
 
         keeper.init();
@@ -137,7 +477,45 @@ impl Keeper {
         keeper
     }
 
-    #[cfg(test)]
+    /// The same keys at version `version`: new blobs are written with the keys of that
+    /// version, and blobs of all versions up to it can be read. `version` must be at least
+    /// `KEY_VERSION`, as `read_key_version` checks.
+    pub fn at_key_version(&self, version: u64) -> Keeper {
+        assert!(version >= KEY_VERSION, "invalid key version: {}", version);
+        Keeper {
+            universal_key: self.universal_key.clone(),
+            fingerprint_key: self.fingerprint_key.clone(),
+            naming_key_pk: self.naming_key_pk.clone(),
+            naming_key_sk: self.naming_key_sk.clone(),
+            chunking_key: self.chunking_key.clone(),
+            generations: (KEY_VERSION..=version)
+                .map(|v| self.derive_generation(v))
+                .collect(),
+        }
+    }
+
+    /// The version of the keys this keeper encrypts new blobs with.
+    pub fn key_version(&self) -> u64 {
+        self.current().key_version
+    }
+
+    /// The versions of the keys this keeper can read blobs of, newest first.
+    pub fn key_versions(&self) -> Vec<u64> {
+        self.generations.iter().rev().map(|g| g.key_version).collect()
+    }
+
+    fn current(&self) -> &Generation {
+        self.generations.last().expect("need keys")
+    }
+
+    fn generation(&self, version: u64) -> &Generation {
+        self.generations
+            .iter()
+            .find(|g| g.key_version == version)
+            .unwrap_or_else(|| panic!("need keys of version {}", version))
+    }
+
+    #[cfg(any(test, feature = "testing"))]
     pub fn new_for_testing() -> Keeper {
         Keeper::new(secstr::SecStr::new(vec![0; 32]))
     }
@@ -146,27 +524,48 @@ impl Keeper {
         // Generate key used for fingerprinting.
         self.fingerprint_key = Some(self.from_nonce("hat:FINGERPRINT-key".as_bytes(), 64));
 
+        // Generate the keys that change when rotating keys.
+        let generation = self.derive_generation(KEY_VERSION);
+        self.generations = vec![generation];
+
+        // Generate naming key.
+        // Required for reading blob names.
+        let (pk, sk) = self.x25519_key_pair_from_nonce("hat:NAMING-key-x25519".as_bytes());
+        self.naming_key_pk = Some(pk);
+        self.naming_key_sk = Some(sk);
+
+        // Generate key deciding where content defined chunks are cut.
+        self.chunking_key = Some(self.from_nonce("hat:CHUNKING-key".as_bytes(), 64));
+    }
+
+    /// The keys of version `key_version`, derived from the universal key. Anyone holding the
+    /// universal key can derive every version, so rotating only locks out holders of older
+    /// read-only keys.
+    fn derive_generation(&self, key_version: u64) -> Generation {
         // Generate key for authenticating blob data.
-        self.blob_authentication_key =
-            Some(self.from_nonce("hat:BLOB-AUTHENTICATION-key".as_bytes(), 64));
+        let blob_authentication_key = self.from_nonce(
+            &versioned_nonce("hat:BLOB-AUTHENTICATION-key", key_version),
+            64,
+        );
 
         // Generate data key.
         // Required for reading blob data without a direct reference.
-        let (pk, sk) = self.x25519_key_pair_from_nonce("hat:DATA-key-x25519".as_bytes());
-        self.data_key_pk = Some(pk);
-        self.data_key_sk = Some(sk);
+        let (data_key_pk, data_key_sk) =
+            self.x25519_key_pair_from_nonce(&versioned_nonce("hat:DATA-key-x25519", key_version));
 
         // Generate access key.
         // Required for reading any blob data (with direct reference or with data key).
-        let (pk, sk) = self.x25519_key_pair_from_nonce("hat:ACCESS-key-x25519".as_bytes());
-        self.access_key_pk = Some(pk);
-        self.access_key_sk = Some(sk);
-
-        // Generate naming key.
-        // Required for reading blob names.
-        let (pk, sk) = self.x25519_key_pair_from_nonce("hat:NAMING-key-x25519".as_bytes());
-        self.naming_key_pk = Some(pk);
-        self.naming_key_sk = Some(sk);
+        let (access_key_pk, access_key_sk) = self
+            .x25519_key_pair_from_nonce(&versioned_nonce("hat:ACCESS-key-x25519", key_version));
+
+        Generation {
+            key_version: key_version,
+            blob_authentication_key: blob_authentication_key,
+            data_key_pk: data_key_pk,
+            data_key_sk: data_key_sk,
+            access_key_pk: access_key_pk,
+            access_key_sk: access_key_sk,
+        }
     }
 
     fn from_key_and_nonce(key: &secstr::SecStr, nonce: &[u8], outlen: usize) -> secstr::SecStr {
@@ -180,7 +579,13 @@ impl Keeper {
     }
 
     pub fn from_nonce(&self, nonce: &[u8], outlen: usize) -> secstr::SecStr {
-        Self::from_key_and_nonce(&self.universal_key, nonce, outlen)
+        let key = self.universal_key.as_ref().expect("need universal key");
+        Self::from_key_and_nonce(key, nonce, outlen)
+    }
+
+    /// Key deciding where content defined chunks are cut.
+    pub fn chunking_key(&self) -> secstr::SecStr {
+        self.chunking_key.clone().expect("need chunking key")
     }
 
     fn x25519_key_pair_from_nonce(&self, nonce: &[u8]) -> (PublicKey, SecretKey) {
@@ -188,80 +593,40 @@ impl Keeper {
         let mut sk = secstr::SecStr::new(vec![0; 32]);
 
         let seed = self.from_nonce(nonce, 32);
-
-        let ret = unsafe {
-            libsodium_sys::crypto_box_seed_keypair(
-                pk.unsecure_mut().as_mut_ptr(),
-                sk.unsecure_mut().as_mut_ptr(),
-                seed.unsecure().as_ptr(),
-            )
-        };
-        assert_eq!(ret, 0);
+        Provider::box_seed_keypair(seed.unsecure(), pk.unsecure_mut(), sk.unsecure_mut());
 
         (PublicKey(pk), SecretKey(sk))
     }
 
     fn asymmetric_lock(pk: &PublicKey, msg: &[u8]) -> Vec<u8> {
-        let mut out = vec![0; msg.len() + libsodium_sys::crypto_box_SEALBYTES as usize];
-        let ret = unsafe {
-            libsodium_sys::crypto_box_seal(
-                out.as_mut_ptr(),
-                msg.as_ptr(),
-                msg.len() as u64,
-                pk.0.unsecure().as_ptr(),
-            )
-        };
-        assert_eq!(0, ret);
-
-        out
+        Provider::box_seal(msg, pk.0.unsecure())
     }
 
     fn asymmetric_unlock(pk: &PublicKey, sk: &SecretKey, ciphertext: &[u8]) -> Vec<u8> {
-        let mut out = vec![0; ciphertext.len() - libsodium_sys::crypto_box_SEALBYTES as usize];
-        let ret = unsafe {
-            libsodium_sys::crypto_box_seal_open(
-                out.as_mut_ptr(),
-                ciphertext.as_ptr(),
-                ciphertext.len() as u64,
-                pk.0.unsecure().as_ptr(),
-                sk.0.unsecure().as_ptr(),
-            )
-        };
-        assert_eq!(0, ret);
-
-        out
+        Provider::box_seal_open(ciphertext, pk.0.unsecure(), sk.0.unsecure())
+            .expect("asymmetric unlock failed")
     }
 
     pub fn data_lock(&self, msg: &[u8]) -> Vec<u8> {
-        Keeper::asymmetric_lock(
-            self.data_key_pk.as_ref().expect("need data public key"),
-            msg,
-        )
+        Keeper::asymmetric_lock(&self.current().data_key_pk, msg)
     }
 
-    pub fn data_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
-        Keeper::asymmetric_unlock(
-            self.data_key_pk.as_ref().expect("need data public key"),
-            self.data_key_sk.as_ref().expect("need data private key"),
-            ciphertext,
-        )
+    /// Unlock `ciphertext`, locked by `data_lock` with the keys of version `version`.
+    pub fn data_unlock(&self, version: u64, ciphertext: &[u8]) -> Vec<u8> {
+        let generation = self.generation(version);
+        Keeper::asymmetric_unlock(&generation.data_key_pk, &generation.data_key_sk, ciphertext)
     }
 
     pub fn access_lock(&self, msg: &[u8]) -> Vec<u8> {
-        Keeper::asymmetric_lock(
-            self.access_key_pk
-                .as_ref()
-                .expect("need access publick key"),
-            msg,
-        )
+        Keeper::asymmetric_lock(&self.current().access_key_pk, msg)
     }
 
-    pub fn access_unlock(&self, ciphertext: &[u8]) -> Vec<u8> {
+    /// Unlock `ciphertext`, locked by `access_lock` with the keys of version `version`.
+    pub fn access_unlock(&self, version: u64, ciphertext: &[u8]) -> Vec<u8> {
+        let generation = self.generation(version);
         Keeper::asymmetric_unlock(
-            self.access_key_pk.as_ref().expect("need access public key"),
-            self.access_key_sk
-                .as_ref()
-                .expect("need access private key"),
+            &generation.access_key_pk,
+            &generation.access_key_sk,
             ciphertext,
         )
     }
@@ -289,61 +654,48 @@ impl Keeper {
     }
 
     pub fn blob_authentication(&self, blob: &[u8], out: &mut [u8]) {
-        let key = self.blob_authentication_key
-            .as_ref()
-            .expect("need blob authentication key");
-        let salt: &[u8; 16] = b"blob~~~~blob~~~~";
-        keyed_fingerprint(key.unsecure(), blob, salt, &mut out[..])
+        let key = &self.current().blob_authentication_key;
+        keyed_fingerprint(key.unsecure(), blob, BLOB_AUTHENTICATION_SALT, &mut out[..])
     }
 
-    pub fn symmetric_lock(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8> {
-        let mut out =
-            vec![0u8; msg.len() + libsodium_sys::crypto_aead_chacha20poly1305_ABYTES as usize];
-        let mut out_len = 0;
-
-        let ret = unsafe {
-            libsodium_sys::crypto_aead_chacha20poly1305_encrypt(
-                out.as_mut_ptr(),
-                &mut out_len,
-                msg.as_ptr(),
-                msg.len() as u64,
-                ad.as_ptr(),
-                ad.len() as u64,
-                [0u8; 0].as_ptr(),
-                nonce.as_ptr(),
-                key.as_ptr(),
-            )
-        };
-        assert_eq!(0, ret);
-        assert_eq!(out_len, out.len() as u64);
+    /// The version of the keys `tag` is the `blob_authentication` of `blob` with, trying the
+    /// newest first. `None` if no version's is.
+    pub fn authenticating_version(&self, blob: &[u8], tag: &[u8]) -> Option<u64> {
+        let mut got = vec![0u8; tag.len()];
+        self.generations
+            .iter()
+            .rev()
+            .filter(|g| {
+                let key = g.blob_authentication_key.unsecure();
+                keyed_fingerprint(key, blob, BLOB_AUTHENTICATION_SALT, &mut got[..]);
+                &got[..] == tag
+            })
+            .map(|g| g.key_version)
+            .next()
+    }
 
-        out
+    /// Compute `blob_authentication` with the keys of version `version` incrementally, for
+    /// blobs read in pieces.
+    pub fn blob_authenticator(&self, version: u64, outlen: usize) -> BlobAuthenticator {
+        let key = &self.generation(version).blob_authentication_key;
+        BlobAuthenticator(Provider::keyed_hash_state(
+            outlen,
+            key.unsecure(),
+            BLOB_AUTHENTICATION_SALT,
+            &HAT_PERSONALIZATION[..],
+        ))
     }
 
-    pub fn symmetric_unlock(key: &[u8], ciphertext: &[u8], ad: &[u8], nonce: &[u8]) -> Vec<u8> {
-        let mut out = vec![
-            0u8;
-            ciphertext.len()
-                - libsodium_sys::crypto_aead_chacha20poly1305_ABYTES as usize
-        ];
-        let mut out_len = 0;
-
-        let ret = unsafe {
-            libsodium_sys::crypto_aead_chacha20poly1305_decrypt(
-                out.as_mut_ptr(),
-                &mut out_len,
-                [0u8; 0].as_mut_ptr(),
-                ciphertext.as_ptr(),
-                ciphertext.len() as u64,
-                ad.as_ptr(),
-                ad.len() as u64,
-                nonce.as_ptr(),
-                key.as_ptr(),
-            )
-        };
-        assert_eq!(0, ret);
-        assert_eq!(out_len, out.len() as u64);
+    pub fn symmetric_lock(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8> {
+        Provider::aead_encrypt(msg, ad, nonce, key)
+    }
 
-        out
+    pub fn symmetric_unlock(
+        key: &[u8],
+        ciphertext: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+    ) -> Option<Vec<u8>> {
+        Provider::aead_decrypt(ciphertext, ad, nonce, key)
     }
 }
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use blob::Key;
+use blob::{self, Compression, Key};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 pub use errors::CryptoError;
 use hash::tree::HashRef;
@@ -20,6 +20,9 @@ use std::io;
 use std::mem;
 
 pub mod keys;
+pub mod provider;
+#[cfg(test)]
+mod tests;
 
 pub struct PlainText(Vec<u8>);
 pub struct PlainTextRef<'a>(&'a [u8]);
@@ -32,13 +35,12 @@ pub struct CipherTextRef<'a>(&'a [u8]);
 
 pub mod authed {
     pub mod desc {
-        use libsodium_sys;
+        use crypto::provider;
         use secstr;
 
-        pub const KEYBYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_KEYBYTES as usize;
-        pub const NONCEBYTES: usize =
-            libsodium_sys::crypto_aead_chacha20poly1305_NPUBBYTES as usize;
-        pub const MACBYTES: usize = libsodium_sys::crypto_aead_chacha20poly1305_ABYTES as usize;
+        pub const KEYBYTES: usize = provider::AEAD_KEYBYTES;
+        pub const NONCEBYTES: usize = provider::AEAD_NONCEBYTES;
+        pub const MACBYTES: usize = provider::AEAD_ABYTES;
         pub type Key = secstr::SecStr;
         pub type Nonce = secstr::SecStr;
     }
@@ -57,7 +59,7 @@ pub mod authed {
             access_key: &super::desc::Key,
             other_key: &super::desc::Key,
         ) -> super::desc::Key {
-            let mut mixed_key = [0u8; super::hash::DIGESTBYTES as usize];
+            let mut mixed_key = [0u8; super::hash::DIGESTBYTES];
             let salt: &[u8; 16] = b"mixkey~~mixkey~~";
             ::crypto::keys::keyed_fingerprint(
                 &access_key.unsecure()[..],
@@ -70,15 +72,15 @@ pub mod authed {
     }
 
     pub mod hash {
-        pub use libsodium_sys::crypto_generichash_blake2b_BYTES_MAX as DIGESTBYTES;
+        pub use crypto::provider::HASH_BYTES_MAX as DIGESTBYTES;
     }
 }
 
 pub mod sealed {
     pub mod desc {
-        use libsodium_sys;
+        use crypto::provider;
 
-        pub const SEALBYTES: usize = libsodium_sys::crypto_box_SEALBYTES as usize;
+        pub const SEALBYTES: usize = provider::BOX_SEALBYTES;
 
         pub fn symmetric_seal_bytes() -> usize {
             // Mac and nonce from inner symmetric seal
@@ -217,22 +219,12 @@ impl CipherText {
         }
     }
     pub fn random_pad(size: usize) -> CipherText {
-        use libsodium_sys::{crypto_stream_chacha20, crypto_stream_chacha20_KEYBYTES,
-                            crypto_stream_chacha20_NONCEBYTES};
+        use crypto::provider::{self, CryptoProvider, Provider};
 
-        let key = keys::random_bytes(crypto_stream_chacha20_KEYBYTES as usize);
-        let nonce = keys::random_bytes(crypto_stream_chacha20_NONCEBYTES as usize);
+        let key = keys::random_bytes(provider::STREAM_KEYBYTES);
+        let nonce = keys::random_bytes(provider::STREAM_NONCEBYTES);
         let mut stream = vec![0u8; size];
-
-        let ret = unsafe {
-            crypto_stream_chacha20(
-                stream.as_mut_ptr(),
-                stream.len() as u64,
-                nonce.unsecure().as_ptr(),
-                key.unsecure().as_ptr(),
-            )
-        };
-        assert_eq!(0, ret);
+        Provider::stream(&mut stream[..], nonce.unsecure(), key.unsecure());
 
         CipherText::new(stream)
     }
@@ -244,6 +236,14 @@ impl CipherText {
         }
         out
     }
+    /// Like `to_vec`, but moves a single chunk out instead of copying it.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        if self.chunks.len() == 1 {
+            self.chunks.pop().unwrap()
+        } else {
+            self.to_vec()
+        }
+    }
 
     pub fn append_authentication(&mut self, keys: &keys::Keeper) {
         self.collapse();
@@ -251,7 +251,7 @@ impl CipherText {
 
         let blob = &mut self.chunks[0];
         let blob_len = blob.len();
-        blob.resize(blob_len + authed::hash::DIGESTBYTES as usize, 0u8);
+        blob.resize(blob_len + authed::hash::DIGESTBYTES, 0u8);
         {
             let (data, hash) = blob.split_at_mut(blob_len);
             keys.blob_authentication(&data[..], &mut hash[..]);
@@ -299,24 +299,28 @@ impl<'a> CipherTextRef<'a> {
         nonce: &authed::desc::Nonce,
         key: &authed::desc::Key,
     ) -> Result<PlainText, CryptoError> {
-        Ok(PlainText::new(keys::Keeper::symmetric_unlock(
+        match keys::Keeper::symmetric_unlock(
             key.unsecure(),
             &self.0,
             additional_data,
             nonce.unsecure(),
-        )))
+        ) {
+            Some(pt) => Ok(PlainText::new(pt)),
+            None => Err(From::from("crypto read failed: to_plaintext")),
+        }
     }
 
-    pub fn strip_authentication(&self, keys: &keys::Keeper) -> Result<CipherTextRef, CryptoError> {
-        let (rest, want) = self.split_from_right(authed::hash::DIGESTBYTES as usize)?;
-
-        let mut got = vec![0u8; authed::hash::DIGESTBYTES as usize];
-        keys.blob_authentication(&rest.0[..], &mut got[..]);
+    /// Check and strip the authentication tag of a blob. Returns the rest of the blob and the
+    /// version of the keys it was written with.
+    pub fn strip_authentication(
+        &self,
+        keys: &keys::Keeper,
+    ) -> Result<(CipherTextRef<'a>, u64), CryptoError> {
+        let (rest, want) = self.split_from_right(authed::hash::DIGESTBYTES)?;
 
-        if want.0 == &got[..] {
-            Ok(rest)
-        } else {
-            Err(From::from("crypto read failed: strip_authentication"))
+        match keys.authenticating_version(rest.0, want.0) {
+            Some(version) => Ok((rest, version)),
+            None => Err(From::from("crypto read failed: strip_authentication")),
         }
     }
 }
@@ -324,11 +328,26 @@ impl<'a> CipherTextRef<'a> {
 pub struct RefKey {}
 
 impl RefKey {
+    /// Seal the chunk `pt` referenced by `href`, compressed with `compression` if that makes it
+    /// smaller. The packing used is recorded in `href`.
     pub fn seal(
         href: &mut HashRef,
         access_key: &::crypto::authed::desc::Key,
         pt: PlainTextRef,
+        compression: Compression,
     ) -> CipherText {
+        let packed = compression.pack(pt.0);
+        let pt = match packed {
+            Some((packing, ref data)) => {
+                href.persistent_ref.packing = Some(packing);
+                PlainTextRef::new(&data[..])
+            }
+            None => {
+                href.persistent_ref.packing = None;
+                pt
+            }
+        };
+
         let partial_key = authed::imp::gen_key();
         href.persistent_ref.key = Some(wrap_key(partial_key.clone()));
 
@@ -352,6 +371,16 @@ impl RefKey {
             href.persistent_ref.offset,
             href.persistent_ref.offset + href.persistent_ref.length,
         );
+        RefKey::unseal_chunk(access_key, href, ct)
+    }
+
+    /// Unseal the chunk referenced by `href`, given only its own cipher text.
+    pub fn unseal_chunk(
+        access_key: &::crypto::authed::desc::Key,
+        href: &HashRef,
+        ct: CipherTextRef,
+    ) -> Result<PlainText, CryptoError> {
+        assert_eq!(ct.len(), href.persistent_ref.length);
         match href.persistent_ref.key {
             Some(Key::AeadChacha20Poly1305(ref key))
                 if href.hash.bytes.len() >= authed::desc::NONCEBYTES =>
@@ -360,7 +389,9 @@ impl RefKey {
 
                 let additional_data = keys::compute_salt(href.node, href.leaf);
                 let real_key = ::crypto::authed::imp::mix_keys(access_key, &key);
-                Ok(ct.to_plaintext(&additional_data, &nonce, &real_key)?)
+                let pt = ct.to_plaintext(&additional_data, &nonce, &real_key)?;
+                let packing = href.persistent_ref.packing.as_ref();
+                Ok(PlainText::new(blob::unpack(packing, pt.into_vec())?))
             }
             _ => Err("crypto read failed: unseal".into()),
         }
@@ -369,11 +400,21 @@ impl RefKey {
 
 pub struct FixedKey<'k> {
     keeper: &'k keys::Keeper,
+    key_version: u64,
 }
 
 impl<'k> FixedKey<'k> {
     pub fn new(keeper: &'k keys::Keeper) -> FixedKey<'k> {
-        FixedKey { keeper: keeper }
+        FixedKey {
+            keeper: keeper,
+            key_version: keeper.key_version(),
+        }
+    }
+
+    /// Unseal with the keys of version `key_version` rather than the current ones.
+    pub fn with_key_version(mut self, key_version: u64) -> FixedKey<'k> {
+        self.key_version = key_version;
+        self
     }
 
     pub fn seal_blob_name(&self, pt: PlainTextRef) -> CipherText {
@@ -389,7 +430,7 @@ impl<'k> FixedKey<'k> {
     }
 
     pub fn unseal_blob_data(&self, ct: CipherTextRef) -> PlainText {
-        PlainText::new(self.keeper.data_unlock(ct.0))
+        PlainText::new(self.keeper.data_unlock(self.key_version, ct.0))
     }
 
     pub fn seal_blob_access(&self, pt: PlainTextRef) -> CipherText {
@@ -397,7 +438,7 @@ impl<'k> FixedKey<'k> {
     }
 
     pub fn unseal_blob_access(&self, ct: CipherTextRef) -> PlainText {
-        PlainText::new(self.keeper.access_unlock(ct.0))
+        PlainText::new(self.keeper.access_unlock(self.key_version, ct.0))
     }
 
     pub fn new_access_partial_key() -> ::crypto::authed::desc::Key {
@@ -457,11 +498,16 @@ impl<'k> FixedKey<'k> {
         ))
     }
 
-    pub fn unseal<'a>(
+    /// Length of the sealed cipher text described by `footer_ct`, which precedes the access
+    /// footer, and the key to unseal it.
+    pub fn sealed_len(&self, footer_ct: CipherTextRef) -> Result<usize, CryptoError> {
+        self.unseal_footer(footer_ct).map(|(len, _key)| len)
+    }
+
+    fn unseal_footer(
         &self,
         footer_ct: CipherTextRef,
-        ct: CipherTextRef<'a>,
-    ) -> Result<(CipherTextRef<'a>, PlainText), CryptoError> {
+    ) -> Result<(usize, ::crypto::authed::desc::Key), CryptoError> {
         assert_eq!(footer_ct.len(), sealed::desc::footer_cipher_bytes());
         let foot_pt = self.unseal_blob_data(footer_ct);
         assert_eq!(foot_pt.len(), sealed::desc::footer_plain_bytes());
@@ -477,16 +523,29 @@ impl<'k> FixedKey<'k> {
             .map_err(|_| "crypto read failed: unseal")?;
         assert!(ct_len > 0);
 
+        Ok((
+            ct_len as usize,
+            ::crypto::authed::desc::Key::from(inner_key.0),
+        ))
+    }
+
+    pub fn unseal<'a>(
+        &self,
+        footer_ct: CipherTextRef,
+        ct: CipherTextRef<'a>,
+    ) -> Result<(CipherTextRef<'a>, PlainText), CryptoError> {
+        let (ct_len, inner_key) = self.unseal_footer(footer_ct)?;
+
         // Read and unseal inner symmetric cipher text.
         let additional_data: &[u8] = b"hat_blob_seal~";
-        let (rest, ct_and_nonce) = ct.split_from_right(ct_len as usize)?;
+        let (rest, ct_and_nonce) = ct.split_from_right(ct_len)?;
         let (ct, nonce) = ct_and_nonce.split_from_right(::crypto::authed::desc::NONCEBYTES)?;
         Ok((
             rest,
             ct.to_plaintext(
                 additional_data,
                 &::crypto::authed::desc::Nonce::from(nonce.0),
-                &::crypto::authed::desc::Key::from(inner_key.0),
+                &inner_key,
             )?,
         ))
     }
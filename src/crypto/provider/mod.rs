@@ -0,0 +1,98 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cryptographic primitives, implemented by a provider selected at compile time.
+//!
+//! The `sodium` feature (default) uses libsodium. The `rust-crypto` feature uses pure-Rust
+//! implementations of the exact same constructions, and takes precedence when enabled. Both
+//! produce identical output, so a repository can be read with either of them.
+
+#[cfg(feature = "rust-crypto")]
+pub mod rust;
+#[cfg(feature = "sodium")]
+pub mod sodium;
+
+#[cfg(test)]
+mod tests;
+
+#[cfg(feature = "rust-crypto")]
+pub use self::rust::RustCrypto as Provider;
+#[cfg(all(feature = "sodium", not(feature = "rust-crypto")))]
+pub use self::sodium::Sodium as Provider;
+
+#[cfg(not(any(feature = "sodium", feature = "rust-crypto")))]
+compile_error!("hat needs a crypto provider: enable either `sodium` or `rust-crypto`");
+
+// Sizes of the constructions below (these match the libsodium constants).
+
+/// ChaCha20-Poly1305 (original construction with 64-bit nonce).
+pub const AEAD_KEYBYTES: usize = 32;
+pub const AEAD_NONCEBYTES: usize = 8;
+pub const AEAD_ABYTES: usize = 16;
+
+/// BLAKE2b with key, salt and personalization.
+pub const HASH_BYTES_MAX: usize = 64;
+pub const HASH_SALTBYTES: usize = 16;
+pub const HASH_PERSONALBYTES: usize = 16;
+
+/// X25519 key pairs and anonymous sealed boxes (X25519-XSalsa20-Poly1305).
+pub const BOX_KEYBYTES: usize = 32;
+pub const BOX_SEALBYTES: usize = 48;
+
+/// ChaCha20 keystream with 64-bit nonce.
+pub const STREAM_KEYBYTES: usize = 32;
+pub const STREAM_NONCEBYTES: usize = 8;
+
+/// Incremental keyed BLAKE2b, for input that is too large to hash in one go.
+pub trait KeyedHashState {
+    fn update(&mut self, msg: &[u8]);
+
+    /// Write the hash of all input so far to `out`, which must have the length given on creation.
+    fn finalize(self, out: &mut [u8]);
+}
+
+pub trait CryptoProvider {
+    type HashState: KeyedHashState;
+
+    /// Prepare the provider for use. Called once before any other function.
+    fn init();
+
+    /// Fill `out` with cryptographically secure random bytes.
+    fn random_bytes(out: &mut [u8]);
+
+    /// Keyed BLAKE2b of `msg` with output length `out.len()`.
+    fn keyed_hash(out: &mut [u8], msg: &[u8], key: &[u8], salt: &[u8], personal: &[u8]);
+
+    /// Incremental form of `keyed_hash` with output length `outlen`.
+    fn keyed_hash_state(outlen: usize, key: &[u8], salt: &[u8], personal: &[u8])
+        -> Self::HashState;
+
+    /// Deterministically derive an X25519 key pair from a 32-byte `seed`.
+    fn box_seed_keypair(seed: &[u8], pk: &mut [u8], sk: &mut [u8]);
+
+    /// Encrypt `msg` anonymously for the holder of the secret key matching `pk`.
+    fn box_seal(msg: &[u8], pk: &[u8]) -> Vec<u8>;
+
+    /// Open a sealed box. Returns `None` if it was not sealed for this key pair.
+    fn box_seal_open(ciphertext: &[u8], pk: &[u8], sk: &[u8]) -> Option<Vec<u8>>;
+
+    /// Authenticated encryption of `msg` and `ad`. The MAC is appended to the ciphertext.
+    fn aead_encrypt(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8>;
+
+    /// Authenticated decryption. Returns `None` if authentication fails.
+    fn aead_decrypt(ciphertext: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Fill `out` with ChaCha20 keystream.
+    fn stream(out: &mut [u8], nonce: &[u8], key: &[u8]);
+}
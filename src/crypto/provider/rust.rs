@@ -0,0 +1,170 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pure-Rust crypto provider, compatible with the libsodium constructions.
+
+use super::*;
+use blake2b_simd;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20Legacy;
+use crypto_box;
+use crypto_box::aead::rand_core::RngCore;
+use crypto_box::aead::OsRng;
+use poly1305::universal_hash::KeyInit;
+use poly1305::Poly1305;
+use sha2::{Digest, Sha512};
+
+pub struct RustCrypto;
+
+pub struct RustHashState(blake2b_simd::State);
+
+impl KeyedHashState for RustHashState {
+    fn update(&mut self, msg: &[u8]) {
+        self.0.update(msg);
+    }
+
+    fn finalize(self, out: &mut [u8]) {
+        out.copy_from_slice(self.0.finalize().as_bytes());
+    }
+}
+
+fn box_key(bytes: &[u8]) -> [u8; BOX_KEYBYTES] {
+    assert_eq!(BOX_KEYBYTES, bytes.len());
+    let mut key = [0u8; BOX_KEYBYTES];
+    key.copy_from_slice(bytes);
+    key
+}
+
+/// Keystream cipher and Poly1305 key for the original ChaCha20-Poly1305 construction.
+fn aead_init(nonce: &[u8], key: &[u8]) -> (ChaCha20Legacy, Poly1305) {
+    assert_eq!(AEAD_NONCEBYTES, nonce.len());
+    assert_eq!(AEAD_KEYBYTES, key.len());
+
+    let mut cipher = ChaCha20Legacy::new(key.into(), nonce.into());
+
+    // The first block of keystream is the one-time Poly1305 key; data starts at block 1.
+    let mut block = [0u8; 64];
+    cipher.apply_keystream(&mut block);
+    let mac = Poly1305::new((&block[..32]).into());
+
+    (cipher, mac)
+}
+
+fn aead_tag(mac: Poly1305, ad: &[u8], ciphertext: &[u8]) -> poly1305::Tag {
+    let mut data = Vec::with_capacity(ad.len() + ciphertext.len() + 16);
+    data.extend_from_slice(ad);
+    data.extend_from_slice(&(ad.len() as u64).to_le_bytes());
+    data.extend_from_slice(ciphertext);
+    data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    mac.compute_unpadded(&data[..])
+}
+
+fn hash_params(outlen: usize, key: &[u8], salt: &[u8], personal: &[u8]) -> blake2b_simd::Params {
+    assert_eq!(HASH_SALTBYTES, salt.len());
+    assert_eq!(HASH_PERSONALBYTES, personal.len());
+
+    let mut params = blake2b_simd::Params::new();
+    params
+        .hash_length(outlen)
+        .key(key)
+        .salt(salt)
+        .personal(personal);
+    params
+}
+
+impl CryptoProvider for RustCrypto {
+    type HashState = RustHashState;
+
+    fn init() {}
+
+    fn random_bytes(out: &mut [u8]) {
+        OsRng.fill_bytes(out);
+    }
+
+    fn keyed_hash(out: &mut [u8], msg: &[u8], key: &[u8], salt: &[u8], personal: &[u8]) {
+        let hash = hash_params(out.len(), key, salt, personal).hash(msg);
+        out.copy_from_slice(hash.as_bytes());
+    }
+
+    fn keyed_hash_state(
+        outlen: usize,
+        key: &[u8],
+        salt: &[u8],
+        personal: &[u8],
+    ) -> RustHashState {
+        RustHashState(hash_params(outlen, key, salt, personal).to_state())
+    }
+
+    fn box_seed_keypair(seed: &[u8], pk: &mut [u8], sk: &mut [u8]) {
+        assert_eq!(BOX_KEYBYTES, seed.len());
+
+        // Same derivation as libsodium: the secret key is the truncated SHA-512 of the seed.
+        let digest = Sha512::digest(seed);
+        let secret = crypto_box::SecretKey::from(box_key(&digest[..BOX_KEYBYTES]));
+        sk.copy_from_slice(&secret.to_bytes()[..]);
+        pk.copy_from_slice(secret.public_key().as_bytes());
+    }
+
+    fn box_seal(msg: &[u8], pk: &[u8]) -> Vec<u8> {
+        crypto_box::PublicKey::from(box_key(pk))
+            .seal(&mut OsRng, msg)
+            .expect("sealing failed")
+    }
+
+    fn box_seal_open(ciphertext: &[u8], pk: &[u8], sk: &[u8]) -> Option<Vec<u8>> {
+        let secret = crypto_box::SecretKey::from(box_key(sk));
+        assert_eq!(secret.public_key().as_bytes(), &box_key(pk));
+        secret.unseal(ciphertext).ok()
+    }
+
+    fn aead_encrypt(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8> {
+        let (mut cipher, mac) = aead_init(nonce, key);
+
+        let mut out = msg.to_vec();
+        cipher.apply_keystream(&mut out[..]);
+        let tag = aead_tag(mac, ad, &out[..]);
+        out.extend_from_slice(&tag[..]);
+
+        out
+    }
+
+    fn aead_decrypt(ciphertext: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < AEAD_ABYTES {
+            return None;
+        }
+        let (ct, want) = ciphertext.split_at(ciphertext.len() - AEAD_ABYTES);
+        let (mut cipher, mac) = aead_init(nonce, key);
+
+        // Compare in constant time.
+        let got = aead_tag(mac, ad, ct);
+        let diff = got.iter().zip(want).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return None;
+        }
+
+        let mut out = ct.to_vec();
+        cipher.apply_keystream(&mut out[..]);
+        Some(out)
+    }
+
+    fn stream(out: &mut [u8], nonce: &[u8], key: &[u8]) {
+        assert_eq!(STREAM_NONCEBYTES, nonce.len());
+        assert_eq!(STREAM_KEYBYTES, key.len());
+
+        for b in out.iter_mut() {
+            *b = 0;
+        }
+        ChaCha20Legacy::new(key.into(), nonce.into()).apply_keystream(out);
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crypto provider backed by libsodium.
+
+use super::*;
+use libsodium_sys;
+use std::os::raw::c_void;
+
+pub struct Sodium;
+
+pub struct SodiumHashState {
+    // Boxed to keep the 64-byte alignment libsodium requires.
+    state: Box<libsodium_sys::crypto_generichash_blake2b_state>,
+    outlen: usize,
+}
+
+impl KeyedHashState for SodiumHashState {
+    fn update(&mut self, msg: &[u8]) {
+        let ret = unsafe {
+            libsodium_sys::crypto_generichash_blake2b_update(
+                &mut *self.state,
+                msg.as_ptr(),
+                msg.len() as u64,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    fn finalize(mut self, out: &mut [u8]) {
+        assert_eq!(self.outlen, out.len());
+        let ret = unsafe {
+            libsodium_sys::crypto_generichash_blake2b_final(
+                &mut *self.state,
+                out.as_mut_ptr(),
+                self.outlen,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+}
+
+impl CryptoProvider for Sodium {
+    type HashState = SodiumHashState;
+
+    fn init() {
+        let ret = unsafe { libsodium_sys::sodium_init() };
+        assert!(ret >= 0, "libsodium failed to initialize");
+
+        assert_eq!(
+            AEAD_KEYBYTES,
+            libsodium_sys::crypto_aead_chacha20poly1305_KEYBYTES as usize
+        );
+        assert_eq!(
+            AEAD_NONCEBYTES,
+            libsodium_sys::crypto_aead_chacha20poly1305_NPUBBYTES as usize
+        );
+        assert_eq!(
+            AEAD_ABYTES,
+            libsodium_sys::crypto_aead_chacha20poly1305_ABYTES as usize
+        );
+        assert_eq!(
+            HASH_BYTES_MAX,
+            libsodium_sys::crypto_generichash_blake2b_BYTES_MAX as usize
+        );
+        assert_eq!(
+            HASH_SALTBYTES,
+            libsodium_sys::crypto_generichash_blake2b_SALTBYTES as usize
+        );
+        assert_eq!(
+            HASH_PERSONALBYTES,
+            libsodium_sys::crypto_generichash_blake2b_PERSONALBYTES as usize
+        );
+        assert_eq!(BOX_SEALBYTES, libsodium_sys::crypto_box_SEALBYTES as usize);
+        assert_eq!(
+            STREAM_KEYBYTES,
+            libsodium_sys::crypto_stream_chacha20_KEYBYTES as usize
+        );
+        assert_eq!(
+            STREAM_NONCEBYTES,
+            libsodium_sys::crypto_stream_chacha20_NONCEBYTES as usize
+        );
+    }
+
+    fn random_bytes(out: &mut [u8]) {
+        unsafe { libsodium_sys::randombytes_buf(out.as_mut_ptr() as *mut c_void, out.len()) };
+    }
+
+    fn keyed_hash(out: &mut [u8], msg: &[u8], key: &[u8], salt: &[u8], personal: &[u8]) {
+        assert_eq!(HASH_SALTBYTES, salt.len());
+        assert_eq!(HASH_PERSONALBYTES, personal.len());
+
+        let outlen = out.len();
+        let ret = unsafe {
+            libsodium_sys::crypto_generichash_blake2b_salt_personal(
+                out.as_mut_ptr(),
+                outlen,
+                msg.as_ptr(),
+                msg.len() as u64,
+                key.as_ptr(),
+                key.len(),
+                salt.as_ptr(),
+                personal.as_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    fn keyed_hash_state(
+        outlen: usize,
+        key: &[u8],
+        salt: &[u8],
+        personal: &[u8],
+    ) -> SodiumHashState {
+        assert_eq!(HASH_SALTBYTES, salt.len());
+        assert_eq!(HASH_PERSONALBYTES, personal.len());
+
+        let mut state = SodiumHashState {
+            state: Box::new(libsodium_sys::crypto_generichash_blake2b_state { opaque: [0; 384] }),
+            outlen: outlen,
+        };
+        let ret = unsafe {
+            libsodium_sys::crypto_generichash_blake2b_init_salt_personal(
+                &mut *state.state,
+                key.as_ptr(),
+                key.len(),
+                outlen,
+                salt.as_ptr(),
+                personal.as_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+        state
+    }
+
+    fn box_seed_keypair(seed: &[u8], pk: &mut [u8], sk: &mut [u8]) {
+        assert_eq!(BOX_KEYBYTES, seed.len());
+        assert_eq!(BOX_KEYBYTES, pk.len());
+        assert_eq!(BOX_KEYBYTES, sk.len());
+
+        let ret = unsafe {
+            libsodium_sys::crypto_box_seed_keypair(
+                pk.as_mut_ptr(),
+                sk.as_mut_ptr(),
+                seed.as_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    fn box_seal(msg: &[u8], pk: &[u8]) -> Vec<u8> {
+        assert_eq!(BOX_KEYBYTES, pk.len());
+
+        let mut out = vec![0; msg.len() + BOX_SEALBYTES];
+        let ret = unsafe {
+            libsodium_sys::crypto_box_seal(
+                out.as_mut_ptr(),
+                msg.as_ptr(),
+                msg.len() as u64,
+                pk.as_ptr(),
+            )
+        };
+        assert_eq!(0, ret);
+
+        out
+    }
+
+    fn box_seal_open(ciphertext: &[u8], pk: &[u8], sk: &[u8]) -> Option<Vec<u8>> {
+        assert_eq!(BOX_KEYBYTES, pk.len());
+        assert_eq!(BOX_KEYBYTES, sk.len());
+        if ciphertext.len() < BOX_SEALBYTES {
+            return None;
+        }
+
+        let mut out = vec![0; ciphertext.len() - BOX_SEALBYTES];
+        let ret = unsafe {
+            libsodium_sys::crypto_box_seal_open(
+                out.as_mut_ptr(),
+                ciphertext.as_ptr(),
+                ciphertext.len() as u64,
+                pk.as_ptr(),
+                sk.as_ptr(),
+            )
+        };
+
+        if ret == 0 {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    fn aead_encrypt(msg: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Vec<u8> {
+        assert_eq!(AEAD_NONCEBYTES, nonce.len());
+        assert_eq!(AEAD_KEYBYTES, key.len());
+
+        let mut out = vec![0u8; msg.len() + AEAD_ABYTES];
+        let mut out_len = 0;
+
+        let ret = unsafe {
+            libsodium_sys::crypto_aead_chacha20poly1305_encrypt(
+                out.as_mut_ptr(),
+                &mut out_len,
+                msg.as_ptr(),
+                msg.len() as u64,
+                ad.as_ptr(),
+                ad.len() as u64,
+                [0u8; 0].as_ptr(),
+                nonce.as_ptr(),
+                key.as_ptr(),
+            )
+        };
+        assert_eq!(0, ret);
+        assert_eq!(out_len, out.len() as u64);
+
+        out
+    }
+
+    fn aead_decrypt(ciphertext: &[u8], ad: &[u8], nonce: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+        assert_eq!(AEAD_NONCEBYTES, nonce.len());
+        assert_eq!(AEAD_KEYBYTES, key.len());
+        if ciphertext.len() < AEAD_ABYTES {
+            return None;
+        }
+
+        let mut out = vec![0u8; ciphertext.len() - AEAD_ABYTES];
+        let mut out_len = 0;
+
+        let ret = unsafe {
+            libsodium_sys::crypto_aead_chacha20poly1305_decrypt(
+                out.as_mut_ptr(),
+                &mut out_len,
+                [0u8; 0].as_mut_ptr(),
+                ciphertext.as_ptr(),
+                ciphertext.len() as u64,
+                ad.as_ptr(),
+                ad.len() as u64,
+                nonce.as_ptr(),
+                key.as_ptr(),
+            )
+        };
+
+        if ret == 0 {
+            assert_eq!(out_len, out.len() as u64);
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    fn stream(out: &mut [u8], nonce: &[u8], key: &[u8]) {
+        assert_eq!(STREAM_NONCEBYTES, nonce.len());
+        assert_eq!(STREAM_KEYBYTES, key.len());
+
+        let ret = unsafe {
+            libsodium_sys::crypto_stream_chacha20(
+                out.as_mut_ptr(),
+                out.len() as u64,
+                nonce.as_ptr(),
+                key.as_ptr(),
+            )
+        };
+        assert_eq!(0, ret);
+    }
+}
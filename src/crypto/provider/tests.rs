@@ -0,0 +1,162 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crypto::provider::*;
+use quickcheck;
+
+fn keypair<P: CryptoProvider>(seed: u8) -> (Vec<u8>, Vec<u8>) {
+    let mut pk = vec![0u8; BOX_KEYBYTES];
+    let mut sk = vec![0u8; BOX_KEYBYTES];
+    P::box_seed_keypair(&[seed; BOX_KEYBYTES], &mut pk[..], &mut sk[..]);
+    (pk, sk)
+}
+
+#[test]
+fn aead_identity() {
+    fn prop(msg: Vec<u8>, ad: Vec<u8>) -> bool {
+        Provider::init();
+        let key = [7u8; AEAD_KEYBYTES];
+        let nonce = [3u8; AEAD_NONCEBYTES];
+
+        let mut ct = Provider::aead_encrypt(&msg[..], &ad[..], &nonce, &key);
+        assert_eq!(ct.len(), msg.len() + AEAD_ABYTES);
+        assert_eq!(
+            Provider::aead_decrypt(&ct[..], &ad[..], &nonce, &key),
+            Some(msg)
+        );
+
+        // Any modification is detected.
+        ct[0] ^= 1;
+        Provider::aead_decrypt(&ct[..], &ad[..], &nonce, &key).is_none()
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>, Vec<u8>) -> bool);
+}
+
+#[test]
+fn seal_identity() {
+    fn prop(msg: Vec<u8>) -> bool {
+        Provider::init();
+        let (pk, sk) = keypair::<Provider>(1);
+        let (other_pk, other_sk) = keypair::<Provider>(2);
+
+        let ct = Provider::box_seal(&msg[..], &pk[..]);
+        assert_eq!(ct.len(), msg.len() + BOX_SEALBYTES);
+        assert!(Provider::box_seal_open(&ct[..], &other_pk[..], &other_sk[..]).is_none());
+        Provider::box_seal_open(&ct[..], &pk[..], &sk[..]) == Some(msg)
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+#[test]
+fn keyed_hash_state() {
+    fn prop(msg: Vec<u8>, split: usize) -> bool {
+        Provider::init();
+        let split = if msg.is_empty() { 0 } else { split % msg.len() };
+        let salt = [1u8; HASH_SALTBYTES];
+        let personal = [2u8; HASH_PERSONALBYTES];
+
+        let mut want = vec![0u8; HASH_BYTES_MAX];
+        Provider::keyed_hash(&mut want[..], &msg[..], b"key", &salt, &personal);
+
+        let mut state = Provider::keyed_hash_state(HASH_BYTES_MAX, b"key", &salt, &personal);
+        state.update(&msg[..split]);
+        state.update(&msg[split..]);
+        let mut got = vec![0u8; HASH_BYTES_MAX];
+        state.finalize(&mut got[..]);
+
+        got == want
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>, usize) -> bool);
+}
+
+#[test]
+fn checksum_is_plain_blake2b() {
+    use crypto::keys::checksum;
+    use hex;
+
+    // BLAKE2b-512 of "abc" from RFC 7693, as `b2sum` prints it.
+    assert_eq!(
+        hex::encode(checksum(&[b"a", b"bc"])),
+        "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+         7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+    );
+}
+
+#[cfg(all(feature = "sodium", feature = "rust-crypto"))]
+mod compat {
+    use super::keypair;
+    use crypto::provider::rust::RustCrypto;
+    use crypto::provider::sodium::Sodium;
+    use crypto::provider::*;
+    use quickcheck;
+
+    #[test]
+    fn keyed_hash() {
+        fn prop(msg: Vec<u8>, key: Vec<u8>, len: u8) -> bool {
+            Sodium::init();
+            let key = &key[..key.len().min(64)];
+            let len = 16 + len as usize % (HASH_BYTES_MAX - 15);
+            let salt = [1u8; HASH_SALTBYTES];
+            let personal = [2u8; HASH_PERSONALBYTES];
+
+            let mut a = vec![0u8; len];
+            let mut b = vec![0u8; len];
+            Sodium::keyed_hash(&mut a[..], &msg[..], key, &salt, &personal);
+            RustCrypto::keyed_hash(&mut b[..], &msg[..], key, &salt, &personal);
+            a == b
+        }
+        quickcheck::quickcheck(prop as fn(Vec<u8>, Vec<u8>, u8) -> bool);
+    }
+
+    #[test]
+    fn keypair_and_seal() {
+        fn prop(msg: Vec<u8>, seed: u8) -> bool {
+            Sodium::init();
+            let (pk, sk) = keypair::<Sodium>(seed);
+            assert_eq!((pk.clone(), sk.clone()), keypair::<RustCrypto>(seed));
+
+            let ct = Sodium::box_seal(&msg[..], &pk[..]);
+            assert_eq!(
+                RustCrypto::box_seal_open(&ct[..], &pk[..], &sk[..]),
+                Some(msg.clone())
+            );
+            let ct = RustCrypto::box_seal(&msg[..], &pk[..]);
+            Sodium::box_seal_open(&ct[..], &pk[..], &sk[..]) == Some(msg)
+        }
+        quickcheck::quickcheck(prop as fn(Vec<u8>, u8) -> bool);
+    }
+
+    #[test]
+    fn aead_and_stream() {
+        fn prop(msg: Vec<u8>, ad: Vec<u8>, seed: u8) -> bool {
+            Sodium::init();
+            let key = [seed; AEAD_KEYBYTES];
+            let nonce = [seed ^ 0xff; AEAD_NONCEBYTES];
+
+            let ct = Sodium::aead_encrypt(&msg[..], &ad[..], &nonce, &key);
+            assert_eq!(ct, RustCrypto::aead_encrypt(&msg[..], &ad[..], &nonce, &key));
+            assert_eq!(
+                RustCrypto::aead_decrypt(&ct[..], &ad[..], &nonce, &key),
+                Some(msg.clone())
+            );
+
+            let mut a = vec![0u8; msg.len()];
+            let mut b = vec![1u8; msg.len()];
+            Sodium::stream(&mut a[..], &nonce, &key);
+            RustCrypto::stream(&mut b[..], &nonce, &key);
+            a == b
+        }
+        quickcheck::quickcheck(prop as fn(Vec<u8>, Vec<u8>, u8) -> bool);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crypto::keys::{self, Keeper, LOCKED_UNIVERSAL_KEY_FILENAME};
+use secstr;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+#[test]
+fn passphrase_protects_universal_key() {
+    let dir = env::temp_dir().join(format!("hat-locked-key-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+
+    Keeper::write_new_universal_key_with_passphrase(&first, b"correct horse").unwrap();
+    assert!(first.join(LOCKED_UNIVERSAL_KEY_FILENAME).exists());
+    assert!(!first.join("secret-universal-key").exists());
+
+    let keys = Keeper::load_with_passphrase(&first, b"correct horse").unwrap();
+    assert!(Keeper::load_with_passphrase(&first, b"wrong horse").is_err());
+
+    // Opening a repository asks for the passphrase.
+    keys::set_passphrase_prompt(Box::new(|_| Ok(secstr::SecStr::from("correct horse"))));
+    let loaded = Keeper::load(&first).unwrap();
+    assert_eq!(loaded.read_only_keys(), keys.read_only_keys());
+
+    // An exported key still needs the passphrase.
+    let exported = dir.join("exported");
+    Keeper::export_universal_key(&first, &exported).unwrap();
+    Keeper::import_universal_key(&exported, &second).unwrap();
+    assert!(!second.join("secret-universal-key").exists());
+    let imported = Keeper::load_with_passphrase(&second, b"correct horse").unwrap();
+    assert_eq!(imported.read_only_keys(), keys.read_only_keys());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn invalid_key_version_is_rejected() {
+    let dir = env::temp_dir().join(format!("hat-key-version-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    assert_eq!(Keeper::read_key_version(&dir).unwrap(), keys::KEY_VERSION);
+    Keeper::write_key_version(&dir, 3).unwrap();
+    assert_eq!(Keeper::read_key_version(&dir).unwrap(), 3);
+    for text in &["0\n", "-1\n", "three\n"] {
+        fs::write(dir.join(keys::KEY_VERSION_FILENAME), text).unwrap();
+        let err = Keeper::read_key_version(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
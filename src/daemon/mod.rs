@@ -0,0 +1,196 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for running Hat as a long-lived service.
+//!
+//! Implements the parts of the systemd service protocol we need without linking libsystemd:
+//! readiness and status notifications (`sd_notify`), watchdog keep-alive pings and a shutdown
+//! flag raised by SIGTERM/SIGINT. Outside of systemd, notifications are silently skipped.
+
+use libc;
+use std::env;
+use std::io;
+use std::mem;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod notification;
+mod schedule;
+#[cfg(test)]
+mod tests;
+
+pub use self::notification::{CommitReport, Notifier};
+pub use self::schedule::{Job, PreemptTimer, Priority, Schedule};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    // Only async-signal-safe work is allowed here.
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Turn SIGTERM and SIGINT into a shutdown request instead of killing the process.
+pub fn install_shutdown_handler() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Whether a shutdown has been requested.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, waking up early if a shutdown is requested.
+/// Returns false if woken by a shutdown request.
+pub fn sleep_unless_shutdown(duration: Duration) -> bool {
+    let step = Duration::from_millis(250);
+    let mut left = duration;
+    while left > Duration::from_millis(0) {
+        if shutdown_requested() {
+            return false;
+        }
+        let next = if left < step { left } else { step };
+        thread::sleep(next);
+        left -= next;
+    }
+    !shutdown_requested()
+}
+
+/// Send `state` (e.g. "READY=1") to the service manager.
+/// Returns Ok(false) when not running under a service manager.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(ref addr) if !addr.is_empty() => notify_socket(addr, state).map(|()| true),
+        _ => Ok(false),
+    }
+}
+
+/// Send `state` to the datagram socket `addr`; a leading '@' denotes an abstract socket.
+pub fn notify_socket(addr: &str, state: &str) -> io::Result<()> {
+    let mut sockaddr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    let path = addr.as_bytes();
+    if path.len() >= sockaddr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "notify socket path too long",
+        ));
+    }
+    for (dst, src) in sockaddr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    if path.first() == Some(&b'@') {
+        sockaddr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &sockaddr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        );
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+        if ret < 0 {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// The watchdog timeout requested by the service manager for this process, if any.
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| parse_watchdog_usec(&usec))
+}
+
+fn parse_watchdog_usec(usec: &str) -> Option<Duration> {
+    match usec.trim().parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Keeps the service manager's watchdog happy while work progresses.
+///
+/// Nothing pings on its own: the daemon calls `ping` from its job loop and its progress
+/// callbacks, so a stuck commit stops the pings and lets the service manager restart us.
+/// Pings closer together than half the watchdog timeout are skipped.
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last: Mutex<Instant>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog::with_interval(watchdog_timeout().map(|timeout| timeout / 2))
+    }
+
+    fn with_interval(interval: Option<Duration>) -> Watchdog {
+        Watchdog {
+            interval: interval,
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// The longest the daemon may go without calling `ping`, if a watchdog is enabled.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Record progress, pinging the watchdog if half its timeout has passed since the last
+    /// ping. Returns whether a ping was due.
+    pub fn ping(&self) -> bool {
+        let interval = match self.interval {
+            None => return false,
+            Some(interval) => interval,
+        };
+        let mut last = self.last.lock().unwrap();
+        if last.elapsed() < interval {
+            return false;
+        }
+        *last = Instant::now();
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("Could not ping watchdog: {}", e);
+        }
+        true
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog::new()
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Success and failure notifications for unattended commits.
+//!
+//! A `CommitReport` is posted as JSON to a webhook (through `curl`) and/or mailed (through
+//! `sendmail`), so failing scheduled backups get noticed. A report that can not be delivered,
+//! because the command is missing or fails, is an error.
+
+use serde_json;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use util::hostname;
+
+/// The outcome of one commit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitReport {
+    pub family: String,
+    pub snapshot_id: Option<u64>,
+    pub started_ts_utc: i64,
+    pub finished_ts_utc: i64,
+    /// Storage added by this commit, according to the blob index.
+    pub uploaded_bytes: u64,
+    /// File data this commit found already stored, e.g. by another family, and did not
+    /// upload again.
+    pub deduplicated_bytes: u64,
+    pub storage_used_bytes: u64,
+    pub error: Option<String>,
+}
+
+impl CommitReport {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "hat: commit of {} on {} {}",
+            self.family,
+            hostname(),
+            if self.succeeded() { "succeeded" } else { "FAILED" }
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "status": if self.succeeded() { "ok" } else { "failed" },
+            "host": hostname(),
+            "family": self.family,
+            "snapshot_id": self.snapshot_id,
+            "started_ts_utc": self.started_ts_utc,
+            "finished_ts_utc": self.finished_ts_utc,
+            "uploaded_bytes": self.uploaded_bytes,
+            "deduplicated_bytes": self.deduplicated_bytes,
+            "storage_used_bytes": self.storage_used_bytes,
+            "error": self.error,
+        }).to_string()
+    }
+}
+
+/// Where to send commit reports. Without a webhook or an email address, nothing is sent.
+pub struct Notifier {
+    pub webhook: Option<String>,
+    pub email: Option<String>,
+    pub only_failures: bool,
+    curl_cmd: String,
+    sendmail_cmd: String,
+}
+
+/// Run `cmd` with `input` on stdin. Fails unless it runs and exits successfully.
+fn run_with_input(name: &str, mut cmd: Command, input: &[u8]) -> Result<(), String> {
+    let mut child = match cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(format!("{} is not installed", name))
+        }
+        Err(e) => return Err(format!("could not run {}: {}", name, e)),
+    };
+    // A command that exits early closes its input; its exit status tells why.
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input);
+    let out = child
+        .wait_with_output()
+        .map_err(|e| format!("could not run {}: {}", name, e))?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    match (out.status.success(), written) {
+        (true, Ok(())) => Ok(()),
+        (true, Err(e)) => Err(format!("could not write to {}: {}", name, e)),
+        (false, _) if stderr.trim().is_empty() => Err(format!("{} failed: {}", name, out.status)),
+        (false, _) => Err(format!("{} failed: {}: {}", name, out.status, stderr.trim())),
+    }
+}
+
+impl Notifier {
+    pub fn new(webhook: Option<String>, email: Option<String>, only_failures: bool) -> Notifier {
+        Notifier::with_commands(webhook, email, only_failures, "curl", "sendmail")
+    }
+
+    /// Use the given commands in place of `curl` and `sendmail`.
+    pub fn with_commands(
+        webhook: Option<String>,
+        email: Option<String>,
+        only_failures: bool,
+        curl_cmd: &str,
+        sendmail_cmd: &str,
+    ) -> Notifier {
+        Notifier {
+            webhook,
+            email,
+            only_failures,
+            curl_cmd: curl_cmd.to_string(),
+            sendmail_cmd: sendmail_cmd.to_string(),
+        }
+    }
+
+    /// Send `report` to every configured destination. All are tried, even if one fails.
+    pub fn send(&self, report: &CommitReport) -> Result<(), String> {
+        if self.only_failures && report.succeeded() {
+            return Ok(());
+        }
+        let json = report.to_json();
+        let mut errors = vec![];
+
+        if let Some(ref url) = self.webhook {
+            let mut cmd = Command::new(&self.curl_cmd);
+            cmd.args(["-fsS", "-m", "30", "-X", "POST"])
+                .args(["-H", "Content-Type: application/json"])
+                .args(["--data-binary", "@-", url.as_str()]);
+            if let Err(e) = run_with_input(&self.curl_cmd, cmd, json.as_bytes()) {
+                errors.push(format!("webhook: {}", e));
+            }
+        }
+
+        if let Some(ref address) = self.email {
+            let mut message = String::new();
+            writeln!(message, "To: {}", address).unwrap();
+            writeln!(message, "Subject: {}", report.subject()).unwrap();
+            writeln!(message, "Content-Type: text/plain; charset=utf-8\n").unwrap();
+            if let Some(ref e) = report.error {
+                writeln!(message, "Error: {}\n", e).unwrap();
+            }
+            writeln!(message, "{}", json).unwrap();
+
+            let mut cmd = Command::new(&self.sendmail_cmd);
+            cmd.arg("-t");
+            if let Err(e) = run_with_input(&self.sendmail_cmd, cmd, message.as_bytes()) {
+                errors.push(format!("email: {}", e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling of several families with different priorities in one daemon.
+//!
+//! High-priority jobs run before any low-priority job that is due. A low-priority commit that
+//! is still running when a high-priority job becomes due is preempted; it runs again once the
+//! high-priority work is done, quickly skipping the files it had already indexed.
+
+use daemon::shutdown_requested;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use util::Preemption;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+impl Priority {
+    /// Seconds between commits unless configured otherwise: hourly for high priority and
+    /// daily for low priority.
+    pub fn default_interval(&self) -> u64 {
+        match *self {
+            Priority::High => 3600,
+            Priority::Low => 24 * 3600,
+        }
+    }
+}
+
+/// A family committed periodically from a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub priority: Priority,
+    pub name: String,
+    pub path: String,
+    pub interval: u64,
+}
+
+impl Job {
+    /// Parse a job given as `PRIORITY:NAME:PATH`, where `PRIORITY` is `high` or `low`.
+    pub fn parse(spec: &str) -> Result<Job, String> {
+        let mut parts = spec.splitn(3, ':');
+        let priority = match parts.next() {
+            Some("high") => Priority::High,
+            Some("low") => Priority::Low,
+            _ => {
+                return Err(format!(
+                    "job '{}' must start with a priority of 'high' or 'low'",
+                    spec
+                ))
+            }
+        };
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(path)) if !name.is_empty() && !path.is_empty() => Ok(Job {
+                priority,
+                name: name.to_owned(),
+                path: path.to_owned(),
+                interval: priority.default_interval(),
+            }),
+            _ => Err(format!("job '{}' must be given as PRIORITY:NAME:PATH", spec)),
+        }
+    }
+}
+
+/// When each job is due next. Times are in seconds, e.g. UTC timestamps.
+pub struct Schedule {
+    jobs: Vec<Job>,
+    due: Vec<i64>,
+}
+
+impl Schedule {
+    /// All jobs are due at `now`.
+    pub fn new(jobs: Vec<Job>, now: i64) -> Schedule {
+        let due = vec![now; jobs.len()];
+        Schedule { jobs, due }
+    }
+
+    pub fn job(&self, index: usize) -> &Job {
+        &self.jobs[index]
+    }
+
+    /// The job to run at `now`: due high-priority jobs first, then the most overdue.
+    /// When no job is due, returns the number of seconds until one is.
+    pub fn next(&self, now: i64) -> Result<usize, u64> {
+        let pick = (0..self.jobs.len())
+            .filter(|&i| self.due[i] <= now)
+            .min_by_key(|&i| (self.jobs[i].priority == Priority::Low, self.due[i]));
+        match pick {
+            Some(i) => Ok(i),
+            None => Err(self.due.iter().map(|&t| (t - now) as u64).min().unwrap_or(0)),
+        }
+    }
+
+    /// When job `index` must give way to a high-priority job, if ever.
+    pub fn preempt_at(&self, index: usize) -> Option<i64> {
+        if self.jobs[index].priority == Priority::High {
+            return None;
+        }
+        (0..self.jobs.len())
+            .filter(|&i| self.jobs[i].priority == Priority::High)
+            .map(|i| self.due[i])
+            .min()
+    }
+
+    /// Job `index` completed (or failed) at `now`; it is due again after its interval.
+    /// A preempted job is not marked done and stays due.
+    pub fn done(&mut self, index: usize, now: i64) {
+        self.due[index] = now + self.jobs[index].interval as i64;
+    }
+}
+
+/// Requests preemption after a delay, or as soon as a shutdown is requested.
+/// The timer stops when dropped.
+pub struct PreemptTimer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PreemptTimer {
+    pub fn start(preemption: Preemption, delay: Option<Duration>) -> PreemptTimer {
+        let (stop, stopped) = mpsc::channel();
+        let deadline = delay.map(|d| Instant::now() + d);
+        let thread = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(Duration::from_millis(250))
+            {
+                if shutdown_requested() || deadline.is_some_and(|d| Instant::now() >= d) {
+                    preemption.request();
+                    return;
+                }
+            }
+        });
+
+        PreemptTimer {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for PreemptTimer {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use daemon::*;
+use serde_json;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use util::Preemption;
+
+#[test]
+fn notify_reaches_socket() {
+    let path = env::temp_dir().join(format!("hat-notify-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+
+    notify_socket(path.to_str().unwrap(), "READY=1\nSTATUS=idle").unwrap();
+
+    let mut buf = [0u8; 64];
+    let n = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"READY=1\nSTATUS=idle");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn notify_missing_socket_fails() {
+    let path = env::temp_dir().join(format!("hat-notify-missing-{}", process::id()));
+    assert!(notify_socket(path.to_str().unwrap(), "READY=1").is_err());
+}
+
+#[test]
+fn watchdog_usec() {
+    assert_eq!(parse_watchdog_usec("0"), None);
+    assert_eq!(parse_watchdog_usec("garbage"), None);
+    assert_eq!(
+        parse_watchdog_usec("30000000"),
+        Some(Duration::from_secs(30))
+    );
+}
+
+#[test]
+fn watchdog_pings_only_when_due() {
+    assert!(!Watchdog::with_interval(None).ping());
+
+    let watchdog = Watchdog::with_interval(Some(Duration::from_millis(50)));
+    assert!(!watchdog.ping());
+    thread::sleep(Duration::from_millis(60));
+    assert!(watchdog.ping());
+    assert!(!watchdog.ping());
+}
+
+#[test]
+fn sleep_without_shutdown() {
+    assert!(sleep_unless_shutdown(Duration::from_millis(10)));
+}
+
+fn report(error: Option<&str>) -> CommitReport {
+    CommitReport {
+        family: "home".to_string(),
+        snapshot_id: if error.is_none() { Some(3) } else { None },
+        started_ts_utc: 100,
+        finished_ts_utc: 160,
+        uploaded_bytes: 4096,
+        deduplicated_bytes: 1024,
+        storage_used_bytes: 8192,
+        error: error.map(|e| e.to_string()),
+    }
+}
+
+#[test]
+fn commit_report_json() {
+    let json: serde_json::Value = serde_json::from_str(&report(None).to_json()).unwrap();
+    assert_eq!(json["status"], "ok");
+    assert!(json["host"].is_string());
+    assert_eq!(json["family"], "home");
+    assert_eq!(json["snapshot_id"], 3);
+    assert_eq!(json["started_ts_utc"], 100);
+    assert_eq!(json["finished_ts_utc"], 160);
+    assert_eq!(json["uploaded_bytes"], 4096);
+    assert_eq!(json["deduplicated_bytes"], 1024);
+    assert_eq!(json["storage_used_bytes"], 8192);
+    assert!(json["error"].is_null());
+
+    let error = "disk \"full\"\n\tsee log\\";
+    let json: serde_json::Value = serde_json::from_str(&report(Some(error)).to_json()).unwrap();
+    assert_eq!(json["status"], "failed");
+    assert!(json["snapshot_id"].is_null());
+    assert_eq!(json["error"], error);
+}
+
+#[test]
+fn notifier_runs_commands() {
+    let dir = env::temp_dir().join(format!("hat-notifier-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // Stand-ins for curl and sendmail that record their arguments and input.
+    let fake = |name: &str| {
+        let script = dir.join(name);
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}.args\ncat > {0}.input\n",
+                script.display()
+            ),
+        ).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script.to_str().unwrap().to_owned()
+    };
+    let (curl, sendmail) = (fake("curl"), fake("sendmail"));
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+
+    let notifier = Notifier::with_commands(
+        Some("https://example.com/hook".to_string()),
+        Some("admin@example.com".to_string()),
+        true,
+        &curl,
+        &sendmail,
+    );
+
+    // Successful commits are skipped when only failures are wanted.
+    notifier.send(&report(None)).unwrap();
+    assert!(!dir.join("curl.input").exists());
+
+    let failed = report(Some("backend unavailable"));
+    notifier.send(&failed).unwrap();
+    assert_eq!(read("curl.input"), failed.to_json());
+    assert!(read("curl.args").trim_end().ends_with("--data-binary @- https://example.com/hook"));
+    assert_eq!(read("sendmail.args").trim_end(), "-t");
+    let mail = read("sendmail.input");
+    assert!(mail.starts_with("To: admin@example.com\nSubject: hat: commit of home on "));
+    assert!(mail.contains("FAILED\n"));
+    assert!(mail.contains(&failed.to_json()));
+
+    // A failing destination is reported, but does not stop the others.
+    let notifier = Notifier::with_commands(
+        Some("https://example.com/hook".to_string()),
+        Some("admin@example.com".to_string()),
+        false,
+        "/nonexistent/curl",
+        &sendmail,
+    );
+    fs::remove_file(dir.join("sendmail.input")).unwrap();
+    let err = notifier.send(&report(None)).unwrap_err();
+    assert_eq!(err, "webhook: /nonexistent/curl is not installed");
+    assert!(read("sendmail.input").contains("succeeded"));
+
+    // So is a command that fails, without reading its input.
+    let failing = dir.join("failing");
+    fs::write(&failing, "#!/bin/sh\necho 'no route to host' >&2\nexit 7\n").unwrap();
+    fs::set_permissions(&failing, fs::Permissions::from_mode(0o755)).unwrap();
+    let notifier = Notifier::with_commands(
+        None,
+        Some("admin@example.com".to_string()),
+        false,
+        &curl,
+        failing.to_str().unwrap(),
+    );
+    let err = notifier.send(&report(None)).unwrap_err();
+    assert!(err.starts_with("email: "));
+    assert!(err.ends_with("failed: exit status: 7: no route to host"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn parse_jobs() {
+    let job = Job::parse("low:media:/srv/media:old").unwrap();
+    assert_eq!(job.priority, Priority::Low);
+    assert_eq!(job.name, "media");
+    assert_eq!(job.path, "/srv/media:old");
+    assert_eq!(job.interval, 24 * 3600);
+    assert_eq!(Job::parse("high:home:/home").unwrap().interval, 3600);
+
+    assert!(Job::parse("urgent:home:/home").is_err());
+    assert!(Job::parse("high:home").is_err());
+    assert!(Job::parse("high::/home").is_err());
+}
+
+#[test]
+fn schedule_prefers_high_priority() {
+    let job = |spec: &str| Job::parse(spec).unwrap();
+    let mut schedule = Schedule::new(
+        vec![job("low:media:/media"), job("high:home:/home"), job("low:srv:/srv")],
+        0,
+    );
+
+    // High priority runs first; nothing preempts it.
+    assert_eq!(schedule.next(0), Ok(1));
+    assert_eq!(schedule.preempt_at(1), None);
+    schedule.done(1, 10);
+
+    // Low-priority jobs give way when the high-priority job is due again.
+    assert_eq!(schedule.next(10), Ok(0));
+    assert_eq!(schedule.preempt_at(0), Some(3610));
+
+    // A preempted job stays due, and runs again after the high-priority job.
+    assert_eq!(schedule.next(3610), Ok(1));
+    schedule.done(1, 3620);
+    assert_eq!(schedule.next(3620), Ok(0));
+    schedule.done(0, 4000);
+    assert_eq!(schedule.next(4000), Ok(2));
+    schedule.done(2, 5000);
+
+    // Nothing is due until the high-priority job.
+    assert_eq!(schedule.next(5000), Err(2220));
+    assert_eq!(schedule.next(7220), Ok(1));
+}
+
+#[test]
+fn preempt_timer() {
+    let preemption = Preemption::new();
+    {
+        let _timer = PreemptTimer::start(preemption.clone(), Some(Duration::from_secs(3600)));
+    }
+    assert!(!preemption.is_requested());
+
+    let _timer = PreemptTimer::start(preemption.clone(), Some(Duration::from_millis(0)));
+    let mut waited = 0;
+    while !preemption.is_requested() && waited < 100 {
+        std::thread::sleep(Duration::from_millis(50));
+        waited += 1;
+    }
+    assert!(preemption.is_requested());
+}
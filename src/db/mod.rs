@@ -27,13 +27,102 @@ use diesel::sqlite::SqliteConnection;
 use errors::DieselError;
 
 use hash;
+use std::collections::BTreeMap;
 use std::sync::{Mutex, MutexGuard};
 use tags;
 use time::Duration;
 use util::{Counter, PeriodicTimer};
 
+mod path_index;
 mod schema;
 
+pub use self::path_index::{IndexedPath, PathIndex, PathVersion};
+
+/// Family of the synthetic snapshots that list all other snapshots, see `Hat::meta_commit`.
+pub const ROOTS_FAMILY_NAME: &str = "__hat__roots__";
+
+/// Whether family `name` holds repository metadata rather than user files.
+pub fn is_internal_family(name: &str) -> bool {
+    name == ROOTS_FAMILY_NAME
+}
+
+/// Copy the index at `src` to a new database at `dst`. Safe to run while `src` is in use.
+pub fn export(src: &str, dst: &str) -> Result<(), DieselError> {
+    use diesel::connection::SimpleConnection;
+
+    let conn = SqliteConnection::establish(src)?;
+    conn.batch_execute(&format!("VACUUM INTO '{}'", dst.replace("'", "''")))?;
+    Ok(())
+}
+
+/// Copy the index at `src` to a new database at `dst`, leaving out all key material:
+/// chunk keys are removed from hash and snapshot references. Safe to run while `src` is in use.
+pub fn export_scrubbed(src: &str, dst: &str) -> Result<(), DieselError> {
+    use diesel::connection::SimpleConnection;
+
+    export(src, dst)?;
+
+    let conn = SqliteConnection::establish(dst)?;
+    conn.batch_execute("PRAGMA secure_delete = ON")?;
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        {
+            use self::schema::hashes::dsl::*;
+            let refs = hashes
+                .filter(blob_ref.is_not_null())
+                .select((id, blob_ref))
+                .load::<(i64, Option<Vec<u8>>)>(&conn)?;
+            for (id_, bytes) in refs {
+                let bytes = bytes.expect("filtered on non-null");
+                let mut cref = blob::ChunkRef::from_bytes(&bytes[..]).expect("Failed to decode chunk");
+                cref.key = None;
+                diesel::update(hashes.find(id_))
+                    .set(blob_ref.eq(cref.as_bytes()))
+                    .execute(&conn)?;
+            }
+        }
+        {
+            use self::schema::snapshots::dsl::*;
+            let refs = snapshots
+                .filter(hash_ref.is_not_null())
+                .select((id, hash_ref))
+                .load::<(i64, Option<Vec<u8>>)>(&conn)?;
+            for (id_, bytes) in refs {
+                let bytes = bytes.expect("filtered on non-null");
+                let mut href = ::hash::tree::HashRef::from_bytes(&bytes[..])
+                    .expect("Failed to decode snapshot reference");
+                href.persistent_ref.key = None;
+                diesel::update(snapshots.find(id_))
+                    .set(hash_ref.eq(href.as_bytes()))
+                    .execute(&conn)?;
+            }
+        }
+        Ok(())
+    })?;
+    // Rebuild the file so no page still holds a removed key.
+    conn.batch_execute("VACUUM")?;
+    Ok(())
+}
+
+/// Bytes of the database at `path` held by free pages, which SQLite reuses but never returns
+/// to the file system by itself.
+pub fn free_bytes(path: &str) -> Result<u64, DieselError> {
+    let conn = SqliteConnection::establish(path)?;
+    let rows: Vec<self::schema::RowId> = diesel::sql_query(
+        "SELECT page_size * freelist_count AS row_id FROM pragma_page_size(), pragma_freelist_count()",
+    ).load(&conn)?;
+    Ok(rows.first().map_or(0, |r| r.row_id as u64))
+}
+
+/// Rebuild all indexes of the database at `path` and rewrite it without free pages.
+/// The database must not be open elsewhere.
+pub fn compact(path: &str) -> Result<(), DieselError> {
+    use diesel::connection::SimpleConnection;
+
+    let conn = SqliteConnection::establish(path)?;
+    conn.batch_execute("REINDEX; VACUUM;")?;
+    Ok(())
+}
+
 pub struct Index(Mutex<InternalIndex>);
 pub type IndexGuard<'a> = MutexGuard<'a, InternalIndex>;
 
@@ -44,7 +133,7 @@ impl Index {
     pub fn lock(&self) -> MutexGuard<InternalIndex> {
         self.0.lock().expect("Database mutex is poisoned")
     }
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     pub fn new_for_testing() -> Index {
         Index(Mutex::new(InternalIndex::new(":memory:").unwrap()))
     }
@@ -96,7 +185,7 @@ pub struct SnapshotInfo {
     pub snapshot_id: u64,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum SnapshotWorkStatus {
     CommitInProgress,
     CommitComplete,
@@ -114,6 +203,42 @@ pub struct SnapshotStatus {
     pub created: chrono::DateTime<chrono::Utc>,
     pub msg: Option<String>,
     pub status: SnapshotWorkStatus,
+    pub manifest: Option<models::SnapshotManifest>,
+    /// Tags given at commit time.
+    pub tags: Vec<String>,
+    /// Notes added after the commit, oldest first.
+    pub annotations: Vec<models::SnapshotAnnotation>,
+}
+
+impl SnapshotStatus {
+    /// Whether this snapshot holds repository metadata rather than user files.
+    pub fn is_internal(&self) -> bool {
+        is_internal_family(&self.family_name)
+    }
+
+    /// The message given at commit time, if any. Snapshots committed before messages could be
+    /// given were all recorded as "anonymous".
+    pub fn message(&self) -> Option<&str> {
+        match self.msg.as_ref().map(|m| &m[..]) {
+            None | Some("") | Some("anonymous") => None,
+            Some(m) => Some(m),
+        }
+    }
+
+    /// Whether the snapshot was tagged `tag`, at commit time or by an annotation.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+            || self.annotations.iter().any(|a| a.tags.iter().any(|t| t == tag))
+    }
+}
+
+/// Snapshots without tags store none, as before tags could be given.
+fn encode_snapshot_tags(tags: &[String]) -> Option<Vec<u8>> {
+    if tags.is_empty() {
+        None
+    } else {
+        Some(serde_cbor::to_vec(&tags).unwrap())
+    }
 }
 
 fn tag_to_work_status(tag: tags::Tag) -> SnapshotWorkStatus {
@@ -136,6 +261,19 @@ fn work_status_to_tag(status: SnapshotWorkStatus) -> tags::Tag {
     }
 }
 
+/// A snapshot whose chunks are in the chunk reference counts, see `chunk_refs_add`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CountedSnapshot {
+    /// The `SnapshotInfo::unique_id` of the snapshot.
+    pub unique_id: u64,
+    /// Chunks reachable from the snapshot and their stored bytes.
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Chunks no other counted snapshot references.
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+}
+
 /// An entry that can be inserted into the hash index.
 #[derive(Clone)]
 pub struct Entry {
@@ -177,7 +315,11 @@ embed_migrations!();
 
 impl InternalIndex {
     fn new(path: &str) -> Result<InternalIndex, DieselError> {
+        use diesel::connection::SimpleConnection;
+
         let conn = SqliteConnection::establish(path)?;
+        // Wait for other processes, such as a mount reading the index, instead of failing at once.
+        conn.batch_execute("PRAGMA busy_timeout = 10000")?;
 
         let mut idx = InternalIndex {
             conn: conn,
@@ -316,10 +458,36 @@ impl InternalIndex {
     }
 
     pub fn hash_delete_not_ready(&mut self) {
+        use diesel::connection::SimpleConnection;
         use self::schema::hashes::dsl::*;
         diesel::delete(hashes.filter(ready.eq(false)))
             .execute(&self.conn)
             .expect("Failed to delete non-ready hashes");
+        // Families are recorded as soon as a hash is reserved, before it is in the index.
+        let orphans = "DELETE FROM hash_families WHERE hash_id NOT IN (SELECT id FROM hashes)";
+        self.conn
+            .batch_execute(orphans)
+            .expect("Failed to delete families of non-ready hashes");
+    }
+
+    /// Record that the family `family_` stored the hash with id `id_` first.
+    pub fn hash_set_family(&mut self, id_: u64, family_: &str) {
+        use self::schema::hash_families::dsl::*;
+        diesel::replace_into(hash_families)
+            .values((hash_id.eq(id_ as i64), family.eq(family_)))
+            .execute(&self.conn)
+            .expect("Error recording hash family");
+    }
+
+    /// The family that stored the hash with id `id_` first, if recorded.
+    pub fn hash_family(&mut self, id_: u64) -> Option<String> {
+        use self::schema::hash_families::dsl::*;
+        hash_families
+            .find(id_ as i64)
+            .select(family)
+            .first::<String>(&self.conn)
+            .optional()
+            .expect("Error reading hash family")
     }
 
     pub fn hash_set_ready(&mut self, id_: u64, entry: &QueueEntry) {
@@ -500,6 +668,23 @@ impl InternalIndex {
             .collect()
     }
 
+    /// The id, hash and child ids of every hash with children.
+    pub fn hash_list_branches(&mut self) -> Vec<(u64, hash::Hash, Vec<u64>)> {
+        use self::schema::hashes::dsl::*;
+
+        hashes
+            .filter(childs.is_not_null())
+            .select((id, hash, childs))
+            .load::<(i64, Vec<u8>, Option<Vec<u8>>)>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|(id_, hash_, childs_)| {
+                let childs_ = decode_childs(&childs_.unwrap()).unwrap();
+                (id_ as u64, self::hash::Hash { bytes: hash_ }, childs_)
+            })
+            .collect()
+    }
+
     pub fn hash_delete(&mut self, id_: u64) {
         {
             use self::schema::hashes::dsl::*;
@@ -515,6 +700,13 @@ impl InternalIndex {
                 .execute(&self.conn)
                 .expect("Error deleting GC metadata");
         }
+
+        {
+            use self::schema::hash_families::dsl::*;
+            diesel::delete(hash_families.find(id_ as i64))
+                .execute(&self.conn)
+                .expect("Error deleting hash family");
+        }
     }
 
     pub fn maybe_flush(&mut self) {
@@ -551,13 +743,37 @@ impl InternalIndex {
             .unwrap_or(0)
     }
 
-    pub fn blob_in_air(&mut self, blob: &blob::BlobDesc) {
+    pub fn blob_count(&self) -> i64 {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .count()
+            .get_result(&self.conn)
+            .expect("Error counting blobs")
+    }
+
+    pub fn blob_names(&self) -> Vec<Vec<u8>> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .select(name)
+            .order(id.asc())
+            .load::<Vec<u8>>(&self.conn)
+            .expect("Error listing blobs")
+    }
+
+    pub fn blob_in_air(
+        &mut self,
+        blob: &blob::BlobDesc,
+        key_version_: Option<u64>,
+        checksum_: Option<&[u8]>,
+    ) {
         use self::schema::blobs::dsl::*;
 
         let new = schema::NewBlob {
             id: blob.id,
             name: &blob.name,
             tag: tags::Tag::InProgress as i32,
+            key_version: key_version_.map(|v| v as i64),
+            checksum: checksum_,
         };
         diesel::insert_into(blobs)
             .values(&new)
@@ -610,6 +826,16 @@ impl InternalIndex {
         };
     }
 
+    /// Record that `blob` was sealed again with keys of version `key_version_`, and now has
+    /// checksum `checksum_`.
+    pub fn blob_set_key_version(&self, blob: &blob::BlobDesc, key_version_: u64, checksum_: &[u8]) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.find(blob.id))
+            .set((key_version.eq(Some(key_version_ as i64)), checksum.eq(Some(checksum_))))
+            .execute(&self.conn)
+            .expect("Error updating blob");
+    }
+
     pub fn blob_delete_by_tag(&self, tag_: tags::Tag) {
         use self::schema::blobs::dsl::*;
         diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
@@ -617,6 +843,217 @@ impl InternalIndex {
             .expect("Error deleting blobs");
     }
 
+    /// The key version of every blob; `None` where it is not known.
+    pub fn blob_key_versions(&self) -> Vec<Option<u64>> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .select(key_version)
+            .load::<Option<i64>>(&self.conn)
+            .expect("Error listing blob key versions")
+            .into_iter()
+            .map(|v| v.map(|v| v as u64))
+            .collect()
+    }
+
+    /// Up to `limit` committed blobs, those with unknown or the oldest key versions first.
+    pub fn blob_list_by_key_age(&self, limit: i64) -> Vec<blob::BlobDesc> {
+        use self::schema::blobs::dsl::*;
+        // SQLite orders NULL before any version.
+        blobs
+            .filter(tag.eq(tags::Tag::Done as i32))
+            .order((key_version.asc(), id.asc()))
+            .limit(limit)
+            .load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| blob::BlobDesc {
+                id: blob_.id,
+                name: blob_.name,
+            })
+            .collect()
+    }
+
+    /// Committed blobs and the checksum recorded when they were stored, if any.
+    pub fn blob_list_checksums(&self) -> Vec<(blob::BlobDesc, Option<Vec<u8>>)> {
+        use self::schema::blobs::dsl::*;
+        blobs
+            .filter(tag.eq(tags::Tag::Done as i32))
+            .order(id.asc())
+            .load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| {
+                let desc = blob::BlobDesc {
+                    id: blob_.id,
+                    name: blob_.name,
+                };
+                (desc, blob_.checksum)
+            })
+            .collect()
+    }
+
+    /// Whether the snapshot with unique id `snapshot` is in the chunk reference counts.
+    pub fn chunk_refs_counted(&self, snapshot: u64) -> bool {
+        use self::schema::chunk_ref_snapshots::dsl::*;
+        chunk_ref_snapshots
+            .find(snapshot as i64)
+            .select(snapshot_id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading counted snapshot")
+            .is_some()
+    }
+
+    fn chunk_ref(&self, id: u64) -> Option<schema::ChunkRef> {
+        use self::schema::chunk_refs::dsl::*;
+        chunk_refs
+            .find(id as i64)
+            .first::<schema::ChunkRef>(&self.conn)
+            .optional()
+            .expect("Error reading chunk references")
+    }
+
+    /// Adjust the chunks only the snapshot with unique id `snapshot` references.
+    fn chunk_refs_adjust_unique(&self, snapshot: i64, chunks: i64, bytes: i64) {
+        use self::schema::chunk_ref_snapshots::dsl::{
+            chunk_ref_snapshots, unique_bytes, unique_chunks,
+        };
+        diesel::update(chunk_ref_snapshots.find(snapshot))
+            .set((unique_chunks.eq(unique_chunks + chunks), unique_bytes.eq(unique_bytes + bytes)))
+            .execute(&self.conn)
+            .expect("Error updating counted snapshot");
+    }
+
+    /// Count a reference from the snapshot with unique id `snapshot` to each of `chunks`, given
+    /// as hash id and stored length. A snapshot that is counted already is left as it is.
+    ///
+    /// Each chunk keeps the sum of the unique ids of the snapshots referencing it, so the one
+    /// snapshot left when the count drops to 1 is known without walking any snapshot.
+    pub fn chunk_refs_add(&self, snapshot: u64, chunks: &BTreeMap<u64, u64>) {
+        if self.chunk_refs_counted(snapshot) {
+            return;
+        }
+        let snapshot = snapshot as i64;
+        let (mut unique_chunks, mut unique_bytes) = (0, 0);
+        for (&id, &length) in chunks {
+            use self::schema::chunk_refs::dsl::*;
+            match self.chunk_ref(id) {
+                None => {
+                    diesel::insert_into(chunk_refs)
+                        .values(&schema::ChunkRef {
+                            hash_id: id as i64,
+                            bytes: length as i64,
+                            snapshots: 1,
+                            snapshot_sum: snapshot,
+                        })
+                        .execute(&self.conn)
+                        .expect("Error inserting chunk references");
+                    unique_chunks += 1;
+                    unique_bytes += length as i64;
+                }
+                Some(refs) => {
+                    if refs.snapshots == 1 {
+                        self.chunk_refs_adjust_unique(refs.snapshot_sum, -1, -refs.bytes);
+                    }
+                    diesel::update(chunk_refs.find(id as i64))
+                        .set((
+                            snapshots.eq(refs.snapshots + 1),
+                            snapshot_sum.eq(refs.snapshot_sum + snapshot),
+                        ))
+                        .execute(&self.conn)
+                        .expect("Error updating chunk references");
+                }
+            }
+        }
+
+        use self::schema::chunk_ref_snapshots::dsl::chunk_ref_snapshots;
+        diesel::insert_into(chunk_ref_snapshots)
+            .values(&schema::ChunkRefSnapshot {
+                snapshot_id: snapshot,
+                chunks: chunks.len() as i64,
+                bytes: chunks.values().sum::<u64>() as i64,
+                unique_chunks: unique_chunks,
+                unique_bytes: unique_bytes,
+            })
+            .execute(&self.conn)
+            .expect("Error inserting counted snapshot");
+    }
+
+    /// Drop the references from the snapshot with unique id `snapshot` to `chunks`, as given to
+    /// `chunk_refs_add`. A snapshot that is not counted is left as it is.
+    pub fn chunk_refs_remove(&self, snapshot: u64, chunks: &BTreeMap<u64, u64>) {
+        if !self.chunk_refs_counted(snapshot) {
+            return;
+        }
+        let snapshot = snapshot as i64;
+        for &id in chunks.keys() {
+            use self::schema::chunk_refs::dsl::*;
+            let refs = match self.chunk_ref(id) {
+                Some(refs) => refs,
+                None => continue,
+            };
+            if refs.snapshots <= 1 {
+                diesel::delete(chunk_refs.find(id as i64))
+                    .execute(&self.conn)
+                    .expect("Error deleting chunk references");
+                continue;
+            }
+            let sum = refs.snapshot_sum - snapshot;
+            diesel::update(chunk_refs.find(id as i64))
+                .set((snapshots.eq(refs.snapshots - 1), snapshot_sum.eq(sum)))
+                .execute(&self.conn)
+                .expect("Error updating chunk references");
+            if refs.snapshots == 2 {
+                self.chunk_refs_adjust_unique(sum, 1, refs.bytes);
+            }
+        }
+
+        use self::schema::chunk_ref_snapshots::dsl::chunk_ref_snapshots;
+        diesel::delete(chunk_ref_snapshots.find(snapshot))
+            .execute(&self.conn)
+            .expect("Error deleting counted snapshot");
+    }
+
+    /// Hash id, stored length and number of counted snapshots of every referenced chunk.
+    pub fn chunk_refs_list(&self) -> Vec<(u64, u64, u64)> {
+        use self::schema::chunk_refs::dsl::*;
+        chunk_refs
+            .load::<schema::ChunkRef>(&self.conn)
+            .expect("Error listing chunk references")
+            .into_iter()
+            .map(|r| (r.hash_id as u64, r.bytes as u64, r.snapshots as u64))
+            .collect()
+    }
+
+    /// The snapshots in the chunk reference counts.
+    pub fn chunk_refs_snapshots(&self) -> Vec<CountedSnapshot> {
+        use self::schema::chunk_ref_snapshots::dsl::*;
+        chunk_ref_snapshots
+            .load::<schema::ChunkRefSnapshot>(&self.conn)
+            .expect("Error listing counted snapshots")
+            .into_iter()
+            .map(|s| CountedSnapshot {
+                unique_id: s.snapshot_id as u64,
+                chunks: s.chunks as u64,
+                bytes: s.bytes as u64,
+                unique_chunks: s.unique_chunks as u64,
+                unique_bytes: s.unique_bytes as u64,
+            })
+            .collect()
+    }
+
+    /// Forget all chunk reference counts.
+    pub fn chunk_refs_clear(&self) {
+        use self::schema::chunk_ref_snapshots::dsl::chunk_ref_snapshots;
+        use self::schema::chunk_refs::dsl::chunk_refs;
+        diesel::delete(chunk_refs)
+            .execute(&self.conn)
+            .expect("Error deleting chunk references");
+        diesel::delete(chunk_ref_snapshots)
+            .execute(&self.conn)
+            .expect("Error deleting counted snapshots");
+    }
+
     pub fn blob_list_by_tag(&self, tag_: tags::Tag) -> Vec<blob::BlobDesc> {
         use self::schema::blobs::dsl::*;
         blobs
@@ -720,6 +1157,9 @@ impl InternalIndex {
                 msg,
                 hash,
                 hash_ref,
+                manifest,
+                annotations,
+                commit_tags,
             ))
             .first::<self::schema::Snapshot>(&self.conn)
             .optional()
@@ -741,20 +1181,29 @@ impl InternalIndex {
         })
     }
 
-    pub fn snapshot_reserve(&mut self, family_: String) -> SnapshotInfo {
+    pub fn snapshot_reserve(
+        &mut self,
+        family_: String,
+        msg_: Option<&str>,
+        tags_: &[String],
+    ) -> SnapshotInfo {
         use self::schema::snapshots::dsl::*;
 
         let family_id_ = self.get_or_create_family_id(&family_);
         let snapshot_id_ = 1 + self.snapshot_latest_id(family_id_).unwrap_or(0);
+        let tags_bytes = encode_snapshot_tags(tags_);
 
         let new = self::schema::NewSnapshot {
             family_id: family_id_,
             snapshot_id: snapshot_id_,
             tag: tags::Tag::Reserved as i32,
             utc_datetime: chrono::Utc::now().naive_utc(),
-            msg: None,
+            msg: msg_,
             hash: None,
             hash_ref: None,
+            manifest: None,
+            annotations: None,
+            commit_tags: tags_bytes.as_ref().map(|b| &b[..]),
         };
 
         diesel::insert_into(snapshots)
@@ -774,22 +1223,38 @@ impl InternalIndex {
     pub fn snapshot_update(
         &mut self,
         snapshot_: &SnapshotInfo,
-        msg_: &str,
         hash_: &hash::Hash,
         hash_ref_: &hash::tree::HashRef,
+        manifest_: Option<&models::SnapshotManifest>,
     ) {
         use self::schema::snapshots::dsl::*;
 
+        let manifest_bytes = manifest_.map(|m| serde_cbor::to_vec(m).unwrap());
         diesel::update(snapshots.find(snapshot_.unique_id as i64))
             .set((
-                msg.eq(Some(msg_)),
                 hash.eq(Some(&hash_.bytes)),
                 hash_ref.eq(Some(hash_ref_.as_bytes())),
+                manifest.eq(manifest_bytes),
             ))
             .execute(&self.conn)
             .expect("Error updating snapshot");
     }
 
+    /// Replace the annotations of a snapshot.
+    pub fn snapshot_set_annotations(
+        &mut self,
+        snapshot_: &SnapshotInfo,
+        annotations_: &[models::SnapshotAnnotation],
+    ) {
+        use self::schema::snapshots::dsl::*;
+
+        let annotations_bytes = serde_cbor::to_vec(&annotations_).unwrap();
+        diesel::update(snapshots.find(snapshot_.unique_id as i64))
+            .set(annotations.eq(Some(annotations_bytes)))
+            .execute(&self.conn)
+            .expect("Error updating snapshot");
+    }
+
     pub fn snapshot_set_tag(&mut self, snapshot_: &SnapshotInfo, tag_: tags::Tag) {
         use self::schema::snapshots::dsl::*;
 
@@ -865,6 +1330,18 @@ impl InternalIndex {
                     hash: hash_,
                     hash_ref: snap.hash_ref,
                     status: status,
+                    // Snapshots from before manifests were recorded have none.
+                    manifest: snap
+                        .manifest
+                        .and_then(|bytes| serde_cbor::from_slice(&bytes[..]).ok()),
+                    annotations: snap
+                        .annotations
+                        .and_then(|bytes| serde_cbor::from_slice(&bytes[..]).ok())
+                        .unwrap_or_default(),
+                    tags: snap
+                        .commit_tags
+                        .and_then(|bytes| serde_cbor::from_slice(&bytes[..]).ok())
+                        .unwrap_or_default(),
                     info: SnapshotInfo {
                         unique_id: snap.id as u64,
                         snapshot_id: snap.snapshot_id as u64,
@@ -882,7 +1359,9 @@ impl InternalIndex {
         family: &str,
         created: chrono::DateTime<chrono::Utc>,
         msg_: &str,
+        tags_: &[String],
         hash_ref_: &hash::tree::HashRef,
+        manifest_: Option<&models::SnapshotManifest>,
         work_opt_: Option<SnapshotWorkStatus>,
     ) {
         let family_id_ = self.get_or_create_family_id(&family);
@@ -899,6 +1378,8 @@ impl InternalIndex {
             use self::schema::snapshots::dsl::*;
 
             let hash_ref_bytes = hash_ref_.as_bytes();
+            let manifest_bytes = manifest_.map(|m| serde_cbor::to_vec(m).unwrap());
+            let tags_bytes = encode_snapshot_tags(tags_);
             let new = self::schema::NewSnapshot {
                 family_id: family_id_,
                 snapshot_id: snapshot_id_ as i64,
@@ -906,6 +1387,9 @@ impl InternalIndex {
                 msg: Some(msg_),
                 hash: Some(&hash_ref_.hash.bytes[..]),
                 hash_ref: Some(&hash_ref_bytes[..]),
+                manifest: manifest_bytes.as_ref().map(|b| &b[..]),
+                annotations: None,
+                commit_tags: tags_bytes.as_ref().map(|b| &b[..]),
                 tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
             };
 
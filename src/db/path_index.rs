@@ -0,0 +1,215 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An optional local index of the paths in each snapshot.
+//!
+//! Every version of a path is kept once, together with the range of snapshots of its family
+//! that hold it unchanged, so finding a file or listing its history needs no tree walks.
+//! The index only grows: snapshots deleted later are filtered out by the caller.
+
+use diesel;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use diesel::sqlite::SqliteConnection;
+use errors::DieselError;
+use std::sync::Mutex;
+
+use super::schema;
+
+const CREATE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS path_index_families (
+        family         VARCHAR PRIMARY KEY,
+        last_snapshot  BIGINT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS path_index_versions (
+        id             INTEGER PRIMARY KEY,
+        family         VARCHAR NOT NULL,
+        path           VARCHAR NOT NULL,
+        name           VARCHAR NOT NULL,
+        hash           BLOB NOT NULL,
+        byte_length    BIGINT,
+        first_snapshot BIGINT NOT NULL,
+        last_snapshot  BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS path_index_versions_path
+        ON path_index_versions(family, path, last_snapshot);
+    CREATE INDEX IF NOT EXISTS path_index_versions_name ON path_index_versions(name);
+";
+
+/// One entry of a snapshot, as handed to `PathIndex::add_snapshot`.
+pub struct IndexedPath {
+    /// Path relative to the snapshot root, with `/` between names.
+    pub path: String,
+    /// Identifies the contents: the tree hash of files and directories, the target of links.
+    pub hash: Vec<u8>,
+    pub byte_length: Option<u64>,
+}
+
+impl IndexedPath {
+    fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// Unchanged contents of a path in snapshots `first_snapshot` to `last_snapshot` of `family`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathVersion {
+    pub family: String,
+    pub path: String,
+    pub hash: Vec<u8>,
+    pub byte_length: Option<u64>,
+    pub first_snapshot: u64,
+    pub last_snapshot: u64,
+}
+
+impl From<schema::PathVersion> for PathVersion {
+    fn from(v: schema::PathVersion) -> PathVersion {
+        PathVersion {
+            family: v.family,
+            path: v.path,
+            hash: v.hash,
+            byte_length: v.byte_length.map(|n| n as u64),
+            first_snapshot: v.first_snapshot as u64,
+            last_snapshot: v.last_snapshot as u64,
+        }
+    }
+}
+
+pub struct PathIndex(Mutex<SqliteConnection>);
+
+impl PathIndex {
+    /// Open the path index at `path`, creating it if needed.
+    pub fn open(path: &str) -> Result<PathIndex, DieselError> {
+        let conn = SqliteConnection::establish(path)?;
+        conn.batch_execute(CREATE_TABLES)?;
+        Ok(PathIndex(Mutex::new(conn)))
+    }
+
+    /// Forget all indexed snapshots.
+    pub fn clear(&self) -> Result<(), DieselError> {
+        let conn = self.0.lock().unwrap();
+        conn.batch_execute("DELETE FROM path_index_versions; DELETE FROM path_index_families;")?;
+        Ok(())
+    }
+
+    /// The newest snapshot of `family_` in the index.
+    pub fn last_indexed(&self, family_: &str) -> Result<Option<u64>, DieselError> {
+        use self::schema::path_index_families::dsl::*;
+        let conn = self.0.lock().unwrap();
+        Ok(path_index_families
+            .find(family_)
+            .select(last_snapshot)
+            .first::<i64>(&*conn)
+            .optional()?
+            .map(|id| id as u64))
+    }
+
+    /// Add snapshot `snapshot_id` of `family_` holding `entries`. Snapshots of a family must be
+    /// added in order; one that is not newer than the last indexed snapshot is ignored.
+    pub fn add_snapshot(
+        &self,
+        family_: &str,
+        snapshot_id: u64,
+        entries: &[IndexedPath],
+    ) -> Result<(), DieselError> {
+        let previous = self.last_indexed(family_)?.map_or(-1, |id| id as i64);
+        let snapshot_id = snapshot_id as i64;
+        if snapshot_id <= previous {
+            return Ok(());
+        }
+
+        let conn = self.0.lock().unwrap();
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            use self::schema::path_index_versions::dsl::*;
+            for entry in entries {
+                // Extend the version seen in the previous snapshot, if the path is unchanged.
+                let extended = diesel::update(
+                    path_index_versions
+                        .filter(family.eq(family_))
+                        .filter(path.eq(&entry.path))
+                        .filter(last_snapshot.eq(previous))
+                        .filter(hash.eq(&entry.hash)),
+                ).set(last_snapshot.eq(snapshot_id))
+                    .execute(&*conn)?;
+                if extended == 0 {
+                    diesel::insert_into(path_index_versions)
+                        .values(&schema::NewPathVersion {
+                            family: family_,
+                            path: &entry.path,
+                            name: entry.name(),
+                            hash: &entry.hash,
+                            byte_length: entry.byte_length.map(|n| n as i64),
+                            first_snapshot: snapshot_id,
+                            last_snapshot: snapshot_id,
+                        })
+                        .execute(&*conn)?;
+                }
+            }
+            {
+                use self::schema::path_index_families::dsl::*;
+                diesel::replace_into(path_index_families)
+                    .values((family.eq(family_), last_snapshot.eq(snapshot_id)))
+                    .execute(&*conn)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// All versions of paths matching the glob `pattern`: `*` and `?` match any characters,
+    /// including `/`. A pattern without `/` is matched against the last name of each path.
+    pub fn find(&self, pattern: &str) -> Result<Vec<PathVersion>, DieselError> {
+        use self::schema::path_index_versions::dsl::*;
+        let column = if pattern.contains('/') { "path" } else { "name" };
+        let conn = self.0.lock().unwrap();
+        let rows = path_index_versions
+            .filter(diesel::dsl::sql::<Bool>(&format!("{} GLOB ", column)).bind::<Text, _>(pattern))
+            .order((family, path, first_snapshot))
+            .load::<schema::PathVersion>(&*conn)?;
+        Ok(rows.into_iter().map(From::from).collect())
+    }
+
+    /// All versions whose contents are one of `hashes`. Hashes are not indexed, so this scans
+    /// every version.
+    pub fn with_hashes(&self, hashes: &[Vec<u8>]) -> Result<Vec<PathVersion>, DieselError> {
+        use self::schema::path_index_versions::dsl::*;
+        let conn = self.0.lock().unwrap();
+        let mut rows = vec![];
+        // SQLite limits the number of parameters of a query.
+        for batch in hashes.chunks(500) {
+            rows.extend(
+                path_index_versions
+                    .filter(hash.eq_any(batch))
+                    .load::<schema::PathVersion>(&*conn)?,
+            );
+        }
+        rows.sort_by(|a, b| {
+            (&a.family, &a.path, a.first_snapshot).cmp(&(&b.family, &b.path, b.first_snapshot))
+        });
+        Ok(rows.into_iter().map(From::from).collect())
+    }
+
+    /// All versions of `path_` in `family_`, oldest first.
+    pub fn history(&self, family_: &str, path_: &str) -> Result<Vec<PathVersion>, DieselError> {
+        use self::schema::path_index_versions::dsl::*;
+        let conn = self.0.lock().unwrap();
+        let rows = path_index_versions
+            .filter(family.eq(family_))
+            .filter(path.eq(path_))
+            .order(first_snapshot)
+            .load::<schema::PathVersion>(&*conn)?;
+        Ok(rows.into_iter().map(From::from).collect())
+    }
+}
@@ -46,6 +46,8 @@ table! {
         id -> BigInt,
         name -> Binary,
         tag -> Integer,
+        key_version -> Nullable<BigInt>,
+        checksum -> Nullable<Binary>,
     }
 }
 
@@ -66,6 +68,57 @@ table! {
         msg -> Nullable<VarChar>,
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+        manifest -> Nullable<Binary>,
+        annotations -> Nullable<Binary>,
+        commit_tags -> Nullable<Binary>,
+    }
+}
+
+// Tables of the optional path index, kept in a database file of its own.
+
+table! {
+    path_index_families (family) {
+        family -> VarChar,
+        last_snapshot -> BigInt,
+    }
+}
+
+table! {
+    path_index_versions {
+        id -> BigInt,
+        family -> VarChar,
+        path -> VarChar,
+        name -> VarChar,
+        hash -> Binary,
+        byte_length -> Nullable<BigInt>,
+        first_snapshot -> BigInt,
+        last_snapshot -> BigInt,
+    }
+}
+
+table! {
+    hash_families (hash_id) {
+        hash_id -> BigInt,
+        family -> VarChar,
+    }
+}
+
+table! {
+    chunk_refs (hash_id) {
+        hash_id -> BigInt,
+        bytes -> BigInt,
+        snapshots -> BigInt,
+        snapshot_sum -> BigInt,
+    }
+}
+
+table! {
+    chunk_ref_snapshots (snapshot_id) {
+        snapshot_id -> BigInt,
+        chunks -> BigInt,
+        bytes -> BigInt,
+        unique_chunks -> BigInt,
+        unique_bytes -> BigInt,
     }
 }
 
@@ -132,6 +185,8 @@ pub struct Blob {
     pub id: i64,
     pub name: Vec<u8>,
     pub tag: i32,
+    pub key_version: Option<i64>,
+    pub checksum: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -140,6 +195,8 @@ pub struct NewBlob<'a> {
     pub id: i64,
     pub name: &'a [u8],
     pub tag: i32,
+    pub key_version: Option<i64>,
+    pub checksum: Option<&'a [u8]>,
 }
 
 #[derive(Queryable)]
@@ -164,6 +221,9 @@ pub struct Snapshot {
     pub msg: Option<String>,
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+    pub manifest: Option<Vec<u8>>,
+    pub annotations: Option<Vec<u8>>,
+    pub commit_tags: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -176,4 +236,50 @@ pub struct NewSnapshot<'a> {
     pub msg: Option<&'a str>,
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
+    pub manifest: Option<&'a [u8]>,
+    pub annotations: Option<&'a [u8]>,
+    pub commit_tags: Option<&'a [u8]>,
+}
+
+#[derive(Queryable)]
+pub struct PathVersion {
+    pub id: i64,
+    pub family: String,
+    pub path: String,
+    pub name: String,
+    pub hash: Vec<u8>,
+    pub byte_length: Option<i64>,
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "path_index_versions"]
+pub struct NewPathVersion<'a> {
+    pub family: &'a str,
+    pub path: &'a str,
+    pub name: &'a str,
+    pub hash: &'a [u8],
+    pub byte_length: Option<i64>,
+    pub first_snapshot: i64,
+    pub last_snapshot: i64,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "chunk_refs"]
+pub struct ChunkRef {
+    pub hash_id: i64,
+    pub bytes: i64,
+    pub snapshots: i64,
+    pub snapshot_sum: i64,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "chunk_ref_snapshots"]
+pub struct ChunkRefSnapshot {
+    pub snapshot_id: i64,
+    pub chunks: i64,
+    pub bytes: i64,
+    pub unique_chunks: i64,
+    pub unique_bytes: i64,
 }
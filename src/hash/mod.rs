@@ -20,7 +20,7 @@ use db;
 
 use errors::{DieselError, RetryError};
 
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use tags;
 use util::UniquePriorityQueue;
 
@@ -87,6 +87,8 @@ type Queue = UniquePriorityQueue<u64, Vec<u8>, db::QueueEntry>;
 pub struct InternalHashIndex {
     index: Arc<db::Index>,
     queue: Mutex<Queue>,
+    /// Signalled when a reserved hash gets its persistent reference.
+    published: Condvar,
 }
 
 impl Drop for InternalHashIndex {
@@ -102,6 +104,7 @@ impl InternalHashIndex {
         Ok(InternalHashIndex {
             index: index,
             queue: Mutex::new(UniquePriorityQueue::new()),
+            published: Condvar::new(),
         })
     }
 
@@ -178,12 +181,16 @@ impl InternalHashIndex {
         }
 
         // If we didn't already commit and pop() the hash, update it:
+        let published = persistent_ref.is_some();
         queue.update_value(&hash.bytes, |qe| {
             qe.node = node;
             qe.leaf = leaf;
             qe.childs = childs;
             qe.persistent_ref = persistent_ref;
         });
+        if published {
+            self.published.notify_all();
+        }
     }
 
     fn insert_completed_in_order(&self, queue: &mut MutexGuard<Queue>, index: &mut db::IndexGuard) {
@@ -255,6 +262,25 @@ impl HashIndex {
         }
     }
 
+    /// Like `fetch_persistent_ref`, but waits for a `Hash` reserved by someone else to get its
+    /// persistent reference, so identical chunks found at the same time are stored only once.
+    pub fn wait_persistent_ref(&self, hash: &Hash) -> Option<blob::ChunkRef> {
+        assert!(!hash.bytes.is_empty());
+        let (mut queue, mut index) = self.0.lock();
+        loop {
+            match self.0.locate(hash, &queue, &mut index) {
+                Some(ref queue_entry) if queue_entry.persistent_ref.is_none() => {
+                    // Keep the lock order: the queue is taken before the index.
+                    drop(index);
+                    queue = self.0.published.wait(queue).expect("Hash queue mutex poisoned");
+                    index = self.0.index.lock();
+                }
+                Some(queue_entry) => return queue_entry.persistent_ref,
+                None => return None,
+            }
+        }
+    }
+
     /// Locate the hash reference (including persistent blob reference) for this `Hash~.
     pub fn fetch_hash_ref(&self, hash: &Hash) -> Result<Option<tree::HashRef>, RetryError> {
         assert!(!hash.bytes.is_empty());
@@ -267,6 +293,7 @@ impl HashIndex {
                 leaf: queue_entry.leaf,
                 info: None,
                 persistent_ref: queue_entry.persistent_ref.expect("persistent_ref"),
+                range: None,
             })),
             None => Ok(None),
         }
@@ -316,6 +343,11 @@ impl HashIndex {
         self.0.index.lock().hash_list()
     }
 
+    /// List the ID, hash and child IDs of all hashes with children.
+    pub fn list_branches(&self) -> Vec<(u64, Hash, Vec<u64>)> {
+        self.0.index.lock().hash_list_branches()
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: u64) {
         self.0.index.lock().hash_delete(id)
@@ -344,6 +376,16 @@ impl HashIndex {
         self.0.index.lock().hash_get_tag(id)
     }
 
+    /// Record that `family` stored the hash with id `id` first.
+    pub fn set_family(&self, id: u64, family: &str) {
+        self.0.index.lock().hash_set_family(id, family)
+    }
+
+    /// The family that stored the hash with id `id` first, if recorded.
+    pub fn family(&self, id: u64) -> Option<String> {
+        self.0.index.lock().hash_family(id)
+    }
+
     /// API related to tagging, which is useful to indicate state during operation stages.
     /// It operates directly on the underlying IDs.
     pub fn get_ids_by_tag(&self, tag: u64) -> Vec<u64> {
@@ -391,4 +433,10 @@ impl HashIndex {
     pub fn flush(&self) {
         self.0.index.lock().flush()
     }
+
+    /// Forget the hashes waiting for their chunks to be stored, as a crash would.
+    #[cfg(test)]
+    pub fn crash(&self) {
+        *self.0.queue_lock() = UniquePriorityQueue::new();
+    }
 }
@@ -14,18 +14,22 @@
 
 use blob::{ChunkRef, LeafType, NodeType};
 use crypto;
+use db;
 use hash::tree::*;
-use hash::Hash;
+use hash::{Entry, Hash, HashIndex, ReserveResult};
 use key;
 use quickcheck;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct MemoryBackend {
     chunks: Arc<Mutex<BTreeMap<Vec<u8>, (NodeType, LeafType, Option<Vec<u64>>, Vec<u8>)>>>,
     seen_chunks: Arc<Mutex<BTreeSet<Vec<u8>>>>,
+    keys: Arc<crypto::keys::Keeper>,
 }
 
 impl MemoryBackend {
@@ -33,12 +37,16 @@ impl MemoryBackend {
         MemoryBackend {
             chunks: Arc::new(Mutex::new(BTreeMap::new())),
             seen_chunks: Arc::new(Mutex::new(BTreeSet::new())),
+            keys: Arc::new(crypto::keys::Keeper::new_for_testing()),
         }
     }
     pub fn saw_chunk(&self, chunk: &Vec<u8>) -> bool {
         let guarded_seen = self.seen_chunks.lock().unwrap();
         guarded_seen.contains(chunk)
     }
+    pub fn forget_seen(&self) {
+        self.seen_chunks.lock().unwrap().clear();
+    }
 }
 
 impl HashTreeBackend for MemoryBackend {
@@ -73,6 +81,11 @@ impl HashTreeBackend for MemoryBackend {
         }
     }
 
+    fn fetch_id(&self, hash: &Hash) -> Option<u64> {
+        let guarded_chunks = self.chunks.lock().unwrap();
+        guarded_chunks.get(&hash.bytes).map(|_| 0)
+    }
+
     fn insert_chunk(
         &self,
         chunk: &[u8],
@@ -87,8 +100,7 @@ impl HashTreeBackend for MemoryBackend {
 
         let mut guarded_chunks = self.chunks.lock().unwrap();
 
-        let keys = crypto::keys::Keeper::new_for_testing();
-        let hash = Hash::new(&keys, node, leaf, chunk);
+        let hash = Hash::new(&self.keys, node, leaf, chunk);
         guarded_chunks.insert(hash.bytes.clone(), (node, leaf, childs, chunk.to_vec()));
 
         Ok((
@@ -106,6 +118,7 @@ impl HashTreeBackend for MemoryBackend {
                     packing: None,
                     key: None,
                 },
+                range: None,
             },
         ))
     }
@@ -272,3 +285,128 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+#[test]
+fn reserved_hash_is_waited_for() {
+    let index = Arc::new(HashIndex::new(Arc::new(db::Index::new_for_testing())).unwrap());
+    let mut entry = Entry {
+        hash: Hash { bytes: vec![1; 64] },
+        node: NodeType::Leaf,
+        leaf: LeafType::FileChunk,
+        childs: None,
+        persistent_ref: None,
+    };
+    let id = match index.reserve(&entry) {
+        ReserveResult::ReserveOk(id) => id,
+        ReserveResult::HashKnown(_) => panic!("new hash is known"),
+    };
+    match index.reserve(&entry) {
+        ReserveResult::HashKnown(known) => assert_eq!(known, id),
+        ReserveResult::ReserveOk(_) => panic!("reserved hash is reserved again"),
+    }
+
+    let waiter = {
+        let (index, hash) = (index.clone(), entry.hash.clone());
+        thread::spawn(move || index.wait_persistent_ref(&hash))
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(index.fetch_persistent_ref(&entry.hash).is_err());
+
+    let chunk_ref = ChunkRef {
+        blob_id: Some(1),
+        blob_name: b"blob".to_vec(),
+        offset: 0,
+        length: 10,
+        packing: None,
+        key: None,
+    };
+    entry.persistent_ref = Some(chunk_ref.clone());
+    index.update_reserved(id, entry);
+    assert_eq!(waiter.join().unwrap().unwrap().blob_name, chunk_ref.blob_name);
+    assert!(index.wait_persistent_ref(&Hash { bytes: vec![2; 64] }).is_none());
+
+    index.commit(id, None);
+}
+
+fn write_tree(backend: &MemoryBackend, order: usize, chunks: &[Vec<u8>]) -> HashRef {
+    let mut ht = SimpleHashTreeWriter::new(LeafType::FileChunk, order, backend.clone());
+    for chunk in chunks {
+        ht.append(chunk).unwrap();
+    }
+    ht.hash(None).unwrap()
+}
+
+fn apply_splices(chunks: &[Vec<u8>], splices: &[Splice]) -> Vec<Vec<u8>> {
+    let mut spliced = vec![];
+    let mut next = 0;
+    for splice in splices {
+        let start = (splice.start as usize).min(chunks.len());
+        spliced.extend_from_slice(&chunks[next..start]);
+        spliced.extend(splice.insert.iter().cloned());
+        next = (start + splice.remove as usize).min(chunks.len());
+    }
+    spliced.extend_from_slice(&chunks[next..]);
+    spliced
+}
+
+#[test]
+fn splice_reuses_unchanged_subtrees() {
+    let order = 4;
+    let backend = MemoryBackend::new();
+    let chunks: Vec<Vec<u8>> = (0..40).map(|i| vec![i]).collect();
+    let old = write_tree(&backend, order, &chunks);
+    backend.forget_seen();
+
+    let splices = vec![
+        Splice {
+            start: 21,
+            remove: 1,
+            insert: vec![vec![200]],
+        },
+    ];
+    let new = splice(backend.clone(), order, old, &splices, None).unwrap();
+
+    // Only the changed leaf is stored; the full subtrees around it are reused unread.
+    assert!(backend.saw_chunk(&vec![200]));
+    for i in (0..40).filter(|&i| i != 21) {
+        assert!(!backend.saw_chunk(&vec![i]), "leaf {} was stored again", i);
+    }
+
+    let expected = write_tree(&backend, order, &apply_splices(&chunks, &splices));
+    assert_eq!(expected.hash, new.hash);
+    let leaves: Vec<_> = LeafIterator::new(backend, new).unwrap().unwrap().collect();
+    assert_eq!(apply_splices(&chunks, &splices), leaves);
+}
+
+#[test]
+fn splice_matches_tree_written_from_scratch() {
+    fn prop(len: u8, edits: Vec<(u8, u8, u8)>) -> bool {
+        let order = 4;
+        let backend = MemoryBackend::new();
+        // A tree of no chunks holds one empty leaf, so start from at least one.
+        let chunks: Vec<Vec<u8>> = (0..=len % 64).map(|i| vec![i]).collect();
+        let old = write_tree(&backend, order, &chunks);
+
+        // Turn the edits into ordered splices that do not overlap, some past the end.
+        let mut splices = vec![];
+        let mut next = 0u64;
+        for (i, &(skip, remove, insert)) in edits.iter().take(5).enumerate() {
+            let start = next + u64::from(skip % 16);
+            let remove = u64::from(remove % 10);
+            let insert = (0..insert % 10).map(|j| vec![i as u8, j, 0xff]).collect();
+            splices.push(Splice {
+                start: start,
+                remove: remove,
+                insert: insert,
+            });
+            next = start + remove;
+        }
+
+        let new = splice(backend.clone(), order, old, &splices, None).unwrap();
+        let expected = write_tree(&backend, order, &apply_splices(&chunks, &splices));
+        expected.hash == new.hash
+    }
+    quickcheck::QuickCheck::new()
+        .tests(50)
+        .quickcheck(prop as fn(u8, Vec<(u8, u8, u8)>) -> bool);
+}
@@ -36,6 +36,9 @@ pub struct HashRef {
     pub leaf: LeafType, // What kind of data the tree leafs contain.
     pub persistent_ref: ChunkRef,
     pub info: Option<key::Info>,
+    /// For small files batched into a composite leaf: the offset and length of their data in
+    /// the leaf. See `key::SmallFiles`.
+    pub range: Option<(u64, u64)>,
 }
 
 impl From<models::HashRef> for HashRef {
@@ -49,6 +52,7 @@ impl From<models::HashRef> for HashRef {
                 models::ExtraInfo::None => None,
                 models::ExtraInfo::FileInfo(info) => Some(From::from(info)),
             },
+            range: v.range,
         }
     }
 }
@@ -65,6 +69,7 @@ impl HashRef {
             } else {
                 models::ExtraInfo::None
             },
+            range: self.range,
         }
     }
 
@@ -76,6 +81,15 @@ impl HashRef {
     pub fn as_bytes(&self) -> Vec<u8> {
         serde_cbor::to_vec(&self.to_model()).unwrap()
     }
+
+    /// The data this reference points at, given the chunk it is stored in.
+    pub fn data_of(&self, mut chunk: Vec<u8>) -> Vec<u8> {
+        if let Some((offset, len)) = self.range {
+            chunk.truncate((offset + len) as usize);
+            chunk.drain(..offset as usize);
+        }
+        chunk
+    }
 }
 
 pub trait HashTreeBackend: Clone {
@@ -83,6 +97,7 @@ pub trait HashTreeBackend: Clone {
 
     fn fetch_chunk(&self, &HashRef) -> Result<Option<Vec<u8>>, Self::Err>;
     fn fetch_childs(&self, &Hash) -> Option<Vec<u64>>;
+    fn fetch_id(&self, hash: &Hash) -> Option<u64>;
     fn fetch_persistent_ref(&self, &Hash) -> Option<ChunkRef>;
     fn insert_chunk(
         &self,
@@ -101,7 +116,7 @@ fn hash_refs_to_bytes(refs: &Vec<HashRef>) -> Vec<u8> {
     serde_cbor::to_vec(&value).unwrap()
 }
 
-fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
+pub fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
     if bytes.is_empty() {
         return Some(vec![]);
     }
@@ -131,6 +146,7 @@ fn test_hash_refs_identity() {
                 leaf: LeafType::FileChunk,
                 info: None,
                 persistent_ref: chunk_ref.clone(),
+                range: None,
             });
         }
         let bytes = hash_refs_to_bytes(&v);
@@ -215,6 +231,15 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
         self.append_hashref_at(level, id, hash_ref, info)
     }
 
+    /// Append a complete subtree of a tree written with the same order, without reading or
+    /// storing it again. The tree must be aligned for it: `level` 0 takes any leaf, and each
+    /// level above needs `order` times as many leaves written before it.
+    fn append_subtree(&mut self, level: usize, id: u64, hashref: HashRef) -> Result<(), B::Err> {
+        assert!(self.levels.iter().take(level).all(|l| l.is_empty()));
+        self.grow_to(level);
+        self.append_hashref_at(level, id, hashref, None)
+    }
+
     fn append_hashref_at(
         &mut self,
         level: usize,
@@ -289,6 +314,128 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
     }
 }
 
+/// A change to the leaves of a hash tree: the `remove` leaves from leaf `start` on are replaced
+/// by the chunks in `insert`. Leaves are numbered from 0 as in the tree being changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Splice {
+    pub start: u64,
+    pub remove: u64,
+    pub insert: Vec<Vec<u8>>,
+}
+
+impl Splice {
+    /// Whether this changes any of the leaves from `first` up to `end`, rather than only what
+    /// comes before or after them.
+    fn touches(&self, first: u64, end: u64) -> bool {
+        let removes = self.start < end && self.start + self.remove > first;
+        removes || (first < self.start && self.start < end)
+    }
+}
+
+/// Copies a tree into a writer, applying splices on the way.
+struct Splicer<'a, B> {
+    writer: SimpleHashTreeWriter<B>,
+    splices: &'a [Splice],
+    /// The first splice whose chunks are not written yet.
+    next: usize,
+    /// Number of leaves written so far.
+    written: u64,
+}
+
+impl<'a, B: HashTreeBackend> Splicer<'a, B> {
+    /// Write the chunks of the splices that start at or before leaf `leaf`.
+    fn insert_until(&mut self, leaf: u64) -> Result<(), B::Err> {
+        while let Some(splice) = self.splices.get(self.next) {
+            if splice.start > leaf {
+                break;
+            }
+            for chunk in &splice.insert {
+                self.writer.append(chunk)?;
+                self.written += 1;
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// Copy the subtree `href`, whose first leaf is leaf `first` of the old tree. Returns the
+    /// number of leaves in it.
+    ///
+    /// A `full` node at height `h` has `order^h` leaves, like every node written before `hash()`
+    /// finalized its tree, and is copied as is when the splices leave it alone and the leaves
+    /// written before it fill whole subtrees of its size. `hash()` only adds nodes at the end of
+    /// each level, at most two of them, so all but the last two childs of a node are full.
+    fn copy(&mut self, href: HashRef, first: u64, full: bool) -> Result<u64, B::Err> {
+        self.insert_until(first)?;
+        let height = match href.node {
+            NodeType::Leaf => 0,
+            NodeType::Branch(height) => height,
+        };
+        if full || height == 0 {
+            let leaves = (self.writer.order as u64).pow(height as u32);
+            let untouched = !self.splices
+                .iter()
+                .any(|s| s.touches(first, first + leaves));
+            if untouched && self.written.is_multiple_of(leaves) {
+                let id = self.writer
+                    .backend
+                    .fetch_id(&href.hash)
+                    .expect("Hash of spliced tree is not known");
+                self.writer.append_subtree(height as usize, id, href)?;
+                self.written += leaves;
+                return Ok(leaves);
+            } else if height == 0 {
+                // A single leaf can only be touched by removing it.
+                return Ok(1);
+            }
+        }
+
+        let data = self.writer
+            .backend
+            .fetch_chunk(&href)?
+            .expect("Invalid hash ref");
+        let childs = hash_refs_from_bytes(&data[..]).unwrap();
+        let partial = if full { 0 } else { 2 };
+        let full_childs = childs.len().saturating_sub(partial);
+        let mut leaves = 0;
+        for (i, child) in childs.into_iter().enumerate() {
+            leaves += self.copy(child, first + leaves, i < full_childs)?;
+        }
+        Ok(leaves)
+    }
+}
+
+/// Write a new tree with the leaves of the tree `root` changed by `splices`, which must be
+/// ordered by `start` and not overlap. Splices starting past the last leaf append to the tree.
+///
+/// `order` must be the order `root` was written with. Subtrees the splices leave alone are
+/// reused without being read or stored again, as long as the leaves before them have not
+/// changed in number or changed by a multiple of their size; they keep their hashes, and the
+/// new tree is identical to one written from scratch with the changed leaves.
+pub fn splice<B: HashTreeBackend>(
+    backend: B,
+    order: usize,
+    root: HashRef,
+    splices: &[Splice],
+    info: Option<&key::Info>,
+) -> Result<HashRef, B::Err> {
+    for pair in splices.windows(2) {
+        assert!(
+            pair[0].start + pair[0].remove <= pair[1].start,
+            "Splices must be ordered and must not overlap"
+        );
+    }
+    let mut splicer = Splicer {
+        writer: SimpleHashTreeWriter::new(root.leaf, order, backend),
+        splices,
+        next: 0,
+        written: 0,
+    };
+    splicer.copy(root, 0, false)?;
+    splicer.insert_until(u64::MAX)?;
+    splicer.writer.hash(info)
+}
+
 pub trait Visitor {
     fn branch_enter(&mut self, _href: &HashRef, _childs: &Vec<HashRef>) -> bool {
         true
@@ -364,7 +511,7 @@ where
             match node.node {
                 NodeType::Leaf => {
                     if visitor.leaf_enter(&node) {
-                        let data = fetch_chunk(&self.backend, &node)?;
+                        let data = node.data_of(fetch_chunk(&self.backend, &node)?);
                         if visitor.leaf_leave(data, &node) {
                             break;
                         }
@@ -418,11 +565,18 @@ impl Visitor for LeafVisitor {
     }
 }
 
+impl<B: HashTreeBackend> LeafIterator<B> {
+    /// The next leaf, walking only as much of the tree as needed to reach it.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
+        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor)? {}
+        Ok(self.visitor.leafs.pop_front())
+    }
+}
+
 impl<B: HashTreeBackend> Iterator for LeafIterator<B> {
     type Item = Vec<u8>;
 
     fn next(&mut self) -> Option<Vec<u8>> {
-        while self.visitor.leafs.is_empty() && self.walker.resume(&mut self.visitor).unwrap() {}
-        self.visitor.leafs.pop_front()
+        self.try_next().unwrap()
     }
 }
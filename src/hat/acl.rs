@@ -0,0 +1,124 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! POSIX access control lists.
+//!
+//! Linux keeps the ACLs of a file in two extended attributes, in a binary form with users and
+//! groups by id. With the `acl` feature they are recorded as entries of their own; without it,
+//! they are neither recorded nor restored, and the permissions alone are.
+
+use models::{Acl, AclEntry, AclTag};
+use std::io;
+use std::path::Path;
+use util;
+
+const ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+const DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+
+const VERSION: u32 = 2;
+const UNDEFINED_ID: u32 = u32::MAX;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+/// Decode the extended attribute value `value` of an ACL.
+pub fn decode(value: &[u8]) -> Option<Vec<AclEntry>> {
+    let u16_at = |i: usize| u16::from(value[i]) | u16::from(value[i + 1]) << 8;
+    let u32_at = |i: usize| u32::from(u16_at(i)) | u32::from(u16_at(i + 2)) << 16;
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) || u32_at(0) != VERSION {
+        return None;
+    }
+    let mut entries = vec![];
+    for i in (4..value.len()).step_by(8) {
+        let id = u32_at(i + 4);
+        let tag = match u16_at(i) {
+            USER_OBJ => AclTag::UserObj,
+            USER => AclTag::User(id),
+            GROUP_OBJ => AclTag::GroupObj,
+            GROUP => AclTag::Group(id),
+            MASK => AclTag::Mask,
+            OTHER => AclTag::Other,
+            _ => return None,
+        };
+        entries.push(AclEntry {
+            tag: tag,
+            perm: u16_at(i + 2),
+        });
+    }
+    Some(entries)
+}
+
+/// Encode `entries` as the extended attribute value of an ACL.
+pub fn encode(entries: &[AclEntry]) -> Vec<u8> {
+    let mut value = VERSION.to_le_bytes().to_vec();
+    for entry in entries {
+        let (tag, id) = match entry.tag {
+            AclTag::UserObj => (USER_OBJ, UNDEFINED_ID),
+            AclTag::User(id) => (USER, id),
+            AclTag::GroupObj => (GROUP_OBJ, UNDEFINED_ID),
+            AclTag::Group(id) => (GROUP, id),
+            AclTag::Mask => (MASK, UNDEFINED_ID),
+            AclTag::Other => (OTHER, UNDEFINED_ID),
+        };
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&entry.perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    value
+}
+
+/// Take the ACLs out of the extended attributes `xattrs` of a file. Returns them, with the
+/// `acl` feature, if the file has any.
+pub fn take_acl(xattrs: &mut Vec<(Vec<u8>, Vec<u8>)>) -> Option<Acl> {
+    let mut acl = Acl::default();
+    let mut found = false;
+    xattrs.retain(|(name, value)| {
+        let entries = if &name[..] == ACCESS_XATTR {
+            &mut acl.access
+        } else if &name[..] == DEFAULT_XATTR {
+            &mut acl.default
+        } else {
+            return true;
+        };
+        if let Some(decoded) = decode(value) {
+            *entries = decoded;
+            found = true;
+        }
+        false
+    });
+    if found && cfg!(feature = "acl") {
+        Some(acl)
+    } else {
+        None
+    }
+}
+
+/// Give the file at `path` the ACLs `acl`, with the `acl` feature. After the permissions, which
+/// set the entries of the owner, group and others as well.
+pub fn restore_acl(path: &Path, acl: &Acl) -> io::Result<()> {
+    if !cfg!(feature = "acl") {
+        return Ok(());
+    }
+    if !acl.access.is_empty() {
+        util::set_xattr(path, ACCESS_XATTR, &encode(&acl.access))?;
+    }
+    if !acl.default.is_empty() {
+        util::set_xattr(path, DEFAULT_XATTR, &encode(&acl.default))?;
+    }
+    Ok(())
+}
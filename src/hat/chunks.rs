@@ -0,0 +1,219 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-chunk reference counts across snapshots.
+//!
+//! The GC only counts references to the top hash of each file and directory. To tell how much
+//! space a snapshot pins by itself, we count how many snapshots reach each stored chunk, and
+//! which chunks only one snapshot reaches. The counts are kept in the local index: a snapshot is
+//! walked down to its chunks once, when it is first counted, and again when it is deleted.
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use db;
+use hat::{list_snapshot, walker, HatRc};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A stored chunk and the number of snapshots that reach it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkRefs {
+    /// Stored length of the chunk.
+    pub bytes: u64,
+    /// Number of snapshots referencing the chunk, counting each snapshot once.
+    pub snapshots: u64,
+}
+
+/// Space referenced by one snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotChunks {
+    pub family_name: String,
+    pub snapshot_id: u64,
+    /// Chunks reachable from the snapshot and their stored bytes.
+    pub chunks: u64,
+    pub bytes: u64,
+    /// Chunks no other snapshot references. Deleting the snapshot frees these bytes.
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ChunkReport {
+    /// Reference counts by hash id.
+    pub chunks: BTreeMap<u64, ChunkRefs>,
+    /// Complete snapshots, sorted by the bytes they pin uniquely, largest first.
+    pub snapshots: Vec<SnapshotChunks>,
+}
+
+impl ChunkReport {
+    /// Number of snapshots referencing the chunk with this hash id.
+    pub fn refcount(&self, hash_id: u64) -> u64 {
+        self.chunks.get(&hash_id).map_or(0, |c| c.snapshots)
+    }
+
+    /// Stored bytes over all referenced chunks.
+    pub fn total_bytes(&self) -> u64 {
+        self.chunks.values().map(|c| c.bytes).sum()
+    }
+
+    /// Stored bytes of chunks referenced by more than one snapshot.
+    pub fn shared_bytes(&self) -> u64 {
+        self.chunks
+            .values()
+            .filter(|c| c.snapshots > 1)
+            .map(|c| c.bytes)
+            .sum()
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Count, for every stored chunk, the complete snapshots that reference it, and how many
+    /// bytes each snapshot pins on its own. Only snapshots committed since the last call are
+    /// walked.
+    pub fn chunk_report(&mut self) -> Result<ChunkReport, HatError> {
+        let complete = self.update_chunk_refs()?;
+
+        let mut report = ChunkReport::default();
+        let index = self.db.lock();
+        for (id, bytes, snapshots) in index.chunk_refs_list() {
+            report.chunks.insert(
+                id,
+                ChunkRefs {
+                    bytes: bytes,
+                    snapshots: snapshots,
+                },
+            );
+        }
+        let counted: BTreeMap<u64, db::CountedSnapshot> = index
+            .chunk_refs_snapshots()
+            .into_iter()
+            .map(|c| (c.unique_id, c))
+            .collect();
+        for (family_name, info) in complete {
+            if let Some(c) = counted.get(&info.unique_id) {
+                report.snapshots.push(SnapshotChunks {
+                    family_name: family_name,
+                    snapshot_id: info.snapshot_id,
+                    chunks: c.chunks,
+                    bytes: c.bytes,
+                    unique_chunks: c.unique_chunks,
+                    unique_bytes: c.unique_bytes,
+                });
+            }
+        }
+        report.snapshots.sort_by(|a, b| {
+            b.unique_bytes
+                .cmp(&a.unique_bytes)
+                .then_with(|| a.family_name.cmp(&b.family_name))
+                .then_with(|| a.snapshot_id.cmp(&b.snapshot_id))
+        });
+
+        Ok(report)
+    }
+
+    /// Add the complete snapshots that are not counted yet to the chunk reference counts.
+    /// Returns the family and info of every complete snapshot.
+    fn update_chunk_refs(&mut self) -> Result<Vec<(String, db::SnapshotInfo)>, HatError> {
+        let mut complete = vec![];
+        for s in self.snapshot_index.list_all() {
+            if s.is_internal() {
+                continue;
+            }
+            if let Some((info, _, Some(top_ref))) =
+                self.snapshot_index.lookup(&s.family_name, s.info.snapshot_id)
+            {
+                complete.push((s.family_name, info, top_ref));
+            }
+        }
+
+        // A counted snapshot that is gone was deleted without being subtracted; start over.
+        let live: BTreeSet<u64> = complete.iter().map(|c| c.1.unique_id).collect();
+        let counted = self.db.lock().chunk_refs_snapshots();
+        if counted.iter().any(|c| !live.contains(&c.unique_id)) {
+            self.db.lock().chunk_refs_clear();
+        }
+
+        for (_, info, top_ref) in &complete {
+            if !self.db.lock().chunk_refs_counted(info.unique_id) {
+                let chunks = self.snapshot_chunks(top_ref.clone())?;
+                self.db.lock().chunk_refs_add(info.unique_id, &chunks);
+            }
+        }
+        self.meta_flush();
+
+        Ok(complete
+            .into_iter()
+            .map(|(family_name, info, _)| (family_name, info))
+            .collect())
+    }
+
+    /// Subtract a snapshot that is about to be deleted from the chunk reference counts. Its tree
+    /// must still be readable.
+    pub fn uncount_chunk_refs(
+        &mut self,
+        info: &db::SnapshotInfo,
+        top_ref: hash::tree::HashRef,
+    ) -> Result<(), HatError> {
+        if self.db.lock().chunk_refs_counted(info.unique_id) {
+            let chunks = self.snapshot_chunks(top_ref)?;
+            self.db.lock().chunk_refs_remove(info.unique_id, &chunks);
+        }
+        Ok(())
+    }
+
+    /// Stored chunks reachable from a snapshot's top directory, with their stored length.
+    fn snapshot_chunks(
+        &self,
+        top_ref: hash::tree::HashRef,
+    ) -> Result<BTreeMap<u64, u64>, HatError> {
+        let hash_backend = self.hash_backend();
+        let mut queue = vec![];
+        for content in list_snapshot(&hash_backend, top_ref) {
+            let href = match content? {
+                walker::Content::Data(href) | walker::Content::Dir(href) => href,
+                walker::Content::Link(_)
+                | walker::Content::Unreadable(_)
+                | walker::Content::Special(_) => continue,
+            };
+            match self.hash_index.get_id(&href.hash) {
+                Some(id) => queue.push(id),
+                None => return Err(From::from("Snapshot references an unknown hash")),
+            }
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut chunks = BTreeMap::new();
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let entry = match self.hash_index.get_hash(id) {
+                Some(entry) => entry,
+                None => return Err(From::from(format!("Unknown hash id {}", id))),
+            };
+            // Empty chunks are not stored in any blob.
+            if let Some(blob::ChunkRef { length, .. }) = entry.persistent_ref {
+                if length > 0 {
+                    chunks.insert(id, length as u64);
+                }
+            }
+            if let Some(childs) = entry.childs {
+                queue.extend(childs);
+            }
+        }
+
+        Ok(chunks)
+    }
+}
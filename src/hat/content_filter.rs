@@ -0,0 +1,137 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filters that decide, at commit time, whether a file is stored as it is, left out, or stored
+//! with other contents, e.g. to keep secrets in `.env` files out of the repository.
+//!
+//! Every decision other than `Keep` is recorded in the snapshot manifest.
+
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use util::PathFilter;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Store the file as it is.
+    Keep,
+    /// Leave the file out of the snapshot.
+    Skip,
+    /// Store these contents in place of the file's.
+    Replace(Vec<u8>),
+}
+
+impl FilterDecision {
+    /// How the decision is recorded in the snapshot manifest.
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            FilterDecision::Keep => "kept",
+            FilterDecision::Skip => "skipped",
+            FilterDecision::Replace(_) => "replaced",
+        }
+    }
+}
+
+pub trait ContentFilter: Send + Sync {
+    /// Describes the filter in the settings of snapshots it was used for.
+    fn name(&self) -> String;
+
+    /// Decide what to store for the regular file at the absolute path `path`. The filter may
+    /// read the file to decide. On error, the file is left out.
+    fn filter(&self, path: &Path) -> Result<FilterDecision, String>;
+}
+
+/// Pipes files whose path matches a pattern through a command, run with `sh -c`.
+///
+/// The command reads the file on stdin and finds its path in `$HAT_FILTER_PATH`. If it exits
+/// with 0, what it prints is stored in place of the file; if it exits with 1, the file is left
+/// out. Other exit codes are errors. Files that match no pattern are kept as they are.
+pub struct CommandFilter {
+    rules: Vec<(PathFilter, String)>,
+}
+
+impl CommandFilter {
+    pub fn new() -> CommandFilter {
+        CommandFilter { rules: vec![] }
+    }
+
+    /// Parse a rule given as `PATTERN:COMMAND`; see `PathFilter` for patterns.
+    pub fn parse_rule(rule: &str) -> Result<(PathFilter, String), String> {
+        let mut parts = rule.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(pattern), Some(command)) if !pattern.is_empty() && !command.is_empty() => {
+                Ok((PathFilter::new(&[pattern]), command.to_owned()))
+            }
+            _ => Err(format!(
+                "Invalid content filter '{}': expected PATTERN:COMMAND",
+                rule
+            )),
+        }
+    }
+
+    /// Pass files matching `patterns` through `command`. The first rule that matches applies.
+    pub fn with_rule(mut self, patterns: PathFilter, command: String) -> CommandFilter {
+        self.rules.push((patterns, command));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Default for CommandFilter {
+    fn default() -> CommandFilter {
+        CommandFilter::new()
+    }
+}
+
+impl ContentFilter for CommandFilter {
+    fn name(&self) -> String {
+        self.rules
+            .iter()
+            .map(|(patterns, command)| format!("{}:{}", patterns, command))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn filter(&self, path: &Path) -> Result<FilterDecision, String> {
+        let command = match self
+            .rules
+            .iter()
+            .find(|(patterns, _)| patterns.matches(path.as_os_str().as_bytes()))
+        {
+            Some((_, command)) => command,
+            None => return Ok(FilterDecision::Keep),
+        };
+
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("HAT_FILTER_PATH", path)
+            .stdin(file)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("{}: {}", command, e))?;
+
+        match output.status.code() {
+            Some(0) => Ok(FilterDecision::Replace(output.stdout)),
+            Some(1) => Ok(FilterDecision::Skip),
+            _ => Err(format!("{} failed: {}", command, output.status)),
+        }
+    }
+}
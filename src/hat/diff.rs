@@ -0,0 +1,234 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differences between two snapshots of a family.
+//!
+//! Both snapshot trees are walked side by side. A directory with the same hash in both snapshots
+//! holds the same files, so it is skipped without being read; comparing two nightly snapshots
+//! thus only reads the directories on the paths to what changed.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash::tree::HashRef;
+use hat::walker::Content;
+use hat::{HashReader, HatRc};
+use key;
+use serde::Serializer;
+use std::collections::BTreeMap;
+use std::ffi;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Only in the second snapshot.
+    Added,
+    /// Only in the first snapshot.
+    Removed,
+    /// In both snapshots, with different contents.
+    Modified,
+}
+
+/// A file, symbolic link or unreadable file that differs between two snapshots. Directories are
+/// not listed themselves; the files below an added or removed directory are.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnapshotDifference {
+    /// Path relative to the snapshot roots.
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub change: Change,
+    /// Size of the file in the first snapshot, if it is a file there.
+    pub old_size: Option<u64>,
+    /// Size of the file in the second snapshot, if it is a file there.
+    pub new_size: Option<u64>,
+}
+
+impl SnapshotDifference {
+    /// Bytes the file grew by from the first snapshot to the second.
+    pub fn size_delta(&self) -> i64 {
+        self.new_size.unwrap_or(0) as i64 - self.old_size.unwrap_or(0) as i64
+    }
+}
+
+/// Paths that are not UTF-8 are written with replacement characters, as they are shown.
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+type Listing = BTreeMap<Vec<u8>, (key::Entry, Content)>;
+
+/// The entries of `dir` by name; an absent directory has none.
+fn list<B: StoreBackend>(
+    reader: &HashReader<B>,
+    dir: Option<HashRef>,
+) -> Result<Listing, HatError> {
+    let mut listing = BTreeMap::new();
+    if let Some(dir) = dir {
+        for entry in reader.list_dir(dir)? {
+            let (entry, content) = entry?;
+            listing.insert(entry.info.name.as_bytes().to_vec(), (entry, content));
+        }
+    }
+    Ok(listing)
+}
+
+fn size(entry: &key::Entry, content: &Content) -> Option<u64> {
+    match *content {
+        Content::Data(_) => entry.info.byte_length,
+        _ => None,
+    }
+}
+
+fn read_data<B: StoreBackend>(reader: &HashReader<B>, href: HashRef) -> Result<Vec<u8>, HatError> {
+    let mut data = vec![];
+    if let Some(leaves) = reader.get_leaf_iter(href)? {
+        for chunk in leaves {
+            data.extend_from_slice(&chunk[..]);
+        }
+    }
+    Ok(data)
+}
+
+/// Whether two entries that are not directories hold the same contents. File metadata, like
+/// permissions and timestamps, is not compared.
+fn same_contents<B: StoreBackend>(
+    reader: &HashReader<B>,
+    a: &Content,
+    b: &Content,
+) -> Result<bool, HatError> {
+    match (a, b) {
+        (Content::Data(a), Content::Data(b)) => {
+            if a.hash.bytes == b.hash.bytes && a.range == b.range {
+                return Ok(true);
+            }
+            // Small files are stored as parts of leaves shared with the files committed along
+            // with them, so the same contents can be referenced differently; read those.
+            match (a.range, b.range) {
+                (Some((_, a_len)), Some((_, b_len))) if a_len == b_len => {
+                    Ok(read_data(reader, a.clone())? == read_data(reader, b.clone())?)
+                }
+                _ => Ok(false),
+            }
+        }
+        (Content::Link(a), Content::Link(b)) => Ok(a == b),
+        (Content::Unreadable(a), Content::Unreadable(b)) => Ok(a == b),
+        (Content::Special(a), Content::Special(b)) => Ok(a == b),
+        _ => Ok(false),
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// The files that were added, removed or modified from snapshot `from_id` of `family_name`
+    /// to snapshot `to_id`, ordered by path.
+    pub fn diff_snapshots(
+        &mut self,
+        family_name: &str,
+        from_id: u64,
+        to_id: u64,
+    ) -> Result<Vec<SnapshotDifference>, HatError> {
+        let mut roots = vec![];
+        for &id in &[from_id, to_id] {
+            match self.snapshot_index.lookup(family_name, id) {
+                Some((_, _, Some(r))) => roots.push(r),
+                _ => {
+                    return Err(From::from(format!(
+                        "No complete snapshot {}/{}",
+                        family_name, id
+                    )))
+                }
+            }
+        }
+        let to_ref = roots.pop();
+        let from_ref = roots.pop();
+
+        let reader = self.hash_reader();
+        let mut diffs = vec![];
+        let mut dirs = vec![(PathBuf::new(), from_ref, to_ref)];
+        while let Some((path, old_dir, new_dir)) = dirs.pop() {
+            if let (Some(a), Some(b)) = (&old_dir, &new_dir) {
+                if a.hash.bytes == b.hash.bytes {
+                    continue;
+                }
+            }
+            let mut old = list(&reader, old_dir)?;
+            let new = list(&reader, new_dir)?;
+
+            for (key, (new_entry, new_content)) in new {
+                let name: ffi::OsString = new_entry.info.name.clone().into();
+                let file_path = path.join(name);
+                let new_size = size(&new_entry, &new_content);
+                match (old.remove(&key), new_content) {
+                    (Some((_, Content::Dir(a))), Content::Dir(b)) => {
+                        dirs.push((file_path, Some(a), Some(b)))
+                    }
+                    (old_file, Content::Dir(b)) => {
+                        // A new directory, possibly replacing a file.
+                        if let Some((old_entry, old_content)) = old_file {
+                            diffs.push(SnapshotDifference {
+                                path: file_path.clone(),
+                                change: Change::Removed,
+                                old_size: size(&old_entry, &old_content),
+                                new_size: None,
+                            });
+                        }
+                        dirs.push((file_path, None, Some(b)));
+                    }
+                    (Some((_, Content::Dir(a))), _) => {
+                        // A file replacing a directory.
+                        dirs.push((file_path.clone(), Some(a), None));
+                        diffs.push(SnapshotDifference {
+                            path: file_path,
+                            change: Change::Added,
+                            old_size: None,
+                            new_size: new_size,
+                        });
+                    }
+                    (Some((old_entry, old_content)), new_content) => {
+                        if !same_contents(&reader, &old_content, &new_content)? {
+                            diffs.push(SnapshotDifference {
+                                path: file_path,
+                                change: Change::Modified,
+                                old_size: size(&old_entry, &old_content),
+                                new_size: new_size,
+                            });
+                        }
+                    }
+                    (None, _) => diffs.push(SnapshotDifference {
+                        path: file_path,
+                        change: Change::Added,
+                        old_size: None,
+                        new_size: new_size,
+                    }),
+                }
+            }
+
+            for (_, (old_entry, old_content)) in old {
+                let name: ffi::OsString = old_entry.info.name.clone().into();
+                let file_path = path.join(name);
+                match old_content {
+                    Content::Dir(old_dir) => dirs.push((file_path, Some(old_dir), None)),
+                    _ => diffs.push(SnapshotDifference {
+                        path: file_path,
+                        change: Change::Removed,
+                        old_size: size(&old_entry, &old_content),
+                        new_size: None,
+                    }),
+                }
+            }
+        }
+        diffs.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(diffs)
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimates of what a commit would upload, without storing anything.
+//!
+//! The paths are walked as a commit walks them. Files the family index shows to be unchanged
+//! are skipped, as a commit skips them; the others are read and split into chunks, and the
+//! chunks the hash index does not know make up the new data.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hat::seed::ScanTotals;
+use hat::HatRc;
+use key;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use util::{ExcludeFilter, FileIterator};
+
+/// What a commit of some paths would read and upload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// Regular files below the paths.
+    pub total: ScanTotals,
+    /// Files the commit would find unchanged and skip without reading them.
+    pub unchanged: ScanTotals,
+    /// File data in chunks that are not stored yet.
+    pub new_bytes: u64,
+    /// Blobs the commit would upload: those for the new data, and one for the directory
+    /// listings and the snapshot list.
+    pub blobs: u64,
+    /// Size of each blob uploaded, as blobs are padded to the full size.
+    pub blob_size: u64,
+}
+
+impl Estimate {
+    pub fn upload_bytes(&self) -> u64 {
+        self.blobs * self.blob_size
+    }
+
+    /// How long the upload takes at `rate` bytes per second.
+    pub fn upload_time(&self, rate: u64) -> Duration {
+        Duration::from_secs_f64(self.upload_bytes() as f64 / rate as f64)
+    }
+}
+
+/// Where the entries of a directory are in the family index: the parent id they are stored
+/// under, or `None` for a directory the index does not have.
+type Parent = Option<Option<u64>>;
+
+struct Estimator<'a, B: 'a> {
+    key_store: &'a key::Store<B>,
+    exclude: &'a ExcludeFilter,
+    /// Hashes of the new chunks seen so far, which later files need not upload again.
+    seen: HashSet<Vec<u8>>,
+    estimate: Estimate,
+}
+
+impl<'a, B: StoreBackend> Estimator<'a, B> {
+    /// Count the entry at `path`. For a directory, returns where its entries are.
+    fn visit(&mut self, parent: Parent, path: &Path) -> Result<Option<Parent>, HatError> {
+        // Entries that can not be read are skipped, as they are by commits.
+        let (meta, name) = match (fs::symlink_metadata(path), path.file_name()) {
+            (Ok(meta), Some(name)) => (meta, name.to_owned()),
+            _ => return Ok(None),
+        };
+        if self.exclude.excludes(path.as_os_str().as_bytes(), meta.is_dir()) {
+            return Ok(None);
+        }
+        let data = if meta.is_dir() {
+            key::Data::DirPlaceholder
+        } else {
+            key::Data::FilePlaceholder
+        };
+        let entry = key::Entry::new(parent.unwrap_or(None), name.into(), data, Some(&meta));
+        let stored = match parent {
+            Some(_) => self.key_store.lookup(&entry)?,
+            None => None,
+        };
+
+        if meta.is_dir() {
+            return Ok(Some(stored.map(|stored| stored.node_id)));
+        } else if !meta.is_file() {
+            return Ok(None);
+        }
+
+        self.estimate.total.files += 1;
+        self.estimate.total.bytes += meta.len();
+        if let Some(ref stored) = stored {
+            if self.key_store.is_unchanged(&entry, stored, true) {
+                self.estimate.unchanged.files += 1;
+                self.estimate.unchanged.bytes += meta.len();
+                return Ok(None);
+            }
+        }
+        let read = FileIterator::new(&path.to_owned()).and_then(|it| {
+            self.key_store
+                .unknown_chunk_bytes(it, entry.info.name.as_bytes(), &mut self.seen)
+        });
+        if let Ok((_, unknown)) = read {
+            self.estimate.new_bytes += unknown;
+        }
+        Ok(None)
+    }
+
+    fn walk(&mut self, parent: Parent, dir: &Path) -> Result<(), HatError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Some(parent) = self.visit(parent, &path)? {
+                self.walk(parent, &path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Estimate what committing `paths` to the family `name` would upload. Excluded paths are
+    /// left out; the content filter of the family is not applied.
+    pub fn estimate(&mut self, name: &str, paths: &[PathBuf]) -> Result<Estimate, HatError> {
+        let family = self.open_family(name.to_owned())?;
+        let mut estimator = Estimator {
+            key_store: &family.key_store,
+            exclude: &family.exclude,
+            seen: HashSet::new(),
+            estimate: Estimate::default(),
+        };
+
+        let mut dirs = paths
+            .iter()
+            .map(fs::canonicalize)
+            .collect::<Result<Vec<_>, _>>()?;
+        dirs.sort();
+        dirs.dedup();
+        let outer = dirs.clone();
+        dirs.retain(|dir| !outer.iter().any(|o| o != dir && dir.starts_with(o)));
+
+        for dir in dirs {
+            // The directories above each path are stored too, from the root down.
+            let mut parent = Some(None);
+            let mut path = PathBuf::from("/");
+            for name in dir.iter().filter(|name| *name != "/") {
+                path.push(name);
+                match estimator.visit(parent, &path)? {
+                    Some(below) => parent = below,
+                    None => break,
+                }
+            }
+            if dir.is_dir() {
+                estimator.walk(parent, &dir)?;
+            }
+        }
+
+        let mut estimate = estimator.estimate;
+        let blob_size = self.blob_max_size as u64;
+        estimate.blobs = estimate.new_bytes.div_ceil(blob_size) + 1;
+        estimate.blob_size = blob_size;
+        Ok(estimate)
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots written out as tar archives, to restore them where hat is not installed.
+//!
+//! Archives keep the permissions, owners, modification times, symbolic links, devices and FIFOs
+//! recorded in the snapshot. Files that could not be read when the snapshot was taken have no
+//! contents, and sockets cannot be archived; both are left out with a warning.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hat::walker::Content;
+use hat::{ChunkReader, HashReader, HatRc};
+use key::Entry;
+use libc;
+use models::SpecialFile;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use util::{EntryHeader, EntryType, TarWriter};
+
+/// Write the entries of `listing` and everything below them as a tar archive to `out`, named
+/// as if `listing` was the directory `base`. Returns the number of entries written.
+pub fn write_tar<B: StoreBackend, W: io::Write>(
+    reader: &HashReader<B>,
+    base: &Path,
+    listing: Vec<(Entry, Content)>,
+    out: W,
+) -> Result<u64, HatError> {
+    let mut tar = TarWriter::new(out);
+    let mut count = 0;
+    let mut stack: Vec<(PathBuf, Entry, Content)> = listing
+        .into_iter()
+        .rev()
+        .map(|(entry, content)| (base.to_owned(), entry, content))
+        .collect();
+
+    while let Some((dir, entry, content)) = stack.pop() {
+        let name: OsString = entry.info.name.clone().into();
+        let entry_path = dir.join(name);
+        let mut name = entry_path.as_os_str().as_bytes().to_vec();
+        let info = &entry.info;
+        let mut header = EntryHeader {
+            name: &[],
+            entry_type: EntryType::File,
+            mode: 0o644,
+            uid: info.user_id.unwrap_or(0),
+            gid: info.group_id.unwrap_or(0),
+            mtime: info.modified_ts_secs.unwrap_or(0).max(0) as u64,
+            size: 0,
+        };
+        let permissions = info.permissions.as_ref().map(|p| p.mode());
+
+        match content {
+            Content::Data(href) => {
+                header.mode = permissions.unwrap_or(0o644);
+                let chunks = reader
+                    .get_leaf_iter(href)?
+                    .map(|t| Box::new(t) as Box<Iterator<Item = Vec<u8>>>)
+                    .unwrap_or_else(|| Box::new(None.into_iter()));
+                match info.byte_length {
+                    Some(len) => {
+                        header.size = len;
+                        header.name = &name[..];
+                        tar.append_entry(&header, ChunkReader::new(chunks))?;
+                    }
+                    None => {
+                        // Without a known length, the file must be read before its header.
+                        let data: Vec<u8> = chunks.flat_map(|c| c.into_iter()).collect();
+                        header.size = data.len() as u64;
+                        header.name = &name[..];
+                        tar.append_entry(&header, &data[..])?;
+                    }
+                }
+            }
+            Content::Dir(href) => {
+                name.push(b'/');
+                header.name = &name[..];
+                header.entry_type = EntryType::Directory;
+                header.mode = permissions.unwrap_or(0o755);
+                tar.append_entry(&header, io::empty())?;
+                let listing = reader.list_dir(href)?.collect::<Result<Vec<_>, _>>()?;
+                for (entry, content) in listing.into_iter().rev() {
+                    stack.push((entry_path.clone(), entry, content));
+                }
+            }
+            Content::Link(target) => {
+                header.name = &name[..];
+                header.entry_type = EntryType::Symlink(target.as_os_str().as_bytes());
+                header.mode = permissions.unwrap_or(0o777);
+                tar.append_entry(&header, io::empty())?;
+            }
+            Content::Special(special) => {
+                let device = |rdev: u64| {
+                    let rdev = rdev as libc::dev_t;
+                    (u64::from(libc::major(rdev)), u64::from(libc::minor(rdev)))
+                };
+                header.entry_type = match special {
+                    SpecialFile::CharDevice(rdev) => {
+                        let (major, minor) = device(rdev);
+                        EntryType::CharDevice(major, minor)
+                    }
+                    SpecialFile::BlockDevice(rdev) => {
+                        let (major, minor) = device(rdev);
+                        EntryType::BlockDevice(major, minor)
+                    }
+                    SpecialFile::Fifo => EntryType::Fifo,
+                    SpecialFile::Socket => {
+                        eprintln!("Skipping socket {}", entry_path.display());
+                        continue;
+                    }
+                };
+                header.name = &name[..];
+                header.mode = permissions.unwrap_or(0o644);
+                tar.append_entry(&header, io::empty())?;
+            }
+            Content::Unreadable(error) => {
+                eprintln!("Skipping unreadable {}: {}", entry_path.display(), error);
+                continue;
+            }
+        }
+        count += 1;
+    }
+
+    tar.finish()?;
+    Ok(count)
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Write snapshot `snapshot_id` of family `family` as a tar archive to `out`. Entries are
+    /// named from the root of the snapshot, so extracting the archive in `/` restores the
+    /// original paths. Returns the number of entries written.
+    pub fn export_tar<W: io::Write>(
+        &mut self,
+        family: &str,
+        snapshot_id: u64,
+        out: W,
+    ) -> Result<u64, HatError> {
+        let top_ref = match self.snapshot_index.lookup(family, snapshot_id) {
+            Some((_, _, Some(top_ref))) => top_ref,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot {}/{}",
+                    family, snapshot_id
+                )))
+            }
+        };
+        let reader = self.hash_reader();
+        let listing = reader.list_dir(top_ref)?.collect::<Result<Vec<_>, _>>()?;
+        write_tar(&reader, Path::new(""), listing, out)
+    }
+}
@@ -17,17 +17,30 @@ use blob;
 use errors::HatError;
 use filetime;
 use hash;
+use hat::content_filter::ContentFilter;
 use hat::insert_path_handler::InsertPathHandler;
+use hat::integrity::IntegrityWriter;
+use hat::list_snapshot;
+use hat::owners::{self, NameResolver};
+use hat::seed::SnapshotProgress;
 use hat::walker;
 use key;
+use libc;
 use models;
 use serde_cbor;
+use std::collections::HashMap;
 use std::ffi;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 use std::str;
-use util::{FileIterator, FnBox, PathHandler};
+use std::sync::{Arc, Mutex};
+use std::vec;
+use util::{make_special_file, ChangedFilePolicy, ExcludeFilter, FileIterator, FileOrder, FnBox,
+           PathFilter, PathHandler, PendingReply, Preemption, ProgressFn, TarEntryKind, TarReader};
 
 fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
 where
@@ -159,6 +172,7 @@ pub mod recover {
                 blob::LeafType::TreeList => true,
                 blob::LeafType::SnapshotList => false,
                 blob::LeafType::FileChunk => unreachable!("Opened a file with DirVisitor"),
+                blob::LeafType::Manifest => unreachable!("Opened a manifest with DirVisitor"),
             }
         }
         fn leaf_leave(&mut self, chunk: Vec<u8>, _href: &tree::HashRef) -> bool {
@@ -168,7 +182,7 @@ pub mod recover {
     }
 }
 
-fn parse_dir_data(chunk: &[u8], out: &mut Vec<walker::FileEntry>) -> Result<(), HatError> {
+pub fn parse_dir_data(chunk: &[u8], out: &mut Vec<walker::FileEntry>) -> Result<(), HatError> {
     if chunk.is_empty() {
         return Ok(());
     }
@@ -181,44 +195,151 @@ fn parse_dir_data(chunk: &[u8], out: &mut Vec<walker::FileEntry>) -> Result<(),
             // TODO(jos): Can we get rid of these?
             break;
         }
+        out.push(file_entry(f));
+    }
+    Ok(())
+}
 
-        let (data, hash_ref) = match f.content {
-            models::Content::Data(r) => (
+fn file_entry(f: models::File) -> walker::FileEntry {
+    let mut hard_link = None;
+    let (data, hash_ref) = match f.content {
+        models::Content::Data(r) => (
+            key::Data::FilePlaceholder,
+            walker::Content::Data(From::from(r)),
+        ),
+        models::Content::HardLink(r, dev, ino) => {
+            hard_link = Some((dev, ino));
+            (
                 key::Data::FilePlaceholder,
                 walker::Content::Data(From::from(r)),
-            ),
-            models::Content::Directory(d) => (
-                key::Data::DirPlaceholder,
-                walker::Content::Dir(From::from(d)),
-            ),
-            models::Content::SymbolicLink(path) => {
-                let link = PathBuf::from(String::from_utf8(path).unwrap());
-                (
-                    key::Data::Symlink(link.clone()),
-                    walker::Content::Link(link),
-                )
+            )
+        }
+        models::Content::Directory(d) => (
+            key::Data::DirPlaceholder,
+            walker::Content::Dir(From::from(d)),
+        ),
+        models::Content::SymbolicLink(path) => {
+            let link = PathBuf::from(String::from_utf8(path).unwrap());
+            (
+                key::Data::Symlink(link.clone()),
+                walker::Content::Link(link),
+            )
+        }
+        models::Content::Unreadable(error) => (
+            key::Data::Unreadable(error.clone()),
+            walker::Content::Unreadable(error),
+        ),
+        models::Content::Special(special) => (
+            key::Data::Special(special),
+            walker::Content::Special(special),
+        ),
+    };
+
+    let mut entry = key::Entry {
+        info: From::from(f.info),
+        data: data,
+        parent_id: None,
+        node_id: Some(f.id),
+        checksum: None,
+    };
+    entry.info.hard_link = hard_link;
+
+    walker::FileEntry {
+        hash_ref: hash_ref,
+        meta: entry,
+    }
+}
+
+/// The entries of a stored directory. Leaves of the directory's tree are fetched and decoded
+/// as the entries are consumed, so huge directories are never held in memory at once.
+pub struct DirIterator<HTB> {
+    leaves: Option<hash::tree::LeafIterator<HTB>>,
+    files: vec::IntoIter<models::File>,
+}
+
+impl<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>> Iterator for DirIterator<HTB> {
+    type Item = Result<(key::Entry, walker::Content), HatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(f) = self.files.next() {
+                if f.info.name.is_empty() {
+                    // Empty entry at the end of a leaf.
+                    self.files = Vec::new().into_iter();
+                    continue;
+                }
+                let f = file_entry(f);
+                return Some(Ok((f.meta, f.hash_ref)));
             }
-        };
 
-        let entry = key::Entry {
-            info: From::from(f.info),
-            data: data,
-            parent_id: None,
-            node_id: Some(f.id),
-        };
+            let res = match self.leaves.as_mut()?.try_next() {
+                Ok(Some(ref chunk)) if chunk.is_empty() => continue,
+                Ok(Some(chunk)) => serde_cbor::from_slice::<models::Files>(&chunk[..])
+                    .map_err(HatError::from),
+                Ok(None) => {
+                    self.leaves = None;
+                    return None;
+                }
+                Err(e) => Err(HatError::from(e)),
+            };
+            match res {
+                Ok(file_list) => self.files = file_list.files.into_iter(),
+                Err(e) => {
+                    // Stop after an error; the rest of the listing cannot be trusted.
+                    self.leaves = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// The path of a tar entry below the root of the family, without `.` and `..` components.
+fn tar_entry_path(name: &[u8]) -> PathBuf {
+    Path::new(ffi::OsStr::from_bytes(name))
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The data of the current entry of a tar archive, shared with the loop reading the entries.
+struct TarMember<R: Read>(Arc<Mutex<TarReader<R>>>);
 
-        out.push(walker::FileEntry {
-            hash_ref: hash_ref,
-            meta: entry,
-        });
+impl<R: Read> Read for TarMember<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
     }
-    Ok(())
 }
 
 pub struct Family<B> {
     pub name: String,
     pub key_store: key::Store<B>,
     pub key_store_process: Vec<key::StoreProcess<FileIterator, B>>,
+    /// Directories snapshotted since the last commit, for the snapshot manifest.
+    pub sources: Arc<Mutex<Vec<PathBuf>>>,
+    /// The order in which snapshots visit the entries of each directory.
+    pub file_order: FileOrder,
+    /// Paths snapshots leave out, and the directories they do not walk into.
+    pub exclude: ExcludeFilter,
+    /// Decides which files snapshots store, and with what contents.
+    pub content_filter: Option<Arc<ContentFilter>>,
+    /// Files the content filter left out or replaced since the last commit, with the decision.
+    pub filtered: Arc<Mutex<Vec<(PathBuf, String)>>>,
+    /// What snapshots do with files that change while being read.
+    pub changed_policy: ChangedFilePolicy,
+    /// Files that changed while last read, since the last commit.
+    pub changed: Arc<Mutex<Vec<PathBuf>>>,
+    /// Names the owners of files in the snapshots committed.
+    pub names: Arc<NameResolver>,
+    /// Number of threads that walk the directories of a snapshot.
+    pub walk_threads: usize,
+    /// Receives the progress events of snapshots.
+    pub events: Option<ProgressFn>,
+    /// The error that stopped a snapshot since the last commit, such as an exceeded quota.
+    pub failure: Arc<Mutex<Option<String>>>,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
@@ -226,58 +347,160 @@ impl<B: StoreBackend> Clone for Family<B> {
             name: self.name.clone(),
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            sources: self.sources.clone(),
+            file_order: self.file_order,
+            exclude: self.exclude.clone(),
+            content_filter: self.content_filter.clone(),
+            filtered: self.filtered.clone(),
+            changed_policy: self.changed_policy,
+            changed: self.changed.clone(),
+            names: self.names.clone(),
+            walk_threads: self.walk_threads,
+            events: self.events.clone(),
+            failure: self.failure.clone(),
         }
     }
 }
 
+/// A flush of a family's key stores, started by `Family::start_flush`.
+pub struct PendingFlush<B>(Vec<PendingReply<key::Reply<B>, key::MsgError>>);
+
+impl<B> PendingFlush<B> {
+    /// Wait until every key store has flushed.
+    pub fn wait(self) -> Result<(), HatError> {
+        // Collect every reply, even after an error: a process panics if its reply is dropped.
+        let mut res = Ok(());
+        for reply in self.0 {
+            let flushed = match reply.wait() {
+                Ok(key::Reply::FlushOk) => Ok(()),
+                Ok(_) => Err(From::from("Unexpected reply from key store")),
+                Err(e) => Err(From::from(e)),
+            };
+            if res.is_ok() {
+                res = flushed;
+            }
+        }
+        res
+    }
+}
+
 impl<B: StoreBackend> Family<B> {
     pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
-
-        let mut parent_path = PathBuf::from("/");
-
-        let dir = fs::canonicalize(dir).unwrap();
-        info!("Committing: {}", dir.display());
-        assert!(dir.is_absolute());
-
-        let mut bailout = false;
-        let mut parent = None;
-        let mut inside_non_dir = false;
-        for name in dir.iter().map(PathBuf::from).filter(|p| !p.has_root()) {
-            if inside_non_dir {
-                // The remaining part of the path is inside a link or similar.
-                // This should not happen, as the path was canonical.
-                warn!(
-                    "Ignoring components after non-dir path: {}",
-                    parent_path.display()
-                );
-                bailout = true;
-                break;
+        self.snapshot_dir_preemptible(dir, Preemption::new());
+    }
+
+    /// Like `snapshot_dir`, but stops early when `preemption` is requested.
+    /// Returns false if the snapshot was preempted; the files indexed so far are kept, and
+    /// nothing is removed from the family index.
+    pub fn snapshot_dir_preemptible(&self, dir: PathBuf, preemption: Preemption) -> bool {
+        self.snapshot_dirs_preemptible(vec![dir], preemption)
+    }
+
+    /// Snapshot each of `dirs` into the family, walking them at the same time. Directories
+    /// inside another of `dirs` are walked only as part of it. The next commit has them all
+    /// in one tree. Stops early when `preemption` is requested, as `snapshot_dir_preemptible`.
+    pub fn snapshot_dirs_preemptible(&self, dirs: Vec<PathBuf>, preemption: Preemption) -> bool {
+        self.snapshot_dirs_with_progress(dirs, preemption, None)
+    }
+
+    /// Like `snapshot_dirs_preemptible`, and counts the regular files handled in `progress`.
+    pub fn snapshot_dirs_with_progress(
+        &self,
+        dirs: Vec<PathBuf>,
+        preemption: Preemption,
+        progress: Option<Arc<SnapshotProgress>>,
+    ) -> bool {
+        let mut handler =
+            InsertPathHandler::new(self.key_store_process.clone(), preemption.clone())
+                .with_order(self.file_order)
+                .with_exclude(self.exclude.clone())
+                .with_changed_files(self.changed_policy, self.changed.clone())
+                .with_failure(self.failure.clone());
+        if let Some(ref filter) = self.content_filter {
+            handler = handler.with_content_filter(filter.clone(), self.filtered.clone());
+        }
+        if let Some(progress) = progress {
+            handler = handler.with_progress(progress);
+        }
+        if let Some(ref events) = self.events {
+            handler = handler.with_events(events.clone());
+        }
+
+        let mut dirs: Vec<PathBuf> = dirs.into_iter()
+            .map(|dir| fs::canonicalize(dir).unwrap())
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        let outer = dirs.clone();
+        dirs.retain(|dir| !outer.iter().any(|o| o != dir && dir.starts_with(o)));
+
+        // The directories above each root are inserted one root at a time, as roots can share
+        // them; only the walks below the roots run concurrently.
+        let mut roots = vec![];
+        for dir in dirs {
+            info!("Committing: {}", dir.display());
+            assert!(dir.is_absolute());
+            {
+                let mut sources = self.sources.lock().unwrap();
+                if !sources.contains(&dir) {
+                    sources.push(dir.clone());
+                }
             }
-            parent_path.push(name);
-            if let Some(new_parent) = handler.handle_path(&parent, &parent_path) {
-                parent = new_parent;
-            } else {
-                // Trigger warning if this is not the final component.
-                // If this is the final component, we just commit'ed a file or link, which is OK.
-                inside_non_dir = true;
+
+            let mut parent_path = PathBuf::from("/");
+            let mut bailout = false;
+            let mut parent = None;
+            let mut inside_non_dir = false;
+            for name in dir.iter().map(PathBuf::from).filter(|p| !p.has_root()) {
+                if inside_non_dir {
+                    // The remaining part of the path is inside a link or similar.
+                    // This should not happen, as the path was canonical.
+                    warn!(
+                        "Ignoring components after non-dir path: {}",
+                        parent_path.display()
+                    );
+                    bailout = true;
+                    break;
+                }
+                parent_path.push(name);
+                if let Some(new_parent) = handler.handle_path(&parent, &parent_path) {
+                    parent = new_parent;
+                } else {
+                    // Trigger warning if this is not the final component.
+                    // If this is the final component, we just commit'ed a file or link, which
+                    // is OK.
+                    inside_non_dir = true;
+                }
             }
+            roots.push((dir, parent, bailout));
         }
 
-        if !bailout && dir.is_dir() {
-            handler.recurse(PathBuf::from(&dir), parent);
+        let walks: Vec<_> = roots
+            .iter()
+            .filter(|&&(ref dir, _, bailout)| !bailout && dir.is_dir())
+            .map(|&(ref dir, parent, _)| (dir.clone(), parent))
+            .collect();
+        handler.recurse_all(walks, self.walk_threads);
+
+        // Batched small files get their data references before their entries are committed.
+        if let Err(e) = self.flush() {
+            self.failure.lock().unwrap().get_or_insert(e.to_string());
+        }
 
-            match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(Some(parent)))
-            {
-                Ok(key::Reply::Ok) => (),
-                _ => panic!("Unexpected reply from keystore"),
-            }
-        } else {
-            match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(None)) {
+        // Only a complete snapshot knows which entries are gone.
+        let completed = !preemption.is_requested() && self.failure.lock().unwrap().is_none();
+        for (dir, parent, bailout) in roots {
+            let cleanup = if completed && !bailout && dir.is_dir() {
+                Some(parent)
+            } else {
+                None
+            };
+            match self.key_store_process[0].send_reply(key::Msg::CommitReservedNodes(cleanup)) {
                 Ok(key::Reply::Ok) => (),
                 _ => panic!("Unexpected reply from keystore"),
             }
         }
+        completed
     }
 
     pub fn snapshot_direct(
@@ -301,10 +524,11 @@ impl<B: StoreBackend> Family<B> {
         is_directory: bool,
         contents: Option<FileIterator>,
     ) -> Result<u64, HatError> {
-        let f = if is_directory {
-            None
-        } else {
-            Some(Box::new(move |()| contents) as Box<FnBox<(), _>>)
+        let f = match contents {
+            Some(contents) if !is_directory => {
+                Some(Box::new(move |()| Ok(contents)) as Box<FnBox<(), _>>)
+            }
+            _ => None,
         };
         let ks = self.key_store_process.iter().last().unwrap();
         let id = match ks.send_reply(key::Msg::Insert(file, f))? {
@@ -314,14 +538,103 @@ impl<B: StoreBackend> Family<B> {
         Ok(id)
     }
 
-    pub fn flush(&self) -> Result<(), HatError> {
-        for ks in &self.key_store_process {
-            if let key::Reply::FlushOk = ks.send_reply(key::Msg::Flush)? {
-                continue;
+    /// Snapshot the entries of the tar archive read from `reader`, instead of files on disk.
+    /// The archive replaces all entries snapshotted before, and its paths are taken relative to
+    /// the root of the family; directories missing from it are added. Hard links and special
+    /// files are kept as unreadable entries, and owner names come from the ids, as for files on
+    /// disk. Returns the number of entries snapshotted.
+    pub fn snapshot_from_tar<R: Read + Send + 'static>(&self, reader: R) -> Result<u64, HatError> {
+        let reader = Arc::new(Mutex::new(TarReader::new(reader)));
+        let mut dirs: HashMap<PathBuf, u64> = HashMap::new();
+        let mut count = 0;
+        loop {
+            let entry = match reader.lock().unwrap().next_entry()? {
+                Some(entry) => entry,
+                None => break,
+            };
+            let path = tar_entry_path(&entry.name);
+            let name = match path.file_name() {
+                Some(name) => name.to_owned(),
+                // The root itself, as in archives of `.`.
+                None => continue,
+            };
+
+            // Add the directories above the entry that the archive did not list before it.
+            let mut parent = None;
+            let mut above = PathBuf::new();
+            for dir in path.parent().into_iter().flat_map(|p| p.iter()) {
+                above.push(dir);
+                if let Some(&id) = dirs.get(&above) {
+                    parent = Some(id);
+                    continue;
+                }
+                let name = dir.to_owned().into();
+                let e = key::Entry::new(parent, name, key::Data::DirPlaceholder, None);
+                let id = self.snapshot_direct_no_commit(e, true, None)?;
+                dirs.insert(above.clone(), id);
+                parent = Some(id);
+            }
+
+            // Modes include the file type, as those read from disk do.
+            let (data, file_type) = match entry.kind {
+                TarEntryKind::File => (key::Data::FilePlaceholder, libc::S_IFREG),
+                TarEntryKind::Directory => (key::Data::DirPlaceholder, libc::S_IFDIR),
+                TarEntryKind::Symlink(ref target) => (
+                    key::Data::Symlink(PathBuf::from(ffi::OsStr::from_bytes(target))),
+                    libc::S_IFLNK,
+                ),
+                TarEntryKind::HardLink(ref target) => (
+                    key::Data::Unreadable(format!(
+                        "hard link to {}",
+                        String::from_utf8_lossy(target)
+                    )),
+                    libc::S_IFREG,
+                ),
+                TarEntryKind::Other(flag) => (
+                    key::Data::Unreadable(format!("unsupported tar entry type {:?}", flag as char)),
+                    libc::S_IFREG,
+                ),
+            };
+            let is_directory = file_type == libc::S_IFDIR;
+            let mut e = key::Entry::new(parent, name.into(), data, None);
+            e.info.modified_ts_secs = Some(entry.mtime);
+            e.info.permissions = Some(fs::Permissions::from_mode(file_type | entry.mode));
+            e.info.user_id = Some(entry.uid);
+            e.info.group_id = Some(entry.gid);
+            let contents = if entry.kind == TarEntryKind::File {
+                e.info.byte_length = Some(entry.size);
+                Some(FileIterator::from_reader(Box::new(TarMember(reader.clone()))))
+            } else {
+                None
+            };
+
+            // The key store has read the contents it needs once it replies.
+            let id = self.snapshot_direct_no_commit(e, is_directory, contents)?;
+            if is_directory {
+                dirs.insert(path, id);
             }
-            return Err(From::from("Unexpected reply from key store"));
+            count += 1;
         }
-        Ok(())
+
+        let ks = self.key_store_process.iter().last().unwrap();
+        match ks.send_reply(key::Msg::CommitReservedNodes(Some(None))) {
+            Ok(key::Reply::Ok) => Ok(count),
+            _ => Err(From::from("Unexpected reply from keystore")),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), HatError> {
+        self.start_flush().wait()
+    }
+
+    /// Ask all key stores to flush, without waiting for them to finish.
+    pub fn start_flush(&self) -> PendingFlush<B> {
+        PendingFlush(
+            self.key_store_process
+                .iter()
+                .map(|ks| ks.send(key::Msg::Flush))
+                .collect(),
+        )
     }
 
     pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
@@ -367,7 +680,17 @@ impl<B: StoreBackend> Family<B> {
                     use std::os::unix::fs::symlink;
                     symlink(link_path, &path)?
                 }
-                _ => unreachable!("Unexpected data entry"),
+                key::Data::Unreadable(error) => {
+                    println!("Skipping '{}': unreadable: {}", path.display(), error);
+                    path.pop();
+                    continue;
+                }
+                key::Data::Special(special) => {
+                    let mode = entry.info.permissions.as_ref().map_or(0o644, |p| p.mode());
+                    let mode = special.file_type_mode() | (mode & 0o7777);
+                    make_special_file(&path, mode, special.rdev())?
+                }
+                key::Data::FileHash(_) => unreachable!("Unexpected data entry"),
             }
 
             if let Some(perms) = entry.info.permissions {
@@ -406,67 +729,128 @@ impl<B: StoreBackend> Family<B> {
         }
     }
 
+    /// Like `list_from_key_store`, but at most `limit` entries with node ids above `after`.
+    pub fn list_page_from_key_store(
+        &self,
+        dir_id: Option<u64>,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<key::DirElem<B>>, HatError> {
+        match self
+            .key_store_process
+            .iter()
+            .last()
+            .unwrap()
+            .send_reply(key::Msg::ListDirPage(dir_id, after, limit))?
+        {
+            key::Reply::ListResult(ls) => Ok(ls),
+            _ => Err(From::from("Unexpected result from key store")),
+        }
+    }
+
     pub fn fetch_dir_data<HTB: hash::tree::HashTreeBackend<Err = key::MsgError>>(
         dir_hash: hash::tree::HashRef,
         backend: HTB,
-    ) -> Result<Vec<(key::Entry, walker::Content)>, HatError> {
+    ) -> Result<DirIterator<HTB>, HatError> {
         let it = hash::tree::LeafIterator::new(backend, dir_hash)?.expect("unable to open dir");
-
-        let mut out = Vec::new();
-        for chunk in it {
-            if !chunk.is_empty() {
-                parse_dir_data(&chunk[..], &mut out)?;
-            }
-        }
-
-        Ok(out.into_iter().map(|f| (f.meta, f.hash_ref)).collect())
+        Ok(DirIterator {
+            leaves: Some(it),
+            files: Vec::new().into_iter(),
+        })
     }
 
-    pub fn commit<F>(&mut self, top_hash_fn: &F) -> Result<hash::tree::HashRef, HatError>
+    /// Commit the snapshot listing, and the integrity manifest of the files in it. Returns the
+    /// root of each, and the total size of the files; there is no manifest if the snapshot holds
+    /// no files.
+    pub fn commit<F>(
+        &mut self,
+        top_hash_fn: &F,
+    ) -> Result<(hash::tree::HashRef, Option<hash::tree::HashRef>, u64), HatError>
     where
         F: Fn(&hash::Hash),
     {
         let mut top_tree = self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-        self.commit_to_tree(&mut top_tree, None, top_hash_fn)?;
+        let mut integrity =
+            IntegrityWriter::new(self.key_store.hash_tree_writer(blob::LeafType::Manifest));
+        let mut file_bytes = 0;
+        self.commit_to_tree(
+            &mut top_tree,
+            None,
+            b"",
+            &mut integrity,
+            &mut file_bytes,
+            top_hash_fn,
+        )?;
+
+        let integrity_ref = integrity.finish()?;
+        if let Some(ref href) = integrity_ref {
+            top_hash_fn(&href.hash);
+        }
 
         let info = key::Info::new(self.name.clone().into(), None);
-        Ok(top_tree.hash(Some(&info))?)
+        Ok((top_tree.hash(Some(&info))?, integrity_ref, file_bytes))
     }
 
+    /// Write the listing of directory `dir_id` to `tree`, where `path` is its path in the
+    /// snapshot, add its files to `integrity`, and their sizes to `file_bytes`.
     pub fn commit_to_tree<F>(
         &mut self,
         tree: &mut hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
         dir_id: Option<u64>,
+        path: &[u8],
+        integrity: &mut IntegrityWriter<B>,
+        file_bytes: &mut u64,
         top_hash_fn: &F,
     ) -> Result<(), HatError>
     where
         F: Fn(&hash::Hash),
     {
         let files_at_a_time = 1024;
-        let mut it = self.list_from_key_store(dir_id)?.into_iter();
+        let mut after = None;
 
         loop {
+            // Fetch the directory a page at a time, so huge directories never sit in memory
+            // at once. Each page becomes its own leaf of the directory's tree.
+            let page = self.list_page_from_key_store(dir_id, after, files_at_a_time)?;
+            after = match page.last() {
+                Some((entry, _, _)) => entry.node_id,
+                None => break,
+            };
             let mut files = vec![];
 
-            for (idx, (entry, data_ref, _data_res_open)) in
-                it.by_ref().take(files_at_a_time).enumerate()
-            {
-                assert!(idx < files_at_a_time);
+            for (mut entry, data_ref, _data_res_open) in page {
+                owners::add_names(&*self.names, &mut entry.info);
+                let mut entry_path = path.to_vec();
+                if !entry_path.is_empty() {
+                    entry_path.push(b'/');
+                }
+                entry_path.extend_from_slice(entry.info.name.as_bytes());
 
                 let content = match entry.data {
                     key::Data::FilePlaceholder => {
+                        if let Some(ref checksum) = entry.checksum {
+                            integrity.add(&entry_path, checksum)?;
+                        }
+                        *file_bytes += entry.info.byte_length.unwrap_or(0);
                         // This is a file, store its data hash.
                         let href = data_ref.expect("Data::File");
                         top_hash_fn(&hash::Hash {
                             bytes: href.hash.bytes.clone(),
                         });
-                        models::Content::Data(href.to_model())
+                        walker::Content::Data(href).to_model(entry.info.hard_link)
                     }
                     key::Data::DirPlaceholder => {
                         // This is a directory, recurse!
                         let mut inner_tree =
                             self.key_store.hash_tree_writer(blob::LeafType::TreeList);
-                        self.commit_to_tree(&mut inner_tree, entry.node_id, top_hash_fn)?;
+                        self.commit_to_tree(
+                            &mut inner_tree,
+                            entry.node_id,
+                            &entry_path,
+                            integrity,
+                            file_bytes,
+                            top_hash_fn,
+                        )?;
 
                         // Store a reference for the sub-tree in our tree:
                         let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
@@ -478,7 +862,9 @@ impl<B: StoreBackend> Family<B> {
                         // Set symbolic link content.
                         models::Content::SymbolicLink(path.to_str().unwrap().into())
                     }
-                    _ => unreachable!("Unexpected key::Data"),
+                    key::Data::Unreadable(error) => models::Content::Unreadable(error),
+                    key::Data::Special(special) => models::Content::Special(special),
+                    key::Data::FileHash(_) => unreachable!("Unexpected key::Data"),
                 };
 
                 files.push(models::File {
@@ -488,15 +874,118 @@ impl<B: StoreBackend> Family<B> {
                 });
             }
 
-            // Flush to our own tree when we have a decent amount.
-            // The tree prevents large directories from clogging ram.
-            if files.is_empty() {
-                break;
-            } else {
-                tree.append(&serde_cbor::to_vec(&models::Files { files: files }).unwrap()[..])?;
-            }
+            tree.append(&serde_cbor::to_vec(&models::Files { files: files }).unwrap()[..])?;
         }
 
         Ok(())
     }
+    /// Write the listing of directory `dir` to `tree`, keeping only the entries `filter`
+    /// accepts, where `path` is the path of `dir` in its snapshot. Accepted entries keep their
+    /// stored contents; directories with only some entries accepted get a new listing.
+    /// `top_hash_fn` is called with every file and directory hash the new listing references,
+    /// including those below accepted directories. Returns the number of entries kept.
+    pub fn derive_to_tree<F>(
+        &mut self,
+        tree: &mut hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
+        dir: hash::tree::HashRef,
+        path: &[u8],
+        filter: &PathFilter,
+        backend: &key::HashStoreBackend<B>,
+        top_hash_fn: &F,
+    ) -> Result<usize, HatError>
+    where
+        F: Fn(&hash::Hash),
+    {
+        let files_at_a_time = 1024;
+        let mut files = vec![];
+        let mut kept = 0;
+
+        for item in Self::fetch_dir_data(dir, backend.clone())? {
+            let (entry, content) = item?;
+            let mut entry_path = path.to_vec();
+            if !entry_path.is_empty() {
+                entry_path.push(b'/');
+            }
+            entry_path.extend_from_slice(entry.info.name.as_bytes());
+
+            let content = match content {
+                walker::Content::Data(href) => {
+                    if !filter.matches(&entry_path) {
+                        continue;
+                    }
+                    top_hash_fn(&href.hash);
+                    walker::Content::Data(href).to_model(entry.info.hard_link)
+                }
+                walker::Content::Dir(href) => if filter.matches(&entry_path) {
+                    // Keep the whole directory as it is.
+                    for inner in list_snapshot(backend, href.clone()) {
+                        match inner? {
+                            walker::Content::Data(h) | walker::Content::Dir(h) => {
+                                top_hash_fn(&h.hash)
+                            }
+                            walker::Content::Link(_)
+                            | walker::Content::Unreadable(_)
+                            | walker::Content::Special(_) => (),
+                        }
+                    }
+                    top_hash_fn(&href.hash);
+                    models::Content::Directory(href.to_model())
+                } else if filter.may_match_below(&entry_path) {
+                    let mut inner_tree = self.key_store.hash_tree_writer(blob::LeafType::TreeList);
+                    let inner_kept = self.derive_to_tree(
+                        &mut inner_tree,
+                        href,
+                        &entry_path,
+                        filter,
+                        backend,
+                        top_hash_fn,
+                    )?;
+                    if inner_kept == 0 {
+                        continue;
+                    }
+                    let dir_hash_ref = inner_tree.hash(Some(&entry.info))?;
+                    top_hash_fn(&dir_hash_ref.hash);
+                    models::Content::Directory(dir_hash_ref.to_model())
+                } else {
+                    continue;
+                },
+                walker::Content::Link(link) => {
+                    if !filter.matches(&entry_path) {
+                        continue;
+                    }
+                    models::Content::SymbolicLink(link.to_str().unwrap().into())
+                }
+                walker::Content::Unreadable(error) => {
+                    if !filter.matches(&entry_path) {
+                        continue;
+                    }
+                    models::Content::Unreadable(error)
+                }
+                walker::Content::Special(special) => {
+                    if !filter.matches(&entry_path) {
+                        continue;
+                    }
+                    models::Content::Special(special)
+                }
+            };
+
+            kept += 1;
+            files.push(models::File {
+                id: entry.node_id.unwrap_or(0),
+                info: entry.info.to_model(),
+                content,
+            });
+            if files.len() == files_at_a_time {
+                let files = mem::take(&mut files);
+                let bytes = serde_cbor::to_vec(&models::Files { files }).unwrap();
+                tree.append(&bytes[..])?;
+            }
+        }
+        if !files.is_empty() {
+            let bytes = serde_cbor::to_vec(&models::Files { files }).unwrap();
+            tree.append(&bytes[..])?;
+        }
+
+        Ok(kept)
+    }
 }
@@ -13,14 +13,23 @@
 // limitations under the License.
 
 use backend::StoreBackend;
+use crypto::keys::Checksum;
+use hat::acl;
+use hat::content_filter::{ContentFilter, FilterDecision};
+use hat::seed::SnapshotProgress;
 use key;
+use models;
 use std::error::Error;
 use std::fs;
 use std::io;
-use std::path::PathBuf;
-use std::sync::{atomic, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{atomic, Arc, Mutex};
+use std::vec;
 use time;
-use util::{FileIterator, PathHandler, SyncPool};
+use std::os::unix::ffi::OsStrExt;
+use util::{read_xattrs, ChangeWatch, ChangedFilePolicy, ExcludeFilter, FileIterator, FileOrder,
+           FileStamp, PathHandler, Preemption, ProgressEvent, ProgressFn, SyncPool,
+           CHANGED_FILE_RETRIES};
 
 struct FileEntry {
     key_entry: key::Entry,
@@ -41,12 +50,24 @@ impl FileEntry {
             } else if meta.file_type().is_symlink() {
                 let path = fs::read_link(&full_path)?;
                 key::Data::Symlink(path)
+            } else if let Some(special) = models::SpecialFile::from_metadata(&meta) {
+                key::Data::Special(special)
             } else {
                 // Unsupported file type. Skipping.
                 return Err(From::from(format!("unknown file kind")));
             };
+            let name = filename.to_owned().into();
+            let mut key_entry = key::Entry::new(parent, name, data, Some(&meta));
+            key_entry.info.xattrs = match read_xattrs(&full_path) {
+                Ok(xattrs) => xattrs,
+                Err(e) => {
+                    warn!("Could not read extended attributes of {}: {}", full_path.display(), e);
+                    vec![]
+                }
+            };
+            key_entry.info.acl = acl::take_acl(&mut key_entry.info.xattrs);
             Ok(FileEntry {
-                key_entry: key::Entry::new(parent, filename.to_owned().into(), data, Some(&meta)),
+                key_entry: key_entry,
                 metadata: meta,
                 full_path: full_path,
             })
@@ -67,27 +88,142 @@ pub struct InsertPathHandler<B: StoreBackend> {
     count: atomic::AtomicIsize,
     last_print: Mutex<time::Timespec>,
     key_store: SyncPool<key::StoreProcess<FileIterator, B>>,
+    preemption: Preemption,
+    order: FileOrder,
+    exclude: ExcludeFilter,
+    content_filter: Option<Arc<ContentFilter>>,
+    filtered: Arc<Mutex<Vec<(PathBuf, String)>>>,
+    changed_policy: ChangedFilePolicy,
+    changed: Arc<Mutex<Vec<PathBuf>>>,
+    progress: Option<Arc<SnapshotProgress>>,
+    events: Option<ProgressFn>,
+    failure: Arc<Mutex<Option<String>>>,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
-    pub fn new(key_stores: Vec<key::StoreProcess<FileIterator, B>>) -> InsertPathHandler<B> {
+    pub fn new(
+        key_stores: Vec<key::StoreProcess<FileIterator, B>>,
+        preemption: Preemption,
+    ) -> InsertPathHandler<B> {
         InsertPathHandler {
             count: atomic::AtomicIsize::new(0),
             last_print: Mutex::new(time::now().to_timespec()),
             key_store: SyncPool::new(key_stores),
+            preemption: preemption,
+            order: FileOrder::default(),
+            exclude: ExcludeFilter::default(),
+            content_filter: None,
+            filtered: Arc::new(Mutex::new(vec![])),
+            changed_policy: ChangedFilePolicy::default(),
+            changed: Arc::new(Mutex::new(vec![])),
+            progress: None,
+            events: None,
+            failure: Arc::new(Mutex::new(None)),
         }
     }
+
+    /// Visit the entries of each directory in `order`.
+    pub fn with_order(mut self, order: FileOrder) -> InsertPathHandler<B> {
+        self.order = order;
+        self
+    }
+
+    /// Leave out the paths `exclude` excludes.
+    pub fn with_exclude(mut self, exclude: ExcludeFilter) -> InsertPathHandler<B> {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Pass regular files through `filter`, and add its decisions other than keeping a file to
+    /// `filtered`, with the path of the file.
+    pub fn with_content_filter(
+        mut self,
+        filter: Arc<ContentFilter>,
+        filtered: Arc<Mutex<Vec<(PathBuf, String)>>>,
+    ) -> InsertPathHandler<B> {
+        self.content_filter = Some(filter);
+        self.filtered = filtered;
+        self
+    }
+
+    /// Handle files that change while being read as `policy` says, and add those that changed
+    /// on the last read to `changed`.
+    pub fn with_changed_files(
+        mut self,
+        policy: ChangedFilePolicy,
+        changed: Arc<Mutex<Vec<PathBuf>>>,
+    ) -> InsertPathHandler<B> {
+        self.changed_policy = policy;
+        self.changed = changed;
+        self
+    }
+
+    /// Count the regular files handled in `progress`, and show it with the files being read.
+    pub fn with_progress(mut self, progress: Arc<SnapshotProgress>) -> InsertPathHandler<B> {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Keep the first error of the key store in `failure`, and stop the snapshot at it.
+    pub fn with_failure(mut self, failure: Arc<Mutex<Option<String>>>) -> InsertPathHandler<B> {
+        self.failure = failure;
+        self
+    }
+
+    /// Report the files handled and the bytes read to `events`.
+    pub fn with_events(mut self, events: ProgressFn) -> InsertPathHandler<B> {
+        self.events = Some(events);
+        self
+    }
+
+    /// What the content filter decides for the file at `path`; errors leave the file out.
+    fn filter_decision(&self, path: &Path) -> FilterDecision {
+        let filter = match self.content_filter {
+            Some(ref filter) => filter,
+            None => return FilterDecision::Keep,
+        };
+        let (decision, note) = match filter.filter(path) {
+            Ok(decision) => {
+                let note = format!("{} by content filter", decision.describe());
+                (decision, note)
+            }
+            Err(e) => {
+                println!("Skipping '{}': content filter failed: {}", path.display(), e);
+                (
+                    FilterDecision::Skip,
+                    format!("skipped, as the content filter failed: {}", e),
+                )
+            }
+        };
+        if decision != FilterDecision::Keep {
+            info!("{}: {}", path.display(), note);
+            self.filtered.lock().unwrap().push((path.to_owned(), note));
+        }
+        decision
+    }
 }
 
 impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
     type DirItem = fs::DirEntry;
-    type DirIter = fs::ReadDir;
+    type DirIter = vec::IntoIter<io::Result<fs::DirEntry>>;
 
     fn read_dir(&self, path: &PathBuf) -> io::Result<Self::DirIter> {
-        fs::read_dir(path)
+        let (entries, errors): (Vec<_>, Vec<_>) = fs::read_dir(path)?.partition(|e| e.is_ok());
+        let mut entries: Vec<_> = entries.into_iter().map(|e| e.unwrap()).collect();
+        self.order
+            .sort(&mut entries, |e| e.metadata().map(|m| m.len()).unwrap_or(0));
+        Ok(errors
+            .into_iter()
+            .chain(entries.into_iter().map(Ok))
+            .collect::<Vec<_>>()
+            .into_iter())
     }
 
     fn handle_path(&self, parent: &Option<u64>, path: &PathBuf) -> Option<Option<u64>> {
+        if self.preemption.is_requested() {
+            return None;
+        }
+
         let count = self.count.fetch_add(1, atomic::Ordering::SeqCst) + 1;
 
         if count % 16 == 0 {
@@ -95,7 +231,15 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             let mut guarded_last_print = self.last_print.lock().unwrap();
             let now = time::now().to_timespec();
             if guarded_last_print.sec <= now.sec - 1 {
-                println!("#{}: {}", count, path.display());
+                match self.progress {
+                    Some(ref progress) => println!(
+                        "#{} ({:.1}%): {}",
+                        count,
+                        progress.percent(),
+                        path.display()
+                    ),
+                    None => println!("#{}: {}", count, path.display()),
+                }
                 *guarded_last_print = now;
             }
         }
@@ -104,34 +248,120 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
             Err(e) => {
                 println!("Skipping '{}': {}", path.display(), e);
             }
+            Ok(ref file_entry)
+                if self.exclude
+                    .excludes(path.as_os_str().as_bytes(), file_entry.is_directory()) =>
+            {
+                debug!("Excluding '{}'", path.display());
+            }
             Ok(file_entry) => {
                 let is_file = file_entry.is_file();
                 let is_directory = file_entry.is_directory();
-                let local_root = path.clone();
+                let mut file_len = file_entry.metadata.len();
+                let mut stamp = FileStamp::new(&file_entry.metadata);
                 let full_path = file_entry.full_path.clone();
+                let preemption = self.preemption.clone();
+                let decision = if is_file {
+                    self.filter_decision(&full_path)
+                } else {
+                    FilterDecision::Keep
+                };
+                let mut key_entry = file_entry.key_entry;
+                let mut replacement = match decision {
+                    FilterDecision::Skip => return None,
+                    FilterDecision::Replace(contents) => {
+                        // Store the new contents even if the file looks unchanged.
+                        let mut checksum = Checksum::new();
+                        checksum.update(&contents[..]);
+                        key_entry.checksum = Some(checksum.finalize());
+                        key_entry.info.byte_length = Some(contents.len() as u64);
+                        Some(contents)
+                    }
+                    FilterDecision::Keep => None,
+                };
 
-                let ks = self.key_store.lock().unwrap();
-                match ks.send_reply(key::Msg::Insert(
-                    file_entry.key_entry,
-                    if is_file {
-                        Some(Box::new(move |()| match FileIterator::new(&full_path) {
-                            Err(e) => {
-                                println!("Skipping '{}': {}", local_root.display(), e.to_string());
-                                None
+                // Replacement contents do not change while being read.
+                let watched = is_file && replacement.is_none();
+                let mut attempt = 0;
+                loop {
+                    let changed = Arc::new(atomic::AtomicBool::new(false));
+                    let last_read = match self.changed_policy {
+                        ChangedFilePolicy::Retry => attempt == CHANGED_FILE_RETRIES,
+                        ChangedFilePolicy::Fuzzy => true,
+                        ChangedFilePolicy::Fail => false,
+                    };
+                    // A read that is not kept fails, so a partial file is not recorded.
+                    let watch =
+                        ChangeWatch::new(full_path.clone(), stamp, changed.clone(), !last_read);
+                    let contents = replacement.take();
+                    let path = full_path.clone();
+                    let preemption = preemption.clone();
+                    let events = self.events.clone();
+
+                    let ks = self.key_store.lock().unwrap();
+                    let id = match ks.send_reply(key::Msg::Insert(
+                        key_entry,
+                        if is_file {
+                            Some(Box::new(move |()| {
+                                let it = match contents {
+                                    Some(contents) => Ok(FileIterator::from_bytes(contents)),
+                                    None => FileIterator::new(&path),
+                                };
+                                it.map(|it| if watched { it.watched(watch) } else { it })
+                                    .map(|it| it.preemptible(preemption))
+                                    .map(|it| match events {
+                                        Some(events) => it.counted(events),
+                                        None => it,
+                                    })
+                                    .map_err(|e| e.to_string())
+                            }))
+                        } else {
+                            None
+                        },
+                    )) {
+                        Ok(key::Reply::Id(id)) => id,
+                        Err(e) => {
+                            // Such as the quota refusing a blob; the rest would fail alike.
+                            self.failure.lock().unwrap().get_or_insert(e.to_string());
+                            self.preemption.request();
+                            return None;
+                        }
+                        _ => panic!("Unexpected reply from key store."),
+                    };
+                    drop(ks);
+
+                    if changed.load(atomic::Ordering::SeqCst) && !self.preemption.is_requested() {
+                        if !last_read && self.changed_policy == ChangedFilePolicy::Retry {
+                            // Read it again as it is now; a file that is gone or no longer a
+                            // regular file is left unreadable.
+                            if let Ok(entry) = FileEntry::new(full_path.clone(), *parent) {
+                                if entry.is_file() {
+                                    attempt += 1;
+                                    file_len = entry.metadata.len();
+                                    stamp = FileStamp::new(&entry.metadata);
+                                    key_entry = entry.key_entry;
+                                    continue;
+                                }
                             }
-                            Ok(it) => Some(it),
-                        }))
-                    } else {
-                        None
-                    },
-                )) {
-                    Ok(key::Reply::Id(id)) => {
-                        if is_directory {
-                            return Some(Some(id));
+                        } else {
+                            info!("{}: changed while being read", full_path.display());
+                            self.changed.lock().unwrap().push(full_path.clone());
+                        }
+                    }
+
+                    // A file cut short by preemption is read again by the next run.
+                    if is_file && !self.preemption.is_requested() {
+                        if let Some(ref progress) = self.progress {
+                            progress.add_file(file_len);
                         }
+                        if let Some(ref events) = self.events {
+                            events(ProgressEvent::FileScanned(file_len));
+                        }
+                    }
+                    if is_directory {
+                        return Some(Some(id));
                     }
-                    Err(e) => panic!("Error from key store: {:?}", e),
-                    _ => panic!("Unexpected reply from key store."),
+                    break;
                 }
             }
         }
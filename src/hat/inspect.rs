@@ -0,0 +1,156 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Introspection of a repository for tests and fuzz targets.
+//!
+//! An `Inspector` reads the indexes and backend behind a `Hat` directly, so tests can check
+//! deduplication and GC invariants instead of only what `ls` shows.
+
+use backend::{shared, StoreBackend};
+use blob;
+use crypto;
+use db;
+use errors::HatError;
+use hash;
+use hex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// A comparable copy of the local indexes at one point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexSnapshot {
+    /// Every hash in the hash index, with the name of the blob holding it, if any.
+    pub hashes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Names of the blobs in the blob index.
+    pub blobs: BTreeSet<Vec<u8>>,
+    /// Snapshots as (family name, snapshot id).
+    pub snapshots: BTreeSet<(String, u64)>,
+}
+
+pub struct Inspector<B> {
+    keys: Arc<crypto::keys::Keeper>,
+    db: Arc<db::Index>,
+    hash_index: Arc<hash::HashIndex>,
+    backend: Arc<B>,
+}
+
+impl<B: StoreBackend> Inspector<B> {
+    pub fn new(
+        keys: Arc<crypto::keys::Keeper>,
+        db: Arc<db::Index>,
+        hash_index: Arc<hash::HashIndex>,
+        backend: Arc<B>,
+    ) -> Inspector<B> {
+        Inspector {
+            keys: keys,
+            db: db,
+            hash_index: hash_index,
+            backend: backend,
+        }
+    }
+
+    /// Number of distinct file data chunks in the hash index. Storing the same data again must not
+    /// change it.
+    pub fn chunk_count(&self) -> usize {
+        self.hash_index
+            .list()
+            .iter()
+            .filter(|e| e.node == blob::NodeType::Leaf && e.leaf == blob::LeafType::FileChunk)
+            .count()
+    }
+
+    /// The packing of each stored file data chunk.
+    pub fn chunk_packings(&self) -> Vec<Option<blob::Packing>> {
+        self.hash_index
+            .list()
+            .into_iter()
+            .filter(|e| e.node == blob::NodeType::Leaf && e.leaf == blob::LeafType::FileChunk)
+            .filter_map(|e| e.persistent_ref.map(|r| r.packing))
+            .collect()
+    }
+
+    pub fn index_snapshot(&self) -> IndexSnapshot {
+        let hashes = self.hash_index
+            .list()
+            .into_iter()
+            .map(|e| {
+                // Empty chunks are not stored in any blob.
+                let stored = e.persistent_ref.filter(|r| r.length > 0);
+                (e.hash.bytes, stored.map(|r| r.blob_name))
+            })
+            .collect();
+        let mut index = self.db.lock();
+        let blobs = index.blob_names().into_iter().collect();
+        let snapshots = index
+            .snapshot_list(None)
+            .into_iter()
+            .map(|s| (s.family_name, s.info.snapshot_id))
+            .collect();
+        IndexSnapshot {
+            hashes: hashes,
+            blobs: blobs,
+            snapshots: snapshots,
+        }
+    }
+
+    /// Names of the blobs stored in the backend.
+    pub fn stored_blobs(&self) -> Result<BTreeSet<Vec<u8>>, HatError> {
+        Ok(self.backend
+            .list()?
+            .into_iter()
+            .filter(|name| !shared::is_control_name(name))
+            .map(|name| name.to_vec())
+            .collect())
+    }
+
+    /// The chunks stored in blob `name`, in the order they were added.
+    pub fn blob_chunks(&self, name: &[u8]) -> Result<Vec<Vec<u8>>, HatError> {
+        let ct = self.backend
+            .retrieve(name)?
+            .ok_or_else(|| format!("Blob {} is missing", hex::encode(name)))?;
+        let mut reader = blob::BlobReader::new(self.keys.clone(), crypto::CipherTextRef::new(&ct[..]))?;
+        let mut chunks = vec![];
+        for href in reader.refs()? {
+            chunks.push(reader.read_chunk(&href)?);
+        }
+        Ok(chunks)
+    }
+
+    /// Blobs referenced by the hash index that are missing from the backend.
+    /// GC must never create any.
+    pub fn dangling_blobs(&self) -> Result<BTreeSet<Vec<u8>>, HatError> {
+        let stored = self.stored_blobs()?;
+        Ok(self.referenced_blobs()
+            .into_iter()
+            .filter(|name| !stored.contains(name))
+            .collect())
+    }
+
+    /// Blobs in the backend that no hash references. GC leaves none behind.
+    pub fn unreferenced_blobs(&self) -> Result<BTreeSet<Vec<u8>>, HatError> {
+        let referenced = self.referenced_blobs();
+        Ok(self.stored_blobs()?
+            .into_iter()
+            .filter(|name| !referenced.contains(name))
+            .collect())
+    }
+
+    fn referenced_blobs(&self) -> BTreeSet<Vec<u8>> {
+        self.index_snapshot()
+            .hashes
+            .into_values()
+            .flatten()
+            .collect()
+    }
+}
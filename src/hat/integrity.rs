@@ -0,0 +1,220 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The integrity manifest of a snapshot: every file path in it with the checksum of its
+//! contents, in the format of `b2sum`.
+//!
+//! The manifest is written at commit and stored as an encrypted tree of its own, next to the
+//! snapshot listing. Checking a restored tree against it needs no walk over the snapshot's
+//! directories, and a shown manifest can be checked with `b2sum -c` as well.
+
+use backend::StoreBackend;
+use crypto::keys::Checksum;
+use errors::HatError;
+use hash;
+use hat::HatRc;
+use hex;
+use key;
+use std::ffi;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub struct IntegrityWriter<B: StoreBackend> {
+    tree: hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
+    buf: Vec<u8>,
+    files: usize,
+}
+
+impl<B: StoreBackend> IntegrityWriter<B> {
+    pub fn new(
+        tree: hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
+    ) -> IntegrityWriter<B> {
+        IntegrityWriter {
+            tree: tree,
+            buf: vec![],
+            files: 0,
+        }
+    }
+
+    /// Record that the file at `path`, relative to the snapshot root, has `checksum`.
+    pub fn add(&mut self, path: &[u8], checksum: &[u8]) -> Result<(), HatError> {
+        self.buf.extend(format_line(path, checksum));
+        self.files += 1;
+        if self.buf.len() >= key::MAX_CHUNK_LEN {
+            self.tree.append(&self.buf[..])?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    /// Store what is left and return the root of the manifest, or `None` if it lists no files.
+    pub fn finish(mut self) -> Result<Option<hash::tree::HashRef>, HatError> {
+        if self.files == 0 {
+            return Ok(None);
+        }
+        if !self.buf.is_empty() {
+            self.tree.append(&self.buf[..])?;
+        }
+        Ok(Some(self.tree.hash(None)?))
+    }
+}
+
+/// One line of `b2sum` output. Names with a newline or backslash are escaped as `b2sum` does:
+/// the line starts with a backslash, and the name has `\n` and `\\` in their place.
+fn format_line(path: &[u8], checksum: &[u8]) -> Vec<u8> {
+    let escape = path.iter().any(|&b| b == b'\n' || b == b'\\');
+    let mut line = vec![];
+    if escape {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(hex::encode(checksum).as_bytes());
+    line.extend_from_slice(b"  ");
+    for &b in path {
+        match b {
+            b'\n' if escape => line.extend_from_slice(b"\\n"),
+            b'\\' if escape => line.extend_from_slice(b"\\\\"),
+            b => line.push(b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+/// Parse a line written by `format_line` into path and checksum.
+fn parse_line(line: &[u8]) -> Result<(Vec<u8>, Vec<u8>), HatError> {
+    let (escaped, line) = match line.first() {
+        Some(&b'\\') => (true, &line[1..]),
+        _ => (false, line),
+    };
+    let split = match line.windows(2).position(|w| w == b"  ") {
+        Some(split) => split,
+        None => return Err("Malformed line in integrity manifest".into()),
+    };
+    let checksum = hex::decode(&line[..split])
+        .map_err(|e| format!("Malformed checksum in integrity manifest: {}", e))?;
+    let name = &line[split + 2..];
+    if !escaped {
+        return Ok((name.to_vec(), checksum));
+    }
+    let mut path = vec![];
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        path.push(match b {
+            b'\\' => match bytes.next() {
+                Some(&b'n') => b'\n',
+                Some(&b'\\') => b'\\',
+                _ => return Err("Malformed escape in integrity manifest".into()),
+            },
+            b => b,
+        });
+    }
+    Ok((path, checksum))
+}
+
+/// The checksum of the contents of the file at `path`, as `b2sum` prints it.
+pub fn file_checksum(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    let mut checksum = Checksum::new();
+    let mut buf = vec![0; key::MAX_CHUNK_LEN];
+    loop {
+        match file.read(&mut buf[..])? {
+            0 => return Ok(checksum.finalize()),
+            n => checksum.update(&buf[..n]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IntegrityReport {
+    /// Files whose contents match the manifest.
+    pub checked: usize,
+    /// Files in the manifest that are missing from the tree, or could not be read.
+    pub missing: Vec<PathBuf>,
+    /// Files whose contents differ from the manifest.
+    pub changed: Vec<PathBuf>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// The root of the integrity manifest of snapshot `snapshot_id` of `family`, if it has one.
+    pub fn integrity_ref(
+        &mut self,
+        family: &str,
+        snapshot_id: u64,
+    ) -> Result<Option<hash::tree::HashRef>, HatError> {
+        let snapshot = self
+            .list_snapshots()
+            .into_iter()
+            .find(|s| s.family_name == family && s.info.snapshot_id == snapshot_id)
+            .ok_or_else(|| format!("No snapshot {}/{}", family, snapshot_id))?;
+        match snapshot.manifest.and_then(|m| m.integrity) {
+            Some(bytes) => Ok(Some(hash::tree::HashRef::from_bytes(&bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The integrity manifest of snapshot `snapshot_id` of `family`, in the format of `b2sum`.
+    pub fn integrity_manifest(
+        &mut self,
+        family: &str,
+        snapshot_id: u64,
+    ) -> Result<Vec<u8>, HatError> {
+        let root = match self.integrity_ref(family, snapshot_id)? {
+            Some(root) => root,
+            None => {
+                return Err(From::from(format!(
+                    "Snapshot {}/{} has no integrity manifest; it holds no files, or was \
+                     committed before manifests were recorded",
+                    family, snapshot_id
+                )))
+            }
+        };
+        let mut manifest = vec![];
+        for chunk in hash::tree::LeafIterator::new(self.hash_backend(), root)?.unwrap() {
+            manifest.extend_from_slice(&chunk[..]);
+        }
+        Ok(manifest)
+    }
+
+    /// Check the files below `root` against the integrity manifest of snapshot `snapshot_id` of
+    /// `family`. Files that are not in the manifest are ignored.
+    pub fn verify_integrity(
+        &mut self,
+        family: &str,
+        snapshot_id: u64,
+        root: &Path,
+    ) -> Result<IntegrityReport, HatError> {
+        let manifest = self.integrity_manifest(family, snapshot_id)?;
+        let mut report = IntegrityReport::default();
+
+        for line in manifest.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let (path, expected) = parse_line(line)?;
+            let path = root.join(ffi::OsStr::from_bytes(&path[..]));
+            match file_checksum(&path) {
+                Ok(ref sum) if *sum == expected => report.checked += 1,
+                Ok(_) => report.changed.push(path),
+                Err(_) => report.missing.push(path),
+            }
+        }
+
+        Ok(report)
+    }
+}
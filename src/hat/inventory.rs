@@ -0,0 +1,105 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks what the backend stores against the local blob index, without downloading blobs.
+//!
+//! The backend's listing shows blobs that went missing. For a sample of the rest, the backend
+//! is asked for the checksum of what it stores, which catches bit rot on the storage side when
+//! compared to the checksum recorded on upload. Backends that cannot compute checksums leave
+//! the sample unchecked; `hat verify` downloads and checks every blob instead.
+
+use backend::{shared, StoreBackend};
+use blob;
+use errors::HatError;
+use hat::HatRc;
+use rand;
+use std::collections::BTreeSet;
+
+/// Blobs sampled for checksums by default.
+pub const DEFAULT_SAMPLE: usize = 100;
+
+#[derive(Clone, Debug, Default)]
+pub struct InventoryReport {
+    /// Committed blobs in the blob index.
+    pub blobs: usize,
+    /// Committed blobs the backend does not list.
+    pub missing: Vec<blob::BlobDesc>,
+    /// Blobs the backend lists that the blob index does not know of, e.g. from an interrupted
+    /// commit. GC deletes them.
+    pub unknown: usize,
+    /// Sampled blobs whose checksum matched the one recorded on upload.
+    pub checked: usize,
+    /// Sampled blobs without a recorded checksum, or that the backend has no checksum for.
+    pub unchecked: usize,
+    /// Sampled blobs whose checksum differs from the one recorded on upload, or could not be
+    /// read.
+    pub failures: Vec<(blob::BlobDesc, String)>,
+}
+
+impl InventoryReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.failures.is_empty()
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Compare the backend's listing with the blob index, and the checksums of up to `sample`
+    /// randomly chosen committed blobs with those recorded on upload.
+    pub fn check_inventory(&self, sample: usize) -> Result<InventoryReport, HatError> {
+        let listed: BTreeSet<Box<[u8]>> = self.backend
+            .list()?
+            .into_iter()
+            .filter(|name| !shared::is_control_name(name))
+            .collect();
+        let blobs = self.blob_index.checksums();
+
+        let known: BTreeSet<&[u8]> = blobs.iter().map(|(b, _)| &b.name[..]).collect();
+        let mut report = InventoryReport {
+            blobs: blobs.len(),
+            unknown: listed.iter().filter(|n| !known.contains(&n[..])).count(),
+            ..InventoryReport::default()
+        };
+
+        let mut present = vec![];
+        for (blob, checksum) in blobs {
+            if listed.contains(&blob.name[..]) {
+                present.push((blob, checksum));
+            } else {
+                report.missing.push(blob);
+            }
+        }
+
+        let sampled = rand::seq::sample_iter(&mut rand::thread_rng(), present, sample)
+            .unwrap_or_else(|all| all);
+        for (blob, recorded) in sampled {
+            let recorded = match recorded {
+                Some(sum) => sum,
+                None => {
+                    report.unchecked += 1;
+                    continue;
+                }
+            };
+            match self.backend.checksum(&blob.name) {
+                Ok(Some(ref sum)) if *sum == recorded => report.checked += 1,
+                Ok(Some(_)) => report
+                    .failures
+                    .push((blob, "checksum differs from the one recorded on upload".into())),
+                Ok(None) => report.unchecked += 1,
+                Err(e) => report.failures.push((blob, e)),
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compaction of the local databases in a state directory.
+//!
+//! SQLite keeps the pages freed by GC and deleted snapshots, and a family's key index stays
+//! behind after its last snapshot is gone, so long-lived state directories only grow.
+//! `compact` returns that space, and `due` decides when doing so is worthwhile.
+
+use db;
+use errors::HatError;
+use key;
+use snapshot;
+use status::{OperationState, StatusLog};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use super::seed::SeedState;
+use super::{hash_index_path, path_index_path};
+
+/// Holds the time and resulting size of the last compaction.
+pub const MAINTENANCE_FILENAME: &str = "maintenance";
+
+/// Space used by one local database.
+#[derive(Clone, Debug)]
+pub struct DatabaseUsage {
+    pub path: PathBuf,
+    pub bytes: u64,
+    /// Bytes held by free pages.
+    pub free_bytes: u64,
+}
+
+/// Thresholds that make a compaction due.
+#[derive(Clone, Copy, Debug)]
+pub struct Triggers {
+    /// Compact at least this often.
+    pub max_age_secs: i64,
+    /// Compact once free pages make up this percentage of the databases.
+    pub max_free_percent: u64,
+    /// Compact once the databases are this many times their size after the last compaction.
+    pub max_growth: u64,
+}
+
+impl Default for Triggers {
+    fn default() -> Triggers {
+        Triggers {
+            max_age_secs: 30 * 24 * 3600,
+            max_free_percent: 25,
+            max_growth: 2,
+        }
+    }
+}
+
+/// The outcome of the last compaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub ts_utc: i64,
+    pub bytes: u64,
+}
+
+impl Record {
+    /// The last compaction of `state_dir`, if any was recorded.
+    pub fn load(state_dir: &Path) -> Option<Record> {
+        let text = fs::read_to_string(state_dir.join(MAINTENANCE_FILENAME)).ok()?;
+        let mut fields = text.split_whitespace().map(|f| f.parse::<i64>());
+        match (fields.next(), fields.next()) {
+            (Some(Ok(ts_utc)), Some(Ok(bytes))) if bytes >= 0 => Some(Record {
+                ts_utc: ts_utc,
+                bytes: bytes as u64,
+            }),
+            _ => None,
+        }
+    }
+
+    fn store(&self, state_dir: &Path) -> Result<(), HatError> {
+        let text = format!("{} {}\n", self.ts_utc, self.bytes);
+        Ok(fs::write(state_dir.join(MAINTENANCE_FILENAME), text)?)
+    }
+}
+
+/// What a compaction did.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Families whose key index was removed, as they have no snapshots left.
+    pub pruned_families: Vec<String>,
+    /// Key index nodes removed, as no data refers to them.
+    pub pruned_nodes: usize,
+}
+
+fn is_database(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    match fs::File::open(path) {
+        Ok(mut f) => f.read_exact(&mut header).is_ok() && &header == b"SQLite format 3\0",
+        Err(_) => false,
+    }
+}
+
+/// The key indexes of `state_dir` by family name.
+fn key_indexes(state_dir: &Path) -> Result<Vec<(String, PathBuf)>, HatError> {
+    let hash_index = PathBuf::from(hash_index_path(state_dir));
+    let path_index = PathBuf::from(path_index_path(state_dir));
+    let mut found = vec![];
+    for entry in fs::read_dir(state_dir.join("cache"))? {
+        let path = entry?.path();
+        if path == hash_index || path == path_index || !is_database(&path) {
+            continue;
+        }
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            found.push((name.to_owned(), path.clone()));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Space used by the local databases of `state_dir`, the hash and path indexes first.
+pub fn usage(state_dir: &Path) -> Result<Vec<DatabaseUsage>, HatError> {
+    let mut paths = vec![
+        PathBuf::from(hash_index_path(state_dir)),
+        PathBuf::from(path_index_path(state_dir)),
+    ];
+    paths.extend(key_indexes(state_dir)?.into_iter().map(|(_, path)| path));
+
+    let mut usage = vec![];
+    for path in paths {
+        if !path.exists() {
+            // Nothing was committed yet.
+            continue;
+        }
+        let bytes = fs::metadata(&path)?.len();
+        let free_bytes = db::free_bytes(&path.to_string_lossy())?;
+        usage.push(DatabaseUsage {
+            path: path,
+            bytes: bytes,
+            free_bytes: free_bytes,
+        });
+    }
+    Ok(usage)
+}
+
+/// Why the databases described by `usage` should be compacted at `now_utc`, if they should.
+pub fn due(
+    triggers: &Triggers,
+    last: Option<Record>,
+    usage: &[DatabaseUsage],
+    now_utc: i64,
+) -> Option<String> {
+    if usage.is_empty() {
+        return None;
+    }
+    let bytes: u64 = usage.iter().map(|u| u.bytes).sum();
+    let free_bytes: u64 = usage.iter().map(|u| u.free_bytes).sum();
+
+    if bytes > 0 && free_bytes * 100 >= bytes * triggers.max_free_percent {
+        return Some(format!("{} of {} bytes are free pages", free_bytes, bytes));
+    }
+    match last {
+        None => Some("never compacted".to_owned()),
+        Some(r) if now_utc - r.ts_utc >= triggers.max_age_secs => Some(format!(
+            "last compacted {} days ago",
+            (now_utc - r.ts_utc) / (24 * 3600)
+        )),
+        Some(r) if r.bytes > 0 && bytes >= r.bytes * triggers.max_growth => Some(format!(
+            "grew from {} to {} bytes since the last compaction",
+            r.bytes, bytes
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Prune and compact the local databases of `state_dir`, and record having done so.
+/// Fails if another process is working in `state_dir`, as compaction needs the databases
+/// for itself.
+pub fn compact(state_dir: &Path, now_utc: i64) -> Result<Summary, HatError> {
+    let busy = StatusLog::open(state_dir)?
+        .operations()
+        .iter()
+        .any(|op| op.pid != process::id() && op.state() == OperationState::Running);
+    if busy {
+        return Err("Another hat process is using the state directory".into());
+    }
+
+    let mut summary = Summary {
+        bytes_before: usage(state_dir)?.iter().map(|u| u.bytes).sum(),
+        ..Default::default()
+    };
+
+    let mut live: BTreeSet<String> = {
+        let index = Arc::new(db::Index::new(&hash_index_path(state_dir))?);
+        snapshot::SnapshotIndex::new(index)
+            .list_all()
+            .into_iter()
+            .map(|s| s.family_name)
+            .collect()
+    };
+    live.extend(SeedState::load_any(state_dir).map(|state| state.family));
+
+    // A key index only speeds up the next commit of its family, so one without snapshots
+    // is dead weight, unless it holds the files read so far by an unfinished seed.
+    for (family, path) in key_indexes(state_dir)? {
+        if live.contains(&family) {
+            summary.pruned_nodes += key::KeyIndex::new(&path.to_string_lossy())?.prune_orphans()?;
+        } else {
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(state_dir.join("cache").join(format!("{}-journal", family)));
+            summary.pruned_families.push(family);
+        }
+    }
+
+    for database in usage(state_dir)? {
+        db::compact(&database.path.to_string_lossy())?;
+    }
+
+    summary.bytes_after = usage(state_dir)?.iter().map(|u| u.bytes).sum();
+    Record {
+        ts_utc: now_utc,
+        bytes: summary.bytes_after,
+    }.store(state_dir)?;
+
+    Ok(summary)
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use backend::StoreBackend;
+use backend::{shared, StoreBackend, UncachedBackend};
 use blob;
 use chrono;
 use crypto;
@@ -28,25 +28,57 @@ use secstr::SecStr;
 use serde_cbor;
 use snapshot;
 use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::ffi;
+use std::fmt;
 use std::fs;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::str;
-use std::sync::{mpsc, Arc};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tags;
-use util::Process;
+use util::{self, Deadline, Process};
 use void::Void;
 
+mod acl;
+pub mod chunks;
+pub mod content_filter;
+pub mod diff;
+pub mod estimate;
+pub mod export;
 mod family;
+#[cfg(any(test, feature = "testing"))]
+pub mod inspect;
+pub mod integrity;
+pub mod inventory;
 mod insert_path_handler;
+pub mod maintenance;
+pub mod owners;
+mod reader;
+pub mod retention;
+mod rotate;
+pub mod seed;
+pub mod selftest;
+pub mod stats;
+pub mod sync;
+pub mod verify;
 pub mod walker;
-pub use self::family::Family;
+pub use blob::{
+    ChunkCache, ChunkStore, Compression, KeyUsage, MemoryChunkStore, RetrieveFailure,
+    RetrieveMetrics, StoreMetrics, CHUNK_CACHE_DIRNAME, COMPRESSION_FILENAME,
+    DEFAULT_RESTORE_POLL, DEFAULT_RESTORE_WAIT,
+};
+pub use db::{is_internal_family, ROOTS_FAMILY_NAME};
+pub use key::{Chunking, ChunkingProfiles, CHUNKING_FILENAME};
+pub use self::family::{DirIterator, Family};
+pub use self::reader::{ChunkReader, HashReader};
 
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 #[cfg(test)]
-mod tests;
+pub mod tests;
 
 pub struct GcBackend {
     hash_index: Arc<hash::HashIndex>,
@@ -117,6 +149,45 @@ impl gc::GcBackend for GcBackend {
     }
 }
 
+/// The outcome of a garbage collection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct GcSummary {
+    pub deleted_hashes: u64,
+    pub live_blobs: u64,
+    /// Unused blobs the backend refused to delete, as they are inside its immutability window.
+    pub retained_blobs: u64,
+    /// False if GC stopped at its deadline, leaving some garbage for the next one.
+    pub completed: bool,
+}
+
+/// How far a commit goes before the command returns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// The snapshot is committed in the local index. Its data is stored too, as the index only
+    /// records data the backend has acknowledged, but the snapshot list that `recover` reads
+    /// from the backend is left for the next commit to upload.
+    Flushed,
+    /// The snapshot list is uploaded as well, and the backend has acknowledged every blob.
+    #[default]
+    Uploaded,
+    /// Every blob stored by the commit is also read back from the backend and checked.
+    Verified,
+}
+
+impl Durability {
+    pub fn parse(s: &str) -> Result<Durability, String> {
+        match s {
+            "flushed" => Ok(Durability::Flushed),
+            "uploaded" => Ok(Durability::Uploaded),
+            "verified" => Ok(Durability::Verified),
+            _ => Err(format!(
+                "Invalid durability '{}'; use flushed, uploaded or verified",
+                s
+            )),
+        }
+    }
+}
+
 pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     keys: Arc<crypto::keys::Keeper>,
     repository_root: Option<PathBuf>,
@@ -127,12 +198,50 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     backend: Arc<B>,
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    /// The blob stores dedicated to file data of the families opened so far.
+    data_blob_stores: Vec<Arc<blob::BlobStore<B>>>,
     blob_max_size: usize,
+    /// Compression of the chunks stored from now on, by all blob stores.
+    compression: blob::Compression,
+    chunking: Arc<key::ChunkingProfiles>,
+    file_order: util::FileOrder,
+    exclude: util::ExcludeFilter,
+    changed_policy: util::ChangedFilePolicy,
+    content_filter: Option<Arc<content_filter::ContentFilter>>,
+    names: Arc<owners::NameResolver>,
+    owner_mapping: owners::OwnerMapping,
+    /// Number of files each family snapshots at the same time.
+    jobs: usize,
+    /// Number of blobs a checkout fetches at the same time.
+    fetch_jobs: usize,
     gc: G,
+    /// Set when the backend is shared with other state directories.
+    writer: Option<String>,
+    remote_lock: Option<shared::RemoteLock>,
+    /// The generation of the backend read by `lock_backend`, set until `unlock_backend`.
+    locked_generation: Option<u64>,
+    /// The generation of the backend the local indexes were last synced with, see `sync`.
+    synced_generation: Option<u64>,
+    /// The optional index of the paths in each snapshot, see `rebuild_path_index`.
+    path_index: Option<db::PathIndex>,
+    /// The message and tags of the snapshots committed from now on.
+    commit_message: Option<String>,
+    commit_tags: Vec<String>,
+    /// Receives the progress events of snapshots, uploads and checkouts.
+    progress: Option<util::ProgressFn>,
 }
 
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
 
+/// Number of files a family snapshots at the same time by default.
+pub const DEFAULT_JOBS: usize = 2;
+
+/// Number of blobs `reseal_oldest_blobs` looks at by default.
+pub const DEFAULT_RESEAL_LIMIT: usize = 100;
+
+/// Number of blobs verified concurrently, to keep the link to the backend busy.
+const VERIFY_PARALLEL_BLOBS: usize = 4;
+
 fn concat_filename(mut a: PathBuf, b: &str) -> String {
     a.push(b);
     a.into_os_string().into_string().unwrap()
@@ -142,8 +251,55 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+fn path_index_name(root: PathBuf) -> String {
+    concat_filename(root, "path_index.sqlite3")
+}
+
+/// The path of the local index of the repository with state directory `repository_root`.
+pub fn hash_index_path(repository_root: &Path) -> String {
+    hash_index_name(repository_root.join("cache"))
+}
+
+/// The path of the path index of the repository with state directory `repository_root`.
+pub fn path_index_path(repository_root: &Path) -> String {
+    path_index_name(repository_root.join("cache"))
+}
+
+/// List snapshots with unfinished work that `resume()` would pick up, without resuming them.
+pub fn list_unfinished_snapshots(
+    repository_root: &Path,
+) -> Result<Vec<db::SnapshotStatus>, HatError> {
+    let db_p = Arc::new(db::Index::new(&hash_index_path(repository_root))?);
+    Ok(snapshot::SnapshotIndex::new(db_p).list_not_done())
+}
+
+/// The chains of snapshots to compact so that one snapshot per calendar month (UTC) is left
+/// among those created before `before`. `snapshots` are the `(id, created)` of the complete
+/// snapshots of a family, by id. Each chain is the first and last snapshot of a month that has
+/// more than one.
+pub fn chains_by_month(
+    snapshots: &[(u64, chrono::DateTime<chrono::Utc>)],
+    before: chrono::DateTime<chrono::Utc>,
+) -> Vec<(u64, u64)> {
+    use chrono::Datelike;
+
+    let mut chains: Vec<((i32, u32), u64, u64)> = vec![];
+    for &(id, created) in snapshots.iter().filter(|&&(_, created)| created < before) {
+        let month = (created.year(), created.month());
+        match chains.last_mut() {
+            Some(&mut (m, _, ref mut last)) if m == month => *last = id,
+            _ => chains.push((month, id, id)),
+        }
+    }
+    chains
+        .into_iter()
+        .filter(|&(_, first, last)| first != last)
+        .map(|(_, first, last)| (first, last))
+        .collect()
+}
+
 fn synthetic_roots_family() -> String {
-    From::from("__hat__roots__")
+    From::from(ROOTS_FAMILY_NAME)
 }
 
 struct SnapshotLister<'a, B: StoreBackend> {
@@ -154,7 +310,8 @@ struct SnapshotLister<'a, B: StoreBackend> {
 
 impl<'a, B: StoreBackend> SnapshotLister<'a, B> {
     fn fetch(&mut self, hash_ref: hash::tree::HashRef) -> Result<(), HatError> {
-        let res = family::Family::<B>::fetch_dir_data(hash_ref, self.backend.clone())?;
+        let res = family::Family::<B>::fetch_dir_data(hash_ref, self.backend.clone())?
+            .collect::<Result<Vec<_>, _>>()?;
         for (_entry, hash_ref) in res.into_iter().rev() {
             let is_dir = match &hash_ref {
                 &walker::Content::Dir(_) => true,
@@ -200,21 +357,164 @@ fn list_snapshot<'a, B: StoreBackend>(
     }
 }
 
+/// What resuming an unfinished operation on a snapshot does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResumeAction {
+    /// Upload the rest of an interrupted commit.
+    Commit,
+    /// Mark a commit whose data is all uploaded as done.
+    FinishCommit,
+    /// Download the rest of an interrupted recovery.
+    Recover,
+    /// Release the data of an interrupted delete.
+    Delete,
+    /// Forget a deleted snapshot whose data is all released.
+    FinishDelete,
+}
+
+/// An unfinished operation on a snapshot, picked up by `resume`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeWork {
+    pub family: String,
+    pub snapshot_id: u64,
+    pub action: ResumeAction,
+}
+
+impl fmt::Display for ResumeWork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            ResumeAction::Commit => "commit",
+            ResumeAction::FinishCommit => "finishing commit",
+            ResumeAction::Recover => "recovery",
+            ResumeAction::Delete => "delete",
+            ResumeAction::FinishDelete => "finishing delete",
+        };
+        write!(f, "{} of {} #{}", action, self.family, self.snapshot_id)
+    }
+}
+
+/// A version of a path in the path index.
+#[derive(Clone, Debug)]
+pub struct IndexedVersion {
+    pub version: db::PathVersion,
+    /// The snapshots holding this version, oldest first.
+    pub snapshots: Vec<u64>,
+}
+
+/// Which entries of a snapshot a checkout restores.
+#[derive(Clone, Copy)]
+enum RestorePass<'a> {
+    All,
+    /// Only the paths the filter matches, with everything below them.
+    Only(&'a util::PathFilter),
+    /// Everything an `Only` pass with the filter left out.
+    Except(&'a util::PathFilter),
+    /// Only the entry at this path, with everything below it.
+    Path(&'a [u8]),
+}
+
+impl<'a> RestorePass<'a> {
+    /// The pass for the entry at `path`, or `None` if it is not restored in this pass.
+    fn inner(self, path: &[u8], is_dir: bool) -> Option<RestorePass<'a>> {
+        match self {
+            RestorePass::All => Some(RestorePass::All),
+            RestorePass::Only(filter) if filter.matches(path) => Some(RestorePass::All),
+            RestorePass::Except(filter) if filter.matches(path) => None,
+            RestorePass::Only(filter) | RestorePass::Except(filter)
+                if is_dir && filter.may_match_below(path) =>
+            {
+                Some(self)
+            }
+            RestorePass::Only(_) => None,
+            RestorePass::Except(_) => Some(RestorePass::All),
+            RestorePass::Path(p) if p == path => Some(RestorePass::All),
+            RestorePass::Path(p) if is_dir && p.starts_with(path) && p[path.len()] == b'/' => {
+                Some(self)
+            }
+            RestorePass::Path(_) => None,
+        }
+    }
+}
+
+/// What was found wrong with the data read back from the backend so far.
+pub struct RetrieveReport {
+    pub metrics: RetrieveMetrics,
+    /// The chunks that failed, and where they are stored.
+    pub failures: Vec<RetrieveFailure>,
+    /// The versions of files and directories the failed chunks belong to, or why they could not
+    /// be found.
+    pub affected: Result<Vec<IndexedVersion>, String>,
+}
+
+/// Append the paths below `dir` to `out`, each directory before its entries.
+fn index_dir<B: StoreBackend>(
+    reader: &HashReader<B>,
+    dir: hash::tree::HashRef,
+    prefix: &str,
+    out: &mut Vec<db::IndexedPath>,
+) -> Result<(), HatError> {
+    for res in reader.list_dir(dir)? {
+        let (entry, content) = res?;
+        let path = format!("{}{}", prefix, entry.info.name.utf8());
+        let hash = match content {
+            walker::Content::Data(ref r) | walker::Content::Dir(ref r) => r.hash.bytes.clone(),
+            walker::Content::Link(ref target) => target.to_string_lossy().into_owned().into_bytes(),
+            // Only versions with contents are indexed.
+            walker::Content::Unreadable(_) | walker::Content::Special(_) => continue,
+        };
+        out.push(db::IndexedPath {
+            path: path.clone(),
+            hash: hash,
+            byte_length: entry.info.byte_length,
+        });
+        if let walker::Content::Dir(href) = content {
+            index_dir(reader, href, &format!("{}/", path), out)?;
+        }
+    }
+    Ok(())
+}
+
 impl<B: StoreBackend> HatRc<B> {
+    /// Open the repository in the state directory `repository_root`, and resume any operations
+    /// left unfinished by an earlier process.
     pub fn open_repository(
+        repository_root: PathBuf,
+        backend: Arc<B>,
+        max_blob_size: usize,
+    ) -> Result<HatRc<B>, HatError> {
+        let mut hat = HatRc::open_repository_without_resume(repository_root, backend, max_blob_size)?;
+        hat.resume()?;
+        Ok(hat)
+    }
+
+    /// Open the repository like `open_repository`, but leave unfinished operations for an
+    /// explicit `resume`. See `unfinished_work`.
+    pub fn open_repository_without_resume(
         mut repository_root: PathBuf,
         backend: Arc<B>,
         max_blob_size: usize,
     ) -> Result<HatRc<B>, HatError> {
-        let keys = Arc::new(crypto::keys::Keeper::load_from_universal_key(
-            &repository_root,
-        )?);
+        let keys = Arc::new(crypto::keys::Keeper::load(&repository_root)?);
+        let writer = shared::read_writer_id(&repository_root)?;
+        let chunking = Arc::new(
+            key::ChunkingProfiles::load(&repository_root)?.with_key(keys.chunking_key().unsecure()),
+        );
+        let compression = blob::Compression::load(&repository_root)?;
 
         repository_root = repository_root.join("cache");
+        let synced_generation = sync::load_generation(&repository_root);
 
         let hash_index_path = hash_index_name(repository_root.clone());
         let db_p = Arc::new(db::Index::new(&hash_index_path)?);
 
+        // The path index is kept up to date once `rebuild_path_index` has created it.
+        let path_index_path = path_index_name(repository_root.clone());
+        let path_index = if Path::new(&path_index_path).exists() {
+            Some(db::PathIndex::open(&path_index_path)?)
+        } else {
+            None
+        };
+
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
 
@@ -225,13 +525,14 @@ impl<B: StoreBackend> HatRc<B> {
             backend.clone(),
             max_blob_size,
         ));
+        bs_p.set_compression(compression);
 
         let gc_backend = GcBackend {
             hash_index: hi_p.clone(),
         };
         let gc = gc::Gc::new(gc_backend);
 
-        let mut hat = Hat {
+        Ok(Hat {
             keys: keys,
             repository_root: Some(repository_root),
             families: vec![],
@@ -241,19 +542,36 @@ impl<B: StoreBackend> HatRc<B> {
             backend: backend,
             blob_index: bi_p,
             blob_store: bs_p,
+            data_blob_stores: vec![],
             blob_max_size: max_blob_size,
+            compression: compression,
+            chunking: chunking,
+            file_order: util::FileOrder::default(),
+            exclude: util::ExcludeFilter::default(),
+            changed_policy: util::ChangedFilePolicy::default(),
+            content_filter: None,
+            names: Arc::new(owners::SystemNames::new()),
+            owner_mapping: owners::OwnerMapping::default(),
+            jobs: DEFAULT_JOBS,
+            fetch_jobs: blob::DEFAULT_FETCH_JOBS,
             gc: gc,
-        };
-
-        // Resume any unfinished commands.
-        hat.resume()?;
-
-        Ok(hat)
+            writer: writer,
+            remote_lock: None,
+            locked_generation: None,
+            synced_generation: synced_generation,
+            path_index: path_index,
+            commit_message: None,
+            commit_tags: vec![],
+            progress: None,
+        })
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testing"))]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<HatRc<B>, HatError> {
         let keys = Arc::new(crypto::keys::Keeper::new_for_testing());
+        let chunking = Arc::new(
+            key::ChunkingProfiles::default().with_key(keys.chunking_key().unsecure()),
+        );
 
         let db_p = Arc::new(db::Index::new_for_testing());
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
@@ -281,9 +599,28 @@ impl<B: StoreBackend> HatRc<B> {
             hash_index: hi_p,
             blob_index: bi_p,
             blob_store: bs_p,
+            data_blob_stores: vec![],
             blob_max_size: max_blob_size,
+            compression: blob::Compression::default(),
+            chunking: chunking,
+            file_order: util::FileOrder::default(),
+            exclude: util::ExcludeFilter::default(),
+            changed_policy: util::ChangedFilePolicy::default(),
+            content_filter: None,
+            names: Arc::new(owners::SystemNames::new()),
+            owner_mapping: owners::OwnerMapping::default(),
+            jobs: DEFAULT_JOBS,
+            fetch_jobs: blob::DEFAULT_FETCH_JOBS,
             backend: backend,
             gc: gc,
+            writer: None,
+            remote_lock: None,
+            locked_generation: None,
+            synced_generation: None,
+            path_index: None,
+            commit_message: None,
+            commit_tags: vec![],
+            progress: None,
         };
 
         // Resume any unfinished commands.
@@ -292,6 +629,27 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(hat)
     }
 
+    /// Like `new_for_testing`, with an `Inspector` of the new repository.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_for_testing_with_inspector(
+        backend: Arc<B>,
+        max_blob_size: usize,
+    ) -> Result<(HatRc<B>, inspect::Inspector<B>), HatError> {
+        let hat = HatRc::new_for_testing(backend, max_blob_size)?;
+        let inspector = hat.inspector();
+        Ok((hat, inspector))
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inspector(&self) -> inspect::Inspector<B> {
+        inspect::Inspector::new(
+            self.keys.clone(),
+            self.db.clone(),
+            self.hash_index.clone(),
+            self.backend.clone(),
+        )
+    }
+
     pub fn hash_tree_writer(
         &self,
         leaf: blob::LeafType,
@@ -299,6 +657,23 @@ impl<B: StoreBackend> HatRc<B> {
         hash::tree::SimpleHashTreeWriter::new(leaf, 8, self.hash_backend())
     }
 
+    /// Write a copy of the tree `root` with its leaves changed by `splices`, reusing the
+    /// subtrees they leave alone; see `hash::tree::splice`.
+    pub fn splice_tree(
+        &self,
+        root: hash::tree::HashRef,
+        splices: &[hash::tree::Splice],
+        info: Option<&key::Info>,
+    ) -> Result<hash::tree::HashRef, HatError> {
+        Ok(hash::tree::splice(
+            self.hash_backend(),
+            8,
+            root,
+            splices,
+            info,
+        )?)
+    }
+
     pub fn open_family(&mut self, name: String) -> Result<Family<B>, HatError> {
         // We setup a standard pipeline of processes:
         // key::Store -> key::Index
@@ -318,7 +693,7 @@ impl<B: StoreBackend> HatRc<B> {
         let ki_p = Arc::new(key::KeyIndex::new(&key_index_path)?);
 
         let mut kss = vec![];
-        for _ in 0..2 {
+        for _ in 0..self.jobs {
             // To avoid mixing chunks from different files, each key store gets its own dedicated
             // blob store.
             let bs = Arc::new(blob::BlobStore::new(
@@ -327,12 +702,19 @@ impl<B: StoreBackend> HatRc<B> {
                 self.backend.clone(),
                 self.blob_max_size,
             ));
-            kss.push(Process::new(key::Store::new(
+            bs.set_compression(self.compression);
+            bs.set_progress(self.progress.clone());
+            self.data_blob_stores.push(bs.clone());
+            let ks = key::Store::new(
                 ki_p.clone(),
                 self.hash_index.clone(),
                 bs,
                 self.keys.clone(),
-            )));
+            );
+            kss.push(Process::new(
+                ks.with_chunking(self.chunking.clone())
+                    .with_family(name.clone()),
+            ));
         }
 
         let ks = key::Store::new(
@@ -340,13 +722,26 @@ impl<B: StoreBackend> HatRc<B> {
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        );
+        ).with_chunking(self.chunking.clone())
+            .with_family(name.clone());
         kss.push(Process::new(ks.clone()));
 
         let family = Family {
             name: name.clone(),
             key_store: ks,
             key_store_process: kss,
+            sources: Arc::new(Mutex::new(vec![])),
+            file_order: self.file_order,
+            exclude: self.exclude.clone(),
+            content_filter: self.content_filter.clone(),
+            filtered: Arc::new(Mutex::new(vec![])),
+            changed_policy: self.changed_policy,
+            changed: Arc::new(Mutex::new(vec![])),
+            names: self.names.clone(),
+            // Threads listing directories should not leave the key stores idle.
+            walk_threads: cmp::max(util::DEFAULT_WALK_THREADS, 2 * self.jobs),
+            events: self.progress.clone(),
+            failure: Arc::new(Mutex::new(None)),
         };
         self.families.push(family.clone());
 
@@ -364,6 +759,7 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn meta_commit(&mut self) -> Result<(), HatError> {
+        self.check_writable()?;
         let all_snapshots = self.snapshot_index.list_all();
 
         let mut snapshots = models::Snapshots { snapshots: vec![] };
@@ -377,9 +773,12 @@ impl<B: StoreBackend> HatRc<B> {
                 hash_ref: hash::tree::HashRef::from_bytes(&snapshot.hash_ref.unwrap()[..])?
                     .to_model(),
                 created_ts_utc: snapshot.created.timestamp(),
+                manifest: snapshot.manifest,
+                annotations: snapshot.annotations,
+                tags: snapshot.tags,
             };
 
-            if model.family_name == synthetic_roots_family() {
+            if model.is_internal() {
                 all_root_ids.push(snapshot.info.snapshot_id);
             }
 
@@ -400,9 +799,9 @@ impl<B: StoreBackend> HatRc<B> {
 
         // Create synthetic snapshot so GC can track the needed blobs and keep them alive.
         self.hash_index.set_tag(top_id, tags::Tag::Reserved);
-        let snap_info = self.snapshot_index.reserve(synthetic_roots_family());
+        let snap_info = self.snapshot_index.reserve(synthetic_roots_family(), None, &[]);
         self.snapshot_index
-            .update(&snap_info, &top_ref.hash, &top_ref);
+            .update(&snap_info, &top_ref.hash, &top_ref, None);
         self.meta_flush();
 
         self.gc.register_final(&snap_info, top_id)?;
@@ -437,17 +836,27 @@ impl<B: StoreBackend> HatRc<B> {
                     blob::LeafType::FileChunk => {
                         warn!("Skipping file contents: {}", hex::encode(&r.hash.bytes))
                     }
+                    blob::LeafType::Manifest => {
+                        warn!("Skipping integrity manifest: {}", hex::encode(&r.hash.bytes))
+                    }
                 }
             }
         }
         Ok(None)
     }
 
-    pub fn recover(&mut self) -> Result<(), HatError> {
+    /// Add the snapshots listed by the latest root in the backend to the snapshot index, with
+    /// `work` left to do for each. Returns the root and the time of its newest snapshot.
+    fn recover_snapshot_list(
+        &mut self,
+        work: Option<db::SnapshotWorkStatus>,
+    ) -> Result<Option<(hash::tree::HashRef, chrono::DateTime<chrono::Utc>)>, HatError> {
+        self.adopt_key_version()?;
         self.blob_store.recover()?;
-        let root_href = self
-            .recover_root()?
-            .expect("Failed to find a commit-ed root.");
+        let root_href = match self.recover_root()? {
+            Some(root_href) => root_href,
+            None => return Ok(None),
+        };
 
         info!(
             "Recovering using root: {}",
@@ -474,13 +883,42 @@ impl<B: StoreBackend> HatRc<B> {
                     &s.family_name,
                     created,
                     &s.msg,
+                    &s.tags,
                     &hash_ref,
-                    Some(db::SnapshotWorkStatus::RecoverInProgress),
+                    s.manifest.as_ref(),
+                    work,
                 );
+                if !s.annotations.is_empty() {
+                    if let Some((info, _, _)) = self.snapshot_index.lookup(&s.family_name, s.id) {
+                        self.snapshot_index.set_annotations(&info, &s.annotations);
+                    }
+                }
             }
         }
 
         self.flush_snapshot_index();
+        Ok(Some((root_href, max_created)))
+    }
+
+    /// Learn the snapshots stored in the backend, without downloading their trees into the
+    /// local indexes as `recover` does. This is enough to read snapshots from a fresh state
+    /// directory, as when restoring with nothing but the backend and the key, but not to commit
+    /// or delete.
+    pub fn recover_for_reading(&mut self) -> Result<(), HatError> {
+        match self.recover_snapshot_list(None)? {
+            Some(_) => Ok(()),
+            None => Err("No snapshot list found in the backend".into()),
+        }
+    }
+
+    pub fn recover(&mut self) -> Result<(), HatError> {
+        // The indexes are rebuilt from what the backend holds now.
+        let generation = shared::read_generation(&*self.backend)?;
+        self.set_synced_generation(generation)?;
+
+        let (root_href, max_created) = self
+            .recover_snapshot_list(Some(db::SnapshotWorkStatus::RecoverInProgress))?
+            .expect("Failed to find a commit-ed root.");
         self.resume()?;
 
         // Register the newly found root. This is needed because root cannot contain itself.
@@ -498,7 +936,9 @@ impl<B: StoreBackend> HatRc<B> {
             &synthetic_roots_family(),
             max_created,
             "",
+            &[],
             &root_href,
+            None,
             Some(db::SnapshotWorkStatus::RecoverInProgress),
         );
         self.flush_snapshot_index();
@@ -511,6 +951,7 @@ impl<B: StoreBackend> HatRc<B> {
         &mut self,
         info: db::SnapshotInfo,
         final_hash: &hash::tree::HashRef,
+        integrity: Option<hash::tree::HashRef>,
     ) -> Result<(), HatError> {
         fn recover_entry<B: StoreBackend>(
             hashes: &hash::HashIndex,
@@ -587,6 +1028,16 @@ impl<B: StoreBackend> HatRc<B> {
             walk.resume(&mut file_v, &mut dir_v)?
         } {}
 
+        // The integrity manifest is a tree of its own, outside the snapshot listing.
+        if let Some(href) = integrity {
+            let mut walk = hash::tree::Walker::new(self.hash_backend(), href.clone())?.unwrap();
+            while walk.resume(&mut file_v)? {}
+            for node in file_v.nodes() {
+                recover_entry(&self.hash_index, &self.blob_store, node);
+            }
+            tops.push(href.hash);
+        }
+
         // Recover hashes for tree-tops. These are also registered with the GC.
         self.hash_index.set_all_tags(tags::Tag::Done);
         for hash in tops {
@@ -595,7 +1046,7 @@ impl<B: StoreBackend> HatRc<B> {
                 self.hash_index.set_tag(id, tags::Tag::Reserved);
             }
         }
-        self.flush_blob_store();
+        self.flush_blob_store()?;
 
         let final_id = self
             .hash_index
@@ -606,93 +1057,139 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
-    pub fn resume(&mut self) -> Result<(), HatError> {
+    /// The operations left unfinished by an earlier process, which `resume` would pick up.
+    pub fn unfinished_work(&mut self) -> Result<Vec<ResumeWork>, HatError> {
+        let mut work = vec![];
+        for snapshot in self.snapshot_index.list_not_done() {
+            work.push(ResumeWork {
+                action: self.resume_action(&snapshot)?,
+                family: snapshot.family_name,
+                snapshot_id: snapshot.info.snapshot_id,
+            });
+        }
+        Ok(work)
+    }
+
+    /// Finish the operations left unfinished by an earlier process. Returns what was resumed.
+    pub fn resume(&mut self) -> Result<Vec<ResumeWork>, HatError> {
+        self.redo_lost_commits();
+        if self.snapshot_index.list_not_done().is_empty() {
+            return Ok(vec![]);
+        }
+        let locked = self.lock_backend()?;
+        let resumed = self.resume_unlocked()?;
+        if locked {
+            self.unlock_backend()?;
+        }
+        Ok(resumed)
+    }
+
+    /// What resuming `snapshot` must do, judged by how far its data got registered with the GC.
+    fn resume_action(
+        &mut self,
+        snapshot: &db::SnapshotStatus,
+    ) -> Result<ResumeAction, HatError> {
+        let registered = match snapshot.hash {
+            Some(ref h) => match self.hash_index.get_id(h) {
+                Some(id) => self.gc.status(id)?.is_some(),
+                None => false,
+            },
+            None => false,
+        };
+        match snapshot.status {
+            // Unless registered, we did not fully commit.
+            db::SnapshotWorkStatus::CommitInProgress if registered => Ok(ResumeAction::FinishCommit),
+            db::SnapshotWorkStatus::CommitInProgress => Ok(ResumeAction::Commit),
+            db::SnapshotWorkStatus::RecoverInProgress if registered => Err(From::from(format!(
+                "unexpected state: ({:?}, {:?})",
+                snapshot.hash, snapshot.status
+            ))),
+            db::SnapshotWorkStatus::RecoverInProgress => Ok(ResumeAction::Recover),
+            db::SnapshotWorkStatus::CommitComplete => match snapshot.hash {
+                Some(_) => Ok(ResumeAction::FinishCommit),
+                None => {
+                    // This should not happen.
+                    Err(From::from(format!(
+                        "Snapshot {:?} is fully registered \
+                         in GC, but has no hash",
+                        snapshot
+                    )))
+                }
+            },
+            db::SnapshotWorkStatus::DeleteInProgress => {
+                let hash = snapshot.hash.as_ref().expect("Snapshot has no hash");
+                let hash_id = self
+                    .hash_index
+                    .get_id(hash)
+                    .expect("Snapshot hash not recognized");
+                match self.gc.status(hash_id)? {
+                    None | Some(gc::Status::InProgress) => Ok(ResumeAction::Delete),
+                    Some(gc::Status::Complete) => Ok(ResumeAction::FinishDelete),
+                }
+            }
+            db::SnapshotWorkStatus::DeleteComplete => Ok(ResumeAction::FinishDelete),
+        }
+    }
+
+    /// A crash before the final flush loses the hashes of snapshots committed since. Commit
+    /// those again; lost snapshot listings are replaced by the next meta commit.
+    fn redo_lost_commits(&mut self) {
+        for snapshot in self.snapshot_index.list_all() {
+            let lost = match (snapshot.status, &snapshot.hash) {
+                (db::SnapshotWorkStatus::CommitComplete, Some(h)) => {
+                    self.hash_index.get_id(h).is_none()
+                }
+                _ => false,
+            };
+            if lost && snapshot.is_internal() {
+                self.snapshot_index.delete(snapshot.info);
+            } else if lost {
+                self.snapshot_index.redo_commit(&snapshot.info);
+            }
+        }
+        self.meta_flush();
+    }
+
+    fn resume_unlocked(&mut self) -> Result<Vec<ResumeWork>, HatError> {
         let need_work = self.snapshot_index.list_not_done();
+        let mut resumed = vec![];
 
         for snapshot in need_work {
-            match snapshot.status {
-                db::SnapshotWorkStatus::CommitInProgress
-                | db::SnapshotWorkStatus::RecoverInProgress => {
-                    let done_hash_opt = match snapshot.hash {
+            let action = self.resume_action(&snapshot)?;
+            resumed.push(ResumeWork {
+                family: snapshot.family_name.clone(),
+                snapshot_id: snapshot.info.snapshot_id,
+                action: action,
+            });
+            match action {
+                ResumeAction::Commit => {
+                    eprintln!("Resuming commit of: {}", snapshot.family_name);
+                    self.commit_by_name(snapshot.family_name, Some(snapshot.info))?
+                }
+                ResumeAction::FinishCommit => {
+                    let hash = snapshot.hash.expect("Snapshot has no hash");
+                    self.commit_finalize(snapshot.info, &hash)?
+                }
+                ResumeAction::Recover => {
+                    eprintln!("Resuming recovery of: {}", snapshot.family_name);
+                    let hash_ref_bytes = snapshot
+                        .hash_ref
+                        .ok_or("Recovered hash tree has no root hash")?;
+                    let hash_ref = hash::tree::HashRef::from_bytes(&hash_ref_bytes[..])?;
+                    let integrity = match snapshot.manifest.and_then(|m| m.integrity) {
+                        Some(bytes) => Some(hash::tree::HashRef::from_bytes(&bytes[..])?),
                         None => None,
-                        Some(ref h) => {
-                            let status_res = self.hash_index.get_id(h).map(|id| self.gc.status(id));
-                            let status_opt = match status_res {
-                                None => None,
-                                Some(res) => res?,
-                            };
-                            match status_opt {
-                                None => None, // We did not fully commit.
-                                Some(gc::Status::InProgress) | Some(gc::Status::Complete) => {
-                                    Some(h)
-                                }
-                            }
-                        }
                     };
-                    match (done_hash_opt, snapshot.status) {
-                        (Some(hash), db::SnapshotWorkStatus::CommitInProgress) => {
-                            self.commit_finalize(snapshot.info, hash)?
-                        }
-                        (None, db::SnapshotWorkStatus::CommitInProgress) => {
-                            println!("Resuming commit of: {}", snapshot.family_name);
-                            self.commit_by_name(snapshot.family_name, Some(snapshot.info))?
-                        }
-                        (None, db::SnapshotWorkStatus::RecoverInProgress) => {
-                            println!("Resuming recovery of: {}", snapshot.family_name);
-                            let hash_ref_bytes = snapshot
-                                .hash_ref
-                                .ok_or("Recovered hash tree has no root hash")?;
-                            let hash_ref =
-                                hash::tree::HashRef::from_bytes(&mut &hash_ref_bytes[..])?;
-                            self.recover_snapshot(snapshot.info, &hash_ref)?
-                        }
-                        (hash, status) => {
-                            return Err(From::from(format!(
-                                "unexpected state: ({:?}, {:?})",
-                                hash, status
-                            )))
-                        }
-                    }
-                }
-                db::SnapshotWorkStatus::CommitComplete => {
-                    match snapshot.hash {
-                        Some(ref h) => self.commit_finalize(snapshot.info, h)?,
-                        None => {
-                            // This should not happen.
-                            return Err(From::from(format!(
-                                "Snapshot {:?} is fully registered \
-                                 in GC, but has no hash",
-                                snapshot
-                            )));
-                        }
-                    }
+                    self.recover_snapshot(snapshot.info, &hash_ref, integrity)?
                 }
-                db::SnapshotWorkStatus::DeleteInProgress => {
-                    let hash = snapshot.hash.expect("Snapshot has no hash");
-                    let hash_id = self
-                        .hash_index
-                        .get_id(&hash)
-                        .expect("Snapshot hash not recognized");
-                    let status = self.gc.status(hash_id)?;
-                    match status {
-                        None | Some(gc::Status::InProgress) => {
-                            println!(
-                                "Resuming delete of: {} #{:?}",
-                                snapshot.family_name, snapshot.info.snapshot_id
-                            );
-                            self.deregister_by_name(
-                                snapshot.family_name,
-                                snapshot.info.snapshot_id,
-                            )?
-                        }
-                        Some(gc::Status::Complete) => self.deregister_finalize_by_name(
-                            snapshot.family_name,
-                            snapshot.info,
-                            hash_id,
-                        )?,
-                    }
+                ResumeAction::Delete => {
+                    eprintln!(
+                        "Resuming delete of: {} #{:?}",
+                        snapshot.family_name, snapshot.info.snapshot_id
+                    );
+                    self.deregister_by_name(snapshot.family_name, snapshot.info.snapshot_id)?
                 }
-                db::SnapshotWorkStatus::DeleteComplete => {
+                ResumeAction::FinishDelete => {
                     let hash = snapshot.hash.expect("Snapshot has no hash");
                     let hash_id = self
                         .hash_index
@@ -707,7 +1204,7 @@ impl<B: StoreBackend> HatRc<B> {
         let need_work = self.snapshot_index.list_not_done();
         assert_eq!(need_work.len(), 0);
 
-        Ok(())
+        Ok(resumed)
     }
 
     pub fn commit_by_name(
@@ -721,11 +1218,45 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Snapshot `dirs` into `family` and commit it, reporting the files handled, the bytes read
+    /// and the blobs flushed and uploaded to `progress` as they happen.
+    pub fn commit_with_progress(
+        &mut self,
+        family: &mut Family<B>,
+        dirs: Vec<PathBuf>,
+        progress: util::ProgressFn,
+    ) -> Result<(), HatError> {
+        let previous = self.progress.take();
+        let family_events = family.events.replace(progress.clone());
+        self.set_progress(Some(progress));
+        family.snapshot_dirs_preemptible(dirs, util::Preemption::new());
+        let res = self.commit(family, None);
+        family.events = family_events;
+        self.set_progress(previous);
+        res
+    }
+
     pub fn commit(
         &mut self,
         family: &mut Family<B>,
         resume_info: Option<db::SnapshotInfo>,
     ) -> Result<(), HatError> {
+        self.check_writable()?;
+        if let Some(e) = family.failure.lock().unwrap().take() {
+            return Err(format!("Not committing, as the snapshot failed: {}", e).into());
+        }
+        if family.changed_policy == util::ChangedFilePolicy::Fail {
+            let mut changed = family.changed.lock().unwrap();
+            if !changed.is_empty() {
+                let paths: Vec<_> = changed.drain(..).map(|p| p.display().to_string()).collect();
+                return Err(format!(
+                    "Not committing, as {} files changed while being read: {}",
+                    paths.len(),
+                    paths.join(", ")
+                ).into());
+            }
+        }
+
         //  Tag 1:
         //  Reserve the snapshot and commit the reservation.
         //  Register all but the last hashes.
@@ -734,13 +1265,18 @@ impl<B: StoreBackend> HatRc<B> {
             Some(info) => info, // Resume already started commit.
             None => {
                 // Create new commit.
-                self.snapshot_index.reserve(family.name.clone())
+                self.snapshot_index.reserve(
+                    family.name.clone(),
+                    self.commit_message.as_ref().map(|m| &m[..]),
+                    &self.commit_tags,
+                )
             }
         };
         self.meta_flush();
+        util::fail_point("commit-reserved")?;
 
         // Commit metadata while registering needed data-hashes (files and dirs).
-        let top_ref = {
+        let (top_ref, integrity, file_bytes) = {
             let local_hash_index = self.hash_index.clone();
             family.commit(&|hash| {
                 let id = local_hash_index
@@ -753,9 +1289,37 @@ impl<B: StoreBackend> HatRc<B> {
         // Tag 2:
         // We update the snapshot entry with the tree hash, which we then register.
         // When the GC has seen the final hash, we flush everything so far.
+        let mut manifest = self.snapshot_manifest(&family.sources.lock().unwrap());
+        manifest.integrity = integrity.map(|href| href.as_bytes());
+        manifest.file_bytes = Some(file_bytes);
+        if !family.exclude.is_empty() {
+            manifest
+                .settings
+                .push(("exclude".to_owned(), family.exclude.to_string()));
+        }
+        if let Some(ref filter) = family.content_filter {
+            manifest
+                .settings
+                .push(("content_filter".to_owned(), filter.name()));
+        }
+        manifest.filtered = family
+            .filtered
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, decision)| (path.to_string_lossy().into_owned(), decision.clone()))
+            .collect();
+        manifest.fuzzy = family
+            .changed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
         self.snapshot_index
-            .update(&snap_info, &top_ref.hash, &top_ref);
+            .update(&snap_info, &top_ref.hash, &top_ref, Some(&manifest));
         self.meta_flush();
+        util::fail_point("commit-updated")?;
 
         // Register the final hash.
         // At this point, the GC should still be able to either resume or rollback safely.
@@ -767,12 +1331,120 @@ impl<B: StoreBackend> HatRc<B> {
             .expect("Hash does not exist");
         self.gc.register_final(&snap_info, hash_id)?;
         self.meta_flush();
+        util::fail_point("commit-registered")?;
 
         self.commit_finalize(snap_info, &top_ref.hash)?;
+        family.sources.lock().unwrap().clear();
+        family.filtered.lock().unwrap().clear();
+        family.changed.lock().unwrap().clear();
 
         Ok(())
     }
 
+    /// Commit a snapshot to `family` holding only the entries of snapshot `snapshot_id` of family
+    /// `from` that `filter` accepts, with paths relative to the snapshot root. No file contents
+    /// are uploaded: the new snapshot shares them with the old one, and only the listings of
+    /// directories that lost entries are written. Fails if `filter` accepts nothing.
+    pub fn derive(
+        &mut self,
+        family: &mut Family<B>,
+        from: &str,
+        snapshot_id: u64,
+        filter: &util::PathFilter,
+    ) -> Result<(), HatError> {
+        self.check_writable()?;
+        let source = match self.snapshot_index.lookup(from, snapshot_id) {
+            Some((_, _, Some(top_ref))) => top_ref,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {}",
+                    from, snapshot_id
+                )))
+            }
+        };
+
+        // Write the new listings first, so nothing is reserved if the filter accepts nothing.
+        let top_ref = {
+            let hash_backend = self.hash_backend();
+            let local_hash_index = self.hash_index.clone();
+            let mut top_tree = family.key_store.hash_tree_writer(blob::LeafType::TreeList);
+            let kept = family.derive_to_tree(
+                &mut top_tree,
+                source,
+                b"",
+                filter,
+                &hash_backend,
+                &|hash| {
+                    let id = local_hash_index
+                        .get_id(hash)
+                        .unwrap_or_else(|| panic!("Top hash: {:?}", hash.bytes));
+                    local_hash_index.set_tag(id, tags::Tag::Reserved);
+                },
+            )?;
+            if kept == 0 {
+                return Err(From::from(format!(
+                    "Nothing in {}/{} matches {}",
+                    from, snapshot_id, filter
+                )));
+            }
+            let info = key::Info::new(family.name.clone().into(), None);
+            top_tree.hash(Some(&info))?
+        };
+
+        // From here on, as in `commit`.
+        let snap_info = self.snapshot_index.reserve(
+            family.name.clone(),
+            self.commit_message.as_ref().map(|m| &m[..]),
+            &self.commit_tags,
+        );
+        self.meta_flush();
+
+        let mut manifest =
+            self.snapshot_manifest(&[PathBuf::from(format!("{}/{}", from, snapshot_id))]);
+        manifest
+            .settings
+            .push(("include".to_owned(), filter.to_string()));
+        self.snapshot_index
+            .update(&snap_info, &top_ref.hash, &top_ref, Some(&manifest));
+        self.meta_flush();
+
+        let hash_id = self
+            .hash_index
+            .get_id(&top_ref.hash)
+            .expect("Hash does not exist");
+        self.gc.register_final(&snap_info, hash_id)?;
+        self.meta_flush();
+
+        self.commit_finalize(snap_info, &top_ref.hash)
+    }
+
+    /// What to record about a snapshot of `sources` taken now, from this host.
+    fn snapshot_manifest(&self, sources: &[PathBuf]) -> models::SnapshotManifest {
+        let mut settings = vec![("max_blob_size".to_owned(), self.blob_max_size.to_string())];
+        if let Some(quota) = self.blob_store.quota() {
+            settings.push(("quota".to_owned(), quota.to_string()));
+        }
+        if let Some(ref writer) = self.writer {
+            settings.push(("writer".to_owned(), writer.clone()));
+        }
+        settings.push(("chunking".to_owned(), self.chunking.to_string()));
+        settings.push(("compression".to_owned(), self.compression.to_string()));
+
+        models::SnapshotManifest {
+            hostname: util::hostname(),
+            source_paths: sources
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+            hat_version: env!("CARGO_PKG_VERSION").to_owned(),
+            settings: settings,
+            integrity: None,
+            filtered: vec![],
+            fuzzy: vec![],
+            file_bytes: None,
+        }
+    }
+
     fn commit_finalize(
         &mut self,
         snap_info: db::SnapshotInfo,
@@ -781,10 +1453,12 @@ impl<B: StoreBackend> HatRc<B> {
         // Commit locally. Let the GC perform any needed cleanup.
         self.snapshot_index.ready_commit(&snap_info);
         self.meta_flush();
+        util::fail_point("commit-ready")?;
 
         let hash_id = self.hash_index.get_id(hash).expect("Hash does not exist");
         self.gc.register_cleanup(&snap_info, hash_id)?;
         self.meta_flush();
+        util::fail_point("commit-cleaned-up")?;
 
         // Tag 0: All is done.
         self.snapshot_index.commit(&snap_info);
@@ -801,60 +1475,754 @@ impl<B: StoreBackend> HatRc<B> {
         for family in &self.families {
             family.flush()?
         }
-        self.blob_store.flush();
+        self.flush_barrier()
+    }
+
+    /// Store the last blob and wait until the backend has stored every blob, so all hashes
+    /// are committed.
+    fn flush_barrier(&self) -> Result<(), HatError> {
+        self.blob_store.flush()?;
+        util::fail_point("flush-barrier")?;
+        self.backend.flush()?;
         self.meta_flush();
+
+        // Every committed snapshot can be read back now; the path index is only a cache, so
+        // failing to update it does not fail the flush.
+        if let Err(e) = self.update_path_index() {
+            warn!("Could not update the path index: {}", e);
+        }
         Ok(())
     }
 
+    /// `meta_commit` followed by `data_flush`, with the snapshot listing serialized while the
+    /// families flush their data blobs. Both are complete when this returns.
+    pub fn meta_commit_and_flush(&mut self) -> Result<(), HatError> {
+        let pending: Vec<_> = self.families.iter().map(|f| f.start_flush()).collect();
+        let committed = self.meta_commit();
+
+        // Wait for every family even after an error, so no flush is left running.
+        let mut flushed = Ok(());
+        for flush in pending {
+            let res = flush.wait();
+            if flushed.is_ok() {
+                flushed = res;
+            }
+        }
+        committed?;
+        flushed?;
+        util::fail_point("meta-commit")?;
+
+        self.flush_barrier()
+    }
+
+    /// The id of the newest blob stored, or 0. Blobs stored later have higher ids.
+    pub fn last_blob_id(&self) -> i64 {
+        let blobs = self.blob_store.list_by_tag(tags::Tag::Done);
+        blobs.iter().map(|b| b.id).max().unwrap_or(0)
+    }
+
+    /// Finish what `commit` started, as far as `durability` asks. When verifying, the blobs
+    /// stored after blob `after_id` are read back. Returns the number of blobs verified.
+    pub fn flush_with_durability(
+        &mut self,
+        durability: Durability,
+        after_id: i64,
+    ) -> Result<usize, HatError> {
+        if durability == Durability::Flushed {
+            self.data_flush()?;
+            return Ok(0);
+        }
+        self.meta_commit_and_flush()?;
+        if durability != Durability::Verified {
+            return Ok(0);
+        }
+
+        let (count, failures, _) = self.verify_blobs_from(after_id, Deadline::none());
+        match failures.first() {
+            None => Ok(count),
+            Some((blob, e)) => Err(format!(
+                "{} of the {} blobs stored by the commit failed verification, e.g. {}: {}",
+                failures.len(),
+                count,
+                hex::encode(&blob.name),
+                e
+            ).into()),
+        }
+    }
+
     pub fn flush_snapshot_index(&mut self) {
         self.snapshot_index.flush();
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        Ok(self.blob_store.flush()?)
     }
 
     pub fn list_snapshots(&mut self) -> Vec<db::SnapshotStatus> {
         self.snapshot_index.list_all()
     }
 
-    pub fn checkout_in_dir(
+    /// The snapshots tagged `tag`, at commit time or by an annotation.
+    pub fn list_snapshots_tagged(&mut self, tag: &str) -> Vec<db::SnapshotStatus> {
+        self.list_snapshots()
+            .into_iter()
+            .filter(|s| s.has_tag(tag))
+            .collect()
+    }
+
+    /// Add `annotation` to a committed snapshot, after its earlier ones. The snapshot list in
+    /// the backend has it after the next `meta_commit`.
+    pub fn annotate(
         &mut self,
-        family_name: String,
-        output_dir: PathBuf,
+        family: &str,
+        snapshot_id: u64,
+        annotation: models::SnapshotAnnotation,
     ) -> Result<(), HatError> {
-        // Extract latest snapshot info:
-        let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
-            Some((i, h, Some(r))) => (i, h, r),
-            _ => panic!(
-                "Tried to checkout family '{}' before first completed commit",
-                family_name
-            ),
-        };
+        self.check_writable()?;
+        let snapshot = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .find(|s| s.family_name == family && s.info.snapshot_id == snapshot_id)
+            .filter(|s| matches!(s.status, db::SnapshotWorkStatus::CommitComplete))
+            .ok_or_else(|| {
+                format!("No complete snapshot found for family {} with id {}", family, snapshot_id)
+            })?;
+        let mut annotations = snapshot.annotations;
+        annotations.push(annotation);
+        self.snapshot_index.set_annotations(&snapshot.info, &annotations);
+        self.flush_snapshot_index();
+        Ok(())
+    }
 
-        let family = self
-            .open_family(family_name.clone())
-            .expect(&format!("Could not open family '{}'", family_name));
+    /// Compact the snapshots of family `family` from `first` to `last` into one, by deleting
+    /// all but `last`. Every snapshot holds a complete tree, so `last` already is the synthetic
+    /// full snapshot of the chain; data only the deleted snapshots used is reclaimed by the
+    /// next GC. Returns the ids of the deleted snapshots.
+    pub fn compact_snapshots(
+        &mut self,
+        family: &str,
+        first: u64,
+        last: u64,
+    ) -> Result<Vec<u64>, HatError> {
+        self.check_writable()?;
+        if first >= last {
+            return Err(From::from(format!(
+                "Cannot compact {}/{} into the earlier snapshot {}",
+                family, first, last
+            )));
+        }
+        let ids: Vec<u64> = self
+            .list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == family)
+            .filter(|s| matches!(s.status, db::SnapshotWorkStatus::CommitComplete))
+            .map(|s| s.info.snapshot_id)
+            .filter(|&id| first <= id && id <= last)
+            .collect();
+        for &id in &[first, last] {
+            if !ids.contains(&id) {
+                return Err(From::from(format!(
+                    "No complete snapshot found for family {} with id {}",
+                    family, id
+                )));
+            }
+        }
 
-        let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, dir_ref)
+        let fam = self.open_family(family.to_owned())?;
+        let dropped: Vec<u64> = ids.into_iter().filter(|&id| id != last).collect();
+        for &id in &dropped {
+            self.deregister(&fam, id)?;
+        }
+        Ok(dropped)
     }
 
-    fn checkout_dir_ref(
-        &self,
-        family: &Family<B>,
-        output: &mut PathBuf,
-        dir_hash: hash::tree::HashRef,
-    ) -> Result<(), HatError> {
-        fs::create_dir_all(&output).unwrap();
-        for (entry, hash_ref) in family::Family::<B>::fetch_dir_data(dir_hash, self.hash_backend())?
-        {
+    /// The chains of snapshots of family `family` to compact to keep one snapshot per month
+    /// among those created before `before`, see `chains_by_month`.
+    pub fn monthly_chains(
+        &mut self,
+        family: &str,
+        before: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<(u64, u64)> {
+        let mut snapshots: Vec<_> = self
+            .list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == family)
+            .filter(|s| matches!(s.status, db::SnapshotWorkStatus::CommitComplete))
+            .map(|s| (s.info.snapshot_id, s.created))
+            .collect();
+        snapshots.sort();
+        chains_by_month(&snapshots, before)
+    }
+
+    /// Create the path index if there is none, and fill it from the snapshot listings.
+    /// Once created, each commit adds its snapshot to the index when the data is flushed.
+    pub fn rebuild_path_index(&mut self) -> Result<(), HatError> {
+        if self.path_index.is_none() {
+            self.path_index = Some(match self.repository_root {
+                Some(ref root) => db::PathIndex::open(&path_index_name(root.clone()))?,
+                None => db::PathIndex::open(":memory:")?,
+            });
+        }
+        self.path_index.as_ref().unwrap().clear()?;
+        self.update_path_index()
+    }
+
+    /// Add the complete snapshots that are newer than the last indexed one of their family.
+    /// Called when all data is flushed, so the snapshot listings can be read.
+    fn update_path_index(&self) -> Result<(), HatError> {
+        if self.path_index.is_none() {
+            return Ok(());
+        }
+        let listed = self.db.lock().snapshot_list(None);
+        let mut snapshots: Vec<_> = listed
+            .into_iter()
+            .filter(|s| !s.is_internal())
+            .filter_map(|s| {
+                let (family, snapshot_id) = (s.family_name, s.info.snapshot_id);
+                s.hash_ref.map(|r| (family, snapshot_id, r))
+            })
+            .collect();
+        snapshots.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+
+        let reader = self.hash_reader();
+        let index = self.path_index.as_ref().unwrap();
+        for (family, snapshot_id, hash_ref) in snapshots {
+            if index.last_indexed(&family)?.is_some_and(|last| last >= snapshot_id) {
+                continue;
+            }
+            let mut entries = vec![];
+            let top = hash::tree::HashRef::from_bytes(&hash_ref[..])?;
+            index_dir(&reader, top, "", &mut entries)?;
+            index.add_snapshot(&family, snapshot_id, &entries)?;
+        }
+        Ok(())
+    }
+
+    /// Versions of the paths matching `pattern`, with the snapshots that still hold each one.
+    /// See `db::PathIndex::find` for the pattern syntax.
+    pub fn find_paths(&mut self, pattern: &str) -> Result<Vec<IndexedVersion>, HatError> {
+        let versions = self.path_index()?.find(pattern)?;
+        Ok(self.with_snapshots(versions))
+    }
+
+    /// Versions of `path` in `family`, oldest first, with the snapshots that still hold each one.
+    pub fn path_history(
+        &mut self,
+        family: &str,
+        path: &str,
+    ) -> Result<Vec<IndexedVersion>, HatError> {
+        let versions = self.path_index()?.history(family, path)?;
+        Ok(self.with_snapshots(versions))
+    }
+
+    /// Versions of the files and directories whose contents include a chunk with one of
+    /// `hashes`, e.g. to tell what damaged chunks affect. Only the local indexes are read.
+    pub fn affected_versions(
+        &mut self,
+        hashes: &[hash::Hash],
+    ) -> Result<Vec<IndexedVersion>, HatError> {
+        if hashes.is_empty() {
+            return Ok(vec![]);
+        }
+        self.path_index()?;
+
+        // Walk up from each chunk to the roots of the trees holding it; the path index knows
+        // files and directories by the hash of their root.
+        let mut parents: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut branches = HashMap::new();
+        for (id, hash, childs) in self.hash_index.list_branches() {
+            for child in childs {
+                parents.entry(child).or_default().push(id);
+            }
+            branches.insert(id, hash);
+        }
+        let mut roots: Vec<Vec<u8>> = hashes.iter().map(|h| h.bytes.clone()).collect();
+        let mut queue: Vec<u64> = hashes
+            .iter()
+            .filter_map(|h| self.hash_index.get_id(h))
+            .collect();
+        let mut seen = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            for parent in parents.get(&id).map_or(&[][..], |p| &p[..]) {
+                roots.push(branches[parent].bytes.clone());
+                queue.push(*parent);
+            }
+        }
+
+        let versions = self.path_index()?.with_hashes(&roots)?;
+        Ok(self.with_snapshots(versions))
+    }
+
+    /// The hashes of the chunks stored in any of the blobs `names`, according to the hash
+    /// index, which is read once for all of them.
+    pub fn blob_hashes(&self, names: &[Vec<u8>]) -> Vec<hash::Hash> {
+        if names.is_empty() {
+            return vec![];
+        }
+        let names: HashSet<&[u8]> = names.iter().map(|n| &n[..]).collect();
+        self.hash_index
+            .list()
+            .into_iter()
+            .filter(|e| {
+                e.persistent_ref
+                    .as_ref()
+                    .is_some_and(|r| names.contains(&r.blob_name[..]))
+            })
+            .map(|e| e.hash)
+            .collect()
+    }
+
+    fn path_index(&self) -> Result<&db::PathIndex, HatError> {
+        self.path_index.as_ref().ok_or_else(|| {
+            From::from("There is no path index; create it with `hat index rebuild`".to_string())
+        })
+    }
+
+    /// Pair each version with the complete snapshots in its range, dropping versions whose
+    /// snapshots have all been deleted.
+    fn with_snapshots(&mut self, versions: Vec<db::PathVersion>) -> Vec<IndexedVersion> {
+        let mut complete: Vec<(String, u64)> = self
+            .list_snapshots()
+            .into_iter()
+            .filter(|s| s.hash_ref.is_some())
+            .map(|s| (s.family_name, s.info.snapshot_id))
+            .collect();
+        complete.sort();
+        versions
+            .into_iter()
+            .map(|v| {
+                let snapshots = complete
+                    .iter()
+                    .filter(|&&(ref f, id)| {
+                        *f == v.family && id >= v.first_snapshot && id <= v.last_snapshot
+                    })
+                    .map(|&(_, id)| id)
+                    .collect();
+                IndexedVersion {
+                    version: v,
+                    snapshots: snapshots,
+                }
+            })
+            .filter(|v| !v.snapshots.is_empty())
+            .collect()
+    }
+
+    /// The snapshots of the roots family, oldest first, each with the snapshots it lists.
+    /// Roots without a stored listing, such as a meta commit in progress, list nothing.
+    pub fn list_roots(
+        &mut self,
+    ) -> Result<Vec<(db::SnapshotStatus, Vec<models::Snapshot>)>, HatError> {
+        let mut roots = vec![];
+        for status in self.snapshot_index.list_all() {
+            if !status.is_internal() {
+                continue;
+            }
+            let mut listed = vec![];
+            if let Some(ref bytes) = status.hash_ref {
+                let root_href = hash::tree::HashRef::from_bytes(&bytes[..])?;
+                if let Some(leaves) = self.hash_reader().get_leaf_iter(root_href)? {
+                    for msg in leaves {
+                        let snapshot_list: models::Snapshots = serde_cbor::from_slice(&msg[..])?;
+                        listed.extend(snapshot_list.snapshots);
+                    }
+                }
+            }
+            roots.push((status, listed));
+        }
+        roots.sort_by_key(|(s, _)| s.info.snapshot_id);
+        Ok(roots)
+    }
+
+    pub fn checkout_in_dir(
+        &mut self,
+        family_name: String,
+        output_dir: PathBuf,
+    ) -> Result<(), HatError> {
+        // Extract latest snapshot info:
+        let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some((i, h, Some(r))) => (i, h, r),
+            _ => panic!(
+                "Tried to checkout family '{}' before first completed commit",
+                family_name
+            ),
+        };
+        self.checkout_ref_in_dir(family_name, dir_ref, output_dir, None)
+    }
+
+    /// Check out snapshot `snapshot_id` of `family_name`, which need not be the latest.
+    pub fn checkout_snapshot_in_dir(
+        &mut self,
+        family_name: String,
+        snapshot_id: u64,
+        output_dir: PathBuf,
+    ) -> Result<(), HatError> {
+        let dir_ref = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            Some((_, _, Some(r))) => r,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot {}/{}",
+                    family_name, snapshot_id
+                )))
+            }
+        };
+        self.checkout_ref_in_dir(family_name, dir_ref, output_dir, None)
+    }
+
+    /// Check out snapshot `snapshot_id` of `family_name`, or its latest snapshot, restoring the
+    /// paths `first` matches before the rest. Paths are relative to the snapshot root, as in
+    /// `derive`; a matching directory is restored with everything below it.
+    pub fn checkout_in_dir_first(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        first: &util::PathFilter,
+        output_dir: PathBuf,
+    ) -> Result<(), HatError> {
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(&family_name, id),
+            None => self.snapshot_index.latest(&family_name),
+        };
+        let dir_ref = match found {
+            Some((_, _, Some(r))) => r,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot of family {} to check out",
+                    family_name
+                )))
+            }
+        };
+        self.checkout_ref_in_dir(family_name, dir_ref, output_dir, Some(first))
+    }
+
+    fn checkout_ref_in_dir(
+        &mut self,
+        family_name: String,
+        dir_ref: hash::tree::HashRef,
+        output_dir: PathBuf,
+        first: Option<&util::PathFilter>,
+    ) -> Result<(), HatError> {
+        let family = self
+            .open_family(family_name.clone())
+            .expect(&format!("Could not open family '{}'", family_name));
+
+        // Fail now rather than halfway through a long restore.
+        let space = util::free_space(&output_dir)?;
+        let mut data_blobs = vec![];
+        let needed = self.restore_size(
+            dir_ref.clone(),
+            &output_dir,
+            space.block_size,
+            &mut data_blobs,
+            &mut HashSet::new(),
+        )?;
+        let data_blobs = self.prepare_restore(&output_dir, &space, needed, data_blobs)?;
+
+        let mut output_dir = output_dir;
+        let (out, links) = (&mut output_dir, &mut HashMap::new());
+        let res = match first {
+            Some(first) => {
+                // Each pass reads the blobs in the same order, skipping those of the other.
+                self.blob_store.prefetch(data_blobs.clone(), self.fetch_jobs);
+                let only = RestorePass::Only(first);
+                self.checkout_dir_ref(&family, out, b"", dir_ref.clone(), only, links)
+                    .and_then(|()| {
+                        eprintln!("Restored the first paths; restoring the rest");
+                        self.blob_store.prefetch(data_blobs, self.fetch_jobs);
+                        let except = RestorePass::Except(first);
+                        self.checkout_dir_ref(&family, out, b"", dir_ref, except, links)
+                    })
+            }
+            None => {
+                self.blob_store.prefetch(data_blobs, self.fetch_jobs);
+                self.checkout_dir_ref(&family, out, b"", dir_ref, RestorePass::All, links)
+            }
+        };
+        self.blob_store.prefetch(vec![], 0);
+        res
+    }
+
+    /// Check out only the file or directory at `path` of snapshot `snapshot_id` of
+    /// `family_name`, or of its latest snapshot. `path` is relative to the snapshot root, and
+    /// the entry is restored at the same path below `output_dir`, as a full checkout would;
+    /// only the directories leading to it are listed.
+    pub fn checkout_path(
+        &mut self,
+        family_name: String,
+        snapshot_id: Option<u64>,
+        path: &Path,
+        output_dir: PathBuf,
+    ) -> Result<(), HatError> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let found = match snapshot_id {
+            Some(id) => self.snapshot_index.lookup(&family_name, id),
+            None => self.snapshot_index.latest(&family_name),
+        };
+        let dir_ref = match found {
+            Some((_, _, Some(r))) => r,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot of family {} to check out",
+                    family_name
+                )))
+            }
+        };
+        let names: Vec<&[u8]> = path.components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        if names.is_empty() {
+            return self.checkout_ref_in_dir(family_name, dir_ref, output_dir, None);
+        }
+        let path_bytes = names.join(&b'/');
+
+        // Find the entry, listing only the directories on the way.
+        let mut found = None;
+        let mut dir = dir_ref.clone();
+        for (i, name) in names.iter().enumerate() {
+            let mut listed = None;
+            for res in family::Family::<B>::fetch_dir_data(dir.clone(), self.hash_backend())? {
+                let (entry, content) = res?;
+                if entry.info.name.as_bytes() == *name {
+                    listed = Some((entry, content));
+                    break;
+                }
+            }
+            match listed {
+                Some(last) if i + 1 == names.len() => found = Some(last),
+                Some((_, walker::Content::Dir(href))) => dir = href,
+                _ => break,
+            }
+        }
+        let (entry, content) = match found {
+            Some(found) => found,
+            _ => {
+                return Err(From::from(format!(
+                    "No such file or directory in the snapshot: {}",
+                    path.display()
+                )))
+            }
+        };
+
+        // Fail now rather than halfway through a long restore; only the entry counts.
+        let space = util::free_space(&output_dir)?;
+        let mut data_blobs = vec![];
+        let needed = match content {
+            walker::Content::Dir(href) => {
+                let target = output_dir.join(ffi::OsStr::from_bytes(&path_bytes));
+                let mut links = HashSet::new();
+                self.restore_size(href, &target, space.block_size, &mut data_blobs, &mut links)?
+            }
+            walker::Content::Data(href) => {
+                self.tree_blobs(&href, &mut data_blobs);
+                entry.info.byte_length.unwrap_or(0)
+            }
+            walker::Content::Link(_)
+            | walker::Content::Unreadable(_)
+            | walker::Content::Special(_) => 0,
+        };
+        let data_blobs = self.prepare_restore(&output_dir, &space, needed, data_blobs)?;
+
+        let family = self.open_family(family_name)?;
+        let mut output_dir = output_dir;
+        let pass = RestorePass::Path(&path_bytes);
+        self.blob_store.prefetch(data_blobs, self.fetch_jobs);
+        let links = &mut HashMap::new();
+        let res = self.checkout_dir_ref(&family, &mut output_dir, b"", dir_ref, pass, links);
+        self.blob_store.prefetch(vec![], 0);
+        res
+    }
+
+    /// Refuse a restore to `output_dir` that needs more than the `space` available, and ask
+    /// the backend to bring the `data_blobs` it reads out of cold storage. Returns the blobs
+    /// in the order they are first read, each once.
+    fn prepare_restore(
+        &self,
+        output_dir: &Path,
+        space: &util::FreeSpace,
+        needed: u64,
+        mut data_blobs: Vec<Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>, HatError> {
+        if needed > space.available {
+            return Err(From::from(format!(
+                "Not enough space to restore to {}: {} bytes needed, {} bytes available",
+                output_dir.display(),
+                needed,
+                space.available
+            )));
+        }
+
+        let mut seen = HashSet::new();
+        data_blobs.retain(|name| seen.insert(name.clone()));
+        let restoring = self.blob_store.queue_restores(&data_blobs)?;
+        if restoring > 0 {
+            eprintln!(
+                "Requested {} of {} blobs from cold storage; files are restored as their blobs \
+                 become available",
+                restoring,
+                data_blobs.len()
+            );
+        }
+        Ok(data_blobs)
+    }
+
+    /// Bytes the latest snapshot of `family_name` takes up when restored to `output_dir`, with
+    /// files rounded up to whole blocks.
+    pub fn checkout_size(
+        &mut self,
+        family_name: &str,
+        output_dir: &Path,
+        block_size: u64,
+    ) -> Result<u64, HatError> {
+        match self.snapshot_index.latest(family_name) {
+            Some((_, _, Some(dir_ref))) => {
+                self.restore_size(dir_ref, output_dir, block_size, &mut vec![], &mut HashSet::new())
+            }
+            _ => Err(From::from(format!("No complete snapshot of family {}", family_name))),
+        }
+    }
+
+    /// Bytes needed to restore the tree below `dir_ref` to `output`. Files already there are
+    /// overwritten, so only growth counts; this lets an interrupted restore be run again.
+    /// The names of the blobs holding the files' hash trees are added to `data_blobs`, in the
+    /// order a checkout reads them. Files with several links count once, and their links are
+    /// added to `links`.
+    fn restore_size(
+        &self,
+        dir_ref: hash::tree::HashRef,
+        output: &Path,
+        block_size: u64,
+        data_blobs: &mut Vec<Vec<u8>>,
+        links: &mut HashSet<(u64, u64)>,
+    ) -> Result<u64, HatError> {
+        let blocks = |len: u64| len.div_ceil(block_size) * block_size;
+        let on_disk = |path: &Path| fs::symlink_metadata(path).map(|m| m.len()).unwrap_or(0);
+
+        let mut needed = if output.exists() { 0 } else { block_size };
+        // Depth first and in listing order, as `checkout_dir_ref` restores the entries.
+        let listing = family::Family::<B>::fetch_dir_data(dir_ref, self.hash_backend())?;
+        for res in listing {
+            let (entry, content) = res?;
+            let name: ffi::OsString = entry.info.name.into();
+            let file_path = output.join(name);
+            match content {
+                walker::Content::Data(href) => {
+                    if let Some(link) = entry.info.hard_link {
+                        if !links.insert(link) {
+                            // Restored as a link to a file counted before.
+                            continue;
+                        }
+                    }
+                    self.tree_blobs(&href, data_blobs);
+                    let len = blocks(entry.info.byte_length.unwrap_or(0));
+                    needed += len.saturating_sub(blocks(on_disk(&file_path)));
+                }
+                walker::Content::Dir(href) => {
+                    needed += self.restore_size(href, &file_path, block_size, data_blobs, links)?
+                }
+                walker::Content::Link(_)
+                | walker::Content::Unreadable(_)
+                | walker::Content::Special(_) => (),
+            }
+        }
+        Ok(needed)
+    }
+
+    /// Add the names of the blobs holding the hash tree at `href` to `blobs`, branches before
+    /// their leafs. The tree is looked up in the local hash index, so no blobs are read; parts
+    /// that are not in the index are left out.
+    fn tree_blobs(&self, href: &hash::tree::HashRef, blobs: &mut Vec<Vec<u8>>) {
+        let childs = match href.node {
+            blob::NodeType::Branch(_) if !href.hash.bytes.is_empty() => {
+                self.hash_index.fetch_childs(&href.hash).and_then(|c| c)
+            }
+            _ => None,
+        };
+        self.push_tree_blobs(Some(&href.persistent_ref), childs, blobs);
+    }
+
+    fn push_tree_blobs(
+        &self,
+        chunk: Option<&blob::ChunkRef>,
+        childs: Option<Vec<u64>>,
+        blobs: &mut Vec<Vec<u8>>,
+    ) {
+        if let Some(chunk) = chunk {
+            if chunk.length > 0 && blobs.last() != Some(&chunk.blob_name) {
+                blobs.push(chunk.blob_name.clone());
+            }
+        }
+        for id in childs.unwrap_or_default() {
+            if let Some(entry) = self.hash_index.get_hash(id) {
+                self.push_tree_blobs(entry.persistent_ref.as_ref(), entry.childs, blobs);
+            }
+        }
+    }
+
+    /// Restore the entries of the directory `dir_hash` that `pass` selects to `output`; `path` is
+    /// the path of the directory relative to the snapshot root. Files with several links are
+    /// restored once, and linked to from the path kept for them in `links`.
+    fn checkout_dir_ref(
+        &self,
+        family: &Family<B>,
+        output: &mut PathBuf,
+        path: &[u8],
+        dir_hash: hash::tree::HashRef,
+        pass: RestorePass,
+        links: &mut HashMap<(u64, u64), PathBuf>,
+    ) -> Result<(), HatError> {
+        fs::create_dir_all(&output).unwrap();
+        let restore_owners = owners::may_restore_owners();
+        let listing = family::Family::<B>::fetch_dir_data(dir_hash, self.hash_backend())?;
+        for res in listing {
+            let (entry, hash_ref) = res?;
             assert!(!entry.info.name.is_empty());
 
-            let name_os_string: ffi::OsString = entry.info.name.into();
+            let mut entry_path = path.to_vec();
+            if !entry_path.is_empty() {
+                entry_path.push(b'/');
+            }
+            entry_path.extend_from_slice(entry.info.name.as_bytes());
+            let is_dir = matches!(hash_ref, walker::Content::Dir(_));
+            let inner = match pass.inner(&entry_path, is_dir) {
+                Some(inner) => inner,
+                None => continue,
+            };
+
+            let name_os_string: ffi::OsString = entry.info.name.clone().into();
             output.push(&name_os_string);
+
+            if let walker::Content::Unreadable(ref error) = hash_ref {
+                // The snapshot has no contents to restore.
+                println!("Skipping '{}': unreadable: {}", output.display(), error);
+                output.pop();
+                continue;
+            }
+            if let walker::Content::Special(special) = hash_ref {
+                // Like owners, only root restores devices.
+                if special.is_device() && !restore_owners {
+                    println!("Skipping '{}': restoring devices requires root", output.display());
+                    output.pop();
+                    continue;
+                }
+            }
             println!("{}", output.display());
 
+            if let Some(first) = entry.info.hard_link.and_then(|link| links.get(&link)) {
+                // The file is restored already, with its owner, permissions and times.
+                if fs::symlink_metadata(&output).is_ok() {
+                    fs::remove_file(&output)?;
+                }
+                fs::hard_link(first, &output)?;
+                output.pop();
+                continue;
+            }
+
             match hash_ref {
                 walker::Content::Data(hash_ref) => {
                     let mut fd = fs::File::create(&output)?;
@@ -862,14 +2230,54 @@ impl<B: StoreBackend> HatRc<B> {
                     if let Some(tree) = tree_opt {
                         family::Family::<B>::write_file_chunks(&mut fd, tree);
                     }
+                    if let Some(ref progress) = self.progress {
+                        let len = entry.info.byte_length.unwrap_or(0);
+                        progress(util::ProgressEvent::FileRestored(len));
+                    }
+                    if let Some(link) = entry.info.hard_link {
+                        links.insert(link, output.clone());
+                    }
                 }
                 walker::Content::Dir(hash_ref) => {
-                    self.checkout_dir_ref(family, output, hash_ref)?;
+                    self.checkout_dir_ref(family, output, &entry_path, hash_ref, inner, links)?;
+                    if let RestorePass::Only(_) | RestorePass::Path(_) = inner {
+                        // Only part of the directory is restored; it must stay writable.
+                        output.pop();
+                        continue;
+                    }
                 }
                 walker::Content::Link(link_path) => {
                     use std::os::unix::fs::symlink;
                     symlink(link_path, &output)?
                 }
+                walker::Content::Special(special) => {
+                    use std::os::unix::fs::PermissionsExt;
+                    if fs::symlink_metadata(&output).is_ok() {
+                        fs::remove_file(&output)?;
+                    }
+                    let mode = entry.info.permissions.as_ref().map_or(0o644, |p| p.mode());
+                    let mode = special.file_type_mode() | (mode & 0o7777);
+                    util::make_special_file(output, mode, special.rdev())?
+                }
+                walker::Content::Unreadable(_) => unreachable!("skipped above"),
+            }
+
+            // Before the permissions, as changing owners clears setuid and setgid bits.
+            if restore_owners {
+                owners::restore_owner(output, &*self.names, self.owner_mapping, &entry.info)?;
+            }
+
+            // After the owner, as changing it clears capabilities, and before the permissions,
+            // as user attributes need write access. Some need privileges; the rest are kept.
+            for (name, value) in &entry.info.xattrs {
+                if let Err(e) = util::set_xattr(output, name, value) {
+                    println!(
+                        "Could not restore extended attribute {} of '{}': {}",
+                        String::from_utf8_lossy(name),
+                        output.display(),
+                        e
+                    );
+                }
             }
 
             if let Some(perms) = entry.info.permissions {
@@ -879,6 +2287,12 @@ impl<B: StoreBackend> HatRc<B> {
                 }
             }
 
+            if let Some(ref acl) = entry.info.acl {
+                if let Err(e) = acl::restore_acl(output, acl) {
+                    println!("Could not restore the ACLs of '{}': {}", output.display(), e);
+                }
+            }
+
             if let (Some(m), Some(a)) = (entry.info.modified_ts_secs, entry.info.accessed_ts_secs) {
                 let atime = filetime::FileTime::from_unix_time(a, 0 /* nanos */);
                 let mtime = filetime::FileTime::from_unix_time(m, 0 /* nanos */);
@@ -902,6 +2316,7 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn deregister(&mut self, family: &Family<B>, snapshot_id: u64) -> Result<(), HatError> {
+        self.check_writable()?;
         let (info, top_hash, top_ref) = match self.snapshot_index.lookup(&family.name, snapshot_id)
         {
             Some((i, h, Some(r))) => (i, h, r),
@@ -914,9 +2329,14 @@ impl<B: StoreBackend> HatRc<B> {
             }
         };
 
+        let integrity = self.integrity_ref(&family.name, snapshot_id)?;
+        // Stop counting its chunks while the tree can still be read; flushed with the mark below.
+        self.uncount_chunk_refs(&info, top_ref.clone())?;
+
         // Make the snapshot to enable resuming.
         self.snapshot_index.will_delete(&info);
         self.flush_snapshot_index();
+        util::fail_point("delete-marked")?;
 
         let final_ref = self
             .hash_index
@@ -942,13 +2362,21 @@ impl<B: StoreBackend> HatRc<B> {
                             let href = match res {
                                 walker::Content::Data(href) => href,
                                 walker::Content::Dir(href) => href,
-                                walker::Content::Link(_) => continue,
+                                walker::Content::Link(_)
+                                | walker::Content::Unreadable(_)
+                                | walker::Content::Special(_) => continue,
                             };
                             match hash_index.get_id(&href.hash) {
                                 Some(id) => id_sender.send(id).unwrap(),
                                 None => panic!("Unexpected reply from hash index."),
                             }
                         }
+                        if let Some(href) = integrity {
+                            let id = hash_index
+                                .get_id(&href.hash)
+                                .expect("Unknown integrity manifest");
+                            id_sender.send(id).unwrap();
+                        }
                     }
                     blob::LeafType::SnapshotList => {
                         // Only the top ref is needed for snapshot lists.
@@ -956,7 +2384,7 @@ impl<B: StoreBackend> HatRc<B> {
                             .send(hash_index.get_id(&top_ref.hash).expect("Unknown top ref"))
                             .unwrap();
                     }
-                    blob::LeafType::FileChunk => {
+                    blob::LeafType::FileChunk | blob::LeafType::Manifest => {
                         unreachable!("Called deregister directly on filechunk tree")
                     }
                 }
@@ -966,6 +2394,7 @@ impl<B: StoreBackend> HatRc<B> {
             gc.deregister(&info, final_ref, listing)?;
         }
         family.flush()?;
+        util::fail_point("delete-deregistered")?;
 
         self.deregister_finalize(family, info, final_ref)
     }
@@ -991,6 +2420,7 @@ impl<B: StoreBackend> HatRc<B> {
         // Mark the snapshot to enable resuming.
         self.snapshot_index.ready_delete(&snap_info);
         self.flush_snapshot_index();
+        util::fail_point("delete-ready")?;
 
         // Clear GC state.
         self.gc.register_cleanup(&snap_info, final_ref)?;
@@ -1005,16 +2435,136 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(self.flush_snapshot_index())
     }
 
+    /// Whether the repository was opened with read-only keys, see `bundle::write_read_only_bundle`.
+    pub fn is_read_only(&self) -> bool {
+        self.keys.is_read_only()
+    }
+
+    /// Fail if the repository was opened with read-only keys. Every operation that changes the
+    /// repository checks this first.
+    fn check_writable(&self) -> Result<(), HatError> {
+        if self.is_read_only() {
+            return Err("The repository was opened with read-only keys".into());
+        }
+        Ok(())
+    }
+
+    /// Prepare to change the backend. A shared backend is locked, and the blobs written by other
+    /// state directories are learned; any other backend must not have been changed by another
+    /// client since the local indexes were synced. Returns false if already locked by us.
+    pub fn lock_backend(&mut self) -> Result<bool, HatError> {
+        self.check_writable()?;
+        if self.locked_generation.is_some() {
+            return Ok(false);
+        }
+        let generation = match self.writer.clone() {
+            Some(writer) => {
+                let now = chrono::Utc::now().timestamp();
+                self.remote_lock = Some(shared::RemoteLock::acquire(&*self.backend, &writer, now)?);
+
+                // New blobs must be numbered after those of the other writers.
+                self.blob_store.recover()?;
+                // The changes of the other writers are coordinated by the lock.
+                let generation = shared::read_generation(&*self.backend)?;
+                self.set_synced_generation(generation)?;
+                generation
+            }
+            None => {
+                let generation = shared::read_generation(&*self.backend)?;
+                self.check_synced_with(generation)?;
+                generation
+            }
+        };
+        self.locked_generation = Some(generation);
+        Ok(true)
+    }
+
+    /// Count the change to the backend, and for a shared one publish the blobs we know of and
+    /// release the lock taken by `lock_backend`.
+    pub fn unlock_backend(&mut self) -> Result<(), HatError> {
+        let generation = match self.locked_generation.take() {
+            Some(generation) => generation,
+            None => return Ok(()),
+        };
+        self.blob_store.flush()?;
+        self.advance_generation(generation)?;
+        if let (Some(lock), Some(writer)) = (self.remote_lock.take(), self.writer.as_ref()) {
+            let now = chrono::Utc::now().timestamp();
+            shared::publish_refs(&*self.backend, writer, now, &self.blob_index.names())?;
+            lock.release(&*self.backend)?;
+        }
+        Ok(())
+    }
+
+    /// Share the backend with other state directories as `writer`.
+    #[cfg(test)]
+    pub fn set_writer(&mut self, writer: Option<String>) {
+        self.writer = writer;
+    }
+
+    /// Drop the repository as a crash would: what is not yet written to the state directory or
+    /// the backend is lost.
+    #[cfg(test)]
+    pub fn crash(self) {
+        self.hash_index.crash();
+        self.blob_store.crash();
+        for bs in &self.data_blob_stores {
+            bs.crash();
+        }
+    }
+
     pub fn gc(&mut self) -> Result<(u64, u64), HatError> {
+        self.gc_until(Deadline::none())
+            .map(|summary| (summary.deleted_hashes, summary.live_blobs))
+    }
+
+    /// Like `gc`, but stops deleting once `deadline` passes. Garbage left behind is picked up
+    /// by the next GC.
+    pub fn gc_until(&mut self, deadline: Deadline) -> Result<GcSummary, HatError> {
+        let locked = self.lock_backend()?;
+        let res = self.gc_unlocked(deadline);
+        if locked {
+            self.unlock_backend()?;
+        }
+        res
+    }
+
+    fn gc_unlocked(&mut self, deadline: Deadline) -> Result<GcSummary, HatError> {
+        // Blobs of a writer that crashed may not be published yet.
+        let foreign = match self.writer {
+            Some(ref writer) => {
+                let now = chrono::Utc::now().timestamp();
+                let stale = shared::stale_writers(&*self.backend, writer, now)?;
+                if !stale.is_empty() {
+                    return Err(format!(
+                        "Writers {} hold stale locks; run `hat resume` there before gc",
+                        stale.join(", ")
+                    ).into());
+                }
+                shared::foreign_refs(&*self.backend, writer)?
+            }
+            None => vec![],
+        };
+
         // Remove unused hashes.
         let mut deleted_hashes = 0;
         let (sender, receiver) = mpsc::channel();
         self.gc.list_unused_ids(sender)?;
         for id in receiver.iter() {
+            if deadline.is_past() {
+                break;
+            }
             deleted_hashes += 1;
             self.hash_index.delete(id);
         }
         self.hash_index.flush();
+        util::fail_point("gc-hashes-deleted")?;
+        if deadline.is_past() {
+            return Ok(GcSummary {
+                deleted_hashes: deleted_hashes,
+                ..GcSummary::default()
+            });
+        }
         // Mark used blobs.
         let entries = self.hash_index.list();
         self.blob_store.tag_all(tags::Tag::InProgress);
@@ -1026,12 +2576,263 @@ impl<B: StoreBackend> HatRc<B> {
                 self.blob_store.tag(pref, tags::Tag::Reserved);
             }
         }
+        // Blobs other writers know of may be used by their snapshots.
+        for name in foreign {
+            self.blob_store.tag_by_name(name, tags::Tag::Reserved);
+        }
+        util::fail_point("gc-blobs-marked")?;
         // Anything still marked "in progress" is not referenced by any hash.
-        self.blob_store.delete_by_tag(tags::Tag::InProgress)?;
+        let (completed, retained_blobs) = self.blob_store
+            .delete_by_tag_until(tags::Tag::InProgress, deadline)?;
         self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
+        self.blob_store.flush()?;
+
+        Ok(GcSummary {
+            deleted_hashes: deleted_hashes,
+            live_blobs: live_blobs,
+            retained_blobs: retained_blobs,
+            completed: completed,
+        })
+    }
+
+    /// Limit the storage used by blobs to `quota` bytes. Storing a blob that would exceed it
+    /// aborts the running commit; `check_quota` lets callers fail before starting one.
+    pub fn set_quota(&self, quota: Option<u64>) {
+        self.blob_store.set_quota(quota);
+    }
+
+    /// Keep the metadata chunks read from the backend in `cache`, so deleting several
+    /// snapshots that share trees, or resuming a delete, does not download them again.
+    pub fn set_chunk_cache(&self, cache: Option<Box<blob::ChunkStore>>) {
+        self.blob_store.set_chunk_cache(cache);
+    }
+
+    /// Wait at most `max_wait` for blobs to be restored from a cold storage tier, reading them
+    /// again after `poll` at first.
+    pub fn set_restore_wait(&self, poll: Duration, max_wait: Duration) {
+        self.blob_store.set_restore_wait(poll, max_wait);
+    }
+
+    /// Bytes of storage used by this repository's blobs.
+    pub fn storage_usage(&self) -> u64 {
+        self.blob_store.usage()
+    }
+
+    /// The version of the keys new blobs are written with.
+    pub fn key_version(&self) -> u64 {
+        self.keys.key_version()
+    }
+
+    /// Storage used by blobs, by the version of the keys they were written with, oldest first.
+    pub fn key_usage(&self) -> Vec<KeyUsage> {
+        self.blob_store.key_usage()
+    }
+
+    /// Compress the chunks stored from now on with `compression`. Chunks already stored keep
+    /// the packing they were stored with.
+    pub fn set_compression(&mut self, compression: blob::Compression) {
+        self.compression = compression;
+        self.blob_store.set_compression(compression);
+        for bs in &self.data_blob_stores {
+            bs.set_compression(compression);
+        }
+    }
+
+    /// Use `chunking` for files committed through families opened from now on.
+    pub fn set_chunking(&mut self, chunking: key::ChunkingProfiles) {
+        self.chunking = Arc::new(chunking.with_key(self.keys.chunking_key().unsecure()));
+    }
+
+    /// Visit directory entries in `order` in snapshots of families opened from now on.
+    pub fn set_file_order(&mut self, order: util::FileOrder) {
+        self.file_order = order;
+    }
+
+    /// Leave out the paths `exclude` excludes in snapshots of families opened from now on.
+    pub fn set_exclude_filter(&mut self, exclude: util::ExcludeFilter) {
+        self.exclude = exclude;
+    }
+
+    /// Handle files that change while being read as `policy` says in snapshots of families
+    /// opened from now on.
+    pub fn set_changed_policy(&mut self, policy: util::ChangedFilePolicy) {
+        self.changed_policy = policy;
+    }
+
+    /// Report the progress of snapshots of families opened from now on, of uploads and of
+    /// checkouts to `progress`.
+    pub fn set_progress(&mut self, progress: Option<util::ProgressFn>) {
+        self.blob_store.set_progress(progress.clone());
+        for bs in &self.data_blob_stores {
+            bs.set_progress(progress.clone());
+        }
+        self.progress = progress;
+    }
+
+    /// Snapshot up to `jobs` files at the same time in families opened from now on, each into
+    /// blobs of its own.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = cmp::max(jobs, 1);
+    }
+
+    /// Have checkouts fetch up to `jobs` blobs from the backend at the same time, ahead of
+    /// the files being written; 1 still fetches the next blob while a file is written.
+    pub fn set_fetch_jobs(&mut self, jobs: usize) {
+        self.fetch_jobs = cmp::max(jobs, 1);
+    }
+
+    /// Pass files through `filter` in snapshots of families opened from now on.
+    pub fn set_content_filter(&mut self, filter: Arc<content_filter::ContentFilter>) {
+        self.content_filter = Some(filter);
+    }
+
+    /// Name the owners of files with `names`, in snapshots of families opened from now on and in
+    /// restores.
+    pub fn set_name_resolver(&mut self, names: Arc<owners::NameResolver>) {
+        self.names = names;
+    }
 
-        Ok((deleted_hashes, live_blobs))
+    /// Record `message` and `tags` with the snapshots committed from now on.
+    pub fn set_commit_message(&mut self, message: Option<String>, tags: Vec<String>) {
+        self.commit_message = message;
+        self.commit_tags = tags;
+    }
+
+    /// Choose the owners of restored files as `mapping` says.
+    pub fn set_owner_mapping(&mut self, mapping: owners::OwnerMapping) {
+        self.owner_mapping = mapping;
+    }
+
+    /// Splits `reader`, the contents of a file named `name`, the way commits split it.
+    pub fn chunker<R: Read>(&self, reader: R, name: &[u8]) -> key::Chunker<R> {
+        self.chunking.chunker(reader, name)
+    }
+
+    /// File data stored by commits so far, and file data they found already stored.
+    pub fn store_metrics(&self) -> StoreMetrics {
+        self.data_blob_stores
+            .iter()
+            .fold(self.blob_store.store_metrics(), |sum, bs| {
+                sum.add(&bs.store_metrics())
+            })
+    }
+
+    /// Verification counts for the data this repository has read back from its backend.
+    pub fn retrieve_metrics(&self) -> RetrieveMetrics {
+        self.blob_store.retrieve_metrics()
+    }
+
+    /// The chunks that failed verification when read back so far, and what they affect.
+    pub fn retrieve_report(&mut self) -> RetrieveReport {
+        let failures = self.blob_store.retrieve_failures();
+        let whole_blobs: Vec<_> = failures
+            .iter()
+            .filter(|f| f.whole_blob)
+            .map(|f| f.blob_name.clone())
+            .collect();
+        let mut hashes = self.blob_hashes(&whole_blobs);
+        hashes.extend(failures.iter().filter(|f| !f.whole_blob).map(|f| f.hash.clone()));
+        RetrieveReport {
+            metrics: self.retrieve_metrics(),
+            affected: self.affected_versions(&hashes).map_err(|e| e.to_string()),
+            failures: failures,
+        }
+    }
+
+    /// Fail if the storage quota leaves no room for another blob.
+    pub fn check_quota(&self) -> Result<(), HatError> {
+        match self.blob_store.quota() {
+            Some(quota) => {
+                let usage = self.storage_usage();
+                if usage + self.blob_max_size as u64 > quota {
+                    return Err(blob::quota_exceeded_message(usage, quota).into());
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Verify every committed blob: its authentication tag, its footer and each chunk in it.
+    /// Blobs are streamed with ranged reads, several at a time, so memory use stays constant.
+    /// Returns the number of blobs checked and the ones that failed.
+    pub fn verify_blobs(&self) -> (usize, Vec<(blob::BlobDesc, blob::BlobError)>) {
+        let (count, failures, _) = self.verify_blobs_from(0, Deadline::none());
+        (count, failures)
+    }
+
+    /// Like `verify_blobs`, but only blobs with ids above `after_id`, in order of id, until
+    /// `deadline` passes. If stopped early, also returns the id of the last verified blob to
+    /// continue after.
+    pub fn verify_blobs_from(
+        &self,
+        after_id: i64,
+        deadline: Deadline,
+    ) -> (usize, Vec<(blob::BlobDesc, blob::BlobError)>, Option<i64>) {
+        // Listed by descending id, so blobs are popped in ascending order.
+        let mut blobs = self.blob_store.list_by_tag(tags::Tag::Done);
+        blobs.retain(|b| b.id > after_id);
+        let queue = Arc::new(Mutex::new(blobs));
+        let verified = Arc::new(Mutex::new((0, after_id)));
+        // Cached copies say nothing about what the storage holds.
+        let verifier = Arc::new(blob::BlobVerifier::new(
+            self.keys.clone(),
+            Arc::new(UncachedBackend::new(self.backend.clone())),
+        ));
+
+        let (sender, receiver) = mpsc::channel();
+        let workers: Vec<_> = (0..VERIFY_PARALLEL_BLOBS)
+            .map(|_| {
+                let queue = queue.clone();
+                let verified = verified.clone();
+                let verifier = verifier.clone();
+                let sender = sender.clone();
+                thread::spawn(move || loop {
+                    // Blobs are taken in order, so those verified always precede the rest.
+                    let next = {
+                        let mut queue = queue.lock().unwrap();
+                        if deadline.is_past() {
+                            None
+                        } else {
+                            queue.pop().inspect(|b| {
+                                let mut verified = verified.lock().unwrap();
+                                verified.0 += 1;
+                                verified.1 = b.id;
+                            })
+                        }
+                    };
+                    match next {
+                        None => break,
+                        Some(b) => if let Err(e) = verifier.verify(&b.name) {
+                            sender.send((b, e)).unwrap();
+                        },
+                    }
+                })
+            })
+            .collect();
+        drop(sender);
+
+        let failures = receiver.iter().collect();
+        for worker in workers {
+            worker.join().expect("verify worker panicked");
+        }
+        let (count, last_id) = *verified.lock().unwrap();
+        let resume_after = if queue.lock().unwrap().is_empty() {
+            None
+        } else {
+            Some(last_id)
+        };
+        (count, failures, resume_after)
+    }
+
+    /// The hash a chunk of file contents is stored under.
+    pub fn file_chunk_hash(&self, chunk: &[u8]) -> hash::Hash {
+        hash::Hash::new(
+            &self.keys,
+            blob::NodeType::Leaf,
+            blob::LeafType::FileChunk,
+            chunk,
+        )
     }
 
     pub fn hash_backend(&self) -> key::HashStoreBackend<B> {
@@ -1041,4 +2842,9 @@ impl<B: StoreBackend> HatRc<B> {
             self.keys.clone(),
         )
     }
+
+    /// A read-only handle on the stored trees that can be used without this `Hat`.
+    pub fn hash_reader(&self) -> HashReader<B> {
+        HashReader::new(self.hash_backend())
+    }
 }
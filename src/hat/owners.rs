@@ -0,0 +1,215 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Names of the users and groups that own files.
+//!
+//! Snapshots record the names of owners next to their numeric ids, as a reinstalled system may
+//! give the same user another id. Restores look the names up again, and use the ids they have
+//! on the system restored to.
+
+use key;
+use libc;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::hash::Hash;
+use std::io;
+use std::mem;
+use std::os::unix::fs::lchown;
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+
+/// Maps user and group ids to names and back.
+pub trait NameResolver: Send + Sync {
+    fn user_name(&self, uid: u64) -> Option<String>;
+    fn group_name(&self, gid: u64) -> Option<String>;
+    fn user_id(&self, name: &str) -> Option<u64>;
+    fn group_id(&self, name: &str) -> Option<u64>;
+}
+
+/// Largest buffer given to the `get*_r` functions, which ask for more with `ERANGE`.
+const MAX_BUFFER: usize = 1024 * 1024;
+
+/// Call `f` with buffers of growing size until it no longer fails with `ERANGE`.
+fn with_buffer<T, F>(f: F) -> Option<T>
+where
+    F: Fn(&mut [libc::c_char]) -> Result<Option<T>, libc::c_int>,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        match f(&mut buf) {
+            Ok(found) => return found,
+            Err(libc::ERANGE) if buf.len() < MAX_BUFFER => {
+                let len = 2 * buf.len();
+                buf.resize(len, 0);
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+fn passwd_by_uid(uid: u64) -> Option<String> {
+    with_buffer(|buf| unsafe {
+        let mut pwd: libc::passwd = mem::zeroed();
+        let mut found = ptr::null_mut();
+        let uid = uid as libc::uid_t;
+        match libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) {
+            0 if found.is_null() => Ok(None),
+            0 => Ok(Some(CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned())),
+            e => Err(e),
+        }
+    })
+}
+
+fn passwd_by_name(name: &str) -> Option<u64> {
+    let name = CString::new(name).ok()?;
+    with_buffer(|buf| unsafe {
+        let mut pwd: libc::passwd = mem::zeroed();
+        let mut found = ptr::null_mut();
+        match libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) {
+            0 if found.is_null() => Ok(None),
+            0 => Ok(Some(u64::from(pwd.pw_uid))),
+            e => Err(e),
+        }
+    })
+}
+
+fn group_by_gid(gid: u64) -> Option<String> {
+    with_buffer(|buf| unsafe {
+        let mut grp: libc::group = mem::zeroed();
+        let mut found = ptr::null_mut();
+        let gid = gid as libc::gid_t;
+        match libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) {
+            0 if found.is_null() => Ok(None),
+            0 => Ok(Some(CStr::from_ptr(grp.gr_name).to_string_lossy().into_owned())),
+            e => Err(e),
+        }
+    })
+}
+
+fn group_by_name(name: &str) -> Option<u64> {
+    let name = CString::new(name).ok()?;
+    with_buffer(|buf| unsafe {
+        let mut grp: libc::group = mem::zeroed();
+        let mut found = ptr::null_mut();
+        match libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) {
+            0 if found.is_null() => Ok(None),
+            0 => Ok(Some(u64::from(grp.gr_gid))),
+            e => Err(e),
+        }
+    })
+}
+
+/// Look `key` up in `cache`, asking `lookup` the first time.
+fn cached<K, V, F>(cache: &Mutex<HashMap<K, Option<V>>>, key: K, lookup: F) -> Option<V>
+where
+    K: Eq + Hash,
+    V: Clone,
+    F: FnOnce(&K) -> Option<V>,
+{
+    let mut cache = cache.lock().unwrap();
+    if let Some(found) = cache.get(&key) {
+        return found.clone();
+    }
+    let found = lookup(&key);
+    cache.insert(key, found.clone());
+    found
+}
+
+/// Resolves names with the user and group databases of the system, as `ls -l` does. Answers
+/// are cached, as a commit asks for the same few owners over and over.
+#[derive(Default)]
+pub struct SystemNames {
+    user_names: Mutex<HashMap<u64, Option<String>>>,
+    group_names: Mutex<HashMap<u64, Option<String>>>,
+    user_ids: Mutex<HashMap<String, Option<u64>>>,
+    group_ids: Mutex<HashMap<String, Option<u64>>>,
+}
+
+impl SystemNames {
+    pub fn new() -> SystemNames {
+        SystemNames::default()
+    }
+}
+
+impl NameResolver for SystemNames {
+    fn user_name(&self, uid: u64) -> Option<String> {
+        cached(&self.user_names, uid, |&uid| passwd_by_uid(uid))
+    }
+
+    fn group_name(&self, gid: u64) -> Option<String> {
+        cached(&self.group_names, gid, |&gid| group_by_gid(gid))
+    }
+
+    fn user_id(&self, name: &str) -> Option<u64> {
+        cached(&self.user_ids, name.to_owned(), |name| passwd_by_name(name))
+    }
+
+    fn group_id(&self, name: &str) -> Option<u64> {
+        cached(&self.group_ids, name.to_owned(), |name| group_by_name(name))
+    }
+}
+
+/// How restores choose the owners of the files they write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OwnerMapping {
+    /// Use the ids the recorded names have on this system, and the recorded ids for owners
+    /// without a name or with a name the system does not know.
+    #[default]
+    ByName,
+    /// Use the recorded ids, as `tar --numeric-owner` does.
+    Numeric,
+}
+
+/// Record the names of the owners in `info`, for the ids that have one.
+pub fn add_names(names: &NameResolver, info: &mut key::Info) {
+    if info.user_name.is_none() {
+        info.user_name = info.user_id.and_then(|uid| names.user_name(uid));
+    }
+    if info.group_name.is_none() {
+        info.group_name = info.group_id.and_then(|gid| names.group_name(gid));
+    }
+}
+
+/// The user and group ids to restore a file described by `info` with.
+pub fn restore_ids(
+    names: &NameResolver,
+    mapping: OwnerMapping,
+    info: &key::Info,
+) -> (Option<u64>, Option<u64>) {
+    if mapping == OwnerMapping::Numeric {
+        return (info.user_id, info.group_id);
+    }
+    let uid = info.user_name.as_ref().and_then(|name| names.user_id(name));
+    let gid = info.group_name.as_ref().and_then(|name| names.group_id(name));
+    (uid.or(info.user_id), gid.or(info.group_id))
+}
+
+/// Whether restores can give files to other users. Like `tar`, only root restores owners.
+pub fn may_restore_owners() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Give the file at `path`, or the symbolic link itself, the owners `info` describes.
+pub fn restore_owner(
+    path: &Path,
+    names: &NameResolver,
+    mapping: OwnerMapping,
+    info: &key::Info,
+) -> Result<(), io::Error> {
+    match restore_ids(names, mapping, info) {
+        (None, None) => Ok(()),
+        (uid, gid) => lchown(path, uid.map(|u| u as u32), gid.map(|g| g as u32)),
+    }
+}
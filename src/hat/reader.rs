@@ -0,0 +1,97 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only access to stored trees.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hash::tree::{HashRef, LeafIterator, Walker};
+use hat::family::{DirIterator, Family};
+use key::HashStoreBackend;
+use std::io;
+
+/// A handle for reading trees out of a repository.
+///
+/// Cloning it is cheap and clones can be sent to other threads. Readers never need the `Hat`
+/// itself, so listing directories and reading files does not wait for a commit or another reader.
+pub struct HashReader<B> {
+    backend: HashStoreBackend<B>,
+}
+
+impl<B> Clone for HashReader<B> {
+    fn clone(&self) -> HashReader<B> {
+        HashReader {
+            backend: self.backend.clone(),
+        }
+    }
+}
+
+impl<B: StoreBackend> HashReader<B> {
+    pub fn new(backend: HashStoreBackend<B>) -> HashReader<B> {
+        HashReader { backend: backend }
+    }
+
+    /// Walk the tree below `root`, nodes included. Returns `None` for an empty tree.
+    pub fn get_tree(&self, root: HashRef) -> Result<Option<Walker<HashStoreBackend<B>>>, HatError> {
+        Ok(Walker::new(self.backend.clone(), root)?)
+    }
+
+    /// The leaves of the tree below `root`, in order. Returns `None` for an empty tree.
+    pub fn get_leaf_iter(
+        &self,
+        root: HashRef,
+    ) -> Result<Option<LeafIterator<HashStoreBackend<B>>>, HatError> {
+        Ok(LeafIterator::new(self.backend.clone(), root)?)
+    }
+
+    /// The entries of the directory stored at `dir`, decoded as they are consumed.
+    pub fn list_dir(&self, dir: HashRef) -> Result<DirIterator<HashStoreBackend<B>>, HatError> {
+        Family::<B>::fetch_dir_data(dir, self.backend.clone())
+    }
+
+    /// The backend behind this reader, for the hash tree functions that take one.
+    pub fn backend(&self) -> HashStoreBackend<B> {
+        self.backend.clone()
+    }
+}
+
+/// Reads the concatenation of a sequence of chunks.
+pub struct ChunkReader {
+    chunks: Box<Iterator<Item = Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl ChunkReader {
+    pub fn new(chunks: Box<Iterator<Item = Vec<u8>>>) -> ChunkReader {
+        ChunkReader {
+            chunks: chunks,
+            chunk: io::Cursor::new(vec![]),
+        }
+    }
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.chunks.next() {
+                Some(chunk) => self.chunk = io::Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retention policies: which snapshots of a family to keep as they age.
+//!
+//! Each rule keeps the newest snapshot of each of the last N periods (days, weeks, months or
+//! years, in UTC) that have a snapshot. A snapshot kept by any rule is kept; all others are
+//! deleted when pruning.
+
+use backend::StoreBackend;
+use chrono::{self, Datelike};
+use db;
+use errors::HatError;
+use hat::HatRc;
+use std::cmp;
+use std::collections::BTreeSet;
+
+type Created = chrono::DateTime<chrono::Utc>;
+
+/// How many snapshots to keep, by age. The default keeps none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// The newest snapshots.
+    pub keep_last: usize,
+    /// The last snapshot of each day.
+    pub keep_daily: usize,
+    /// The last snapshot of each ISO week.
+    pub keep_weekly: usize,
+    /// The last snapshot of each month.
+    pub keep_monthly: usize,
+    /// The last snapshot of each year.
+    pub keep_yearly: usize,
+}
+
+impl Policy {
+    /// Whether the policy keeps no snapshots at all.
+    pub fn is_empty(&self) -> bool {
+        *self == Policy::default()
+    }
+}
+
+/// Keep the newest snapshot of each of the last `count` periods of `snapshots`, which are
+/// ordered newest first.
+fn keep_per_period<F>(keep: &mut BTreeSet<u64>, snapshots: &[(u64, Created)], count: usize, f: F)
+where
+    F: Fn(&Created) -> (i32, u32),
+{
+    let mut last = None;
+    let mut left = count;
+    for &(id, ref created) in snapshots {
+        if left == 0 {
+            break;
+        }
+        let period = f(created);
+        if last != Some(period) {
+            keep.insert(id);
+            last = Some(period);
+            left -= 1;
+        }
+    }
+}
+
+/// The ids of the snapshots `policy` does not keep, in ascending order. `snapshots` are the
+/// `(id, created)` of the complete snapshots of a family.
+pub fn select(policy: &Policy, snapshots: &[(u64, Created)]) -> Vec<u64> {
+    let mut newest_first = snapshots.to_vec();
+    newest_first.sort_by_key(|&(id, created)| cmp::Reverse((created, id)));
+
+    let mut keep = BTreeSet::new();
+    keep.extend(newest_first.iter().take(policy.keep_last).map(|&(id, _)| id));
+    keep_per_period(&mut keep, &newest_first, policy.keep_daily, |c| {
+        (c.year(), c.ordinal())
+    });
+    keep_per_period(&mut keep, &newest_first, policy.keep_weekly, |c| {
+        (c.iso_week().year(), c.iso_week().week())
+    });
+    keep_per_period(&mut keep, &newest_first, policy.keep_monthly, |c| {
+        (c.year(), c.month())
+    });
+    keep_per_period(&mut keep, &newest_first, policy.keep_yearly, |c| (c.year(), 0));
+
+    let mut dropped: Vec<u64> = snapshots
+        .iter()
+        .map(|&(id, _)| id)
+        .filter(|id| !keep.contains(id))
+        .collect();
+    dropped.sort();
+    dropped
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// The snapshots of family `family` that `policy` does not keep, in ascending order.
+    pub fn prune_candidates(&mut self, family: &str, policy: &Policy) -> Vec<u64> {
+        let snapshots: Vec<_> = self
+            .list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == family)
+            .filter(|s| matches!(s.status, db::SnapshotWorkStatus::CommitComplete))
+            .map(|s| (s.info.snapshot_id, s.created))
+            .collect();
+        select(policy, &snapshots)
+    }
+
+    /// Delete the snapshots of family `family` that `policy` does not keep. Data only they used
+    /// is reclaimed by the next GC. Returns the ids of the deleted snapshots.
+    pub fn prune(&mut self, family: &str, policy: &Policy) -> Result<Vec<u64>, HatError> {
+        self.check_writable()?;
+        if policy.is_empty() {
+            return Err(From::from("The retention policy keeps no snapshots"));
+        }
+        let dropped = self.prune_candidates(family, policy);
+        if !dropped.is_empty() {
+            let fam = self.open_family(family.to_owned())?;
+            for &id in &dropped {
+                self.deregister(&fam, id)?;
+            }
+        }
+        Ok(dropped)
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rotating the keys new blobs are written with.
+//!
+//! Each key version derives its own data, access and blob authentication keys from the
+//! universal key. Blobs keep the version they were written with, and a reader tries the
+//! versions it knows, newest first. The current version is kept in the state directory and in
+//! the backend, where other state directories pick it up when they sync or recover.
+//!
+//! As every version is derived from the universal key, rotating protects against leaked
+//! read-only keys, which cannot derive newer versions, but not against a leaked universal key.
+
+use backend::{shared, StoreBackend};
+use blob::NodeType;
+use crypto::keys::Keeper;
+use errors::HatError;
+use hash;
+use hash::tree::{HashRef, HashTreeBackend};
+use hat::HatRc;
+use hex;
+use key;
+use std::cmp;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Rotate the keys: write new blobs with keys of the next version, and write the snapshot
+    /// list again under them, sealing the blobs of the older lists again too. Other blobs
+    /// written before keep their keys and stay readable. Returns the new key version.
+    ///
+    /// The version is published to the backend last, so other state directories only adopt it
+    /// once the lists are sealed. If rotating fails before, rotating again finishes the job.
+    pub fn rotate_keys(&mut self) -> Result<u64, HatError> {
+        self.check_writable()?;
+
+        let known = shared::read_key_version(&*self.backend)?;
+        let version = cmp::max(self.keys.key_version(), known) + 1;
+        self.use_key_version(version)?;
+
+        self.meta_commit_and_flush()?;
+        let resealed = self.reseal_snapshot_lists()?;
+        info!("Sealed {} blobs of snapshot lists with the new keys", resealed);
+        self.flush_barrier()?;
+
+        shared::write_key_version(&*self.backend, version)?;
+        Ok(version)
+    }
+
+    /// Seal the blobs holding the snapshot lists kept in the backend with the current keys,
+    /// wherever they were sealed with older ones. Returns the number of blobs sealed again.
+    pub fn reseal_snapshot_lists(&mut self) -> Result<usize, HatError> {
+        let backend = key::HashStoreBackend::new(
+            self.hash_index.clone(),
+            self.blob_store.clone(),
+            self.keys.clone(),
+        );
+        let mut stack = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            match snapshot.hash_ref {
+                Some(ref bytes) if snapshot.is_internal() => {
+                    stack.push(HashRef::from_bytes(&bytes[..])?)
+                }
+                _ => (),
+            }
+        }
+
+        let mut names = BTreeSet::new();
+        while let Some(href) = stack.pop() {
+            if let NodeType::Branch(..) = href.node {
+                let chunk = backend
+                    .fetch_chunk(&href)?
+                    .ok_or("Snapshot list chunk is missing")?;
+                stack.extend(
+                    hash::tree::hash_refs_from_bytes(&chunk[..])
+                        .ok_or("Invalid snapshot list tree node")?,
+                );
+            }
+            names.insert(href.persistent_ref.blob_name);
+        }
+
+        let mut resealed = 0;
+        for name in names {
+            let blob = self
+                .blob_index
+                .find(&name)
+                .ok_or_else(|| format!("Unknown blob {}", hex::encode(&name)))?;
+            if self.blob_store.reseal(blob)? {
+                resealed += 1;
+            }
+        }
+        Ok(resealed)
+    }
+
+    /// Seal up to `limit` blobs again with the current keys, looking at those under the oldest
+    /// keys first, so older keys protect less and less data. Returns the number of blobs sealed
+    /// again; the ones found to use the current keys already count towards `limit` too.
+    pub fn reseal_oldest_blobs(&mut self, limit: usize) -> Result<usize, HatError> {
+        self.check_writable()?;
+
+        let mut resealed = 0;
+        for blob in self.blob_index.oldest_key_blobs(limit) {
+            if self.blob_store.reseal(blob)? {
+                resealed += 1;
+            }
+        }
+        self.flush_barrier()?;
+        Ok(resealed)
+    }
+
+    /// Start writing with the keys of a newer version that the backend was rotated to from
+    /// another state directory. Read-only keys cannot derive it, and stay as they are.
+    pub fn adopt_key_version(&mut self) -> Result<(), HatError> {
+        if self.keys.is_read_only() {
+            return Ok(());
+        }
+        let version = shared::read_key_version(&*self.backend)?;
+        if version > self.keys.key_version() {
+            self.use_key_version(version)?;
+        }
+        Ok(())
+    }
+
+    /// Write new blobs with the keys of version `version`, from now on and when the state
+    /// directory is opened again.
+    fn use_key_version(&mut self, version: u64) -> Result<(), HatError> {
+        let keys = Arc::new(self.keys.at_key_version(version));
+        if let Some(state_dir) = self.repository_root.as_ref().and_then(|root| root.parent()) {
+            Keeper::write_key_version(state_dir, version)?;
+        }
+
+        self.blob_store.set_keys(keys.clone());
+        for bs in &self.data_blob_stores {
+            bs.set_keys(keys.clone());
+        }
+        self.keys = keys;
+        Ok(())
+    }
+}
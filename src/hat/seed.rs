@@ -0,0 +1,222 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Seeding: the first, very large commit of a family, spread over as many runs as it takes.
+//!
+//! The paths are scanned once to learn how much there is to read, so each run can report how
+//! far the seed has come. While snapshotting, the family is flushed often, so a run that is
+//! stopped or killed loses little of its work.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hat::family::Family;
+use hat::HatRc;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// Name of the state of an unfinished seed in the state directory.
+pub const SEED_FILENAME: &str = "seed";
+
+/// How often a seed flushes what it has read so far.
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Regular files below some paths, and their total size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanTotals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+fn scan_path(path: &Path, totals: &mut ScanTotals) {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return,
+    };
+    if meta.is_file() {
+        totals.files += 1;
+        totals.bytes += meta.len();
+    } else if meta.is_dir() {
+        // Unreadable directories are skipped here, as they are by the snapshot.
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            scan_path(&entry.path(), totals);
+        }
+    }
+}
+
+/// Count the regular files below `paths`, without following symlinks.
+pub fn scan(paths: &[PathBuf]) -> ScanTotals {
+    let mut totals = ScanTotals::default();
+    for path in paths {
+        scan_path(path, &mut totals);
+    }
+    totals
+}
+
+/// The files a snapshot has handled so far, out of the totals of a scan.
+pub struct SnapshotProgress {
+    total: ScanTotals,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl SnapshotProgress {
+    pub fn new(total: ScanTotals) -> SnapshotProgress {
+        SnapshotProgress {
+            total: total,
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a regular file of `bytes` bytes as handled, whether it was read or found unchanged.
+    pub fn add_file(&self, bytes: u64) {
+        self.files.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn total(&self) -> ScanTotals {
+        self.total
+    }
+
+    pub fn done(&self) -> ScanTotals {
+        ScanTotals {
+            files: self.files.load(Ordering::SeqCst),
+            bytes: self.bytes.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Percentage of the bytes handled; files that grew since the scan can not take it past 100.
+    pub fn percent(&self) -> f64 {
+        let done = self.done();
+        if self.total.bytes == 0 {
+            return if done.files >= self.total.files { 100.0 } else { 0.0 };
+        }
+        (done.bytes as f64 * 100.0 / self.total.bytes as f64).min(100.0)
+    }
+}
+
+/// An unfinished seed, kept in the state directory between runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeedState {
+    pub family: String,
+    pub started_utc: i64,
+    /// Number of runs so far, including the current one.
+    pub runs: u64,
+    pub total: ScanTotals,
+}
+
+impl SeedState {
+    /// The unfinished seed of `family` in `state_dir`, if any.
+    pub fn load(state_dir: &Path, family: &str) -> Option<SeedState> {
+        SeedState::load_any(state_dir).filter(|state| state.family == family)
+    }
+
+    /// The unfinished seed in `state_dir`, of whichever family.
+    pub fn load_any(state_dir: &Path) -> Option<SeedState> {
+        let text = fs::read_to_string(state_dir.join(SEED_FILENAME)).ok()?;
+        let mut lines = text.splitn(2, '\n');
+        let mut fields = lines.next()?.split_whitespace().map(|f| f.parse::<i64>());
+        let name = lines.next()?.trim_end_matches('\n');
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(Ok(started)), Some(Ok(runs)), Some(Ok(files)), Some(Ok(bytes)))
+                if runs >= 0 && files >= 0 && bytes >= 0 =>
+            {
+                Some(SeedState {
+                    family: name.to_owned(),
+                    started_utc: started,
+                    runs: runs as u64,
+                    total: ScanTotals {
+                        files: files as u64,
+                        bytes: bytes as u64,
+                    },
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn store(&self, state_dir: &Path) -> Result<(), HatError> {
+        let text = format!(
+            "{} {} {} {}\n{}\n",
+            self.started_utc, self.runs, self.total.files, self.total.bytes, self.family
+        );
+        Ok(fs::write(state_dir.join(SEED_FILENAME), text)?)
+    }
+
+    /// Forget the seed, once its snapshot is committed.
+    pub fn remove(state_dir: &Path) -> Result<(), HatError> {
+        match fs::remove_file(state_dir.join(SEED_FILENAME)) {
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+            res => res.map_err(From::from),
+        }
+    }
+}
+
+/// Flushes a family every interval while it is being snapshotted, so files read so far are
+/// stored and found unchanged by the next run. Stops when dropped.
+pub struct Checkpointer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Checkpointer {
+    fn start<B: StoreBackend>(
+        family: Family<B>,
+        backend: Arc<B>,
+        interval: Duration,
+        progress: Arc<SnapshotProgress>,
+    ) -> Checkpointer {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // The hashes of the blobs are committed once the backend has stored them.
+                let flushed = family.flush().and_then(|()| Ok(backend.flush()?));
+                match flushed {
+                    Ok(()) => println!("Checkpoint: {:.1}% done", progress.percent()),
+                    Err(e) => eprintln!("Checkpoint failed: {}", e),
+                }
+            }
+        });
+
+        Checkpointer {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Checkpoint `family` every `interval` until the returned checkpointer is dropped.
+    pub fn start_checkpoints(
+        &self,
+        family: &Family<B>,
+        interval: Duration,
+        progress: Arc<SnapshotProgress>,
+    ) -> Checkpointer {
+        Checkpointer::start(family.clone(), self.backend.clone(), interval, progress)
+    }
+}
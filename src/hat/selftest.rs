@@ -0,0 +1,163 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A round trip through the backend, to check a new installation.
+//!
+//! Sample data is committed to a throwaway family, the blobs the commit stored are read back,
+//! and the snapshot is restored and compared with the sample. The snapshot is then deleted and
+//! its data collected; only the snapshot lists uploaded meanwhile stay in the repository's
+//! history of them.
+
+use backend::StoreBackend;
+use db;
+use errors::HatError;
+use hat::seed::{self, ScanTotals};
+use hat::{concat_filename, Durability, HatRc};
+use rand::{self, Rng};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use util::Deadline;
+
+/// The family the sample data is committed to.
+pub const SELF_TEST_FAMILY: &str = "hat-self-test";
+
+/// What `self_test` committed and checked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// The sample data, which was committed, restored and found unchanged.
+    pub sample: ScanTotals,
+    /// Blobs stored by the commit and read back from the backend.
+    pub verified_blobs: usize,
+    /// Hashes deleted when collecting the sample data again.
+    pub deleted_hashes: u64,
+}
+
+/// Write a few files of fresh random data below `dir`: one spanning many chunks, a copy of it,
+/// a small one, an empty one and one in a subdirectory.
+fn write_sample(dir: &Path) -> Result<(), io::Error> {
+    let mut large = vec![0u8; 3 * 1024 * 1024 + 17];
+    rand::thread_rng().fill(&mut large[..]);
+    let mut small = vec![0u8; 1000];
+    rand::thread_rng().fill(&mut small[..]);
+
+    fs::create_dir_all(dir.join("dir"))?;
+    fs::write(dir.join("large"), &large)?;
+    fs::write(dir.join("large-copy"), &large)?;
+    fs::write(dir.join("empty"), b"")?;
+    fs::write(dir.join("dir").join("small"), &small)?;
+    Ok(())
+}
+
+/// Check that the tree at `restored` has the same files and directories as the one at
+/// `sample`, with the same contents.
+fn compare_trees(sample: &Path, restored: &Path) -> Result<(), String> {
+    let names = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+        let mut names: Vec<PathBuf> = fs::read_dir(dir)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.file_name().into())).collect())
+            .map_err(|e| format!("could not list {}: {}", dir.display(), e))?;
+        names.sort();
+        Ok(names)
+    };
+    let (expected, found) = (names(sample)?, names(restored)?);
+    if expected != found {
+        return Err(format!(
+            "{} holds {:?} instead of {:?}",
+            restored.display(),
+            found,
+            expected
+        ));
+    }
+    for name in expected {
+        let (sample, restored) = (sample.join(&name), restored.join(&name));
+        if sample.is_dir() {
+            compare_trees(&sample, &restored)?;
+        } else if fs::read(&sample).ok() != fs::read(&restored).ok() {
+            return Err(format!("{} differs from the sample", restored.display()));
+        }
+    }
+    Ok(())
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Commit sample data to `SELF_TEST_FAMILY`, verify the blobs stored for it, restore it and
+    /// compare it with the sample, then delete the snapshot and collect its data. `work_dir` is
+    /// where the sample is written and restored.
+    ///
+    /// Deleting needs a working backend, so a failed self-test leaves its snapshot for the next
+    /// one to delete.
+    pub fn self_test(&mut self, work_dir: &Path) -> Result<SelfTestReport, HatError> {
+        let mut report = self.self_test_round_trip(work_dir).map_err(|e| {
+            format!(
+                "{}; the next self-test deletes what this one left in family {}",
+                e, SELF_TEST_FAMILY
+            )
+        })?;
+        report.deleted_hashes = self.remove_self_test_family()
+            .map_err(|e| format!("Cleaning up failed: {}", e))?;
+        Ok(report)
+    }
+
+    fn self_test_round_trip(&mut self, work_dir: &Path) -> Result<SelfTestReport, HatError> {
+        let sample = work_dir.join("sample");
+        let restored = work_dir.join("restored");
+        write_sample(&sample).map_err(|e| format!("Writing sample data failed: {}", e))?;
+
+        let after_id = self.last_blob_id();
+        let mut family = self.open_family(SELF_TEST_FAMILY.to_owned())?;
+        family.snapshot_dir(sample.clone());
+        family
+            .flush()
+            .and_then(|()| self.commit(&mut family, None))
+            .map_err(|e| format!("Commit failed: {}", e))?;
+        let verified_blobs = self.flush_with_durability(Durability::Verified, after_id)
+            .map_err(|e| format!("Uploading and verifying failed: {}", e))?;
+
+        self.checkout_in_dir(SELF_TEST_FAMILY.to_owned(), restored.clone())
+            .map_err(|e| format!("Restore failed: {}", e))?;
+        // Snapshots hold the absolute paths of what was committed.
+        let absolute = fs::canonicalize(&sample)?;
+        let restored = restored.join(absolute.strip_prefix("/").unwrap_or(&absolute));
+        compare_trees(&sample, &restored).map_err(|e| format!("Restored data differs: {}", e))?;
+
+        Ok(SelfTestReport {
+            sample: seed::scan(&[sample]),
+            verified_blobs: verified_blobs,
+            deleted_hashes: 0,
+        })
+    }
+
+    /// Delete the snapshots of `SELF_TEST_FAMILY`, including any an earlier self-test left
+    /// behind, collect their data and forget the family. Returns the number of hashes deleted.
+    fn remove_self_test_family(&mut self) -> Result<u64, HatError> {
+        let family = self.open_family(SELF_TEST_FAMILY.to_owned())?;
+        for snapshot in self.snapshot_index.list_all() {
+            let complete = matches!(snapshot.status, db::SnapshotWorkStatus::CommitComplete);
+            if snapshot.family_name == SELF_TEST_FAMILY && complete {
+                self.deregister(&family, snapshot.info.snapshot_id)?;
+            }
+        }
+        self.meta_commit_and_flush()?;
+        let summary = self.gc_until(Deadline::none())?;
+
+        self.families.retain(|f| f.name != SELF_TEST_FAMILY);
+        if let Some(ref root) = self.repository_root {
+            match fs::remove_file(concat_filename(root.clone(), SELF_TEST_FAMILY)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+        Ok(summary.deleted_hashes)
+    }
+}
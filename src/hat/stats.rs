@@ -0,0 +1,235 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The size of the repository over time, recorded in the state directory after each commit and
+//! GC, to plan capacity without external monitoring.
+//!
+//! Each record is a line of the history file: time, command, bytes of storage used, number of
+//! blobs, and the size of the latest snapshot of each family as `family=bytes`.
+
+use backend::StoreBackend;
+use chrono;
+use db;
+use errors::HatError;
+use hat::HatRc;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Name of the statistics history in the state directory.
+pub const STATS_HISTORY_FILENAME: &str = "stats-history";
+
+/// The history is moved aside to `<history>.1` when it grows beyond this size.
+const MAX_HISTORY_BYTES: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsRecord {
+    pub ts_utc: i64,
+    /// The command after which the record was taken.
+    pub command: String,
+    /// Bytes of storage used by blobs.
+    pub storage_bytes: u64,
+    pub blobs: u64,
+    /// Total size of the files in the latest snapshot of each family, by family name. `None`
+    /// for snapshots committed before sizes were recorded.
+    pub families: BTreeMap<String, Option<u64>>,
+}
+
+/// Escape the characters that separate fields and values.
+fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        match c {
+            '%' | '=' | ' ' | '\t' | '\n' => escaped.push_str(&format!("%{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2)?;
+            bytes.push(u8::from_str_radix(::std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl StatsRecord {
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} {} {} {}",
+            self.ts_utc,
+            escape(&self.command),
+            self.storage_bytes,
+            self.blobs
+        );
+        for (name, bytes) in &self.families {
+            match *bytes {
+                Some(bytes) => line.push_str(&format!(" {}={}", escape(name), bytes)),
+                None => line.push_str(&format!(" {}=?", escape(name))),
+            }
+        }
+        line
+    }
+
+    /// Parse a line written by `to_line`; `None` if it is malformed.
+    pub fn parse(line: &str) -> Option<StatsRecord> {
+        let mut fields = line.split_whitespace();
+        let mut record = StatsRecord {
+            ts_utc: fields.next()?.parse().ok()?,
+            command: unescape(fields.next()?)?,
+            storage_bytes: fields.next()?.parse().ok()?,
+            blobs: fields.next()?.parse().ok()?,
+            families: BTreeMap::new(),
+        };
+        for field in fields {
+            let mut parts = field.splitn(2, '=');
+            let name = unescape(parts.next()?)?;
+            let bytes = match parts.next()? {
+                "?" => None,
+                bytes => Some(bytes.parse().ok()?),
+            };
+            record.families.insert(name, bytes);
+        }
+        Some(record)
+    }
+}
+
+/// Add `record` to the history in `state_dir`.
+pub fn append(state_dir: &Path, record: &StatsRecord) -> Result<(), HatError> {
+    let path = state_dir.join(STATS_HISTORY_FILENAME);
+    if fs::metadata(&path).map(|m| m.len() > MAX_HISTORY_BYTES).unwrap_or(false) {
+        let mut old = path.clone().into_os_string();
+        old.push(".1");
+        fs::rename(&path, old)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    writeln!(file, "{}", record.to_line())?;
+    Ok(())
+}
+
+/// The history in `state_dir`, oldest first. Malformed lines, e.g. from an interrupted write,
+/// are left out.
+pub fn load(state_dir: &Path) -> Result<Vec<StatsRecord>, HatError> {
+    let path = state_dir.join(STATS_HISTORY_FILENAME);
+    let mut old = path.clone().into_os_string();
+    old.push(".1");
+
+    let mut records = vec![];
+    for path in &[Path::new(&old), &path] {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        records.extend(text.lines().filter_map(StatsRecord::parse));
+    }
+    records.sort_by_key(|r| r.ts_utc);
+    Ok(records)
+}
+
+/// The last record of each UTC day.
+pub fn daily(records: &[StatsRecord]) -> Vec<&StatsRecord> {
+    let mut days: Vec<&StatsRecord> = vec![];
+    for record in records {
+        let day = record.ts_utc.div_euclid(24 * 3600);
+        match days.last_mut() {
+            Some(last) if last.ts_utc.div_euclid(24 * 3600) == day => *last = record,
+            _ => days.push(record),
+        }
+    }
+    days
+}
+
+/// Average growth of the storage used, in bytes per day, over the history. `None` if it spans
+/// less than a day.
+pub fn growth_per_day(records: &[StatsRecord]) -> Option<i64> {
+    let (first, last) = (records.first()?, records.last()?);
+    let secs = last.ts_utc - first.ts_utc;
+    if secs < 24 * 3600 {
+        return None;
+    }
+    let growth = last.storage_bytes as i128 - first.storage_bytes as i128;
+    Some((growth * 24 * 3600 / secs as i128) as i64)
+}
+
+/// Days until the storage used reaches `quota` at `growth` bytes per day, from `usage` bytes.
+/// `None` if it does not grow.
+pub fn days_until_quota(usage: u64, quota: u64, growth: i64) -> Option<u64> {
+    if growth <= 0 {
+        return None;
+    }
+    Some(quota.saturating_sub(usage) / growth as u64)
+}
+
+/// One line per record, with a bar of `width` characters for the largest storage used.
+pub fn plot(records: &[&StatsRecord], width: usize) -> Vec<String> {
+    let max = records.iter().map(|r| r.storage_bytes).max().unwrap_or(0).max(1);
+    records
+        .iter()
+        .map(|r| {
+            let bar = (r.storage_bytes as u128 * width as u128 / max as u128) as usize;
+            let date = chrono::NaiveDateTime::from_timestamp(r.ts_utc, 0).date();
+            format!(
+                "{}  {:>14} bytes  {:>7} blobs  {}",
+                date,
+                r.storage_bytes,
+                r.blobs,
+                "#".repeat(bar)
+            )
+        })
+        .collect()
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// The current size of the repository, to record after `command`.
+    pub fn stats_record(&mut self, command: &str) -> StatsRecord {
+        let mut families = BTreeMap::new();
+        let mut latest = BTreeMap::new();
+        for snapshot in self.list_snapshots() {
+            let complete = matches!(snapshot.status, db::SnapshotWorkStatus::CommitComplete);
+            if snapshot.is_internal() || !complete {
+                continue;
+            }
+            let id = snapshot.info.snapshot_id;
+            match latest.get(&snapshot.family_name) {
+                Some(&newest) if newest > id => continue,
+                _ => (),
+            }
+            latest.insert(snapshot.family_name.clone(), id);
+            let bytes = snapshot.manifest.and_then(|m| m.file_bytes);
+            families.insert(snapshot.family_name, bytes);
+        }
+        StatsRecord {
+            ts_utc: chrono::Utc::now().timestamp(),
+            command: command.to_owned(),
+            storage_bytes: self.storage_usage(),
+            blobs: self.blob_store.blob_count(),
+            families: families,
+        }
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keeps the local indexes coherent with a backend that other clients may change.
+//!
+//! Each change to the backend advances its generation (see `backend::shared`), and the state
+//! directory remembers the generation it last saw. Commits and GC refuse to run when the two
+//! differ, as they would act on a stale view: deduplicate against blobs that are gone, number
+//! new blobs like ones another client wrote, or collect data they do not know is in use.
+//! `sync_index` reconciles the indexes with the backend.
+
+use backend::{shared, StoreBackend};
+use errors::HatError;
+use hat::HatRc;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::process;
+use tags;
+
+/// Name of the file in the local cache that holds the generation of the backend it last saw.
+pub const GENERATION_FILENAME: &str = "backend-generation";
+
+/// The generation recorded in the cache directory `dir`, if any. A file that can not be read
+/// counts as never synced, so `check_synced` reports it and `sync_index` writes it anew.
+pub fn load_generation(dir: &Path) -> Option<u64> {
+    match fs::read_to_string(dir.join(GENERATION_FILENAME)) {
+        Ok(text) => match text.trim().parse() {
+            Ok(generation) => Some(generation),
+            Err(_) => {
+                warn!("Ignoring invalid {}: {:?}", GENERATION_FILENAME, text);
+                None
+            }
+        },
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("Ignoring unreadable {}: {}", GENERATION_FILENAME, e);
+            None
+        }
+    }
+}
+
+/// What `sync_index` changed in the local indexes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// The generation of the backend the indexes are now synced with.
+    pub generation: u64,
+    /// Blobs the backend stores that the blob index did not know of.
+    pub imported_blobs: usize,
+    /// Blobs in the blob index that the backend no longer stores.
+    pub missing_blobs: usize,
+    /// Hashes forgotten because their data, or data below them, was in a missing blob.
+    pub dropped_hashes: usize,
+    /// Snapshots, as `family/id`, whose data is no longer all in the backend.
+    pub damaged_snapshots: Vec<String>,
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Remember that the local indexes reflect generation `generation` of the backend.
+    pub fn set_synced_generation(&mut self, generation: u64) -> Result<(), HatError> {
+        if let Some(ref root) = self.repository_root {
+            // Replace the file atomically, so a crash never leaves a partial generation behind.
+            let path = root.join(GENERATION_FILENAME);
+            let tmp = path.with_extension(format!("tmp-{}", process::id()));
+            fs::write(&tmp, format!("{}\n", generation))?;
+            fs::rename(&tmp, &path)?;
+        }
+        self.synced_generation = Some(generation);
+        Ok(())
+    }
+
+    /// Fail if another client changed the backend since the local indexes were synced with it.
+    pub fn check_synced(&self) -> Result<(), HatError> {
+        let generation = shared::read_generation(&*self.backend)?;
+        self.check_synced_with(generation)
+    }
+
+    /// Like `check_synced`, for a backend at generation `generation`.
+    pub fn check_synced_with(&self, generation: u64) -> Result<(), HatError> {
+        let synced = self.synced_generation.unwrap_or(0);
+        if generation == synced {
+            return Ok(());
+        }
+        Err(format!(
+            "The backend was changed by another client since the local indexes were synced \
+             with it (generation {} here, {} in the backend); run `hat sync-index` first",
+            synced, generation
+        ).into())
+    }
+
+    /// Count a change this state directory made to a backend that was at generation `current`.
+    pub fn advance_generation(&mut self, current: u64) -> Result<(), HatError> {
+        let synced = self.synced_generation.unwrap_or(0);
+        let next = cmp::max(current, synced) + 1;
+        shared::write_generation(&*self.backend, next)?;
+        // Another client that changed the backend meanwhile leaves us out of sync.
+        if current == synced {
+            self.set_synced_generation(next)?;
+        }
+        Ok(())
+    }
+
+    /// Reconcile the local indexes with what the backend stores: learn the blobs other clients
+    /// added, and forget the ones they deleted together with every hash whose data depends on
+    /// them, so later commits read and store that data again.
+    pub fn sync_index(&mut self) -> Result<SyncReport, HatError> {
+        // Writers of a shared backend only change it while holding its lock.
+        let locked = self.writer.is_some() && self.lock_backend()?;
+        let res = self.sync_index_unlocked();
+        if locked {
+            self.unlock_backend()?;
+        }
+        let mut report = res?;
+        report.generation = self.synced_generation.unwrap_or(0);
+        Ok(report)
+    }
+
+    fn sync_index_unlocked(&mut self) -> Result<SyncReport, HatError> {
+        let generation = shared::read_generation(&*self.backend)?;
+        self.adopt_key_version()?;
+
+        let listed: BTreeSet<Vec<u8>> = self.backend
+            .list()?
+            .into_iter()
+            .filter(|name| !shared::is_control_name(name))
+            .map(|name| name.into_vec())
+            .collect();
+        let known: BTreeSet<Vec<u8>> = self.blob_index.names().into_iter().collect();
+        let imported_blobs = listed.difference(&known).count();
+
+        let missing: Vec<_> = self.blob_index
+            .checksums()
+            .into_iter()
+            .map(|(blob, _)| blob)
+            .filter(|blob| !listed.contains(&blob.name))
+            .collect();
+        let missing_names: BTreeSet<&[u8]> = missing.iter().map(|b| &b.name[..]).collect();
+
+        // Drop the hashes stored in missing blobs, and every tree above them.
+        let mut parents: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        for (id, _, childs) in self.hash_index.list_branches() {
+            for child in childs {
+                parents.entry(child).or_default().push(id);
+            }
+        }
+        let mut dropped = BTreeSet::new();
+        let mut pending: Vec<u64> = self.hash_index
+            .list()
+            .into_iter()
+            .filter(|entry| match entry.persistent_ref {
+                Some(ref r) => missing_names.contains(&r.blob_name[..]),
+                None => false,
+            })
+            .filter_map(|entry| self.hash_index.get_id(&entry.hash))
+            .collect();
+        while let Some(id) = pending.pop() {
+            if dropped.insert(id) {
+                pending.extend(parents.get(&id).into_iter().flatten());
+            }
+        }
+
+        let mut damaged_snapshots = vec![];
+        for snapshot in self.snapshot_index.list_all() {
+            let id = snapshot
+                .hash
+                .as_ref()
+                .and_then(|hash| self.hash_index.get_id(hash));
+            if id.is_some_and(|id| dropped.contains(&id)) && !snapshot.is_internal() {
+                damaged_snapshots.push(format!(
+                    "{}/{}",
+                    snapshot.family_name, snapshot.info.snapshot_id
+                ));
+            }
+        }
+
+        for &id in &dropped {
+            self.hash_index.delete(id);
+        }
+        self.hash_index.flush();
+        for blob in &missing {
+            self.blob_index.tag(blob, tags::Tag::DeleteComplete);
+        }
+        self.blob_index.delete_by_tag(tags::Tag::DeleteComplete);
+
+        // New blobs must not take the names of the ones other clients stored.
+        self.blob_store.recover()?;
+        self.blob_index.flush();
+        self.set_synced_generation(generation)?;
+
+        Ok(SyncReport {
+            generation: generation,
+            imported_blobs: imported_blobs,
+            missing_blobs: missing.len(),
+            dropped_hashes: dropped.len(),
+            damaged_snapshots: damaged_snapshots,
+        })
+    }
+}
@@ -12,14 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use backend::{MemoryBackend, StoreBackend};
+use backend::{shared, LayeredBackend, MemoryBackend, StoreBackend};
+use blob;
+use crypto::{self, CipherText};
 use errors::HatError;
+use hat::content_filter::{CommandFilter, ContentFilter, FilterDecision};
 use hat::family::Family;
-use hat::HatRc;
+use hat::{self, acl, diff, owners, retention, walker, Durability, HatRc, ResumeAction, ResumeWork};
+use hash;
 use key;
+use models;
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
 use std::sync::Arc;
-use util::FileIterator;
+use std::thread;
+use std::time::Duration;
+use util::{self, Deadline, FileIterator, Preemption};
 
 pub fn setup_hat<B: StoreBackend>(backend: Arc<B>) -> HatRc<B> {
     let max_blob_size = 4 * 1024 * 1024;
@@ -123,6 +137,75 @@ fn snapshot_commit() {
     let (deleted, live) = hat.gc().unwrap();
     assert_eq!(deleted, 0);
     assert!(live > 0);
+
+    let (checked, failures) = hat.verify_blobs();
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+}
+
+#[test]
+fn quota_check() {
+    let (backend, mut hat, mut fam) = setup_family();
+    hat.check_quota().unwrap();
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Usage is accounted from the blob index; blobs all have the maximum size.
+    let usage = hat.storage_usage();
+    assert_eq!(usage, backend.list().unwrap().len() as u64 * 4 * 1024 * 1024);
+
+    hat.set_quota(Some(usage));
+    assert!(hat.check_quota().is_err());
+    hat.set_quota(Some(usage + 4 * 1024 * 1024));
+    hat.check_quota().unwrap();
+    hat.set_quota(None);
+    hat.check_quota().unwrap();
+}
+
+#[test]
+fn snapshot_preempted() {
+    let (_, hat, fam) = setup_family();
+    let preemption = Preemption::new();
+    let has_data = |fam: &Family<MemoryBackend>| {
+        let mut files: Vec<_> = fam.list_from_key_store(None)
+            .unwrap()
+            .into_iter()
+            .map(|(e, hash_ref, _)| (e.info.name.utf8().to_owned(), hash_ref.is_some()))
+            .collect();
+        files.sort();
+        files
+    };
+
+    // A file whose reading was preempted is kept without data, so it is read again later.
+    let big = FileIterator::from_bytes(vec![1; 1000000]).preemptible(preemption.clone());
+    preemption.request();
+    fam.snapshot_direct(entry("big".to_string()), false, Some(big))
+        .unwrap();
+    fam.snapshot_direct(
+        entry("small".to_string()),
+        false,
+        Some(FileIterator::from_bytes(vec![2; 10])),
+    ).unwrap();
+    let before = vec![("big".to_string(), false), ("small".to_string(), true)];
+    assert_eq!(has_data(&fam), before);
+
+    // A preempted directory snapshot stops before visiting any path.
+    let dir = env::temp_dir().join(format!("hat-preempt-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("file"), "contents").unwrap();
+    assert!(!fam.snapshot_dir_preemptible(dir.clone(), preemption));
+    assert_eq!(has_data(&fam), before);
+
+    assert!(fam.snapshot_dir_preemptible(dir.clone(), Preemption::new()));
+    assert_eq!(has_data(&fam).len(), 3);
+
+    fam.flush().unwrap();
+    hat.data_flush().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
 }
 
 #[test]
@@ -181,6 +264,446 @@ fn snapshot_commit_many_empty_directories() {
     assert_eq!(live, 0);
 }
 
+#[test]
+fn compact_snapshots_keeps_last_of_chain() {
+    let (_, mut hat, mut fam) = setup_family();
+    for i in 0..4u8 {
+        snapshot_files(&fam, vec![("file", vec![i; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+    let (deleted, _) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+
+    assert!(hat.compact_snapshots("familyname", 3, 2).is_err());
+    assert!(hat.compact_snapshots("familyname", 2, 5).is_err());
+    assert_eq!(hat.compact_snapshots("familyname", 1, 3).unwrap(), vec![1, 2]);
+
+    let mut ids: Vec<u64> = hat.list_snapshots()
+        .into_iter()
+        .filter(|s| s.family_name == "familyname")
+        .map(|s| s.info.snapshot_id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![3, 4]);
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert!(live > 0);
+}
+
+#[test]
+fn deleting_snapshots_reuses_cached_trees() {
+    let (_, mut hat, mut fam) = setup_family();
+    for _ in 0..2 {
+        snapshot_files(&fam, vec![("file", vec![1; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit_and_flush().unwrap();
+
+    let dir = env::temp_dir().join(format!("hat-chunk-cache-delete-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    hat.set_chunk_cache(Some(Box::new(hat::ChunkCache::new(dir.clone()))));
+
+    // Both snapshots have the same tree, so the second delete finds it cached.
+    hat.deregister(&fam, 1).unwrap();
+    assert_eq!(hat.retrieve_metrics().cache_hits, 0);
+    assert!(fs::read_dir(&dir).unwrap().count() > 0);
+    hat.deregister(&fam, 2).unwrap();
+    assert!(hat.retrieve_metrics().cache_hits > 0);
+    assert_eq!(hat.retrieve_metrics().failures(), 0);
+
+    hat.meta_commit_and_flush().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deleting_snapshots_reuses_trees_kept_in_memory() {
+    let (_, mut hat, mut fam) = setup_family();
+    for _ in 0..2 {
+        snapshot_files(&fam, vec![("file", vec![2; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit_and_flush().unwrap();
+
+    hat.set_chunk_cache(Some(Box::new(hat::MemoryChunkStore::new(100))));
+    hat.deregister(&fam, 1).unwrap();
+    assert_eq!(hat.retrieve_metrics().cache_hits, 0);
+    hat.deregister(&fam, 2).unwrap();
+    assert!(hat.retrieve_metrics().cache_hits > 0);
+    assert_eq!(hat.retrieve_metrics().failures(), 0);
+    hat.meta_commit_and_flush().unwrap();
+}
+
+#[test]
+fn chains_by_month_keeps_last_of_each_month() {
+    use chrono::{TimeZone, Utc};
+
+    let day = |m, d| Utc.ymd(2018, m, d).and_hms(12, 0, 0);
+    let snapshots = [
+        (1, day(1, 3)),
+        (2, day(1, 17)),
+        (3, day(1, 30)),
+        (4, day(2, 14)),
+        (5, day(3, 1)),
+        (6, day(3, 2)),
+        (7, day(3, 20)),
+    ];
+    assert_eq!(hat::chains_by_month(&snapshots, day(3, 10)), vec![(1, 3), (5, 6)]);
+    assert_eq!(hat::chains_by_month(&snapshots, day(1, 1)), vec![]);
+}
+
+#[test]
+fn retention_keeps_last_of_each_period() {
+    use chrono::{TimeZone, Utc};
+
+    let day = |m, d, h| Utc.ymd(2018, m, d).and_hms(h, 0, 0);
+    let snapshots = [
+        (1, day(1, 3, 12)),
+        (2, day(1, 30, 12)),
+        (3, day(2, 14, 12)),
+        (4, day(3, 5, 12)), // Monday
+        (5, day(3, 7, 9)),
+        (6, day(3, 7, 18)),
+        (7, day(3, 12, 12)), // Monday
+        (8, day(3, 13, 12)),
+    ];
+    let select = |policy| retention::select(&policy, &snapshots);
+
+    let last = retention::Policy { keep_last: 3, ..Default::default() };
+    assert_eq!(select(last), vec![1, 2, 3, 4, 5]);
+    let daily = retention::Policy { keep_daily: 3, ..Default::default() };
+    assert_eq!(select(daily), vec![1, 2, 3, 4, 5]);
+    let weekly = retention::Policy { keep_weekly: 2, ..Default::default() };
+    assert_eq!(select(weekly), vec![1, 2, 3, 4, 5, 7]);
+    let monthly = retention::Policy { keep_monthly: 2, ..Default::default() };
+    assert_eq!(select(monthly), vec![1, 2, 4, 5, 6, 7]);
+    let yearly = retention::Policy { keep_yearly: 5, ..Default::default() };
+    assert_eq!(select(yearly), vec![1, 2, 3, 4, 5, 6, 7]);
+
+    // Rules add up; a snapshot kept by one of them is kept.
+    let combined = retention::Policy {
+        keep_daily: 2,
+        keep_monthly: 3,
+        ..Default::default()
+    };
+    assert_eq!(select(combined), vec![1, 4, 5, 6]);
+    assert_eq!(select(Default::default()), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    assert!(retention::Policy::default().is_empty());
+}
+
+#[test]
+fn prune_deletes_snapshots_the_policy_does_not_keep() {
+    let (_, mut hat, mut fam) = setup_family();
+    for i in 0..4u8 {
+        snapshot_files(&fam, vec![("file", vec![i; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+    }
+    hat.meta_commit().unwrap();
+
+    assert!(hat.prune("familyname", &Default::default()).is_err());
+    let policy = retention::Policy { keep_last: 2, ..Default::default() };
+    assert_eq!(hat.prune_candidates("familyname", &policy), vec![1, 2]);
+    assert_eq!(hat.prune("familyname", &policy).unwrap(), vec![1, 2]);
+    assert_eq!(hat.prune("familyname", &policy).unwrap(), Vec::<u64>::new());
+
+    let mut ids: Vec<u64> = hat.list_snapshots()
+        .into_iter()
+        .filter(|s| s.family_name == "familyname")
+        .map(|s| s.info.snapshot_id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec![3, 4]);
+    let (deleted, live) = hat.gc().unwrap();
+    assert!(deleted > 0);
+    assert!(live > 0);
+}
+
+#[test]
+fn rotate_keys_keeps_older_snapshots_readable() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 100])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    assert_eq!(hat.rotate_keys().unwrap(), 2);
+    assert_eq!(hat.key_version(), 2);
+    snapshot_files(&fam, vec![("a", vec![1; 100]), ("b", vec![2; 5000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    let versions: Vec<_> = hat.key_usage().into_iter().map(|u| u.key_version).collect();
+    assert_eq!(versions, vec![Some(1), Some(2)]);
+
+    // A fresh state directory picks up the new version from the backend.
+    let mut fresh = setup_hat(backend);
+    assert_eq!(fresh.key_version(), 1);
+    fresh.recover_for_reading().unwrap();
+    assert_eq!(fresh.key_version(), 2);
+
+    let dir = env::temp_dir().join(format!("hat-rotate-keys-{}", process::id()));
+    for &(id, files) in &[(1, 1), (2, 2)] {
+        let _ = fs::remove_dir_all(&dir);
+        fresh.checkout_snapshot_in_dir("familyname".to_owned(), id, dir.clone()).unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), vec![1; 100]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), files);
+    }
+    assert_eq!(fs::read(dir.join("b")).unwrap(), vec![2; 5000]);
+    assert_eq!(fresh.retrieve_metrics().failures(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reseal_oldest_blobs_after_rotating_keys() {
+    let (_backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 100]), ("b", vec![2; 5000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    assert_eq!(hat.rotate_keys().unwrap(), 2);
+
+    // The blobs under the old keys are sealed again a few at a time, until none are left.
+    assert_eq!(hat.reseal_oldest_blobs(1).unwrap(), 1);
+    while hat.reseal_oldest_blobs(1).unwrap() > 0 {}
+    let versions: Vec<_> = hat.key_usage().into_iter().map(|u| u.key_version).collect();
+    assert_eq!(versions, vec![Some(2)]);
+
+    let (checked, failures) = hat.verify_blobs();
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+}
+
+#[test]
+fn rotate_keys_reseals_snapshot_lists() {
+    let (backend, mut hat, mut fam) = setup_family();
+    for _ in 0..2 {
+        snapshot_files(&fam, vec![("a", vec![1; 100])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+    }
+
+    assert_eq!(hat.rotate_keys().unwrap(), 2);
+    // Every snapshot list, old or new, is sealed with the new keys now.
+    assert_eq!(hat.reseal_snapshot_lists().unwrap(), 0);
+
+    let mut fresh = setup_hat(backend);
+    fresh.recover_for_reading().unwrap();
+    let ids: Vec<_> = fresh
+        .list_snapshots()
+        .into_iter()
+        .filter(|s| s.family_name == "familyname")
+        .map(|s| s.info.snapshot_id)
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+#[test]
+fn fetch_dir_data_streams_entries() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    let names: Vec<String> = (0..3000).map(|i| format!("name-{}", i)).collect();
+    snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let (_, _, top_ref) = hat.snapshot_index.latest("familyname").unwrap();
+    let listing =
+        Family::<MemoryBackend>::fetch_dir_data(top_ref.unwrap(), hat.hash_backend()).unwrap();
+
+    // The listing spans many leaves; every entry comes out once.
+    let mut seen: Vec<String> = listing
+        .map(|res| res.unwrap().0.info.name.utf8().to_owned())
+        .collect();
+    seen.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(seen, expected);
+}
+
+/// Names ids `u<id>` and `g<id>`, and finds those names 1000 ids further on, as another system
+/// might.
+struct ShiftedNames;
+
+impl owners::NameResolver for ShiftedNames {
+    fn user_name(&self, uid: u64) -> Option<String> {
+        Some(format!("u{}", uid))
+    }
+    fn group_name(&self, gid: u64) -> Option<String> {
+        Some(format!("g{}", gid))
+    }
+    fn user_id(&self, name: &str) -> Option<u64> {
+        name.strip_prefix('u').and_then(|id| id.parse::<u64>().ok()).map(|id| id + 1000)
+    }
+    fn group_id(&self, name: &str) -> Option<u64> {
+        name.strip_prefix('g').and_then(|id| id.parse::<u64>().ok()).map(|id| id + 1000)
+    }
+}
+
+#[test]
+fn owner_names_are_recorded_and_preferred_on_restore() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = env::temp_dir().join(format!("hat-owners-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let meta = fs::metadata(&dir).unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    fam.names = Arc::new(ShiftedNames);
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // The snapshot holds the directories from the root down; find the one committed.
+    let mut dir_ref = hat.snapshot_index.latest("familyname").unwrap().2.unwrap();
+    let mut info = None;
+    for name in fs::canonicalize(&dir).unwrap().iter().skip(1) {
+        let (entry, content) =
+            Family::<MemoryBackend>::fetch_dir_data(dir_ref, hat.hash_backend())
+                .unwrap()
+                .map(|res| res.unwrap())
+                .find(|(entry, _)| entry.info.name.utf8() == name.to_str().unwrap())
+                .unwrap();
+        match content {
+            walker::Content::Dir(href) => dir_ref = href,
+            other => panic!("unexpected content: {:?}", other),
+        }
+        info = Some(entry.info);
+    }
+    let info = info.unwrap();
+    assert_eq!(info.user_name, Some(format!("u{}", meta.uid())));
+    assert_eq!(info.group_name, Some(format!("g{}", meta.gid())));
+
+    let uid = u64::from(meta.uid());
+    let gid = u64::from(meta.gid());
+    let restore = |info: &key::Info, mapping| owners::restore_ids(&ShiftedNames, mapping, info);
+    assert_eq!(
+        restore(&info, owners::OwnerMapping::ByName),
+        (Some(uid + 1000), Some(gid + 1000))
+    );
+    assert_eq!(restore(&info, owners::OwnerMapping::Numeric), (Some(uid), Some(gid)));
+
+    // Names unknown here, and owners recorded without names, keep their ids.
+    let mut unknown = info.clone();
+    unknown.user_name = Some("nobody-here".to_owned());
+    unknown.group_name = None;
+    assert_eq!(
+        restore(&unknown, owners::OwnerMapping::ByName),
+        (Some(uid), Some(gid))
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unreadable_files_are_recorded_in_snapshots() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("readable", vec![1; 100])]).unwrap();
+    let ks = fam.key_store_process.iter().last().unwrap();
+    let unreadable = Box::new(|()| Err("Permission denied".to_owned()));
+    ks.send_reply(key::Msg::Insert(entry("locked".to_owned()), Some(unreadable)))
+        .unwrap();
+    ks.send_reply(key::Msg::CommitReservedNodes(None)).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let (_, _, top_ref) = hat.snapshot_index.latest("familyname").unwrap();
+    let mut listing: Vec<_> =
+        Family::<MemoryBackend>::fetch_dir_data(top_ref.unwrap(), hat.hash_backend())
+            .unwrap()
+            .map(|res| res.unwrap())
+            .map(|(entry, content)| (entry.info.name.utf8().to_owned(), content))
+            .collect();
+    listing.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(listing.len(), 2);
+    match listing[0] {
+        (ref name, walker::Content::Unreadable(ref error)) => {
+            assert_eq!(name, "locked");
+            assert_eq!(error, "Permission denied");
+        }
+        ref other => panic!("unexpected entry: {:?}", other),
+    }
+    match listing[1].1 {
+        walker::Content::Data(_) => (),
+        ref other => panic!("unexpected content: {:?}", other),
+    }
+}
+
+#[test]
+fn key_store_lists_directory_in_pages() {
+    let (_, _hat, fam) = setup_family();
+
+    let names: Vec<String> = (0..2500).map(|i| format!("name-{}", i)).collect();
+    snapshot_files(&fam, names.iter().map(|n| (n.as_str(), vec![])).collect()).unwrap();
+    fam.flush().unwrap();
+
+    let mut after = None;
+    let mut pages = vec![];
+    let mut seen = vec![];
+    loop {
+        let page = fam.list_page_from_key_store(None, after, 1000).unwrap();
+        if page.is_empty() {
+            break;
+        }
+        pages.push(page.len());
+        for (entry, _, _) in &page {
+            assert!(entry.node_id > after);
+            after = entry.node_id;
+            seen.push(entry.info.name.utf8().to_owned());
+        }
+    }
+    assert_eq!(pages, vec![1000, 1000, 500]);
+    seen.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(seen, expected);
+}
+
+#[test]
+fn hash_reader_works_without_hat() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 5000]), ("b", vec![2; 10])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let (_, _, top_ref) = hat.snapshot_index.latest("familyname").unwrap();
+    let top_ref = top_ref.unwrap();
+    let reader = hat.hash_reader();
+    drop(fam);
+    drop(hat);
+
+    // Clones are independent and can be used from other threads.
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let reader = reader.clone();
+            let top_ref = top_ref.clone();
+            thread::spawn(move || {
+                let mut sizes = vec![];
+                for res in reader.list_dir(top_ref).unwrap() {
+                    if let walker::Content::Data(href) = res.unwrap().1 {
+                        let leaves = reader.get_leaf_iter(href).unwrap().unwrap();
+                        sizes.push(leaves.map(|c| c.len()).sum::<usize>());
+                    }
+                }
+                sizes.sort();
+                sizes
+            })
+        })
+        .collect();
+    for h in handles {
+        assert_eq!(h.join().unwrap(), vec![10, 5000]);
+    }
+}
+
 #[test]
 fn snapshot_reuse_index() {
     let (_, mut hat, mut fam) = setup_family();
@@ -276,3 +799,2472 @@ fn recover() {
     assert!(deleted > 0);
     assert_eq!(live4, 0);
 }
+
+#[test]
+fn commit_durability_levels() {
+    let (backend, mut hat, mut fam) = setup_family();
+    let recovered = |backend: &Arc<MemoryBackend>| {
+        let mut hat = setup_hat(backend.clone());
+        hat.recover().unwrap();
+        let mut ids: Vec<u64> = hat.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == "familyname")
+            .map(|s| s.info.snapshot_id)
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    // Flushed: committed locally, with its data stored, but not yet listed in the backend.
+    snapshot_files(&fam, vec![("a", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let after = hat.last_blob_id();
+    hat.commit(&mut fam, None).unwrap();
+    assert_eq!(hat.flush_with_durability(Durability::Flushed, after).unwrap(), 0);
+    assert!(hat.last_blob_id() > after);
+    assert!(!hat.list_snapshots().iter().any(|s| s.is_internal()));
+
+    // Verified: the new blobs are read back, but not the ones stored before.
+    snapshot_files(&fam, vec![("b", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    let after = hat.last_blob_id();
+    hat.commit(&mut fam, None).unwrap();
+    let verified = hat.flush_with_durability(Durability::Verified, after).unwrap();
+    assert!(verified > 0);
+    assert!(verified < hat.verify_blobs().0);
+    assert_eq!(recovered(&backend), vec![1, 2]);
+
+    assert_eq!(Durability::parse("uploaded"), Ok(Durability::default()));
+    assert!(Durability::parse("synced").is_err());
+}
+
+#[test]
+fn commit_reuses_data_of_other_families() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    let mut first = hat.open_family("first".to_owned()).unwrap();
+    snapshot_files(&first, vec![("a", data.clone()), ("b", vec![2; 1000])]).unwrap();
+    first.flush().unwrap();
+    hat.commit(&mut first, None).unwrap();
+    let before = hat.store_metrics();
+    assert_eq!(before.bytes_stored, 301000);
+    assert_eq!(before.bytes_reused, 0);
+    assert_eq!(before.bytes_shared, 0);
+
+    // The same data under a new family is found through the shared hash index.
+    let mut second = hat.open_family("second".to_owned()).unwrap();
+    snapshot_files(&second, vec![("copy/a", data), ("c", vec![3; 10])]).unwrap();
+    second.flush().unwrap();
+    hat.commit(&mut second, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let added = hat.store_metrics().since(&before);
+    assert_eq!(added.bytes_stored, 10);
+    assert_eq!(added.bytes_reused, 300000);
+    assert_eq!(added.chunks_reused, 3);
+    assert_eq!(added.bytes_shared, 300000);
+    assert_eq!(added.chunks_shared, 3);
+}
+
+#[test]
+fn commit_does_not_count_data_of_own_family_as_shared() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let data: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    let mut fam = hat.open_family("first".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("a", data.clone())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    let before = hat.store_metrics();
+
+    // The family already stored this data itself: reused, but not from another family.
+    let mut fam = hat.open_family("first".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("again", data)]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let added = hat.store_metrics().since(&before);
+    assert_eq!(added.bytes_stored, 0);
+    assert_eq!(added.bytes_reused, 300000);
+    assert_eq!(added.chunks_shared, 0);
+    assert_eq!(added.bytes_shared, 0);
+}
+
+#[test]
+fn snapshot_manifest_survives_recover() {
+    let dir = env::temp_dir().join(format!("hat-manifest-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("file"), b"contents").unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |snapshots: Vec<::db::SnapshotStatus>| {
+        let s = snapshots
+            .into_iter()
+            .find(|s| s.family_name == "familyname")
+            .unwrap();
+        let manifest = s.manifest.expect("snapshot without manifest");
+        assert_eq!(
+            manifest.source_paths,
+            vec![fs::canonicalize(&dir).unwrap().to_string_lossy().into_owned()]
+        );
+        assert_eq!(manifest.hat_version, env!("CARGO_PKG_VERSION"));
+        assert!(!manifest.hostname.is_empty());
+        assert!(manifest.settings.iter().any(|(name, _)| name == "chunking"));
+    };
+    check(hat.list_snapshots());
+
+    // The manifest is part of the snapshot list in the backend.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(hat2.list_snapshots());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn annotations_are_appended_and_survive_recover() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let good = models::SnapshotAnnotation {
+        created_ts_utc: 1500000000,
+        tags: vec!["known-good".to_owned()],
+        ..models::SnapshotAnnotation::default()
+    };
+    let incident = models::SnapshotAnnotation {
+        created_ts_utc: 1500000100,
+        message: Some("last snapshot before the incident".to_owned()),
+        meta: vec![("ticket".to_owned(), "OPS-12".to_owned())],
+        ..models::SnapshotAnnotation::default()
+    };
+    hat.annotate("familyname", 1, good.clone()).unwrap();
+    hat.annotate("familyname", 1, incident.clone()).unwrap();
+    assert!(hat.annotate("familyname", 2, good.clone()).is_err());
+    assert!(hat.annotate("otherfamily", 1, good.clone()).is_err());
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |snapshots: Vec<::db::SnapshotStatus>| {
+        let s = snapshots
+            .into_iter()
+            .find(|s| s.family_name == "familyname")
+            .unwrap();
+        assert_eq!(s.message(), None);
+        assert_eq!(s.annotations, vec![good.clone(), incident.clone()]);
+    };
+    check(hat.list_snapshots());
+
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(hat2.list_snapshots());
+}
+
+#[test]
+fn commit_message_and_tags_survive_recover() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.set_commit_message(
+        Some("before the upgrade".to_owned()),
+        vec!["pre-upgrade".to_owned(), "weekly".to_owned()],
+    );
+    hat.commit(&mut fam, None).unwrap();
+
+    snapshot_files(&fam, vec![("a", vec![4, 5, 6])]).unwrap();
+    fam.flush().unwrap();
+    hat.set_commit_message(None, vec![]);
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // An annotation tags the second snapshot as well.
+    let annotation = models::SnapshotAnnotation {
+        tags: vec!["weekly".to_owned()],
+        ..models::SnapshotAnnotation::default()
+    };
+    hat.annotate("familyname", 2, annotation).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |hat: &mut HatRc<MemoryBackend>| {
+        let ids = |snapshots: Vec<::db::SnapshotStatus>| -> Vec<u64> {
+            snapshots.iter().map(|s| s.info.snapshot_id).collect()
+        };
+        assert_eq!(ids(hat.list_snapshots_tagged("pre-upgrade")), vec![1]);
+        assert_eq!(ids(hat.list_snapshots_tagged("weekly")), vec![1, 2]);
+        assert!(hat.list_snapshots_tagged("other").is_empty());
+
+        let first = hat.list_snapshots_tagged("pre-upgrade").pop().unwrap();
+        assert_eq!(first.message(), Some("before the upgrade"));
+        assert_eq!(first.tags, vec!["pre-upgrade", "weekly"]);
+        let second = hat.list_snapshots_tagged("weekly").pop().unwrap();
+        assert_eq!(second.message(), None);
+        assert!(second.tags.is_empty());
+    };
+    check(&mut hat);
+
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(&mut hat2);
+}
+
+#[test]
+fn snapshot_from_tar_stream() {
+    let out = env::temp_dir().join(format!("hat-tar-import-{}", process::id()));
+    let _ = fs::remove_dir_all(&out);
+
+    let header = |name, entry_type, mode, size| util::EntryHeader {
+        name: name,
+        entry_type: entry_type,
+        mode: mode,
+        uid: 0,
+        gid: 0,
+        mtime: 1_500_000_000,
+        size: size,
+    };
+    let big: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    let conf = b"port = 80\n";
+    let mut w = util::TarWriter::new(vec![]);
+    w.append_entry(&header(b"./", util::EntryType::Directory, 0o755, 0), io::empty())
+        .unwrap();
+    w.append_entry(&header(b"./data", util::EntryType::Directory, 0o700, 0), io::empty())
+        .unwrap();
+    let dump = header(b"./data/dump.sql", util::EntryType::File, 0o600, big.len() as u64);
+    w.append_entry(&dump, &big[..]).unwrap();
+    // The directories above are not in the archive.
+    let app = header(b"./etc/app/app.conf", util::EntryType::File, 0o644, conf.len() as u64);
+    w.append_entry(&app, &conf[..]).unwrap();
+    let link = util::EntryType::Symlink(b"data/dump.sql");
+    w.append_entry(&header(b"./current", link, 0o777, 0), io::empty())
+        .unwrap();
+    let archive = w.finish().unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    assert_eq!(fam.snapshot_from_tar(io::Cursor::new(archive)).unwrap(), 4);
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    assert_eq!(fs::read(out.join("data/dump.sql")).unwrap(), big);
+    assert_eq!(fs::read(out.join("etc/app/app.conf")).unwrap(), conf);
+    assert_eq!(fs::read_link(out.join("current")).unwrap(), Path::new("data/dump.sql"));
+    let mode = |path: &str| fs::metadata(out.join(path)).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode("data"), 0o700);
+    assert_eq!(mode("data/dump.sql"), 0o600);
+
+    // A later archive, committed as from a new process, replaces the entries of the earlier one.
+    let mut w = util::TarWriter::new(vec![]);
+    w.append_bytes("etc/app/app.conf", b"port = 8080\n").unwrap();
+    let archive = w.finish().unwrap();
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    let mut fam2 = hat2.open_family("familyname".to_owned()).unwrap();
+    assert_eq!(fam2.snapshot_from_tar(io::Cursor::new(archive)).unwrap(), 1);
+    hat2.commit(&mut fam2, None).unwrap();
+    hat2.meta_commit_and_flush().unwrap();
+    let names: Vec<_> = fam2.list_from_key_store(None)
+        .unwrap()
+        .into_iter()
+        .map(|(entry, _, _)| entry.info.name)
+        .collect();
+    assert_eq!(names, vec!["etc".to_owned().into()]);
+
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn checkout_restores_hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = env::temp_dir().join(format!("hat-hard-links-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-hard-links-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a"), vec![5; 300000]).unwrap();
+    fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+    fs::hard_link(dir.join("a"), dir.join("sub").join("c")).unwrap();
+    fs::write(dir.join("single"), vec![5; 300000]).unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |hat: &mut HatRc<MemoryBackend>| {
+        let _ = fs::remove_dir_all(&out);
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+        let meta = |path: &str| fs::metadata(restored.join(path)).unwrap();
+        assert_eq!(meta("a").nlink(), 3);
+        assert_eq!(meta("b").ino(), meta("a").ino());
+        assert_eq!(meta("sub/c").ino(), meta("a").ino());
+        assert_eq!(fs::read(restored.join("sub/c")).unwrap(), vec![5; 300000]);
+        // Files with the same contents are not linked.
+        assert_eq!(meta("single").nlink(), 1);
+    };
+    check(&mut hat);
+
+    // The links are recorded in the snapshot.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(&mut hat2);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn commit_with_progress_reports_events() {
+    use util::ProgressEvent;
+
+    let dir = env::temp_dir().join(format!("hat-progress-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a"), vec![1; 3000]).unwrap();
+    fs::write(dir.join("sub/b"), vec![2; 5000]).unwrap();
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    let log = Arc::new(::std::sync::Mutex::new(vec![]));
+    let recorded = log.clone();
+    let progress = Arc::new(move |event| recorded.lock().unwrap().push(event));
+    hat.commit_with_progress(&mut fam, vec![dir.clone()], progress)
+        .unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let events = log.lock().unwrap().clone();
+    let sum = |f: &Fn(&ProgressEvent) -> Option<u64>| events.iter().filter_map(f).sum::<u64>();
+    let mut scanned: Vec<u64> = events
+        .iter()
+        .filter_map(|e| match *e {
+            ProgressEvent::FileScanned(len) => Some(len),
+            _ => None,
+        })
+        .collect();
+    scanned.sort();
+    assert_eq!(scanned, vec![3000, 5000]);
+    let hashed = sum(&|e| match *e {
+        ProgressEvent::BytesHashed(n) => Some(n),
+        _ => None,
+    });
+    assert_eq!(hashed, 8000);
+    let flushed = sum(&|e| match *e {
+        ProgressEvent::BlobFlushed(n) => Some(n),
+        _ => None,
+    });
+    let uploaded = sum(&|e| match *e {
+        ProgressEvent::BytesUploaded(n) => Some(n),
+        _ => None,
+    });
+    assert!(flushed > 0);
+    assert_eq!(uploaded, flushed);
+
+    // The events stop with the commit.
+    fs::write(dir.join("c"), b"more").unwrap();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    assert_eq!(log.lock().unwrap().len(), events.len());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkout_restores_xattrs() {
+    use util::{read_xattrs, set_xattr};
+
+    let dir = env::temp_dir().join(format!("hat-xattrs-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-xattrs-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("file"), b"contents").unwrap();
+    if set_xattr(&dir.join("file"), b"user.hat.test", b"value").is_err() {
+        // The temporary directory does not support user attributes.
+        fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+    set_xattr(&dir.join("file"), b"user.hat.empty", b"").unwrap();
+    set_xattr(&dir.join("sub"), b"user.hat.dir", &[0, 1, 2]).unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |hat: &mut HatRc<MemoryBackend>| {
+        let _ = fs::remove_dir_all(&out);
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+        let user = |path: &str| -> Vec<(Vec<u8>, Vec<u8>)> {
+            read_xattrs(&restored.join(path))
+                .unwrap()
+                .into_iter()
+                .filter(|(name, _)| name.starts_with(b"user.hat."))
+                .collect()
+        };
+        assert_eq!(
+            user("file"),
+            vec![
+                (b"user.hat.empty".to_vec(), vec![]),
+                (b"user.hat.test".to_vec(), b"value".to_vec()),
+            ]
+        );
+        assert_eq!(user("sub"), vec![(b"user.hat.dir".to_vec(), vec![0, 1, 2])]);
+    };
+    check(&mut hat);
+
+    // The attributes are recorded in the snapshot.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(&mut hat2);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn acl_entries_round_trip() {
+    use models::{AclEntry, AclTag};
+
+    let entries = vec![
+        AclEntry { tag: AclTag::UserObj, perm: 6 },
+        AclEntry { tag: AclTag::User(1234), perm: 4 },
+        AclEntry { tag: AclTag::GroupObj, perm: 4 },
+        AclEntry { tag: AclTag::Group(0), perm: 7 },
+        AclEntry { tag: AclTag::Mask, perm: 7 },
+        AclEntry { tag: AclTag::Other, perm: 0 },
+    ];
+    let value = acl::encode(&entries);
+    assert_eq!(value.len(), 4 + 8 * entries.len());
+    assert_eq!(acl::decode(&value), Some(entries));
+
+    assert_eq!(acl::decode(&value[..value.len() - 1]), None);
+    assert_eq!(acl::decode(&[1, 0, 0, 0]), None);
+}
+
+#[test]
+fn checkout_restores_acls() {
+    use models::{AclEntry, AclTag};
+    use util::{read_xattrs, set_xattr};
+
+    let dir = env::temp_dir().join(format!("hat-acls-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-acls-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("file"), b"contents").unwrap();
+    let entries = vec![
+        AclEntry { tag: AclTag::UserObj, perm: 6 },
+        AclEntry { tag: AclTag::User(1234), perm: 4 },
+        AclEntry { tag: AclTag::GroupObj, perm: 4 },
+        AclEntry { tag: AclTag::Mask, perm: 4 },
+        AclEntry { tag: AclTag::Other, perm: 0 },
+    ];
+    let access = acl::encode(&entries);
+    if set_xattr(&dir.join("file"), b"system.posix_acl_access", &access).is_err() {
+        // The temporary directory does not support ACLs.
+        fs::remove_dir_all(&dir).unwrap();
+        return;
+    }
+    set_xattr(&dir.join("sub"), b"system.posix_acl_default", &access).unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |hat: &mut HatRc<MemoryBackend>| {
+        let _ = fs::remove_dir_all(&out);
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+        let acls = |path: &str| -> Vec<(Vec<u8>, Vec<u8>)> {
+            read_xattrs(&restored.join(path))
+                .unwrap()
+                .into_iter()
+                .filter(|(name, _)| name.starts_with(b"system.posix_acl_"))
+                .collect()
+        };
+        if cfg!(feature = "acl") {
+            let name = |n: &str| n.as_bytes().to_vec();
+            assert_eq!(acls("file"), vec![(name("system.posix_acl_access"), access.clone())]);
+            assert_eq!(acls("sub"), vec![(name("system.posix_acl_default"), access.clone())]);
+        } else {
+            // Only the permissions are restored.
+            assert_eq!(acls("file"), vec![]);
+            assert_eq!(acls("sub"), vec![]);
+        }
+        let mode = fs::metadata(restored.join("file")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    };
+    check(&mut hat);
+
+    // The entries are recorded in the snapshot.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(&mut hat2);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn checkout_restores_special_files() {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::net::UnixListener;
+    use util::make_special_file;
+
+    let dir = env::temp_dir().join(format!("hat-special-files-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-special-files-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(&dir).unwrap();
+    make_special_file(&dir.join("fifo"), libc::S_IFIFO | 0o640, 0).unwrap();
+    let _listener = UnixListener::bind(dir.join("socket")).unwrap();
+    // Only root creates devices, and not in all containers.
+    let rdev = libc::makedev(1, 3);
+    let device = make_special_file(&dir.join("null"), libc::S_IFCHR | 0o600, rdev).is_ok();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let check = |hat: &mut HatRc<MemoryBackend>| {
+        let _ = fs::remove_dir_all(&out);
+        hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+        let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+        let meta = |path: &str| fs::symlink_metadata(restored.join(path)).unwrap();
+        assert!(meta("fifo").file_type().is_fifo());
+        assert_eq!(meta("fifo").permissions().mode() & 0o7777, 0o640);
+        assert!(meta("socket").file_type().is_socket());
+        if device {
+            assert!(meta("null").file_type().is_char_device());
+            assert_eq!(meta("null").rdev(), rdev);
+        }
+    };
+    check(&mut hat);
+
+    // Archives hold the FIFO and the device, but have no kind for the socket.
+    let mut archive = vec![];
+    hat.export_tar("familyname", 1, &mut archive).unwrap();
+    let mut reader = util::TarReader::new(&archive[..]);
+    let mut kinds = HashMap::new();
+    while let Some(entry) = reader.next_entry().unwrap() {
+        let name = entry.name.rsplit(|&b| b == b'/').next().unwrap().to_vec();
+        kinds.insert(name, entry.kind);
+    }
+    assert_eq!(kinds.get(&b"fifo"[..]), Some(&util::TarEntryKind::Other(b'6')));
+    assert!(!kinds.contains_key(&b"socket"[..]));
+    if device {
+        assert_eq!(kinds.get(&b"null"[..]), Some(&util::TarEntryKind::Other(b'3')));
+    }
+
+    // The kinds are recorded in the snapshot.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    check(&mut hat2);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn integrity_manifest_checks_restored_tree() {
+    let dir = env::temp_dir().join(format!("hat-integrity-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-integrity-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("file"), b"contents").unwrap();
+    fs::write(dir.join("sub").join("new\nline"), vec![3; 300000]).unwrap();
+
+    let (backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    let manifest = hat.integrity_manifest("familyname", 1).unwrap();
+    assert_eq!(manifest.split(|&b| b == b'\n').filter(|l| !l.is_empty()).count(), 2);
+
+    // The manifest is recovered along with the snapshot.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    let report = hat2.verify_integrity("familyname", 1, &out).unwrap();
+    assert!(report.is_ok());
+    assert_eq!(report.checked, 2);
+
+    let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+    fs::write(restored.join("file"), b"altered").unwrap();
+    fs::remove_file(restored.join("sub").join("new\nline")).unwrap();
+    let report = hat2.verify_integrity("familyname", 1, &out).unwrap();
+    assert_eq!(report.checked, 0);
+    assert_eq!(report.changed, vec![restored.join("file")]);
+    assert_eq!(report.missing, vec![restored.join("sub").join("new\nline")]);
+
+    // Deleting the snapshot releases the manifest with it.
+    hat2.deregister_by_name("familyname".to_owned(), 1).unwrap();
+    hat2.gc().unwrap();
+    assert!(hat2.integrity_manifest("familyname", 1).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+struct RedactSecrets;
+
+impl ContentFilter for RedactSecrets {
+    fn name(&self) -> String {
+        "redact-secrets".to_owned()
+    }
+
+    fn filter(&self, path: &Path) -> Result<FilterDecision, String> {
+        match path.file_name().and_then(|n| n.to_str()) {
+            Some(".env") => Ok(FilterDecision::Replace(b"REDACTED".to_vec())),
+            Some(name) if name.ends_with(".key") => Ok(FilterDecision::Skip),
+            Some("broken") => Err("cannot decide".to_owned()),
+            _ => Ok(FilterDecision::Keep),
+        }
+    }
+}
+
+#[test]
+fn content_filter_redacts_and_skips_files() {
+    let dir = env::temp_dir().join(format!("hat-filter-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-filter-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(".env"), b"PASSWORD=hunter2").unwrap();
+    fs::write(dir.join("file"), b"contents").unwrap();
+
+    // The first snapshot stores the secret; the filter must replace it though it is unchanged.
+    let (_backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+
+    fs::write(dir.join("id.key"), b"private").unwrap();
+    fs::write(dir.join("broken"), b"unknown").unwrap();
+    fam.content_filter = Some(Arc::new(RedactSecrets));
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+
+    let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+    assert_eq!(fs::read(restored.join(".env")).unwrap(), b"REDACTED");
+    assert_eq!(fs::read(restored.join("file")).unwrap(), b"contents");
+    assert!(!restored.join("id.key").exists());
+    assert!(!restored.join("broken").exists());
+
+    let manifest = hat
+        .list_snapshots()
+        .into_iter()
+        .find(|s| s.info.snapshot_id == 2)
+        .and_then(|s| s.manifest)
+        .unwrap();
+    assert!(
+        manifest
+            .settings
+            .contains(&("content_filter".to_owned(), "redact-secrets".to_owned()))
+    );
+    let mut filtered: Vec<_> = manifest
+        .filtered
+        .iter()
+        .map(|(path, decision)| (Path::new(path).file_name().unwrap().to_owned(), decision))
+        .collect();
+    filtered.sort();
+    assert_eq!(filtered.len(), 3);
+    assert_eq!(filtered[0].0, ".env");
+    assert_eq!(filtered[0].1, "replaced by content filter");
+    assert_eq!(filtered[1].0, "broken");
+    assert!(filtered[1].1.starts_with("skipped, as the content filter failed"));
+    assert_eq!(filtered[2].0, "id.key");
+    assert_eq!(filtered[2].1, "skipped by content filter");
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn excluded_paths_are_left_out() {
+    let dir = env::temp_dir().join(format!("hat-exclude-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::create_dir_all(dir.join("target").join("debug")).unwrap();
+    fs::write(dir.join("src").join("main.c"), b"int main;").unwrap();
+    fs::write(dir.join("src").join("main.o"), b"object").unwrap();
+    fs::write(dir.join("src").join("keep.o"), b"object").unwrap();
+    fs::write(dir.join("target").join("debug").join("hat"), b"binary").unwrap();
+    // Only directories match a pattern ending in a slash.
+    fs::write(dir.join("src").join("target"), b"file").unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_exclude_filter(util::ExcludeFilter::new(&["*.o", "target/"], &["keep.o"]));
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap().to_str().unwrap().to_owned();
+    let below: Vec<_> = snapshot_paths(&mut hat, "familyname")
+        .into_iter()
+        .filter_map(|p| p.strip_prefix(&format!("{}/", root)).map(|p| p.to_owned()))
+        .collect();
+    assert_eq!(below, vec!["src", "src/keep.o", "src/main.c", "src/target"]);
+
+    let manifest = hat
+        .list_snapshots()
+        .into_iter()
+        .find(|s| s.family_name == "familyname")
+        .and_then(|s| s.manifest)
+        .unwrap();
+    assert!(
+        manifest
+            .settings
+            .contains(&("exclude".to_owned(), "-*.o -target/ +keep.o".to_owned()))
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn diff_lists_changed_files_between_snapshots() {
+    let dir = env::temp_dir().join(format!("hat-diff-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("same").join("deep")).unwrap();
+    fs::create_dir_all(dir.join("gone")).unwrap();
+    fs::write(dir.join("same").join("deep").join("file"), b"unchanged").unwrap();
+    fs::write(dir.join("grows"), b"abc").unwrap();
+    fs::write(dir.join("removed"), b"12345").unwrap();
+    fs::write(dir.join("gone").join("file"), b"xy").unwrap();
+    fs::write(dir.join("swap"), b"file").unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let commit = |hat: &mut HatRc<MemoryBackend>| {
+        let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+        fam.snapshot_dir(dir.clone());
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+    };
+    commit(&mut hat);
+
+    fs::write(dir.join("grows"), b"abcdef").unwrap();
+    fs::remove_file(dir.join("removed")).unwrap();
+    fs::remove_dir_all(dir.join("gone")).unwrap();
+    fs::remove_file(dir.join("swap")).unwrap();
+    fs::create_dir_all(dir.join("swap")).unwrap();
+    fs::write(dir.join("swap").join("inner"), b"inner").unwrap();
+    fs::write(dir.join("added"), b"new").unwrap();
+    // Start from an empty family index, as a new process would, so the gone files are dropped.
+    hat.families.clear();
+    commit(&mut hat);
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap().to_owned();
+    let diffs: Vec<_> = hat
+        .diff_snapshots("familyname", 1, 2)
+        .unwrap()
+        .into_iter()
+        .map(|d| {
+            let path = d.path.strip_prefix(&root).unwrap().to_str().unwrap().to_owned();
+            (path, d.change, d.size_delta())
+        })
+        .collect();
+    assert_eq!(
+        diffs,
+        vec![
+            ("added".to_owned(), diff::Change::Added, 3),
+            ("gone/file".to_owned(), diff::Change::Removed, -2),
+            ("grows".to_owned(), diff::Change::Modified, 3),
+            ("removed".to_owned(), diff::Change::Removed, -5),
+            ("swap".to_owned(), diff::Change::Removed, -4),
+            ("swap/inner".to_owned(), diff::Change::Added, 5),
+        ]
+    );
+
+    // Comparing the other way around swaps additions and removals.
+    let back = hat.diff_snapshots("familyname", 2, 1).unwrap();
+    assert_eq!(back.len(), 6);
+    assert_eq!(back[0].change, diff::Change::Removed);
+    assert_eq!(back[2].size_delta(), -3);
+    assert!(hat.diff_snapshots("familyname", 1, 1).unwrap().is_empty());
+    assert!(hat.diff_snapshots("familyname", 1, 3).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn differences_serialize_as_json() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let diffs = vec![
+        diff::SnapshotDifference {
+            path: "dir/grows".into(),
+            change: diff::Change::Modified,
+            old_size: Some(2),
+            new_size: Some(5),
+        },
+        diff::SnapshotDifference {
+            path: Path::new(OsStr::from_bytes(b"bad\xff")).to_path_buf(),
+            change: diff::Change::Added,
+            old_size: None,
+            new_size: Some(1),
+        },
+    ];
+    assert_eq!(
+        ::serde_json::to_string(&diffs).unwrap(),
+        "[{\"path\":\"dir/grows\",\"change\":\"modified\",\"old_size\":2,\"new_size\":5},\
+         {\"path\":\"bad\u{fffd}\",\"change\":\"added\",\"old_size\":null,\"new_size\":1}]"
+    );
+}
+
+/// Appends to files named `growing` when deciding on them, so they change after the snapshot
+/// looked at them and before it reads them.
+struct AppendToGrowing;
+
+impl ContentFilter for AppendToGrowing {
+    fn name(&self) -> String {
+        "append-to-growing".to_owned()
+    }
+
+    fn filter(&self, path: &Path) -> Result<FilterDecision, String> {
+        if path.file_name().and_then(|n| n.to_str()) == Some("growing") {
+            let mut contents = fs::read(path).map_err(|e| e.to_string())?;
+            contents.push(b'+');
+            fs::write(path, contents).map_err(|e| e.to_string())?;
+        }
+        Ok(FilterDecision::Keep)
+    }
+}
+
+#[test]
+fn files_changed_while_read_follow_policy() {
+    use filetime::{self, FileTime};
+
+    let dir = env::temp_dir().join(format!("hat-changed-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-changed-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("growing"), b"data").unwrap();
+    fs::write(dir.join("steady"), b"data").unwrap();
+    let growing = fs::canonicalize(dir.join("growing")).unwrap();
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    fam.content_filter = Some(Arc::new(AppendToGrowing));
+    let fuzzy = |hat: &mut HatRc<MemoryBackend>, id: u64| {
+        hat.list_snapshots()
+            .into_iter()
+            .find(|s| s.info.snapshot_id == id)
+            .and_then(|s| s.manifest)
+            .unwrap()
+            .fuzzy
+    };
+
+    // Retrying reads the file again as it is now.
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    assert!(fuzzy(&mut hat, 1).is_empty());
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let restored = out.join(fs::canonicalize(&dir).unwrap().strip_prefix("/").unwrap());
+    assert_eq!(fs::read(restored.join("growing")).unwrap(), b"data+");
+    assert_eq!(fs::read(restored.join("steady")).unwrap(), b"data");
+
+    // Fuzzy files are kept as read and listed in the manifest. Snapshots only read files with
+    // a new modification time, in seconds.
+    fs::write(&growing, b"new").unwrap();
+    let time = FileTime::from_unix_time(1000, 0);
+    filetime::set_file_times(&growing, time, time).unwrap();
+    fam.changed_policy = util::ChangedFilePolicy::Fuzzy;
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    assert_eq!(fuzzy(&mut hat, 2), vec![growing.to_string_lossy().into_owned()]);
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(fs::read(restored.join("growing")).unwrap(), b"new+");
+
+    // Failing names the file, and does not fail the next commit for it too.
+    fs::write(&growing, b"newer").unwrap();
+    let time = FileTime::from_unix_time(2000, 0);
+    filetime::set_file_times(&growing, time, time).unwrap();
+    fam.changed_policy = util::ChangedFilePolicy::Fail;
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    let err = hat.commit(&mut fam, None).unwrap_err().to_string();
+    assert!(err.contains("changed while being read"), "{}", err);
+    assert!(err.contains(&growing.display().to_string()), "{}", err);
+    assert!(fam.changed.lock().unwrap().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn command_filter_pipes_matching_files() {
+    let dir = env::temp_dir().join(format!("hat-command-filter-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.env"), b"secret").unwrap();
+    fs::write(dir.join("b.key"), b"secret").unwrap();
+    fs::write(dir.join("c.txt"), b"plain").unwrap();
+
+    let rule = |r: &str| CommandFilter::parse_rule(r).unwrap();
+    let (env_files, upcase) = rule("**/*.env:tr a-z A-Z");
+    let (key_files, skip) = rule("**/*.key:exit 1");
+    let filter = CommandFilter::new()
+        .with_rule(env_files, upcase)
+        .with_rule(key_files, skip);
+
+    assert_eq!(
+        filter.filter(&dir.join("a.env")).unwrap(),
+        FilterDecision::Replace(b"SECRET".to_vec())
+    );
+    assert_eq!(filter.filter(&dir.join("b.key")).unwrap(), FilterDecision::Skip);
+    assert_eq!(filter.filter(&dir.join("c.txt")).unwrap(), FilterDecision::Keep);
+    assert!(CommandFilter::parse_rule("no-command").is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn restore_passes_split_the_snapshot() {
+    use hat::RestorePass;
+
+    let first = util::PathFilter::new(&["etc/app/**", "var/db"]);
+    let only = RestorePass::Only(&first);
+    let except = RestorePass::Except(&first);
+    let restores = |pass: RestorePass, path: &str, is_dir: bool| {
+        pass.inner(path.as_bytes(), is_dir).map(|inner| match inner {
+            RestorePass::All => "all",
+            RestorePass::Only(_) => "only",
+            RestorePass::Except(_) => "except",
+            RestorePass::Path(_) => "path",
+        })
+    };
+
+    assert_eq!(restores(only, "etc", true), Some("only"));
+    assert_eq!(restores(only, "etc/app/conf", false), Some("all"));
+    assert_eq!(restores(only, "etc/passwd", false), None);
+    assert_eq!(restores(only, "var/db", true), Some("all"));
+    assert_eq!(restores(only, "home", true), None);
+
+    assert_eq!(restores(except, "etc", true), Some("except"));
+    assert_eq!(restores(except, "etc/app/conf", false), None);
+    assert_eq!(restores(except, "etc/passwd", false), Some("all"));
+    assert_eq!(restores(except, "var/db", true), None);
+    assert_eq!(restores(except, "home", true), Some("all"));
+
+    let path = RestorePass::Path(b"home/alice");
+    assert_eq!(restores(path, "home", true), Some("path"));
+    assert_eq!(restores(path, "home", false), None);
+    assert_eq!(restores(path, "home/alice", true), Some("all"));
+    assert_eq!(restores(path, "home/alice2", true), None);
+    assert_eq!(restores(path, "home/bob", true), None);
+    assert_eq!(restores(path, "etc", true), None);
+}
+
+#[test]
+fn checkout_first_restores_everything() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("hat-first-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-first-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("etc")).unwrap();
+    fs::create_dir_all(dir.join("home")).unwrap();
+    fs::write(dir.join("etc").join("app.conf"), b"config").unwrap();
+    fs::write(dir.join("etc").join("other"), b"other").unwrap();
+    fs::write(dir.join("home").join("file"), b"contents").unwrap();
+    // Only part of this directory is restored first, so it must stay writable until the end.
+    fs::set_permissions(dir.join("etc"), fs::Permissions::from_mode(0o555)).unwrap();
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let first = util::PathFilter::new(&[root.join("etc/app.conf").to_str().unwrap()]);
+    hat.checkout_in_dir_first("familyname".to_owned(), None, &first, out.clone())
+        .unwrap();
+
+    let restored = out.join(root);
+    assert_eq!(fs::read(restored.join("etc").join("app.conf")).unwrap(), b"config");
+    assert_eq!(fs::read(restored.join("etc").join("other")).unwrap(), b"other");
+    assert_eq!(fs::read(restored.join("home").join("file")).unwrap(), b"contents");
+    let mode = fs::metadata(restored.join("etc")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o555);
+
+    assert!(
+        hat.checkout_in_dir_first("nosuchfamily".to_owned(), None, &first, out.clone())
+            .is_err()
+    );
+
+    for tree in &[&dir, &restored] {
+        fs::set_permissions(tree.join("etc"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn checkout_path_restores_only_that_path() {
+    let dir = env::temp_dir().join(format!("hat-checkout-path-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-checkout-path-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("docs").join("old")).unwrap();
+    fs::create_dir_all(dir.join("music")).unwrap();
+    fs::write(dir.join("docs").join("old").join("letter"), b"dear").unwrap();
+    fs::write(dir.join("docs").join("notes"), b"notes").unwrap();
+    fs::write(dir.join("music").join("song"), b"la la").unwrap();
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let root = fs::canonicalize(&dir).unwrap();
+    let root = root.strip_prefix("/").unwrap();
+    let restored = out.join(root);
+    hat.checkout_path("familyname".to_owned(), Some(1), &root.join("docs"), out.clone())
+        .unwrap();
+    assert_eq!(fs::read(restored.join("docs").join("old").join("letter")).unwrap(), b"dear");
+    assert_eq!(fs::read(restored.join("docs").join("notes")).unwrap(), b"notes");
+    assert!(!restored.join("music").exists());
+
+    // A single file, given with a leading slash, from the latest snapshot.
+    let song = Path::new("/").join(root).join("music").join("song");
+    hat.checkout_path("familyname".to_owned(), None, &song, out.clone()).unwrap();
+    assert_eq!(fs::read(restored.join("music").join("song")).unwrap(), b"la la");
+
+    for missing in &["docs/nothing", "docs/notes/below", "nothing/at/all"] {
+        let path = root.join(missing);
+        assert!(hat.checkout_path("familyname".to_owned(), None, &path, out.clone()).is_err());
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn snapshot_dirs_merges_concurrent_roots() {
+    let dir = env::temp_dir().join(format!("hat-roots-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-roots-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    for name in &["etc", "home/user", "var"] {
+        fs::create_dir_all(dir.join(name)).unwrap();
+    }
+    for i in 0..20 {
+        fs::write(dir.join("etc").join(format!("conf-{}", i)), format!("conf {}", i)).unwrap();
+        fs::write(dir.join("home/user").join(format!("doc-{}", i)), vec![i as u8; 5000]).unwrap();
+    }
+    fs::write(dir.join("var").join("log"), b"log").unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_jobs(4);
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    assert_eq!(fam.key_store_process.len(), 5);
+
+    // The nested root is walked as part of the one containing it.
+    let roots = vec![dir.join("etc"), dir.join("home"), dir.join("home/user")];
+    assert!(fam.snapshot_dirs_preemptible(roots, Preemption::new()));
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    // Counted over the blob stores of every key store.
+    assert_eq!(hat.store_metrics().bytes_stored, 10 * 6 + 10 * 7 + 20 * 5000);
+
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let root = fs::canonicalize(&dir).unwrap();
+    let restored = out.join(root.strip_prefix("/").unwrap());
+    for i in 0..20 {
+        let conf = fs::read(restored.join("etc").join(format!("conf-{}", i))).unwrap();
+        assert_eq!(conf, format!("conf {}", i).into_bytes());
+        let doc = fs::read(restored.join("home/user").join(format!("doc-{}", i))).unwrap();
+        assert_eq!(doc, vec![i as u8; 5000]);
+    }
+    assert!(!restored.join("var").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn concurrent_copies_are_stored_once() {
+    let dir = env::temp_dir().join(format!("hat-inflight-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mut x = 1u32;
+    let data: Vec<u8> = (0..2 * 1024 * 1024)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect();
+    for root in &["a", "b"] {
+        fs::create_dir_all(dir.join(root)).unwrap();
+        for i in 0..4 {
+            fs::write(dir.join(root).join(format!("copy-{}", i)), &data).unwrap();
+        }
+    }
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_jobs(8);
+    let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+    let roots = vec![dir.join("a"), dir.join("b")];
+    assert!(fam.snapshot_dirs_preemptible(roots, Preemption::new()));
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // The copies read at the same time wait for the first to be stored, and share its chunks.
+    let metrics = hat.store_metrics();
+    assert_eq!(metrics.bytes_stored, data.len() as u64);
+    assert_eq!(metrics.bytes_reused, 7 * data.len() as u64);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn small_files_share_composite_leaves() {
+    use filetime::{self, FileTime};
+
+    let dir = env::temp_dir().join(format!("hat-small-files-{}", process::id()));
+    let out = env::temp_dir().join(format!("hat-small-files-out-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(dir.join("mail")).unwrap();
+    for i in 0..300 {
+        fs::write(dir.join("mail").join(format!("msg-{}", i)), format!("message {}", i)).unwrap();
+    }
+    let big = vec![7; 2 * key::BATCH_MAX_FILE_LEN as usize];
+    fs::write(dir.join("big"), &big).unwrap();
+    fs::write(dir.join("empty"), b"").unwrap();
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    hat.set_jobs(2);
+    let commit = |hat: &mut HatRc<MemoryBackend>| {
+        let mut fam = hat.open_family("familyname".to_owned()).unwrap();
+        assert!(fam.snapshot_dirs_preemptible(vec![dir.clone()], Preemption::new()));
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+    };
+    commit(&mut hat);
+
+    // At most one composite leaf per key store holds the small files; the big file and the
+    // empty one are stored as before.
+    let metrics = hat.store_metrics();
+    assert!(metrics.chunks_stored <= 2 + 1 + 1, "{:?}", metrics);
+
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    let root = fs::canonicalize(&dir).unwrap();
+    let restored = out.join(root.strip_prefix("/").unwrap());
+    for i in 0..300 {
+        let msg = fs::read(restored.join("mail").join(format!("msg-{}", i))).unwrap();
+        assert_eq!(msg, format!("message {}", i).into_bytes());
+    }
+    assert_eq!(fs::read(restored.join("big")).unwrap(), big);
+    assert_eq!(fs::read(restored.join("empty")).unwrap(), b"");
+
+    // Unchanged files keep their parts of the old leaves; a changed one gets a new leaf.
+    let msg = dir.join("mail").join("msg-7");
+    fs::write(&msg, b"edited").unwrap();
+    let time = FileTime::from_unix_time(1000, 0);
+    filetime::set_file_times(&msg, time, time).unwrap();
+    let before = hat.store_metrics();
+    commit(&mut hat);
+    assert_eq!(hat.store_metrics().since(&before).chunks_stored, 1);
+
+    // The old leaves stay alive while the new snapshot uses them.
+    hat.deregister_by_name("familyname".to_owned(), 1).unwrap();
+    hat.gc().unwrap();
+    fs::remove_dir_all(&out).unwrap();
+    hat.checkout_in_dir("familyname".to_owned(), out.clone()).unwrap();
+    assert_eq!(fs::read(restored.join("mail").join("msg-7")).unwrap(), b"edited");
+    assert_eq!(fs::read(restored.join("mail").join("msg-8")).unwrap(), b"message 8");
+    let (checked, failures) = hat.verify_blobs();
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn seed_counts_progress_and_keeps_state() {
+    use hat::seed::{self, ScanTotals, SeedState, SnapshotProgress};
+
+    let dir = env::temp_dir().join(format!("hat-seed-{}", process::id()));
+    let state_dir = env::temp_dir().join(format!("hat-seed-state-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&state_dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::create_dir_all(&state_dir).unwrap();
+    fs::write(dir.join("a"), vec![1; 3000]).unwrap();
+    fs::write(dir.join("sub").join("b"), vec![2; 1000]).unwrap();
+    ::std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+
+    let total = seed::scan(::std::slice::from_ref(&dir));
+    assert_eq!(total, ScanTotals { files: 2, bytes: 4000 });
+
+    assert_eq!(SeedState::load(&state_dir, "familyname"), None);
+    let state = SeedState {
+        family: "familyname".to_owned(),
+        started_utc: 1500000000,
+        runs: 2,
+        total: total,
+    };
+    state.store(&state_dir).unwrap();
+    assert_eq!(SeedState::load(&state_dir, "familyname"), Some(state));
+    assert_eq!(SeedState::load(&state_dir, "other"), None);
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    let progress = Arc::new(SnapshotProgress::new(total));
+    assert_eq!(progress.percent(), 0.0);
+    let checkpoints = hat.start_checkpoints(&fam, Duration::from_millis(1), progress.clone());
+    assert!(fam.snapshot_dirs_with_progress(
+        vec![dir.clone()],
+        Preemption::new(),
+        Some(progress.clone())
+    ));
+    drop(checkpoints);
+    assert_eq!(progress.done(), total);
+    assert_eq!(progress.percent(), 100.0);
+
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    SeedState::remove(&state_dir).unwrap();
+    SeedState::remove(&state_dir).unwrap();
+    assert_eq!(SeedState::load(&state_dir, "familyname"), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[test]
+fn meta_commit_overlaps_flush() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (mut hat, inspector) =
+        HatRc::new_for_testing_with_inspector(backend.clone(), 4 * 1024 * 1024).unwrap();
+    let mut fam1 = hat.open_family("first".to_owned()).unwrap();
+    let mut fam2 = hat.open_family("second".to_owned()).unwrap();
+    basic_snapshot(&fam1);
+    snapshot_files(&fam2, vec![("unique", vec![7; 100000])]).unwrap();
+    fam1.flush().unwrap();
+    fam2.flush().unwrap();
+
+    hat.commit(&mut fam1, None).unwrap();
+    hat.commit(&mut fam2, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // Everything referenced was stored by the single barrier at the end.
+    assert!(inspector.dangling_blobs().unwrap().is_empty());
+    assert!(inspector.unreferenced_blobs().unwrap().is_empty());
+
+    let (deleted, live1) = hat.gc().unwrap();
+    assert_eq!(deleted, 0);
+
+    // The snapshot listing is complete.
+    let mut hat2 = setup_hat(backend);
+    hat2.recover().unwrap();
+    let (deleted, live2) = hat2.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert_eq!(live1, live2);
+}
+
+#[test]
+fn chunk_report_counts_snapshot_references() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = setup_hat(backend);
+    let mut fam1 = hat.open_family("first".to_owned()).unwrap();
+    let mut fam2 = hat.open_family("second".to_owned()).unwrap();
+    let shared: Vec<u8> = (0..200000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let only1: Vec<u8> = (0..300000u32).map(|i| (i * 13 % 241) as u8).collect();
+    snapshot_files(&fam1, vec![("shared", shared.clone()), ("only1", only1)]).unwrap();
+    snapshot_files(&fam2, vec![("shared", shared)]).unwrap();
+    fam1.flush().unwrap();
+    fam2.flush().unwrap();
+    hat.commit(&mut fam1, None).unwrap();
+    hat.commit(&mut fam2, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let report = hat.chunk_report().unwrap();
+    assert_eq!(report.snapshots.len(), 2);
+
+    // The first snapshot pins its own file; the second only its directory listing.
+    let first = &report.snapshots[0];
+    let second = &report.snapshots[1];
+    assert_eq!((first.family_name.as_str(), first.snapshot_id), ("first", 1));
+    assert_eq!((second.family_name.as_str(), second.snapshot_id), ("second", 1));
+    assert!(first.unique_bytes > second.unique_bytes);
+    assert!(first.unique_chunks < first.chunks);
+
+    // Shared chunks are counted once per snapshot, and once in the total.
+    assert!(report.shared_bytes() > 0);
+    assert!(report.chunks.values().all(|c| c.snapshots == 1 || c.snapshots == 2));
+    assert_eq!(
+        report.total_bytes(),
+        first.unique_bytes + second.unique_bytes + report.shared_bytes()
+    );
+    assert_eq!(first.bytes - first.unique_bytes, report.shared_bytes());
+
+    // Without the second snapshot, everything is unique to the first. Deleting it updates the
+    // stored counts, so the first snapshot is not walked again.
+    hat.deregister(&fam2, 1).unwrap();
+    let counted = hat.db.lock().chunk_refs_snapshots();
+    assert_eq!(counted.len(), 1);
+    assert_eq!(counted[0].unique_bytes, counted[0].bytes);
+    let report = hat.chunk_report().unwrap();
+    assert_eq!(report.snapshots.len(), 1);
+    assert_eq!(report.shared_bytes(), 0);
+    assert_eq!(report.snapshots[0].unique_bytes, report.snapshots[0].bytes);
+}
+
+#[test]
+fn list_roots_shows_internal_snapshots() {
+    let (_, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let snapshots = hat.list_snapshots();
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(snapshots.iter().filter(|s| s.is_internal()).count(), 1);
+    assert!(!hat::is_internal_family("familyname"));
+
+    // The roots family lists the user snapshot.
+    let roots = hat.list_roots().unwrap();
+    assert_eq!(roots.len(), 1);
+    let (ref root, ref listed) = roots[0];
+    assert_eq!(root.family_name, hat::ROOTS_FAMILY_NAME);
+    assert!(root.is_internal());
+    assert_eq!(listed.len(), 1);
+    assert!(!listed[0].is_internal());
+    assert_eq!((listed[0].family_name.as_str(), listed[0].id), ("familyname", 1));
+}
+
+#[test]
+fn check_inventory_finds_missing_and_damaged_blobs() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let report = hat.check_inventory(100).unwrap();
+    assert!(report.is_ok());
+    assert!(report.blobs > 1);
+    assert_eq!((report.checked, report.unchecked, report.unknown), (report.blobs, 0, 0));
+
+    // Flip a bit in one blob, drop another and add one the index does not know.
+    let names = backend.list().unwrap();
+    let mut data = backend.retrieve(&names[0]).unwrap().unwrap();
+    data[0] ^= 1;
+    backend.delete(&names[0]).unwrap();
+    backend.store(&names[0], CipherText::new(data), Box::new(|()| ())).unwrap();
+    backend.delete(&names[1]).unwrap();
+    backend.store(b"stray", CipherText::new(vec![1]), Box::new(|()| ())).unwrap();
+
+    let report = hat.check_inventory(100).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.missing.len(), 1);
+    assert_eq!(&report.missing[0].name[..], &names[1][..]);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(&report.failures[0].0.name[..], &names[0][..]);
+    assert_eq!(report.unknown, 1);
+    assert_eq!(report.checked, report.blobs - 2);
+
+    // The sample is capped.
+    let report = hat.check_inventory(1).unwrap();
+    assert_eq!(report.checked + report.unchecked + report.failures.len(), 1);
+}
+
+#[test]
+fn chunking_profiles_apply_by_name() {
+    let mut state = 7u32;
+    let data: Vec<u8> = (0..1000000)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    let mut shifted = data[..5000].to_vec();
+    shifted.extend_from_slice(b"a few more bytes");
+    shifted.extend_from_slice(&data[5000..]);
+
+    let chunks_after_edit = |profiles: &str| {
+        let backend = Arc::new(MemoryBackend::new());
+        let (mut hat, inspector) =
+            HatRc::new_for_testing_with_inspector(backend, 4 * 1024 * 1024).unwrap();
+        hat.set_chunking(key::ChunkingProfiles::parse(profiles).unwrap());
+        let fam = hat.open_family("images".to_owned()).unwrap();
+        snapshot_files(&fam, vec![("disk.img", data.clone())]).unwrap();
+        let before = inspector.chunk_count();
+        snapshot_files(&fam, vec![("disk.img", shifted.clone())]).unwrap();
+        fam.flush().unwrap();
+        (before, inspector.chunk_count() - before)
+    };
+
+    // Fixed size chunks all move after the insertion.
+    let (fixed, fixed_new) = chunks_after_edit("*.txt cdc:16K");
+    assert_eq!(fixed, 8);
+    assert_eq!(fixed_new, 8);
+
+    // Content-defined chunks only change around it.
+    let (cdc, cdc_new) = chunks_after_edit("*.img cdc:16K");
+    assert!(cdc > 30);
+    assert!(cdc_new <= 3);
+}
+
+#[test]
+fn compression_applies_by_name() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (mut hat, inspector) =
+        HatRc::new_for_testing_with_inspector(backend, 4 * 1024 * 1024).unwrap();
+    hat.set_chunking(key::ChunkingProfiles::parse("*.raw fixed:64K none").unwrap());
+    let mut fam = hat.open_family("images".to_owned()).unwrap();
+    snapshot_files(&fam, vec![("disk.raw", vec![0; 200000]), ("notes.txt", vec![1; 200000])])
+        .unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let mut packings = inspector.chunk_packings();
+    packings.sort_by_key(|p| p.is_some());
+    packings.dedup();
+    assert_eq!(packings, vec![None, Some(blob::Packing::Zstd)]);
+
+    // Both read back.
+    let dir = env::temp_dir().join(format!("hat-compression-by-name-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    hat.checkout_in_dir("images".to_owned(), dir.clone()).unwrap();
+    assert_eq!(fs::read(dir.join("disk.raw")).unwrap(), vec![0; 200000]);
+    assert_eq!(fs::read(dir.join("notes.txt")).unwrap(), vec![1; 200000]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkout_counts_needed_space() {
+    let (_backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("small", vec![1; 100]), ("dir/large", vec![2; 5000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let dir = env::temp_dir().join(format!("hat-checkout-space-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    // The output directory, "small", "dir" and two blocks of "dir/large".
+    assert_eq!(hat.checkout_size("familyname", &dir, 4096).unwrap(), 5 * 4096);
+
+    // Restoring again overwrites the same files, which needs no more space.
+    hat.checkout_in_dir("familyname".to_owned(), dir.clone()).unwrap();
+    assert_eq!(hat.checkout_size("familyname", &dir, 4096).unwrap(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover_for_reading_restores_without_indexes() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1; 100]), ("dir/b", vec![2; 5000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // Nothing in the backend yet.
+    let mut empty = setup_hat(Arc::new(MemoryBackend::new()));
+    assert!(empty.recover_for_reading().is_err());
+
+    // A fresh state directory learns the snapshots, but not their hashes.
+    let mut fresh = setup_hat(backend);
+    fresh.recover_for_reading().unwrap();
+    let snapshots: Vec<_> = fresh.list_snapshots()
+        .into_iter()
+        .map(|s| (s.family_name, s.info.snapshot_id))
+        .collect();
+    assert_eq!(snapshots, vec![("familyname".to_owned(), 1)]);
+    assert!(fresh.unfinished_work().unwrap().is_empty());
+
+    let dir = env::temp_dir().join(format!("hat-recover-reading-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fresh.checkout_snapshot_in_dir("familyname".to_owned(), 1, dir.clone()).unwrap();
+    assert_eq!(fs::read(dir.join("a")).unwrap(), vec![1; 100]);
+    assert_eq!(fs::read(dir.join("dir/b")).unwrap(), vec![2; 5000]);
+    assert_eq!(fresh.retrieve_metrics().failures(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover_from_parent() {
+    // Several blobs worth of data that does not compress or deduplicate by itself.
+    let mut state = 1u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    };
+    let files = vec![
+        ("big1", noise(3000000)),
+        ("big2", noise(3000000)),
+        ("dir/big3", noise(3000000)),
+    ];
+
+    // Prepare a parent repository.
+    let (parent, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, files.clone()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let parent_blobs = parent.list().unwrap();
+    assert!(parent_blobs.len() > 2);
+
+    // Build a new repository on top of it.
+    let own = Arc::new(MemoryBackend::new());
+    let mut child = setup_hat(Arc::new(LayeredBackend::new(
+        own.clone(),
+        Some(parent.clone()),
+    )));
+    child.recover().unwrap();
+
+    let mut fam2 = child.open_family("child".to_string()).unwrap();
+    snapshot_files(&fam2, files).unwrap();
+    snapshot_files(&fam2, vec![("unique", "only in child".into())]).unwrap();
+    fam2.flush().unwrap();
+    child.commit(&mut fam2, None).unwrap();
+    child.meta_commit().unwrap();
+    child.data_flush().unwrap();
+
+    // Only the new data and metadata were uploaded.
+    assert!(own.list().unwrap().len() < parent_blobs.len());
+
+    // Deleting everything leaves the parent untouched.
+    child.delete_all_snapshots().unwrap();
+    let (deleted, live) = child.gc().unwrap();
+    assert!(deleted > 0);
+    assert_eq!(live, 0);
+    assert_eq!(parent.list().unwrap(), parent_blobs);
+    let mut own_blobs = own.list().unwrap();
+    own_blobs.retain(|n| !shared::is_control_name(n));
+    assert!(own_blobs.is_empty());
+}
+
+#[test]
+fn sync_index_after_external_change() {
+    let (backend, mut hat, mut fam) = setup_family();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    hat.gc().unwrap();
+    hat.check_synced().unwrap();
+
+    // A client with a state directory of its own has not seen the changes.
+    let mut other = setup_hat(backend.clone());
+    assert!(other.gc().is_err());
+    let report = other.sync_index().unwrap();
+    assert!(report.imported_blobs > 0);
+    assert_eq!((report.missing_blobs, report.dropped_hashes), (0, 0));
+    other.check_synced().unwrap();
+
+    // It deletes every blob.
+    let blobs: Vec<_> = backend
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|n| !shared::is_control_name(n))
+        .collect();
+    for name in &blobs {
+        backend.delete(name).unwrap();
+    }
+    shared::write_generation(&*backend, 10).unwrap();
+
+    let err = hat.gc().unwrap_err();
+    assert!(err.to_string().contains("hat sync-index"), "{}", err);
+    let report = hat.sync_index().unwrap();
+    assert_eq!(report.generation, 10);
+    assert_eq!(report.missing_blobs, blobs.len());
+    assert!(report.dropped_hashes > 0);
+    assert_eq!(report.damaged_snapshots, vec!["familyname/1".to_string()]);
+    assert!(hat.hash_index.list().is_empty());
+    hat.check_synced().unwrap();
+
+    // The file is not found unchanged, so its data is stored again.
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    let report = hat.check_inventory(100).unwrap();
+    assert!(report.is_ok());
+    assert!(report.blobs > 1);
+}
+
+#[test]
+fn invalid_generation_file_needs_sync() {
+    let dir = env::temp_dir().join(format!("hat-invalid-generation-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("cache")).unwrap();
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    let open = || HatRc::open_repository(dir.clone(), backend.clone(), 4 * 1024 * 1024).unwrap();
+
+    let mut hat = open();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(&fam, vec![("a", vec![1, 2, 3])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    hat.gc().unwrap();
+    hat.check_synced().unwrap();
+    drop(fam);
+    drop(hat);
+
+    // A torn write does not keep the repository from opening, but it must be synced again.
+    fs::write(dir.join("cache").join(hat::sync::GENERATION_FILENAME), "").unwrap();
+    let mut hat = open();
+    let err = hat.check_synced().unwrap_err();
+    assert!(err.to_string().contains("hat sync-index"), "{}", err);
+    hat.sync_index().unwrap();
+    drop(hat);
+    open().check_synced().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shared_backend() {
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat1 = setup_hat(backend.clone());
+    let mut hat2 = setup_hat(backend.clone());
+    hat1.set_writer(Some("one".to_string()));
+    hat2.set_writer(Some("two".to_string()));
+
+    // Only one writer can hold the lock.
+    assert!(hat1.lock_backend().unwrap());
+    assert!(!hat1.lock_backend().unwrap());
+    assert!(hat2.lock_backend().is_err());
+
+    let mut fam = hat1.open_family("familyname".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat1.commit(&mut fam, None).unwrap();
+    hat1.meta_commit().unwrap();
+    hat1.data_flush().unwrap();
+    hat1.unlock_backend().unwrap();
+    let list_blobs = || {
+        let mut names = backend.list().unwrap();
+        names.retain(|n| !shared::is_control_name(n));
+        names
+    };
+    let blobs = list_blobs();
+    assert!(!blobs.is_empty());
+
+    // The second writer has no snapshots, but keeps the blobs published by the first.
+    let (deleted, live) = hat2.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert_eq!(live, 0);
+    assert_eq!(list_blobs(), blobs);
+    let (checked, failures) = hat1.verify_blobs();
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+
+    // A stale lock does not block commits, but GC waits for its writer to resume.
+    shared::RemoteLock::acquire(&*backend, "three", 0).unwrap();
+    assert!(hat2.lock_backend().unwrap());
+    hat2.unlock_backend().unwrap();
+    assert!(hat1.gc().is_err());
+}
+
+#[test]
+fn stop_at_deadline() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Verification continues where an earlier window stopped.
+    let past = Deadline::after(Some(Duration::from_secs(0)));
+    let (checked, failures, resume_after) = hat.verify_blobs_from(0, past);
+    assert_eq!(checked, 0);
+    assert!(failures.is_empty());
+    assert_eq!(resume_after, Some(0));
+
+    let (checked, failures, resume_after) = hat.verify_blobs_from(0, Deadline::none());
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+    assert_eq!(resume_after, None);
+    let (checked, _, resume_after) = hat.verify_blobs_from(i64::MAX, Deadline::none());
+    assert_eq!(checked, 0);
+    assert_eq!(resume_after, None);
+
+    // Garbage left by a GC that ran out of time is deleted by the next one.
+    hat.deregister(&fam, 1).unwrap();
+    assert!(!hat.gc_until(past).unwrap().completed);
+    let summary = hat.gc_until(Deadline::none()).unwrap();
+    assert!(summary.deleted_hashes > 0);
+    assert!(summary.completed);
+}
+
+#[test]
+fn inspect_dedup_and_gc() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (mut hat, inspector) = HatRc::new_for_testing_with_inspector(backend, 4 * 1024 * 1024)
+        .unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+
+    snapshot_files(&fam, vec![("a", "same data".into()), ("b", "other data".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let chunks = inspector.chunk_count();
+    assert!(chunks >= 2);
+
+    // Identical contents are stored once.
+    snapshot_files(&fam, vec![("c", "same data".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(inspector.chunk_count(), chunks);
+
+    let stored: Vec<Vec<u8>> = inspector
+        .stored_blobs()
+        .unwrap()
+        .iter()
+        .flat_map(|name| inspector.blob_chunks(name).unwrap())
+        .collect();
+    assert_eq!(stored.iter().filter(|c| &c[..] == b"same data").count(), 1);
+
+    let before = inspector.index_snapshot();
+    assert!(before.snapshots.contains(&("familyname".to_string(), 2)));
+    hat.gc().unwrap();
+    assert_eq!(inspector.index_snapshot(), before);
+    assert!(inspector.dangling_blobs().unwrap().is_empty());
+    assert!(inspector.unreferenced_blobs().unwrap().is_empty());
+
+    // Deleting everything leaves no data behind.
+    hat.delete_all_snapshots().unwrap();
+    hat.gc().unwrap();
+    assert!(!inspector
+        .index_snapshot()
+        .snapshots
+        .iter()
+        .any(|s| s.0 == "familyname"));
+    assert!(inspector.dangling_blobs().unwrap().is_empty());
+    assert!(inspector.stored_blobs().unwrap().is_empty());
+}
+
+/// Paths of all entries in the latest snapshot of `family`, sorted.
+fn snapshot_paths<B: StoreBackend>(hat: &mut HatRc<B>, family: &str) -> Vec<String> {
+    fn walk<B: StoreBackend>(
+        reader: &hat::HashReader<B>,
+        dir: hash::tree::HashRef,
+        prefix: &str,
+        out: &mut Vec<String>,
+    ) {
+        for res in reader.list_dir(dir).unwrap() {
+            let (entry, content) = res.unwrap();
+            let path = format!("{}{}", prefix, entry.info.name.utf8());
+            if let walker::Content::Dir(href) = content {
+                walk(reader, href, &format!("{}/", path), out);
+            }
+            out.push(path);
+        }
+    }
+    let (_, _, top_ref) = hat.snapshot_index.latest(family).unwrap();
+    let mut paths = vec![];
+    walk(&hat.hash_reader(), top_ref.unwrap(), "", &mut paths);
+    paths.sort();
+    paths
+}
+
+#[test]
+fn path_index_follows_commits() {
+    let (_backend, mut hat, mut fam) = setup_family();
+    assert!(hat.find_paths("*").is_err());
+    hat.rebuild_path_index().unwrap();
+
+    let commit = |hat: &mut HatRc<MemoryBackend>, fam: &mut Family<MemoryBackend>, b: &str| {
+        snapshot_files(
+            fam,
+            vec![("a", "same".into()), ("docs/b", b.into())],
+        ).unwrap();
+        fam.flush().unwrap();
+        hat.commit(fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    };
+    commit(&mut hat, &mut fam, "first");
+    commit(&mut hat, &mut fam, "second");
+    commit(&mut hat, &mut fam, "second");
+
+    let snapshots = |versions: Vec<hat::IndexedVersion>| {
+        versions
+            .into_iter()
+            .map(|v| (v.version.path, v.snapshots))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        snapshots(hat.find_paths("a").unwrap()),
+        vec![("a".to_string(), vec![1, 2, 3])]
+    );
+    assert_eq!(
+        snapshots(hat.path_history("familyname", "docs/b").unwrap()),
+        vec![("docs/b".to_string(), vec![1]), ("docs/b".to_string(), vec![2, 3])]
+    );
+    assert_eq!(snapshots(hat.find_paths("doc*/*").unwrap()).len(), 2);
+    assert!(hat.find_paths("c").unwrap().is_empty());
+
+    // Deleted snapshots drop out, and a rebuild finds the same.
+    hat.deregister_by_name("familyname".to_string(), 1).unwrap();
+    let history = snapshots(hat.path_history("familyname", "docs/b").unwrap());
+    assert_eq!(history, vec![("docs/b".to_string(), vec![2, 3])]);
+    hat.rebuild_path_index().unwrap();
+    assert_eq!(
+        snapshots(hat.path_history("familyname", "docs/b").unwrap()),
+        history
+    );
+}
+
+#[test]
+fn affected_versions_walk_up_to_files() {
+    let (_backend, mut hat, mut fam) = setup_family();
+    let big: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    snapshot_files(&fam, vec![("a", big), ("docs/b", "small".into())]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let leaves: Vec<hash::Hash> = hat.hash_index
+        .list()
+        .into_iter()
+        .filter(|e| e.node == blob::NodeType::Leaf && e.leaf == blob::LeafType::FileChunk)
+        .map(|e| e.hash)
+        .collect();
+    assert!(leaves.len() > 2);
+    assert!(hat.affected_versions(&leaves).is_err());
+    hat.rebuild_path_index().unwrap();
+
+    // Each data chunk belongs to one file; those of `a` are found through its tree.
+    let mut paths = vec![];
+    for leaf in &leaves {
+        let affected = hat.affected_versions(slice::from_ref(leaf)).unwrap();
+        assert_eq!(affected.len(), 1);
+        assert_eq!(affected[0].snapshots, vec![1]);
+        paths.push(affected[0].version.path.clone());
+    }
+    assert_eq!(paths.iter().filter(|p| *p == "docs/b").count(), 1);
+    assert_eq!(paths.len(), leaves.len());
+    assert!(paths.iter().all(|p| p == "a" || p == "docs/b"));
+
+    // The chunks of a blob are all found in the hash index.
+    let blob_name = hat.hash_index
+        .list()
+        .into_iter()
+        .filter_map(|e| e.persistent_ref)
+        .next()
+        .unwrap()
+        .blob_name;
+    assert!(!hat.blob_hashes(&[blob_name]).is_empty());
+    assert!(hat.blob_hashes(&[]).is_empty());
+}
+
+#[test]
+fn derive_keeps_filtered_subtree() {
+    let backend = Arc::new(MemoryBackend::new());
+    let (mut hat, inspector) = HatRc::new_for_testing_with_inspector(backend, 4 * 1024 * 1024)
+        .unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    snapshot_files(
+        &fam,
+        vec![
+            ("home/alice/a", "alice data".into()),
+            ("home/alice/docs/b", "more alice data".into()),
+            ("home/bob/c", "bob data".into()),
+            ("etc/hat.conf", "config".into()),
+        ],
+    ).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let filter = util::PathFilter::new(&["home/alice/**"]);
+    let mut alice = hat.open_family("alice-only".to_string()).unwrap();
+    hat.derive(&mut alice, "familyname", 1, &filter).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    assert_eq!(
+        snapshot_paths(&mut hat, "alice-only"),
+        vec!["home", "home/alice", "home/alice/a", "home/alice/docs", "home/alice/docs/b"]
+    );
+    // File contents are shared, not stored again.
+    let stored: Vec<Vec<u8>> = inspector
+        .stored_blobs()
+        .unwrap()
+        .iter()
+        .flat_map(|name| inspector.blob_chunks(name).unwrap())
+        .collect();
+    assert_eq!(stored.iter().filter(|c| &c[..] == b"alice data").count(), 1);
+
+    // Nothing matches: no snapshot is made.
+    let nothing = util::PathFilter::new(&["home/carol/**"]);
+    assert!(hat.derive(&mut alice, "familyname", 1, &nothing).is_err());
+    assert!(hat.derive(&mut alice, "familyname", 7, &filter).is_err());
+
+    // The derived snapshot holds on to its data after the original is deleted.
+    hat.deregister_by_name("familyname".to_string(), 1).unwrap();
+    hat.gc().unwrap();
+    assert!(inspector.dangling_blobs().unwrap().is_empty());
+    assert_eq!(snapshot_paths(&mut hat, "alice-only").len(), 5);
+    let (checked, failures) = hat.verify_blobs();
+    assert!(checked > 0);
+    assert!(failures.is_empty());
+
+    hat.delete_all_snapshots().unwrap();
+    hat.gc().unwrap();
+    assert!(inspector.stored_blobs().unwrap().is_empty());
+}
+
+#[test]
+fn gc_keeps_immutable_blobs() {
+    let window = Duration::from_secs(3600);
+    let backend = Arc::new(MemoryBackend::with_immutability(window));
+    let (mut hat, inspector) =
+        HatRc::new_for_testing_with_inspector(backend.clone(), 4 * 1024 * 1024).unwrap();
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+    let blobs = inspector.stored_blobs().unwrap();
+
+    // Young blobs survive GC, even once unused.
+    hat.delete_all_snapshots().unwrap();
+    let summary = hat.gc_until(Deadline::none()).unwrap();
+    assert!(summary.completed);
+    assert_eq!(summary.live_blobs, 0);
+    assert_eq!(summary.retained_blobs as usize, blobs.len());
+    assert_eq!(inspector.stored_blobs().unwrap(), blobs);
+
+    // Still inside the window, they are kept.
+    backend.advance_clock(window - Duration::from_secs(60));
+    let summary = hat.gc_until(Deadline::none()).unwrap();
+    assert_eq!(summary.retained_blobs as usize, blobs.len());
+    assert_eq!(inspector.stored_blobs().unwrap(), blobs);
+
+    // After the window, they are deleted.
+    backend.advance_clock(Duration::from_secs(60));
+    let summary = hat.gc_until(Deadline::none()).unwrap();
+    assert_eq!(summary.retained_blobs, 0);
+    assert!(inspector.stored_blobs().unwrap().is_empty());
+}
+
+#[test]
+fn maintenance_triggers() {
+    use hat::maintenance::{due, DatabaseUsage, Record, Triggers};
+    use std::path::PathBuf;
+
+    let triggers = Triggers::default();
+    let usage = |bytes, free_bytes| {
+        vec![DatabaseUsage {
+            path: PathBuf::from("hash_index.sqlite3"),
+            bytes: bytes,
+            free_bytes: free_bytes,
+        }]
+    };
+    let last = Some(Record {
+        ts_utc: 1000,
+        bytes: 4096,
+    });
+
+    // Nothing to compact before the first commit.
+    assert_eq!(None, due(&triggers, None, &[], 1000));
+    assert!(due(&triggers, None, &usage(4096, 0), 1000).is_some());
+
+    assert_eq!(None, due(&triggers, last, &usage(6000, 1000), 2000));
+    assert!(due(&triggers, last, &usage(6000, 2000), 2000).is_some());
+    assert!(due(&triggers, last, &usage(8192, 0), 2000).is_some());
+    assert!(due(&triggers, last, &usage(4096, 0), 1000 + triggers.max_age_secs).is_some());
+}
+
+#[test]
+fn maintenance_compacts_state_dir() {
+    use db;
+    use diesel;
+    use diesel::prelude::*;
+    use diesel::sqlite::SqliteConnection;
+    use hat::{hash_index_path, maintenance};
+    use snapshot;
+
+    let dir = env::temp_dir().join(format!("hat-maintenance-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("cache")).unwrap();
+    let cache = |name: &str| dir.join("cache").join(name).to_string_lossy().into_owned();
+
+    {
+        let index = Arc::new(db::Index::new(&hash_index_path(&dir)).unwrap());
+        let mut snapshots = snapshot::SnapshotIndex::new(index);
+        snapshots.reserve("live".to_owned(), None, &[]);
+        snapshots.flush();
+    }
+    for family in &["live", "gone"] {
+        let index = key::KeyIndex::new(&cache(family)).unwrap();
+        for i in 0..100 {
+            index.insert(entry(format!("file{}", i)), None).unwrap();
+        }
+        index.flush().unwrap();
+    }
+    // A node without data, as left behind by a failed commit.
+    let conn = SqliteConnection::establish(&cache("live")).unwrap();
+    diesel::sql_query("INSERT INTO key_tree (parent_id, name) VALUES (NULL, x'6f727068616e')")
+        .execute(&conn)
+        .unwrap();
+    drop(conn);
+
+    let summary = maintenance::compact(&dir, 1000).unwrap();
+    assert_eq!(vec!["gone".to_owned()], summary.pruned_families);
+    assert_eq!(1, summary.pruned_nodes);
+    assert!(summary.bytes_after < summary.bytes_before);
+    assert!(!dir.join("cache").join("gone").exists());
+
+    let usage = maintenance::usage(&dir).unwrap();
+    assert_eq!(2, usage.len());
+    assert!(usage.iter().all(|u| u.free_bytes == 0));
+
+    let last = maintenance::Record::load(&dir);
+    assert_eq!(
+        Some(maintenance::Record {
+            ts_utc: 1000,
+            bytes: summary.bytes_after,
+        }),
+        last
+    );
+    assert_eq!(
+        None,
+        maintenance::due(&Default::default(), last, &usage, 1000)
+    );
+
+    // The surviving key index still works.
+    let index = key::KeyIndex::new(&cache("live")).unwrap();
+    assert!(index.lookup(None, "file7".to_owned().into()).unwrap().is_some());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Check that `hat` finished all its work, holds exactly the complete snapshots `expected`,
+/// and agrees with a repository recovered from `backend` on their contents and live blobs.
+/// `point` names the fail point that was hit, for failure messages.
+fn assert_consistent(
+    dir: &Path,
+    backend: &Arc<MemoryBackend>,
+    hat: &mut HatRc<MemoryBackend>,
+    expected: &[(&str, u64)],
+    point: &str,
+) {
+    fn complete(hat: &mut HatRc<MemoryBackend>) -> Vec<(String, u64)> {
+        let mut snapshots: Vec<_> = hat
+            .list_snapshots()
+            .into_iter()
+            .filter(|s| !s.is_internal() && s.hash_ref.is_some())
+            .map(|s| (s.family_name, s.info.snapshot_id))
+            .collect();
+        snapshots.sort();
+        snapshots
+    }
+    let expected: Vec<_> = expected.iter().map(|&(f, id)| (f.to_owned(), id)).collect();
+
+    assert!(hat.snapshot_index.list_not_done().is_empty(), "after {}", point);
+    assert_eq!(complete(hat), expected, "after {}", point);
+    let (_, live) = hat.gc().unwrap();
+    assert!(hat.check_inventory(1000).unwrap().is_ok(), "after {}", point);
+
+    // Recover into a state dir of its own, sharing only the keys and settings of `dir`.
+    let recovery = dir.with_extension("recovered");
+    let _ = fs::remove_dir_all(&recovery);
+    fs::create_dir_all(recovery.join("cache")).unwrap();
+    for file in fs::read_dir(dir).unwrap() {
+        let file = file.unwrap();
+        if file.file_type().unwrap().is_file() {
+            fs::copy(file.path(), recovery.join(file.file_name())).unwrap();
+        }
+    }
+    let mut recovered = reopen(&recovery, backend);
+    recovered.recover().unwrap();
+    assert_eq!(complete(&mut recovered), expected, "after {}", point);
+    for (family, _) in &expected {
+        let paths = snapshot_paths(hat, family);
+        assert_eq!(snapshot_paths(&mut recovered, family), paths, "after {}", point);
+    }
+    // The recovered repository keeps only the newest snapshot list, so it may use less.
+    let (deleted, recovered_live) = recovered.gc().unwrap();
+    assert_eq!(deleted, 0, "after {}", point);
+    assert!(recovered_live <= live, "after {}", point);
+    drop(recovered);
+    fs::remove_dir_all(&recovery).unwrap();
+}
+
+/// A state directory of its own, so a test can crash a repository and open it again from what
+/// reached the disk.
+fn setup_state_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("hat-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("cache")).unwrap();
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+    dir
+}
+
+/// Open the repository in `dir` as a new process would, leaving its unfinished work to `resume`.
+fn reopen(dir: &Path, backend: &Arc<MemoryBackend>) -> HatRc<MemoryBackend> {
+    HatRc::open_repository_without_resume(dir.to_owned(), backend.clone(), 4 * 1024 * 1024)
+        .unwrap()
+}
+
+#[test]
+fn commit_resumes_after_fail_points() {
+    let points = [
+        "commit-reserved",
+        "commit-updated",
+        "commit-registered",
+        "commit-ready",
+        "commit-cleaned-up",
+        "meta-commit",
+        "flush-barrier",
+    ];
+    for point in &points {
+        let dir = setup_state_dir(&format!("commit-{}", point));
+        let backend = Arc::new(MemoryBackend::new());
+        let mut hat = reopen(&dir, &backend);
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+
+        util::failpoint::arm(point);
+        let res = hat.commit(&mut fam, None)
+            .and_then(|()| hat.meta_commit_and_flush());
+        assert!(!util::failpoint::disarm(), "{} was not reached", point);
+        assert!(res.is_err());
+        drop(fam);
+        hat.crash();
+
+        let mut hat = reopen(&dir, &backend);
+        hat.resume().unwrap();
+        hat.meta_commit_and_flush().unwrap();
+        assert_consistent(&dir, &backend, &mut hat, &[("familyname", 1)], point);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn resume_reports_unfinished_work() {
+    let (_backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    assert_eq!(hat.unfinished_work().unwrap(), vec![]);
+
+    util::failpoint::arm("commit-reserved");
+    assert!(hat.commit(&mut fam, None).is_err());
+    assert!(!util::failpoint::disarm());
+
+    let unfinished = hat.unfinished_work().unwrap();
+    assert_eq!(
+        unfinished,
+        vec![ResumeWork {
+            family: "familyname".to_owned(),
+            snapshot_id: 1,
+            action: ResumeAction::Commit,
+        }]
+    );
+    assert_eq!(unfinished[0].to_string(), "commit of familyname #1");
+
+    // Listing does not resume anything.
+    assert_eq!(hat.unfinished_work().unwrap(), unfinished);
+    assert_eq!(hat.resume().unwrap(), unfinished);
+    assert_eq!(hat.unfinished_work().unwrap(), vec![]);
+    assert_eq!(hat.resume().unwrap(), vec![]);
+    hat.meta_commit_and_flush().unwrap();
+}
+
+#[test]
+fn delete_resumes_after_fail_points() {
+    for point in &["delete-marked", "delete-deregistered", "delete-ready"] {
+        let dir = setup_state_dir(&format!("delete-{}", point));
+        let backend = Arc::new(MemoryBackend::new());
+        let mut hat = reopen(&dir, &backend);
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        snapshot_files(&fam, vec![("new", vec![3; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+
+        util::failpoint::arm(point);
+        let res = hat.deregister(&fam, 1);
+        assert!(!util::failpoint::disarm(), "{} was not reached", point);
+        assert!(res.is_err());
+        drop(fam);
+        hat.crash();
+
+        let mut hat = reopen(&dir, &backend);
+        hat.resume().unwrap();
+        hat.meta_commit_and_flush().unwrap();
+        assert_consistent(&dir, &backend, &mut hat, &[("familyname", 2)], point);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn gc_reruns_after_fail_points() {
+    for point in &["gc-hashes-deleted", "gc-blobs-marked"] {
+        let dir = setup_state_dir(&format!("gc-{}", point));
+        let backend = Arc::new(MemoryBackend::new());
+        let mut hat = reopen(&dir, &backend);
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        snapshot_files(&fam, vec![("new", vec![3; 1000])]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+        hat.deregister(&fam, 1).unwrap();
+        hat.meta_commit_and_flush().unwrap();
+
+        util::failpoint::arm(point);
+        let res = hat.gc();
+        assert!(!util::failpoint::disarm(), "{} was not reached", point);
+        assert!(res.is_err());
+        drop(fam);
+        hat.crash();
+
+        let mut hat = reopen(&dir, &backend);
+        hat.resume().unwrap();
+        assert_consistent(&dir, &backend, &mut hat, &[("familyname", 2)], point);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn stats_history_tracks_growth() {
+    use hat::stats::{self, StatsRecord};
+
+    let dir = env::temp_dir().join(format!("hat-stats-{}", process::id()));
+    let state = env::temp_dir().join(format!("hat-stats-state-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&state);
+    fs::create_dir_all(&dir).unwrap();
+    fs::create_dir_all(&state).unwrap();
+    fs::write(dir.join("file"), vec![1; 1000]).unwrap();
+    fs::write(dir.join("other"), vec![2; 234]).unwrap();
+
+    let (_backend, mut hat, mut fam) = setup_family();
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // Snapshot sizes come from the manifest, so no listing is read.
+    let mut record = hat.stats_record("commit");
+    assert_eq!(record.command, "commit");
+    assert_eq!(record.storage_bytes, hat.storage_usage());
+    assert!(record.blobs > 0);
+    assert_eq!(record.families.get("familyname"), Some(&Some(1234)));
+
+    // Names survive the line format, whatever they hold.
+    record.families.insert("odd name=100%".to_owned(), None);
+    assert_eq!(StatsRecord::parse(&record.to_line()), Some(record.clone()));
+
+    let day = 24 * 3600;
+    let mut later = record.clone();
+    later.ts_utc += 2 * day;
+    later.storage_bytes += 2000;
+    let mut same_day = later.clone();
+    same_day.ts_utc += 60;
+    same_day.storage_bytes += 1000;
+    for r in &[&record, &later, &same_day] {
+        stats::append(&state, r).unwrap();
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .open(state.join(stats::STATS_HISTORY_FILENAME))
+        .and_then(|mut f| ::std::io::Write::write_all(&mut f, b"interrupted wri"))
+        .unwrap();
+
+    let records = stats::load(&state).unwrap();
+    assert_eq!(records.len(), 3);
+    let daily = stats::daily(&records);
+    assert_eq!(daily.len(), 2);
+    assert_eq!(*daily[1], same_day);
+    assert_eq!(stats::plot(&daily, 10).len(), 2);
+    assert!(stats::plot(&daily, 10)[1].ends_with(&"#".repeat(10)));
+
+    let growth = stats::growth_per_day(&records).unwrap();
+    assert!(growth > 1400 && growth < 1500, "{}", growth);
+    assert_eq!(stats::days_until_quota(1000, 1000 + 30 * 1500, 1500), Some(30));
+    assert_eq!(stats::days_until_quota(1000, 2000, -5), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&state).unwrap();
+}
+
+#[test]
+fn self_test_cleans_up_after_itself() {
+    use hat::selftest::SELF_TEST_FAMILY;
+
+    let dir = env::temp_dir().join(format!("hat-self-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (_, mut hat, fam) = setup_family();
+    snapshot_files(&fam, vec![("kept", b"kept".to_vec())]).unwrap();
+    hat.commit(&mut fam.clone(), None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    // A second run finds nothing left of the first.
+    for run in 0..2 {
+        let report = hat.self_test(&dir.join(run.to_string())).unwrap();
+        assert_eq!(report.sample.files, 4);
+        assert!(report.verified_blobs > 0);
+        assert!(report.deleted_hashes > 0);
+        assert!(
+            !hat.list_snapshots()
+                .iter()
+                .any(|s| s.family_name == SELF_TEST_FAMILY)
+        );
+        assert_eq!(hat.gc().unwrap().0, 0);
+    }
+
+    hat.checkout_in_dir("familyname".to_owned(), dir.join("out")).unwrap();
+    assert_eq!(fs::read(dir.join("out").join("kept")).unwrap(), b"kept");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn estimate_counts_only_new_data() {
+    use filetime::{self, FileTime};
+    use rand::{self, Rng};
+
+    let dir = env::temp_dir().join(format!("hat-estimate-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let mut data = vec![0u8; 300000];
+    rand::thread_rng().fill(&mut data[..]);
+    fs::write(dir.join("a"), &data).unwrap();
+    fs::write(dir.join("sub").join("a-copy"), &data).unwrap();
+    fs::write(dir.join("b"), b"bbbb").unwrap();
+
+    let (_, mut hat, mut fam) = setup_family();
+    let estimate = hat.estimate("familyname", slice::from_ref(&dir)).unwrap();
+    assert_eq!(estimate.total.files, 3);
+    assert_eq!(estimate.total.bytes, 600004);
+    assert_eq!(estimate.unchanged.files, 0);
+    // The copy needs no upload of its own.
+    assert_eq!(estimate.new_bytes, 300004);
+    assert_eq!(estimate.blobs, 2);
+    assert_eq!(estimate.upload_bytes(), 2 * 4 * 1024 * 1024);
+
+    fam.snapshot_dir(dir.clone());
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+    let estimate = hat.estimate("familyname", slice::from_ref(&dir)).unwrap();
+    assert_eq!(estimate.unchanged, estimate.total);
+    assert_eq!(estimate.new_bytes, 0);
+
+    // A touched file is read again, but only a new file has new data.
+    let time = FileTime::from_unix_time(1000, 0);
+    filetime::set_file_times(dir.join("b"), time, time).unwrap();
+    fs::write(dir.join("sub").join("c"), b"cc").unwrap();
+    let estimate = hat.estimate("familyname", &[dir.join("sub"), dir.clone()]).unwrap();
+    assert_eq!(estimate.total.files, 4);
+    assert_eq!(estimate.unchanged.files, 2);
+    assert_eq!(estimate.new_bytes, 2);
+    assert_eq!(estimate.upload_time(1024 * 1024), Duration::from_secs(8));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn verify_snapshot_reads_back_every_file() {
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit_and_flush().unwrap();
+
+    let report = hat.verify_snapshot("familyname", 1).unwrap();
+    assert!(report.is_ok(), "{:?}", report.failures);
+    assert_eq!((report.dirs, report.files), (29, 17));
+    // Identical files are read once.
+    assert_eq!(report.bytes, 3000055);
+    assert!(hat.verify_snapshot("familyname", 2).is_err());
+
+    // Drop the blob holding the contents of "ones"; reads are not served from a cache.
+    let top_ref = match hat.snapshot_index.lookup("familyname", 1) {
+        Some((_, _, Some(r))) => r,
+        _ => panic!("no snapshot"),
+    };
+    let ones = Family::<MemoryBackend>::fetch_dir_data(top_ref, hat.hash_backend())
+        .unwrap()
+        .map(|r| r.unwrap())
+        .find(|(e, _)| e.info.name.utf8() == "ones")
+        .unwrap();
+    let blob_name = match ones.1 {
+        walker::Content::Data(href) => href.persistent_ref.blob_name,
+        _ => panic!("not a file"),
+    };
+    backend.delete(&blob_name).unwrap();
+
+    let report = hat.verify_snapshot("familyname", 1).unwrap();
+    assert!(!report.is_ok());
+    assert!(report.failures.iter().any(|(p, _)| p == Path::new("ones")));
+    assert!(report.failures.iter().all(|(_, e)| e.ends_with("is missing")));
+    // The other files were still checked.
+    assert_eq!(report.files, 17);
+}
@@ -0,0 +1,167 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that a snapshot can be restored, by reading back everything it references.
+//!
+//! Where `hat verify` without a snapshot checks each committed blob on its own, this walks the
+//! hash trees of one snapshot: every directory listing and every chunk of file data is fetched,
+//! decrypted and compared against the hash it is referenced by. A chunk that fails is reported
+//! with the path it belongs to, and the walk continues with the rest of the snapshot.
+
+use backend::{StoreBackend, UncachedBackend};
+use blob::{self, NodeType};
+use errors::HatError;
+use hash;
+use hash::tree::{HashRef, HashTreeBackend};
+use hat::family::parse_dir_data;
+use hat::{walker, HatRc};
+use hex;
+use key;
+use std::collections::BTreeSet;
+use std::ffi;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotVerification {
+    /// Directories whose listing was read.
+    pub dirs: u64,
+    /// Files whose contents were read.
+    pub files: u64,
+    /// Chunks fetched and checked against their hash, including those of directory listings.
+    pub chunks: u64,
+    /// Bytes of file contents checked.
+    pub bytes: u64,
+    /// Paths, relative to the snapshot root, that could not be read back, with the reason.
+    /// The contents of a failed directory are not checked.
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+impl SnapshotVerification {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Fetch every chunk of the tree below `root`, calling `leaf` with the data of each leaf.
+/// Stops at the first chunk that cannot be read.
+fn check_tree<B, F>(
+    backend: &key::HashStoreBackend<B>,
+    root: HashRef,
+    report: &mut SnapshotVerification,
+    mut leaf: F,
+) -> Result<(), String>
+where
+    B: StoreBackend,
+    F: FnMut(&mut SnapshotVerification, Vec<u8>) -> Result<(), String>,
+{
+    let mut stack = vec![root];
+    while let Some(href) = stack.pop() {
+        let chunk = match backend.fetch_chunk(&href) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                return Err(format!(
+                    "blob {} is missing",
+                    hex::encode(&href.persistent_ref.blob_name)
+                ))
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        report.chunks += 1;
+        match href.node {
+            NodeType::Leaf => leaf(report, href.data_of(chunk))?,
+            NodeType::Branch(..) => {
+                let mut childs = hash::tree::hash_refs_from_bytes(&chunk[..])
+                    .ok_or_else(|| "invalid tree node".to_string())?;
+                childs.reverse();
+                stack.extend(childs);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Read back every directory listing and file of snapshot `snapshot_id` of `family_name`,
+    /// checking each chunk against its hash.
+    pub fn verify_snapshot(
+        &mut self,
+        family_name: &str,
+        snapshot_id: u64,
+    ) -> Result<SnapshotVerification, HatError> {
+        let top_ref = match self.snapshot_index.lookup(family_name, snapshot_id) {
+            Some((_, _, Some(r))) => r,
+            _ => {
+                return Err(From::from(format!(
+                    "No complete snapshot {}/{}",
+                    family_name, snapshot_id
+                )))
+            }
+        };
+
+        // A blob store of its own, without the caches of the shared one, and reading past the
+        // caches of the backend, so that every chunk is read from the storage again.
+        let backend = key::HashStoreBackend::new(
+            self.hash_index.clone(),
+            Arc::new(blob::BlobStore::new(
+                self.keys.clone(),
+                self.blob_index.clone(),
+                Arc::new(UncachedBackend::new(self.backend.clone())),
+                self.blob_max_size,
+            )),
+            self.keys.clone(),
+        );
+        let mut report = SnapshotVerification::default();
+        // Identical files share their tree, which only needs checking once.
+        let mut checked = BTreeSet::new();
+        let mut dirs = vec![(PathBuf::new(), top_ref)];
+        while let Some((path, dir_ref)) = dirs.pop() {
+            report.dirs += 1;
+            let mut entries = vec![];
+            let listed = check_tree(&backend, dir_ref, &mut report, |_, chunk| {
+                parse_dir_data(&chunk[..], &mut entries).map_err(|e| e.to_string())
+            });
+            if let Err(e) = listed {
+                report.failures.push((path.clone(), e));
+            }
+
+            for entry in entries {
+                let name: ffi::OsString = entry.meta.info.name.into();
+                let file_path = path.join(name);
+                match entry.hash_ref {
+                    walker::Content::Data(href) => {
+                        report.files += 1;
+                        if !checked.insert((href.hash.bytes.clone(), href.range)) {
+                            continue;
+                        }
+                        let res = check_tree(&backend, href, &mut report, |report, data| {
+                            report.bytes += data.len() as u64;
+                            Ok(())
+                        });
+                        if let Err(e) = res {
+                            report.failures.push((file_path, e));
+                        }
+                    }
+                    walker::Content::Dir(href) => dirs.push((file_path, href)),
+                    walker::Content::Link(_)
+                    | walker::Content::Unreadable(_)
+                    | walker::Content::Special(_) => (),
+                }
+            }
+        }
+        report.failures.sort();
+
+        Ok(report)
+    }
+}
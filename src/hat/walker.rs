@@ -14,7 +14,9 @@
 
 use hash;
 use key;
+use models;
 use std::collections::VecDeque;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
 #[derive(Clone, Debug)]
@@ -22,6 +24,29 @@ pub enum Content {
     Data(hash::tree::HashRef),
     Dir(hash::tree::HashRef),
     Link(PathBuf),
+    /// A file that could not be read when the snapshot was taken, with the error.
+    Unreadable(String),
+    /// A device node, FIFO or socket.
+    Special(models::SpecialFile),
+}
+
+impl Content {
+    /// The content as stored in a snapshot, where `hard_link` has the device and inode of the
+    /// links of a file with more than one.
+    pub fn to_model(&self, hard_link: Option<(u64, u64)>) -> models::Content {
+        match *self {
+            Content::Data(ref r) => match hard_link {
+                Some((dev, ino)) => models::Content::HardLink(r.to_model(), dev, ino),
+                None => models::Content::Data(r.to_model()),
+            },
+            Content::Dir(ref r) => models::Content::Directory(r.to_model()),
+            Content::Link(ref path) => {
+                models::Content::SymbolicLink(path.as_os_str().as_bytes().to_vec())
+            }
+            Content::Unreadable(ref error) => models::Content::Unreadable(error.clone()),
+            Content::Special(special) => models::Content::Special(special),
+        }
+    }
 }
 
 #[derive(Clone)]
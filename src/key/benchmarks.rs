@@ -38,7 +38,7 @@ fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
 
         ks_p.send_reply(Msg::Insert(
             entry.key_entry.clone(),
-            Some(Box::new(move |()| Some(entry))),
+            Some(Box::new(move |()| Ok(entry))),
         )).unwrap();
     });
 
@@ -70,7 +70,7 @@ fn insert_1_key_x_128000_unique(bench: &mut Bencher) {
 
         ks_p.send_reply(Msg::Insert(
             entry.key_entry.clone(),
-            Some(Box::new(move |()| Some(entry))),
+            Some(Box::new(move |()| Ok(entry))),
         )).unwrap();
     });
 
@@ -93,7 +93,7 @@ fn insert_1_key_x_16_x_128000_zeros(bench: &mut Bencher) {
         };
         ks_p.send_reply(Msg::Insert(
             entry.key_entry.clone(),
-            Some(Box::new(move |()| Some(entry))),
+            Some(Box::new(move |()| Ok(entry))),
         )).unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -137,7 +137,7 @@ fn insert_1_key_x_16_x_128000_unique(bench: &mut Bencher) {
 
         ks_p.send_reply(Msg::Insert(
             entry.key_entry.clone(),
-            Some(Box::new(move |()| Some(entry))),
+            Some(Box::new(move |()| Ok(entry))),
         )).unwrap();
 
         match ks_p.send_reply(Msg::Flush).unwrap() {
@@ -189,10 +189,16 @@ fn insert_1_key_updated_empty(bench: &mut Bencher) {
                     accessed_ts_secs: Some(i),
                     group_id: None,
                     user_id: None,
+                    user_name: None,
+                    group_name: None,
                     permissions: None,
                     byte_length: None,
                     snapshot_ts_utc: 0,
+                    hard_link: None,
+                    xattrs: vec![],
+                    acl: None,
                 },
+                checksum: None,
             },
         };
         ks_p.send_reply(Msg::Insert(entry.key_entry.clone(), None))
@@ -0,0 +1,358 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How file contents are split into chunks.
+//!
+//! Files are split into fixed size chunks by default. A `chunking` file in the state directory
+//! picks another profile by file name, one pattern and profile per line, optionally followed by
+//! the compression of the file's chunks:
+//!
+//! ```text
+//! # Disk images shift data around; cut them where the content says so.
+//! *.vmdk  cdc:1M          none
+//! *.iso   cdc:256K/1M/2M
+//! *.sql   fixed:16K       zstd:19
+//! ```
+//!
+//! The first matching pattern wins. Files without a compression of their own use the
+//! repository's. Patterns match the file name only, and support `*` and `?`.
+//!
+//! Where content defined chunks are cut depends on a key derived from the repository key, so
+//! the same file is cut differently in repositories with different keys.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::MAX_CHUNK_LEN;
+use blob::Compression;
+use crypto::keys::keyed_fingerprint_simple;
+use util::{glob_match, parse_size};
+
+/// Holds the chunking profiles of a state directory.
+pub const CHUNKING_FILENAME: &str = "chunking";
+
+/// Upper bound on the chunks any profile may produce, so a chunk always fits in a blob.
+pub const MAX_PROFILE_CHUNK_LEN: usize = 2 * 1024 * 1024;
+
+const MIN_PROFILE_CHUNK_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of exactly this many bytes (the last one may be shorter).
+    Fixed(usize),
+    /// Chunks cut where the content matches, about `avg` bytes on average and between `min`
+    /// and `max` bytes long (the last one may be shorter). Inserting data in a file only
+    /// changes the chunks around it.
+    ContentDefined { min: usize, avg: usize, max: usize },
+}
+
+impl Default for Chunking {
+    fn default() -> Chunking {
+        Chunking::Fixed(MAX_CHUNK_LEN)
+    }
+}
+
+impl Chunking {
+    /// Content defined chunks of about `avg` bytes, between a quarter and twice that long.
+    pub fn content_defined(avg: usize) -> Chunking {
+        Chunking::ContentDefined {
+            min: avg / 4,
+            avg: avg,
+            max: 2 * avg,
+        }
+    }
+
+    /// Parse `fixed:SIZE`, `cdc:AVG` or `cdc:MIN/AVG/MAX`.
+    pub fn parse(s: &str) -> Result<Chunking, String> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let sizes = match parts.next() {
+            Some(sizes) => sizes
+                .split('/')
+                .map(parse_chunk_size)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => return Err(format!("Missing chunk size in '{}'", s)),
+        };
+        let chunking = match (kind, &sizes[..]) {
+            ("fixed", &[size]) => Chunking::Fixed(size),
+            ("cdc", &[avg]) => Chunking::content_defined(avg),
+            ("cdc", &[min, avg, max]) if min <= avg && avg < max => {
+                Chunking::ContentDefined {
+                    min: min,
+                    avg: avg,
+                    max: max,
+                }
+            }
+            ("cdc", _) => {
+                return Err(format!(
+                    "Invalid chunk sizes in '{}'; use cdc:AVG or cdc:MIN/AVG/MAX, with \
+                     MIN <= AVG < MAX",
+                    s
+                ))
+            }
+            ("fixed", _) => return Err(format!("Invalid chunk size in '{}'", s)),
+            _ => return Err(format!("Unknown chunking '{}'; use fixed:SIZE or cdc:SIZE", kind)),
+        };
+        if sizes[0] < MIN_PROFILE_CHUNK_LEN || chunking.max_len() > MAX_PROFILE_CHUNK_LEN {
+            return Err(format!(
+                "Chunk size in '{}' must be between {} and {} bytes",
+                s,
+                MIN_PROFILE_CHUNK_LEN,
+                MAX_PROFILE_CHUNK_LEN
+            ));
+        }
+        Ok(chunking)
+    }
+
+    /// Length of the longest chunk.
+    pub fn max_len(&self) -> usize {
+        match *self {
+            Chunking::Fixed(len) => len,
+            Chunking::ContentDefined { max, .. } => max,
+        }
+    }
+}
+
+impl fmt::Display for Chunking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chunking::Fixed(len) => write!(f, "fixed:{}", len),
+            Chunking::ContentDefined { avg, .. } if *self == Chunking::content_defined(avg) => {
+                write!(f, "cdc:{}", avg)
+            }
+            Chunking::ContentDefined { min, avg, max } => {
+                write!(f, "cdc:{}/{}/{}", min, avg, max)
+            }
+        }
+    }
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let size = parse_size(s)?;
+    usize::try_from(size).map_err(|_| format!("Size too large: '{}'", s))
+}
+
+/// Chunking profiles by file name pattern.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkingProfiles {
+    rules: Vec<(Vec<u8>, Chunking, Option<Compression>)>,
+    gear: GearTable,
+}
+
+impl ChunkingProfiles {
+    pub fn parse(text: &str) -> Result<ChunkingProfiles, String> {
+        let mut rules = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 && fields.len() != 3 {
+                return Err(format!(
+                    "Line {} of the chunking profiles: expected a pattern, a profile and \
+                     optionally a compression",
+                    n + 1
+                ));
+            }
+            let chunking = Chunking::parse(fields[1])
+                .map_err(|e| format!("Line {} of the chunking profiles: {}", n + 1, e))?;
+            let compression = match fields.get(2) {
+                Some(c) => Some(
+                    Compression::parse(c)
+                        .map_err(|e| format!("Line {} of the chunking profiles: {}", n + 1, e))?,
+                ),
+                None => None,
+            };
+            rules.push((fields[0].as_bytes().to_vec(), chunking, compression));
+        }
+        Ok(ChunkingProfiles {
+            rules: rules,
+            gear: GearTable::default(),
+        })
+    }
+
+    /// Cut content defined chunks where keyed with `key`, rather than where every repository
+    /// would cut them.
+    pub fn with_key(mut self, key: &[u8]) -> ChunkingProfiles {
+        self.gear = GearTable::keyed(key);
+        self
+    }
+
+    /// The profiles of state directory `dir`; without a profile file, every file uses the
+    /// default chunking.
+    pub fn load(dir: &Path) -> Result<ChunkingProfiles, String> {
+        match fs::read_to_string(dir.join(CHUNKING_FILENAME)) {
+            Ok(text) => ChunkingProfiles::parse(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(ChunkingProfiles::default()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// The chunking for a file named `name`.
+    pub fn for_name(&self, name: &[u8]) -> Chunking {
+        self.rules
+            .iter()
+            .find(|(pattern, _, _)| glob_match(pattern, name))
+            .map(|&(_, chunking, _)| chunking)
+            .unwrap_or_default()
+    }
+
+    /// The compression for the chunks of a file named `name`, or `None` for the repository's.
+    pub fn compression_for_name(&self, name: &[u8]) -> Option<Compression> {
+        self.rules
+            .iter()
+            .find(|(pattern, _, _)| glob_match(pattern, name))
+            .and_then(|&(_, _, compression)| compression)
+    }
+
+    /// Splits `reader`, the contents of a file named `name`.
+    pub fn chunker<R: Read>(&self, reader: R, name: &[u8]) -> Chunker<R> {
+        let mut chunker = Chunker::new(reader, self.for_name(name));
+        chunker.gear = self.gear.clone();
+        chunker
+    }
+}
+
+/// The rules in order, ending with the default for files no pattern matches.
+impl fmt::Display for ChunkingProfiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pattern, chunking, compression) in &self.rules {
+            write!(f, "{} {}", String::from_utf8_lossy(pattern), chunking)?;
+            if let Some(compression) = compression {
+                write!(f, " {}", compression)?;
+            }
+            write!(f, ", ")?;
+        }
+        write!(f, "* {}", Chunking::default())
+    }
+}
+
+/// Splits a reader into chunks according to a `Chunking`.
+pub struct Chunker<R> {
+    reader: R,
+    chunking: Chunking,
+    gear: GearTable,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R, chunking: Chunking) -> Chunker<R> {
+        Chunker {
+            reader: reader,
+            chunking: chunking,
+            gear: GearTable::default(),
+            buf: vec![0; chunking.max_len()],
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    /// The next chunk, or `None` when the reader is exhausted.
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        // Keep what is left of the previous read, and fill up to the longest chunk.
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        while !self.eof && self.end < self.buf.len() {
+            match self.reader.read(&mut self.buf[self.end..]) {
+                Ok(0) => self.eof = true,
+                Ok(size) => self.end += size,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.end == 0 {
+            return Ok(None);
+        }
+
+        let len = self.cut_point(&self.buf[..self.end]);
+        self.start = len;
+        Ok(Some(&self.buf[..len]))
+    }
+
+    fn cut_point(&self, data: &[u8]) -> usize {
+        match self.chunking {
+            Chunking::Fixed(len) => len.min(data.len()),
+            Chunking::ContentDefined { min, avg, max } => {
+                if data.len() <= min {
+                    return data.len();
+                }
+                // Cut where the low bits of a hash over the last 64 bytes are all zero.
+                let mask = (1u64 << (63 - (avg as u64).leading_zeros())) - 1;
+                let limit = data.len().min(max);
+                let mut hash = 0u64;
+                for (i, &b) in data.iter().enumerate().take(limit).skip(min) {
+                    hash = (hash << 1).wrapping_add(self.gear.0[b as usize]);
+                    if hash & mask == 0 {
+                        return i + 1;
+                    }
+                }
+                limit
+            }
+        }
+    }
+}
+
+/// Values for the rolling hash. They decide where chunks are cut, so changing them would stop
+/// new snapshots from deduplicating against old ones.
+#[derive(Clone, PartialEq, Eq)]
+struct GearTable(Arc<[u64; 256]>);
+
+impl GearTable {
+    /// Values only known to holders of `key`.
+    fn keyed(key: &[u8]) -> GearTable {
+        let mut table = [0u64; 256];
+        let mut out = [0u8; 16];
+        for (i, v) in table.iter_mut().enumerate() {
+            keyed_fingerprint_simple(key, &[i as u8], &mut out);
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&out[..8]);
+            *v = u64::from_le_bytes(bytes);
+        }
+        GearTable(Arc::new(table))
+    }
+}
+
+/// Random values shared by every repository, for chunkers without a key.
+impl Default for GearTable {
+    fn default() -> GearTable {
+        let mut table = [0u64; 256];
+        let mut state = 0x6861_742d_6364_6321u64;
+        for v in table.iter_mut() {
+            // SplitMix64.
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *v = z ^ (z >> 31);
+        }
+        GearTable(Arc::new(table))
+    }
+}
+
+/// The values themselves stay out of logs.
+impl fmt::Debug for GearTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GearTable")
+    }
+}
@@ -15,7 +15,6 @@
 use backend::StoreBackend;
 use blob;
 use crypto;
-use errors::RetryError;
 use hash;
 use hash::tree::HashTreeBackend;
 use key;
@@ -26,6 +25,11 @@ pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    /// Compression of file data chunks, instead of the blob store's.
+    compression: Option<blob::Compression>,
+    /// The family whose file data is stored, to tell data it stored before from data stored by
+    /// other families.
+    family: Option<String>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
@@ -33,6 +37,8 @@ impl<B> Clone for HashStoreBackend<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            compression: self.compression,
+            family: self.family.clone(),
         }
     }
 }
@@ -47,8 +53,25 @@ impl<B: StoreBackend> HashStoreBackend<B> {
             hash_index: hash_index,
             blob_store: blob_store,
             keys: keys,
+            compression: None,
+            family: None,
         }
     }
+
+    /// Compress file data chunks with `compression`, or as the blob store does if `None`.
+    pub fn with_compression(
+        mut self,
+        compression: Option<blob::Compression>,
+    ) -> HashStoreBackend<B> {
+        self.compression = compression;
+        self
+    }
+
+    /// Store file data on behalf of `family`.
+    pub fn with_family(mut self, family: Option<String>) -> HashStoreBackend<B> {
+        self.family = family;
+        self
+    }
 }
 
 impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
@@ -57,29 +80,14 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
     fn fetch_chunk(&self, href: &hash::tree::HashRef) -> Result<Option<Vec<u8>>, MsgError> {
         assert!(!href.hash.bytes.is_empty());
 
-        Ok(self.blob_store.retrieve(&href)?.and_then(|data| {
-            let actual_hash = hash::Hash::new(&self.keys, href.node, href.leaf, &data[..]);
-            if href.hash == actual_hash {
-                Some(data)
-            } else {
-                error!(
-                    "Data hash does not match expectation: {:?} instead of {:?}",
-                    actual_hash, href.hash
-                );
-                None
-            }
-        }))
+        // The blob store checks the chunk against its hash before returning it.
+        Ok(self.blob_store.retrieve(href)?)
     }
 
     fn fetch_persistent_ref(&self, hash: &hash::Hash) -> Option<blob::ChunkRef> {
         assert!(!hash.bytes.is_empty());
-        loop {
-            match self.hash_index.fetch_persistent_ref(hash) {
-                Ok(Some(r)) => return Some(r), // done
-                Ok(None) => return None,       // done
-                Err(RetryError) => (),         // continue loop
-            }
-        }
+        // A chunk still being stored by another key store is waited for, not stored again.
+        self.hash_index.wait_persistent_ref(hash)
     }
 
     fn fetch_childs(&self, hash: &hash::Hash) -> Option<Vec<u64>> {
@@ -89,6 +97,10 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         }
     }
 
+    fn fetch_id(&self, hash: &hash::Hash) -> Option<u64> {
+        self.hash_index.get_id(hash)
+    }
+
     fn insert_chunk(
         &self,
         chunk: &[u8],
@@ -116,6 +128,12 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 );
 
                 // Someone came before us: piggyback on their result.
+                if node == blob::NodeType::Leaf && leaf == blob::LeafType::FileChunk {
+                    let shared = self.family.as_ref().is_some_and(|family| {
+                        self.hash_index.family(id).is_some_and(|first| first != *family)
+                    });
+                    self.blob_store.count_reused(chunk.len(), shared);
+                }
                 let pref = self
                     .fetch_persistent_ref(&hash_entry.hash)
                     .expect("Could not find persistent ref for known hash");
@@ -127,6 +145,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                         leaf: leaf,
                         info: None,
                         persistent_ref: pref,
+                        range: None,
                     },
                 ))
             }
@@ -140,6 +159,11 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 );
 
                 // We came first: this data-chunk is ours to process.
+                if let (Some(ref family), blob::NodeType::Leaf, blob::LeafType::FileChunk) =
+                    (&self.family, node, leaf)
+                {
+                    self.hash_index.set_family(id, family);
+                }
                 let local_hash_index = self.hash_index.clone();
 
                 let m = Arc::new(Mutex::new(()));
@@ -153,14 +177,27 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                     drop(guard);
                 });
 
-                let href = self.blob_store.store(
-                    chunk,
-                    hash_entry.hash.clone(),
-                    node,
-                    leaf,
-                    info,
-                    callback,
-                );
+                let href = match self.compression {
+                    Some(compression)
+                        if node == blob::NodeType::Leaf && leaf == blob::LeafType::FileChunk =>
+                    {
+                        self.blob_store.store_file_chunk(
+                            chunk,
+                            hash_entry.hash.clone(),
+                            info,
+                            compression,
+                            callback,
+                        )?
+                    }
+                    _ => self.blob_store.store(
+                        chunk,
+                        hash_entry.hash.clone(),
+                        node,
+                        leaf,
+                        info,
+                        callback,
+                    )?,
+                };
 
                 // Update the hash entry now to enable reuse before the hash is fully committed.
                 hash_entry.persistent_ref = Some(href.persistent_ref.clone());
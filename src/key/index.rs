@@ -28,6 +28,7 @@ use errors::DieselError;
 use filetime::FileTime;
 use hash;
 use models;
+use serde_cbor;
 
 use std::sync::{Mutex, MutexGuard};
 
@@ -43,6 +44,10 @@ pub enum Data {
     FileHash(Vec<u8>),
     DirPlaceholder,
     Symlink(PathBuf),
+    /// A file that could not be read, with the error.
+    Unreadable(String),
+    /// A device node, FIFO or socket.
+    Special(models::SpecialFile),
 }
 
 #[derive(Clone, Debug)]
@@ -52,6 +57,8 @@ pub struct Entry {
 
     pub data: Data,
     pub info: Info,
+    /// BLAKE2b-512 of the file contents, as printed by `b2sum`.
+    pub checksum: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -65,9 +72,20 @@ pub struct Info {
     pub permissions: Option<fs::Permissions>,
     pub user_id: Option<u64>,
     pub group_id: Option<u64>,
+    /// Names of the owners, as recorded in snapshots; the key index does not keep them.
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
 
     pub byte_length: Option<u64>,
     pub snapshot_ts_utc: i64,
+
+    /// Device and inode of a file with more than one hard link; the links in a snapshot that
+    /// share them are restored as links to the same file.
+    pub hard_link: Option<(u64, u64)>,
+    /// Extended attributes, as name and value, ordered by name.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// POSIX ACLs beyond the permissions.
+    pub acl: Option<models::Acl>,
 }
 
 impl Entry {
@@ -82,16 +100,64 @@ impl Entry {
             parent_id: parent,
             data: data,
             info: Info::new(name, meta),
+            checksum: None,
+        }
+    }
+
+    /// The kind of a special file entry.
+    fn special(&self) -> Option<models::SpecialFile> {
+        match self.data {
+            Data::Special(s) => Some(s),
+            _ => None,
         }
     }
 
+    /// Whether `them` likely holds the data of this entry. A checksum known up front, as for
+    /// contents replaced by a content filter, must match as well.
     pub fn data_looks_unchanged(&self, them: &Entry) -> bool {
         self.info.modified_ts_secs.is_some()
             && ((self.parent_id, &self.info.name, self.info.modified_ts_secs)
                 == (them.parent_id, &them.info.name, them.info.modified_ts_secs))
+            && self.info.hard_link == them.info.hard_link
+            && self.info.xattrs == them.info.xattrs
+            && self.info.acl == them.info.acl
+            && self.special() == them.special()
+            && (self.checksum.is_none() || self.checksum == them.checksum)
     }
 }
 
+/// The device and inode of a file with several links, as stored in the index.
+fn stored_hard_link(data: &schema::KeyData) -> Option<(u64, u64)> {
+    let bytes = data.hard_link.as_ref().filter(|b| b.len() == 16)?;
+    let mut dev = [0u8; 8];
+    let mut ino = [0u8; 8];
+    dev.copy_from_slice(&bytes[..8]);
+    ino.copy_from_slice(&bytes[8..]);
+    Some((u64::from_be_bytes(dev), u64::from_be_bytes(ino)))
+}
+
+/// The kind of a special file, as stored in the index.
+fn stored_special(data: &schema::KeyData) -> Option<models::SpecialFile> {
+    data.special
+        .as_ref()
+        .and_then(|bytes| serde_cbor::from_slice(bytes).ok())
+}
+
+/// The ACLs of an entry, as stored in the index.
+fn stored_acl(data: &schema::KeyData) -> Option<models::Acl> {
+    data.acl
+        .as_ref()
+        .and_then(|bytes| serde_cbor::from_slice(bytes).ok())
+}
+
+/// The extended attributes of an entry, as stored in the index.
+fn stored_xattrs(data: &schema::KeyData) -> Vec<(Vec<u8>, Vec<u8>)> {
+    data.xattrs
+        .as_ref()
+        .and_then(|bytes| serde_cbor::from_slice(bytes).ok())
+        .unwrap_or_default()
+}
+
 impl From<models::FileInfo> for Info {
     fn from(info: models::FileInfo) -> Info {
         fn none_if_zero_i64(x: i64) -> Option<i64> {
@@ -109,6 +175,11 @@ impl From<models::FileInfo> for Info {
             }
         }
 
+        let (user_name, group_name) = match info.owner {
+            models::Owner::None => (None, None),
+            models::Owner::UserGroup(ref ug) => (ug.user_name.clone(), ug.group_name.clone()),
+        };
+
         Info {
             name: info.name,
             created_ts_secs: none_if_zero_i64(info.created_ts),
@@ -127,7 +198,12 @@ impl From<models::FileInfo> for Info {
                 models::Owner::None => None,
                 models::Owner::UserGroup(ref ug) => Some(ug.group_id as u64),
             },
+            user_name: user_name,
+            group_name: group_name,
             snapshot_ts_utc: info.snapshot_ts_utc,
+            hard_link: None,
+            xattrs: info.xattrs,
+            acl: info.acl,
         }
     }
 }
@@ -153,9 +229,17 @@ impl Info {
 
             user_id: meta.map(|m| m.st_uid() as u64),
             group_id: meta.map(|m| m.st_gid() as u64),
+            user_name: None,
+            group_name: None,
 
             byte_length: meta.map(|m| m.len()),
             snapshot_ts_utc: chrono::Utc::now().timestamp(),
+
+            hard_link: meta
+                .filter(|m| m.is_file() && m.st_nlink() > 1)
+                .map(|m| (m.st_dev(), m.st_ino())),
+            xattrs: vec![],
+            acl: None,
         }
     }
 
@@ -164,6 +248,8 @@ impl Info {
             (Some(user_id), Some(group_id)) => models::Owner::UserGroup(models::UserGroup {
                 user_id: user_id as i64,
                 group_id: group_id as i64,
+                user_name: self.user_name.clone(),
+                group_name: self.group_name.clone(),
             }),
             _ => models::Owner::None,
         };
@@ -180,6 +266,8 @@ impl Info {
             byte_length: self.byte_length.unwrap_or(0) as i64,
             owner: owner,
             snapshot_ts_utc: self.snapshot_ts_utc,
+            xattrs: self.xattrs.clone(),
+            acl: self.acl.clone(),
         }
     }
 }
@@ -276,13 +364,33 @@ impl InternalKeyIndex {
 
         {
             let link_path = match &entry.data {
-                &Data::DirPlaceholder | &Data::FilePlaceholder => None,
+                &Data::DirPlaceholder
+                | &Data::FilePlaceholder
+                | &Data::Unreadable(_)
+                | &Data::Special(_) => None,
                 &Data::Symlink(ref path) => path.to_str(),
                 &Data::FileHash(_) => unreachable!("Unexpected FileHash"),
             };
             assert!(!(link_path.is_some() && hash_ref_opt.is_some()));
+            let error_text = match entry.data {
+                Data::Unreadable(ref e) => Some(&e[..]),
+                _ => None,
+            };
 
             let hash_ref_bytes = hash_ref_opt.map(|r| r.as_bytes());
+            let hard_link_bytes = entry.info.hard_link.map(|(dev, ino)| {
+                [dev.to_be_bytes(), ino.to_be_bytes()].concat()
+            });
+            let special_bytes = match entry.data {
+                Data::Special(ref s) => Some(serde_cbor::to_vec(s).unwrap()),
+                _ => None,
+            };
+            let acl_bytes = entry.info.acl.as_ref().map(|a| serde_cbor::to_vec(a).unwrap());
+            let xattrs_bytes = if entry.info.xattrs.is_empty() {
+                None
+            } else {
+                Some(serde_cbor::to_vec(&entry.info.xattrs).unwrap())
+            };
             let new = schema::NewKeyData {
                 node_id: entry.node_id.map(|i| i as i64),
                 committed: false,
@@ -297,6 +405,12 @@ impl InternalKeyIndex {
                 symbolic_link_path: link_path.map(|s| s.as_bytes()),
                 hash: hash_ref_opt.map(|h| &h.hash.bytes[..]),
                 hash_ref: hash_ref_bytes.as_ref().map(|v| &v[..]),
+                read_error: error_text,
+                checksum: entry.checksum.as_ref().map(|c| &c[..]),
+                hard_link: hard_link_bytes.as_ref().map(|b| &b[..]),
+                xattrs: xattrs_bytes.as_ref().map(|b| &b[..]),
+                special: special_bytes.as_ref().map(|b| &b[..]),
+                acl: acl_bytes.as_ref().map(|b| &b[..]),
             };
 
             // Insert replaces when (node_id, committed) already exists.
@@ -340,13 +454,17 @@ impl InternalKeyIndex {
         };
 
         if let Some((node, data)) = row_opt {
+            let (links, attrs) = (stored_hard_link(&data), stored_xattrs(&data));
+            let (kind, access) = (stored_special(&data), stored_acl(&data));
             Ok(Some(Entry {
                 node_id: node.node_id.map(|n| n as u64),
                 parent_id: node.parent_id.map(|p| p as u64),
-                data: data
-                    .hash
-                    .map(|h| Data::FileHash(h))
-                    .unwrap_or(Data::DirPlaceholder),
+                data: match (data.hash, data.read_error, kind) {
+                    (Some(h), _, _) => Data::FileHash(h),
+                    (None, Some(e), _) => Data::Unreadable(e),
+                    (None, None, Some(s)) => Data::Special(s),
+                    (None, None, None) => Data::DirPlaceholder,
+                },
 
                 info: Info {
                     name: name_,
@@ -358,9 +476,15 @@ impl InternalKeyIndex {
                         .map(|m| fs::Permissions::from_mode(m as u32)),
                     user_id: data.user_id.map(|x| x as u64),
                     group_id: data.group_id.map(|x| x as u64),
+                    user_name: None,
+                    group_name: None,
                     byte_length: data.file_size.map(|x| x as u64),
                     snapshot_ts_utc: 0,
+                    hard_link: links,
+                    xattrs: attrs,
+                    acl: access,
                 },
+                checksum: data.checksum,
             }))
         } else {
             Ok(None)
@@ -368,27 +492,34 @@ impl InternalKeyIndex {
     }
 
     /// List a directory (aka. `level`) in the index.
-    /// Returns `ListResult` with all the entries under the given parent.
+    /// Returns at most `limit` entries under the given parent with node ids above `after`,
+    /// ordered by node id, so a huge directory can be listed a page at a time.
     fn list_dir(
         &mut self,
         parent_opt: Option<u64>,
+        after: Option<u64>,
+        limit: Option<usize>,
     ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
         use super::schema::key_data::dsl::{committed, key_data};
         use super::schema::key_tree::dsl::*;
         use diesel::prelude::*;
 
-        let rows = match parent_opt {
-            Some(p) => key_tree
-                .inner_join(key_data)
-                .filter(parent_id.eq(p as i64))
-                .filter(committed.eq(true))
-                .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?,
-            None => key_tree
-                .inner_join(key_data)
-                .filter(parent_id.is_null())
-                .filter(committed.eq(true))
-                .load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?,
+        let mut query = key_tree
+            .inner_join(key_data)
+            .filter(committed.eq(true))
+            .order(node_id)
+            .into_boxed();
+        query = match parent_opt {
+            Some(p) => query.filter(parent_id.eq(p as i64)),
+            None => query.filter(parent_id.is_null()),
         };
+        if let Some(after) = after {
+            query = query.filter(node_id.gt(after as i64));
+        }
+        if let Some(limit) = limit {
+            query = query.limit(limit as i64);
+        }
+        let rows = query.load::<(schema::KeyNode, schema::KeyData)>(&self.conn)?;
 
         Ok(rows
             .into_iter()
@@ -397,9 +528,13 @@ impl InternalKeyIndex {
                     Entry {
                         node_id: node.node_id.map(|n| n as u64),
                         parent_id: node.parent_id.map(|i| i as u64),
-                        data: match (data.hash.as_ref(), data.symbolic_link_path) {
+                        data: match (data.hash.as_ref(), data.symbolic_link_path.take()) {
                             (Some(_), None) => Data::FilePlaceholder,
-                            (None, None) => Data::DirPlaceholder,
+                            (None, None) => match (data.read_error.take(), stored_special(&data)) {
+                                (Some(e), _) => Data::Unreadable(e),
+                                (None, Some(s)) => Data::Special(s),
+                                (None, None) => Data::DirPlaceholder,
+                            },
                             (None, Some(path)) => {
                                 Data::Symlink(PathBuf::from(str::from_utf8(&path[..]).unwrap()))
                             }
@@ -417,9 +552,15 @@ impl InternalKeyIndex {
                                 .map(|m| fs::Permissions::from_mode(m as u32)),
                             user_id: data.user_id.map(|x| x as u64),
                             group_id: data.group_id.map(|x| x as u64),
+                            user_name: None,
+                            group_name: None,
                             byte_length: data.file_size.map(|x| x as u64),
                             snapshot_ts_utc: 0,
+                            hard_link: stored_hard_link(&data),
+                            xattrs: stored_xattrs(&data),
+                            acl: stored_acl(&data),
                         },
+                        checksum: data.checksum.take(),
                     },
                     data.hash_ref
                         .as_mut()
@@ -485,6 +626,24 @@ impl InternalKeyIndex {
 
         Ok(())
     }
+
+    /// Delete nodes that no longer have any data, together with everything below them.
+    /// Such nodes are left behind by commits that failed half-way and are never looked up.
+    fn prune_orphans(&mut self) -> Result<usize, DieselError> {
+        use super::schema::key_data::dsl::{key_data, node_id as data_node_id};
+        use super::schema::key_tree::dsl::*;
+
+        let live = key_data
+            .select(data_node_id)
+            .filter(data_node_id.is_not_null());
+        let count =
+            diesel::delete(key_tree.filter(diesel::dsl::not(node_id.eq_any(live))))
+                .execute(&self.conn)?;
+
+        self.flush()?;
+
+        Ok(count)
+    }
 }
 
 impl KeyIndex {
@@ -521,7 +680,16 @@ impl KeyIndex {
         &self,
         parent_opt: Option<u64>,
     ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
-        self.lock().list_dir(parent_opt)
+        self.lock().list_dir(parent_opt, None, None)
+    }
+
+    pub fn list_dir_page(
+        &self,
+        parent_opt: Option<u64>,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(Entry, Option<hash::tree::HashRef>)>, DieselError> {
+        self.lock().list_dir(parent_opt, after, Some(limit))
     }
 
     pub fn mark_reserved(&self, entry: &Entry) -> Result<(), DieselError> {
@@ -536,6 +704,10 @@ impl KeyIndex {
         self.lock().cleanup_unused(parent_opt)
     }
 
+    pub fn prune_orphans(&self) -> Result<usize, DieselError> {
+        self.lock().prune_orphans()
+    }
+
     pub fn flush(&self) -> Result<(), DieselError> {
         self.lock().flush()
     }
@@ -21,22 +21,27 @@ use errors::{DieselError, RetryError};
 use hash;
 use hash::tree::{LeafIterator, SimpleHashTreeWriter};
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::io;
 use std::sync::Arc;
 
 use util::{FnBox, MsgHandler, Process};
 
+mod chunking;
 mod hash_store_backend;
 mod index;
 mod schema;
+mod small_files;
 
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 #[cfg(test)]
 mod tests;
 
+pub use self::chunking::{Chunker, Chunking, ChunkingProfiles, CHUNKING_FILENAME};
 pub use self::hash_store_backend::HashStoreBackend;
 pub use self::index::{Data, Entry, Info, KeyIndex};
+pub use self::small_files::{SmallFiles, BATCH_MAX_FILE_LEN};
 
 error_type! {
     #[derive(Debug)]
@@ -60,6 +65,9 @@ error_type! {
 
 pub type StoreProcess<IT, B> = Process<Msg<IT>, Reply<B>, MsgError>;
 
+/// File contents are split into chunks of this size by default (the last chunk may be shorter).
+pub const MAX_CHUNK_LEN: usize = 128 * 1024;
+
 pub type DirElem<B> = (
     Entry,
     Option<hash::tree::HashRef>,
@@ -84,13 +92,19 @@ impl<B: StoreBackend> HashTreeReaderInitializer<B> {
 pub enum Msg<IT> {
     /// Insert a key into the index. If this key has associated data a "chunk-iterator creator"
     /// can be passed along with it. If the data turns out to be unreadable, this iterator proc
-    /// can return `None`. Returns `Id` with the new entry ID.
-    Insert(Entry, Option<Box<FnBox<(), Option<IT>>>>),
+    /// returns the error, which is recorded in place of the data. Returns `Id` with the new
+    /// entry ID.
+    Insert(Entry, Option<Box<FnBox<(), Result<IT, String>>>>),
 
     /// List a "directory" (aka. a `level`) in the index.
     /// Returns `ListResult` with all the entries under the given parent.
     ListDir(Option<u64>),
 
+    /// List at most `limit` entries of a directory with node ids above the given one, ordered
+    /// by node id, so huge directories can be listed a page at a time.
+    /// Returns `ListResult`.
+    ListDirPage(Option<u64>, Option<u64>, usize),
+
     /// Commit all reserved nodes and optionally execute recursive cleanup of part of the tree.
     /// Returns `Ok`.
     CommitReservedNodes(Option<Option<u64>>),
@@ -112,6 +126,11 @@ pub struct Store<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     keys: Arc<crypto::keys::Keeper>,
+    chunking: Arc<ChunkingProfiles>,
+    /// The family this store belongs to, see `HashStoreBackend::with_family`.
+    family: Option<String>,
+    /// Small files read by this store that are not stored yet. Clones start without any.
+    small_files: SmallFiles,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -120,6 +139,9 @@ impl<B> Clone for Store<B> {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
             keys: self.keys.clone(),
+            chunking: self.chunking.clone(),
+            family: self.family.clone(),
+            small_files: SmallFiles::new(),
         }
     }
 }
@@ -137,9 +159,24 @@ impl<B: StoreBackend> Store<B> {
             hash_index,
             blob_store,
             keys,
+            chunking: Arc::new(ChunkingProfiles::default()),
+            family: None,
+            small_files: SmallFiles::new(),
         }
     }
 
+    /// Split file contents according to `chunking` instead of the default.
+    pub fn with_chunking(mut self, chunking: Arc<ChunkingProfiles>) -> Store<B> {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Store file data on behalf of the family `family`.
+    pub fn with_family(mut self, family: String) -> Store<B> {
+        self.family = Some(family);
+        self
+    }
+
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         use crypto;
@@ -161,11 +198,15 @@ impl<B: StoreBackend> Store<B> {
             hash_index: hi_p,
             blob_store: bs_p,
             keys: Arc::new(crypto::keys::Keeper::new_for_testing()),
+            chunking: Arc::new(ChunkingProfiles::default()),
+            family: None,
+            small_files: SmallFiles::new(),
         })
     }
 
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        self.flush_small_files()?;
+        self.blob_store.flush()?;
         self.hash_index.flush();
         self.index.flush()?;
 
@@ -180,9 +221,85 @@ impl<B: StoreBackend> Store<B> {
             self.hash_index.clone(),
             self.blob_store.clone(),
             self.keys.clone(),
-        );
+        ).with_family(self.family.clone());
         SimpleHashTreeWriter::new(leaf, 8, backend)
     }
+
+    /// A writer for the contents of the file `name`, compressed as its chunking profile says.
+    fn file_tree_writer(&mut self, name: &[u8]) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
+        let backend = HashStoreBackend::new(
+            self.hash_index.clone(),
+            self.blob_store.clone(),
+            self.keys.clone(),
+        ).with_compression(self.chunking.compression_for_name(name))
+            .with_family(self.family.clone());
+        SimpleHashTreeWriter::new(blob::LeafType::FileChunk, 8, backend)
+    }
+
+    /// Store the batched small files in a composite leaf, and record their parts of it.
+    pub fn flush_small_files(&mut self) -> Result<(), MsgError> {
+        if self.small_files.is_empty() {
+            return Ok(());
+        }
+        let (data, files) = self.small_files.take();
+        let mut tree = self.hash_tree_writer(blob::LeafType::FileChunk);
+        tree.append(&data)?;
+        let leaf = tree.hash(None)?;
+        for (entry, offset, len) in files {
+            let mut hash_ref = leaf.clone();
+            hash_ref.range = Some((offset, len));
+            self.index.insert(entry, Some(&hash_ref))?;
+        }
+        Ok(())
+    }
+
+    /// The stored entry with the name and parent of `entry`, if any.
+    pub fn lookup(&self, entry: &Entry) -> Result<Option<Entry>, MsgError> {
+        Ok(self.index.lookup(entry.parent_id, entry.info.name.clone())?)
+    }
+
+    /// Whether inserting `entry` can keep `stored` as it is, without reading the data of
+    /// `entry`, if `has_data`.
+    pub fn is_unchanged(&self, entry: &Entry, stored: &Entry, has_data: bool) -> bool {
+        if !entry.data_looks_unchanged(stored) {
+            return false;
+        }
+        match stored.data {
+            // Files stored before checksums were recorded are read once more.
+            Data::FileHash(ref hash_bytes) if has_data && stored.checksum.is_some() => {
+                self.hash_index.hash_exists(&hash::Hash {
+                    bytes: hash_bytes.to_vec(),
+                })
+            }
+            _ => !has_data,
+        }
+    }
+
+    /// Split the contents read from `it` into chunks as inserting the file `name` would.
+    /// Returns the number of bytes read, and the number of those in chunks that are neither
+    /// stored nor in `seen`; their hashes are added to `seen`.
+    pub fn unknown_chunk_bytes<R: io::Read>(
+        &self,
+        it: R,
+        name: &[u8],
+        seen: &mut HashSet<Vec<u8>>,
+    ) -> Result<(u64, u64), io::Error> {
+        let mut chunker = self.chunking.chunker(it, name);
+        let (mut total, mut unknown) = (0, 0);
+        while let Some(chunk) = chunker.next_chunk()? {
+            total += chunk.len() as u64;
+            let hash = hash::Hash::new(
+                &self.keys,
+                blob::NodeType::Leaf,
+                blob::LeafType::FileChunk,
+                chunk,
+            );
+            if !self.hash_index.hash_exists(&hash) && seen.insert(hash.bytes) {
+                unknown += chunk.len() as u64;
+            }
+        }
+        Ok((total, unknown))
+    }
 }
 
 fn file_size_warning(name: &str, wanted: u64, got: u64) {
@@ -199,6 +316,33 @@ fn file_size_warning(name: &str, wanted: u64, got: u64) {
     }
 }
 
+impl<B: StoreBackend> Store<B> {
+    /// Attach the data reference and a reader to each listed entry that has data.
+    fn dir_elems(&self, entries: Vec<(Entry, Option<hash::tree::HashRef>)>) -> Vec<DirElem<B>> {
+        let mut my_entries: Vec<DirElem<B>> = Vec::with_capacity(entries.len());
+        for (entry, hash_ref_opt) in entries {
+            let hash_ref = hash_ref_opt.or_else(|| match entry.data {
+                Data::FileHash(ref hash_bytes) => {
+                    let h = hash::Hash {
+                        bytes: hash_bytes.clone(),
+                    };
+                    self.hash_index.fetch_hash_ref(&h).expect("Unknown hash")
+                }
+                _ => None,
+            });
+            let open_fn = hash_ref.as_ref().map(|r| HashTreeReaderInitializer {
+                hash_ref: r.clone(),
+                hash_index: self.hash_index.clone(),
+                blob_store: self.blob_store.clone(),
+                keys: self.keys.clone(),
+            });
+
+            my_entries.push((entry, hash_ref, open_fn));
+        }
+        my_entries
+    }
+}
+
 impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
     type Err = MsgError;
 
@@ -223,34 +367,25 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                 reply_ok!(Reply::FlushOk)
             }
 
-            Msg::ListDir(parent) => match self.index.list_dir(parent) {
-                Ok(entries) => {
-                    let mut my_entries: Vec<DirElem<B>> = Vec::with_capacity(entries.len());
-                    for (entry, hash_ref_opt) in entries {
-                        let hash_ref = hash_ref_opt.or_else(|| match entry.data {
-                            Data::FileHash(ref hash_bytes) => {
-                                let h = hash::Hash {
-                                    bytes: hash_bytes.clone(),
-                                };
-                                self.hash_index.fetch_hash_ref(&h).expect("Unknown hash")
-                            }
-                            _ => None,
-                        });
-                        let open_fn = hash_ref.as_ref().map(|r| HashTreeReaderInitializer {
-                            hash_ref: r.clone(),
-                            hash_index: self.hash_index.clone(),
-                            blob_store: self.blob_store.clone(),
-                            keys: self.keys.clone(),
-                        });
-
-                        my_entries.push((entry, hash_ref, open_fn));
-                    }
-                    reply_ok!(Reply::ListResult(my_entries))
+            Msg::ListDir(parent) => {
+                // Batched files have no data reference until their leaf is stored.
+                self.flush_small_files()?;
+                match self.index.list_dir(parent) {
+                    Ok(entries) => reply_ok!(Reply::ListResult(self.dir_elems(entries))),
+                    Err(e) => reply_err!(From::from(e)),
                 }
-                Err(e) => reply_err!(From::from(e)),
-            },
+            }
+
+            Msg::ListDirPage(parent, after, limit) => {
+                self.flush_small_files()?;
+                match self.index.list_dir_page(parent, after, limit) {
+                    Ok(entries) => reply_ok!(Reply::ListResult(self.dir_elems(entries))),
+                    Err(e) => reply_err!(From::from(e)),
+                }
+            }
 
             Msg::CommitReservedNodes(clean_parent_opt) => {
+                self.flush_small_files()?;
                 self.index.commit_reserved_nodes()?;
                 if let Some(parent) = clean_parent_opt {
                     self.index.cleanup_unused(parent)?;
@@ -259,78 +394,93 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
             }
 
             Msg::Insert(insert_entry, chunk_it_opt) => {
-                let entry = match self
-                    .index
-                    .lookup(insert_entry.parent_id, insert_entry.info.name.clone())?
-                {
-                    Some(ref stored_entry) if insert_entry.data_looks_unchanged(stored_entry) => {
-                        match stored_entry.data {
-                            Data::FileHash(ref hash_bytes) if chunk_it_opt.is_some() => {
-                                let hash = hash::Hash {
-                                    bytes: hash_bytes.to_vec(),
-                                };
-                                if self.hash_index.hash_exists(&hash) {
-                                    // Short-circuit: We have the data.
-                                    debug!("Skip entry: {:?}", stored_entry.info.name);
-                                    self.index.mark_reserved(stored_entry)?;
-                                    return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                                }
-                            }
-                            _ if chunk_it_opt.is_none() => {
-                                // Short-circuit: No data needed.
-                                debug!("Skip empty entry: {:?}", stored_entry.info.name);
-                                self.index.mark_reserved(stored_entry)?;
-                                return reply_ok!(Reply::Id(stored_entry.node_id.unwrap()));
-                            }
-                            _ => (),
-                        }
-                        // Our stored entry is incomplete.
-                        Entry {
-                            node_id: stored_entry.node_id,
-                            ..insert_entry
-                        }
+                let stored = self.lookup(&insert_entry)?;
+                let entry = match stored {
+                    Some(ref stored)
+                        if self.is_unchanged(&insert_entry, stored, chunk_it_opt.is_some()) =>
+                    {
+                        // Short-circuit: We have the data, or no data is needed.
+                        debug!("Skip entry: {:?}", stored.info.name);
+                        self.index.mark_reserved(stored)?;
+                        return reply_ok!(Reply::Id(stored.node_id.unwrap()));
                     }
-                    Some(entry) => Entry {
-                        node_id: entry.node_id,
+                    // Our stored entry is incomplete or outdated.
+                    Some(stored) => Entry {
+                        node_id: stored.node_id,
                         ..insert_entry
                     },
                     None => insert_entry,
                 };
 
                 // Check if we have an data source:
-                let it_opt = chunk_it_opt.and_then(|open| open.call(()));
-                if it_opt.is_none() {
-                    // No data is associated with this entry.
-                    debug!("Insert entry: {:?}", entry.info.name);
-                    let entry = self.index.insert(entry, None)?;
+                let it = match chunk_it_opt.map(|open| open.call(())) {
+                    Some(Ok(it)) => it,
+                    Some(Err(e)) => {
+                        // Record the error, so the snapshot shows what it is missing.
+                        println!("Skipping '{}': {}", entry.info.name.utf8(), e);
+                        let entry = Entry {
+                            data: Data::Unreadable(e),
+                            ..entry
+                        };
+                        let entry = self.index.insert(entry, None)?;
+                        return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+                    }
+                    None => {
+                        // No data is associated with this entry.
+                        debug!("Insert entry: {:?}", entry.info.name);
+                        let entry = self.index.insert(entry, None)?;
 
-                    // Bail out before storing data that does not exist:
-                    return reply_ok!(Reply::Id(entry.node_id.unwrap()));
-                }
+                        // Bail out before storing data that does not exist:
+                        return reply_ok!(Reply::Id(entry.node_id.unwrap()));
+                    }
+                };
 
                 // Setup hash tree structure
-                let mut tree = self.hash_tree_writer(blob::LeafType::FileChunk);
+                let mut tree = self.file_tree_writer(entry.info.name.as_bytes());
 
                 // Read and insert all file chunks:
                 // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
-                let mut reader = it_opt.unwrap();
+                let mut chunker = self.chunking.chunker(it, entry.info.name.as_bytes());
+                let mut checksum = crypto::keys::Checksum::new();
                 let mut file_len = 0u64;
+                let mut read_error = None;
+                // The chunks of a file that may still be small enough to batch.
+                let mut small = Some(vec![]);
                 loop {
-                    let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
-                        chunk_len += match reader.read(&mut chunk[chunk_len..]) {
-                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
-                            Ok(size) => size,
+                    match chunker.next_chunk() {
+                        Ok(Some(chunk)) => {
+                            file_len += chunk.len() as u64;
+                            checksum.update(chunk);
+                            match small {
+                                Some(ref mut chunks) if file_len <= BATCH_MAX_FILE_LEN => {
+                                    chunks.push(chunk.to_vec());
+                                    continue;
+                                }
+                                _ => (),
+                            }
+                            for earlier in small.take().unwrap_or_default() {
+                                tree.append(&earlier)?;
+                            }
+                            tree.append(chunk)?
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            read_error = Some(e);
+                            break;
                         }
                     }
-                    if chunk_len == 0 {
-                        break;
-                    }
-                    file_len += chunk_len as u64;
-                    tree.append(&chunk[..chunk_len])?
+                }
+
+                if let Some(e) = read_error {
+                    // Do not record a partial file: its modification time would make later
+                    // snapshots skip it. Without data, it is read again next time.
+                    println!("Skipping '{}': {}", entry.info.name.utf8(), e);
+                    let entry = Entry {
+                        data: Data::Unreadable(e.to_string()),
+                        ..entry
+                    };
+                    let entry = self.index.insert(entry, None)?;
+                    return reply_ok!(Reply::Id(entry.node_id.unwrap()));
                 }
 
                 // Warn the user if we did not read the expected size:
@@ -340,6 +490,19 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
 
                 let mut entry = entry;
                 entry.info.byte_length = Some(file_len);
+                entry.checksum = Some(checksum.finalize());
+
+                // Empty files keep their tree: it is the same for all of them.
+                if let Some(chunks) = small.filter(|_| file_len > 0) {
+                    // The data reference is recorded when the batch is stored.
+                    debug!("Batch entry: {:?}", entry.info.name);
+                    let entry = self.index.insert(entry, None)?;
+                    let id = entry.node_id.unwrap();
+                    if self.small_files.push(entry, &chunks.concat()) {
+                        self.flush_small_files()?;
+                    }
+                    return reply_ok!(Reply::Id(id));
+                }
 
                 // Get top tree hash:
                 let hash_ref = tree.hash(Some(&entry.info))?;
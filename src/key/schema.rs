@@ -43,6 +43,13 @@ table! {
         file_size -> Nullable<BigInt>,
         hash -> Nullable<Binary>,
         hash_ref -> Nullable<Binary>,
+
+        read_error -> Nullable<Text>,
+        checksum -> Nullable<Binary>,
+        hard_link -> Nullable<Binary>,
+        xattrs -> Nullable<Binary>,
+        special -> Nullable<Binary>,
+        acl -> Nullable<Binary>,
     }
 }
 
@@ -91,6 +98,13 @@ pub struct KeyData {
     pub file_size: Option<i64>,
     pub hash: Option<Vec<u8>>,
     pub hash_ref: Option<Vec<u8>>,
+
+    pub read_error: Option<String>,
+    pub checksum: Option<Vec<u8>>,
+    pub hard_link: Option<Vec<u8>>,
+    pub xattrs: Option<Vec<u8>>,
+    pub special: Option<Vec<u8>>,
+    pub acl: Option<Vec<u8>>,
 }
 
 #[derive(Insertable)]
@@ -113,4 +127,11 @@ pub struct NewKeyData<'a> {
     pub file_size: Option<i64>,
     pub hash: Option<&'a [u8]>,
     pub hash_ref: Option<&'a [u8]>,
+
+    pub read_error: Option<&'a str>,
+    pub checksum: Option<&'a [u8]>,
+    pub hard_link: Option<&'a [u8]>,
+    pub xattrs: Option<&'a [u8]>,
+    pub special: Option<&'a [u8]>,
+    pub acl: Option<&'a [u8]>,
 }
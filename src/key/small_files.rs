@@ -0,0 +1,66 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Batching of small files into composite leaves.
+//!
+//! Every chunk costs a hash, an encryption and an index row, and every file adds a tree node
+//! with its info on top of its chunks. For trees of tiny files, like mail directories, that
+//! overhead dwarfs the data. Files no longer than `BATCH_MAX_FILE_LEN` are instead collected
+//! until they fill a composite leaf, which is stored as one chunk; each file refers to its
+//! part of the leaf by offset and length (see `hash::tree::HashRef::range`).
+//!
+//! Small files only share a leaf with the files they were batched with, so identical small
+//! files in different batches are stored once per batch.
+
+use std::mem;
+
+use super::index::Entry;
+use super::MAX_CHUNK_LEN;
+
+/// Files up to this long are batched into composite leaves.
+pub const BATCH_MAX_FILE_LEN: u64 = 16 * 1024;
+
+/// A composite leaf is stored once it holds this many bytes.
+pub const BATCH_LEN: usize = MAX_CHUNK_LEN;
+
+/// Small files waiting to be stored in the next composite leaf.
+#[derive(Default)]
+pub struct SmallFiles {
+    data: Vec<u8>,
+    /// The entries of the files, with the offset and length of their data.
+    files: Vec<(Entry, u64, u64)>,
+}
+
+impl SmallFiles {
+    pub fn new() -> SmallFiles {
+        SmallFiles::default()
+    }
+
+    /// Add the file `entry` with contents `data`. Returns true once the leaf is full.
+    pub fn push(&mut self, entry: Entry, data: &[u8]) -> bool {
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(data);
+        self.files.push((entry, offset, data.len() as u64));
+        self.data.len() >= BATCH_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The contents of the composite leaf and its files, leaving this batch empty.
+    pub fn take(&mut self) -> (Vec<u8>, Vec<(Entry, u64, u64)>) {
+        (mem::take(&mut self.data), mem::take(&mut self.files))
+    }
+}
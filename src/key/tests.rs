@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use backend::{MemoryBackend, StoreBackend};
+use blob::Compression;
 use key::*;
 
 use quickcheck;
@@ -104,9 +105,15 @@ fn rng_filesystem(size: usize) -> FileSystem {
                         permissions: None,
                         user_id: None,
                         group_id: None,
+                        user_name: None,
+                        group_name: None,
 
                         snapshot_ts_utc: 0,
+                        hard_link: None,
+                        xattrs: vec![],
+                        acl: None,
                     },
+                    checksum: None,
                 },
             };
 
@@ -133,9 +140,15 @@ fn rng_filesystem(size: usize) -> FileSystem {
                 permissions: None,
                 user_id: None,
                 group_id: None,
+                user_name: None,
+                group_name: None,
                 byte_length: None,
                 snapshot_ts_utc: 0,
+                hard_link: None,
+                xattrs: vec![],
+                acl: None,
             },
+            checksum: None,
         },
     };
 
@@ -151,7 +164,7 @@ fn insert_and_update_fs<B: StoreBackend>(fs: &mut FileSystem, ks_p: &StoreProces
         .send_reply(Msg::Insert(
             fs.file.key_entry.clone(),
             if fs.file.data.is_some() {
-                Some(Box::new(move |()| Some(local_file)))
+                Some(Box::new(move |()| Ok(local_file)))
             } else {
                 None
             },
@@ -261,3 +274,163 @@ fn identity() {
     }
     quickcheck::quickcheck(prop as fn(u8) -> bool);
 }
+
+fn chunks(data: &[u8], chunking: Chunking) -> Vec<Vec<u8>> {
+    let mut chunker = Chunker::new(data, chunking);
+    let mut out = vec![];
+    while let Some(chunk) = chunker.next_chunk().unwrap() {
+        out.push(chunk.to_vec());
+    }
+    out
+}
+
+fn noise(len: usize, mut state: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn fixed_chunks() {
+    let data = noise(10000, 1);
+    let out = chunks(&data[..], Chunking::Fixed(4096));
+    assert_eq!(
+        out.iter().map(|c| c.len()).collect::<Vec<_>>(),
+        vec![4096, 4096, 1808]
+    );
+    assert_eq!(out.concat(), data);
+    assert!(chunks(&[], Chunking::Fixed(4096)).is_empty());
+}
+
+#[test]
+fn content_defined_chunks_survive_insertions() {
+    let avg = 4096;
+    let data = noise(200000, 2);
+    let before = chunks(&data[..], Chunking::content_defined(avg));
+    assert_eq!(before.concat(), data);
+    assert!(before.iter().all(|c| c.len() <= 2 * avg));
+    assert!(before.len() > 200000 / (2 * avg));
+
+    // Insert a few bytes near the start: most chunks are unchanged.
+    let mut shifted = data[..1000].to_vec();
+    shifted.extend_from_slice(b"inserted");
+    shifted.extend_from_slice(&data[1000..]);
+    let after = chunks(&shifted[..], Chunking::content_defined(avg));
+    assert_eq!(after.concat(), shifted);
+    let common = after.iter().filter(|c| before.contains(c)).count();
+    assert!(common + 3 >= before.len());
+}
+
+#[test]
+fn keyed_chunks_differ_between_keys() {
+    let data = noise(200000, 3);
+    let profiles = ChunkingProfiles::parse("*.img cdc:4K").unwrap();
+    let lens = |profiles: &ChunkingProfiles| {
+        let mut chunker = profiles.chunker(&data[..], b"disk.img");
+        let mut lens = vec![];
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            lens.push(chunk.len());
+        }
+        lens
+    };
+    let a = profiles.clone().with_key(&[1; 32]);
+    let b = profiles.clone().with_key(&[2; 32]);
+
+    // The same key cuts the same chunks, so repositories sharing a key deduplicate.
+    assert_eq!(lens(&a), lens(&a.clone()));
+    assert_eq!(lens(&a), lens(&profiles.clone().with_key(&[1; 32])));
+    assert_ne!(lens(&a), lens(&b));
+    assert_ne!(lens(&a), lens(&profiles));
+    assert_eq!(lens(&a).iter().sum::<usize>(), data.len());
+}
+
+#[test]
+fn profiles_by_name() {
+    let profiles = ChunkingProfiles::parse(
+        "# comment\n\n*.vmdk cdc:1M\n*.sql  fixed:16K\ndump-??.sql fixed:4K\n",
+    ).unwrap();
+    assert_eq!(
+        profiles.for_name(b"disk.vmdk"),
+        Chunking::content_defined(1024 * 1024)
+    );
+    assert_eq!(profiles.for_name(b"dump-01.sql"), Chunking::Fixed(16 * 1024));
+    assert_eq!(profiles.for_name(b"notes.txt"), Chunking::default());
+    assert_eq!(profiles.for_name(b"vmdk"), Chunking::default());
+
+    assert!(ChunkingProfiles::parse("*.vmdk").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk zip:1M").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk cdc:2M").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk fixed:10").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk cdc:1M gzip").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk cdc:1M none zstd").is_err());
+    assert!(ChunkingProfiles::parse("*.vmdk cdc:99999999999999999999K").is_err());
+
+    // Profiles print in a form they can be parsed from again.
+    assert_eq!(
+        profiles.to_string(),
+        "*.vmdk cdc:1048576, *.sql fixed:16384, dump-??.sql fixed:4096, * fixed:131072"
+    );
+    let c = Chunking::content_defined(64 * 1024);
+    assert_eq!(Chunking::parse(&c.to_string()), Ok(c));
+}
+
+#[test]
+fn profiles_set_compression() {
+    let profiles =
+        ChunkingProfiles::parse("*.vmdk cdc:1M none\n*.sql fixed:16K zstd:19\n*.log fixed:16K")
+            .unwrap();
+    assert_eq!(profiles.compression_for_name(b"disk.vmdk"), Some(Compression::None));
+    assert_eq!(profiles.compression_for_name(b"dump.sql"), Some(Compression::Zstd(19)));
+    assert_eq!(profiles.compression_for_name(b"app.log"), None);
+    assert_eq!(profiles.compression_for_name(b"notes.txt"), None);
+    assert_eq!(
+        profiles.to_string(),
+        "*.vmdk cdc:1048576 none, *.sql fixed:16384 zstd:19, *.log fixed:16384, * fixed:131072"
+    );
+}
+
+#[test]
+fn content_defined_bounds() {
+    let c = Chunking::parse("cdc:2K/4K/6K").unwrap();
+    assert_eq!(
+        c,
+        Chunking::ContentDefined {
+            min: 2048,
+            avg: 4096,
+            max: 6144,
+        }
+    );
+    assert_eq!(c.to_string(), "cdc:2048/4096/6144");
+    assert_eq!(Chunking::parse(&c.to_string()), Ok(c));
+    assert_eq!(Chunking::parse("cdc:1K/4K/8K"), Ok(Chunking::content_defined(4096)));
+
+    let data = noise(200000, 4);
+    let out = chunks(&data[..], c);
+    assert_eq!(out.concat(), data);
+    let (last, rest) = out.split_last().unwrap();
+    assert!(rest.iter().all(|c| c.len() >= 2048 && c.len() <= 6144));
+    assert!(last.len() <= 6144);
+
+    assert!(Chunking::parse("cdc:4K/2K/8K").is_err());
+    assert!(Chunking::parse("cdc:2K/4K/4K").is_err());
+    assert!(Chunking::parse("cdc:2K/4K").is_err());
+    assert!(Chunking::parse("cdc:512/4K/8K").is_err());
+    assert!(Chunking::parse("cdc:1M/1M/4M").is_err());
+    assert!(Chunking::parse("fixed:4K/8K").is_err());
+}
+
+#[test]
+fn profile_patterns() {
+    let matches = |pattern: &str, name: &[u8]| {
+        let profiles = ChunkingProfiles::parse(&format!("{} fixed:4K", pattern)).unwrap();
+        profiles.for_name(name) == Chunking::Fixed(4096)
+    };
+    assert!(matches("*", b""));
+    assert!(matches("*.tar.*", b"a.tar.gz"));
+    assert!(matches("a*b*c", b"aXbYbZc"));
+    assert!(!matches("a*b*c", b"aXbYbZ"));
+    assert!(!matches("?", b""));
+}
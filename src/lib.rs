@@ -26,17 +26,31 @@ extern crate test;
 extern crate time;
 
 // Rust crates.
+extern crate argon2;
 extern crate byteorder;
 extern crate chrono;
 extern crate filetime;
+#[cfg(feature = "fuse")]
 extern crate fuse;
 extern crate hex;
 extern crate libc;
+#[cfg(feature = "sodium")]
 extern crate libsodium_sys;
 extern crate lru_cache;
+#[cfg(feature = "rust-crypto")]
+extern crate blake2b_simd;
+#[cfg(feature = "rust-crypto")]
+extern crate chacha20;
+#[cfg(feature = "rust-crypto")]
+extern crate crypto_box;
+#[cfg(feature = "rust-crypto")]
+extern crate poly1305;
+#[cfg(feature = "rust-crypto")]
+extern crate sha2;
 extern crate scoped_pool;
 extern crate secstr;
 extern crate void;
+extern crate zstd;
 
 // Error definition macros.
 #[macro_use]
@@ -55,13 +69,16 @@ extern crate quickcheck;
 // Serde
 extern crate serde;
 extern crate serde_cbor;
+extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
 // Submodules
 pub mod backend;
 mod blob;
+pub mod bundle;
 pub mod crypto;
+pub mod daemon;
 mod db;
 mod errors;
 mod gc;
@@ -70,6 +87,7 @@ pub mod hat;
 mod key;
 pub mod models;
 mod snapshot;
+pub mod status;
 mod tags;
 pub mod util;
 pub mod vfs;
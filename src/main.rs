@@ -16,202 +16,2918 @@
 extern crate hat;
 
 // Rust crates.
+extern crate chrono;
 extern crate env_logger;
-extern crate libsodium_sys;
+extern crate rpassword;
+extern crate secstr;
+#[macro_use]
+extern crate serde_json;
 
 // We use Clap for argument parsing.
 #[macro_use]
 extern crate clap;
 
-use clap::{App, SubCommand};
-use std::env;
+use clap::{App, Arg, SubCommand};
+use std::env;
+
+use hat::backend;
+use std::borrow::ToOwned;
+use std::collections::BTreeSet;
+use std::convert::From;
+use std::ffi;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+
+/// Present in state directories of repositories that build on a read-only parent repository,
+/// holding the path of the parent's state directory.
+static PARENT_FILENAME: &str = "parent";
+
+/// Holds the state directories of the named repositories sharing a state directory.
+static REPOS_DIRNAME: &str = "repos";
+
+/// Holds the storage quota of the repository in bytes, if one is configured.
+static QUOTA_FILENAME: &str = "quota";
+
+/// Holds the id of the last blob checked by a `verify` that ran out of time.
+static VERIFY_CHECKPOINT_FILENAME: &str = "verify-checkpoint";
+
+/// Exit status of commands that stopped at their `--stop-after` deadline.
+const EXIT_STOPPED: i32 = 3;
+
+/// Blobs served from the local cache are not throttled, and only calls that reach the storage
+/// are traced.
+type Backend = backend::CachingBackend<
+    backend::ThrottledBackend<
+        backend::TracedBackend<
+            backend::RetryBackend<
+                backend::LayeredBackend<Box<backend::StoreBackend>, backend::CmdBackend>,
+            >,
+        >,
+    >,
+>;
+
+/// Store blobs over SFTP at `[user@]host:dir`, instead of through the `hat-backup-*` commands.
+static SFTP_VAR: &str = "HAT_BACKUP_SFTP";
+
+/// Connections to keep open to the SFTP server, instead of the default.
+static SFTP_CONNECTIONS_VAR: &str = "HAT_BACKUP_SFTP_CONNECTIONS";
+
+/// Directory the `localdir` backend stores blobs in, instead of the current directory.
+static STORAGE_DIR_VAR: &str = "HAT_BACKUP_STORAGE_DIR";
+
+/// Local directories the backend writes to: none over SFTP, else the current directory, where
+/// the commands run, and the storage directory of the `localdir` backend.
+fn backend_dirs() -> Vec<PathBuf> {
+    if env::var(SFTP_VAR).is_ok() {
+        return vec![];
+    }
+    let mut dirs: Vec<PathBuf> = env::current_dir().into_iter().collect();
+    dirs.extend(env::var_os(STORAGE_DIR_VAR).map(PathBuf::from));
+    dirs
+}
+
+/// The backend holding the blobs of this repository: SFTP if configured, else the commands.
+fn own_backend() -> Box<backend::StoreBackend> {
+    let location = match env::var(SFTP_VAR) {
+        Ok(location) => location,
+        Err(_) => return Box::new(backend::CmdBackend::new()),
+    };
+    let mut sftp = backend::SftpBackend::parse(&location).unwrap_or_else(|e| {
+        eprintln!("Error: {}: {}", SFTP_VAR, e);
+        std::process::exit(1);
+    });
+    if let Ok(repo) = env::var("HAT_REPO") {
+        sftp = sftp.with_repo(&repo).unwrap_or_else(|e| {
+            eprintln!("Error: HAT_REPO: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if let Some(n) = env::var(SFTP_CONNECTIONS_VAR).ok().and_then(|n| n.parse().ok()) {
+        sftp = sftp.with_connections(n);
+    }
+    Box::new(sftp)
+}
+
+/// Passphrase of a passphrase-protected universal key, instead of asking for it.
+static PASSPHRASE_VAR: &str = "HAT_PASSPHRASE";
+
+/// The passphrase in `$HAT_PASSPHRASE`, or else read from the terminal after `prompt`.
+fn read_passphrase(prompt: &str) -> Result<secstr::SecStr, String> {
+    let passphrase = match env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::prompt_password(prompt)
+            .map_err(|e| format!("could not read the passphrase: {}", e))?,
+    };
+    Ok(secstr::SecStr::from(passphrase))
+}
+
+/// A passphrase for a new key, entered twice to catch typos.
+fn new_passphrase() -> Result<secstr::SecStr, String> {
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if passphrase.unsecure().is_empty() {
+        return Err("the passphrase is empty".to_owned());
+    }
+    let typed = env::var_os(PASSPHRASE_VAR).is_none();
+    if typed && read_passphrase("Repeat passphrase: ")? != passphrase {
+        return Err("the passphrases do not match".to_owned());
+    }
+    Ok(passphrase)
+}
+
+/// Asks for the passphrase of a passphrase-protected universal key once per run.
+fn passphrase_prompt() -> hat::crypto::keys::PassphrasePrompt {
+    let entered = Mutex::new(None);
+    Box::new(move |dir: &Path| {
+        let mut entered = entered.lock().unwrap();
+        if entered.is_none() {
+            *entered = Some(read_passphrase(&format!("Passphrase for {}: ", dir.display()))?);
+        }
+        Ok(entered.clone().unwrap())
+    })
+}
+
+/// Backend calls that take this long are logged as slow, instead of the default.
+static SLOW_BACKEND_OP_VAR: &str = "HAT_SLOW_BACKEND_OP";
+
+/// Keep this many bytes of downloaded blobs in the state directory, instead of the default;
+/// zero disables the blob cache.
+static BLOB_CACHE_SIZE_VAR: &str = "HAT_BLOB_CACHE_SIZE";
+
+/// Limit uploads to this rate or schedule, as `--limit-upload` does.
+static LIMIT_UPLOAD_VAR: &str = "HAT_LIMIT_UPLOAD";
+
+/// Limit downloads to this rate or schedule, as `--limit-download` does.
+static LIMIT_DOWNLOAD_VAR: &str = "HAT_LIMIT_DOWNLOAD";
+
+/// Print the results of commands as `text` or `json`, as `--output` does.
+static OUTPUT_VAR: &str = "HAT_OUTPUT";
+
+/// Whether commands print their results as JSON, for scripts.
+fn output_json() -> bool {
+    env::var(OUTPUT_VAR).map(|v| v == "json").unwrap_or(false)
+}
+
+/// Print a line about how a command is going: to stdout, or to stderr when stdout is JSON.
+fn note(line: &str) {
+    if output_json() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// The bandwidth schedule in the environment variable `var`, if set and valid.
+fn schedule_var(var: &str) -> Option<backend::BandwidthSchedule> {
+    env::var(var)
+        .ok()
+        .and_then(|s| backend::BandwidthSchedule::parse(&s).ok())
+}
+
+/// The backend for the state directory `cache_dir`, including its parent repository if any.
+/// Slow calls are logged in `cache_dir`. Traffic is limited as `--limit-upload` and
+/// `--limit-download` say, until other schedules are set.
+fn open_backend(cache_dir: &Path) -> Arc<Backend> {
+    let parent = match fs::read_to_string(cache_dir.join(PARENT_FILENAME)) {
+        Ok(path) => Some(Arc::new(
+            backend::CmdBackend::new_parent(path.trim_end()),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => panic!("Could not read the parent repository: {}", e),
+    };
+    let layered = backend::LayeredBackend::new(Arc::new(own_backend()), parent);
+    let slow_after = env::var(SLOW_BACKEND_OP_VAR)
+        .ok()
+        .and_then(|s| hat::util::parse_duration(&s).ok())
+        .unwrap_or(backend::DEFAULT_SLOW_AFTER);
+    let retry = backend::RetryBackend::new(Arc::new(layered));
+    let traced = backend::TracedBackend::new(Arc::new(retry))
+        .with_slow_after(slow_after)
+        .with_log(cache_dir.join(backend::SLOW_LOG_FILENAME));
+    let throttled = backend::ThrottledBackend::new(Arc::new(traced));
+    throttled.set_upload_schedule(schedule_var(LIMIT_UPLOAD_VAR));
+    throttled.set_download_schedule(schedule_var(LIMIT_DOWNLOAD_VAR));
+    let cache_size = env::var(BLOB_CACHE_SIZE_VAR)
+        .ok()
+        .and_then(|s| hat::util::parse_size(&s).ok())
+        .unwrap_or(backend::DEFAULT_BLOB_CACHE_SIZE);
+    Arc::new(backend::CachingBackend::new(
+        Arc::new(throttled),
+        cache_dir.join(backend::BLOB_CACHE_DIRNAME),
+        cache_size,
+    ))
+}
+
+/// Set to leave unfinished operations alone when opening a repository, see `open_repository`.
+static NO_AUTO_RESUME_VAR: &str = "HAT_NO_AUTO_RESUME";
+
+/// Open the repository in the state directory `cache_dir`. Operations left unfinished by an
+/// earlier process are resumed first, unless `--no-auto-resume` was given; then opening fails
+/// while there are any, instead of starting a long resume unannounced.
+fn open_repository(
+    cache_dir: PathBuf,
+    backend: Arc<Backend>,
+) -> Result<hat::hat::HatRc<Backend>, String> {
+    let mut hat = hat::Hat::open_repository_without_resume(cache_dir, backend, MAX_BLOB_SIZE)
+        .map_err(|e| e.to_string())?;
+    resume_unless_disabled(&mut hat)?;
+    Ok(hat)
+}
+
+/// Reuse the metadata cached by recent maintenance commands for this long, instead of the
+/// default; zero disables the chunk cache.
+static CHUNK_CACHE_AGE_VAR: &str = "HAT_CHUNK_CACHE_AGE";
+
+/// The chunk cache of the state directory `cache_dir`, unless disabled.
+fn chunk_cache(cache_dir: &Path) -> Option<hat::hat::ChunkCache> {
+    let cache = hat::hat::ChunkCache::new(cache_dir.join(hat::hat::CHUNK_CACHE_DIRNAME));
+    let max_age = env::var(CHUNK_CACHE_AGE_VAR)
+        .ok()
+        .and_then(|s| hat::util::parse_duration(&s).ok());
+    match max_age {
+        Some(age) if age.as_secs() == 0 => None,
+        Some(age) => Some(cache.with_max_age(age)),
+        None => Some(cache),
+    }
+}
+
+/// Like `open_repository`, but reading metadata through the chunk cache of `cache_dir`, for
+/// commands that walk the trees of many snapshots.
+fn open_maintenance_repository(
+    cache_dir: PathBuf,
+    backend: Arc<Backend>,
+) -> Result<hat::hat::HatRc<Backend>, String> {
+    let cache = chunk_cache(&cache_dir);
+    let mut hat = hat::Hat::open_repository_without_resume(cache_dir, backend, MAX_BLOB_SIZE)
+        .map_err(|e| e.to_string())?;
+    hat.set_chunk_cache(cache.map(|c| Box::new(c) as Box<hat::hat::ChunkStore>));
+    resume_unless_disabled(&mut hat)?;
+    Ok(hat)
+}
+
+fn resume_unless_disabled(hat: &mut hat::hat::HatRc<Backend>) -> Result<(), String> {
+    if env::var_os(NO_AUTO_RESUME_VAR).is_some() {
+        let unfinished = hat.unfinished_work().map_err(|e| e.to_string())?;
+        if !unfinished.is_empty() {
+            return Err(format!(
+                "{} unfinished operations; `hat resume --status` lists them and `hat resume` \
+                 finishes them",
+                unfinished.len()
+            ));
+        }
+    } else {
+        for work in hat.resume().map_err(|e| e.to_string())? {
+            eprintln!("Resumed {}", work);
+        }
+    }
+    Ok(())
+}
+
+/// The state directory of repository `repo` in the state directory `dir`, or `dir` itself when
+/// no repository is named.
+fn repo_state_dir(dir: &Path, repo: Option<&str>) -> Result<PathBuf, String> {
+    match repo {
+        None => Ok(dir.to_path_buf()),
+        Some(name) if name.is_empty() || name.starts_with('.') || name.contains('/') => {
+            Err(format!("Invalid repository name '{}'", name))
+        }
+        Some(name) => Ok(dir.join(REPOS_DIRNAME).join(name)),
+    }
+}
+
+/// The storage quota configured for the state directory `cache_dir`. Exits with an error if
+/// the quota file does not hold a number of bytes.
+fn read_quota(cache_dir: &Path) -> Option<u64> {
+    let path = cache_dir.join(QUOTA_FILENAME);
+    let quota = fs::read_to_string(&path).ok()?;
+    match quota.trim().parse() {
+        Ok(quota) => Some(quota),
+        Err(e) => {
+            eprintln!("Error: Invalid quota file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn license() {
+    println!(include_str!("../LICENSE"));
+    println!("clap (Command Line Argument Parser) License:");
+    println!(include_str!("../LICENSE-CLAP"));
+}
+
+/// Unwrap `res`, or record its error in the status log and exit.
+fn check<T, E: fmt::Display>(status: &mut hat::status::StatusLog, res: Result<T, E>) -> T {
+    match res {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = e.to_string();
+            if let Err(log_err) = status.fail(&msg) {
+                eprintln!("Could not record failure: {}", log_err);
+            }
+            eprintln!("Error: {}", msg);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print the single line of a monitoring check, and exit with the code of its state.
+fn exit_check(check: hat::status::Check) -> ! {
+    println!("{}", check);
+    std::process::exit(check.state.exit_code());
+}
+
+/// Like `check`, but in `--check-mode` an error is the UNKNOWN state of the monitoring check.
+fn check_or_unknown<T, E: fmt::Display>(
+    status: &mut hat::status::StatusLog,
+    res: Result<T, E>,
+    check_mode: bool,
+) -> T {
+    match res {
+        Err(ref e) if check_mode => {
+            let msg = e.to_string();
+            if let Err(log_err) = status.fail(&msg) {
+                eprintln!("Could not record failure: {}", log_err);
+            }
+            exit_check(hat::status::Check::new(hat::status::CheckState::Unknown, msg))
+        }
+        res => check(status, res),
+    }
+}
+
+/// The age thresholds of a monitoring check, from `--warn-age` and `--crit-age`.
+fn check_thresholds(
+    cmd: &clap::ArgMatches,
+    mut thresholds: hat::status::Thresholds,
+) -> Result<hat::status::Thresholds, String> {
+    if let Some(age) = cmd.value_of("warn-age") {
+        thresholds.warning = hat::util::parse_duration(age)?;
+    }
+    if let Some(age) = cmd.value_of("crit-age") {
+        thresholds.critical = hat::util::parse_duration(age)?;
+    }
+    if thresholds.warning > thresholds.critical {
+        return Err("--warn-age must not exceed --crit-age".to_owned());
+    }
+    Ok(thresholds)
+}
+
+/// Warn about data that failed verification when it was read back from the backend, with
+/// where it is stored and the file versions it belongs to.
+fn report_retrieve_failures(report: hat::hat::RetrieveReport) {
+    let metrics = report.metrics;
+    if metrics.failures() == 0 {
+        return;
+    }
+    eprintln!(
+        "Verification failed reading from the backend: {} blobs, {} chunks",
+        metrics.blob_failures, metrics.chunk_failures
+    );
+    for failure in &report.failures {
+        eprintln!("Failed: {}", failure);
+    }
+    for line in affected_lines(report.affected) {
+        eprintln!("{}", line);
+    }
+}
+
+/// Describe the file versions that damaged data belongs to, as found by `affected_versions`.
+fn affected_lines(affected: Result<Vec<hat::hat::IndexedVersion>, String>) -> Vec<String> {
+    match affected {
+        Ok(versions) => versions
+            .iter()
+            .map(|v| {
+                format!(
+                    "Affected: {}/{} (snapshots {})",
+                    v.version.family,
+                    v.version.path,
+                    snapshot_ranges(&v.snapshots)
+                )
+            })
+            .collect(),
+        Err(e) => vec![format!("Cannot tell which files are affected: {}", e)],
+    }
+}
+
+/// Warn about backend calls that were slow, which tells why a command took long.
+fn report_slow_backend_calls(backend: &Backend) {
+    let backend = backend.inner().inner();
+    let count: u64 = backend.stats().iter().map(|&(_, stats)| stats.slow).sum();
+    if let Some(slowest) = backend.slow_calls().iter().max_by_key(|call| call.elapsed) {
+        eprintln!(
+            "{} backend calls took {} s or longer, the slowest: {}; see {} in the state directory",
+            count,
+            backend.slow_after().as_secs(),
+            slowest,
+            backend::SLOW_LOG_FILENAME
+        );
+    }
+}
+
+/// Add the current size of the repository to the statistics history, after `command`.
+fn record_stats(hat: &mut hat::hat::HatRc<Backend>, state_dir: &Path, command: &str) {
+    let record = hat.stats_record(command);
+    if let Err(e) = hat::hat::stats::append(state_dir, &record) {
+        eprintln!("Could not record repository statistics: {}", e);
+    }
+}
+
+/// Print the statistics history of `state_dir`, a line per day, and how fast storage grows.
+fn print_stats_history(state_dir: &Path) -> Result<(), String> {
+    use hat::hat::stats;
+
+    let records = stats::load(state_dir).map_err(|e| e.to_string())?;
+    let last = match records.last() {
+        Some(last) => last,
+        None => {
+            println!("No statistics recorded yet; they are recorded after each commit and gc");
+            return Ok(());
+        }
+    };
+    for line in stats::plot(&stats::daily(&records), 40) {
+        println!("{}", line);
+    }
+
+    println!("Latest snapshot of each family:");
+    for (family, bytes) in &last.families {
+        let first = records
+            .iter()
+            .filter_map(|r| r.families.get(family).cloned().and_then(|b| b.map(|b| (r, b))))
+            .next();
+        match (*bytes, first) {
+            (Some(bytes), Some((first, first_bytes))) if first.ts_utc < last.ts_utc => println!(
+                "  {}: {} bytes ({:+} bytes since {})",
+                family,
+                bytes,
+                bytes as i64 - first_bytes as i64,
+                chrono::NaiveDateTime::from_timestamp(first.ts_utc, 0).date()
+            ),
+            (Some(bytes), _) => println!("  {}: {} bytes", family, bytes),
+            (None, _) => println!("  {}: size not recorded", family),
+        }
+    }
+
+    match stats::growth_per_day(&records) {
+        Some(growth) => {
+            println!("Storage growth: {} bytes per day", growth);
+            let quota = read_quota(state_dir);
+            if let Some(days) =
+                quota.and_then(|q| stats::days_until_quota(last.storage_bytes, q, growth))
+            {
+                println!("The quota is reached in about {} days", days);
+            }
+        }
+        None => println!("Storage growth: not known until the history spans a day"),
+    }
+    Ok(())
+}
+
+/// The commit notifications requested by the flags in `cmd` or the environment.
+fn notifier(cmd: &clap::ArgMatches) -> hat::daemon::Notifier {
+    let flag_or_env = |name: &str, var: &str| {
+        cmd.value_of(name)
+            .map(|s| s.to_owned())
+            .or_else(|| env::var(var).ok())
+            .filter(|s| !s.is_empty())
+    };
+    hat::daemon::Notifier::new(
+        flag_or_env("notify-webhook", "HAT_NOTIFY_WEBHOOK"),
+        flag_or_env("notify-email", "HAT_NOTIFY_EMAIL"),
+        cmd.is_present("notify-failures-only"),
+    )
+}
+
+/// Storage counters taken before a commit, to tell what it added.
+struct StorageBefore {
+    usage: u64,
+    stored: hat::hat::StoreMetrics,
+}
+
+impl StorageBefore {
+    fn take<B: backend::StoreBackend>(hat: &hat::hat::HatRc<B>) -> StorageBefore {
+        StorageBefore {
+            usage: hat.storage_usage(),
+            stored: hat.store_metrics(),
+        }
+    }
+}
+
+/// Report a commit into family `name` that started at `started` (UTC seconds). Fails if the
+/// report could not be delivered.
+fn send_commit_report<B: backend::StoreBackend>(
+    notifier: &hat::daemon::Notifier,
+    hat: &mut hat::hat::HatRc<B>,
+    name: &str,
+    started: i64,
+    before: &StorageBefore,
+    error: Option<String>,
+) -> Result<(), String> {
+    let snapshot_id = if error.is_none() {
+        hat.list_snapshots()
+            .into_iter()
+            .filter(|s| s.family_name == name)
+            .map(|s| s.info.snapshot_id)
+            .max()
+    } else {
+        None
+    };
+    let usage = hat.storage_usage();
+    let report = hat::daemon::CommitReport {
+        family: name.to_owned(),
+        snapshot_id,
+        started_ts_utc: started,
+        finished_ts_utc: chrono::Utc::now().timestamp(),
+        uploaded_bytes: usage.saturating_sub(before.usage),
+        deduplicated_bytes: hat.store_metrics().since(&before.stored).bytes_reused,
+        storage_used_bytes: usage,
+        error,
+    };
+    notifier
+        .send(&report)
+        .map_err(|e| format!("could not send commit notification: {}", e))
+}
+
+/// Run `f` while holding the lock of a shared backend. The lock is released even if `f` fails,
+/// so the blobs it uploaded are published to the other writers.
+fn with_backend_lock<B, T, F>(hat: &mut hat::hat::HatRc<B>, f: F) -> Result<T, String>
+where
+    B: backend::StoreBackend,
+    F: FnOnce(&mut hat::hat::HatRc<B>) -> Result<T, String>,
+{
+    let locked = hat.lock_backend().map_err(|e| e.to_string())?;
+    let res = f(hat);
+    if locked {
+        hat.unlock_backend().map_err(|e| e.to_string())?;
+    }
+    res
+}
+
+/// Sorted snapshot ids as a list of ranges, e.g. "1-3, 5".
+fn snapshot_ranges(ids: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &id in ids {
+        match ranges.last_mut() {
+            Some(&mut (_, ref mut last)) if *last + 1 == id => *last = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// An annotation on one line, e.g. `2018-10-21 12:00:00 "restored fine"; tags: known-good`.
+fn annotation_line(annotation: &hat::models::SnapshotAnnotation) -> String {
+    let mut parts =
+        vec![chrono::NaiveDateTime::from_timestamp(annotation.created_ts_utc, 0).to_string()];
+    if let Some(ref message) = annotation.message {
+        parts.push(format!("{:?}", message));
+    }
+    if !annotation.tags.is_empty() {
+        parts.push(format!("tags: {}", annotation.tags.join(", ")));
+    }
+    for (name, value) in &annotation.meta {
+        parts.push(format!("{}={}", name, value));
+    }
+    parts.join("; ")
+}
+
+/// The annotation given by the `--message`, `--tag` and `--meta` arguments of `cmd`.
+fn annotation(cmd: &clap::ArgMatches) -> Result<hat::models::SnapshotAnnotation, String> {
+    let values = |name: &str| -> Vec<String> {
+        cmd.values_of(name)
+            .map_or(vec![], |v| v.map(|s| s.to_owned()).collect())
+    };
+    let mut meta = vec![];
+    for pair in values("meta") {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => {
+                meta.push((name.to_owned(), value.to_owned()))
+            }
+            _ => return Err(format!("Invalid --meta '{}': expected KEY=VALUE", pair)),
+        }
+    }
+    let annotation = hat::models::SnapshotAnnotation {
+        created_ts_utc: chrono::Utc::now().timestamp(),
+        message: cmd.value_of("message").map(|m| m.to_owned()),
+        tags: values("tag"),
+        meta: meta,
+    };
+    if annotation.message.is_none() && annotation.tags.is_empty() && annotation.meta.is_empty() {
+        return Err("Nothing to annotate: give --message, --tag or --meta".into());
+    }
+    Ok(annotation)
+}
+
+/// Parse a `family[/snapshot[/path]]` address, picking the snapshot `latest` or `^N` refer to.
+fn resolve_address<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    address: &str,
+) -> Result<hat::vfs::Address, String> {
+    hat::vfs::Address::parse(address)?.resolve(&hat.list_snapshots())
+}
+
+/// Commit a snapshot of `paths` to family `name`, or of the tar archive on stdin if `None`,
+/// recording progress in `status`. Returns false if the snapshot stopped at `deadline`, before
+/// anything was committed.
+fn commit<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    status: &mut hat::status::StatusLog,
+    name: &str,
+    paths: Option<&[&str]>,
+    deadline: hat::util::Deadline,
+    quiesce: Option<&hat::util::Quiesce>,
+    progress: Option<Arc<hat::hat::seed::SnapshotProgress>>,
+) -> Result<bool, String> {
+    // Fail before uploading anything if there is no room left.
+    hat.check_quota().map_err(|e| e.to_string())?;
+
+    // Update the family index.
+    status.phase("snapshot").map_err(|e| e.to_string())?;
+    let mut family = hat.open_family(name.to_owned()).map_err(|e| e.to_string())?;
+    let preemption = hat::util::Preemption::new();
+    let timer = hat::daemon::PreemptTimer::start(preemption.clone(), deadline.remaining());
+    // The filesystem is thawed when the guard goes, also if the snapshot panics.
+    let quiesced = quiesce.map(|q| q.begin()).transpose()?;
+    let completed = match paths {
+        Some(paths) => {
+            let dirs = paths.iter().map(PathBuf::from).collect();
+            let checkpoints = progress.clone().map(|progress| {
+                let interval = hat::hat::seed::DEFAULT_CHECKPOINT_INTERVAL;
+                hat.start_checkpoints(&family, interval, progress)
+            });
+            let completed = family.snapshot_dirs_with_progress(dirs, preemption, progress);
+            drop(checkpoints);
+            completed
+        }
+        None => {
+            let entries = family
+                .snapshot_from_tar(std::io::stdin())
+                .map_err(|e| e.to_string())?;
+            println!("Read {} entries from the tar archive", entries);
+            true
+        }
+    };
+    if let Some(quiesced) = quiesced {
+        if !quiesced.release()? {
+            eprintln!(
+                "Warning: thawed {} at the --quiesce-timeout before the snapshot was read; \
+                 it may be inconsistent",
+                quiesce.unwrap().mountpoint().display()
+            );
+        }
+    }
+    drop(timer);
+    if let Some(e) = family.failure.lock().unwrap().take() {
+        return Err(format!("The snapshot failed: {}", e));
+    }
+    if !completed {
+        // The files indexed so far are kept, so the next commit continues quickly. Their
+        // hashes are only kept once the backend has stored their blobs.
+        hat.data_flush().map_err(|e| e.to_string())?;
+        return Ok(false);
+    }
+
+    // Commit the updated index; `finish_commit` makes it durable.
+    status.phase("commit").map_err(|e| e.to_string())?;
+    hat.commit(&mut family, None).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// Shows how far a commit or checkout has come on stderr every second. Stops when dropped.
+struct ProgressDisplay {
+    stop: Option<std::sync::mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ProgressDisplay {
+    /// Show the progress events of `hat` from now on. Without a `total` of bytes to handle, the
+    /// files below `scan` are counted in the background to estimate the time left.
+    fn start<B: backend::StoreBackend>(
+        hat: &mut hat::hat::HatRc<B>,
+        total: Option<u64>,
+        scan: Vec<PathBuf>,
+    ) -> ProgressDisplay {
+        use std::sync::mpsc;
+        use std::time::{Duration, Instant};
+
+        let meter = Arc::new(hat::util::ProgressMeter::new());
+        let events = meter.clone();
+        hat.set_progress(Some(Arc::new(move |event| events.record(event))));
+        match total {
+            Some(total) => meter.set_total(total),
+            None if !scan.is_empty() => {
+                let meter = meter.clone();
+                std::thread::spawn(move || meter.set_total(hat::hat::seed::scan(&scan).bytes));
+            }
+            None => (),
+        }
+
+        let started = Instant::now();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let second = Duration::from_secs(1);
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(second) {
+                eprintln!("{}", meter.line(started.elapsed()));
+            }
+            eprintln!("{}", meter.line(started.elapsed()));
+        });
+        ProgressDisplay {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for ProgressDisplay {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Flush a commit as far as `durability` asks, reading back the blobs stored after blob
+/// `after_id` when verifying.
+fn finish_commit<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    status: &mut hat::status::StatusLog,
+    durability: hat::hat::Durability,
+    after_id: i64,
+) -> Result<(), String> {
+    use hat::hat::Durability;
+
+    let phase = match durability {
+        Durability::Flushed => "flush",
+        // Meta commit, while the remaining data blobs are flushed.
+        Durability::Uploaded => "meta commit and flush",
+        Durability::Verified => "meta commit, flush and verify",
+    };
+    status.phase(phase).map_err(|e| e.to_string())?;
+    let verified = hat.flush_with_durability(durability, after_id)
+        .map_err(|e| e.to_string())?;
+    match durability {
+        Durability::Flushed => {
+            println!("The backend lists the snapshot once a later commit uploads the snapshot list")
+        }
+        Durability::Uploaded => (),
+        Durability::Verified => println!("Verified {} new blobs", verified),
+    }
+    Ok(())
+}
+
+/// Start or continue seeding the family `name` from `paths`: the paths are scanned by the first
+/// run, and the totals kept in `state_dir` for the runs that follow.
+fn start_seed(
+    state_dir: &Path,
+    name: &str,
+    paths: &[&str],
+) -> Result<hat::hat::seed::SnapshotProgress, String> {
+    use hat::hat::seed;
+
+    let state = match seed::SeedState::load(state_dir, name) {
+        Some(state) => seed::SeedState {
+            runs: state.runs + 1,
+            ..state
+        },
+        None => {
+            println!("Scanning {} to seed {}", paths.join(" "), name);
+            let dirs: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+            seed::SeedState {
+                family: name.to_owned(),
+                started_utc: chrono::Utc::now().timestamp(),
+                runs: 1,
+                total: seed::scan(&dirs),
+            }
+        }
+    };
+    state.store(state_dir).map_err(|e| e.to_string())?;
+    println!(
+        "Seeding {}, run {}: {} files, {} bytes",
+        name, state.runs, state.total.files, state.total.bytes
+    );
+    Ok(seed::SnapshotProgress::new(state.total))
+}
+
+/// Read the paths to restore first from `file`: one per line, relative to the snapshot root and
+/// with patterns as in `derive --include`. Empty lines and lines starting with `#` are ignored.
+fn read_first_from(file: &str) -> Result<hat::util::PathFilter, String> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| format!("could not read {}: {}", file, e))?;
+    let paths: Vec<&str> = contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    if paths.is_empty() {
+        return Err(format!("{} lists no paths", file));
+    }
+    Ok(hat::util::PathFilter::new(&paths))
+}
+
+/// Check out the snapshot at `address` into the directory `path`, or to stdout as a tar archive
+/// without one.
+fn checkout<B: backend::StoreBackend>(
+    mut hat: hat::hat::HatRc<B>,
+    address: &hat::vfs::Address,
+    path: Option<&str>,
+    first: Option<hat::util::PathFilter>,
+    only: Option<&str>,
+) -> Result<(), String> {
+    match path {
+        Some(path) => {
+            let family = match address.family {
+                Some(ref family) if address.path == Path::new("") => family.clone(),
+                _ => return Err("checkout to a directory needs <family>[/<snapshot>]".to_owned()),
+            };
+            let res = match (address.snapshot_id(), first, only) {
+                (_, Some(_), Some(_)) => {
+                    return Err("--first-from does not apply to --path".to_owned())
+                }
+                (id, None, Some(only)) => {
+                    hat.checkout_path(family, id, Path::new(only), PathBuf::from(path))
+                }
+                (id, Some(first), None) => {
+                    hat.checkout_in_dir_first(family, id, &first, PathBuf::from(path))
+                }
+                (Some(id), None, None) => {
+                    hat.checkout_snapshot_in_dir(family, id, PathBuf::from(path))
+                }
+                (None, None, None) => hat.checkout_in_dir(family, PathBuf::from(path)),
+            };
+            report_retrieve_failures(hat.retrieve_report());
+            res.map_err(|e| e.to_string())
+        }
+        None if first.is_some() => Err("--first-from does not apply to --to-stdout-tar".to_owned()),
+        None if only.is_some() => {
+            Err("--path does not apply to --to-stdout-tar; give the path in SNAPSHOT".to_owned())
+        }
+        None => {
+            // Only the archive goes to stdout, so it can be piped to `tar -x`.
+            let stdout = std::io::stdout();
+            let out = std::io::BufWriter::new(stdout.lock());
+            let mut fs = hat::vfs::Filesystem::new(hat);
+            let res = fs.write_tar(&address.to_path(), out);
+            report_retrieve_failures(fs.retrieve_report());
+            eprintln!("Wrote {} entries", res.map_err(|e| e.to_string())?);
+            Ok(())
+        }
+    }
+}
+
+/// Apply `--restore-wait` to `hat`.
+fn set_restore_wait(hat: &hat::hat::HatRc<Backend>, cmd: &clap::ArgMatches) -> Result<(), String> {
+    if let Some(wait) = cmd.value_of("restore-wait") {
+        let wait = hat::util::parse_duration(wait)?;
+        hat.set_restore_wait(hat::hat::DEFAULT_RESTORE_POLL, wait);
+    }
+    Ok(())
+}
+
+/// Apply `--fetch-jobs` to `hat`.
+fn set_fetch_jobs(
+    hat: &mut hat::hat::HatRc<Backend>,
+    cmd: &clap::ArgMatches,
+) -> Result<(), String> {
+    if let Some(n) = cmd.value_of("fetch-jobs") {
+        match n.parse() {
+            Ok(n) if n > 0 => hat.set_fetch_jobs(n),
+            _ => return Err(format!("Invalid --fetch-jobs '{}': expected a positive number", n)),
+        }
+    }
+    Ok(())
+}
+
+/// Apply `--numeric-owner` to `hat`.
+fn set_owner_mapping(hat: &mut hat::hat::HatRc<Backend>, cmd: &clap::ArgMatches) {
+    if cmd.is_present("numeric-owner") {
+        hat.set_owner_mapping(hat::hat::owners::OwnerMapping::Numeric);
+    }
+}
+
+/// Restore a snapshot as `hat extract` does, using the new state directory `dir`.
+fn extract(dir: &Path, cmd: &clap::ArgMatches) -> Result<(), String> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let to_tar = cmd.is_present("to-stdout-tar");
+    let path = match (cmd.value_of("PATH"), to_tar) {
+        (Some(_), true) => return Err("--to-stdout-tar does not take a PATH".to_owned()),
+        (None, false) => return Err("PATH is required without --to-stdout-tar".to_owned()),
+        (path, _) => path,
+    };
+    let first = cmd.value_of("first-from").map(read_first_from).transpose()?;
+
+    // Only we can read the key while it is in the temporary directory.
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(dir)
+        .and_then(|()| fs::create_dir(dir.join("cache")))
+        .map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+    let key = Path::new(cmd.value_of("key").unwrap());
+    hat::crypto::keys::Keeper::import_universal_key(key, dir)
+        .map_err(|e| format!("could not read key from {}: {}", key.display(), e))?;
+
+    let backend = open_backend(dir);
+    let mut hat = hat::Hat::open_repository_without_resume(dir.to_owned(), backend, MAX_BLOB_SIZE)
+        .map_err(|e| e.to_string())?;
+    hat.recover_for_reading().map_err(|e| e.to_string())?;
+    set_restore_wait(&hat, cmd)?;
+    set_fetch_jobs(&mut hat, cmd)?;
+    set_owner_mapping(&mut hat, cmd);
+    let address = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap())?;
+    checkout(hat, &address, path, first, cmd.value_of("path"))
+}
+
+/// Record that `command` stopped at its `--stop-after` deadline, and exit.
+fn exit_stopped(status: &mut hat::status::StatusLog, command: &str) -> ! {
+    let reason = if hat::daemon::shutdown_requested() {
+        "stopped by request"
+    } else {
+        "stopped at the --stop-after deadline"
+    };
+    let msg = format!("{}; run `hat {}` again to continue", reason, command);
+    if let Err(log_err) = status.fail(&msg) {
+        eprintln!("Could not record stop: {}", log_err);
+    }
+    note(&format!("Stopped: {}", msg));
+    std::process::exit(EXIT_STOPPED);
+}
+
+/// The deadline given by `--stop-after`, if any.
+fn stop_after(cmd: &clap::ArgMatches) -> hat::util::Deadline {
+    let budget = cmd.value_of("stop-after").map(|s| {
+        hat::util::parse_duration(s).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    });
+    hat::util::Deadline::after(budget)
+}
+
+/// The retention policy given by the `--keep-*` options of `prune`.
+fn retention_policy(cmd: &clap::ArgMatches) -> Result<hat::hat::retention::Policy, String> {
+    let count = |name: &str| -> Result<usize, String> {
+        cmd.value_of(name).map_or(Ok(0), |s| {
+            s.parse().map_err(|_| format!("Invalid --{}: {}", name, s))
+        })
+    };
+    let policy = hat::hat::retention::Policy {
+        keep_last: count("keep-last")?,
+        keep_daily: count("keep-daily")?,
+        keep_weekly: count("keep-weekly")?,
+        keep_monthly: count("keep-monthly")?,
+        keep_yearly: count("keep-yearly")?,
+    };
+    if policy.is_empty() {
+        return Err("give at least one of the --keep-* options".to_owned());
+    }
+    Ok(policy)
+}
+
+/// The order given by `--order`, in which commits visit the entries of each directory.
+fn file_order(cmd: &clap::ArgMatches) -> Result<hat::util::FileOrder, String> {
+    cmd.value_of("order")
+        .map_or(Ok(hat::util::FileOrder::default()), hat::util::FileOrder::parse)
+}
+
+/// The policy given by `--on-change`, for files that change while a commit reads them.
+fn changed_policy(cmd: &clap::ArgMatches) -> Result<hat::util::ChangedFilePolicy, String> {
+    cmd.value_of("on-change")
+        .map_or(Ok(hat::util::ChangedFilePolicy::default()), hat::util::ChangedFilePolicy::parse)
+}
+
+fn parallel_jobs(cmd: &clap::ArgMatches) -> Result<usize, String> {
+    match cmd.value_of("jobs") {
+        None => Ok(hat::hat::DEFAULT_JOBS),
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Invalid --jobs '{}': expected a positive number", n)),
+        },
+    }
+}
+
+fn bandwidth(cmd: &clap::ArgMatches) -> Result<Option<backend::BandwidthSchedule>, String> {
+    cmd.value_of("bandwidth")
+        .map(backend::BandwidthSchedule::parse)
+        .transpose()
+}
+
+/// The paths `--exclude` and `--include` leave out of commits.
+fn exclude_filter(cmd: &clap::ArgMatches) -> hat::util::ExcludeFilter {
+    let patterns = |name| cmd.values_of(name).into_iter().flatten().collect::<Vec<_>>();
+    hat::util::ExcludeFilter::new(&patterns("exclude"), &patterns("include"))
+}
+
+fn content_filter(
+    cmd: &clap::ArgMatches,
+) -> Result<Option<hat::hat::content_filter::CommandFilter>, String> {
+    let mut filter = hat::hat::content_filter::CommandFilter::new();
+    for rule in cmd.values_of("content-filter").into_iter().flatten() {
+        let (patterns, command) = hat::hat::content_filter::CommandFilter::parse_rule(rule)?;
+        filter = filter.with_rule(patterns, command);
+    }
+    Ok(if filter.is_empty() { None } else { Some(filter) })
+}
+
+/// One scheduled commit of `path` into family `name`, as run by the daemon.
+/// Returns false if it stopped early because of a shutdown request or `preemption`.
+fn daemon_commit<B: backend::StoreBackend>(
+    hat: &mut hat::hat::HatRc<B>,
+    status: &mut hat::status::StatusLog,
+    name: &str,
+    path: &str,
+    preemption: hat::util::Preemption,
+) -> Result<bool, String> {
+    status.begin("commit", 3).map_err(|e| e.to_string())?;
+    hat.check_quota().map_err(|e| e.to_string())?;
+    if hat::daemon::shutdown_requested() {
+        status.fail("stopped by shutdown request before snapshot").map_err(|e| e.to_string())?;
+        return Ok(false);
+    }
+    status.phase("snapshot").map_err(|e| e.to_string())?;
+    let mut family = hat.open_family(name.to_owned()).map_err(|e| e.to_string())?;
+    let completed = family.snapshot_dir_preemptible(PathBuf::from(path), preemption);
+    hat.data_flush().map_err(|e| e.to_string())?;
+
+    if !completed || hat::daemon::shutdown_requested() {
+        // Nothing has been reserved yet; the indexed files make the next run quick.
+        let reason = if hat::daemon::shutdown_requested() {
+            "stopped by shutdown request before commit"
+        } else {
+            "preempted by a higher-priority job before commit"
+        };
+        status.fail(reason).map_err(|e| e.to_string())?;
+        return Ok(false);
+    }
+
+    // Once started, a commit is tracked by the snapshot index and completed by `resume`
+    // if we are killed before it finishes.
+    status.phase("commit").map_err(|e| e.to_string())?;
+    hat.commit(&mut family, None).map_err(|e| e.to_string())?;
+
+    status.phase("meta commit and flush").map_err(|e| e.to_string())?;
+    hat.meta_commit_and_flush().map_err(|e| e.to_string())?;
+
+    status.finish().map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// Compact the local databases of `cache_dir` if one of the default triggers has fired.
+/// Problems are only reported, so they never hold up the command that follows.
+fn maintain_if_due(cache_dir: &Path) {
+    use hat::hat::maintenance;
+
+    let now = chrono::Utc::now().timestamp();
+    let usage = match maintenance::usage(cache_dir) {
+        Ok(usage) => usage,
+        Err(e) => return eprintln!("Could not check local databases: {}", e),
+    };
+    let last = maintenance::Record::load(cache_dir);
+    if let Some(reason) = maintenance::due(&Default::default(), last, &usage, now) {
+        note(&format!("Compacting local databases: {}", reason));
+        match maintenance::compact(cache_dir, now) {
+            Ok(summary) => print_maintenance(&summary),
+            Err(e) => eprintln!("Could not compact local databases: {}", e),
+        }
+    }
+}
+
+fn print_maintenance(summary: &hat::hat::maintenance::Summary) {
+    for family in &summary.pruned_families {
+        note(&format!("Removed key index of {}, which has no snapshots", family));
+    }
+    if summary.pruned_nodes > 0 {
+        note(&format!("Removed unused key index entries: {}", summary.pruned_nodes));
+    }
+    note(&format!(
+        "Local databases: {} bytes (was {} bytes)",
+        summary.bytes_after, summary.bytes_before
+    ));
+}
+
+fn print_status(cache_dir: &Path) {
+    use chrono::TimeZone;
+    use hat::status::OperationState;
+
+    let log = hat::status::StatusLog::open(cache_dir).unwrap();
+    let fmt_ts = |ts: i64| chrono::Utc.timestamp(ts, 0).to_rfc3339();
+
+    if log.operations().is_empty() {
+        println!("No operations recorded.");
+    }
+    for op in log.operations() {
+        let state = match op.state() {
+            OperationState::Running => "running",
+            OperationState::Interrupted => "interrupted",
+            OperationState::Failed => "failed",
+            OperationState::Done => "done",
+        };
+        println!(
+            "{} {} [{}] phase: {} ({}/{}), pid {}, last update {}",
+            fmt_ts(op.started_ts_utc),
+            op.command,
+            state,
+            op.phase,
+            op.step,
+            op.steps,
+            op.pid,
+            fmt_ts(op.updated_ts_utc)
+        );
+        if let Some(ref err) = op.error {
+            println!("    error: {}", err);
+        }
+    }
+
+    let running = log.operations()
+        .iter()
+        .any(|op| op.state() == OperationState::Running);
+    if running {
+        // The index is in use; reading it now could block on its lock.
+        return;
+    }
+    match hat::hat::list_unfinished_snapshots(cache_dir) {
+        Ok(ref pending) if pending.is_empty() => {
+            if !log.interrupted().is_empty() {
+                println!("Interrupted operations left no unfinished snapshots behind.");
+            }
+        }
+        Ok(pending) => {
+            println!("Unfinished work that `resume` will pick up:");
+            for snapshot in pending {
+                println!(
+                    "    {} #{}: {:?}",
+                    snapshot.family_name, snapshot.info.snapshot_id, snapshot.status
+                );
+            }
+        }
+        Err(e) => println!("Could not read snapshot index: {}", e),
+    }
+}
+
+fn main() {
+    // Initialize libraries
+    hat::crypto::keys::init();
+    hat::crypto::keys::set_passphrase_prompt(passphrase_prompt());
+    env_logger::init();
+
+    // Because "snapshot" and "checkout" use the exact same type of arguments, we can make a
+    // template. This template defines two positional arguments, both are required
+    let arg_template = "<NAME> 'Name of the snapshot'
+                        <PATH>... 'The paths of the snapshot; several are walked at the same time'";
+
+    // Where "commit" and "daemon" report the outcome of each commit.
+    let notify_args = "--notify-webhook=[URL] 'POST a JSON report of each commit to URL (or $HAT_NOTIFY_WEBHOOK)'
+                       --notify-email=[ADDRESS] 'Mail a report of each commit through sendmail (or $HAT_NOTIFY_EMAIL)'
+                       --notify-failures-only 'Only report failed commits'";
+    let stop_after_arg = "--stop-after=[DURATION] 'Stop cleanly after DURATION (e.g. 90m or 6h); run again to continue'";
+    let order_arg = "--order=[ORDER] 'Snapshot the entries of each directory in directory (default), name, size or extension order'";
+    let on_change_arg = "--on-change=[POLICY] 'For files that change while being read: retry (default), fuzzy to keep them as read, or fail the commit'";
+    let jobs_arg = "--jobs=[N] 'Snapshot up to N files at the same time (default: 2)'";
+    let bandwidth_arg = "--bandwidth=[SCHEDULE] 'Limit uploads to RATE bytes/s, or by local time of day: e.g. 512K or 08:00-18:00=256K,4M; overrides --limit-upload'";
+    // One value per occurrence, so the flag does not swallow NAME and PATH.
+    let content_filter_arg = Arg::from_usage(
+        "--content-filter=[PATTERN:COMMAND]... 'Pipe files matching PATTERN through COMMAND; store its output, or leave the file out if it exits with 1'",
+    ).number_of_values(1);
+    let exclude_args = [
+        Arg::from_usage(
+            "--exclude=[PATTERN]... 'Leave out paths matching PATTERN, e.g. *.o or target/; without a / it matches the last name only, with a trailing / only directories'",
+        ).number_of_values(1),
+        Arg::from_usage(
+            "--include=[PATTERN]... 'Keep paths matching PATTERN even if an --exclude matches them'",
+        ).number_of_values(1),
+    ];
+    let check_args = "--check-mode 'Print one status line and exit 0, 1, 2 or 3 for OK, WARNING, CRITICAL or UNKNOWN, like a Nagios plugin'
+                      --warn-age=[DURATION] 'In check mode, warn if the last success is older than DURATION'
+                      --crit-age=[DURATION] 'In check mode, be critical if the last success is older than DURATION'";
+
+    // Create valid arguments
+    let version = format!("v{}", crate_version!());
+    let app = App::new("hat")
+        .version(&version[..])
+        .about("Create backup snapshots")
+        .args_from_usage(
+            "-l, --license 'Display the license'
+            --hat_state_dir=[DIR] 'Location of Hat\'s local state'
+            --repo=[NAME] 'Repository in the state directory to use (or $HAT_REPO); each has its own key and caches'
+            --no-auto-resume 'Fail instead of resuming unfinished operations first (or $HAT_NO_AUTO_RESUME)'
+            --slow-backend-op=[DURATION] 'Log backend calls that take DURATION or longer (default 10s; or $HAT_SLOW_BACKEND_OP)'
+            --chunk-cache-age=[DURATION] 'Reuse metadata cached by delete and gc for DURATION (default 12h; 0 disables; or $HAT_CHUNK_CACHE_AGE)'
+            --blob-cache-size=[SIZE] 'Keep up to SIZE of downloaded blobs, e.g. 2G (default 256M; 0 disables; or $HAT_BLOB_CACHE_SIZE)'
+            --limit-upload=[SCHEDULE] 'Limit uploads to RATE bytes/s, or by local time of day: e.g. 512K or 08:00-18:00=256K,4M (or $HAT_LIMIT_UPLOAD)'
+            --limit-download=[SCHEDULE] 'Limit downloads to RATE bytes/s, or by local time of day, as --limit-upload does (or $HAT_LIMIT_DOWNLOAD)'
+            --output=[FORMAT] 'Print the results of ls, gc, recover, delete, diff and verify as text (default) or json (or $HAT_OUTPUT)'",
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Init state directory with a new key and cache dir")
+                .args_from_usage(
+                    "--parent=[PARENT] 'State directory of a read-only parent repository to build on'
+                     --shared 'Allow other state directories to write to the same backend'
+                     --join=[STATE_DIR] 'Write to the backend of STATE_DIR, sharing it'
+                     --passphrase 'Protect the new key with a passphrase (or $HAT_PASSPHRASE), asked for whenever the repository is opened'
+                     <DIR> 'New state directory to initialize; with --repo, the state directory to add the repository to'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repos")
+                .about("List the named repositories in the state directory"),
+        )
+        .subcommand(
+            SubCommand::with_name("commit")
+                .about("Commit a new snapshot")
+                .args_from_usage("<NAME> 'Name of the snapshot'")
+                .arg(
+                    Arg::from_usage("[PATH]... 'The paths of the snapshot; several are walked at the same time'")
+                        .required_unless("stdin"),
+                )
+                .arg(
+                    Arg::from_usage("--stdin 'Commit the tar archive read from stdin instead of PATHs'")
+                        .conflicts_with_all(&["PATH", "seed", "quiesce"]),
+                )
+                .args_from_usage("-f --force 'Commit even if the storage quota is exceeded'")
+                .args_from_usage(stop_after_arg)
+                .args_from_usage(order_arg)
+                .args_from_usage(on_change_arg)
+                .args_from_usage(jobs_arg)
+                .args_from_usage(bandwidth_arg)
+                .args_from_usage("--seed 'First commit of a large tree: show progress, checkpoint often and stop cleanly on Ctrl-C; run again to continue'")
+                .arg(
+                    Arg::from_usage("--progress 'Show the files read, the blobs uploaded and the time left on stderr every second'")
+                        .conflicts_with("stdin"),
+                )
+                .args_from_usage("--durability=[LEVEL] 'Return once the snapshot is in the local index (flushed), also listed in the backend (uploaded; default), or its new blobs read back and checked (verified)'")
+                .args_from_usage("-m --message=[MESSAGE] 'A message to record with the snapshot'")
+                .arg(
+                    Arg::from_usage("--tag=[TAG]... 'A tag to record with the snapshot, e.g. pre-upgrade'")
+                        .number_of_values(1),
+                )
+                .args(&exclude_args)
+                .arg(content_filter_arg.clone())
+                .args_from_usage(notify_args)
+                .args_from_usage(
+                    "--quiesce=[MOUNTPOINT] 'Quiesce the filesystem at MOUNTPOINT while reading PATH'
+                     --quiesce-mode=[MODE] 'freeze (with fsfreeze; default) or sync (sync and wait 5s)'
+                     --quiesce-timeout=[DURATION] 'Thaw a frozen filesystem after DURATION even if not done (default 10m)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Estimate what committing the paths would upload, without storing anything")
+                .args_from_usage(arg_template)
+                .args(&exclude_args)
+                .args_from_usage("--bandwidth=[SCHEDULE] 'Estimate the upload time at RATE bytes/s, or at the rate a schedule allows now: e.g. 512K or 08:00-18:00=256K,4M'"),
+        )
+        .subcommand(
+            SubCommand::with_name("checkout")
+                .about("Checkout a snapshot")
+                .args_from_usage(
+                    "<SNAPSHOT> 'The snapshot: <family>[/<snapshot>], or with --to-stdout-tar <family>/<snapshot>[/path]'
+                     [PATH] 'Directory to check out into'
+                     --to-stdout-tar 'Write the snapshot to stdout as a tar archive instead'
+                     --first-from=[FILE] 'Restore the paths listed in FILE (one per line, relative to the snapshot root) before the rest'
+                     --path=[PATH] 'Restore only this file or directory, given relative to the snapshot root'
+                     --restore-wait=[DURATION] 'Wait at most DURATION for blobs in cold storage to be restored (default 12h)'
+                     --fetch-jobs=[N] 'Fetch up to N blobs from the backend at the same time (default: 4)'
+                     --numeric-owner 'Restore owners by their recorded ids, even where the recorded names have other ids here'",
+                )
+                .arg(
+                    Arg::from_usage("--progress 'Show the files restored on stderr every second'")
+                        .conflicts_with("to-stdout-tar"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("recover").about("Recover list of commit'ed snapshots"))
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Restore a snapshot from the backend and an exported key, without a state directory")
+                .args_from_usage(
+                    "--key=<FILE> 'Key written by `hat export-key`'
+                     <SNAPSHOT> 'The snapshot: <family>[/<snapshot>], or with --to-stdout-tar <family>/<snapshot>[/path]'
+                     [PATH] 'Directory to check out into'
+                     --to-stdout-tar 'Write the snapshot to stdout as a tar archive instead'
+                     --first-from=[FILE] 'Restore the paths listed in FILE (one per line, relative to the snapshot root) before the rest'
+                     --path=[PATH] 'Restore only this file or directory, given relative to the snapshot root'
+                     --restore-wait=[DURATION] 'Wait at most DURATION for blobs in cold storage to be restored (default 12h)'
+                     --fetch-jobs=[N] 'Fetch up to N blobs from the backend at the same time (default: 4)'
+                     --numeric-owner 'Restore owners by their recorded ids, even where the recorded names have other ids here'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-key")
+                .about("Write the repository key to a new file, for restoring with `hat extract`")
+                .args_from_usage("<FILE> 'File to write the key to; it must not exist'"),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate-key")
+                .about("Write new blobs with a new version of the keys; older blobs stay readable"),
+        )
+        .subcommand(
+            SubCommand::with_name("reseal")
+                .about("Seal blobs written with older keys again with the current ones, oldest first")
+                .args_from_usage(
+                    "--limit=[N] 'Number of blobs to look at (default: 100)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("derive")
+                .about("Make a snapshot of part of another, without uploading file contents again")
+                .args_from_usage(
+                    "--include=<PATTERN>... 'Path inside the snapshot to keep, e.g. home/alice/**; * and ? match within a name, ** any number of directories'
+                     --as=<NAME> 'Family of the new snapshot'
+                     <SNAPSHOT> 'The snapshot to derive from: <family>/<snapshot>'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete a snapshot")
+                .args_from_usage(
+                    "<SNAPSHOT> 'The snapshot to delete: <family>/<snapshot>'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("annotate")
+                .about("Add a message, tags or metadata to a snapshot, keeping what it had")
+                .args_from_usage(
+                    "<FAMILY> 'Family of the snapshot'
+                     <ID> 'Id of the snapshot'
+                     --message=[MESSAGE] 'A note, e.g. why the snapshot matters'",
+                )
+                .arg(
+                    Arg::from_usage("--tag=[TAG]... 'A tag, e.g. known-good or pre-incident'")
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::from_usage("--meta=[KEY=VALUE]... 'Free-form metadata'")
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact-history")
+                .about("Compact a chain of snapshots into its last one, deleting the others")
+                .args_from_usage(
+                    "<FAMILY> 'Family whose snapshots to compact'
+                     [FIRST] 'First snapshot of the chain'
+                     [LAST] 'Last snapshot of the chain, which is kept'
+                     --monthly-before=[AGE] 'Instead keep one snapshot per month among those older than AGE (e.g. 90d)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Delete the snapshots a retention policy does not keep")
+                .args_from_usage(
+                    "[FAMILY] 'Family to prune (default: all families)'
+                     --keep-last=[N] 'Keep the N newest snapshots'
+                     --keep-daily=[N] 'Keep the last snapshot of each of the last N days'
+                     --keep-weekly=[N] 'Keep the last snapshot of each of the last N weeks'
+                     --keep-monthly=[N] 'Keep the last snapshot of each of the last N months'
+                     --keep-yearly=[N] 'Keep the last snapshot of each of the last N years'
+                     -p --pretend 'Only list the snapshots that would be deleted'
+                     --gc 'Garbage collect afterwards to reclaim the data of deleted snapshots'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Garbage collect: identify and remove unused data blocks.")
+                .args_from_usage("-p --pretend 'Do not modify any data'")
+                .args_from_usage(stop_after_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("Resume previous failed command.")
+                .args_from_usage("--status 'List the unfinished operations instead of resuming them'"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that all stored blobs are intact, streaming them from the backend")
+                .args_from_usage(stop_after_arg)
+                .args_from_usage(check_args)
+                .arg(
+                    Arg::from_usage("[SNAPSHOT] 'Instead, read back every file of the snapshot <family>/<snapshot>, checking each chunk against its hash'")
+                        .conflicts_with_all(&["check-mode", "warn-age", "crit-age", "stop-after"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-inventory")
+                .about("Check that the backend lists every blob, and spot-check their checksums")
+                .args_from_usage(
+                    "--sample=[N] 'Number of blobs to compare checksums of (default: 100)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("self-test").about(
+                "Commit, verify, restore and compare sample data in a throwaway family, then \
+                 delete it; checks the backend setup",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("sync-index")
+                .about("Reconcile the local indexes with a backend changed by another client"),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Commit snapshots periodically; supports systemd notify and watchdog")
+                .args_from_usage(
+                    "--interval=[SECONDS] 'Time between snapshots of NAME (default: 3600)'
+                     --job=[JOB]... 'Also commit a family given as PRIORITY:NAME:PATH; high priority runs hourly and preempts low priority, which runs daily'
+                     [NAME] 'Name of a high-priority snapshot'
+                     [PATH] 'The path of the snapshot'",
+                )
+                .args_from_usage(order_arg)
+                .args_from_usage(on_change_arg)
+                .args_from_usage(jobs_arg)
+                .args_from_usage(bandwidth_arg)
+                .args(&exclude_args)
+                .arg(content_filter_arg.clone())
+                .args_from_usage(notify_args),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show active and recent operations, and any interrupted work")
+                .args_from_usage(check_args),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List Hat snapshots paths")
+                .args_from_usage(
+                    "[PATH] 'Path to list inside hat: <family>[/<snapshot>[/path]]'
+                     --tag=[TAG] 'Only list snapshots tagged TAG, when committed or annotated'
+                     -l --long 'Show the permissions, owner, group, size and modification time of files'
+                     --json 'Print the listing as JSON, in the models snapshots are stored with'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("maintenance")
+                .about("Compact the local databases when they have grown, or when forced")
+                .args_from_usage("-f --force 'Compact even if it is not due'"),
+        )
+        .subcommand(
+            SubCommand::with_name("debug-bundle")
+                .about("Archive the local index, status log and settings (without keys) for a bug report")
+                .args_from_usage("<FILE> 'Tar archive to write'"),
+        )
+        .subcommand(
+            SubCommand::with_name("export-readonly-bundle")
+                .about("Archive a state directory that can list, verify and check out snapshots, but not change them")
+                .args_from_usage("<FILE> 'Tar archive to write; it holds keys for reading all backups'"),
+        )
+        .subcommand(
+            SubCommand::with_name("backend")
+                .about("Work with the hat-backup-* commands that store the blobs")
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Check that the commands follow the backend protocol, using a throwaway blob")
+                        .args_from_usage("--cmd=[DIR] 'Check the commands in DIR instead of the ones in PATH'"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Inspect repository internals")
+                .subcommand(
+                    SubCommand::with_name("roots")
+                        .about("List the internal roots snapshots and the snapshots each one lists"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Manage the local index of the paths in each snapshot")
+                .subcommand(
+                    SubCommand::with_name("rebuild")
+                        .about("Create the path index, or regenerate it from the snapshot listings"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Use the integrity manifest of a snapshot: each file path with its BLAKE2b checksum")
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Print the manifest in the format of b2sum")
+                        .args_from_usage("<SNAPSHOT> 'Snapshot as <family>/<snapshot>'"),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Check a restored tree against the manifest")
+                        .args_from_usage(
+                            "<SNAPSHOT> 'Snapshot as <family>/<snapshot>'
+                             <DIR> 'Directory the snapshot was checked out to'",
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("Find paths in all snapshots using the path index")
+                .args_from_usage(
+                    "<PATTERN> 'Glob to match, e.g. *.pdf or home/*/notes.txt; without a / it matches the last name only'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("List the versions of a path in the snapshots of a family, using the path index")
+                .args_from_usage(
+                    "<FAMILY> 'Family of the snapshots'
+                     <PATH> 'Path inside the snapshots'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("quota")
+                .about("Show storage usage, or set the storage quota")
+                .args_from_usage("[BYTES] 'New quota in bytes; 0 removes the quota'"),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show how much storage the snapshots reference")
+                .args_from_usage(
+                    "--chunks 'List snapshots by the bytes no other snapshot references'
+                     --key-usage 'Show storage by the version of the keys blobs were written with'
+                     --history 'Show how storage grew, as recorded after each commit and gc'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("Search file contents of a snapshot for a string")
+                .args_from_usage(
+                    "-a --binary 'Also search binary files'
+                     --max-size=[BYTES] 'Skip files larger than this; 0 for no limit (default: 100 MiB)'
+                     <PATH> 'Path to search inside hat: <family>/<snapshot>[/path]'
+                     <PATTERN> 'String to search for'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write a snapshot to stdout as an archive, to restore it without hat")
+                .args_from_usage(
+                    "<SNAPSHOT> 'The snapshot: <family>/<snapshot>'
+                     --format=[FORMAT] 'Archive format; only tar for now (default)'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Write a file of a snapshot to stdout")
+                .args_from_usage("<PATH> 'File to write: <family>/<snapshot>/path'"),
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("Compare a snapshot with a live directory, without restoring it")
+                .args_from_usage(
+                    "<SNAPSHOT> 'Path inside hat: <family>/<snapshot>[/path]'
+                     <PATH> 'Live file or directory to compare with'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("List the files added, removed or modified between two snapshots of a family")
+                .args_from_usage(
+                    "<NAME> 'Family of the snapshots'
+                     <ID1> 'Snapshot to compare from'
+                     <ID2> 'Snapshot to compare to'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("Browse snapshots interactively with ls, cd, cat, get and tab completion; works without FUSE")
+                .args_from_usage("[PATH] 'Directory to start in: <family>[/<snapshot>[/path]]'"),
+        )
+        // Without FUSE support, `mount` explains what to use instead.
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount Hat snapshots on a mountpoint path using FUSE")
+                .args_from_usage(
+                    "<PATH> 'Path of the mount point'
+                     [SNAPSHOT] 'Mount only this family or snapshot: <family>[/<snapshot>]'
+                     --refresh=[DURATION] 'Show new snapshots when browsing, checking at most every DURATION (default 1m; 0 only when .hat-refresh is read)'",
+                )
+                .arg(
+                    Arg::from_usage(
+                        "-o, --option=[OPTIONS]... 'Extra FUSE mount options, comma-separated, e.g. allow_other'",
+                    ).number_of_values(1),
+                ),
+        );
+
+    let matches = app.get_matches();
+
+    // Check for license flag
+    if matches.is_present("license") {
+        license();
+        std::process::exit(0);
+    }
+
+    let flag_or_env = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|x| x.to_string())
+            .or_else(|| env::var_os(name.to_uppercase()).map(|s| s.into_string().unwrap()))
+            .expect(&format!("{} required", name))
+    };
+
+    // Backend commands see which repository they serve, so they can pick its remote.
+    let repo = matches
+        .value_of("repo")
+        .map(|x| x.to_string())
+        .or_else(|| env::var("HAT_REPO").ok());
+    if let Some(ref repo) = repo {
+        env::set_var("HAT_REPO", repo);
+    }
+    if matches.is_present("no-auto-resume") {
+        env::set_var(NO_AUTO_RESUME_VAR, "1");
+    }
+    if let Some(slow) = matches
+        .value_of("slow-backend-op")
+        .map(|x| x.to_string())
+        .or_else(|| env::var(SLOW_BACKEND_OP_VAR).ok())
+    {
+        if let Err(e) = hat::util::parse_duration(&slow) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        env::set_var(SLOW_BACKEND_OP_VAR, slow);
+    }
+    if let Some(age) = matches
+        .value_of("chunk-cache-age")
+        .map(|x| x.to_string())
+        .or_else(|| env::var(CHUNK_CACHE_AGE_VAR).ok())
+    {
+        if let Err(e) = hat::util::parse_duration(&age) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        env::set_var(CHUNK_CACHE_AGE_VAR, age);
+    }
+    if let Some(size) = matches
+        .value_of("blob-cache-size")
+        .map(|x| x.to_string())
+        .or_else(|| env::var(BLOB_CACHE_SIZE_VAR).ok())
+    {
+        if let Err(e) = hat::util::parse_size(&size) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        env::set_var(BLOB_CACHE_SIZE_VAR, size);
+    }
+    for &(flag, var) in &[
+        ("limit-upload", LIMIT_UPLOAD_VAR),
+        ("limit-download", LIMIT_DOWNLOAD_VAR),
+    ] {
+        if let Some(schedule) = matches
+            .value_of(flag)
+            .map(|x| x.to_string())
+            .or_else(|| env::var(var).ok())
+        {
+            if let Err(e) = backend::BandwidthSchedule::parse(&schedule) {
+                eprintln!("Error: --{}: {}", flag, e);
+                std::process::exit(1);
+            }
+            env::set_var(var, schedule);
+        }
+    }
+    if let Some(format) = matches
+        .value_of("output")
+        .map(|x| x.to_string())
+        .or_else(|| env::var(OUTPUT_VAR).ok())
+    {
+        if format != "text" && format != "json" {
+            eprintln!("Error: Invalid output format '{}'; use text or json", format);
+            std::process::exit(1);
+        }
+        env::set_var(OUTPUT_VAR, format);
+    }
+    let repo_dir = |dir: &Path| {
+        repo_state_dir(dir, repo.as_ref().map(|r| &r[..])).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        })
+    };
+
+    // Special cased one-off commands
+    match matches.subcommand() {
+        ("init", Some(cmd)) => {
+            let dir = repo_dir(Path::new(cmd.value_of("DIR").expect("missing DIR to initialize")));
+            if dir.exists() {
+                eprintln!("Error: directory already exists ({})", dir.display());
+                std::process::exit(1);
+            }
+            let passphrase = if !cmd.is_present("passphrase") {
+                None
+            } else if cmd.is_present("parent") || cmd.is_present("join") {
+                eprintln!("Error: --passphrase is for a new key; --parent and --join share one");
+                std::process::exit(1);
+            } else {
+                Some(new_passphrase().unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }))
+            };
+
+            fs::create_dir_all(&dir).unwrap();
+            fs::create_dir_all(dir.join("cache")).unwrap();
+
+            if cmd.is_present("shared") || cmd.is_present("join") {
+                hat::backend::shared::create_writer_id(&dir).unwrap();
+            }
+
+            match (cmd.value_of("parent"), cmd.value_of("join")) {
+                (Some(_), Some(_)) => {
+                    eprintln!("Error: --parent and --join cannot be combined");
+                    std::process::exit(1);
+                }
+                (None, Some(other)) => {
+                    // Both state directories must share the key and take turns on the backend.
+                    let other = PathBuf::from(other);
+                    hat::backend::shared::create_writer_id(&other).unwrap();
+                    hat::crypto::keys::Keeper::copy_universal_key(&other, &dir).unwrap();
+
+                    let backend = open_backend(&dir);
+                    let mut hat = open_repository(dir, backend).unwrap();
+                    hat.lock_backend().unwrap();
+                    hat.recover().unwrap();
+                    hat.unlock_backend().unwrap();
+                }
+                (None, None) => match passphrase {
+                    Some(passphrase) => {
+                        hat::crypto::keys::Keeper::write_new_universal_key_with_passphrase(
+                            &dir,
+                            passphrase.unsecure(),
+                        ).unwrap()
+                    }
+                    None => hat::crypto::keys::Keeper::write_new_universal_key(&dir).unwrap(),
+                },
+                (Some(parent), None) => {
+                    // Sharing the parent's key makes its chunks usable for deduplication.
+                    let parent = PathBuf::from(parent);
+                    hat::crypto::keys::Keeper::copy_universal_key(&parent, &dir).unwrap();
+                    fs::write(
+                        dir.join(PARENT_FILENAME),
+                        format!("{}\n", parent.display()),
+                    ).unwrap();
+
+                    // Import the parent's snapshots and hashes from its backend.
+                    let backend = open_backend(&dir);
+                    let mut hat = open_repository(dir, backend).unwrap();
+                    hat.recover().unwrap();
+                }
+            }
+
+            std::process::exit(0);
+        }
+        ("backend", Some(cmd)) => match cmd.subcommand() {
+            ("check", Some(cmd)) => {
+                // Give up on failing uploads, instead of retrying them forever.
+                let mut backend = backend::CmdBackend::new().with_put_attempts(3);
+                if let Some(dir) = cmd.value_of("cmd") {
+                    backend = backend.with_command_dir(PathBuf::from(dir));
+                }
+                println!("Protocol version {}", backend::PROTOCOL_VERSION);
+                let checks = backend::check_conformance(&backend);
+                for check in &checks {
+                    match check.outcome {
+                        backend::CheckOutcome::Passed => println!("ok   {}", check.name),
+                        backend::CheckOutcome::Skipped(ref why) => {
+                            println!("skip {}: {}", check.name, why)
+                        }
+                        backend::CheckOutcome::Failed(ref why) => {
+                            println!("FAIL {}: {}", check.name, why)
+                        }
+                    }
+                }
+                std::process::exit(if checks.iter().any(|c| c.failed()) { 1 } else { 0 });
+            }
+            _ => {
+                eprintln!("Missing backend command; see hat backend --help");
+                std::process::exit(1);
+            }
+        },
+        ("extract", Some(cmd)) => {
+            // Everything but the key is downloaded again, so a throwaway state directory will do.
+            let dir = env::temp_dir().join(format!("hat-extract-{}", std::process::id()));
+            let res = extract(&dir, cmd);
+            let _ = fs::remove_dir_all(&dir);
+            if let Err(e) = res {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+        _ => (),
+    }
+
+    // Setup config variables that can take their value from either flag or environment.
+    let state_dir = PathBuf::from(flag_or_env("hat_state_dir"));
+    let cache_dir = repo_dir(&state_dir);
+
+    if let ("repos", Some(_cmd)) = matches.subcommand() {
+        let mut names: Vec<_> = match fs::read_dir(state_dir.join(REPOS_DIRNAME)) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(_) => vec![],
+        };
+        names.sort();
+        names.iter().for_each(|name| println!("{}", name));
+        std::process::exit(0);
+    }
+
+    if let ("export-key", Some(cmd)) = matches.subcommand() {
+        let file = Path::new(cmd.value_of("FILE").unwrap());
+        if let Err(e) = hat::crypto::keys::Keeper::export_universal_key(&cache_dir, file) {
+            eprintln!("Error: could not export key to {}: {}", file.display(), e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    if let ("status", Some(cmd)) = matches.subcommand() {
+        if cmd.is_present("check-mode") {
+            use hat::status::{check_commits, Check, CheckState, StatusLog, Thresholds};
+            let res = check_thresholds(cmd, Thresholds::commits()).and_then(|thresholds| {
+                let log = StatusLog::open(&cache_dir).map_err(|e| e.to_string())?;
+                Ok(check_commits(&log, chrono::Utc::now().timestamp(), thresholds))
+            });
+            exit_check(res.unwrap_or_else(|e| Check::new(CheckState::Unknown, e)));
+        }
+        print_status(&cache_dir);
+        std::process::exit(0);
+    }
+
+    if let ("debug-bundle", Some(cmd)) = matches.subcommand() {
+        let path = cmd.value_of("FILE").unwrap();
+        let file = std::io::BufWriter::new(fs::File::create(path).unwrap());
+        let settings = [
+            PARENT_FILENAME,
+            QUOTA_FILENAME,
+            VERIFY_CHECKPOINT_FILENAME,
+            hat::hat::maintenance::MAINTENANCE_FILENAME,
+            hat::hat::CHUNKING_FILENAME,
+            hat::hat::COMPRESSION_FILENAME,
+            hat::backend::shared::WRITER_ID_FILENAME,
+            hat::backend::SLOW_LOG_FILENAME,
+        ];
+        match hat::bundle::write_debug_bundle(&cache_dir, &settings, file) {
+            Ok(names) => for name in names {
+                println!("{}", name);
+            },
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    if let ("export-readonly-bundle", Some(cmd)) = matches.subcommand() {
+        use std::os::unix::fs::OpenOptionsExt;
+        let path = cmd.value_of("FILE").unwrap();
+        let res = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| e.into())
+            .and_then(|file| {
+                let settings = [PARENT_FILENAME, hat::hat::CHUNKING_FILENAME];
+                let file = std::io::BufWriter::new(file);
+                hat::bundle::write_read_only_bundle(&cache_dir, &settings, file)
+            });
+        match res {
+            Ok(names) => for name in names {
+                println!("{}", name);
+            },
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    // Record what we are doing, so `hat status` can report on it.
+    let mut status = hat::status::StatusLog::open(&cache_dir).unwrap();
+    let steps = match matches.subcommand_name() {
+        Some("commit") => 4,
+        Some("checkout") | Some("recover") | Some("delete") | Some("compact-history") | Some("derive") | Some("gc") | Some("verify")
+        | Some("check-inventory") | Some("self-test") => 2,
+        Some("prune") => 3,
+        Some("resume") | Some("maintenance") | Some("rotate-key") | Some("reseal") => 1,
+        _ => 0,
+    };
+    if steps > 0 {
+        // Verifying one snapshot is not a full verification, which check mode reports on.
+        let name = match matches.subcommand() {
+            ("verify", Some(cmd)) if cmd.is_present("SNAPSHOT") => "verify-snapshot",
+            (name, _) => name,
+        };
+        status.begin(name, steps).unwrap();
+        status.phase("open repository").unwrap();
+    }
+
+    match matches.subcommand() {
+        ("resume", Some(cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let cache = chunk_cache(&cache_dir);
+            let res = hat::Hat::open_repository_without_resume(cache_dir, backend, MAX_BLOB_SIZE);
+            let mut hat = check(&mut status, res);
+            hat.set_chunk_cache(cache.map(|c| Box::new(c) as Box<hat::hat::ChunkStore>));
+            let res = if cmd.is_present("status") {
+                hat.unfinished_work()
+            } else {
+                hat.resume()
+            };
+            let work = check(&mut status, res);
+            if work.is_empty() {
+                println!("Nothing to resume");
+            }
+            for work in work {
+                if cmd.is_present("status") {
+                    println!("Unfinished {}", work);
+                } else {
+                    println!("Resumed {}", work);
+                }
+            }
+        }
+        ("commit", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap().to_owned();
+            let paths: Vec<&str> = cmd.values_of("PATH").map_or(vec![], |v| v.collect());
+            let source = if cmd.is_present("stdin") {
+                None
+            } else {
+                Some(&paths[..])
+            };
+            let quota = if cmd.is_present("force") {
+                None
+            } else {
+                read_quota(&cache_dir)
+            };
+
+            let notifier = notifier(cmd);
+            let started = chrono::Utc::now().timestamp();
+            let quiesce = cmd.value_of("quiesce").map(|mountpoint| {
+                let mode = cmd.value_of("quiesce-mode").unwrap_or("freeze");
+                let timeout = cmd.value_of("quiesce-timeout")
+                    .map(hat::util::parse_duration)
+                    .unwrap_or(Ok(hat::util::DEFAULT_QUIESCE_TIMEOUT));
+                hat::util::QuiesceMode::parse(mode)
+                    .and_then(|mode| timeout.map(|timeout| (mode, timeout)))
+                    .map(|(mode, timeout)| {
+                        hat::util::Quiesce::new(mode, PathBuf::from(mountpoint))
+                            .with_timeout(timeout)
+                    })
+                    .and_then(|q| {
+                        // Both must take writes while the filesystem is frozen.
+                        let mut dirs = backend_dirs();
+                        dirs.push(cache_dir.clone());
+                        for dir in &dirs {
+                            q.check_writable(dir)?;
+                        }
+                        Ok(q)
+                    })
+            });
+            let quiesce = check(&mut status, quiesce.transpose());
+            let order = check(&mut status, file_order(cmd));
+            let on_change = check(&mut status, changed_policy(cmd));
+            let durability = cmd.value_of("durability")
+                .map_or(Ok(hat::hat::Durability::default()), hat::hat::Durability::parse);
+            let durability = check(&mut status, durability);
+            let parallel = check(&mut status, parallel_jobs(cmd));
+            let schedule = check(&mut status, bandwidth(cmd));
+            let filter = check(&mut status, content_filter(cmd));
+            let progress = if cmd.is_present("seed") {
+                // Ctrl-C stops the seed at the next file, as --stop-after does.
+                hat::daemon::install_shutdown_handler();
+                let progress = start_seed(&cache_dir, &name, &paths);
+                Some(Arc::new(check(&mut status, progress)))
+            } else {
+                None
+            };
+
+            // Compaction needs the local databases to itself, so it runs before opening them.
+            maintain_if_due(&cache_dir);
+            let backend = open_backend(&cache_dir);
+            if schedule.is_some() {
+                backend.inner().set_upload_schedule(schedule);
+            }
+            let res = open_repository(cache_dir.clone(), backend.clone());
+            let mut hat = check(&mut status, res);
+            hat.set_quota(quota);
+            hat.set_file_order(order);
+            hat.set_exclude_filter(exclude_filter(cmd));
+            hat.set_changed_policy(on_change);
+            hat.set_jobs(parallel);
+            if let Some(filter) = filter {
+                hat.set_content_filter(Arc::new(filter));
+            }
+            let tags = cmd.values_of("tag")
+                .map_or(vec![], |v| v.map(|s| s.to_owned()).collect());
+            hat.set_commit_message(cmd.value_of("message").map(|s| s.to_owned()), tags);
+
+            let deadline = stop_after(cmd);
+            let before = StorageBefore::take(&hat);
+            let after_id = hat.last_blob_id();
+            let display = if cmd.is_present("progress") {
+                // A seed knows its total from the scan of its first run.
+                let total = progress.as_ref().map(|p| p.total().bytes);
+                let scan = paths.iter().map(PathBuf::from).collect();
+                Some(ProgressDisplay::start(&mut hat, total, scan))
+            } else {
+                None
+            };
+            let res = with_backend_lock(&mut hat, |hat| {
+                let progress = progress.clone();
+                let quiesce = quiesce.as_ref();
+                let done = commit(hat, &mut status, &name, source, deadline, quiesce, progress)?;
+                if done {
+                    finish_commit(hat, &mut status, durability, after_id)?;
+                }
+                Ok(done)
+            });
+            drop(display);
+            let error = match res {
+                Ok(false) if hat::daemon::shutdown_requested() => {
+                    Some("stopped by request before commit".to_owned())
+                }
+                Ok(false) => Some("stopped at the --stop-after deadline before commit".to_owned()),
+                _ => res.as_ref().err().cloned(),
+            };
+            let notified = send_commit_report(&notifier, &mut hat, &name, started, &before, error);
+            if let Err(ref e) = notified {
+                eprintln!("Error: {}", e);
+            }
+            report_slow_backend_calls(&backend);
+            if !check(&mut status, res) {
+                if let Some(ref progress) = progress {
+                    println!("The seed is {:.1}% done", progress.percent());
+                }
+                exit_stopped(&mut status, "commit");
+            }
+            if progress.is_some() {
+                if let Err(e) = hat::hat::seed::SeedState::remove(&cache_dir) {
+                    eprintln!("Could not remove the seed state: {}", e);
+                }
+                println!("Seed of {} complete", name);
+            }
+            let stored = hat.store_metrics().since(&before.stored);
+            println!(
+                "Stored {} bytes of new file data; {} bytes were already stored and not uploaded \
+                 again, {} of them by other families",
+                stored.bytes_stored, stored.bytes_reused, stored.bytes_shared
+            );
+            record_stats(&mut hat, &cache_dir, "commit");
+            if notified.is_err() {
+                std::process::exit(1);
+            }
+        }
+        ("estimate", Some(cmd)) => {
+            let name = cmd.value_of("NAME").unwrap();
+            let paths: Vec<PathBuf> = cmd.values_of("PATH").unwrap().map(PathBuf::from).collect();
+            let schedule = bandwidth(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+
+            let backend = open_backend(&cache_dir);
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            hat.set_exclude_filter(exclude_filter(cmd));
+            let estimate = hat.estimate(name, &paths).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            println!(
+                "Files: {} ({} bytes), of which {} ({} bytes) are unchanged and not read",
+                estimate.total.files,
+                estimate.total.bytes,
+                estimate.unchanged.files,
+                estimate.unchanged.bytes
+            );
+            println!("New file data: {} bytes", estimate.new_bytes);
+            println!(
+                "Upload: {} blobs of {} bytes, {} bytes in all",
+                estimate.blobs,
+                estimate.blob_size,
+                estimate.upload_bytes()
+            );
+            match schedule.map(|s| s.rate_now()) {
+                Some(Some(rate)) => {
+                    let secs = estimate.upload_time(rate).as_secs();
+                    println!(
+                        "Upload time at {} bytes/s: {}h{:02}m{:02}s",
+                        rate,
+                        secs / 3600,
+                        secs / 60 % 60,
+                        secs % 60
+                    );
+                }
+                Some(None) => println!("Uploads are not limited now"),
+                None => (),
+            }
+        }
+        ("checkout", Some(cmd)) => {
+            let address = cmd.value_of("SNAPSHOT").unwrap();
+            let to_tar = cmd.is_present("to-stdout-tar");
+            let path = match (cmd.value_of("PATH"), to_tar) {
+                (Some(_), true) => Err("--to-stdout-tar does not take a PATH"),
+                (None, false) => Err("PATH is required without --to-stdout-tar"),
+                (path, _) => Ok(path),
+            };
+            let path = check(&mut status, path);
+            let first = cmd.value_of("first-from").map(read_first_from).transpose();
+            let first = check(&mut status, first);
+
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            let res = set_restore_wait(&hat, cmd);
+            check(&mut status, res);
+            let res = set_fetch_jobs(&mut hat, cmd);
+            check(&mut status, res);
+            set_owner_mapping(&mut hat, cmd);
+            let res = resolve_address(&mut hat, address);
+            let address = check(&mut status, res);
+
+            status.phase("checkout").unwrap();
+            let display = if cmd.is_present("progress") {
+                Some(ProgressDisplay::start(&mut hat, None, vec![]))
+            } else {
+                None
+            };
+            let res = checkout(hat, &address, path, first, cmd.value_of("path"));
+            drop(display);
+            check(&mut status, res);
+        }
+        ("recover", Some(_cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+
+            status.phase("recover").unwrap();
+            let res = hat.recover();
+            check(&mut status, res);
+            if output_json() {
+                // The snapshots known now, as `ls --json` lists them.
+                let snapshots = hat.list_snapshots()
+                    .into_iter()
+                    .filter(|s| !s.is_internal())
+                    .collect();
+                println!("{}", hat::vfs::fs::List::Snapshots(snapshots).to_json());
+            }
+        }
+        ("derive", Some(cmd)) => {
+            let source = cmd.value_of("SNAPSHOT").unwrap();
+            let name = cmd.value_of("as").unwrap().to_owned();
+            let include: Vec<&str> = cmd.values_of("include").unwrap().collect();
+            let filter = hat::util::PathFilter::new(&include);
+
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            let res = resolve_address(&mut hat, source)
+                .and_then(|a| a.expect_snapshot("derive", false).map(|_| a));
+            let source = check(&mut status, res);
+            let (id, from) = (source.snapshot_id().unwrap(), source.family.unwrap());
+
+            status.phase("derive").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                let mut family = hat.open_family(name.clone()).map_err(|e| e.to_string())?;
+                hat.derive(&mut family, &from, id, &filter)
+                    .map_err(|e| e.to_string())?;
+                hat.meta_commit_and_flush().map_err(|e| e.to_string())
+            });
+            check(&mut status, res);
+        }
+        ("delete", Some(cmd)) => {
+            let address = cmd.value_of("SNAPSHOT").unwrap();
+
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            let res = resolve_address(&mut hat, address)
+                .and_then(|a| a.expect_snapshot("delete", false).map(|_| a));
+            let address = check(&mut status, res);
+            let (id, name) = (address.snapshot_id().unwrap(), address.family.unwrap());
+
+            let deleted: Vec<_> = hat.list_snapshots()
+                .into_iter()
+                .filter(|s| s.family_name == name && s.info.snapshot_id == id)
+                .collect();
+
+            status.phase("delete").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                hat.deregister_by_name(name, id).map_err(|e| e.to_string())
+            });
+            check(&mut status, res);
+            if output_json() {
+                println!("{}", hat::vfs::fs::List::Snapshots(deleted).to_json());
+            }
+        }
+        ("annotate", Some(cmd)) => {
+            let family = cmd.value_of("FAMILY").unwrap().to_owned();
+            let res = cmd.value_of("ID").unwrap().parse::<u64>();
+            let id = check(&mut status, res.map_err(|e| format!("Invalid snapshot id: {}", e)));
+            let annotation = check(&mut status, annotation(cmd));
+
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
 
-use hat::backend;
-use std::borrow::ToOwned;
-use std::collections::BTreeSet;
-use std::convert::From;
-use std::ffi;
-use std::fs;
-use std::path::PathBuf;
-use std::sync::Arc;
+            status.phase("annotate").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                hat.annotate(&family, id, annotation)
+                    .map_err(|e| e.to_string())?;
+                hat.meta_commit_and_flush().map_err(|e| e.to_string())
+            });
+            check(&mut status, res);
+            println!("Annotated {}/{}", family, id);
+        }
+        ("compact-history", Some(cmd)) => {
+            let family = cmd.value_of("FAMILY").unwrap().to_owned();
+            let id = |name: &str| {
+                cmd.value_of(name).map(|s| {
+                    s.parse::<u64>()
+                        .map_err(|_| format!("Invalid snapshot id: {}", s))
+                })
+            };
+            let res = match (id("FIRST"), id("LAST"), cmd.value_of("monthly-before")) {
+                (Some(first), Some(last), None) => first.and_then(|f| last.map(|l| vec![(f, l)])),
+                (None, None, Some(_)) => Ok(vec![]),
+                _ => Err("give FIRST and LAST, or --monthly-before".to_owned()),
+            };
+            let mut chains = check(&mut status, res);
+            let res = cmd.value_of("monthly-before").map(|age| {
+                hat::util::parse_duration(age).and_then(|age| {
+                    chrono::Duration::from_std(age)
+                        .map(|age| chrono::Utc::now() - age)
+                        .map_err(|e| e.to_string())
+                })
+            });
+            let monthly_before = check(&mut status, res.transpose());
 
-static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            if let Some(before) = monthly_before {
+                chains = hat.monthly_chains(&family, before);
+            }
 
-fn license() {
-    println!(include_str!("../LICENSE"));
-    println!("clap (Command Line Argument Parser) License:");
-    println!(include_str!("../LICENSE-CLAP"));
-}
+            status.phase("compact").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                let mut dropped = vec![];
+                for (first, last) in chains {
+                    dropped.extend(
+                        hat.compact_snapshots(&family, first, last)
+                            .map_err(|e| e.to_string())?,
+                    );
+                }
+                Ok(dropped)
+            });
+            let dropped = check(&mut status, res);
+            if dropped.is_empty() {
+                println!("Nothing to compact");
+            } else {
+                println!(
+                    "Deleted {}/{}; run `hat gc` to reclaim their data",
+                    family,
+                    snapshot_ranges(&dropped)
+                );
+            }
+        }
+        ("prune", Some(cmd)) => {
+            let res = retention_policy(cmd);
+            let policy = check(&mut status, res);
 
-fn main() {
-    // Initialize libraries
-    unsafe { libsodium_sys::sodium_init() };
-    env_logger::init();
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir.clone(), backend);
+            let mut hat = check(&mut status, res);
+            let families: Vec<String> = match cmd.value_of("FAMILY") {
+                Some(family) => vec![family.to_owned()],
+                None => hat.list_snapshots()
+                    .into_iter()
+                    .filter(|s| !s.is_internal())
+                    .map(|s| s.family_name)
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect(),
+            };
 
-    // Because "snapshot" and "checkout" use the exact same type of arguments, we can make a
-    // template. This template defines two positional arguments, both are required
-    let arg_template = "<NAME> 'Name of the snapshot'
-                        <PATH> 'The path of the snapshot'";
+            status.phase("prune").unwrap();
+            let pretend = cmd.is_present("pretend");
+            let res = with_backend_lock(&mut hat, |hat| {
+                let mut dropped = vec![];
+                for family in families {
+                    let ids = if pretend {
+                        hat.prune_candidates(&family, &policy)
+                    } else {
+                        hat.prune(&family, &policy).map_err(|e| e.to_string())?
+                    };
+                    dropped.push((family, ids));
+                }
+                Ok(dropped)
+            });
+            let dropped = check(&mut status, res);
+            let verb = if pretend { "Would delete" } else { "Deleted" };
+            let mut any = false;
+            for (family, ids) in dropped.into_iter().filter(|(_, ids)| !ids.is_empty()) {
+                println!("{} {}/{}", verb, family, snapshot_ranges(&ids));
+                any = true;
+            }
+            if !any {
+                println!("Nothing to prune");
+            } else if pretend {
+                println!("Nothing was deleted (--pretend)");
+            } else if cmd.is_present("gc") {
+                status.phase("gc").unwrap();
+                let res = hat.gc();
+                let (deleted, live) = check(&mut status, res);
+                println!("Deleted hashes: {:?}", deleted);
+                println!("Live data blobs after deletion: {:?}", live);
+                record_stats(&mut hat, &cache_dir, "gc");
+            } else {
+                println!("Run `hat gc` to reclaim their data");
+            }
+        }
+        ("rotate-key", Some(_cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir.clone(), backend);
+            let mut hat = check(&mut status, res);
 
-    // Create valid arguments
-    let matches = App::new("hat")
-        .version(&format!("v{}", crate_version!())[..])
-        .about("Create backup snapshots")
-        .args_from_usage(
-            "-l, --license 'Display the license'
-            --hat_state_dir=[DIR] 'Location of Hat\'s local state'",
-        )
-        .subcommand(
-            SubCommand::with_name("init")
-                .about("Init state directory with a new key and cache dir")
-                .args_from_usage("<DIR> 'New state directory to initialize'"),
-        )
-        .subcommand(
-            SubCommand::with_name("commit")
-                .about("Commit a new snapshot")
-                .args_from_usage(arg_template),
-        )
-        .subcommand(
-            SubCommand::with_name("checkout")
-                .about("Checkout a snapshot")
-                .args_from_usage(arg_template),
-        )
-        .subcommand(SubCommand::with_name("recover").about("Recover list of commit'ed snapshots"))
-        .subcommand(
-            SubCommand::with_name("delete")
-                .about("Delete a snapshot")
-                .args_from_usage(
-                    "<NAME> 'Name of the snapshot family'
-                     <ID> 'The snapshot id to delete'",
-                ),
-        )
-        .subcommand(
-            SubCommand::with_name("gc")
-                .about("Garbage collect: identify and remove unused data blocks.")
-                .args_from_usage("-p --pretend 'Do not modify any data'"),
-        )
-        .subcommand(SubCommand::with_name("resume").about("Resume previous failed command."))
-        .subcommand(
-            SubCommand::with_name("mount")
-                .about("Mount Hat snapshots on a mountpoint path using FUSE")
-                .args_from_usage("<PATH> 'Path of the mount point'"),
-        )
-        .subcommand(
-            SubCommand::with_name("ls")
-                .about("List Hat snapshots paths")
-                .args_from_usage("<PATH> 'Path to list inside hat'"),
-        )
-        .get_matches();
+            status.phase("rotate").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                hat.rotate_keys().map_err(|e| e.to_string())
+            });
+            let version = check(&mut status, res);
+            println!("New blobs are written with key version {}", version);
+            println!("Read-only bundles exported before cannot read them; export new ones");
+        }
+        ("reseal", Some(cmd)) => {
+            let res = cmd.value_of("limit")
+                .map_or(Ok(hat::hat::DEFAULT_RESEAL_LIMIT), |s| {
+                    s.parse().map_err(|_| format!("Invalid --limit: {}", s))
+                });
+            let limit = check(&mut status, res);
 
-    // Check for license flag
-    if matches.is_present("license") {
-        license();
-        std::process::exit(0);
-    }
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir.clone(), backend);
+            let mut hat = check(&mut status, res);
 
-    let flag_or_env = |name: &str| {
-        matches
-            .value_of(name)
-            .map(|x| x.to_string())
-            .or_else(|| env::var_os(name.to_uppercase()).map(|s| s.into_string().unwrap()))
-            .expect(&format!("{} required", name))
-    };
+            status.phase("reseal").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                hat.reseal_oldest_blobs(limit).map_err(|e| e.to_string())
+            });
+            let resealed = check(&mut status, res);
+            println!("Sealed {} blobs again with key version {}", resealed, hat.key_version());
+        }
+        ("daemon", Some(cmd)) => {
+            use hat::daemon::{Job, Priority};
 
-    // Special cased one-off commands
-    match matches.subcommand() {
-        ("init", Some(dir)) => {
-            let dir = PathBuf::from(dir.value_of("DIR").expect("missing DIR to initialize"));
-            if dir.exists() {
-                eprintln!("Error: directory already exists ({})", dir.display());
+            let mut jobs = vec![];
+            if let (Some(name), Some(path)) = (cmd.value_of("NAME"), cmd.value_of("PATH")) {
+                let interval = cmd.value_of("interval")
+                    .map_or(Ok(Priority::High.default_interval()), |s| {
+                        s.parse().map_err(|_| format!("Invalid --interval: {}", s))
+                    });
+                jobs.push(Job {
+                    priority: Priority::High,
+                    name: name.to_owned(),
+                    path: path.to_owned(),
+                    interval: interval.unwrap_or_else(|e| {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }),
+                });
+            }
+            for spec in cmd.values_of("job").into_iter().flatten() {
+                jobs.push(Job::parse(spec).unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }));
+            }
+            if jobs.is_empty() {
+                eprintln!("Error: give a NAME and PATH, or at least one --job");
                 std::process::exit(1);
             }
+            let order = file_order(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let on_change = changed_policy(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let parallel = parallel_jobs(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let filter = content_filter(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let schedule = bandwidth(cmd).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
 
-            fs::create_dir_all(&dir).unwrap();
-            fs::create_dir_all(dir.join("cache")).unwrap();
-            hat::crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+            hat::daemon::install_shutdown_handler();
+            let watchdog = Arc::new(hat::daemon::Watchdog::new());
 
-            std::process::exit(0);
-        }
-        _ => (),
-    }
+            // Opening the repository resumes anything a previous run left unfinished.
+            let backend = open_backend(&cache_dir);
+            if schedule.is_some() {
+                backend.inner().set_upload_schedule(schedule);
+            }
+            let mut hat =
+                open_repository(cache_dir.clone(), backend).unwrap();
+            hat.set_quota(read_quota(&cache_dir));
+            hat.set_file_order(order);
+            hat.set_exclude_filter(exclude_filter(cmd));
+            hat.set_changed_policy(on_change);
+            hat.set_jobs(parallel);
+            if let Some(filter) = filter {
+                hat.set_content_filter(Arc::new(filter));
+            }
+            let notifier = notifier(cmd);
+            {
+                // Files scanned and blobs uploaded are the progress that keeps us alive.
+                let watchdog = watchdog.clone();
+                hat.set_progress(Some(Arc::new(move |_| {
+                    watchdog.ping();
+                })));
+            }
 
-    // Setup config variables that can take their value from either flag or environment.
-    let cache_dir = PathBuf::from(flag_or_env("hat_state_dir"));
+            let notify = |state: &str| {
+                if let Err(e) = hat::daemon::notify(state) {
+                    eprintln!("Could not notify service manager: {}", e);
+                }
+            };
+            notify("READY=1");
 
-    match matches.subcommand() {
-        ("resume", Some(_cmd)) => {
-            // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::CmdBackend::new());
-            hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
-        }
-        ("commit", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
-            let path = cmd.value_of("PATH").unwrap();
+            let now = || chrono::Utc::now().timestamp();
+            let mut schedule = hat::daemon::Schedule::new(jobs, now());
+            while !hat::daemon::shutdown_requested() {
+                watchdog.ping();
+                let index = match schedule.next(now()) {
+                    Ok(index) => index,
+                    Err(wait) => {
+                        notify(&format!("STATUS=Idle; next commit in {}s", wait));
+                        let mut wait = std::time::Duration::from_secs(wait);
+                        if let Some(interval) = watchdog.interval() {
+                            // Come back in time to ping; the schedule tells the rest.
+                            wait = wait.min(interval);
+                        }
+                        if !hat::daemon::sleep_unless_shutdown(wait) {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let name = schedule.job(index).name.clone();
+                let path = schedule.job(index).path.clone();
 
-            let backend = Arc::new(backend::CmdBackend::new());
-            let mut hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+                // Give way when a high-priority job becomes due, or on shutdown.
+                let preemption = hat::util::Preemption::new();
+                let delay = schedule
+                    .preempt_at(index)
+                    .map(|t| std::time::Duration::from_secs((t - now()).max(0) as u64));
+                let _timer = hat::daemon::PreemptTimer::start(preemption.clone(), delay);
+
+                notify(&format!("STATUS=Committing {}", name));
+                let started = now();
+                let before = StorageBefore::take(&hat);
+                let res = with_backend_lock(&mut hat, |hat| {
+                    daemon_commit(hat, &mut status, &name, &path, preemption)
+                });
+                match res {
+                    Ok(true) => {
+                        let notified =
+                            send_commit_report(&notifier, &mut hat, &name, started, &before, None);
+                        if let Err(e) = notified {
+                            eprintln!("Error: {}", e);
+                        }
+                        record_stats(&mut hat, &cache_dir, "commit");
+                        schedule.done(index, now());
+                    }
+                    Ok(false) if hat::daemon::shutdown_requested() => break,
+                    Ok(false) => println!("Preempted commit of {}; it continues later", name),
+                    Err(e) => {
+                        let error = Some(e.clone());
+                        let notified =
+                            send_commit_report(&notifier, &mut hat, &name, started, &before, error);
+                        eprintln!("Error: scheduled commit failed: {}", e);
+                        if let Err(e) = notified {
+                            eprintln!("Error: {}", e);
+                        }
+                        if let Err(log_err) = status.fail(&e) {
+                            eprintln!("Could not record failure: {}", log_err);
+                        }
+                        schedule.done(index, now());
+                    }
+                }
+            }
 
-            // Update the family index.
-            let mut family = hat
-                .open_family(name.clone())
-                .expect(&format!("Could not open family '{}'", name));
-            family.snapshot_dir(PathBuf::from(path));
+            notify("STOPPING=1");
+        }
+        ("gc", Some(cmd)) => {
+            let deadline = stop_after(cmd);
+            maintain_if_due(&cache_dir);
+            let backend = open_backend(&cache_dir);
+            let res = open_maintenance_repository(cache_dir.clone(), backend);
+            let mut hat = check(&mut status, res);
 
-            // Commit the updated index.
-            hat.commit(&mut family, None).unwrap();
+            status.phase("gc").unwrap();
+            let res = hat.gc_until(deadline);
+            let summary = check(&mut status, res);
+            if output_json() {
+                println!("{}", serde_json::to_string(&summary).unwrap());
+            } else {
+                println!("Deleted hashes: {:?}", summary.deleted_hashes);
+                if summary.retained_blobs > 0 {
+                    println!(
+                        "Unused blobs kept by the storage immutability window: {}",
+                        summary.retained_blobs
+                    );
+                }
+            }
+            if !summary.completed {
+                exit_stopped(&mut status, "gc");
+            }
+            if !output_json() {
+                println!("Live data blobs after deletion: {:?}", summary.live_blobs);
+            }
+            record_stats(&mut hat, &cache_dir, "gc");
+        }
+        ("maintenance", Some(cmd)) => {
+            use hat::hat::maintenance;
 
-            // Meta commit.
-            hat.meta_commit().unwrap();
+            status.phase("compact").unwrap();
+            let now = chrono::Utc::now().timestamp();
+            let res = maintenance::usage(&cache_dir);
+            let usage = check(&mut status, res);
+            let last = maintenance::Record::load(&cache_dir);
+            let reason = match maintenance::due(&Default::default(), last, &usage, now) {
+                None if cmd.is_present("force") => Some("forced".to_owned()),
+                reason => reason,
+            };
+            match reason {
+                Some(reason) => {
+                    println!("Compacting: {}", reason);
+                    let res = maintenance::compact(&cache_dir, now);
+                    print_maintenance(&check(&mut status, res));
+                }
+                None => println!("Nothing to do; use --force to compact anyway"),
+            }
 
-            // Flush any remaining blobs.
-            hat.data_flush().unwrap();
+            // Without a chunk cache, whatever an earlier one left behind is stale.
+            let dir = cache_dir.join(hat::hat::CHUNK_CACHE_DIRNAME);
+            let cache = chunk_cache(&cache_dir).unwrap_or_else(|| {
+                hat::hat::ChunkCache::new(dir).with_max_age(std::time::Duration::from_secs(0))
+            });
+            let expired = check(&mut status, cache.expire());
+            if expired > 0 {
+                println!("Removed expired chunk cache entries: {}", expired);
+            }
         }
-        ("checkout", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
-            let path = cmd.value_of("PATH").unwrap();
+        ("verify", Some(cmd)) if cmd.is_present("SNAPSHOT") => {
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            let res = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap())
+                .and_then(|a| a.expect_snapshot("verify", false).map(|_| a));
+            let address = check(&mut status, res);
+            let (id, family) = (address.snapshot_id().unwrap(), address.family.unwrap());
+
+            status.phase("verify snapshot").unwrap();
+            let report = check(&mut status, hat.verify_snapshot(&family, id));
+            if output_json() {
+                let failures: Vec<_> = report
+                    .failures
+                    .iter()
+                    .map(|(path, err)| json!({"path": path.to_string_lossy(), "error": err}))
+                    .collect();
+                let json = json!({
+                    "family": family,
+                    "snapshot_id": id,
+                    "dirs": report.dirs,
+                    "files": report.files,
+                    "chunks": report.chunks,
+                    "bytes": report.bytes,
+                    "failures": failures,
+                });
+                println!("{}", json);
+            } else {
+                for (path, err) in &report.failures {
+                    println!("Failed: /{}: {}", path.display(), err);
+                }
+                println!(
+                    "Checked {} directories and {} files: {} chunks, {} bytes of file data",
+                    report.dirs, report.files, report.chunks, report.bytes
+                );
+            }
+            if !report.is_ok() {
+                let msg = format!(
+                    "{} paths of {}/{} failed verification",
+                    report.failures.len(),
+                    family,
+                    id
+                );
+                check(&mut status, Err::<(), _>(msg));
+            }
+        }
+        ("verify", Some(cmd)) => {
+            use hat::status::{check_age, Check, CheckState, Thresholds};
+
+            // Monitoring reads a single line, so check mode prints nothing else.
+            let check_mode = cmd.is_present("check-mode");
+            let thresholds = check_or_unknown(
+                &mut status,
+                check_thresholds(cmd, Thresholds::verification()),
+                check_mode,
+            );
+            let deadline = stop_after(cmd);
+            let checkpoint = cache_dir.join(VERIFY_CHECKPOINT_FILENAME);
+            let after_id = match fs::read_to_string(&checkpoint) {
+                Ok(id) => id.trim().parse().unwrap_or_else(|_| {
+                    eprintln!("Ignoring invalid verify checkpoint; verifying from the start");
+                    0
+                }),
+                Err(_) => 0,
+            };
 
-            let backend = Arc::new(backend::CmdBackend::new());
-            let mut hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check_or_unknown(&mut status, res, check_mode);
+
+            status.phase("verify").unwrap();
+            if after_id > 0 && !check_mode {
+                note(&format!("Continuing after blob {}", after_id));
+            }
+            let (checked, failures, resume_after) = hat.verify_blobs_from(after_id, deadline);
+            if !check_mode && output_json() {
+                let names: Vec<_> = failures.iter().map(|(b, _)| b.name.clone()).collect();
+                let hashes = hat.blob_hashes(&names);
+                let affected = if hashes.is_empty() {
+                    Ok(vec![])
+                } else {
+                    hat.affected_versions(&hashes).map_err(|e| e.to_string())
+                };
+                let failed: Vec<_> = failures
+                    .iter()
+                    .map(|(blob, err)| json!({"blob_id": blob.id, "error": err.to_string()}))
+                    .collect();
+                let mut json = json!({
+                    "verified_blobs": checked - failures.len(),
+                    "failures": failed,
+                    "stopped_after_blob": resume_after,
+                });
+                match affected {
+                    Ok(versions) => {
+                        let affected: Vec<_> = versions
+                            .iter()
+                            .map(|v| {
+                                json!({
+                                    "family": v.version.family,
+                                    "path": v.version.path,
+                                    "snapshots": v.snapshots,
+                                })
+                            })
+                            .collect();
+                        json["affected"] = json!(affected);
+                    }
+                    Err(e) => json["affected_error"] = json!(e),
+                }
+                println!("{}", json);
+            } else if !check_mode {
+                for (blob, err) in &failures {
+                    println!("Blob {} failed verification: {}", blob.id, err);
+                }
+                if !failures.is_empty() {
+                    let names: Vec<_> = failures.iter().map(|(b, _)| b.name.clone()).collect();
+                    let hashes = hat.blob_hashes(&names);
+                    let affected = hat.affected_versions(&hashes).map_err(|e| e.to_string());
+                    for line in affected_lines(affected) {
+                        println!("{}", line);
+                    }
+                }
+                println!("Verified blobs: {}", checked - failures.len());
+            }
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            // The next run continues after the blobs checked now, or starts over.
+            match resume_after {
+                Some(id) => {
+                    // Replace the file atomically, so a crash never leaves a partial checkpoint.
+                    let tmp = checkpoint.with_extension(format!("tmp-{}", std::process::id()));
+                    fs::write(&tmp, format!("{}\n", id))
+                        .and_then(|()| fs::rename(&tmp, &checkpoint))
+                        .unwrap()
+                }
+                None => {
+                    let _ = fs::remove_file(&checkpoint);
+                }
+            }
+            if check_mode {
+                let verified = format!("verified {} blobs", checked);
+                let check = if !failures.is_empty() {
+                    let msg =
+                        format!("{} of {} blobs failed verification", failures.len(), checked);
+                    status.fail(&msg).unwrap();
+                    Check::new(CheckState::Critical, msg)
+                } else {
+                    match resume_after {
+                        Some(id) => status.fail(&format!("stopped after blob {}", id)).unwrap(),
+                        None => status.finish().unwrap(),
+                    }
+                    let now = chrono::Utc::now().timestamp();
+                    let last = status.last_success("verify");
+                    check_age("complete verification", last, now, thresholds).with_note(
+                        &match resume_after {
+                            Some(id) => format!("{}, continuing after blob {}", verified, id),
+                            None => verified,
+                        },
+                    )
+                };
+                exit_check(check);
+            }
+            if !failures.is_empty() {
+                let msg = format!("{} of {} blobs failed verification", failures.len(), checked);
+                check(&mut status, Err::<(), _>(msg));
+            }
+            if resume_after.is_some() {
+                exit_stopped(&mut status, "verify");
+            }
         }
-        ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::CmdBackend::new());
-            let mut hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+        ("check-inventory", Some(cmd)) => {
+            let res = cmd.value_of("sample")
+                .map_or(Ok(hat::hat::inventory::DEFAULT_SAMPLE), |s| {
+                    s.parse().map_err(|_| format!("Invalid --sample: {}", s))
+                });
+            let sample = check(&mut status, res);
 
-            hat.recover().unwrap();
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let hat = check(&mut status, res);
+
+            status.phase("check inventory").unwrap();
+            let report = check(&mut status, hat.check_inventory(sample));
+            for blob in &report.missing {
+                println!("Blob {} is missing from the backend", blob.id);
+            }
+            for (blob, err) in &report.failures {
+                println!("Blob {} failed its checksum: {}", blob.id, err);
+            }
+            println!("Blobs: {}", report.blobs);
+            println!("Unknown blobs in the backend: {}", report.unknown);
+            println!("Checksums matched: {}", report.checked);
+            if report.unchecked > 0 {
+                println!("Checksums not available: {}", report.unchecked);
+            }
+            if !report.is_ok() {
+                let msg = format!(
+                    "{} blobs missing, {} failed their checksum",
+                    report.missing.len(),
+                    report.failures.len()
+                );
+                check(&mut status, Err::<(), _>(msg));
+            }
         }
-        ("delete", Some(cmd)) => {
-            let name = cmd.value_of("NAME").unwrap().to_owned();
-            let id = cmd.value_of("ID").unwrap().to_owned();
+        ("self-test", Some(_cmd)) => {
+            use hat::hat::selftest::SELF_TEST_FAMILY;
 
-            let backend = Arc::new(backend::CmdBackend::new());
-            let mut hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
+            let dir = env::temp_dir().join(format!("hat-self-test-{}", std::process::id()));
+            let backend = open_backend(&cache_dir);
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
 
-            hat.deregister_by_name(name, id.parse::<u64>().unwrap())
-                .unwrap();
+            status.phase("self-test").unwrap();
+            let res = with_backend_lock(&mut hat, |hat| {
+                hat.self_test(&dir).map_err(|e| e.to_string())
+            });
+            let _ = fs::remove_dir_all(&dir);
+            let report = check(&mut status, res);
+            println!(
+                "Committed {} files, {} bytes, to {}",
+                report.sample.files, report.sample.bytes, SELF_TEST_FAMILY
+            );
+            println!("Read back {} blobs from the backend", report.verified_blobs);
+            println!("Restored the snapshot and found it identical to the sample");
+            println!(
+                "Deleted the snapshot; garbage collection removed {} hashes",
+                report.deleted_hashes
+            );
+            println!("Self-test passed");
         }
-        ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::CmdBackend::new());
-            let mut hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
-            let (deleted_hashes, live_blobs) = hat.gc().unwrap();
-            println!("Deleted hashes: {:?}", deleted_hashes);
-            println!("Live data blobs after deletion: {:?}", live_blobs);
+        ("sync-index", Some(_cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let res = hat::Hat::open_repository_without_resume(cache_dir, backend, MAX_BLOB_SIZE);
+            let mut hat = check(&mut status, res);
+
+            status.phase("sync index").unwrap();
+            let report = check(&mut status, hat.sync_index());
+            for snapshot in &report.damaged_snapshots {
+                println!("Snapshot {} refers to blobs the backend no longer has", snapshot);
+            }
+            println!("Blobs learned from the backend: {}", report.imported_blobs);
+            println!("Blobs no longer in the backend: {}", report.missing_blobs);
+            println!("Hashes forgotten: {}", report.dropped_hashes);
+            println!("Synced with backend generation {}", report.generation);
         }
+        #[cfg(feature = "fuse")]
         ("mount", Some(cmd)) => {
             let path = cmd.value_of("PATH").unwrap();
-            let backend = Arc::new(backend::CmdBackend::new());
+            let extra: Vec<&str> = cmd.values_of("option").into_iter().flatten().collect();
+            let options = hat::vfs::mount::MountOptions::parse(&extra).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let path_var = env::var_os("PATH").unwrap_or_default();
+            let method = hat::vfs::mount::plan(Path::new(hat::vfs::mount::FUSE_DEVICE), &path_var);
+            let method = method.unwrap_or_else(|e| {
+                eprintln!("Error: {}.", e);
+                eprintln!("Install fuse and load its kernel module, or use `hat shell` to browse snapshots.");
+                std::process::exit(1);
+            });
+            let shim_dir = cache_dir.join("fusermount");
+            let options = hat::vfs::mount::prepare(&method, options, &shim_dir);
+            let options = options.unwrap_or_else(|e| {
+                eprintln!("Error: could not prepare the mount helper: {}", e);
+                std::process::exit(1);
+            });
+            let backend = open_backend(&cache_dir);
 
-            let hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
-            hat::vfs::Fuse::new(hat).mount(&path).unwrap();
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let only = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap_or(""))
+                .and_then(|a| if a.path == Path::new("") {
+                    Ok(a)
+                } else {
+                    Err("mount takes <family>[/<snapshot>]".to_owned())
+                });
+            let only = only.unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let refresh = cmd.value_of("refresh").map(hat::util::parse_duration).transpose();
+            let refresh = refresh.unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            });
+            let mut fuse = hat::vfs::Fuse::new_at(hat, only).with_mount_options(options);
+            if let Some(refresh) = refresh {
+                // Zero leaves refreshing to the control file.
+                fuse = fuse.with_refresh_interval(Some(refresh).filter(|d| d.as_secs() > 0));
+            }
+            if let Err(e) = fuse.mount(&path) {
+                eprintln!("Error: could not mount {}: {}", path, e);
+                eprintln!("Use `hat shell` to browse snapshots without FUSE.");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(feature = "fuse"))]
+        ("mount", Some(_cmd)) => {
+            eprintln!("Error: this hat was built without FUSE support (the `fuse` feature).");
+            eprintln!("Use `hat shell` to browse snapshots, or rebuild with --features fuse.");
+            std::process::exit(1);
+        }
+        ("shell", Some(cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let hat = open_repository(cache_dir, backend).unwrap();
+            let mut shell = hat::vfs::Shell::new(hat::vfs::Filesystem::new(hat));
+            if let Some(path) = cmd.value_of("PATH") {
+                if let Err(e) = shell.execute(&format!("cd {}", path), &mut std::io::stderr()) {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            let stdin = std::io::stdin();
+            let stdout = std::io::stdout();
+            if stdin.is_terminal() {
+                let mut editor = hat::util::LineEditor::new().unwrap();
+                shell.run_interactive(&mut editor, &mut stdout.lock()).unwrap();
+            } else {
+                shell.run(stdin.lock(), &mut stdout.lock()).unwrap();
+            }
         }
         ("ls", Some(cmd)) => {
-            let path: PathBuf = cmd.value_of("PATH").unwrap().into();
-            let backend = Arc::new(backend::CmdBackend::new());
+            let backend = open_backend(&cache_dir);
 
-            let hat = hat::Hat::open_repository(cache_dir, backend, MAX_BLOB_SIZE).unwrap();
-            if let Some(f) = hat::vfs::Filesystem::new(hat).ls(&path).unwrap() {
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let path = resolve_address(&mut hat, cmd.value_of("PATH").unwrap_or(""))
+                .map(|a| a.to_path())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                });
+            let mut fs = hat::vfs::Filesystem::new(hat);
+            if let Some(tag) = cmd.value_of("tag") {
+                fs = fs.with_tag(tag);
+            }
+            let long = cmd.is_present("long");
+            if let Some(f) = fs.ls(&path).unwrap() {
                 match f {
+                    f if cmd.is_present("json") || output_json() => println!("{}", f.to_json()),
                     hat::vfs::fs::List::Root(snapshots) => {
                         snapshots
                             .into_iter()
@@ -221,20 +2937,476 @@ fn main() {
                             .for_each(|name| println!("{}", name));
                     }
                     hat::vfs::fs::List::Snapshots(snapshots) => for si in snapshots {
-                        println!(
-                            "{}",
-                            PathBuf::from(si.family_name)
-                                .join(format!("{}", si.info.snapshot_id))
-                                .display()
-                        );
+                        let mut parts = vec![];
+                        if let Some(message) = si.message() {
+                            parts.push(format!("{:?}", message));
+                        }
+                        if !si.tags.is_empty() {
+                            parts.push(format!("tags: {}", si.tags.join(", ")));
+                        }
+                        let path = PathBuf::from(si.family_name)
+                            .join(format!("{}", si.info.snapshot_id));
+                        match si.manifest {
+                            Some(m) => {
+                                println!(
+                                    "{}\t{}:{} (hat {}; {})",
+                                    path.display(),
+                                    m.hostname,
+                                    m.source_paths.join(","),
+                                    m.hat_version,
+                                    m.settings
+                                        .iter()
+                                        .map(|(name, value)| format!("{}={}", name, value))
+                                        .collect::<Vec<_>>()
+                                        .join(", ")
+                                );
+                                for (file, decision) in m.filtered {
+                                    println!("\t{}: {}", file, decision);
+                                }
+                                for file in m.fuzzy {
+                                    println!("\t{}: changed while being read", file);
+                                }
+                            }
+                            None => println!("{}", path.display()),
+                        }
+                        if !parts.is_empty() {
+                            println!("\t{}", parts.join("; "));
+                        }
+                        for annotation in &si.annotations {
+                            println!("\tannotated {}", annotation_line(annotation));
+                        }
                     },
-                    hat::vfs::fs::List::Dir(files) => for (entry, _) in files {
+                    hat::vfs::fs::List::Dir(files) => for (entry, content) in files {
+                        let columns = if long {
+                            format!("{} ", hat::vfs::fs::long_columns(&entry, &content))
+                        } else {
+                            String::new()
+                        };
                         let name_os_string: ffi::OsString = entry.info.name.into();
-                        println!("{}", path.join(name_os_string).display());
+                        let path = path.join(name_os_string);
+                        match content {
+                            hat::hat::walker::Content::Unreadable(error) => {
+                                println!("{}{}\t(unreadable: {})", columns, path.display(), error)
+                            }
+                            hat::hat::walker::Content::Link(ref target) if long => {
+                                println!("{}{} -> {}", columns, path.display(), target.display())
+                            }
+                            _ => println!("{}{}", columns, path.display()),
+                        }
                     },
                 }
             }
         }
+        ("quota", Some(cmd)) => {
+            match cmd.value_of("BYTES") {
+                None => (),
+                Some("0") => {
+                    let _ = fs::remove_file(cache_dir.join(QUOTA_FILENAME));
+                }
+                Some(bytes) => {
+                    let bytes = bytes.parse::<u64>().unwrap_or_else(|e| {
+                        eprintln!("Error: The quota must be a number of bytes: {}", e);
+                        std::process::exit(1);
+                    });
+                    fs::write(cache_dir.join(QUOTA_FILENAME), format!("{}\n", bytes)).unwrap();
+                }
+            }
+
+            let quota = read_quota(&cache_dir);
+            let backend = open_backend(&cache_dir);
+            let hat = open_repository(cache_dir, backend).unwrap();
+            println!("Storage used: {} bytes", hat.storage_usage());
+            match quota {
+                Some(quota) => println!("Quota: {} bytes", quota),
+                None => println!("Quota: none"),
+            }
+        }
+        ("stats", Some(cmd)) if cmd.is_present("history") => {
+            if let Err(e) = print_stats_history(&cache_dir) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        ("stats", Some(cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let report = hat.chunk_report();
+            report_retrieve_failures(hat.retrieve_report());
+            let report = match report {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("Snapshots: {}", report.snapshots.len());
+            println!(
+                "Chunks: {} ({} bytes, {} bytes shared between snapshots)",
+                report.chunks.len(),
+                report.total_bytes(),
+                report.shared_bytes()
+            );
+            if cmd.is_present("chunks") {
+                for s in &report.snapshots {
+                    println!(
+                        "{}/{}: {} bytes unique ({} of {} chunks), {} bytes referenced",
+                        s.family_name,
+                        s.snapshot_id,
+                        s.unique_bytes,
+                        s.unique_chunks,
+                        s.chunks,
+                        s.bytes
+                    );
+                }
+            }
+            if cmd.is_present("key-usage") {
+                println!("Current key version: {}", hat.key_version());
+                for usage in hat.key_usage() {
+                    let version = match usage.key_version {
+                        Some(v) => v.to_string(),
+                        None => "unknown".to_owned(),
+                    };
+                    println!(
+                        "Key version {}: {} blobs ({} bytes)",
+                        version, usage.blobs, usage.bytes
+                    );
+                }
+            }
+        }
+        ("debug", Some(cmd)) => match cmd.subcommand() {
+            ("roots", Some(_cmd)) => {
+                let backend = open_backend(&cache_dir);
+                let mut hat =
+                    open_repository(cache_dir, backend).unwrap();
+                let roots = hat.list_roots();
+                report_retrieve_failures(hat.retrieve_report());
+                let roots = match roots {
+                    Ok(roots) => roots,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+                for (root, listed) in roots {
+                    println!(
+                        "{}/{}: {} ({:?}), {} snapshots",
+                        root.family_name,
+                        root.info.snapshot_id,
+                        root.created.to_rfc3339(),
+                        root.status,
+                        listed.len()
+                    );
+                    for s in listed {
+                        println!("  {}/{}", s.family_name, s.id);
+                    }
+                }
+            }
+            _ => {
+                eprintln!("Missing debug command; see hat debug --help");
+                std::process::exit(1);
+            }
+        },
+        ("index", Some(cmd)) => match cmd.subcommand() {
+            ("rebuild", Some(_cmd)) => {
+                let backend = open_backend(&cache_dir);
+                let mut hat =
+                    open_repository(cache_dir, backend).unwrap();
+                let res = hat.rebuild_path_index();
+                report_retrieve_failures(hat.retrieve_report());
+                if let Err(e) = res {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                eprintln!("Missing index command; see hat index --help");
+                std::process::exit(1);
+            }
+        },
+        ("manifest", Some(cmd)) => {
+            let (verify, cmd) = match cmd.subcommand() {
+                ("show", Some(cmd)) => (false, cmd),
+                ("verify", Some(cmd)) => (true, cmd),
+                _ => {
+                    eprintln!("Missing manifest command; see hat manifest --help");
+                    std::process::exit(1);
+                }
+            };
+            let backend = open_backend(&cache_dir);
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let address = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap())
+                .and_then(|a| a.expect_snapshot("manifest", false).map(|_| a))
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                });
+            let (id, family) = (address.snapshot_id().unwrap(), address.family.unwrap());
+
+            if !verify {
+                match hat.integrity_manifest(&family, id) {
+                    Ok(manifest) => std::io::stdout().write_all(&manifest[..]).unwrap(),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                let dir = Path::new(cmd.value_of("DIR").unwrap());
+                let report = match hat.verify_integrity(&family, id, dir) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                };
+                for path in &report.changed {
+                    println!("Changed: {}", path.display());
+                }
+                for path in &report.missing {
+                    println!("Missing: {}", path.display());
+                }
+                println!("Files checked: {}", report.checked);
+                if !report.is_ok() {
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("find", Some(cmd)) => {
+            let backend = open_backend(&cache_dir);
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            match hat.find_paths(cmd.value_of("PATTERN").unwrap()) {
+                Ok(found) => for v in found {
+                    println!(
+                        "{}/{}/{} (snapshots {})",
+                        v.version.family,
+                        v.snapshots.last().unwrap(),
+                        v.version.path,
+                        snapshot_ranges(&v.snapshots)
+                    );
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("history", Some(cmd)) => {
+            let family = cmd.value_of("FAMILY").unwrap();
+            let path = cmd.value_of("PATH").unwrap().trim_matches('/');
+            let backend = open_backend(&cache_dir);
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            match hat.path_history(family, path) {
+                Ok(ref versions) if versions.is_empty() => {
+                    eprintln!("Error: {} is in no snapshot of {}", path, family);
+                    std::process::exit(1);
+                }
+                Ok(versions) => for v in versions {
+                    let size = v
+                        .version
+                        .byte_length
+                        .map_or("-".to_string(), |n| format!("{} bytes", n));
+                    println!(
+                        "{}/{}/{}: {}, snapshots {}",
+                        family,
+                        v.snapshots[0],
+                        path,
+                        size,
+                        snapshot_ranges(&v.snapshots)
+                    );
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("grep", Some(cmd)) => {
+            let path = cmd.value_of("PATH").unwrap();
+            let pattern = cmd.value_of("PATTERN").unwrap();
+            let max_size = cmd.value_of("max-size")
+                .map_or(Ok(hat::vfs::grep::DEFAULT_MAX_FILE_SIZE), |s| {
+                    s.parse().map_err(|_| format!("Invalid --max-size: {}", s))
+                })
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                });
+            let backend = open_backend(&cache_dir);
+
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let path = resolve_address(&mut hat, path)
+                .map(|a| a.to_path())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                });
+            let matcher = hat::vfs::grep::Matcher::new(pattern.as_bytes(), cmd.is_present("binary"));
+            let max_size = if max_size == 0 { None } else { Some(max_size) };
+            let mut fs = hat::vfs::Filesystem::new(hat);
+            let res = fs.grep(&path, &matcher, max_size, |file, m| {
+                match m {
+                    hat::vfs::grep::Match::Line { number, line } => println!(
+                        "{}:{}:{}",
+                        file.display(),
+                        number,
+                        String::from_utf8_lossy(&line[..])
+                    ),
+                    hat::vfs::grep::Match::Binary => {
+                        println!("Binary file {} matches", file.display())
+                    }
+                }
+            });
+            report_retrieve_failures(fs.retrieve_report());
+            let summary = match res {
+                Ok(summary) => summary,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            };
+            if summary.skipped_large > 0 {
+                eprintln!("Skipped {} files larger than --max-size", summary.skipped_large);
+            }
+            if summary.matched_files == 0 {
+                std::process::exit(1);
+            }
+        }
+        ("export", Some(cmd)) => {
+            match cmd.value_of("format").unwrap_or("tar") {
+                "tar" => (),
+                format => check(&mut status, Err(format!("Unknown export format: {}", format))),
+            }
+            let backend = open_backend(&cache_dir);
+
+            let res = open_repository(cache_dir, backend);
+            let mut hat = check(&mut status, res);
+            let res = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap())
+                .and_then(|a| a.expect_snapshot("export", false).map(|_| a));
+            let address = check(&mut status, res);
+            let (id, family) = (address.snapshot_id().unwrap(), address.family.unwrap());
+
+            // Only the archive goes to stdout, so it can be piped to `tar -x`.
+            let stdout = std::io::stdout();
+            let out = std::io::BufWriter::new(stdout.lock());
+            let res = hat.export_tar(&family, id, out);
+            report_retrieve_failures(hat.retrieve_report());
+            eprintln!("Wrote {} entries", check(&mut status, res));
+        }
+        ("cat", Some(cmd)) => {
+            let backend = open_backend(&cache_dir);
+
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let path = resolve_address(&mut hat, cmd.value_of("PATH").unwrap())
+                .map(|a| a.to_path())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                });
+            // Only the file goes to stdout, so it can be piped to `diff` or `less`.
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            let mut fs = hat::vfs::Filesystem::new(hat);
+            let res = fs
+                .cat(&path, &mut out)
+                .and_then(|_| out.flush().map_err(From::from));
+            report_retrieve_failures(fs.retrieve_report());
+            if let Err(e) = res {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        ("compare", Some(cmd)) => {
+            use hat::vfs::compare::Difference;
+
+            let live: PathBuf = cmd.value_of("PATH").unwrap().into();
+            let backend = open_backend(&cache_dir);
+
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let snapshot = resolve_address(&mut hat, cmd.value_of("SNAPSHOT").unwrap())
+                .map(|a| a.to_path())
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                });
+            let mut fs = hat::vfs::Filesystem::new(hat);
+            let res = fs.compare(&snapshot, &live, |path, d| match d {
+                Difference::Missing => println!("- {}", path.display()),
+                Difference::Added => println!("+ {}", path.display()),
+                Difference::Changed(changes) => {
+                    let names: Vec<_> = changes.iter().map(|c| c.name()).collect();
+                    println!("M {} ({})", path.display(), names.join(", "))
+                }
+                Difference::Unreadable(e) => println!("! {}: {}", path.display(), e),
+            });
+            report_retrieve_failures(fs.retrieve_report());
+            match res {
+                Ok(ref summary) if summary.differences == 0 => (),
+                Ok(summary) => {
+                    eprintln!(
+                        "{} differences ({} paths in both compared)",
+                        summary.differences, summary.compared
+                    );
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        ("diff", Some(cmd)) => {
+            use hat::hat::diff::Change;
+
+            let family = cmd.value_of("NAME").unwrap();
+            let ids: Vec<u64> = ["ID1", "ID2"]
+                .iter()
+                .map(|arg| {
+                    cmd.value_of(arg).unwrap().parse().unwrap_or_else(|e| {
+                        eprintln!("Error: Invalid snapshot id: {}", e);
+                        std::process::exit(2);
+                    })
+                })
+                .collect();
+            let backend = open_backend(&cache_dir);
+
+            let mut hat = open_repository(cache_dir, backend).unwrap();
+            let diffs = hat.diff_snapshots(family, ids[0], ids[1]).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                std::process::exit(2);
+            });
+            if output_json() {
+                // Exits as the listing below does, which then has nothing to list.
+                println!("{}", serde_json::to_string(&diffs).unwrap());
+                if !diffs.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            let mut counts = [0; 3];
+            let mut delta = 0;
+            for d in &diffs {
+                let (sign, i) = match d.change {
+                    Change::Added => ("+", 0),
+                    Change::Removed => ("-", 1),
+                    Change::Modified => ("M", 2),
+                };
+                counts[i] += 1;
+                delta += d.size_delta();
+                match (d.change, d.old_size.or(d.new_size)) {
+                    (Change::Modified, _) => {
+                        println!("{} {} ({:+} bytes)", sign, d.path.display(), d.size_delta())
+                    }
+                    (_, Some(size)) => println!("{} {} ({} bytes)", sign, d.path.display(), size),
+                    (_, None) => println!("{} {}", sign, d.path.display()),
+                }
+            }
+            if !diffs.is_empty() {
+                eprintln!(
+                    "{} added, {} removed, {} modified ({:+} bytes)",
+                    counts[0], counts[1], counts[2], delta
+                );
+                std::process::exit(1);
+            }
+        }
         _ => {
             println!(
                 "No subcommand specified\n{}\nFor more information re-run with --help",
@@ -243,4 +3415,8 @@ fn main() {
             std::process::exit(1);
         }
     }
+
+    if steps > 0 {
+        status.finish().unwrap();
+    }
 }
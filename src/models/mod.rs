@@ -10,7 +10,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use db;
+use libc;
 use std::ffi;
+use std::fs;
 
 #[derive(Serialize, Deserialize)]
 pub struct Snapshot {
@@ -24,6 +27,61 @@ pub struct Snapshot {
     pub msg: String,
     #[serde(rename = "c")]
     pub created_ts_utc: i64,
+    #[serde(rename = "a", default)]
+    pub manifest: Option<SnapshotManifest>,
+    #[serde(rename = "n", default)]
+    pub annotations: Vec<SnapshotAnnotation>,
+    #[serde(rename = "t", default)]
+    pub tags: Vec<String>,
+}
+
+impl Snapshot {
+    /// Whether this snapshot holds repository metadata rather than user files.
+    pub fn is_internal(&self) -> bool {
+        db::is_internal_family(&self.family_name)
+    }
+}
+
+/// Where a snapshot was taken from, and with what.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    #[serde(rename = "h")]
+    pub hostname: String,
+    /// Absolute paths committed into the snapshot.
+    #[serde(rename = "p")]
+    pub source_paths: Vec<String>,
+    #[serde(rename = "v")]
+    pub hat_version: String,
+    /// Repository settings at the time, as name and value.
+    #[serde(rename = "s")]
+    pub settings: Vec<(String, String)>,
+    /// Root of the integrity manifest, as the bytes of a `hash::tree::HashRef`.
+    #[serde(rename = "i", default)]
+    pub integrity: Option<Vec<u8>>,
+    /// Files the content filter left out or replaced, as path and decision.
+    #[serde(rename = "f", default)]
+    pub filtered: Vec<(String, String)>,
+    /// Files that changed while being read, and were stored as read.
+    #[serde(rename = "z", default)]
+    pub fuzzy: Vec<String>,
+    /// Total size of the files in the snapshot.
+    #[serde(rename = "b", default)]
+    pub file_bytes: Option<u64>,
+}
+
+/// A note added to a snapshot after it was committed, e.g. to mark it as known to be good.
+/// Annotations are only ever appended, so the original message and earlier notes are kept.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotAnnotation {
+    #[serde(rename = "c")]
+    pub created_ts_utc: i64,
+    #[serde(rename = "m", default)]
+    pub message: Option<String>,
+    #[serde(rename = "t", default)]
+    pub tags: Vec<String>,
+    /// Free-form metadata, as name and value.
+    #[serde(rename = "k", default)]
+    pub meta: Vec<(String, String)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -40,6 +98,8 @@ pub enum Packing {
     GZip,
     #[serde(rename = "s")]
     Snappy,
+    #[serde(rename = "z")]
+    Zstd,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -72,6 +132,9 @@ pub enum LeafType {
     TreeList,
     #[serde(rename = "s")]
     SnapshotList,
+    /// The integrity manifest of a snapshot.
+    #[serde(rename = "m")]
+    Manifest,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -94,6 +157,9 @@ pub struct HashRef {
     pub leaf_type: LeafType,
     #[serde(rename = "e")]
     pub extra: ExtraInfo,
+    /// Offset and length of the data within a composite leaf shared by small files.
+    #[serde(rename = "p", default)]
+    pub range: Option<(u64, u64)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -114,6 +180,11 @@ pub struct UserGroup {
     pub user_id: i64,
     #[serde(rename = "g")]
     pub group_id: i64,
+    /// Names of the user and group at commit time, as ids differ between systems.
+    #[serde(rename = "un", default)]
+    pub user_name: Option<String>,
+    #[serde(rename = "gn", default)]
+    pub group_name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -202,6 +273,12 @@ impl FileName {
             FileName::RawAndLossyUtf8(_, ref s) => s,
         }
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            FileName::Utf8(ref s) => s.as_bytes(),
+            FileName::RawAndLossyUtf8(ref raw, _) => raw,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -222,6 +299,49 @@ pub struct FileInfo {
     pub permissions: Permissions,
     #[serde(rename = "s")]
     pub snapshot_ts_utc: i64,
+    /// Extended attributes, as name and value, ordered by name.
+    #[serde(rename = "x", default)]
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// POSIX ACLs beyond the permissions, recorded with the `acl` feature.
+    #[serde(rename = "r", default)]
+    pub acl: Option<Acl>,
+}
+
+/// The POSIX access control lists of a file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    /// The entries checked on access.
+    #[serde(rename = "a", default)]
+    pub access: Vec<AclEntry>,
+    /// The entries files created in a directory start with.
+    #[serde(rename = "d", default)]
+    pub default: Vec<AclEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclEntry {
+    #[serde(rename = "t")]
+    pub tag: AclTag,
+    /// Read, write and execute bits, as in a mode.
+    #[serde(rename = "p")]
+    pub perm: u16,
+}
+
+/// Whom an ACL entry applies to; users and groups by id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AclTag {
+    #[serde(rename = "u")]
+    UserObj,
+    #[serde(rename = "U")]
+    User(u32),
+    #[serde(rename = "g")]
+    GroupObj,
+    #[serde(rename = "G")]
+    Group(u32),
+    #[serde(rename = "m")]
+    Mask,
+    #[serde(rename = "o")]
+    Other,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -232,6 +352,73 @@ pub enum Content {
     Directory(HashRef),
     #[serde(rename = "l")]
     SymbolicLink(Vec<u8>),
+    /// A file that could not be read when the snapshot was taken, with the error.
+    #[serde(rename = "e")]
+    Unreadable(String),
+    /// The data of a file with more than one hard link, with the device and inode the links
+    /// share. Checkout restores the files of a snapshot that share both as links to one file.
+    #[serde(rename = "h")]
+    HardLink(HashRef, u64, u64),
+    /// A device node, FIFO or socket.
+    #[serde(rename = "n")]
+    Special(SpecialFile),
+}
+
+/// A file without contents, recreated from its kind alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpecialFile {
+    /// A character device, with its device number.
+    #[serde(rename = "c")]
+    CharDevice(u64),
+    /// A block device, with its device number.
+    #[serde(rename = "b")]
+    BlockDevice(u64),
+    #[serde(rename = "p")]
+    Fifo,
+    #[serde(rename = "s")]
+    Socket,
+}
+
+impl SpecialFile {
+    /// The kind of the file `meta` describes, if it is special.
+    pub fn from_metadata(meta: &fs::Metadata) -> Option<SpecialFile> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let file_type = meta.file_type();
+        if file_type.is_char_device() {
+            Some(SpecialFile::CharDevice(meta.rdev()))
+        } else if file_type.is_block_device() {
+            Some(SpecialFile::BlockDevice(meta.rdev()))
+        } else if file_type.is_fifo() {
+            Some(SpecialFile::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialFile::Socket)
+        } else {
+            None
+        }
+    }
+
+    /// The file type bits of a mode of this kind.
+    pub fn file_type_mode(&self) -> u32 {
+        match *self {
+            SpecialFile::CharDevice(_) => libc::S_IFCHR,
+            SpecialFile::BlockDevice(_) => libc::S_IFBLK,
+            SpecialFile::Fifo => libc::S_IFIFO,
+            SpecialFile::Socket => libc::S_IFSOCK,
+        }
+    }
+
+    /// The device number of a device, and 0 for other kinds.
+    pub fn rdev(&self) -> u64 {
+        match *self {
+            SpecialFile::CharDevice(rdev) | SpecialFile::BlockDevice(rdev) => rdev,
+            SpecialFile::Fifo | SpecialFile::Socket => 0,
+        }
+    }
+
+    /// Whether this is a device, which only root may create.
+    pub fn is_device(&self) -> bool {
+        matches!(*self, SpecialFile::CharDevice(_) | SpecialFile::BlockDevice(_))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -248,3 +435,59 @@ pub struct Files {
     #[serde(rename = "f")]
     pub files: Vec<File>,
 }
+
+/// Keys derived from the universal key that are needed to read a repository, without the
+/// universal key itself. See `crypto::keys::Keeper::read_only_keys`.
+#[derive(Serialize, Deserialize)]
+pub struct ReadOnlyKeys {
+    #[serde(rename = "v")]
+    pub key_version: u64,
+    #[serde(rename = "f")]
+    pub fingerprint: Vec<u8>,
+    #[serde(rename = "b")]
+    pub blob_authentication: Vec<u8>,
+    #[serde(rename = "c")]
+    pub chunking: Vec<u8>,
+    /// Public and secret key of the data, access and naming key pairs.
+    #[serde(rename = "d")]
+    pub data: (Vec<u8>, Vec<u8>),
+    #[serde(rename = "a")]
+    pub access: (Vec<u8>, Vec<u8>),
+    #[serde(rename = "n")]
+    pub naming: (Vec<u8>, Vec<u8>),
+    /// Keys of the earlier key versions, for blobs written before the keys were rotated.
+    #[serde(rename = "o", default)]
+    pub older: Vec<KeyGeneration>,
+}
+
+/// The read-only keys that change when the keys of a repository are rotated.
+#[derive(Serialize, Deserialize)]
+pub struct KeyGeneration {
+    #[serde(rename = "v")]
+    pub key_version: u64,
+    #[serde(rename = "b")]
+    pub blob_authentication: Vec<u8>,
+    #[serde(rename = "d")]
+    pub data: (Vec<u8>, Vec<u8>),
+    #[serde(rename = "a")]
+    pub access: (Vec<u8>, Vec<u8>),
+}
+
+/// The universal key, encrypted under a key derived from a passphrase with Argon2id. See
+/// `crypto::keys::Keeper::write_new_universal_key_with_passphrase`.
+#[derive(Serialize, Deserialize)]
+pub struct LockedKey {
+    #[serde(rename = "s")]
+    pub salt: Vec<u8>,
+    /// Argon2id memory cost in KiB, number of passes and lanes.
+    #[serde(rename = "m")]
+    pub memory_kib: u32,
+    #[serde(rename = "t")]
+    pub passes: u32,
+    #[serde(rename = "p")]
+    pub lanes: u32,
+    #[serde(rename = "n")]
+    pub nonce: Vec<u8>,
+    #[serde(rename = "k")]
+    pub sealed_key: Vec<u8>,
+}
@@ -17,6 +17,7 @@
 use chrono;
 use db;
 use hash;
+use models;
 use std::sync::Arc;
 use tags;
 
@@ -43,8 +44,14 @@ impl SnapshotIndex {
         self.index.lock().snapshot_lookup(family_name, snapshot_id)
     }
 
-    pub fn reserve(&mut self, family: String) -> db::SnapshotInfo {
-        self.index.lock().snapshot_reserve(family)
+    /// Reserve the next snapshot of `family`, committed with `msg` and `tags`.
+    pub fn reserve(
+        &mut self,
+        family: String,
+        msg: Option<&str>,
+        tags: &[String],
+    ) -> db::SnapshotInfo {
+        self.index.lock().snapshot_reserve(family, msg, tags)
     }
 
     /// Update existing snapshot.
@@ -53,10 +60,22 @@ impl SnapshotIndex {
         snapshot: &db::SnapshotInfo,
         hash: &hash::Hash,
         hash_ref: &hash::tree::HashRef,
+        manifest: Option<&models::SnapshotManifest>,
+    ) {
+        self.index
+            .lock()
+            .snapshot_update(snapshot, hash, hash_ref, manifest);
+    }
+
+    /// Replace the annotations of a snapshot.
+    pub fn set_annotations(
+        &mut self,
+        snapshot: &db::SnapshotInfo,
+        annotations: &[models::SnapshotAnnotation],
     ) {
         self.index
             .lock()
-            .snapshot_update(snapshot, "anonymous", hash, hash_ref);
+            .snapshot_set_annotations(snapshot, annotations)
     }
 
     /// ReadyCommit.
@@ -73,6 +92,13 @@ impl SnapshotIndex {
             .snapshot_set_tag(snapshot, tags::Tag::Done)
     }
 
+    /// Commit this snapshot again, as its data was lost before it reached the backend.
+    pub fn redo_commit(&mut self, snapshot: &db::SnapshotInfo) {
+        self.index
+            .lock()
+            .snapshot_set_tag(snapshot, tags::Tag::InProgress)
+    }
+
     /// We are deleting this snapshot.
     pub fn will_delete(&mut self, snapshot: &db::SnapshotInfo) {
         self.index
@@ -116,12 +142,21 @@ impl SnapshotIndex {
         family: &str,
         created: chrono::DateTime<chrono::Utc>,
         msg: &str,
+        tags: &[String],
         hash_ref: &hash::tree::HashRef,
+        manifest: Option<&models::SnapshotManifest>,
         work_opt: Option<db::SnapshotWorkStatus>,
     ) {
-        self.index
-            .lock()
-            .snapshot_recover(snapshot_id, family, created, msg, hash_ref, work_opt)
+        self.index.lock().snapshot_recover(
+            snapshot_id,
+            family,
+            created,
+            msg,
+            tags,
+            hash_ref,
+            manifest,
+            work_opt,
+        )
     }
 
     /// Flush the hash index to clear internal buffers and commit the underlying database.
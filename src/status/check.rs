@@ -0,0 +1,177 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks for monitoring systems, in the form of Nagios plugins.
+//!
+//! A check prints a single line, `HAT <STATE> - <message>`, optionally followed by performance
+//! data after a `|`, and exits with the code of its state.
+
+use super::{OperationState, StatusLog};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckState {
+    Ok,
+    Warning,
+    Critical,
+    /// The check itself could not run, e.g. as the repository could not be opened.
+    Unknown,
+}
+
+impl CheckState {
+    /// The exit code monitoring systems expect for this state.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            CheckState::Ok => 0,
+            CheckState::Warning => 1,
+            CheckState::Critical => 2,
+            CheckState::Unknown => 3,
+        }
+    }
+}
+
+impl fmt::Display for CheckState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            CheckState::Ok => "OK",
+            CheckState::Warning => "WARNING",
+            CheckState::Critical => "CRITICAL",
+            CheckState::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How old the last success may get before a check warns, and before it is critical.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    pub warning: Duration,
+    pub critical: Duration,
+}
+
+impl Thresholds {
+    /// For commits, which are expected at least daily.
+    pub fn commits() -> Thresholds {
+        Thresholds {
+            warning: Duration::from_secs(26 * 3600),
+            critical: Duration::from_secs(48 * 3600),
+        }
+    }
+
+    /// For verification, which is expected to complete a pass over all blobs weekly.
+    pub fn verification() -> Thresholds {
+        Thresholds {
+            warning: Duration::from_secs(8 * 24 * 3600),
+            critical: Duration::from_secs(15 * 24 * 3600),
+        }
+    }
+}
+
+/// The outcome of a check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub state: CheckState,
+    pub message: String,
+    /// Age of the last success and the thresholds it was held to, in seconds.
+    pub age: Option<(i64, Thresholds)>,
+}
+
+impl Check {
+    pub fn new(state: CheckState, message: String) -> Check {
+        Check {
+            state: state,
+            message: message,
+            age: None,
+        }
+    }
+
+    /// Add `note` to the message.
+    pub fn with_note(mut self, note: &str) -> Check {
+        self.message = format!("{}; {}", self.message, note);
+        self
+    }
+
+    /// Raise the state to `state` if it is worse, and add `note` to the message.
+    pub fn escalate(mut self, state: CheckState, note: &str) -> Check {
+        self.state = self.state.max(state);
+        self.with_note(note)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Messages must stay on the single line monitoring systems read.
+        let message = self.message.replace('\n', " ").replace('|', "/");
+        write!(f, "HAT {} - {}", self.state, message)?;
+        if let Some((age, thresholds)) = self.age {
+            write!(
+                f,
+                " | age={}s;{};{}",
+                age,
+                thresholds.warning.as_secs(),
+                thresholds.critical.as_secs()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_age(secs: i64) -> String {
+    match secs {
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 48 * 3600 => format!("{}h", s / 3600),
+        s => format!("{}d", s / (24 * 3600)),
+    }
+}
+
+/// Check that `what` last succeeded at `last` (UTC seconds), recently enough at `now`.
+pub fn check_age(what: &str, last: Option<i64>, now: i64, thresholds: Thresholds) -> Check {
+    let last = match last {
+        Some(last) => last,
+        None => return Check::new(CheckState::Critical, format!("no {} recorded", what)),
+    };
+    let age = (now - last).max(0);
+    let state = if age as u64 >= thresholds.critical.as_secs() {
+        CheckState::Critical
+    } else if age as u64 >= thresholds.warning.as_secs() {
+        CheckState::Warning
+    } else {
+        CheckState::Ok
+    };
+    Check {
+        state: state,
+        message: format!("last {} {} ago", what, format_age(age)),
+        age: Some((age, thresholds)),
+    }
+}
+
+/// Check that a commit succeeded recently, and that the latest one did not fail.
+pub fn check_commits(log: &StatusLog, now: i64, thresholds: Thresholds) -> Check {
+    let check = check_age("successful commit", log.last_success("commit"), now, thresholds);
+    let latest = log.operations().iter().rev().find(|op| op.command == "commit");
+    match latest.map(|op| (op.state(), op)) {
+        Some((OperationState::Failed, op)) => check.escalate(
+            CheckState::Warning,
+            &format!(
+                "latest commit failed: {}",
+                op.error.as_ref().map_or("", |e| &e[..])
+            ),
+        ),
+        Some((OperationState::Interrupted, _)) => {
+            check.escalate(CheckState::Warning, "latest commit was interrupted")
+        }
+        _ => check,
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bookkeeping of active and recent operations in the state directory.
+//!
+//! Every command that touches the repository records what it is doing (command, phase and
+//! progress) in a small file next to the key. If the command fails, the error is recorded too.
+//! An operation that never finished and whose process is gone was interrupted, and is likely
+//! to be picked up by `resume`.
+
+use chrono;
+use errors::HatError;
+use libc;
+use serde_cbor;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod check;
+#[cfg(test)]
+mod tests;
+
+pub use self::check::{check_age, check_commits, Check, CheckState, Thresholds};
+
+pub const STATUS_FILENAME: &str = "operation-status";
+
+/// Number of finished operations to remember.
+const HISTORY_LEN: usize = 20;
+
+/// Names temporary files uniquely within this process.
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    pub command: String,
+    pub pid: u32,
+    pub phase: String,
+    pub step: u64,
+    pub steps: u64,
+    pub started_ts_utc: i64,
+    pub updated_ts_utc: i64,
+    pub finished_ts_utc: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperationState {
+    Running,
+    Interrupted,
+    Failed,
+    Done,
+}
+
+impl Operation {
+    pub fn state(&self) -> OperationState {
+        match (self.finished_ts_utc, &self.error) {
+            (Some(_), &Some(_)) => OperationState::Failed,
+            (Some(_), &None) => OperationState::Done,
+            (None, _) if process_is_alive(self.pid) => OperationState::Running,
+            (None, _) => OperationState::Interrupted,
+        }
+    }
+}
+
+fn process_is_alive(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    if pid == 0 || pid > i32::MAX as u32 {
+        // Not a single process; kill() would signal a whole group.
+        return false;
+    }
+    // Signal 0 only checks that the process exists.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct History {
+    operations: Vec<Operation>,
+    /// When each command last finished successfully, kept beyond the bounded history.
+    #[serde(default)]
+    last_success: BTreeMap<String, i64>,
+}
+
+/// An exclusive lock on the status log of a state directory, held until dropped, so commands
+/// updating it at the same time do not lose each other's changes.
+struct LogLock {
+    /// Closing the file releases the lock.
+    _file: fs::File,
+}
+
+impl LogLock {
+    fn acquire(log: &Path) -> Result<LogLock, HatError> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log.with_extension("lock"))?;
+        loop {
+            // The lock goes with the file, also if the process dies.
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(LogLock { _file: file });
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(From::from(e));
+            }
+        }
+    }
+}
+
+/// Persistent log of operations kept in the state directory.
+pub struct StatusLog {
+    path: PathBuf,
+    history: History,
+}
+
+impl StatusLog {
+    /// Open the status log of the state directory `dir`.
+    pub fn open(dir: &Path) -> Result<StatusLog, HatError> {
+        let mut log = StatusLog {
+            path: dir.join(STATUS_FILENAME),
+            history: History::default(),
+        };
+        log.reload()?;
+        Ok(log)
+    }
+
+    /// All known operations, oldest first.
+    pub fn operations(&self) -> &[Operation] {
+        &self.history.operations[..]
+    }
+
+    /// When `command` last finished successfully, if ever.
+    pub fn last_success(&self, command: &str) -> Option<i64> {
+        self.history.last_success.get(command).cloned()
+    }
+
+    /// Operations that never finished, but whose process is no longer running.
+    pub fn interrupted(&self) -> Vec<&Operation> {
+        self.history
+            .operations
+            .iter()
+            .filter(|op| op.state() == OperationState::Interrupted)
+            .collect()
+    }
+
+    /// Record the start of a new operation made up of `steps` phases.
+    pub fn begin(&mut self, command: &str, steps: u64) -> Result<(), HatError> {
+        let _lock = LogLock::acquire(&self.path)?;
+        self.reload()?;
+        let ts = now();
+        self.history.operations.push(Operation {
+            command: command.to_owned(),
+            pid: process::id(),
+            phase: "starting".to_owned(),
+            step: 0,
+            steps: steps,
+            started_ts_utc: ts,
+            updated_ts_utc: ts,
+            finished_ts_utc: None,
+            error: None,
+        });
+
+        let len = self.history.operations.len();
+        if len > HISTORY_LEN {
+            self.history.operations.drain(..len - HISTORY_LEN);
+        }
+
+        self.write()
+    }
+
+    /// Move the current operation on to its next phase.
+    pub fn phase(&mut self, name: &str) -> Result<(), HatError> {
+        let _lock = LogLock::acquire(&self.path)?;
+        self.reload()?;
+        if let Some(op) = self.current_mut() {
+            op.phase = name.to_owned();
+            op.step += 1;
+            op.updated_ts_utc = now();
+        }
+        self.write()
+    }
+
+    /// Mark the current operation as successfully finished.
+    pub fn finish(&mut self) -> Result<(), HatError> {
+        let _lock = LogLock::acquire(&self.path)?;
+        self.reload()?;
+        let ts = now();
+        let mut command = None;
+        if let Some(op) = self.current_mut() {
+            op.step = op.steps;
+            op.updated_ts_utc = ts;
+            op.finished_ts_utc = Some(ts);
+            command = Some(op.command.clone());
+        }
+        if let Some(command) = command {
+            self.history.last_success.insert(command, ts);
+        }
+        self.write()
+    }
+
+    /// Mark the current operation as failed with `error`.
+    pub fn fail(&mut self, error: &str) -> Result<(), HatError> {
+        let _lock = LogLock::acquire(&self.path)?;
+        self.reload()?;
+        if let Some(op) = self.current_mut() {
+            let ts = now();
+            op.updated_ts_utc = ts;
+            op.finished_ts_utc = Some(ts);
+            op.error = Some(error.to_owned());
+        }
+        self.write()
+    }
+
+    fn current_mut(&mut self) -> Option<&mut Operation> {
+        let pid = process::id();
+        self.history
+            .operations
+            .iter_mut()
+            .rev()
+            .find(|op| op.pid == pid && op.finished_ts_utc.is_none())
+    }
+
+    fn reload(&mut self) -> Result<(), HatError> {
+        // Other commands may have logged their operations since we last looked.
+        self.history = match fs::File::open(&self.path) {
+            Ok(mut f) => {
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf)?;
+                serde_cbor::from_slice(&buf[..])?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => History::default(),
+            Err(e) => return Err(From::from(e)),
+        };
+        Ok(())
+    }
+
+    fn write(&self) -> Result<(), HatError> {
+        // Replace the file atomically so a crash never leaves a partial log behind. The name of
+        // the temporary file is our own, so a concurrent writer can not replace it under us.
+        let tmp = self.path.with_extension(format!(
+            "tmp-{}-{}",
+            process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::SeqCst)
+        ));
+        {
+            let mut f = fs::File::create(&tmp)?;
+            f.write_all(&serde_cbor::to_vec(&self.history)?[..])?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
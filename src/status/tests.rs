@@ -0,0 +1,170 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use status::*;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+fn setup_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("hat-status-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn operation_lifecycle() {
+    let dir = setup_dir("lifecycle");
+
+    let mut log = StatusLog::open(&dir).unwrap();
+    assert!(log.operations().is_empty());
+
+    log.begin("commit", 2).unwrap();
+    log.phase("snapshot").unwrap();
+    {
+        let log = StatusLog::open(&dir).unwrap();
+        let op = log.operations().last().unwrap();
+        assert_eq!(op.command, "commit");
+        assert_eq!(op.phase, "snapshot");
+        assert_eq!((op.step, op.steps), (1, 2));
+        assert_eq!(op.state(), OperationState::Running);
+    }
+
+    log.finish().unwrap();
+    log.begin("gc", 1).unwrap();
+    log.fail("backend unavailable").unwrap();
+
+    let log = StatusLog::open(&dir).unwrap();
+    let ops = log.operations();
+    assert_eq!(ops.len(), 2);
+    assert_eq!(ops[0].state(), OperationState::Done);
+    assert_eq!(ops[0].step, ops[0].steps);
+    assert_eq!(ops[1].state(), OperationState::Failed);
+    assert_eq!(ops[1].error, Some("backend unavailable".to_owned()));
+    assert!(log.interrupted().is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn concurrent_updates_are_kept() {
+    let dir = setup_dir("concurrent");
+
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let dir = dir.clone();
+            thread::spawn(move || {
+                let mut log = StatusLog::open(&dir).unwrap();
+                log.begin(&format!("first-{}", i), 1).unwrap();
+                log.begin(&format!("second-{}", i), 1).unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let log = StatusLog::open(&dir).unwrap();
+    assert_eq!(log.operations().len(), 16);
+    // Nothing is left behind but the log and its lock.
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![STATUS_FILENAME.to_owned(), format!("{}.lock", STATUS_FILENAME)]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn detect_interrupted() {
+    let dir = setup_dir("interrupted");
+
+    let mut log = StatusLog::open(&dir).unwrap();
+    log.begin("commit", 3).unwrap();
+
+    // Pretend the operation was started by a process that is now gone.
+    log.history.operations[0].pid = i32::MAX as u32 + 1;
+    log.write().unwrap();
+
+    let log = StatusLog::open(&dir).unwrap();
+    assert_eq!(log.operations()[0].state(), OperationState::Interrupted);
+    assert_eq!(log.interrupted().len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn history_is_bounded() {
+    let dir = setup_dir("bounded");
+
+    let mut log = StatusLog::open(&dir).unwrap();
+    for _ in 0..2 * HISTORY_LEN {
+        log.begin("gc", 1).unwrap();
+        log.finish().unwrap();
+    }
+    assert_eq!(StatusLog::open(&dir).unwrap().operations().len(), HISTORY_LEN);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn check_commit_age() {
+    let dir = setup_dir("check");
+    let thresholds = Thresholds::commits();
+    let hours = |h: i64| h * 3600;
+
+    let mut log = StatusLog::open(&dir).unwrap();
+    let check = check_commits(&log, 0, thresholds);
+    assert_eq!(check.state, CheckState::Critical);
+    assert_eq!(check.to_string(), "HAT CRITICAL - no successful commit recorded");
+
+    log.begin("commit", 1).unwrap();
+    log.finish().unwrap();
+    let done = log.last_success("commit").unwrap();
+    let check = check_commits(&log, done + hours(3), thresholds);
+    assert_eq!(check.state, CheckState::Ok);
+    assert_eq!(
+        check.to_string(),
+        "HAT OK - last successful commit 3h ago | age=10800s;93600;172800"
+    );
+    assert_eq!(check_commits(&log, done + hours(30), thresholds).state, CheckState::Warning);
+    assert_eq!(check_commits(&log, done + hours(50), thresholds).state, CheckState::Critical);
+
+    // A failed commit warns, even while the last success is recent; other commands do not.
+    log.begin("commit", 1).unwrap();
+    log.fail("backend unavailable").unwrap();
+    log.begin("gc", 1).unwrap();
+    log.finish().unwrap();
+    let check = check_commits(&log, done + hours(3), thresholds);
+    assert_eq!(check.state, CheckState::Warning);
+    assert!(check.message.ends_with("latest commit failed: backend unavailable"));
+    assert_eq!(log.last_success("commit"), Some(done));
+
+    // The last success outlives the bounded history.
+    for _ in 0..30 {
+        log.begin("verify", 1).unwrap();
+        log.finish().unwrap();
+    }
+    let log = StatusLog::open(&dir).unwrap();
+    assert!(log.operations().iter().all(|op| op.command == "verify"));
+    assert_eq!(check_commits(&log, done + hours(3), thresholds).state, CheckState::Ok);
+    assert_eq!(CheckState::Unknown.exit_code(), 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
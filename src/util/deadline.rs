@@ -0,0 +1,99 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+/// A point in time after which long-running work stops at its next checkpoint.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// A deadline that never passes.
+    pub fn none() -> Deadline {
+        Deadline(None)
+    }
+
+    /// A deadline `budget` from now, or none without a budget or with one too far away to
+    /// represent.
+    pub fn after(budget: Option<Duration>) -> Deadline {
+        Deadline(budget.and_then(|d| Instant::now().checked_add(d)))
+    }
+
+    pub fn is_past(&self) -> bool {
+        self.0.is_some_and(|t| Instant::now() >= t)
+    }
+
+    /// Time left before the deadline, if there is one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0.map(|t| t.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Parse a duration such as `90`, `90s`, `45m`, `6h` or `1d`; plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("Invalid duration: '{}'", s))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        _ => return Err(format!("Invalid duration unit in '{}'; use s, m, h or d", s)),
+    };
+    n.checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Duration too long: '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("45m").unwrap(), Duration::from_secs(45 * 60));
+        assert_eq!(parse_duration("6h").unwrap(), Duration::from_secs(6 * 3600));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(24 * 3600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("5m3s").is_err());
+    }
+
+    #[test]
+    fn parse_overflow() {
+        let max = u64::MAX.to_string();
+        assert_eq!(parse_duration(&max).unwrap(), Duration::from_secs(u64::MAX));
+        assert!(parse_duration(&format!("{}m", max)).is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 3600)).is_err());
+        assert!(parse_duration("99999999999999999999999s").is_err());
+        assert!(!Deadline::after(Some(Duration::from_secs(u64::MAX))).is_past());
+    }
+
+    #[test]
+    fn deadline() {
+        assert!(!Deadline::none().is_past());
+        assert_eq!(Deadline::none().remaining(), None);
+        assert!(Deadline::after(Some(Duration::from_secs(0))).is_past());
+        assert!(!Deadline::after(Some(Duration::from_secs(3600))).is_past());
+    }
+}
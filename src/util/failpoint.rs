@@ -0,0 +1,78 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Labelled points where tests can make an operation fail part-way, to check that `resume` and
+//! `recover` bring the repository back to a consistent state from there.
+//!
+//! Outside tests, `fail_point` does nothing.
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+#[cfg(test)]
+thread_local! {
+    static ARMED: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Fail with an error if a test armed the point `name` on this thread.
+#[inline]
+pub fn fail_point(name: &str) -> Result<(), String> {
+    #[cfg(test)]
+    {
+        let hit = ARMED.with(|armed| {
+            let mut armed = armed.borrow_mut();
+            if armed.as_ref().is_some_and(|a| a == name) {
+                armed.take();
+                true
+            } else {
+                false
+            }
+        });
+        if hit {
+            return Err(format!("Stopped at fail point {}", name));
+        }
+    }
+    let _ = name;
+    Ok(())
+}
+
+/// Make the next `fail_point(name)` on this thread fail, once.
+#[cfg(test)]
+pub fn arm(name: &str) {
+    ARMED.with(|armed| *armed.borrow_mut() = Some(name.to_owned()));
+}
+
+/// Forget the armed point; returns whether it was still armed, i.e. never reached.
+#[cfg(test)]
+pub fn disarm() -> bool {
+    ARMED.with(|armed| armed.borrow_mut().take().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fails_once_on_the_arming_thread() {
+        arm("point");
+        assert!(fail_point("other").is_ok());
+        thread::spawn(|| assert!(fail_point("point").is_ok()))
+            .join()
+            .unwrap();
+        assert!(fail_point("point").is_err());
+        assert!(fail_point("point").is_ok());
+        assert!(!disarm());
+    }
+}
@@ -16,11 +16,103 @@ use std::fs;
 use std::io;
 use std::io::Read;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use util::{Preemption, ProgressEvent, ProgressFn};
+
+/// What a snapshot does with a file that changed while it was being read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChangedFilePolicy {
+    /// Read it again, up to `CHANGED_FILE_RETRIES` times, then keep it as fuzzy.
+    #[default]
+    Retry,
+    /// Keep what was read, and list the file as fuzzy in the snapshot manifest.
+    Fuzzy,
+    /// Fail the commit.
+    Fail,
+}
+
+/// Number of times a file that changed while being read is read again under
+/// `ChangedFilePolicy::Retry`.
+pub const CHANGED_FILE_RETRIES: usize = 3;
+
+impl ChangedFilePolicy {
+    pub fn parse(s: &str) -> Result<ChangedFilePolicy, String> {
+        match s {
+            "retry" => Ok(ChangedFilePolicy::Retry),
+            "fuzzy" => Ok(ChangedFilePolicy::Fuzzy),
+            "fail" => Ok(ChangedFilePolicy::Fail),
+            _ => Err(format!(
+                "Invalid policy for changed files '{}'; use retry, fuzzy or fail",
+                s
+            )),
+        }
+    }
+}
+
+/// The size and modification time of a file, which change when it is written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    pub fn new(meta: &fs::Metadata) -> FileStamp {
+        FileStamp {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+/// Watches a file being read for changes, by comparing its stamp at the end of the file with
+/// the one taken before it was opened.
+pub struct ChangeWatch {
+    path: PathBuf,
+    before: FileStamp,
+    changed: Arc<AtomicBool>,
+    fail: bool,
+}
+
+impl ChangeWatch {
+    /// Set `changed` if the file at `path` no longer has the stamp `before` once read; with
+    /// `fail`, also fail the read so what was read is not stored.
+    pub fn new(
+        path: PathBuf,
+        before: FileStamp,
+        changed: Arc<AtomicBool>,
+        fail: bool,
+    ) -> ChangeWatch {
+        ChangeWatch {
+            path: path,
+            before: before,
+            changed: changed,
+            fail: fail,
+        }
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let after = fs::metadata(&self.path).map(|meta| FileStamp::new(&meta));
+        if after.ok() == Some(self.before) {
+            return Ok(());
+        }
+        self.changed.store(true, Ordering::SeqCst);
+        if self.fail {
+            Err(io::Error::other("changed while being read"))
+        } else {
+            Ok(())
+        }
+    }
+}
 
 pub enum FileIterator {
     File(io::BufReader<fs::File>),
     Buf(Vec<u8>, usize),
-    #[cfg(all(test, feature = "benchmarks"))]
+    Preemptible(Box<FileIterator>, Preemption),
+    Watched(Box<FileIterator>, ChangeWatch),
+    Counted(Box<FileIterator>, ProgressFn),
     Reader(Box<Read + Send>),
 }
 
@@ -35,7 +127,21 @@ impl FileIterator {
         FileIterator::Buf(contents, 0)
     }
 
-    #[cfg(all(test, feature = "benchmarks"))]
+    /// Fail further reads once `preemption` is requested.
+    pub fn preemptible(self, preemption: Preemption) -> FileIterator {
+        FileIterator::Preemptible(Box::new(self), preemption)
+    }
+
+    /// Check for changes to the file at the end of it, as `watch` says.
+    pub fn watched(self, watch: ChangeWatch) -> FileIterator {
+        FileIterator::Watched(Box::new(self), watch)
+    }
+
+    /// Report the bytes read as `ProgressEvent::BytesHashed` to `progress`.
+    pub fn counted(self, progress: ProgressFn) -> FileIterator {
+        FileIterator::Counted(Box::new(self), progress)
+    }
+
     pub fn from_reader<R>(r: Box<R>) -> FileIterator
     where
         R: Read + Send + 'static,
@@ -59,7 +165,27 @@ impl Read for FileIterator {
                     Ok(next.len())
                 }
             }
-            #[cfg(all(test, feature = "benchmarks"))]
+            FileIterator::Preemptible(ref mut it, ref preemption) => {
+                if preemption.is_requested() {
+                    Err(io::Error::other("preempted"))
+                } else {
+                    it.read(buf)
+                }
+            }
+            FileIterator::Watched(ref mut it, ref watch) => {
+                let n = it.read(buf)?;
+                if n == 0 && !buf.is_empty() {
+                    watch.check()?;
+                }
+                Ok(n)
+            }
+            FileIterator::Counted(ref mut it, ref progress) => {
+                let n = it.read(buf)?;
+                if n > 0 {
+                    progress(ProgressEvent::BytesHashed(n as u64));
+                }
+                Ok(n)
+            }
             FileIterator::Reader(ref mut r) => r.read(buf),
         }
     }
@@ -0,0 +1,71 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Free space on the filesystem holding a path.
+
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FreeSpace {
+    /// Bytes available to unprivileged users.
+    pub available: u64,
+    /// Allocation unit of the filesystem; files take up whole blocks.
+    pub block_size: u64,
+}
+
+/// Free space where `path` is, or would be created: missing directories are looked up through
+/// their closest existing parent.
+pub fn free_space(path: &Path) -> io::Result<FreeSpace> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = if stat.f_frsize > 0 {
+        stat.f_frsize as u64
+    } else {
+        stat.f_bsize as u64
+    };
+    Ok(FreeSpace {
+        available: stat.f_bavail as u64 * block_size,
+        block_size: block_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn missing_directories_use_their_parent() {
+        let tmp = env::temp_dir();
+        let space = free_space(&tmp).unwrap();
+        assert!(space.block_size > 0);
+
+        let missing = free_space(&tmp.join("hat-missing").join("deeper")).unwrap();
+        assert_eq!(missing.block_size, space.block_size);
+    }
+}
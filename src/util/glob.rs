@@ -0,0 +1,220 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shell-style patterns for file names and paths.
+
+use std::fmt;
+
+/// Match `name` against `pattern`, where `*` matches any run of bytes and `?` any one byte.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*`, if the bytes after it stop matching.
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Path patterns such as `home/*/Documents/**`. Each `/`-separated part of a pattern matches
+/// one part of a path as in `glob_match`, except `**`, which matches any number of parts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PathFilter {
+    patterns: Vec<Vec<Vec<u8>>>,
+}
+
+impl PathFilter {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> PathFilter {
+        PathFilter {
+            patterns: patterns
+                .iter()
+                .map(|p| split(p.as_ref().as_bytes()))
+                .collect(),
+        }
+    }
+
+    /// Whether a pattern matches the path `path`.
+    pub fn matches(&self, path: &[u8]) -> bool {
+        let path = split(path);
+        self.patterns.iter().any(|p| match_parts(p, &path))
+    }
+
+    /// Whether a pattern may match a path inside the directory `dir`, so it is worth looking
+    /// into.
+    pub fn may_match_below(&self, dir: &[u8]) -> bool {
+        let dir = split(dir);
+        self.patterns.iter().any(|p| prefix_parts(p, &dir))
+    }
+}
+
+impl fmt::Display for PathFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let patterns: Vec<String> = self.patterns
+            .iter()
+            .map(|p| String::from_utf8_lossy(&p.join(&b'/')).into_owned())
+            .collect();
+        write!(f, "{}", patterns.join(" "))
+    }
+}
+
+/// The paths a commit leaves out, given as exclude and include patterns.
+///
+/// A pattern without a `/` matches the last name of a path, at any depth; one with a `/` matches
+/// the whole absolute path, as in `PathFilter`. A pattern ending in `/` only matches
+/// directories. A path is left out if an exclude pattern matches it and no include pattern
+/// does. Nothing below a directory that is left out is visited.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExcludeFilter {
+    exclude: Vec<(PathFilter, bool)>,
+    include: Vec<(PathFilter, bool)>,
+    patterns: Vec<String>,
+}
+
+impl ExcludeFilter {
+    pub fn new<S: AsRef<str>>(exclude: &[S], include: &[S]) -> ExcludeFilter {
+        let patterns = exclude
+            .iter()
+            .map(|p| format!("-{}", p.as_ref()))
+            .chain(include.iter().map(|p| format!("+{}", p.as_ref())))
+            .collect();
+        ExcludeFilter {
+            exclude: exclude.iter().map(|p| exclude_pattern(p.as_ref())).collect(),
+            include: include.iter().map(|p| exclude_pattern(p.as_ref())).collect(),
+            patterns: patterns,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exclude.is_empty()
+    }
+
+    /// Whether to leave out the path `path`, which is a directory if `is_dir`.
+    pub fn excludes(&self, path: &[u8], is_dir: bool) -> bool {
+        let matches = |patterns: &[(PathFilter, bool)]| {
+            patterns
+                .iter()
+                .any(|(filter, dir_only)| (is_dir || !dir_only) && filter.matches(path))
+        };
+        matches(&self.exclude) && !matches(&self.include)
+    }
+}
+
+/// Lists the exclude patterns prefixed with `-` and the include patterns with `+`.
+impl fmt::Display for ExcludeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.patterns.join(" "))
+    }
+}
+
+fn exclude_pattern(pattern: &str) -> (PathFilter, bool) {
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.contains('/') {
+        (PathFilter::new(&[pattern]), dir_only)
+    } else {
+        (PathFilter::new(&[format!("**/{}", pattern)]), dir_only)
+    }
+}
+
+fn split(path: &[u8]) -> Vec<Vec<u8>> {
+    path.split(|&c| c == b'/')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_vec())
+        .collect()
+}
+
+fn match_parts(pattern: &[Vec<u8>], path: &[Vec<u8>]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if &first[..] == b"**" => {
+            (0..path.len() + 1).any(|skip| match_parts(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => glob_match(first, name) && match_parts(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Whether `pattern` may match some path that starts with `dir` and is longer.
+fn prefix_parts(pattern: &[Vec<u8>], dir: &[Vec<u8>]) -> bool {
+    match (pattern.split_first(), dir.split_first()) {
+        (Some((first, _)), _) if &first[..] == b"**" => true,
+        (Some(_), None) => true,
+        (Some((first, rest)), Some((name, dir))) => {
+            glob_match(first, name) && prefix_parts(rest, dir)
+        }
+        (None, _) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(glob_match(b"*.vmdk", b"disk.vmdk"));
+        assert!(glob_match(b"a?c*", b"abcdef"));
+        assert!(!glob_match(b"*.vmdk", b"disk.vmdk.bak"));
+    }
+
+    #[test]
+    fn paths() {
+        let filter = PathFilter::new(&["home/alice/**", "etc/*.conf"]);
+        assert!(filter.matches(b"home/alice"));
+        assert!(filter.matches(b"home/alice/docs/a.txt"));
+        assert!(filter.matches(b"etc/hat.conf"));
+        assert!(!filter.matches(b"etc/ssh/sshd.conf"));
+        assert!(!filter.matches(b"home/bob/a.txt"));
+
+        assert!(filter.may_match_below(b""));
+        assert!(filter.may_match_below(b"home"));
+        assert!(filter.may_match_below(b"home/alice/docs"));
+        assert!(filter.may_match_below(b"etc"));
+        assert!(!filter.may_match_below(b"etc/ssh"));
+        assert!(!filter.may_match_below(b"home/bob"));
+
+        assert_eq!(filter.to_string(), "home/alice/** etc/*.conf");
+    }
+
+    #[test]
+    fn excludes() {
+        let filter = ExcludeFilter::new(&["*.o", "target/", "/home/*/.cache"], &["keep.o"]);
+        assert!(filter.excludes(b"/src/main.o", false));
+        assert!(filter.excludes(b"/src/a/b/lib.o", false));
+        assert!(!filter.excludes(b"/src/keep.o", false));
+        assert!(!filter.excludes(b"/src/main.c", false));
+
+        assert!(filter.excludes(b"/src/target", true));
+        assert!(!filter.excludes(b"/src/target", false));
+        assert!(filter.excludes(b"/home/alice/.cache", true));
+        assert!(!filter.excludes(b"/home/alice/src/.cache", true));
+
+        assert!(!ExcludeFilter::default().excludes(b"/src/main.o", false));
+        assert_eq!(filter.to_string(), "-*.o -target/ -/home/*/.cache +keep.o");
+    }
+}
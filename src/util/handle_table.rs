@@ -0,0 +1,121 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Numbered handles to open resources.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Hands out ids for open resources, and looks them up again.
+///
+/// Ids start at 1 and are never reused, so a stale id can not reach a resource opened later.
+/// Each entry has its own lock: using one handle does not wait on another.
+pub struct HandleTable<T> {
+    next_id: AtomicUsize,
+    entries: RwLock<HashMap<u64, Arc<Mutex<T>>>>,
+}
+
+impl<T> HandleTable<T> {
+    pub fn new() -> HandleTable<T> {
+        HandleTable {
+            next_id: AtomicUsize::new(1),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add `value` and return its id.
+    pub fn insert(&self, value: T) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+        self.entries
+            .write()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(value)));
+        id
+    }
+
+    /// The entry with this id, if it is still open.
+    pub fn get(&self, id: u64) -> Option<Arc<Mutex<T>>> {
+        self.entries.read().unwrap().get(&id).cloned()
+    }
+
+    /// Close the entry with this id. Users holding on to it may finish with it.
+    pub fn remove(&self, id: u64) -> Option<Arc<Mutex<T>>> {
+        self.entries.write().unwrap().remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> HandleTable<T> {
+        HandleTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn ids_are_not_reused() {
+        let table = HandleTable::new();
+        let a = table.insert("a");
+        let b = table.insert("b");
+        assert!(table.remove(a).is_some());
+
+        let c = table.insert("c");
+        assert!(c != a && c != b);
+        assert!(table.get(a).is_none());
+        assert_eq!(*table.get(b).unwrap().lock().unwrap(), "b");
+        assert_eq!(*table.get(c).unwrap().lock().unwrap(), "c");
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn handles_are_used_concurrently() {
+        let table = Arc::new(HandleTable::new());
+        let ids: Vec<u64> = (0..4).map(|_| table.insert(0u64)).collect();
+
+        // Holding one handle does not block the others.
+        let first = table.get(ids[0]).unwrap();
+        let _held = first.lock().unwrap();
+
+        let threads: Vec<_> = ids[1..]
+            .iter()
+            .map(|&id| {
+                let table = table.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        *table.get(id).unwrap().lock().unwrap() += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        for &id in &ids[1..] {
+            assert_eq!(*table.get(id).unwrap().lock().unwrap(), 1000);
+        }
+    }
+}
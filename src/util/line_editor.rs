@@ -0,0 +1,189 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal line editor for interactive prompts on a terminal, with tab completion.
+//!
+//! Only what a prompt needs is supported: typing, backspace, Ctrl-C to drop the line, Ctrl-D
+//! to end input and Tab to complete the last word. Other escape sequences are ignored.
+
+use libc;
+use std::io::{self, Read, Write};
+use std::mem;
+
+const CTRL_C: u8 = 3;
+const CTRL_D: u8 = 4;
+const BACKSPACE: u8 = 8;
+const TAB: u8 = 9;
+const ESCAPE: u8 = 27;
+const DELETE: u8 = 127;
+
+/// Reads lines from the terminal on stdin, which stays in non-canonical mode until dropped.
+pub struct LineEditor {
+    original: libc::termios,
+}
+
+impl LineEditor {
+    /// Take over the terminal on stdin. Fails if stdin is not a terminal.
+    pub fn new() -> io::Result<LineEditor> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(LineEditor { original: original })
+    }
+
+    /// Show `prompt` and read a line, without its line ending. `complete` is called with the
+    /// line so far when Tab is pressed, and returns the candidates for its last word.
+    /// Returns `None` when input ends.
+    pub fn read_line<F>(&mut self, prompt: &str, mut complete: F) -> io::Result<Option<String>>
+    where
+        F: FnMut(&str) -> Vec<String>,
+    {
+        let stdin = io::stdin();
+        let mut input = stdin.lock().bytes();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let mut line: Vec<u8> = vec![];
+
+        write!(out, "{}", prompt)?;
+        out.flush()?;
+        loop {
+            let byte = match input.next() {
+                Some(byte) => byte?,
+                None => return Ok(None),
+            };
+            match byte {
+                b'\r' | b'\n' => {
+                    writeln!(out)?;
+                    return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+                }
+                CTRL_D if line.is_empty() => {
+                    writeln!(out)?;
+                    return Ok(None);
+                }
+                CTRL_C => {
+                    line.clear();
+                    write!(out, "^C\n{}", prompt)?;
+                }
+                BACKSPACE | DELETE => {
+                    // Remove the whole last character, including its UTF-8 continuation bytes.
+                    while let Some(b) = line.pop() {
+                        if b & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                    redraw(&mut out, prompt, &line)?;
+                }
+                TAB => {
+                    let current = String::from_utf8_lossy(&line).into_owned();
+                    let candidates = complete(&current);
+                    let (completed, ambiguous) = apply_completion(&current, &candidates);
+                    if ambiguous && completed == current {
+                        writeln!(out)?;
+                        for candidate in &candidates {
+                            writeln!(out, "{}", candidate)?;
+                        }
+                    }
+                    line = completed.into_bytes();
+                    redraw(&mut out, prompt, &line)?;
+                }
+                ESCAPE => {
+                    // Skip arrow keys and the like: ESC [ and a final letter.
+                    let _ = input.next();
+                    let _ = input.next();
+                }
+                b if b >= b' ' => {
+                    line.push(b);
+                    out.write_all(&[b])?;
+                }
+                _ => (),
+            }
+            out.flush()?;
+        }
+    }
+}
+
+impl Drop for LineEditor {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+fn redraw<W: Write>(out: &mut W, prompt: &str, line: &[u8]) -> io::Result<()> {
+    write!(out, "\r\x1b[K{}", prompt)?;
+    out.write_all(line)
+}
+
+/// `line` with its last word replaced by the longest prefix shared by all `candidates`, and
+/// whether more than one candidate remains. A single candidate is completed in full, followed
+/// by a space unless it is a directory.
+pub fn apply_completion(line: &str, candidates: &[String]) -> (String, bool) {
+    let start = line.rfind(' ').map_or(0, |i| i + 1);
+    let mut prefix = match candidates.first() {
+        Some(first) => first.clone(),
+        None => return (line.to_owned(), false),
+    };
+    for candidate in &candidates[1..] {
+        let shared = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|&((_, a), b)| a != b)
+            .map_or(prefix.len().min(candidate.len()), |((i, _), _)| i);
+        prefix.truncate(shared);
+    }
+    if prefix.len() < line.len() - start {
+        return (line.to_owned(), candidates.len() > 1);
+    }
+    let mut completed = format!("{}{}", &line[..start], prefix);
+    if candidates.len() == 1 && !prefix.ends_with('/') {
+        completed.push(' ');
+    }
+    (completed, candidates.len() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_the_last_word() {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            apply_completion("c", &strings(&["cat", "cd"])),
+            ("c".to_owned(), true)
+        );
+        assert_eq!(
+            apply_completion("ca", &strings(&["cat"])),
+            ("cat ".to_owned(), false)
+        );
+        assert_eq!(
+            apply_completion("cd home/l", &strings(&["home/latest/"])),
+            ("cd home/latest/".to_owned(), false)
+        );
+        assert_eq!(
+            apply_completion("ls d", &strings(&["docs/", "downloads/"])),
+            ("ls do".to_owned(), true)
+        );
+        assert_eq!(apply_completion("ls x", &[]), ("ls x".to_owned(), false));
+    }
+}
@@ -30,6 +30,55 @@ impl HasPath for fs::DirEntry {
     }
 }
 
+/// The order in which the entries of a directory are snapshotted, and so packed into blobs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileOrder {
+    /// As the filesystem lists them.
+    #[default]
+    Directory,
+    Name,
+    /// Smallest first, so files of similar size share blobs.
+    Size,
+    /// By extension, so files of the same type share blobs.
+    Extension,
+}
+
+impl FileOrder {
+    pub fn parse(s: &str) -> Result<FileOrder, String> {
+        match s {
+            "directory" => Ok(FileOrder::Directory),
+            "name" => Ok(FileOrder::Name),
+            "size" => Ok(FileOrder::Size),
+            "extension" => Ok(FileOrder::Extension),
+            _ => Err(format!(
+                "Invalid file order '{}'; use directory, name, size or extension",
+                s
+            )),
+        }
+    }
+
+    /// Sort the entries of one directory, given the size of each. Ties are broken by name.
+    /// Keys are computed once per entry, as sizes may take a system call.
+    pub fn sort<T, F>(self, entries: &mut [T], size: F)
+    where
+        T: HasPath,
+        F: Fn(&T) -> u64,
+    {
+        let name = |e: &T| e.path().file_name().map(|n| n.to_owned());
+        match self {
+            FileOrder::Directory => (),
+            FileOrder::Name => entries.sort_by_cached_key(|e| name(e)),
+            FileOrder::Size => entries.sort_by_cached_key(|e| (size(e), name(e))),
+            FileOrder::Extension => {
+                entries.sort_by_cached_key(|e| (e.path().extension().map(|x| x.to_owned()), name(e)))
+            }
+        }
+    }
+}
+
+/// Number of threads that walk the directories of a snapshot by default.
+pub const DEFAULT_WALK_THREADS: usize = 10;
+
 pub trait PathHandler<P: Send + 'static>: Sync {
     type DirItem: HasPath;
     type DirIter: iter::Iterator<Item = io::Result<Self::DirItem>>;
@@ -66,9 +115,17 @@ pub trait PathHandler<P: Send + 'static>: Sync {
     }
 
     fn recurse(&self, root: PathBuf, payload: P) {
-        let pool = scoped_pool::Pool::new(10);
+        self.recurse_all(vec![(root, payload)], DEFAULT_WALK_THREADS);
+    }
+
+    /// Walk each of `roots` with a shared pool of `threads` threads, so a wide root does not
+    /// wait for a deep one. The roots must not contain each other.
+    fn recurse_all(&self, roots: Vec<(PathBuf, P)>, threads: usize) {
+        let pool = scoped_pool::Pool::new(threads.max(1));
         pool.scoped(move |scope| {
-            self.recurse_worker(scope, root, payload);
+            for (root, payload) in roots {
+                self.recurse_worker(scope, root, payload);
+            }
         });
         pool.shutdown();
     }
@@ -187,4 +244,48 @@ mod tests {
         assert_eq!(handler.not_visited(), vec![PathBuf::from("/")]);
     }
 
+    #[test]
+    fn can_visit_all_roots() {
+        let paths = ["/", "/a/", "/a/1", "/a/2/", "/a/2/x", "/b/", "/b/1", "/c/", "/c/1"];
+
+        let handler = StubPathHandler::new(paths.iter().map(PathBuf::from).collect());
+        let roots = vec![
+            (PathBuf::from("/a"), Some(PathBuf::from("/a"))),
+            (PathBuf::from("/b"), Some(PathBuf::from("/b"))),
+        ];
+        handler.recurse_all(roots, 3);
+
+        let not_visited = ["/", "/a/", "/b/", "/c/", "/c/1"];
+        assert_eq!(
+            handler.not_visited(),
+            not_visited.iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sort_entries() {
+        let sorted = |order: FileOrder| {
+            let mut entries: Vec<PathBuf> = ["/d/b.txt", "/d/c", "/d/a.jpg", "/d/d.txt"]
+                .iter()
+                .map(PathBuf::from)
+                .collect();
+            order.sort(&mut entries, |p| match p.to_str().unwrap() {
+                "/d/b.txt" => 30,
+                "/d/c" => 10,
+                _ => 20,
+            });
+            entries
+                .iter()
+                .map(|p| p.file_name().unwrap().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(sorted(FileOrder::Directory), ["b.txt", "c", "a.jpg", "d.txt"]);
+        assert_eq!(sorted(FileOrder::Name), ["a.jpg", "b.txt", "c", "d.txt"]);
+        assert_eq!(sorted(FileOrder::Size), ["c", "a.jpg", "d.txt", "b.txt"]);
+        assert_eq!(sorted(FileOrder::Extension), ["c", "a.jpg", "b.txt", "d.txt"]);
+        assert_eq!(FileOrder::parse("size"), Ok(FileOrder::Size));
+        assert!(FileOrder::parse("random").is_err());
+    }
+
 }
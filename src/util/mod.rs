@@ -13,20 +13,49 @@
 // limitations under the License.
 
 mod counter;
+mod deadline;
+pub mod failpoint;
 mod file_iterator;
 mod fnbox;
+mod free_space;
+mod glob;
+mod handle_table;
+mod hostname;
+mod line_editor;
 mod listdir;
 mod ordered_collection;
 mod periodic_timer;
+mod preemption;
 mod process;
+mod progress;
+mod quiesce;
+mod size;
+mod special_file;
 mod sync_pool;
+mod tar;
 mod unique_priority_queue;
+mod xattr;
 
 pub use self::counter::Counter;
-pub use self::file_iterator::FileIterator;
+pub use self::deadline::{parse_duration, Deadline};
+pub use self::failpoint::fail_point;
+pub use self::file_iterator::{ChangeWatch, ChangedFilePolicy, FileIterator, FileStamp,
+                              CHANGED_FILE_RETRIES};
 pub use self::fnbox::FnBox;
-pub use self::listdir::{HasPath, PathHandler};
+pub use self::free_space::{free_space, FreeSpace};
+pub use self::glob::{glob_match, ExcludeFilter, PathFilter};
+pub use self::handle_table::HandleTable;
+pub use self::hostname::hostname;
+pub use self::line_editor::{apply_completion, LineEditor};
+pub use self::listdir::{FileOrder, HasPath, PathHandler, DEFAULT_WALK_THREADS};
 pub use self::periodic_timer::PeriodicTimer;
-pub use self::process::{MsgHandler, Process};
+pub use self::preemption::Preemption;
+pub use self::process::{MsgHandler, PendingReply, Process};
+pub use self::progress::{ProgressEvent, ProgressFn, ProgressMeter};
+pub use self::quiesce::{Quiesce, QuiesceMode, Quiesced, DEFAULT_QUIESCE_TIMEOUT};
+pub use self::size::parse_size;
+pub use self::special_file::make_special_file;
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
+pub use self::tar::{EntryHeader, EntryType, TarEntry, TarEntryKind, TarReader, TarWriter};
 pub use self::unique_priority_queue::UniquePriorityQueue;
+pub use self::xattr::{read_xattrs, set_xattr};
@@ -113,11 +113,29 @@ where
     ///
     /// Will always wait for a reply from the receiving `process`.
     pub fn send_reply(&self, msg: Msg) -> Result<Reply, E> {
+        self.send(msg).wait()
+    }
+
+    /// Asynchronous send.
+    ///
+    /// Returns once the receiving `process` has queued `msg`, so the caller can do other work
+    /// before waiting for the reply.
+    pub fn send(&self, msg: Msg) -> PendingReply<Reply, E> {
         let (sender, receiver) = mpsc::channel();
 
         self.sender
             .send((msg, sender))
             .expect("Could not send message; process looks dead");
-        receiver.recv().expect("Could not read reply")
+        PendingReply(receiver)
+    }
+}
+
+/// A reply from a `process` that may not have arrived yet.
+pub struct PendingReply<Reply, E>(mpsc::Receiver<Result<Reply, E>>);
+
+impl<Reply, E> PendingReply<Reply, E> {
+    /// Wait for the reply.
+    pub fn wait(self) -> Result<Reply, E> {
+        self.0.recv().expect("Could not read reply")
     }
 }
@@ -0,0 +1,167 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Something a long operation has done, reported as it happens so it can be shown to the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A regular file of this many bytes was handled by a snapshot, read or not.
+    FileScanned(u64),
+    /// This many bytes were read from new or changed files and hashed.
+    BytesHashed(u64),
+    /// A blob of this many bytes was closed and handed to the backend.
+    BlobFlushed(u64),
+    /// The backend finished storing this many bytes.
+    BytesUploaded(u64),
+    /// A file of this many bytes was restored by a checkout.
+    FileRestored(u64),
+}
+
+/// Receives the progress events of an operation, possibly from several threads.
+pub type ProgressFn = Arc<Fn(ProgressEvent) + Send + Sync>;
+
+/// Adds up the progress events of an operation, and describes how far it has come.
+#[derive(Debug, Default)]
+pub struct ProgressMeter {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    bytes_hashed: AtomicU64,
+    blobs: AtomicU64,
+    bytes_flushed: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    /// Bytes the operation handles in all, once known.
+    total: Mutex<Option<u64>>,
+}
+
+/// Width of the bar drawn by `ProgressMeter::line`, in characters.
+const BAR_WIDTH: u64 = 30;
+
+fn hms(secs: u64) -> String {
+    format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl ProgressMeter {
+    pub fn new() -> ProgressMeter {
+        ProgressMeter::default()
+    }
+
+    pub fn record(&self, event: ProgressEvent) {
+        let add = |counter: &AtomicU64, n: u64| counter.fetch_add(n, Ordering::SeqCst);
+        match event {
+            ProgressEvent::FileScanned(len) | ProgressEvent::FileRestored(len) => {
+                add(&self.files, 1);
+                add(&self.bytes, len);
+            }
+            ProgressEvent::BytesHashed(len) => {
+                add(&self.bytes_hashed, len);
+            }
+            ProgressEvent::BlobFlushed(len) => {
+                add(&self.blobs, 1);
+                add(&self.bytes_flushed, len);
+            }
+            ProgressEvent::BytesUploaded(len) => {
+                add(&self.bytes_uploaded, len);
+            }
+        }
+    }
+
+    /// Expect `total` bytes of files in all, so `line` can show a bar and the time left.
+    pub fn set_total(&self, total: u64) {
+        *self.total.lock().unwrap() = Some(total);
+    }
+
+    /// One line saying how far the operation has come after running for `elapsed`.
+    pub fn line(&self, elapsed: Duration) -> String {
+        let files = self.files.load(Ordering::SeqCst);
+        let bytes = self.bytes.load(Ordering::SeqCst);
+        let hashed = self.bytes_hashed.load(Ordering::SeqCst);
+        let blobs = self.blobs.load(Ordering::SeqCst);
+        let flushed = self.bytes_flushed.load(Ordering::SeqCst);
+        let uploaded = self.bytes_uploaded.load(Ordering::SeqCst);
+
+        let mut line = match *self.total.lock().unwrap() {
+            Some(total) => {
+                let done = cmp::min(bytes, total);
+                let (filled, percent) = match total {
+                    0 => (BAR_WIDTH, 100.0),
+                    _ => (
+                        done * BAR_WIDTH / total,
+                        done as f64 * 100.0 / total as f64,
+                    ),
+                };
+                let eta = match done {
+                    0 => "?".to_owned(),
+                    _ => {
+                        let secs = elapsed.as_secs_f64() * (total - done) as f64 / done as f64;
+                        hms(secs.round() as u64)
+                    }
+                };
+                format!(
+                    "[{}{}] {:5.1}% {} of {} bytes, {} files, ETA {}",
+                    "#".repeat(filled as usize),
+                    ".".repeat((BAR_WIDTH - filled) as usize),
+                    percent,
+                    done,
+                    total,
+                    files,
+                    eta
+                )
+            }
+            None => format!("{} files, {} bytes", files, bytes),
+        };
+        if hashed > 0 {
+            line += &format!("; read {} bytes", hashed);
+        }
+        if blobs > 0 {
+            line += &format!(
+                "; {} blobs flushed, {} of {} bytes uploaded",
+                blobs, uploaded, flushed
+            );
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_estimates_time_left() {
+        let meter = ProgressMeter::new();
+        meter.record(ProgressEvent::FileScanned(100));
+        meter.record(ProgressEvent::BytesHashed(100));
+        assert_eq!(
+            meter.line(Duration::from_secs(10)),
+            "1 files, 100 bytes; read 100 bytes"
+        );
+
+        meter.set_total(200);
+        meter.record(ProgressEvent::BlobFlushed(64));
+        meter.record(ProgressEvent::BytesUploaded(64));
+        assert_eq!(
+            meter.line(Duration::from_secs(10)),
+            format!(
+                "[{}{}]  50.0% 100 of 200 bytes, 1 files, ETA 0h00m10s; read 100 bytes; \
+                 1 blobs flushed, 64 of 64 bytes uploaded",
+                "#".repeat(15),
+                ".".repeat(15)
+            )
+        );
+    }
+}
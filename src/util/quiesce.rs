@@ -0,0 +1,299 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Quiescing a filesystem while a snapshot reads it.
+//!
+//! Filesystems that cannot be snapshotted (by LVM or the filesystem itself) still change while
+//! they are read. Freezing them with `fsfreeze` blocks writers until the snapshot is read; where
+//! that is too disruptive, `sync` and a grace period at least flush what was written before.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A frozen filesystem is thawed after this long, even if the snapshot is not done.
+pub const DEFAULT_QUIESCE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Time given to writers to settle after `sync`.
+pub const DEFAULT_SYNC_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuiesceMode {
+    /// Block writes with `fsfreeze` until the snapshot is read.
+    Freeze,
+    /// Flush written data with `sync`, then wait a grace period.
+    Sync,
+}
+
+impl QuiesceMode {
+    pub fn parse(s: &str) -> Result<QuiesceMode, String> {
+        match s {
+            "freeze" => Ok(QuiesceMode::Freeze),
+            "sync" => Ok(QuiesceMode::Sync),
+            _ => Err(format!("Invalid quiesce mode '{}'; use freeze or sync", s)),
+        }
+    }
+}
+
+/// How to quiesce the filesystem mounted at a mountpoint.
+pub struct Quiesce {
+    mode: QuiesceMode,
+    mountpoint: PathBuf,
+    timeout: Duration,
+    grace: Duration,
+    fsfreeze_cmd: String,
+    sync_cmd: String,
+}
+
+impl Quiesce {
+    pub fn new(mode: QuiesceMode, mountpoint: PathBuf) -> Quiesce {
+        Quiesce {
+            mode: mode,
+            mountpoint: mountpoint,
+            timeout: DEFAULT_QUIESCE_TIMEOUT,
+            grace: DEFAULT_SYNC_GRACE,
+            fsfreeze_cmd: "fsfreeze".to_owned(),
+            sync_cmd: "sync".to_owned(),
+        }
+    }
+
+    /// Thaw a frozen filesystem after `timeout`, and give up on commands that take longer.
+    pub fn with_timeout(mut self, timeout: Duration) -> Quiesce {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_grace(mut self, grace: Duration) -> Quiesce {
+        self.grace = grace;
+        self
+    }
+
+    /// Use other commands than `fsfreeze` and `sync`.
+    pub fn with_commands(mut self, fsfreeze_cmd: &str, sync_cmd: &str) -> Quiesce {
+        self.fsfreeze_cmd = fsfreeze_cmd.to_owned();
+        self.sync_cmd = sync_cmd.to_owned();
+        self
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Fail if freezing would block writes to `path`, which must stay writable meanwhile. A
+    /// path that does not exist yet is judged by its nearest existing parent.
+    pub fn check_writable(&self, path: &Path) -> Result<(), String> {
+        if self.mode != QuiesceMode::Freeze {
+            return Ok(());
+        }
+        let device = |p: &Path| {
+            p.metadata()
+                .map(|m| m.dev())
+                .map_err(|e| format!("{}: {}", p.display(), e))
+        };
+        let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+        if device(existing)? == device(&self.mountpoint)? {
+            return Err(format!(
+                "{} is on the filesystem to freeze at {}",
+                path.display(),
+                self.mountpoint.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Quiesce the filesystem until the returned guard is released or dropped. A frozen
+    /// filesystem is also thawed once the timeout passes.
+    pub fn begin(&self) -> Result<Quiesced, String> {
+        match self.mode {
+            QuiesceMode::Sync => {
+                let mut cmd = Command::new(&self.sync_cmd);
+                cmd.arg("-f").arg(&self.mountpoint);
+                run_with_timeout(cmd, self.timeout)?;
+                thread::sleep(self.grace);
+                Ok(Quiesced {
+                    thaw: None,
+                    expired: Arc::new(AtomicBool::new(false)),
+                })
+            }
+            QuiesceMode::Freeze => {
+                let thaw = {
+                    let cmd = self.fsfreeze_cmd.clone();
+                    let mountpoint = self.mountpoint.clone();
+                    let timeout = self.timeout;
+                    move || {
+                        let mut thaw = Command::new(&cmd);
+                        thaw.arg("--unfreeze").arg(&mountpoint);
+                        run_with_timeout(thaw, timeout)
+                    }
+                };
+
+                let mut freeze = Command::new(&self.fsfreeze_cmd);
+                freeze.arg("--freeze").arg(&self.mountpoint);
+                if let Err(e) = run_with_timeout(freeze, self.timeout) {
+                    // A freeze that timed out may still have taken effect.
+                    let _ = thaw();
+                    return Err(format!("Could not freeze {}: {}", self.mountpoint.display(), e));
+                }
+
+                let expired = Arc::new(AtomicBool::new(false));
+                let (release, released) = mpsc::channel::<()>();
+                let timeout = self.timeout;
+                let expired_flag = expired.clone();
+                let watchdog = thread::spawn(move || {
+                    // Released, or the guard is gone: the sender hangs up either way.
+                    if let Err(mpsc::RecvTimeoutError::Timeout) = released.recv_timeout(timeout) {
+                        expired_flag.store(true, Ordering::SeqCst);
+                    }
+                    thaw()
+                });
+                Ok(Quiesced {
+                    thaw: Some((release, watchdog)),
+                    expired: expired,
+                })
+            }
+        }
+    }
+}
+
+/// Thaws the filesystem when released, or once the timeout passes.
+type Watchdog = thread::JoinHandle<Result<(), String>>;
+
+/// A quiesced filesystem; frozen ones are thawed when this is released or dropped.
+pub struct Quiesced {
+    thaw: Option<(mpsc::Sender<()>, Watchdog)>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Quiesced {
+    /// Whether the filesystem was thawed early, as the timeout passed.
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    /// Thaw the filesystem. Returns false if it was thawed early, as the timeout passed.
+    pub fn release(mut self) -> Result<bool, String> {
+        self.thaw()?;
+        Ok(!self.expired())
+    }
+
+    fn thaw(&mut self) -> Result<(), String> {
+        match self.thaw.take() {
+            Some((release, watchdog)) => {
+                drop(release);
+                watchdog
+                    .join()
+                    .unwrap_or_else(|_| Err("thaw panicked".to_owned()))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Quiesced {
+    fn drop(&mut self) {
+        if let Err(e) = self.thaw() {
+            error!("Could not thaw filesystem: {}", e);
+        }
+    }
+}
+
+/// Run `cmd`, and kill it if it takes longer than `timeout`.
+fn run_with_timeout(mut cmd: Command, timeout: Duration) -> Result<(), String> {
+    let started = Instant::now();
+    let mut child = cmd.spawn().map_err(|e| e.to_string())?;
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(format!("{:?} failed: {}", cmd, status));
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{:?} timed out", cmd));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    /// A stand-in for fsfreeze that records its calls, and fails to freeze if asked to.
+    fn fake_fsfreeze(dir: &Path) -> String {
+        let script = dir.join("fsfreeze");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$1\" >> {0}.calls\n[ \"$1\" != --freeze ] || [ ! -e {0}.fail ]\n",
+                script.display()
+            ),
+        ).unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn freeze_is_thawed() {
+        let dir = env::temp_dir().join(format!("hat-quiesce-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let calls = || fs::read_to_string(dir.join("fsfreeze.calls")).unwrap();
+        let quiesce = Quiesce::new(QuiesceMode::Freeze, dir.clone())
+            .with_commands(&fake_fsfreeze(&dir), "true");
+
+        assert!(quiesce.check_writable(&dir).is_err());
+        assert!(quiesce.check_writable(&dir.join("not/yet")).is_err());
+        assert_eq!(quiesce.begin().unwrap().release(), Ok(true));
+        assert_eq!(calls(), "--freeze\n--unfreeze\n");
+
+        // Dropping the guard, as on errors, thaws too.
+        drop(quiesce.begin().unwrap());
+        assert_eq!(calls().lines().count(), 4);
+
+        // So does the timeout, if the snapshot takes too long.
+        let quiesce = quiesce.with_timeout(Duration::from_millis(300));
+        let frozen = quiesce.begin().unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        assert!(frozen.expired());
+        assert_eq!(calls().lines().last(), Some("--unfreeze"));
+        assert_eq!(frozen.release(), Ok(false));
+        assert_eq!(calls().lines().count(), 6);
+
+        // A failed freeze is undone, in case it took effect anyway.
+        fs::write(dir.join("fsfreeze.fail"), "").unwrap();
+        assert!(quiesce.begin().is_err());
+        assert_eq!(calls().lines().last(), Some("--unfreeze"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(QuiesceMode::parse("freeze"), Ok(QuiesceMode::Freeze));
+        assert_eq!(QuiesceMode::parse("sync"), Ok(QuiesceMode::Sync));
+        assert!(QuiesceMode::parse("lvm").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creating device nodes, FIFOs and sockets.
+
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Create the special file `path` with `mode`, which includes its file type, and device number
+/// `rdev`, which only devices use.
+pub fn make_special_file(path: &Path, mode: u32, rdev: u64) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))?;
+    if unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::FileTypeExt;
+    use std::process;
+
+    #[test]
+    fn make_fifo_and_socket() {
+        let dir = env::temp_dir().join(format!("hat-special-file-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        make_special_file(&dir.join("fifo"), libc::S_IFIFO | 0o640, 0).unwrap();
+        make_special_file(&dir.join("socket"), libc::S_IFSOCK | 0o600, 0).unwrap();
+        let file_type = |name: &str| fs::symlink_metadata(dir.join(name)).unwrap().file_type();
+        assert!(file_type("fifo").is_fifo());
+        assert!(file_type("socket").is_socket());
+
+        // An existing file is not replaced.
+        let err = make_special_file(&dir.join("fifo"), libc::S_IFIFO | 0o640, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,598 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal writer and reader for ustar archives of regular files, directories, symbolic links,
+//! devices and FIFOs.
+//!
+//! Names, link targets and numbers that do not fit in a ustar header are written as pax
+//! extended headers, which GNU tar, bsdtar and busybox all understand. The reader also takes
+//! the long names of GNU tar and the base-256 numbers of large fields.
+
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use time;
+
+const BLOCK: usize = 512;
+
+/// Largest value of the 12 byte numeric fields (size and mtime).
+const MAX_LONG_FIELD: u64 = 0o77_777_777_777;
+/// Largest value of the 8 byte numeric fields (mode, uid and gid).
+const MAX_SHORT_FIELD: u64 = 0o7_777_777;
+
+pub enum EntryType<'a> {
+    File,
+    Directory,
+    Symlink(&'a [u8]),
+    /// A character device, with its major and minor device number.
+    CharDevice(u64, u64),
+    /// A block device, with its major and minor device number.
+    BlockDevice(u64, u64),
+    Fifo,
+}
+
+/// Metadata of one archive entry.
+pub struct EntryHeader<'a> {
+    /// Path of the entry within the archive, without a leading `/`.
+    pub name: &'a [u8],
+    pub entry_type: EntryType<'a>,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub mtime: u64,
+    /// Number of data bytes; only files have any.
+    pub size: u64,
+}
+
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+/// Write `value` as a zero-padded octal number followed by NUL into `field`.
+fn octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    if digits.len() >= field.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "value too large for tar header",
+        ));
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+/// Split `name` into a ustar prefix and name, if it is too long for the name field alone.
+fn split_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((&[], name));
+    }
+    let lowest = name.len().saturating_sub(101);
+    (lowest..name.len().min(156))
+        .filter(|&i| name[i] == b'/' && i > 0 && i + 1 < name.len() && name.len() - i - 1 <= 100)
+        .map(|i| (&name[..i], &name[i + 1..]))
+        .next()
+}
+
+/// Append the pax record `key=value` to `records`.
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The record starts with its own length in decimal, including the length itself.
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while format!("{}", len).len() + rest > len {
+        len += 1;
+    }
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn header_block(
+    prefix: &[u8],
+    name: &[u8],
+    type_flag: u8,
+    link: &[u8],
+    (mode, uid, gid): (u64, u64, u64),
+    size: u64,
+    mtime: u64,
+) -> io::Result<[u8; BLOCK]> {
+    let mut h = [0u8; BLOCK];
+    h[..name.len()].copy_from_slice(name);
+    octal(&mut h[100..108], mode)?;
+    octal(&mut h[108..116], uid)?;
+    octal(&mut h[116..124], gid)?;
+    octal(&mut h[124..136], size)?;
+    octal(&mut h[136..148], mtime)?;
+    h[156] = type_flag;
+    h[157..157 + link.len()].copy_from_slice(link);
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix);
+    set_checksum(&mut h)?;
+    Ok(h)
+}
+
+/// Fill in the checksum of header block `h`, which is computed with its own field set to spaces.
+fn set_checksum(h: &mut [u8; BLOCK]) -> io::Result<()> {
+    for b in &mut h[148..156] {
+        *b = b' ';
+    }
+    let sum: u64 = h.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut h[148..155], sum)
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let pad = (BLOCK - (size % BLOCK as u64) as usize) % BLOCK;
+        self.out.write_all(&[0u8; BLOCK][..pad])
+    }
+
+    /// Append an entry described by `header`, with `header.size` bytes read from `data`.
+    pub fn append_entry<R: Read>(&mut self, header: &EntryHeader, data: R) -> io::Result<()> {
+        let mut pax = vec![];
+        let (prefix, name) = match split_name(header.name) {
+            Some(split) => split,
+            None => {
+                pax_record(&mut pax, "path", header.name);
+                (&[][..], &header.name[..100])
+            }
+        };
+        let (type_flag, link) = match header.entry_type {
+            EntryType::File => (b'0', &[][..]),
+            EntryType::Directory => (b'5', &[][..]),
+            EntryType::Symlink(target) if target.len() <= 100 => (b'2', target),
+            EntryType::Symlink(target) => {
+                pax_record(&mut pax, "linkpath", target);
+                (b'2', &target[..100])
+            }
+            EntryType::CharDevice(..) => (b'3', &[][..]),
+            EntryType::BlockDevice(..) => (b'4', &[][..]),
+            EntryType::Fifo => (b'6', &[][..]),
+        };
+        let mut field = |key: &str, value: u64, max: u64| {
+            if value > max {
+                pax_record(&mut pax, key, format!("{}", value).as_bytes());
+                0
+            } else {
+                value
+            }
+        };
+        let size = field("size", header.size, MAX_LONG_FIELD);
+        let mtime = field("mtime", header.mtime, MAX_LONG_FIELD);
+        let uid = field("uid", header.uid, MAX_SHORT_FIELD);
+        let gid = field("gid", header.gid, MAX_SHORT_FIELD);
+
+        if !pax.is_empty() {
+            let pax_name = b"././@PaxHeader";
+            let size = pax.len() as u64;
+            let block = header_block(&[], pax_name, b'x', &[], (0o644, 0, 0), size, mtime)?;
+            self.out.write_all(&block)?;
+            self.out.write_all(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+        let mode = u64::from(header.mode & 0o7777);
+        let mut block =
+            header_block(prefix, name, type_flag, link, (mode, uid, gid), size, mtime)?;
+        match header.entry_type {
+            EntryType::CharDevice(major, minor) | EntryType::BlockDevice(major, minor) => {
+                octal(&mut block[329..337], major)?;
+                octal(&mut block[337..345], minor)?;
+                set_checksum(&mut block)?;
+            }
+            _ => (),
+        }
+        self.out.write_all(&block)?;
+
+        let copied = io::copy(&mut data.take(header.size), &mut self.out)?;
+        if copied != header.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} shrank while archiving it",
+                    String::from_utf8_lossy(header.name)
+                ),
+            ));
+        }
+        self.pad(header.size)
+    }
+
+    /// Append a file called `name` with `size` bytes read from `data`.
+    pub fn append<R: Read>(&mut self, name: &str, size: u64, data: R) -> io::Result<()> {
+        let header = EntryHeader {
+            name: name.as_bytes(),
+            entry_type: EntryType::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: time::now_utc().to_timespec().sec as u64,
+            size: size,
+        };
+        self.append_entry(&header, data)
+    }
+
+    pub fn append_bytes(&mut self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.append(name, bytes.len() as u64, bytes)
+    }
+
+    pub fn append_file(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        let file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        self.append(name, size, file)
+    }
+
+    /// Write the end-of-archive marker and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// The kind of an entry read from an archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TarEntryKind {
+    File,
+    Directory,
+    Symlink(Vec<u8>),
+    /// A hard link to an earlier entry, which has the data.
+    HardLink(Vec<u8>),
+    /// Devices, FIFOs and other entries without data, by their type flag.
+    Other(u8),
+}
+
+/// Metadata of one entry read from an archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TarEntry {
+    /// Path of the entry as stored, which may start with `/` or `./`.
+    pub name: Vec<u8>,
+    pub kind: TarEntryKind,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub user_name: Option<String>,
+    pub group_name: Option<String>,
+    pub mtime: i64,
+    /// Number of data bytes, read through the `TarReader` before the next entry.
+    pub size: u64,
+}
+
+/// Reads an archive from a stream, one entry at a time. The data of the current entry is read
+/// through `Read`; what is left of it is skipped when moving on to the next entry.
+pub struct TarReader<R: Read> {
+    input: R,
+    /// Data bytes of the current entry not read yet.
+    remaining: u64,
+    /// Padding after the data of the current entry.
+    padding: u64,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// The number in a numeric header field: octal digits, or base-256 if the high bit is set.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let mut value = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .ok_or_else(|| invalid("number too large in tar header"))?
+                + u64::from(b);
+        }
+        return Ok(value);
+    }
+    let digits: Vec<u8> = field
+        .iter()
+        .cloned()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    let digits = String::from_utf8(digits).map_err(|_| invalid("bad number in tar header"))?;
+    u64::from_str_radix(&digits, 8).map_err(|_| invalid("bad number in tar header"))
+}
+
+/// The contents of a string header field, up to the first NUL.
+fn parse_string(field: &[u8]) -> Vec<u8> {
+    field.iter().cloned().take_while(|&b| b != 0).collect()
+}
+
+/// The records of a pax extended header, as `(key, value)` pairs.
+fn parse_pax(mut records: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut parsed = vec![];
+    while !records.is_empty() && records[0] != 0 {
+        let space = records
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid("bad pax record"))?;
+        let len: usize = String::from_utf8_lossy(&records[..space])
+            .parse()
+            .map_err(|_| invalid("bad pax record length"))?;
+        if len <= space + 1 || len > records.len() || records[len - 1] != b'\n' {
+            return Err(invalid("bad pax record length"));
+        }
+        let record = &records[space + 1..len - 1];
+        let equals = record
+            .iter()
+            .position(|&b| b == b'=')
+            .ok_or_else(|| invalid("bad pax record"))?;
+        let key = String::from_utf8_lossy(&record[..equals]).into_owned();
+        parsed.push((key, record[equals + 1..].to_vec()));
+        records = &records[len..];
+    }
+    Ok(parsed)
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(input: R) -> TarReader<R> {
+        TarReader {
+            input: input,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    /// Skip what is left of the current entry.
+    fn skip_entry(&mut self) -> io::Result<()> {
+        let skip = self.remaining + self.padding;
+        let skipped = io::copy(&mut (&mut self.input).take(skip), &mut io::sink())?;
+        if skipped != skip {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar archive"));
+        }
+        self.remaining = 0;
+        self.padding = 0;
+        Ok(())
+    }
+
+    /// Read the data of an entry of `size` bytes, with its padding.
+    fn read_data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        (&mut self.input).take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar archive"));
+        }
+        self.remaining = 0;
+        self.padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+        self.skip_entry()?;
+        Ok(data)
+    }
+
+    /// The next entry, after skipping what is left of the current one. Returns `None` at the
+    /// end of the archive, having read the input to its end.
+    pub fn next_entry(&mut self) -> io::Result<Option<TarEntry>> {
+        self.skip_entry()?;
+        let mut pax = vec![];
+        let mut long_name = None;
+        let mut long_link = None;
+        loop {
+            let mut h = [0u8; BLOCK];
+            let mut read = 0;
+            while read < BLOCK {
+                match self.input.read(&mut h[read..])? {
+                    0 if read == 0 => return Ok(None),
+                    0 => return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated tar header",
+                    )),
+                    n => read += n,
+                }
+            }
+            if h.iter().all(|&b| b == 0) {
+                // The end-of-archive marker; the writer may still pad the stream after it.
+                io::copy(&mut self.input, &mut io::sink())?;
+                return Ok(None);
+            }
+
+            let stored_sum = parse_number(&h[148..156])?;
+            let sum: u64 = h[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&h[156..])
+                .map(|&b| u64::from(b))
+                .sum();
+            if sum != stored_sum {
+                return Err(invalid("bad tar header checksum"));
+            }
+
+            let size = parse_number(&h[124..136])?;
+            match h[156] {
+                b'x' => {
+                    pax.extend(parse_pax(&self.read_data(size)?)?);
+                    continue;
+                }
+                b'g' => {
+                    // Global pax headers hold nothing about the entries kept here.
+                    self.read_data(size)?;
+                    continue;
+                }
+                b'L' => {
+                    long_name = Some(parse_string(&self.read_data(size)?));
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(parse_string(&self.read_data(size)?));
+                    continue;
+                }
+                _ => (),
+            }
+
+            let mut name = parse_string(&h[..100]);
+            if &h[257..262] == b"ustar" {
+                let prefix = parse_string(&h[345..500]);
+                if !prefix.is_empty() {
+                    name = [&prefix[..], b"/", &name[..]].concat();
+                }
+            }
+            let text = |field: &[u8]| {
+                let s = parse_string(field);
+                if s.is_empty() {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(&s).into_owned())
+                }
+            };
+            let mut entry = TarEntry {
+                name: long_name.take().unwrap_or(name),
+                kind: TarEntryKind::Other(h[156]),
+                mode: parse_number(&h[100..108])? as u32 & 0o7777,
+                uid: parse_number(&h[108..116])?,
+                gid: parse_number(&h[116..124])?,
+                user_name: text(&h[265..297]),
+                group_name: text(&h[297..329]),
+                mtime: parse_number(&h[136..148])? as i64,
+                size: size,
+            };
+            let mut link = long_link.take().unwrap_or_else(|| parse_string(&h[157..257]));
+            for (key, value) in pax.drain(..) {
+                let number = || {
+                    // Times may have a fraction of a second, which is dropped.
+                    let s = String::from_utf8_lossy(&value).into_owned();
+                    let whole = s.split('.').next().unwrap_or("").to_owned();
+                    whole.parse::<i64>().map_err(|_| invalid("bad pax number"))
+                };
+                match &key[..] {
+                    "path" => entry.name = value.clone(),
+                    "linkpath" => link = value.clone(),
+                    "size" => entry.size = number()? as u64,
+                    "mtime" => entry.mtime = number()?,
+                    "uid" => entry.uid = number()? as u64,
+                    "gid" => entry.gid = number()? as u64,
+                    "uname" => entry.user_name = Some(String::from_utf8_lossy(&value).into_owned()),
+                    "gname" => {
+                        entry.group_name = Some(String::from_utf8_lossy(&value).into_owned())
+                    }
+                    _ => (),
+                }
+            }
+            entry.kind = match h[156] {
+                b'0' | 0 | b'7' => TarEntryKind::File,
+                b'5' => TarEntryKind::Directory,
+                b'2' => TarEntryKind::Symlink(link),
+                b'1' => TarEntryKind::HardLink(link),
+                flag => TarEntryKind::Other(flag),
+            };
+            // Only files have data; the size of a hard link is that of its target.
+            if entry.kind != TarEntryKind::File {
+                entry.size = if h[156] == b'1' { 0 } else { entry.size };
+            }
+            self.remaining = entry.size;
+            self.padding = (BLOCK as u64 - entry.size % BLOCK as u64) % BLOCK as u64;
+            return Ok(Some(entry));
+        }
+    }
+}
+
+impl<R: Read> Read for TarReader<R> {
+    /// Read the data of the current entry.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = cmp::min(buf.len() as u64, self.remaining) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.input.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar archive"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(name: &'a [u8], entry_type: EntryType<'a>, size: u64) -> EntryHeader<'a> {
+        EntryHeader {
+            name: name,
+            entry_type: entry_type,
+            mode: 0o640,
+            uid: 1000,
+            gid: 100,
+            mtime: 1_500_000_000,
+            size: size,
+        }
+    }
+
+    #[test]
+    fn read_what_was_written() {
+        let long = vec![b'x'; 300];
+        let mut w = TarWriter::new(vec![]);
+        w.append_entry(&header(b"dir", EntryType::Directory, 0), io::empty())
+            .unwrap();
+        w.append_entry(&header(&long, EntryType::File, 3), &b"abc"[..])
+            .unwrap();
+        let mut big = header(b"dir/big", EntryType::File, 1000);
+        big.uid = 1 << 40;
+        w.append_entry(&big, &[7u8; 1000][..]).unwrap();
+        w.append_entry(&header(b"link", EntryType::Symlink(b"dir/big"), 0), io::empty())
+            .unwrap();
+        w.append_entry(&header(b"null", EntryType::CharDevice(1, 3), 0), io::empty())
+            .unwrap();
+        w.append_entry(&header(b"fifo", EntryType::Fifo, 0), io::empty())
+            .unwrap();
+        let archive = w.finish().unwrap();
+
+        // The device numbers are in the ustar devmajor and devminor fields.
+        let null = archive.chunks(BLOCK).find(|b| b.starts_with(b"null\0")).unwrap();
+        assert_eq!(&null[329..345], b"0000001\00000003\0");
+
+        let mut r = TarReader::new(&archive[..]);
+        let dir = r.next_entry().unwrap().unwrap();
+        assert_eq!(&dir.name[..], b"dir");
+        assert_eq!((dir.kind, dir.mode), (TarEntryKind::Directory, 0o640));
+        assert_eq!((dir.uid, dir.gid, dir.mtime), (1000, 100, 1_500_000_000));
+
+        let file = r.next_entry().unwrap().unwrap();
+        assert_eq!((file.name, file.kind, file.size), (long, TarEntryKind::File, 3));
+        let mut data = vec![];
+        r.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abc");
+
+        // The data of an entry not read is skipped.
+        let big = r.next_entry().unwrap().unwrap();
+        assert_eq!((big.size, big.uid), (1000, 1 << 40));
+
+        let link = r.next_entry().unwrap().unwrap();
+        assert_eq!(link.kind, TarEntryKind::Symlink(b"dir/big".to_vec()));
+        let null = r.next_entry().unwrap().unwrap();
+        assert_eq!((null.name, null.kind), (b"null".to_vec(), TarEntryKind::Other(b'3')));
+        let fifo = r.next_entry().unwrap().unwrap();
+        assert_eq!((fifo.name, fifo.kind), (b"fifo".to_vec(), TarEntryKind::Other(b'6')));
+        assert!(r.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn reject_bad_archives() {
+        let mut w = TarWriter::new(vec![]);
+        w.append_bytes("file", &[1; 2000]).unwrap();
+        let archive = w.finish().unwrap();
+
+        let mut truncated = TarReader::new(&archive[..1024]);
+        truncated.next_entry().unwrap().unwrap();
+        assert!(truncated.next_entry().is_err());
+
+        let mut corrupt = archive.clone();
+        corrupt[0] = b'g';
+        assert!(TarReader::new(&corrupt[..]).next_entry().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extended attributes of files, such as SELinux labels, ACLs and macOS metadata.
+//!
+//! Symbolic links are not followed: the attributes of the link itself are read and set.
+
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))
+}
+
+/// Call `f` with a buffer large enough for its result, as it says when asked with an empty one.
+/// Retries if the result grows in between.
+fn read_sized<F>(f: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// Whether `err` says the file system or file has no extended attributes to offer.
+fn unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// The extended attributes of `path`, as name and value, ordered by name. A file system
+/// without extended attributes gives none.
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let path = c_path(path)?;
+    let names = match read_sized(|buf, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(names) => names,
+        Err(ref e) if unsupported(e) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = vec![];
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name).unwrap();
+        match read_sized(|buf, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), buf, size)
+        }) {
+            Ok(value) => xattrs.push((name.to_vec(), value)),
+            // Removed since it was listed.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    xattrs.sort();
+    Ok(xattrs)
+}
+
+/// Set the extended attribute `name` of `path` to `value`.
+pub fn set_xattr(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let path = c_path(path)?;
+    let name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains NUL"))?;
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn set_and_read() {
+        let path = env::temp_dir().join(format!("hat-xattr-{}", process::id()));
+        fs::write(&path, b"").unwrap();
+        match set_xattr(&path, b"user.hat.b", b"2") {
+            Ok(()) => (),
+            Err(ref e) if unsupported(e) || e.raw_os_error() == Some(libc::EPERM) => {
+                // The temporary directory has no user attributes to test with.
+                fs::remove_file(&path).unwrap();
+                return;
+            }
+            Err(e) => panic!("{}", e),
+        }
+        set_xattr(&path, b"user.hat.a", b"").unwrap();
+        let xattrs = read_xattrs(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let ours: Vec<_> = xattrs
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(b"user.hat."))
+            .collect();
+        assert_eq!(
+            ours,
+            vec![
+                (b"user.hat.a".to_vec(), vec![]),
+                (b"user.hat.b".to_vec(), b"2".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Addresses of families, snapshots and paths inside them, as given on the command line.
+//!
+//! An address is `family[/snapshot[/path...]]`. The snapshot is a snapshot id, `latest` for the
+//! newest complete snapshot of the family, or `^N` for the one N snapshots before it:
+//!
+//! ```text
+//! home              the family
+//! home/latest       its newest complete snapshot
+//! home/^1/etc/fstab a file in the snapshot before that
+//! home/3/etc        a directory in snapshot 3
+//! ```
+
+use db::SnapshotStatus;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Picks a snapshot of a family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selector {
+    /// The snapshot with this id.
+    Id(u64),
+    /// The newest complete snapshot.
+    Latest,
+    /// The complete snapshot this many before the newest; `Back(0)` is the newest.
+    Back(usize),
+}
+
+impl Selector {
+    pub fn parse(s: &str) -> Result<Selector, String> {
+        if s == "latest" {
+            return Ok(Selector::Latest);
+        }
+        let res = match s.strip_prefix('^') {
+            Some(n) => n.parse().map(Selector::Back),
+            None => s.parse().map(Selector::Id),
+        };
+        res.map_err(|_| format!("Invalid snapshot '{}'; use an id, latest or ^N", s))
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Selector::Id(id) => write!(f, "{}", id),
+            Selector::Latest => write!(f, "latest"),
+            Selector::Back(n) => write!(f, "^{}", n),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Address {
+    /// `None` addresses the list of families.
+    pub family: Option<String>,
+    pub snapshot: Option<Selector>,
+    /// Path inside the snapshot; empty for its root.
+    pub path: PathBuf,
+}
+
+impl Address {
+    pub fn parse(s: &str) -> Result<Address, String> {
+        let mut parts = s.split('/').filter(|p| !p.is_empty() && *p != ".");
+        let family = parts.next().map(|f| f.to_owned());
+        let snapshot = match parts.next() {
+            Some(snapshot) => Some(Selector::parse(snapshot)?),
+            None => None,
+        };
+        Ok(Address {
+            family: family,
+            snapshot: snapshot,
+            path: parts.collect(),
+        })
+    }
+
+    /// The address with its snapshot picked among `snapshots`, as an id. Only complete
+    /// snapshots count for `latest` and `^N`.
+    pub fn resolve(&self, snapshots: &[SnapshotStatus]) -> Result<Address, String> {
+        let (family, selector) = match (&self.family, self.snapshot) {
+            (Some(family), Some(selector)) => (family, selector),
+            _ => return Ok(self.clone()),
+        };
+        let mut ids: Vec<u64> = snapshots
+            .iter()
+            .filter(|s| &s.family_name == family && s.hash_ref.is_some())
+            .map(|s| s.info.snapshot_id)
+            .collect();
+        ids.sort();
+
+        let back = match selector {
+            Selector::Id(_) => return Ok(self.clone()),
+            Selector::Latest => 0,
+            Selector::Back(n) => n,
+        };
+        if ids.len() <= back {
+            return Err(format!(
+                "{}/{}: {} has {} complete snapshots",
+                family,
+                selector,
+                family,
+                ids.len()
+            ));
+        }
+        Ok(Address {
+            snapshot: Some(Selector::Id(ids[ids.len() - 1 - back])),
+            ..self.clone()
+        })
+    }
+
+    /// The snapshot id of a resolved address.
+    pub fn snapshot_id(&self) -> Option<u64> {
+        match self.snapshot {
+            Some(Selector::Id(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The address as a path: `family/snapshot/path`.
+    pub fn to_path(&self) -> PathBuf {
+        let mut path = PathBuf::new();
+        if let Some(ref family) = self.family {
+            path.push(family);
+        }
+        if let Some(selector) = self.snapshot {
+            path.push(selector.to_string());
+        }
+        path.join(&self.path)
+    }
+
+    /// Check the address names a snapshot, and nothing inside it unless `allow_path`.
+    /// `command` names the caller in errors.
+    pub fn expect_snapshot(&self, command: &str, allow_path: bool) -> Result<(), String> {
+        if self.snapshot.is_none() {
+            return Err(format!("{} needs a snapshot: <family>/<snapshot>", command));
+        }
+        if !allow_path && self.path != Path::new("") {
+            return Err(format!(
+                "{} takes a snapshot, not a path inside it: <family>/<snapshot>",
+                command
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_path().display())
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differences between a snapshot and a live directory tree.
+
+use hash::Hash;
+use key;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A file became a directory, a link, or similar.
+    Kind,
+    Content,
+    Size,
+    Modified,
+    Permissions,
+    Owner,
+    LinkTarget,
+    /// The file could not be read when the snapshot was taken.
+    Unreadable,
+}
+
+impl Change {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Change::Kind => "type",
+            Change::Content => "content",
+            Change::Size => "size",
+            Change::Modified => "mtime",
+            Change::Permissions => "mode",
+            Change::Owner => "owner",
+            Change::LinkTarget => "target",
+            Change::Unreadable => "unreadable",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// In the snapshot, but not in the live tree.
+    Missing,
+    /// In the live tree, but not in the snapshot.
+    Added,
+    Changed(Vec<Change>),
+    /// The live path could not be read.
+    Unreadable(String),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompareSummary {
+    /// Paths found in both the snapshot and the live tree.
+    pub compared: usize,
+    pub differences: usize,
+}
+
+/// Metadata of the live path that differs from the snapshot's. Only metadata recorded in
+/// the snapshot is compared.
+pub fn metadata_changes(stored: &key::Info, live: &fs::Metadata) -> Vec<Change> {
+    let mut changes = vec![];
+    if live.is_file() && stored.byte_length.is_some_and(|len| len != live.len()) {
+        changes.push(Change::Size);
+    }
+    if stored
+        .modified_ts_secs
+        .is_some_and(|mtime| mtime != live.mtime())
+    {
+        changes.push(Change::Modified);
+    }
+    let mode = |p: &fs::Permissions| p.mode() & 0o7777;
+    if stored
+        .permissions
+        .as_ref()
+        .is_some_and(|p| mode(p) != mode(&live.permissions()))
+    {
+        changes.push(Change::Permissions);
+    }
+    if stored.user_id.is_some_and(|uid| uid != u64::from(live.uid()))
+        || stored.group_id.is_some_and(|gid| gid != u64::from(live.gid()))
+    {
+        changes.push(Change::Owner);
+    }
+    changes
+}
+
+/// The chunk hashes of a live file, split by `chunker` the way commits split it.
+pub fn file_chunk_hashes<R, F>(mut chunker: key::Chunker<R>, hash: F) -> io::Result<Vec<Hash>>
+where
+    R: io::Read,
+    F: Fn(&[u8]) -> Hash,
+{
+    let mut hashes = vec![];
+    while let Some(chunk) = chunker.next_chunk()? {
+        hashes.push(hash(chunk));
+    }
+    if hashes.is_empty() {
+        // An empty file is stored as one empty chunk.
+        hashes.push(hash(&[]));
+    }
+    Ok(hashes)
+}
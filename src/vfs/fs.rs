@@ -2,14 +2,28 @@ use backend::StoreBackend;
 use db;
 use errors::HatError;
 use hash::tree::{self, HashRef, HashTreeBackend};
-use hat;
+use hat::{self, ChunkReader};
 use hat::walker::Content;
-use key::Entry;
-use models::FileName;
+use key::{self, Entry};
+use models::{self, FileName};
+use util;
+use vfs::compare::{self, Change, CompareSummary, Difference};
+use vfs::grep::{Match, Matcher};
 
+use chrono;
+use serde_json;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
 use std::mem;
 use std::path::{self, Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Mutex;
+
+/// How many directory levels of the newest snapshots `warm_up` fetches.
+pub const WARM_UP_DEPTH: usize = 2;
 
 pub struct FileReader {
     rest: Option<Box<Iterator<Item = Vec<u8>>>>,
@@ -98,17 +112,148 @@ pub enum List {
     Dir(Vec<(Entry, Content)>),
 }
 
+impl List {
+    /// The listing as JSON, for scripts: the family names, the `models::Snapshot` of each
+    /// complete snapshot, or the `models::File` of each directory entry.
+    pub fn to_json(&self) -> String {
+        match *self {
+            List::Root(ref snapshots) => {
+                let names: BTreeSet<_> = snapshots.iter().map(|s| &s.family_name).collect();
+                serde_json::to_string(&names).unwrap()
+            }
+            List::Snapshots(ref snapshots) => {
+                let models: Vec<_> = snapshots.iter().filter_map(snapshot_model).collect();
+                serde_json::to_string(&models).unwrap()
+            }
+            List::Dir(ref files) => {
+                let models: Vec<_> = files
+                    .iter()
+                    .map(|(entry, content)| models::File {
+                        id: entry.node_id.unwrap_or(0),
+                        info: entry.info.to_model(),
+                        content: content.to_model(entry.info.hard_link),
+                    })
+                    .collect();
+                serde_json::to_string(&models).unwrap()
+            }
+        }
+    }
+}
+
+/// The model of `snapshot` as stored in the snapshot list, if it is complete.
+fn snapshot_model(snapshot: &db::SnapshotStatus) -> Option<models::Snapshot> {
+    let hash_ref = snapshot
+        .hash_ref
+        .as_ref()
+        .and_then(|bytes| HashRef::from_bytes(&bytes[..]).ok())?;
+    Some(models::Snapshot {
+        id: snapshot.info.snapshot_id,
+        hash_ref: hash_ref.to_model(),
+        family_name: snapshot.family_name.clone(),
+        msg: snapshot.msg.clone().unwrap_or_default(),
+        created_ts_utc: snapshot.created.timestamp(),
+        manifest: snapshot.manifest.clone(),
+        annotations: snapshot.annotations.clone(),
+        tags: snapshot.tags.clone(),
+    })
+}
+
+/// The columns `ls -l` shows before the name of `entry`: its type and permissions, owner,
+/// group, size in bytes and modification time in UTC. Unrecorded values show as `?`.
+pub fn long_columns(entry: &Entry, content: &Content) -> String {
+    let mut mode = String::new();
+    mode.push(match *content {
+        Content::Data(_) => '-',
+        Content::Dir(_) => 'd',
+        Content::Link(_) => 'l',
+        Content::Unreadable(_) => '?',
+        Content::Special(models::SpecialFile::CharDevice(_)) => 'c',
+        Content::Special(models::SpecialFile::BlockDevice(_)) => 'b',
+        Content::Special(models::SpecialFile::Fifo) => 'p',
+        Content::Special(models::SpecialFile::Socket) => 's',
+    });
+    match entry.info.permissions {
+        Some(ref permissions) => {
+            let bits = permissions.mode();
+            for &shift in &[6, 3, 0] {
+                for &(bit, c) in &[(4, 'r'), (2, 'w'), (1, 'x')] {
+                    mode.push(if bits >> shift & bit != 0 { c } else { '-' });
+                }
+            }
+        }
+        None => mode.push_str("?????????"),
+    }
+
+    let owner = |name: &Option<String>, id: Option<u64>| {
+        name.clone()
+            .or_else(|| id.map(|id| id.to_string()))
+            .unwrap_or_else(|| "?".to_owned())
+    };
+    let size = entry
+        .info
+        .byte_length
+        .map_or("?".to_owned(), |len| len.to_string());
+    let modified = entry.info.modified_ts_secs.map_or("?".to_owned(), |secs| {
+        chrono::NaiveDateTime::from_timestamp(secs, 0)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    });
+    format!(
+        "{} {:<8} {:<8} {:>12} {}",
+        mode,
+        owner(&entry.info.user_name, entry.info.user_id),
+        owner(&entry.info.group_name, entry.info.group_id),
+        size,
+        modified
+    )
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GrepSummary {
+    pub files: usize,
+    pub matched_files: usize,
+    pub skipped_large: usize,
+}
+
 pub struct Filesystem<B: StoreBackend> {
     hat: hat::HatRc<B>,
+    reader: hat::HashReader<B>,
+    /// Only snapshots with this tag are listed, when set.
+    tag: Option<String>,
 }
 
 impl<B: StoreBackend> Filesystem<B> {
     pub fn new(hat: hat::HatRc<B>) -> Filesystem<B> {
-        Filesystem { hat }
+        Filesystem {
+            reader: hat.hash_reader(),
+            hat,
+            tag: None,
+        }
+    }
+
+    /// Only list the snapshots tagged `tag`.
+    pub fn with_tag(mut self, tag: &str) -> Filesystem<B> {
+        self.tag = Some(tag.to_owned());
+        self
+    }
+
+    /// Verification counts for the data read back so far.
+    pub fn retrieve_metrics(&self) -> hat::RetrieveMetrics {
+        self.hat.retrieve_metrics()
+    }
+
+    /// The chunks that failed verification when read back so far, and what they affect.
+    pub fn retrieve_report(&mut self) -> hat::RetrieveReport {
+        self.hat.retrieve_report()
     }
 
     pub fn ls(&mut self, path: &Path) -> Result<Option<List>, HatError> {
-        let snapshots = self.hat.list_snapshots();
+        // Internal families are not directories; `Hat::list_roots` shows the snapshot lists.
+        let snapshots: Vec<_> = match self.tag {
+            None => self.hat.list_snapshots(),
+            Some(ref tag) => self.hat.list_snapshots_tagged(tag),
+        };
+        let snapshots: Vec<_> = snapshots.into_iter().filter(|s| !s.is_internal()).collect();
 
         let mut components = path.components();
 
@@ -128,34 +273,27 @@ impl<B: StoreBackend> Filesystem<B> {
         };
 
         if let Some(href_bytes) = snapshot_opt.and_then(|s| s.hash_ref.as_ref()) {
-            let href = HashRef::from_bytes(&href_bytes[..])?;
-            let mut listing = self.ls_ref(href.clone())?;
-            let mut href_opt = Some(href);
+            // Only the listed directory is read in full; its parents are read up to the entry
+            // on the path.
+            let mut href = HashRef::from_bytes(&href_bytes[..])?;
             loop {
-                match (href_opt, components.next()) {
-                    (_, None) => return Ok(Some(List::Dir(listing))),
-                    (None, _) => return Ok(None),
-                    (Some(href), Some(name)) => {
-                        let name: FileName = name.as_os_str().to_owned().into();
-                        if let Some((entry, content)) = listing
-                            .into_iter()
-                            .find(|&(ref e, ref c)| e.info.name == name)
-                        {
-                            match content {
-                                Content::Data(..) | Content::Link(..) => {
-                                    href_opt = None;
-                                    listing = vec![(entry, content)];
-                                    continue;
-                                }
-                                Content::Dir(dir_href) => {
-                                    listing = self.ls_ref(dir_href.clone())?;
-                                    href_opt = Some(dir_href);
-                                    continue;
-                                }
-                            }
-                        } else {
-                            return Ok(None);
-                        }
+                let name: FileName = match components.next() {
+                    None => return Ok(Some(List::Dir(self.ls_ref(href)?))),
+                    Some(name) => name.as_os_str().to_owned().into(),
+                };
+                let found = self
+                    .ls_iter(href)?
+                    .find(|res| res.as_ref().map_or(true, |(e, _)| e.info.name == name))
+                    .transpose()?;
+                match found {
+                    None => return Ok(None),
+                    Some((_, Content::Dir(dir_href))) => href = dir_href,
+                    Some(file) => {
+                        // Nothing is below a file or a link.
+                        return Ok(match components.next() {
+                            None => Some(List::Dir(vec![file])),
+                            Some(_) => None,
+                        });
                     }
                 }
             }
@@ -165,7 +303,447 @@ impl<B: StoreBackend> Filesystem<B> {
     }
 
     pub fn ls_ref(&mut self, hash_ref: HashRef) -> Result<Vec<(Entry, Content)>, HatError> {
-        let backend = self.hat.hash_backend();
-        Ok(hat::Family::<B>::fetch_dir_data(hash_ref, backend)?)
+        self.ls_iter(hash_ref)?.collect()
+    }
+
+    /// The entries of a directory, read as they are consumed.
+    pub fn ls_iter(
+        &self,
+        hash_ref: HashRef,
+    ) -> Result<hat::DirIterator<key::HashStoreBackend<B>>, HatError> {
+        self.reader.list_dir(hash_ref)
+    }
+
+    /// The listing at `path` (`<family>/<id>[/path]`), and whether `path` names a single file
+    /// rather than a directory. `command` names the caller in errors.
+    fn snapshot_listing(&mut self, path: &Path, command: &str) -> Result<(Listing, bool), HatError> {
+        let listing = match self.ls(path)? {
+            Some(List::Dir(listing)) => listing,
+            Some(_) => {
+                return Err(format!(
+                    "{} needs a snapshot path: <family>/<snapshot>[/path]",
+                    command
+                ).into())
+            }
+            None => return Err(format!("No such path: {}", path.display()).into()),
+        };
+
+        // `ls` of a file lists just the file itself.
+        let is_file = match (listing.first(), path.parent()) {
+            (Some(&(ref entry, Content::Data(..))), Some(parent)) if listing.len() == 1 => {
+                let name: OsString = entry.info.name.clone().into();
+                path.file_name() == Some(&name[..]) && match self.ls(parent)? {
+                    Some(List::Dir(siblings)) => siblings.iter().any(|(e, c)| {
+                        e.info.name == entry.info.name && !matches!(*c, Content::Dir(..))
+                    }),
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        Ok((listing, is_file))
+    }
+
+    /// Search the files at or below `path` (`<family>/<id>[/path]`) for the pattern of
+    /// `matcher`, streaming their contents from the backend. Files larger than
+    /// `max_file_size` are skipped. `found` is called with the path of each match.
+    pub fn grep<F>(
+        &mut self,
+        path: &Path,
+        matcher: &Matcher,
+        max_file_size: Option<u64>,
+        mut found: F,
+    ) -> Result<GrepSummary, HatError>
+    where
+        F: FnMut(&Path, Match),
+    {
+        let (listing, is_file) = self.snapshot_listing(path, "grep")?;
+        let base = match path.parent() {
+            Some(parent) if is_file => parent.to_owned(),
+            _ => path.to_owned(),
+        };
+
+        let mut summary = GrepSummary::default();
+        let mut stack: Vec<(PathBuf, Entry, Content)> = listing
+            .into_iter()
+            .rev()
+            .map(|(entry, content)| (base.clone(), entry, content))
+            .collect();
+
+        while let Some((dir, entry, content)) = stack.pop() {
+            let name: OsString = entry.info.name.into();
+            let file_path = dir.join(name);
+            match content {
+                Content::Link(..) | Content::Unreadable(..) | Content::Special(..) => (),
+                Content::Dir(href) => {
+                    for (entry, content) in self.ls_ref(href)?.into_iter().rev() {
+                        stack.push((file_path.clone(), entry, content));
+                    }
+                }
+                Content::Data(href) => {
+                    let too_large = match (entry.info.byte_length, max_file_size) {
+                        (Some(len), Some(max)) => len > max,
+                        _ => false,
+                    };
+                    if too_large {
+                        summary.skipped_large += 1;
+                        continue;
+                    }
+
+                    summary.files += 1;
+                    let matched = match self.reader.get_leaf_iter(href)? {
+                        Some(chunks) => matcher.search(chunks, |m| found(&file_path, m)),
+                        None => false,
+                    };
+                    if matched {
+                        summary.matched_files += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Compare the snapshot at `path` (`<family>/<id>[/path]`) with the live file or
+    /// directory `live`, without restoring anything. File contents are compared by their chunk
+    /// hashes, so only directory and file tree nodes are fetched from the backend.
+    /// `found` is called with the live path of each difference.
+    pub fn compare<F>(
+        &mut self,
+        path: &Path,
+        live: &Path,
+        mut found: F,
+    ) -> Result<CompareSummary, HatError>
+    where
+        F: FnMut(&Path, Difference),
+    {
+        let (listing, is_file) = self.snapshot_listing(path, "compare")?;
+        let mut stack = vec![];
+        if is_file {
+            let exists = fs::symlink_metadata(live).is_ok();
+            stack.push((live.to_owned(), listing.into_iter().next(), exists));
+        } else {
+            merge_dir(listing, live, &mut stack)?;
+        }
+
+        let mut summary = CompareSummary::default();
+        while let Some((live_path, stored, in_live)) = stack.pop() {
+            let difference = match (stored, in_live) {
+                (None, _) => Some(Difference::Added),
+                (Some(_), false) => Some(Difference::Missing),
+                (Some((entry, content)), true) => {
+                    summary.compared += 1;
+                    match self.compare_entry(&entry, content, &live_path, &mut stack) {
+                        Ok(ref changes) if changes.is_empty() => None,
+                        Ok(changes) => Some(Difference::Changed(changes)),
+                        Err(e) => Some(Difference::Unreadable(e.to_string())),
+                    }
+                }
+            };
+            if let Some(difference) = difference {
+                summary.differences += 1;
+                found(&live_path, difference);
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn compare_entry(
+        &mut self,
+        entry: &Entry,
+        content: Content,
+        live_path: &Path,
+        stack: &mut Vec<CompareItem>,
+    ) -> Result<Vec<Change>, HatError> {
+        let meta = fs::symlink_metadata(live_path)?;
+        let kind_matches = match content {
+            Content::Data(..) | Content::Unreadable(..) => meta.is_file(),
+            Content::Dir(..) => meta.is_dir(),
+            Content::Link(..) => meta.file_type().is_symlink(),
+            Content::Special(special) => models::SpecialFile::from_metadata(&meta) == Some(special),
+        };
+        if !kind_matches {
+            return Ok(vec![Change::Kind]);
+        }
+
+        let mut changes = compare::metadata_changes(&entry.info, &meta);
+        match content {
+            Content::Data(href) => {
+                if changes.contains(&Change::Size) {
+                    changes.push(Change::Content);
+                } else {
+                    let mut stored = LeafHashes(vec![]);
+                    if let Some(mut walker) = self.reader.get_tree(href)? {
+                        walker.resume(&mut stored)?;
+                    }
+                    let hat = &self.hat;
+                    let chunker =
+                        hat.chunker(fs::File::open(live_path)?, entry.info.name.as_bytes());
+                    let live = compare::file_chunk_hashes(chunker, |c| hat.file_chunk_hash(c))?;
+                    let mut hashes = vec![];
+                    for leaf in stored.0 {
+                        match leaf {
+                            StoredLeaf::Hash(hash) => hashes.push(hash),
+                            StoredLeaf::Data(data) => {
+                                // Cut as the live file is, which need not be in one chunk.
+                                let chunker = hat.chunker(&data[..], entry.info.name.as_bytes());
+                                hashes.extend(compare::file_chunk_hashes(chunker, |c| {
+                                    hat.file_chunk_hash(c)
+                                })?);
+                            }
+                        }
+                    }
+                    if hashes != live {
+                        changes.push(Change::Content);
+                    }
+                }
+            }
+            Content::Dir(href) => merge_dir(self.ls_ref(href)?, live_path, stack)?,
+            Content::Link(target) => {
+                if fs::read_link(live_path)? != target {
+                    changes.push(Change::LinkTarget);
+                }
+            }
+            Content::Unreadable(..) => changes.push(Change::Unreadable),
+            Content::Special(..) => (),
+        }
+        Ok(changes)
+    }
+
+    /// Whether `path` names a family, a snapshot or a directory inside a snapshot.
+    pub fn is_dir(&mut self, path: &Path) -> Result<bool, HatError> {
+        match self.ls(path)? {
+            None => Ok(false),
+            Some(List::Snapshots(ref snapshots)) => Ok(!snapshots.is_empty()),
+            Some(List::Dir(_)) if path.components().count() > 2 => {
+                Ok(!self.snapshot_listing(path, "cd")?.1)
+            }
+            Some(_) => Ok(true),
+        }
+    }
+
+    /// Write the contents of the file at `path` (`<family>/<id>/path`) to `out`, streaming
+    /// them from the backend. Returns the number of bytes written.
+    pub fn cat<W: io::Write>(&mut self, path: &Path, out: &mut W) -> Result<u64, HatError> {
+        let (mut listing, is_file) = self.snapshot_listing(path, "cat")?;
+        match listing.pop() {
+            Some((_, Content::Data(href))) if is_file => {
+                let chunks = self.reader.get_leaf_iter(href)?
+                    .map(|t| Box::new(t) as Box<Iterator<Item = Vec<u8>>>)
+                    .unwrap_or_else(|| Box::new(None.into_iter()));
+                Ok(io::copy(&mut ChunkReader::new(chunks), out)?)
+            }
+            _ => Err(format!("Not a file: {}", path.display()).into()),
+        }
+    }
+
+    /// Restore the file or directory at `path` (`<family>/<id>[/path]`) as `dest`, with the
+    /// stored permissions. Returns the number of entries written.
+    pub fn extract(&mut self, path: &Path, dest: &Path) -> Result<u64, HatError> {
+        let (listing, is_file) = self.snapshot_listing(path, "get")?;
+        let mut stack: Vec<(PathBuf, Entry, Content)> = if is_file {
+            listing
+                .into_iter()
+                .map(|(entry, content)| (dest.to_owned(), entry, content))
+                .collect()
+        } else {
+            fs::create_dir_all(dest)?;
+            listing
+                .into_iter()
+                .rev()
+                .map(|(entry, content)| {
+                    let name: OsString = entry.info.name.clone().into();
+                    (dest.join(name), entry, content)
+                })
+                .collect()
+        };
+
+        let mut count = 0;
+        let mut permissions = vec![];
+        while let Some((out_path, entry, content)) = stack.pop() {
+            match content {
+                Content::Data(href) => {
+                    let mut fd = fs::File::create(&out_path)?;
+                    if let Some(chunks) = self.reader.get_leaf_iter(href)? {
+                        io::copy(&mut ChunkReader::new(Box::new(chunks)), &mut fd)?;
+                    }
+                }
+                Content::Dir(href) => {
+                    fs::create_dir_all(&out_path)?;
+                    for (entry, content) in self.ls_ref(href)?.into_iter().rev() {
+                        let name: OsString = entry.info.name.clone().into();
+                        stack.push((out_path.join(name), entry, content));
+                    }
+                }
+                Content::Link(target) => ::std::os::unix::fs::symlink(target, &out_path)?,
+                Content::Special(special) => {
+                    if special.is_device() && !hat::owners::may_restore_owners() {
+                        // Only root restores devices.
+                        continue;
+                    }
+                    let mode = entry.info.permissions.as_ref().map_or(0o644, |p| p.mode());
+                    let mode = special.file_type_mode() | (mode & 0o7777);
+                    util::make_special_file(&out_path, mode, special.rdev())?
+                }
+                // The snapshot has no contents to restore.
+                Content::Unreadable(..) => continue,
+            }
+            if let Some(perms) = entry.info.permissions {
+                if !fs::symlink_metadata(&out_path)?.file_type().is_symlink() {
+                    permissions.push((out_path, perms));
+                }
+            }
+            count += 1;
+        }
+        // Directories get their permissions last, as they may not allow adding entries.
+        for (out_path, perms) in permissions.into_iter().rev() {
+            fs::set_permissions(&out_path, perms)?;
+        }
+        Ok(count)
+    }
+
+    /// Write the snapshot at `path` (`<family>/<id>[/path]`) as a tar archive to `out`.
+    /// Entries are named from the root of the snapshot, so extracting the archive in `/`
+    /// restores the original paths. Returns the number of entries written.
+    pub fn write_tar<W: io::Write>(&mut self, path: &Path, out: W) -> Result<u64, HatError> {
+        let (listing, is_file) = self.snapshot_listing(path, "checkout")?;
+        let dir = if is_file { path.parent() } else { Some(path) };
+        let base: PathBuf = dir.map_or(PathBuf::new(), |d| d.iter().skip(2).collect());
+
+        hat::export::write_tar(&self.reader, &base, listing, out)
+    }
+}
+
+type Listing = Vec<(Entry, Content)>;
+
+/// A live path, its snapshot entry if any, and whether it exists in the live tree.
+type CompareItem = (PathBuf, Option<(Entry, Content)>, bool);
+
+/// A leaf of a stored file: its hash, or the data of a small file batched into a composite
+/// leaf, whose hash says nothing about the file.
+enum StoredLeaf {
+    Hash(::hash::Hash),
+    Data(Vec<u8>),
+}
+
+/// Collects the hashes of a tree's leaves without fetching them, except for batched files.
+struct LeafHashes(Vec<StoredLeaf>);
+
+impl tree::Visitor for LeafHashes {
+    fn leaf_enter(&mut self, href: &HashRef) -> bool {
+        if href.range.is_some() {
+            return true;
+        }
+        self.0.push(StoredLeaf::Hash(href.hash.clone()));
+        false
+    }
+
+    fn leaf_leave(&mut self, chunk: Vec<u8>, _href: &HashRef) -> bool {
+        self.0.push(StoredLeaf::Data(chunk));
+        false
+    }
+}
+
+/// Pair the entries of a snapshot directory with those of the live directory `live_dir`,
+/// pushing them onto `stack` in name order.
+fn merge_dir(
+    listing: Listing,
+    live_dir: &Path,
+    stack: &mut Vec<CompareItem>,
+) -> io::Result<()> {
+    let mut stored: BTreeMap<OsString, (Entry, Content)> = listing
+        .into_iter()
+        .map(|(entry, content)| (entry.info.name.clone().into(), (entry, content)))
+        .collect();
+    let mut live = BTreeMap::new();
+    for entry in fs::read_dir(live_dir)? {
+        live.insert(entry?.file_name(), ());
+    }
+
+    let mut names: Vec<OsString> = stored.keys().chain(live.keys()).cloned().collect();
+    names.sort();
+    names.dedup();
+    for name in names.into_iter().rev() {
+        let in_live = live.contains_key(&name);
+        stack.push((live_dir.join(&name), stored.remove(&name), in_live));
+    }
+    Ok(())
+}
+
+/// Directory listings fetched ahead of use, keyed by the hash of the directory.
+#[derive(Default)]
+pub struct DirCache {
+    dirs: Mutex<HashMap<Vec<u8>, Listing>>,
+}
+
+impl DirCache {
+    pub fn new() -> DirCache {
+        DirCache {
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, dir: &HashRef, listing: Listing) {
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.hash.bytes.clone(), listing);
+    }
+
+    /// Remove and return the cached listing of `dir`, if any.
+    pub fn take(&self, dir: &HashRef) -> Option<Listing> {
+        self.dirs.lock().unwrap().remove(&dir.hash.bytes)
+    }
+}
+
+/// The root directory of the newest snapshot in each family.
+pub fn newest_snapshot_roots(snapshots: Vec<db::SnapshotStatus>) -> Vec<HashRef> {
+    let mut newest: BTreeMap<String, db::SnapshotStatus> = BTreeMap::new();
+    for s in snapshots {
+        if s.is_internal() || s.hash_ref.is_none() {
+            continue;
+        }
+        let is_newer = match newest.get(&s.family_name) {
+            Some(n) => n.info.snapshot_id < s.info.snapshot_id,
+            None => true,
+        };
+        if is_newer {
+            newest.insert(s.family_name.clone(), s);
+        }
+    }
+
+    newest
+        .values()
+        .filter_map(|s| s.hash_ref.as_ref())
+        .filter_map(|b| HashRef::from_bytes(&b[..]).ok())
+        .collect()
+}
+
+/// Fetch the top `WARM_UP_DEPTH` directory levels below each of `roots` into `cache`, so the
+/// first listings after mounting a cold repository do not wait for the backend.
+/// Returns the number of directories fetched.
+pub fn warm_up<B: StoreBackend>(
+    reader: hat::HashReader<B>,
+    roots: Vec<HashRef>,
+    cache: &DirCache,
+) -> Result<usize, HatError> {
+    let mut level = roots;
+    let mut fetched = 0;
+    for _ in 0..WARM_UP_DEPTH {
+        let mut next_level = vec![];
+        for dir in level {
+            let listing = reader.list_dir(dir.clone())?.collect::<Result<Listing, _>>()?;
+            for (_, content) in &listing {
+                if let Content::Dir(sub_dir) = content {
+                    next_level.push(sub_dir.clone());
+                }
+            }
+            cache.insert(&dir, listing);
+            fetched += 1;
+        }
+        level = next_level;
     }
+    Ok(fetched)
 }
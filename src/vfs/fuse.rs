@@ -1,19 +1,37 @@
+use super::address::Address;
 use super::fs;
+use super::mount::MountOptions;
 use backend;
 use errors::{self, HatError};
 use hash;
 use hat::{self, walker};
 use libc::{self, c_int};
+use models;
 
 use fuse;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use time::Timespec;
+use std::thread;
+use std::time::{Duration, Instant};
+use time::{self, Timespec};
+use util;
+
+/// Name of the file at the root of the mount that re-reads the snapshot list when opened.
+pub const CONTROL_FILE: &str = ".hat-refresh";
+
+/// How often browsing the mount re-reads the snapshot list, unless told otherwise.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+const ROOT_INO: INode = 1;
+
+/// Tells the kernel to pass reads through without caching, as the control file has no size.
+const FOPEN_DIRECT_IO: u32 = 1;
 
 #[derive(Clone)]
 enum FileType {
@@ -21,6 +39,10 @@ enum FileType {
     ParentTop(hash::tree::HashRef),
     FileTop(hash::tree::HashRef),
     SymbolicLink(PathBuf),
+    /// A device node, FIFO or socket, shown without contents.
+    Special,
+    /// The control file, see `CONTROL_FILE`.
+    Control,
 }
 
 type INode = u64;
@@ -31,34 +53,106 @@ struct File {
     file_type: FileType,
     attr: fuse::FileAttr,
     parent: Option<INode>,
+    /// Extended attributes, as name and value, ordered by name.
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 pub struct Fuse<B: backend::StoreBackend> {
     hat: Arc<Mutex<hat::HatRc<B>>>,
+    reader: hat::HashReader<B>,
     inodes: HashMap<INode, File>,
     parent: HashMap<INode, Vec<INode>>,
-    open_files: HashMap<usize, fs::FileReader>,
+    open_files: util::HandleTable<fs::FileReader>,
+    dir_cache: Arc<fs::DirCache>,
+    only: Address,
+    refresh_interval: Option<Duration>,
+    last_refresh: Instant,
+    mount_options: MountOptions,
+}
+
+/// Snapshots that appeared and disappeared in a refresh of the snapshot list, as `family/id`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Refreshed {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl fmt::Display for Refreshed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for name in &self.added {
+            writeln!(f, "added {}", name)?;
+        }
+        for name in &self.removed {
+            writeln!(f, "removed {}", name)?;
+        }
+        if self.added.is_empty() && self.removed.is_empty() {
+            writeln!(f, "no changes")?;
+        }
+        Ok(())
+    }
 }
 
 impl<B: backend::StoreBackend> Fuse<B> {
     pub fn new(hat: hat::HatRc<B>) -> Fuse<B> {
+        Fuse::new_at(hat, Address::default())
+    }
+
+    /// Mount only the family or snapshot of a resolved `only`.
+    pub fn new_at(hat: hat::HatRc<B>, only: Address) -> Fuse<B> {
         let mut fs = Fuse {
+            reader: hat.hash_reader(),
             hat: Arc::new(Mutex::new(hat)),
             inodes: HashMap::new(),
             parent: HashMap::new(),
-            open_files: HashMap::new(),
+            open_files: util::HandleTable::new(),
+            dir_cache: Arc::new(fs::DirCache::new()),
+            only: only,
+            refresh_interval: Some(DEFAULT_REFRESH_INTERVAL),
+            last_refresh: Instant::now(),
+            mount_options: MountOptions::default(),
         };
 
         fs.populate_from_snapshot_list();
+        fs.start_warm_up();
 
         fs
     }
 
+    /// Prefetch the newest snapshots' top directories in the background.
+    fn start_warm_up(&self) {
+        let snapshots = self.hat.lock().unwrap().list_snapshots();
+        let hat = self.hat.clone();
+        let reader = self.reader.clone();
+        let cache = self.dir_cache.clone();
+        thread::spawn(move || {
+            let roots = fs::newest_snapshot_roots(snapshots);
+            match fs::warm_up(reader, roots, &cache) {
+                Ok(n) => info!("Prefetched {} directories", n),
+                Err(e) => warn!("Could not prefetch directories: {}", e),
+            }
+            hat.lock().unwrap().meta_flush();
+        });
+    }
+
+    /// Re-read the snapshot list when the mount is browsed, at most once every `interval`, or
+    /// only when the control file is read if `None`.
+    pub fn with_refresh_interval(mut self, interval: Option<Duration>) -> Fuse<B> {
+        self.refresh_interval = interval;
+        self
+    }
+
+    pub fn with_mount_options(mut self, options: MountOptions) -> Fuse<B> {
+        self.mount_options = options;
+        self
+    }
+
     pub fn mount<P>(self, mountpoint: &P) -> Result<(), io::Error>
     where
         P: AsRef<Path>,
     {
-        fuse::mount(self, mountpoint, &[])
+        let args = self.mount_options.args();
+        let args: Vec<&OsStr> = args.iter().map(|a| a.as_os_str()).collect();
+        fuse::mount(self, mountpoint, &args)
     }
 
     fn add_file(&mut self, mut file: File) -> u64 {
@@ -101,55 +195,177 @@ impl<B: backend::StoreBackend> Fuse<B> {
             file_type: FileType::Parent,
             attr: Self::default_attr(fuse::FileType::Directory),
             parent: None,
+            xattrs: vec![],
+        });
+        assert_eq!(root_ino, ROOT_INO);
+
+        let mut attr = Self::default_attr(fuse::FileType::RegularFile);
+        attr.perm = 0o444;
+        self.add_file(File {
+            name: CONTROL_FILE.into(),
+            file_type: FileType::Control,
+            attr: attr,
+            parent: Some(root_ino),
+            xattrs: vec![],
         });
 
-        let mut snapshots = HashMap::new();
-        for si in self.hat.lock().unwrap().list_snapshots() {
-            if !snapshots.contains_key(&si.family_name) {
-                snapshots.insert(si.family_name.clone(), vec![]);
+        self.refresh();
+    }
+
+    /// Re-read the snapshot list: add the snapshots committed since it was last read, and
+    /// remove those that were deleted. Existing inodes are kept, so open files stay readable.
+    pub fn refresh(&mut self) -> Refreshed {
+        self.last_refresh = Instant::now();
+        let snapshot_list = {
+            let mut hat = self.hat.lock().unwrap();
+            // Start a new transaction, to see what other processes committed meanwhile.
+            hat.meta_flush();
+            let snapshots = hat.list_snapshots();
+            hat.meta_flush();
+            snapshots
+        };
+
+        let mut families: HashMap<OsString, INode> = HashMap::new();
+        let mut present = HashSet::new();
+        for &family_ino in self.parent.get(&ROOT_INO).into_iter().flatten() {
+            let family = &self.inodes[&family_ino];
+            if let FileType::Parent = family.file_type {
+                families.insert(family.name.clone(), family_ino);
+                for ino in self.parent.get(&family_ino).into_iter().flatten() {
+                    present.insert((family_ino, self.inodes[ino].name.clone()));
+                }
             }
-            snapshots.get_mut(&si.family_name).unwrap().push(si);
         }
 
-        for (family_name, snapshots) in snapshots {
-            if family_name == "__hat__roots__" {
+        let mut refreshed = Refreshed::default();
+        let mut listed = HashSet::new();
+        let now = time::get_time();
+        for s in snapshot_list {
+            if s.is_internal()
+                || self.only.family.as_ref().is_some_and(|f| *f != s.family_name)
+                || self.only.snapshot_id().is_some_and(|id| id != s.info.snapshot_id)
+            {
                 continue;
             }
+            let hash_ref = match s
+                .hash_ref
+                .as_ref()
+                .map(|b| hash::tree::HashRef::from_bytes(&b[..]))
+            {
+                Some(Ok(hash_ref)) => hash_ref,
+                _ => continue,
+            };
 
-            let family_ino = self.add_file(File {
-                name: family_name.into(),
-                file_type: FileType::Parent,
-                attr: Self::default_attr(fuse::FileType::Directory),
-                parent: Some(root_ino),
-            });
-            for s in snapshots {
-                if let Some(Ok(hash_ref)) = s
-                    .hash_ref
-                    .as_ref()
-                    .map(|b| hash::tree::HashRef::from_bytes(&b[..]))
-                {
-                    let mut attr = Self::default_attr(fuse::FileType::Directory);
-                    attr.ctime.sec = s.created.timestamp();
-                    attr.mtime.sec = s.created.timestamp();
-
-                    self.add_file(File {
-                        name: format!("{}", s.info.snapshot_id).into(),
-                        file_type: FileType::ParentTop(hash_ref),
-                        attr: attr,
-                        parent: Some(family_ino),
+            let family_name = OsString::from(&s.family_name);
+            let family_ino = match families.get(&family_name) {
+                Some(&ino) => ino,
+                None => {
+                    let ino = self.add_file(File {
+                        name: family_name.clone(),
+                        file_type: FileType::Parent,
+                        attr: Self::default_attr(fuse::FileType::Directory),
+                        parent: Some(ROOT_INO),
+                        xattrs: vec![],
                     });
+                    self.touch(ROOT_INO, now);
+                    families.insert(family_name, ino);
+                    ino
                 }
+            };
+
+            let name = OsString::from(format!("{}", s.info.snapshot_id));
+            listed.insert((family_ino, name.clone()));
+            if present.contains(&(family_ino, name.clone())) {
+                continue;
+            }
+            let mut attr = Self::default_attr(fuse::FileType::Directory);
+            attr.ctime.sec = s.created.timestamp();
+            attr.mtime.sec = s.created.timestamp();
+            self.add_file(File {
+                name: name,
+                file_type: FileType::ParentTop(hash_ref),
+                attr: attr,
+                parent: Some(family_ino),
+                xattrs: vec![],
+            });
+            self.touch(family_ino, now);
+            refreshed
+                .added
+                .push(format!("{}/{}", s.family_name, s.info.snapshot_id));
+        }
+
+        // Deleted snapshots disappear from their family, and emptied families from the root.
+        for (family_name, family_ino) in families {
+            let children = self.parent.get(&family_ino).cloned().unwrap_or_default();
+            let (kept, gone): (Vec<_>, Vec<_>) = children
+                .into_iter()
+                .partition(|ino| listed.contains(&(family_ino, self.inodes[ino].name.clone())));
+            if gone.is_empty() {
+                continue;
+            }
+            for ino in gone {
+                refreshed.removed.push(format!(
+                    "{}/{}",
+                    family_name.to_string_lossy(),
+                    self.inodes[&ino].name.to_string_lossy()
+                ));
+            }
+            self.touch(family_ino, now);
+            if kept.is_empty() {
+                self.parent.remove(&family_ino);
+                self.parent
+                    .get_mut(&ROOT_INO)
+                    .unwrap()
+                    .retain(|&ino| ino != family_ino);
+                self.touch(ROOT_INO, now);
+            } else {
+                self.parent.insert(family_ino, kept);
+            }
+        }
+
+        refreshed.added.sort();
+        refreshed.removed.sort();
+        refreshed
+    }
+
+    /// Refresh the snapshot list if it is due, before the root or a family directory is read.
+    fn refresh_if_due(&mut self, ino: INode) {
+        let lists_snapshots = ino == ROOT_INO
+            || self.inodes.get(&ino).and_then(|f| f.parent) == Some(ROOT_INO);
+        let due = self
+            .refresh_interval
+            .is_some_and(|interval| self.last_refresh.elapsed() >= interval);
+        if lists_snapshots && due {
+            let refreshed = self.refresh();
+            if refreshed != Refreshed::default() {
+                info!("Refreshed snapshot list: {}", refreshed);
             }
         }
     }
 
+    /// End the read transaction on the index, so other processes can commit while the mount
+    /// is idle.
+    fn release_index(&self) {
+        self.hat.lock().unwrap().meta_flush();
+    }
+
+    /// Mark the directory `ino` as changed at `now`.
+    fn touch(&mut self, ino: INode, now: Timespec) {
+        if let Some(file) = self.inodes.get_mut(&ino) {
+            file.attr.mtime = now;
+            file.attr.ctime = now;
+        }
+    }
+
     pub fn fetch_dir(
         &mut self,
         parent: INode,
         hash_ref: hash::tree::HashRef,
     ) -> Result<(), HatError> {
-        let backend = self.hat.lock().unwrap().hash_backend();
-        let entries = hat::Family::<B>::fetch_dir_data(hash_ref, backend)?;
+        let entries = match self.dir_cache.take(&hash_ref) {
+            Some(entries) => entries,
+            None => self.reader.list_dir(hash_ref)?.collect::<Result<Vec<_>, _>>()?,
+        };
 
         for (entry, hash_ref) in entries {
             let mut file = File {
@@ -157,6 +373,7 @@ impl<B: backend::StoreBackend> Fuse<B> {
                 file_type: FileType::Parent,
                 attr: Self::default_attr(fuse::FileType::Directory),
                 parent: Some(parent),
+                xattrs: entry.info.xattrs,
             };
 
             match hash_ref {
@@ -173,6 +390,18 @@ impl<B: backend::StoreBackend> Fuse<B> {
                     file.file_type = FileType::SymbolicLink(link_path);
                     file.attr.kind = fuse::FileType::Symlink;
                 }
+                walker::Content::Special(special) => {
+                    file.file_type = FileType::Special;
+                    file.attr.kind = match special {
+                        models::SpecialFile::CharDevice(_) => fuse::FileType::CharDevice,
+                        models::SpecialFile::BlockDevice(_) => fuse::FileType::BlockDevice,
+                        models::SpecialFile::Fifo => fuse::FileType::NamedPipe,
+                        models::SpecialFile::Socket => fuse::FileType::Socket,
+                    };
+                    file.attr.rdev = special.rdev() as u32;
+                }
+                // There are no contents to show; `hat ls` lists the error.
+                walker::Content::Unreadable(_) => continue,
             }
 
             if let Some(perms) = entry.info.permissions {
@@ -209,7 +438,10 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
         Ok(())
     }
     fn lookup(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
-        for child_ino in self.childs(parent) {
+        self.refresh_if_due(parent);
+        let children = self.childs(parent);
+        self.release_index();
+        for child_ino in children {
             let child = self.inodes.get(&child_ino).unwrap();
             if child.name.as_os_str() == name {
                 reply.entry(&Timespec { sec: 60, nsec: 0 }, &child.attr, 1);
@@ -233,16 +465,52 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
             }
         }
     }
+    fn getxattr(
+        &mut self,
+        _req: &fuse::Request,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuse::ReplyXattr,
+    ) {
+        use std::os::unix::ffi::OsStrExt;
+        let value = self.inodes.get(&ino).and_then(|file| {
+            file.xattrs
+                .iter()
+                .find(|(n, _)| &n[..] == name.as_bytes())
+                .map(|(_, value)| value.clone())
+        });
+        match value {
+            None => reply.error(libc::ENODATA),
+            Some(value) => reply_xattr(reply, size, &value),
+        }
+    }
+    fn listxattr(&mut self, _req: &fuse::Request, ino: u64, size: u32, reply: fuse::ReplyXattr) {
+        let mut names = vec![];
+        if let Some(file) = self.inodes.get(&ino) {
+            for (name, _) in &file.xattrs {
+                names.extend_from_slice(name);
+                names.push(0);
+            }
+        }
+        reply_xattr(reply, size, &names);
+    }
     fn open(&mut self, req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
-        let backend = self.hat.lock().unwrap().hash_backend();
-
         if let Some(file) = self.inodes.get(&ino).cloned() {
             match file.file_type {
                 FileType::FileTop(hash_ref) => {
-                    let fh = self.open_files.len() + 1;
-                    self.open_files
-                        .insert(fh, fs::FileReader::new(backend, hash_ref).unwrap());
-                    reply.opened(fh as u64, flags);
+                    let file = fs::FileReader::new(self.reader.backend(), hash_ref).unwrap();
+                    self.release_index();
+                    let fh = self.open_files.insert(file);
+                    reply.opened(fh, flags);
+                }
+                FileType::Control => {
+                    let report = self.refresh().to_string().into_bytes();
+                    let file = fs::FileReader::new_from_iter(Some(Box::new(
+                        vec![report].into_iter(),
+                    )));
+                    let fh = self.open_files.insert(file);
+                    reply.opened(fh, FOPEN_DIRECT_IO);
                 }
                 _ => (),
             }
@@ -257,11 +525,12 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
         size: u32,
         reply: fuse::ReplyData,
     ) {
-        if let Some(ref mut file) = self.open_files.get_mut(&(fh as usize)) {
-            match file.read(offset as u64, size as usize) {
+        if let Some(file) = self.open_files.get(fh) {
+            match file.lock().unwrap().read(offset as u64, size as usize) {
                 None => reply.data(&[]),
                 Some(data) => reply.data(&data),
             }
+            self.release_index();
         }
     }
     fn release(
@@ -274,7 +543,7 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
         flush: bool,
         reply: fuse::ReplyEmpty,
     ) {
-        self.open_files.remove(&(fh as usize));
+        self.open_files.remove(fh);
         reply.ok();
     }
     fn opendir(&mut self, req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
@@ -288,6 +557,9 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
         offset: i64,
         mut reply: fuse::ReplyDirectory,
     ) {
+        if offset == 0 {
+            self.refresh_if_due(ino);
+        }
         let file = self.inodes.get(&ino).unwrap().clone();
         let mut files: Vec<(INode, fuse::FileType, OsString)> = vec![];
 
@@ -306,14 +578,19 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
                         FileType::SymbolicLink(..) => {
                             files.push((f_ino, fuse::FileType::Symlink, f.name.clone()));
                         }
-                        FileType::FileTop(..) => {
+                        FileType::FileTop(..) | FileType::Control => {
                             files.push((f_ino, fuse::FileType::RegularFile, f.name.clone()));
                         }
+                        FileType::Special => files.push((f_ino, f.attr.kind, f.name.clone())),
                     };
                 }
             },
-            FileType::FileTop(..) | FileType::SymbolicLink(..) => (),
+            FileType::FileTop(..)
+            | FileType::SymbolicLink(..)
+            | FileType::Special
+            | FileType::Control => (),
         }
+        self.release_index();
 
         files
             .into_iter()
@@ -336,3 +613,14 @@ impl<B: backend::StoreBackend> fuse::Filesystem for Fuse<B> {
         reply.ok();
     }
 }
+
+/// Reply with the size of `data` when asked for it, with `data` when it fits in `size` bytes.
+fn reply_xattr(reply: fuse::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Search file contents for a fixed byte pattern, one chunk at a time.
+
+use std::iter;
+use std::mem;
+
+/// Files with a NUL byte within their first `BINARY_SNIFF_BYTES` are considered binary.
+pub const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+/// Lines longer than this are not buffered; the rest of the file is searched as binary.
+pub const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Files larger than this are skipped unless another limit is given.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Match {
+    /// A matching line of a text file, numbered from 1 and without its line ending.
+    Line { number: u64, line: Vec<u8> },
+    /// The pattern occurs in binary content; reported at most once per file.
+    Binary,
+}
+
+pub struct Matcher {
+    pattern: Vec<u8>,
+    search_binary: bool,
+}
+
+impl Matcher {
+    pub fn new(pattern: &[u8], search_binary: bool) -> Matcher {
+        Matcher {
+            pattern: pattern.to_vec(),
+            search_binary,
+        }
+    }
+
+    fn matches(&self, haystack: &[u8]) -> bool {
+        self.pattern.is_empty()
+            || haystack
+                .windows(self.pattern.len())
+                .any(|w| w == &self.pattern[..])
+    }
+
+    /// Search the file given as a sequence of chunks, calling `found` for every match.
+    /// Binary files are skipped without reading past their first chunks, unless the matcher
+    /// was asked to search them. Returns whether anything matched.
+    pub fn search<I, F>(&self, chunks: I, mut found: F) -> bool
+    where
+        I: Iterator<Item = Vec<u8>>,
+        F: FnMut(Match),
+    {
+        let mut rest = chunks;
+        let mut head = vec![];
+        while head.len() < BINARY_SNIFF_BYTES {
+            match rest.next() {
+                Some(chunk) => head.extend_from_slice(&chunk[..]),
+                None => break,
+            }
+        }
+
+        let binary = head[..head.len().min(BINARY_SNIFF_BYTES)].contains(&0);
+        let chunks = iter::once(head).chain(rest);
+        if binary {
+            self.search_binary && self.search_bytes(chunks, &mut found)
+        } else {
+            self.search_lines(chunks, &mut found)
+        }
+    }
+
+    fn search_lines<I, F>(&self, mut chunks: I, found: &mut F) -> bool
+    where
+        I: Iterator<Item = Vec<u8>>,
+        F: FnMut(Match),
+    {
+        let mut line = vec![];
+        let mut number = 1;
+        let mut matched = false;
+        let mut long_line = false;
+
+        for chunk in chunks.by_ref() {
+            let mut start = 0;
+            while let Some(end) = chunk[start..].iter().position(|&b| b == b'\n') {
+                line.extend_from_slice(&chunk[start..start + end]);
+                if self.matches(&line[..]) {
+                    found(Match::Line {
+                        number,
+                        line: line.clone(),
+                    });
+                    matched = true;
+                }
+                line.clear();
+                number += 1;
+                start += end + 1;
+            }
+            line.extend_from_slice(&chunk[start..]);
+
+            if line.len() > MAX_LINE_BYTES {
+                long_line = true;
+                break;
+            }
+        }
+
+        if long_line {
+            let partial = mem::take(&mut line);
+            let rest = iter::once(partial).chain(chunks);
+            return self.search_bytes(rest, found) || matched;
+        }
+
+        if !line.is_empty() && self.matches(&line[..]) {
+            found(Match::Line { number, line });
+            matched = true;
+        }
+        matched
+    }
+
+    fn search_bytes<I, F>(&self, chunks: I, found: &mut F) -> bool
+    where
+        I: Iterator<Item = Vec<u8>>,
+        F: FnMut(Match),
+    {
+        // Keep enough of the previous chunk to find matches spanning a chunk boundary.
+        let keep = self.pattern.len().saturating_sub(1);
+        let mut window = vec![];
+        for chunk in chunks {
+            window.extend_from_slice(&chunk[..]);
+            if self.matches(&window[..]) {
+                found(Match::Binary);
+                return true;
+            }
+            let drop = window.len().saturating_sub(keep);
+            window.drain(..drop);
+        }
+        false
+    }
+}
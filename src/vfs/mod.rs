@@ -1,8 +1,17 @@
+pub mod address;
+pub mod compare;
 pub mod fs;
+pub mod grep;
+pub mod mount;
+pub mod shell;
+#[cfg(feature = "fuse")]
 mod fuse;
 
+pub use self::address::Address;
 pub use self::fs::Filesystem;
-pub use self::fuse::Fuse;
+pub use self::shell::Shell;
+#[cfg(feature = "fuse")]
+pub use self::fuse::{Fuse, Refreshed, CONTROL_FILE};
 
 #[cfg(test)]
 pub mod tests;
@@ -0,0 +1,180 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How a FUSE mount is set up, for hosts and containers alike.
+//!
+//! A process that can open the FUSE device mounts directly; this includes root in a user
+//! namespace with its own mount namespace. Others, such as unprivileged users in rootless
+//! containers where the device belongs to an unmapped owner, leave the mount to the setuid
+//! `fusermount` helper of libfuse 2 or `fusermount3` of libfuse 3.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The FUSE device.
+pub const FUSE_DEVICE: &str = "/dev/fuse";
+
+/// Helpers that mount on behalf of unprivileged users, in order of preference. libfuse 2 runs
+/// the first one; the second speaks the same protocol.
+pub const HELPERS: [&str; 2] = ["fusermount", "fusermount3"];
+
+/// Options given to every mount: name the filesystem in the mount table, and refuse writes.
+const DEFAULT_OPTIONS: [&str; 3] = ["fsname=hat", "subtype=hat", "ro"];
+
+/// Options passed to the kernel and the mount helper with `-o`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountOptions {
+    options: Vec<String>,
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions {
+            options: DEFAULT_OPTIONS.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+}
+
+impl MountOptions {
+    /// The default options followed by `extra`, each a comma-separated list such as
+    /// `allow_other,uid=1000`.
+    pub fn parse(extra: &[&str]) -> Result<MountOptions, String> {
+        let mut options = MountOptions::default();
+        for list in extra {
+            for option in list.split(',') {
+                let option = option.trim();
+                if option.is_empty() || option.contains(char::is_whitespace) {
+                    return Err(format!("Invalid mount option '{}' in '{}'", option, list));
+                }
+                if option == "rw" {
+                    return Err("Snapshots can only be mounted read-only".to_owned());
+                }
+                options = options.with_option(option);
+            }
+        }
+        Ok(options)
+    }
+
+    /// Add `option` unless it is already given.
+    pub fn with_option(mut self, option: &str) -> MountOptions {
+        if !self.options.iter().any(|o| o == option) {
+            self.options.push(option.to_owned());
+        }
+        self
+    }
+
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    /// Arguments for `fuse::mount`.
+    pub fn args(&self) -> Vec<OsString> {
+        vec![OsString::from("-o"), OsString::from(self.options.join(","))]
+    }
+}
+
+/// How to mount.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountMethod {
+    /// Open the FUSE device and mount it.
+    Direct,
+    /// Leave it to the mount helper at this path.
+    Helper(PathBuf),
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// The first of `HELPERS` found in the directories of `path_var`, a `PATH`.
+pub fn find_helper(path_var: &OsStr) -> Option<PathBuf> {
+    HELPERS.iter().find_map(|name| {
+        env::split_paths(path_var)
+            .map(|dir| dir.join(name))
+            .find(|path| is_executable(path))
+    })
+}
+
+/// Decide how to mount, given the FUSE device and the `PATH` to find helpers in. Whether the
+/// device can be opened is what counts, not who owns it.
+pub fn plan(device: &Path, path_var: &OsStr) -> Result<MountMethod, String> {
+    let open = fs::OpenOptions::new().read(true).write(true).open(device);
+    let err = match open {
+        Ok(_) => return Ok(MountMethod::Direct),
+        Err(e) => e,
+    };
+    match find_helper(path_var) {
+        Some(helper) => Ok(MountMethod::Helper(helper)),
+        None if err.kind() == io::ErrorKind::NotFound => Err(format!(
+            "FUSE is not available on this system: there is no {} and no {} or {} in PATH",
+            device.display(),
+            HELPERS[0],
+            HELPERS[1]
+        )),
+        None => Err(format!(
+            "Can not open {} ({}), and there is no {} or {} in PATH to mount with",
+            device.display(),
+            err,
+            HELPERS[0],
+            HELPERS[1]
+        )),
+    }
+}
+
+/// Prepare to mount with `method`, returning the options to mount with.
+///
+/// With a helper, the options ask libfuse to use it rather than open the device first, which
+/// fails when the device is not ours; this also has the helper unmount if hat dies. libfuse 2
+/// only runs a helper called `fusermount`, so a `fusermount3` is linked under that name in
+/// `shim_dir`, and `shim_dir` is put first in `PATH`.
+pub fn prepare(
+    method: &MountMethod,
+    options: MountOptions,
+    shim_dir: &Path,
+) -> Result<MountOptions, io::Error> {
+    let helper = match *method {
+        MountMethod::Direct => return Ok(options),
+        MountMethod::Helper(ref helper) => helper,
+    };
+    if helper.file_name() != Some(OsStr::new(HELPERS[0])) {
+        link_helper(helper, shim_dir)?;
+        let path_var = env::var_os("PATH").unwrap_or_default();
+        let dirs = Some(shim_dir.to_owned())
+            .into_iter()
+            .chain(env::split_paths(&path_var));
+        let joined =
+            env::join_paths(dirs).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        env::set_var("PATH", joined);
+    }
+    Ok(options.with_option("auto_unmount"))
+}
+
+/// Link `helper` as `fusermount` in `shim_dir`, replacing an earlier link.
+pub fn link_helper(helper: &Path, shim_dir: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(shim_dir)?;
+    let link = shim_dir.join(HELPERS[0]);
+    match fs::symlink_metadata(&link) {
+        Ok(_) => fs::remove_file(&link)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    symlink(helper, link)
+}
@@ -0,0 +1,284 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An interactive browser of snapshots, for systems where `mount` is not available.
+//!
+//! The shell keeps a current directory inside hat and understands the same addresses as the
+//! command line, so `cd home/latest` enters the newest snapshot of `home`. Files are read
+//! straight from the backend; nothing is restored unless asked for with `get`.
+
+use backend::StoreBackend;
+use errors::HatError;
+use hat::walker::Content;
+use std::ffi::OsString;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use util::LineEditor;
+use vfs::fs::{Filesystem, List};
+use vfs::Address;
+
+const COMMANDS: &[&str] = &["cat", "cd", "exit", "get", "help", "ls", "pwd"];
+
+const HELP: &str = "Commands:
+  ls [PATH]         list a directory, a snapshot or a family
+  cd [PATH]         change directory; without PATH, go to the top
+  pwd               print the current directory
+  cat PATH          print the contents of a file
+  get PATH [DEST]   restore a file or directory to DEST (default: its name, here)
+  help              show this help
+  exit              leave the shell
+Paths are family/snapshot/path; the snapshot may be `latest` or `^N`. Tab completes commands
+and paths.
+";
+
+pub struct Shell<B: StoreBackend> {
+    fs: Filesystem<B>,
+    cwd: PathBuf,
+}
+
+impl<B: StoreBackend> Shell<B> {
+    pub fn new(fs: Filesystem<B>) -> Shell<B> {
+        Shell {
+            fs: fs,
+            cwd: PathBuf::new(),
+        }
+    }
+
+    /// The current directory, as `family/snapshot/path`.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    /// Read commands from `input` until it ends or says `exit`. Output and errors go to `out`.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        for line in input.lines() {
+            match self.execute(&line?, out) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => writeln!(out, "Error: {}", e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Read commands from the terminal through `editor` until input ends or says `exit`, with
+    /// tab completion of commands and paths.
+    pub fn run_interactive<W: Write>(
+        &mut self,
+        editor: &mut LineEditor,
+        out: &mut W,
+    ) -> io::Result<()> {
+        loop {
+            let prompt = format!("hat:/{}> ", self.cwd.display());
+            let line = match editor.read_line(&prompt, |line| self.complete(line))? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            match self.execute(&line, out) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(e) => writeln!(out, "Error: {}", e)?,
+            }
+            out.flush()?;
+        }
+    }
+
+    /// Candidates for the last word of `line`: a command for the first word, a path after it.
+    /// Directories end in `/`.
+    pub fn complete(&mut self, line: &str) -> Vec<String> {
+        let word = match line.rfind(' ') {
+            None => {
+                return COMMANDS
+                    .iter()
+                    .filter(|c| c.starts_with(line))
+                    .map(|c| c.to_string())
+                    .collect()
+            }
+            Some(i) => &line[i + 1..],
+        };
+        let (dir, partial) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+
+        let names = match self.names(dir) {
+            Ok(names) => names,
+            Err(_) => return vec![],
+        };
+        names
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(partial))
+            .map(|(name, is_dir)| format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+            .collect()
+    }
+
+    /// The names in directory `dir`, and whether each is a directory.
+    fn names(&mut self, dir: &str) -> Result<Vec<(String, bool)>, HatError> {
+        let path = self.resolve(dir)?;
+        if !self.fs.is_dir(&path)? {
+            return Ok(vec![]);
+        }
+        Ok(match self.fs.ls(&path)? {
+            Some(List::Root(snapshots)) => {
+                let mut families: Vec<_> = snapshots
+                    .into_iter()
+                    .map(|s| (s.family_name, true))
+                    .collect();
+                families.sort();
+                families.dedup();
+                families
+            }
+            Some(List::Snapshots(snapshots)) => {
+                let mut ids: Vec<_> = snapshots
+                    .into_iter()
+                    .filter(|s| s.hash_ref.is_some())
+                    .map(|s| (s.info.snapshot_id.to_string(), true))
+                    .collect();
+                ids.push(("latest".to_owned(), true));
+                ids
+            }
+            Some(List::Dir(listing)) => listing
+                .into_iter()
+                .map(|(entry, content)| {
+                    let name: OsString = entry.info.name.into();
+                    let is_dir = matches!(content, Content::Dir(_));
+                    (name.to_string_lossy().into_owned(), is_dir)
+                })
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    /// Run the command `line`, writing its output to `out`. Returns false on `exit`.
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> Result<bool, HatError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match (words.first().cloned(), words.len()) {
+            (None, _) => (),
+            (Some("exit"), 1) | (Some("quit"), 1) => return Ok(false),
+            (Some("help"), 1) => write!(out, "{}", HELP)?,
+            (Some("pwd"), 1) => writeln!(out, "/{}", self.cwd.display())?,
+            (Some("cd"), 1) => self.cwd = PathBuf::new(),
+            (Some("cd"), 2) => {
+                let path = self.resolve(words[1])?;
+                if !self.fs.is_dir(&path)? {
+                    return Err(format!("Not a directory: {}", words[1]).into());
+                }
+                self.cwd = path;
+            }
+            (Some("ls"), 1) | (Some("ls"), 2) => {
+                let path = self.resolve(words.get(1).cloned().unwrap_or(""))?;
+                self.ls(&path, out)?;
+            }
+            (Some("cat"), 2) => {
+                let path = self.resolve(words[1])?;
+                self.fs.cat(&path, out)?;
+            }
+            (Some("get"), 2) | (Some("get"), 3) => {
+                let path = self.resolve(words[1])?;
+                let dest = match words.get(2) {
+                    Some(dest) => PathBuf::from(dest),
+                    None => match path.file_name() {
+                        Some(name) if path.components().count() > 2 => PathBuf::from(name),
+                        _ => return Err("get of a whole snapshot needs a DEST".to_string().into()),
+                    },
+                };
+                let count = self.fs.extract(&path, &dest)?;
+                writeln!(out, "Restored {} entries to {}", count, dest.display())?;
+            }
+            (Some(command), _) => {
+                return Err(
+                    format!("Unknown command or wrong arguments: {}; try help", command).into(),
+                )
+            }
+        }
+        Ok(true)
+    }
+
+    /// `arg` relative to the current directory, with `.`, `..` and snapshot selectors applied.
+    /// A leading `/` starts from the top.
+    fn resolve(&mut self, arg: &str) -> Result<PathBuf, HatError> {
+        let mut parts: Vec<String> = if arg.starts_with('/') {
+            vec![]
+        } else {
+            self.cwd
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect()
+        };
+        for part in arg.split('/') {
+            match part {
+                "" | "." => (),
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part.to_owned()),
+            }
+        }
+
+        let snapshots = match self.fs.ls(Path::new(""))? {
+            Some(List::Root(snapshots)) => snapshots,
+            _ => vec![],
+        };
+        let path = Address::parse(&parts.join("/"))?.resolve(&snapshots)?.to_path();
+        Ok(path.iter().collect())
+    }
+
+    fn ls<W: Write>(&mut self, path: &Path, out: &mut W) -> Result<(), HatError> {
+        match self.fs.ls(path)? {
+            Some(List::Root(snapshots)) => {
+                let mut families: Vec<_> = snapshots.into_iter().map(|s| s.family_name).collect();
+                families.sort();
+                families.dedup();
+                for family in families {
+                    writeln!(out, "{}/", family)?;
+                }
+            }
+            Some(List::Snapshots(ref snapshots)) if snapshots.is_empty() => {
+                return Err(format!("No such family: {}", path.display()).into())
+            }
+            Some(List::Snapshots(snapshots)) => {
+                for s in snapshots {
+                    let complete = if s.hash_ref.is_some() {
+                        ""
+                    } else {
+                        " (incomplete)"
+                    };
+                    writeln!(
+                        out,
+                        "{}/\t{}{}",
+                        s.info.snapshot_id,
+                        s.created.to_rfc3339(),
+                        complete
+                    )?;
+                }
+            }
+            Some(List::Dir(listing)) => {
+                for (entry, content) in listing {
+                    let name: OsString = entry.info.name.into();
+                    let name = name.to_string_lossy();
+                    match content {
+                        Content::Dir(_) => writeln!(out, "{}/", name)?,
+                        Content::Data(_) | Content::Special(_) => writeln!(out, "{}", name)?,
+                        Content::Link(target) => writeln!(out, "{} -> {}", name, target.display())?,
+                        Content::Unreadable(error) => {
+                            writeln!(out, "{}\t(unreadable: {})", name, error)?
+                        }
+                    }
+                }
+            }
+            None => return Err(format!("No such path: /{}", path.display()).into()),
+        }
+        Ok(())
+    }
+}
@@ -10,8 +10,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::fs::FileReader;
+use super::address::{Address, Selector};
+use super::compare::{Change, CompareSummary, Difference};
+use super::fs::{self, DirCache, FileReader, Filesystem, GrepSummary};
+use super::grep::{Match, Matcher, MAX_LINE_BYTES};
+use super::shell::Shell;
+use backend::MemoryBackend;
+use hash::tree::HashRef;
+use hat::tests::{entry, setup_hat};
+use hat::walker::Content;
+use key::Entry;
+use models::{self, FileName};
+use filetime::{self, FileTime};
 use quickcheck;
+use serde_json;
+use std::env;
+use std::fs as std_fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::Arc;
+use util::FileIterator;
 
 #[test]
 fn filereader() {
@@ -44,3 +64,746 @@ fn filereader() {
 
     quickcheck::quickcheck(prop as fn(Vec<Vec<u8>>, u16, u8) -> bool);
 }
+
+#[test]
+fn parse_and_resolve_addresses() {
+    let address = Address::parse("/home/^1/etc//fstab").unwrap();
+    assert_eq!(address.family, Some("home".to_owned()));
+    assert_eq!(address.snapshot, Some(Selector::Back(1)));
+    assert_eq!(address.path, Path::new("etc/fstab"));
+    assert_eq!(Address::parse("").unwrap(), Address::default());
+    assert_eq!(Address::parse("home/latest").unwrap().snapshot, Some(Selector::Latest));
+    assert!(Address::parse("home/newest").is_err());
+    assert!(Address::parse("home/^x").is_err());
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("home".to_string()).unwrap();
+    for name in &["a", "b", "c"] {
+        family
+            .snapshot_direct(entry(name.to_string()), false, Some(FileIterator::from_bytes(vec![1])))
+            .unwrap();
+        family.flush().unwrap();
+        hat.commit(&mut family, None).unwrap();
+    }
+    hat.data_flush().unwrap();
+    let snapshots = hat.list_snapshots();
+
+    let resolve = |s: &str| Address::parse(s).unwrap().resolve(&snapshots);
+    assert_eq!(resolve("home/latest").unwrap().to_path(), Path::new("home/3"));
+    assert_eq!(resolve("home/^2/a").unwrap().to_path(), Path::new("home/1/a"));
+    assert_eq!(resolve("home/2/b").unwrap().snapshot_id(), Some(2));
+    assert_eq!(resolve("home").unwrap().to_path(), Path::new("home"));
+    assert!(resolve("home/^3").is_err());
+    assert!(resolve("other/latest").is_err());
+
+    // Resolved addresses list like the paths they stand for.
+    let mut filesystem = Filesystem::new(hat);
+    let latest = resolve("home/latest/c").unwrap();
+    assert!(filesystem.ls(&latest.to_path()).unwrap().is_some());
+    assert!(latest.expect_snapshot("derive", false).is_err());
+    assert!(latest.expect_snapshot("grep", true).is_ok());
+    assert!(resolve("home").unwrap().expect_snapshot("delete", false).is_err());
+}
+
+#[test]
+fn ls_lists_only_tagged_snapshots() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    for &(name, tag) in &[("home", None), ("home", Some("keep")), ("etc", None)] {
+        let mut family = hat.open_family(name.to_string()).unwrap();
+        family
+            .snapshot_direct(entry("a".to_string()), false, Some(FileIterator::from_bytes(vec![1])))
+            .unwrap();
+        family.flush().unwrap();
+        hat.set_commit_message(None, tag.into_iter().map(|t| t.to_owned()).collect());
+        hat.commit(&mut family, None).unwrap();
+    }
+    hat.data_flush().unwrap();
+
+    let mut filesystem = Filesystem::new(hat).with_tag("keep");
+    match filesystem.ls(Path::new("")).unwrap() {
+        Some(fs::List::Root(snapshots)) => {
+            let names: Vec<_> = snapshots.iter().map(|s| &s.family_name[..]).collect();
+            assert_eq!(names, vec!["home"]);
+        }
+        _ => panic!("expected the families"),
+    }
+    match filesystem.ls(Path::new("home")).unwrap() {
+        Some(fs::List::Snapshots(snapshots)) => {
+            let ids: Vec<_> = snapshots.iter().map(|s| s.info.snapshot_id).collect();
+            assert_eq!(ids, vec![2]);
+        }
+        _ => panic!("expected the snapshots of home"),
+    }
+    assert!(filesystem.ls(Path::new("home/1")).unwrap().is_none());
+    assert!(filesystem.ls(Path::new("home/2/a")).unwrap().is_some());
+}
+
+#[test]
+fn ls_long_columns_and_json() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    let mut file = entry("file".to_string());
+    file.info.permissions = Some(std_fs::Permissions::from_mode(0o100640));
+    // Ids without names here, as committing records the names of the local ones.
+    file.info.user_id = Some(54321);
+    file.info.group_id = Some(54322);
+    file.info.byte_length = Some(3);
+    file.info.modified_ts_secs = Some(1500000000);
+    family
+        .snapshot_direct(file, false, Some(FileIterator::from_bytes(vec![1, 2, 3])))
+        .unwrap();
+    family.flush().unwrap();
+    hat.set_commit_message(Some("first".to_owned()), vec!["keep".to_owned()]);
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut filesystem = Filesystem::new(hat);
+    let list = filesystem.ls(Path::new("family/1")).unwrap().unwrap();
+    match list {
+        fs::List::Dir(ref files) => {
+            assert_eq!(files.len(), 1);
+            let (ref entry, ref content) = files[0];
+            assert_eq!(
+                fs::long_columns(entry, content),
+                "-rw-r----- 54321    54322               3 2017-07-14 02:40"
+            );
+        }
+        _ => panic!("expected a directory"),
+    }
+    let files: Vec<models::File> = serde_json::from_str(&list.to_json()).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].info.name, FileName::from("file".to_owned()));
+    assert_eq!(files[0].info.byte_length, 3);
+    assert_eq!(files[0].info.modified_ts, 1500000000);
+
+    let root = filesystem.ls(Path::new("")).unwrap().unwrap();
+    assert_eq!(root.to_json(), r#"["family"]"#);
+    let snapshots = filesystem.ls(Path::new("family")).unwrap().unwrap();
+    let snapshots: Vec<models::Snapshot> = serde_json::from_str(&snapshots.to_json()).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].msg, "first");
+    assert_eq!(snapshots[0].tags, vec!["keep"]);
+}
+
+#[test]
+fn warm_up_newest_snapshots() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+
+    // Two snapshots; only the second has a sub-directory with a file in it.
+    family
+        .snapshot_direct(entry("old".to_string()), false, Some(FileIterator::from_bytes(vec![1])))
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+
+    let dir = family.snapshot_direct(entry("dir".to_string()), true, None).unwrap();
+    let mut file = entry("file".to_string());
+    file.parent_id = Some(dir);
+    family
+        .snapshot_direct(file, false, Some(FileIterator::from_bytes(vec![2])))
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let roots = fs::newest_snapshot_roots(hat.list_snapshots());
+    assert_eq!(roots.len(), 1);
+    let root = roots[0].clone();
+
+    let cache = DirCache::new();
+    assert_eq!(fs::warm_up(hat.hash_reader(), roots, &cache).unwrap(), 2);
+
+    // The cached listings match what we would fetch ourselves.
+    let mut filesystem = Filesystem::new(hat);
+    fn names(listing: &[(Entry, Content)]) -> Vec<FileName> {
+        listing.iter().map(|(e, _)| e.info.name.clone()).collect()
+    }
+    let listing = cache.take(&root).unwrap();
+    assert_eq!(names(&listing), names(&filesystem.ls_ref(root.clone()).unwrap()));
+
+    let sub_dir: HashRef = match listing
+        .into_iter()
+        .find(|(_, c)| matches!(*c, Content::Dir(..)))
+    {
+        Some((_, Content::Dir(href))) => href,
+        _ => panic!("missing sub-directory"),
+    };
+    assert_eq!(
+        names(&cache.take(&sub_dir).unwrap()),
+        vec![FileName::Utf8("file".to_string())]
+    );
+    assert!(cache.take(&root).is_none());
+}
+
+fn grep_chunks(matcher: &Matcher, chunks: Vec<Vec<u8>>) -> Vec<Match> {
+    let mut found = vec![];
+    matcher.search(chunks.into_iter(), |m| found.push(m));
+    found
+}
+
+#[test]
+fn grep_lines_across_chunks() {
+    fn prop(split: Vec<u8>) -> bool {
+        let text = b"first line\nneedle here\nnothing\nneedle at the end".to_vec();
+
+        // Cut the text into chunks at arbitrary places.
+        let mut chunks = vec![];
+        let mut rest = &text[..];
+        for s in split {
+            let at = (s as usize) % (rest.len() + 1);
+            chunks.push(rest[..at].to_vec());
+            rest = &rest[at..];
+        }
+        chunks.push(rest.to_vec());
+
+        grep_chunks(&Matcher::new(b"needle", false), chunks)
+            == vec![
+                Match::Line {
+                    number: 2,
+                    line: b"needle here".to_vec(),
+                },
+                Match::Line {
+                    number: 4,
+                    line: b"needle at the end".to_vec(),
+                },
+            ]
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+#[test]
+fn grep_binary_and_long_lines() {
+    let binary = vec![b"abc\0ne".to_vec(), b"edle".to_vec()];
+    assert!(grep_chunks(&Matcher::new(b"needle", false), binary.clone()).is_empty());
+    assert_eq!(
+        grep_chunks(&Matcher::new(b"needle", true), binary),
+        vec![Match::Binary]
+    );
+
+    let mut long = vec![b'x'; MAX_LINE_BYTES + 1];
+    long.extend_from_slice(b"needle");
+    assert_eq!(
+        grep_chunks(&Matcher::new(b"needle", false), vec![long]),
+        vec![Match::Binary]
+    );
+}
+
+#[test]
+fn grep_snapshot() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+
+    let dir = family.snapshot_direct(entry("dir".to_string()), true, None).unwrap();
+    let files = vec![
+        (Some(dir), "a", b"hello\nsecret word\n".to_vec()),
+        (Some(dir), "b", b"nothing here\n".to_vec()),
+        (None, "big", b"another secret in a bigger file\n".to_vec()),
+    ];
+    for (parent, name, contents) in files {
+        let mut file = entry(name.to_string());
+        file.parent_id = parent;
+        file.info.byte_length = Some(contents.len() as u64);
+        family
+            .snapshot_direct(file, false, Some(FileIterator::from_bytes(contents)))
+            .unwrap();
+    }
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut filesystem = Filesystem::new(hat);
+    let matcher = Matcher::new(b"secret", false);
+    let grep = |fs: &mut Filesystem<MemoryBackend>, path: &str, max: Option<u64>| {
+        let mut found = vec![];
+        let summary = fs
+            .grep(Path::new(path), &matcher, max, |p, m| found.push((p.to_owned(), m)))
+            .unwrap();
+        (summary, found)
+    };
+    let secret = |path: &str, line: &[u8]| {
+        (
+            PathBuf::from(path),
+            Match::Line {
+                number: 2,
+                line: line.to_vec(),
+            },
+        )
+    };
+
+    let (summary, found) = grep(&mut filesystem, "family/1", None);
+    assert_eq!(
+        summary,
+        GrepSummary {
+            files: 3,
+            matched_files: 2,
+            skipped_large: 0,
+        }
+    );
+    assert_eq!(found.len(), 2);
+    assert!(found.contains(&secret("family/1/dir/a", b"secret word")));
+
+    // The size limit skips "big", and a file can be searched on its own.
+    let (summary, found) = grep(&mut filesystem, "family/1", Some(20));
+    assert_eq!(summary.skipped_large, 1);
+    assert_eq!(found, vec![secret("family/1/dir/a", b"secret word")]);
+
+    let (summary, found) = grep(&mut filesystem, "family/1/dir/a", None);
+    assert_eq!(summary.files, 1);
+    assert_eq!(found, vec![secret("family/1/dir/a", b"secret word")]);
+
+    assert!(filesystem
+        .grep(Path::new("family/1/missing"), &matcher, None, |_, _| ())
+        .is_err());
+}
+
+#[test]
+fn compare_with_live_tree() {
+    let dir = env::temp_dir().join(format!("hat-compare-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dir);
+    std_fs::create_dir_all(dir.join("sub")).unwrap();
+    let big: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    std_fs::write(dir.join("big"), &big).unwrap();
+    std_fs::write(dir.join("empty"), b"").unwrap();
+    std_fs::write(dir.join("sub/file"), b"contents").unwrap();
+    std_fs::write(dir.join("sub/gone"), b"bye").unwrap();
+    std_fs::write(dir.join("sub/mode"), b"mode").unwrap();
+    symlink("big", dir.join("link")).unwrap();
+
+    // Pin modification times, so changes made within the same second are not missed.
+    let old = FileTime::from_unix_time(1_500_000_000, 0);
+    let pin = |path: &str| filetime::set_symlink_file_times(dir.join(path), old, old).unwrap();
+    for path in &["big", "empty", "link", "sub/file", "sub/gone", "sub/mode", "sub"] {
+        pin(path);
+    }
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    family.snapshot_dir(dir.clone());
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut filesystem = Filesystem::new(hat);
+    let snapshot = Path::new("family/1").join(dir.strip_prefix("/").unwrap());
+    let mut compare = |snapshot: &Path, live: &Path| {
+        let mut found = vec![];
+        let summary = filesystem
+            .compare(snapshot, live, |p, d| found.push((p.to_owned(), d)))
+            .unwrap();
+        (summary, found)
+    };
+
+    // An unchanged tree has no differences.
+    let (summary, found) = compare(&snapshot, &dir);
+    assert_eq!(found, vec![]);
+    assert_eq!(summary.compared, 7);
+
+    // Same size and modification time, different contents.
+    let file = dir.join("sub/file");
+    std_fs::write(&file, b"CONTENTS").unwrap();
+    std_fs::remove_file(dir.join("sub/gone")).unwrap();
+    std_fs::write(dir.join("sub/new"), b"new").unwrap();
+    std_fs::set_permissions(dir.join("sub/mode"), std_fs::Permissions::from_mode(0o600)).unwrap();
+    std_fs::remove_file(dir.join("link")).unwrap();
+    symlink("empty", dir.join("link")).unwrap();
+    for path in &["link", "sub/file", "sub"] {
+        pin(path);
+    }
+    let newer = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_times(dir.join("empty"), newer, newer).unwrap();
+
+    let (summary, found) = compare(&snapshot, &dir);
+    let changed = |path: &str, changes: Vec<Change>| (dir.join(path), Difference::Changed(changes));
+    assert_eq!(
+        found,
+        vec![
+            changed("empty", vec![Change::Modified]),
+            changed("link", vec![Change::LinkTarget]),
+            changed("sub/file", vec![Change::Content]),
+            (dir.join("sub/gone"), Difference::Missing),
+            changed("sub/mode", vec![Change::Permissions]),
+            (dir.join("sub/new"), Difference::Added),
+        ]
+    );
+    assert_eq!(
+        summary,
+        CompareSummary {
+            compared: 6,
+            differences: 6,
+        }
+    );
+
+    // A single file can be compared on its own.
+    let (_, found) = compare(&snapshot.join("sub/file"), &file);
+    assert_eq!(found, vec![(file.clone(), Difference::Changed(vec![Change::Content]))]);
+    let (_, found) = compare(&snapshot.join("big"), &dir.join("big"));
+    assert_eq!(found, vec![]);
+
+    std_fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The (name, type flag, mode, contents or link target) of each entry in a tar archive.
+fn untar(mut archive: &[u8]) -> Vec<(String, u8, u32, Vec<u8>)> {
+    let field = |h: &[u8], range: ::std::ops::Range<usize>| -> Vec<u8> {
+        h[range].iter().cloned().take_while(|&b| b != 0).collect()
+    };
+    let octal = |bytes: Vec<u8>| u64::from_str_radix(str::from_utf8(&bytes).unwrap(), 8).unwrap();
+
+    let mut entries = vec![];
+    let mut pax: Vec<(String, Vec<u8>)> = vec![];
+    while archive[0] != 0 {
+        let h = &archive[..512];
+        let size = octal(field(h, 124..136)) as usize;
+        let data = archive[512..512 + size].to_vec();
+        archive = &archive[512 + size.div_ceil(512) * 512..];
+
+        if h[156] == b'x' {
+            for record in data.split(|&b| b == b'\n').filter(|r| !r.is_empty()) {
+                let record = &record[record.iter().position(|&b| b == b' ').unwrap() + 1..];
+                let eq = record.iter().position(|&b| b == b'=').unwrap();
+                let key = String::from_utf8(record[..eq].to_vec()).unwrap();
+                pax.push((key, record[eq + 1..].to_vec()));
+            }
+            continue;
+        }
+        let mut name = field(h, 345..500);
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend(field(h, 0..100));
+        let mut body = if h[156] == b'2' { field(h, 157..257) } else { data };
+        for (key, value) in pax.drain(..) {
+            match &key[..] {
+                "path" => name = value,
+                "linkpath" => body = value,
+                _ => panic!("unexpected pax key {}", key),
+            }
+        }
+        let mode = octal(field(h, 100..108)) as u32;
+        entries.push((String::from_utf8(name).unwrap(), h[156], mode, body));
+    }
+    assert_eq!(archive, &[0u8; 1024][..]);
+    entries
+}
+
+#[test]
+fn tar_snapshot() {
+    let dir = env::temp_dir().join(format!("hat-tar-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dir);
+    let deep = "d".repeat(90);
+    let long = "f".repeat(120);
+    std_fs::create_dir_all(dir.join(&deep).join(&deep)).unwrap();
+    let big: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    std_fs::write(dir.join("big"), &big).unwrap();
+    std_fs::write(dir.join(&deep).join(&deep).join("file"), b"deep").unwrap();
+    std_fs::write(dir.join(&deep).join(&long), b"long").unwrap();
+    std_fs::set_permissions(dir.join("big"), std_fs::Permissions::from_mode(0o600)).unwrap();
+    symlink(dir.join(&deep).join(&long), dir.join("link")).unwrap();
+
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    family.snapshot_dir(dir.clone());
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    // The whole snapshot, with the directories leading to `dir`.
+    let relative = dir.strip_prefix("/").unwrap().to_str().unwrap().to_owned();
+    let mut exported = vec![];
+    let count = hat.export_tar("family", 1, &mut exported).unwrap();
+    assert_eq!(count, relative.split('/').count() as u64 + 6);
+    assert!(hat.export_tar("family", 2, &mut vec![]).is_err());
+
+    let mut filesystem = Filesystem::new(hat);
+    let mut whole = vec![];
+    filesystem.write_tar(Path::new("family/1"), &mut whole).unwrap();
+    assert_eq!(untar(&exported), untar(&whole));
+
+    let snapshot = Path::new("family/1").join(&relative);
+    let mut archive = vec![];
+    assert_eq!(filesystem.write_tar(&snapshot, &mut archive).unwrap(), 6);
+
+    let mut entries = untar(&archive);
+    entries.sort();
+    let path = |p: &str| format!("{}/{}", relative, p);
+    let link_target = dir.join(&deep).join(&long).to_str().unwrap().as_bytes().to_vec();
+    assert_eq!(
+        entries,
+        vec![
+            (path("big"), b'0', 0o600, big),
+            (path(&format!("{}/", deep)), b'5', 0o755, vec![]),
+            (path(&format!("{}/{}/", deep, deep)), b'5', 0o755, vec![]),
+            (path(&format!("{}/{}/file", deep, deep)), b'0', 0o644, b"deep".to_vec()),
+            (path(&format!("{}/{}", deep, long)), b'0', 0o644, b"long".to_vec()),
+            (path("link"), b'2', 0o777, link_target),
+        ]
+    );
+
+    // A single file is archived under its own path.
+    let mut archive = vec![];
+    filesystem.write_tar(&snapshot.join("big"), &mut archive).unwrap();
+    let entries = untar(&archive);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, path("big"));
+
+    std_fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cat_streams_a_file_of_several_chunks() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    let contents: Vec<u8> = (0..500000).map(|i| (i % 251) as u8).collect();
+    let file = FileIterator::from_bytes(contents.clone());
+    family
+        .snapshot_direct(entry("big".to_string()), false, Some(file))
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut filesystem = Filesystem::new(hat);
+    let mut out = vec![];
+    assert_eq!(filesystem.cat(Path::new("family/1/big"), &mut out).unwrap(), 500000);
+    assert!(out == contents);
+    assert!(filesystem.cat(Path::new("family/1"), &mut vec![]).is_err());
+    assert!(filesystem.cat(Path::new("family/1/missing"), &mut vec![]).is_err());
+}
+
+#[test]
+fn shell_browses_snapshots() {
+    let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+    let mut family = hat.open_family("family".to_string()).unwrap();
+    let dir = family.snapshot_direct(entry("dir".to_string()), true, None).unwrap();
+    let mut file = entry("a".to_string());
+    file.parent_id = Some(dir);
+    file.info.byte_length = Some(6);
+    family
+        .snapshot_direct(file, false, Some(FileIterator::from_bytes(b"hello\n".to_vec())))
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let mut shell = Shell::new(Filesystem::new(hat));
+    let run = |shell: &mut Shell<MemoryBackend>, commands: &str| {
+        let mut out = vec![];
+        shell.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(
+        run(&mut shell, "ls\ncd family/latest\npwd\nls\nls dir"),
+        "family/\n/family/1\ndir/\na\n"
+    );
+    assert_eq!(run(&mut shell, "cd dir\ncat a\ncd ..\ncat /family/^0/dir/a"), "hello\nhello\n");
+    assert_eq!(
+        run(&mut shell, "cat dir\ncd dir/a\nfoo\nexit\nls"),
+        "Error: Not a file: family/1/dir\nError: Not a directory: dir/a\n\
+         Error: Unknown command or wrong arguments: foo; try help\n"
+    );
+
+    // Tab completion of commands and paths.
+    assert_eq!(shell.complete("c"), vec!["cat", "cd"]);
+    assert_eq!(shell.complete("cd /f"), vec!["/family/"]);
+    assert_eq!(shell.complete("ls /family/"), vec!["/family/1/", "/family/latest/"]);
+    assert_eq!(shell.complete("cat /family/1/dir/"), vec!["/family/1/dir/a"]);
+    assert!(shell.complete("cat /family/1/dir/a/").is_empty());
+
+    let dest = env::temp_dir().join(format!("hat-shell-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dest);
+    let out = run(&mut shell, &format!("get /family/1/dir {}", dest.display()));
+    assert_eq!(out, format!("Restored 1 entries to {}\n", dest.display()));
+    assert_eq!(std_fs::read(dest.join("a")).unwrap(), b"hello\n");
+    std_fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(feature = "fuse")]
+#[test]
+fn mount_refreshes_snapshot_list() {
+    use super::fuse::{Fuse, Refreshed};
+    use crypto;
+    use hat::HatRc;
+
+    let dir = env::temp_dir().join(format!("hat-mount-refresh-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dir);
+    std_fs::create_dir_all(dir.join("cache")).unwrap();
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    let open = || HatRc::open_repository(dir.clone(), backend.clone(), 4 * 1024 * 1024).unwrap();
+    let commit = |hat: &mut HatRc<MemoryBackend>, family: &str| {
+        let mut family = hat.open_family(family.to_string()).unwrap();
+        family
+            .snapshot_direct(entry("file".to_string()), false, Some(FileIterator::from_bytes(vec![1])))
+            .unwrap();
+        family.flush().unwrap();
+        hat.commit(&mut family, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    };
+
+    let mut writer = open();
+    commit(&mut writer, "home");
+    let mut fuse = Fuse::new(open()).with_refresh_interval(None);
+    let root_entries = fuse.childs(1).len();
+    assert_eq!(fuse.refresh(), Refreshed::default());
+
+    // The mount does not keep other processes from committing.
+    commit(&mut writer, "home");
+    commit(&mut writer, "etc");
+    let refreshed = fuse.refresh();
+    assert_eq!(refreshed.added, vec!["etc/1".to_owned(), "home/2".to_owned()]);
+    assert!(refreshed.removed.is_empty());
+    assert_eq!(fuse.childs(1).len(), root_entries + 1);
+    assert_eq!(fuse.refresh(), Refreshed::default());
+
+    writer.deregister_by_name("etc".to_owned(), 1).unwrap();
+    let refreshed = fuse.refresh();
+    assert_eq!(refreshed.removed, vec!["etc/1".to_owned()]);
+    assert_eq!(fuse.childs(1).len(), root_entries);
+    assert_eq!(refreshed.to_string(), "removed etc/1\n");
+
+    std_fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn plan_mount_without_device_access() {
+    use super::mount::{self, MountMethod, MountOptions};
+
+    let dir = env::temp_dir().join(format!("hat-mount-plan-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dir);
+    std_fs::create_dir_all(dir.join("bin")).unwrap();
+    let bin = dir.join("bin");
+    let no_device = dir.join("no-such-device");
+
+    // A device we can open is mounted directly, whoever owns it.
+    std_fs::write(dir.join("device"), b"").unwrap();
+    let path_var = bin.clone().into_os_string();
+    assert_eq!(mount::plan(&dir.join("device"), &path_var), Ok(MountMethod::Direct));
+    assert!(mount::plan(&no_device, &path_var).is_err());
+
+    // Otherwise a helper mounts, preferring the one libfuse 2 runs.
+    let helper = |name: &str| {
+        std_fs::write(bin.join(name), b"#!/bin/sh\n").unwrap();
+        std_fs::set_permissions(bin.join(name), std_fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    helper("fusermount3");
+    let fusermount3 = MountMethod::Helper(bin.join("fusermount3"));
+    assert_eq!(mount::plan(&no_device, &path_var), Ok(fusermount3.clone()));
+    helper("fusermount");
+    let fusermount = MountMethod::Helper(bin.join("fusermount"));
+    assert_eq!(mount::plan(&no_device, &path_var), Ok(fusermount.clone()));
+
+    let options = mount::prepare(&fusermount, MountOptions::default(), &dir.join("shim"));
+    assert_eq!(
+        options.unwrap().options(),
+        &["fsname=hat", "subtype=hat", "ro", "auto_unmount"]
+    );
+    assert!(!dir.join("shim").exists());
+    let options = mount::prepare(&MountMethod::Direct, MountOptions::default(), &dir);
+    assert_eq!(options.unwrap(), MountOptions::default());
+
+    // libfuse 2 finds fusermount3 under the name it knows.
+    mount::link_helper(&bin.join("fusermount3"), &dir.join("shim")).unwrap();
+    mount::link_helper(&bin.join("fusermount3"), &dir.join("shim")).unwrap();
+    let shim_path = dir.join("shim").into_os_string();
+    assert_eq!(
+        mount::find_helper(&shim_path),
+        Some(dir.join("shim").join("fusermount"))
+    );
+    assert_eq!(
+        std_fs::read_link(dir.join("shim").join("fusermount")).unwrap(),
+        bin.join("fusermount3")
+    );
+
+    std_fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn parse_mount_options() {
+    use super::mount::MountOptions;
+
+    let options = MountOptions::parse(&["allow_other,uid=1000", "ro", " noatime "]).unwrap();
+    assert_eq!(
+        options.options(),
+        &["fsname=hat", "subtype=hat", "ro", "allow_other", "uid=1000", "noatime"]
+    );
+    assert_eq!(
+        options.args(),
+        vec!["-o", "fsname=hat,subtype=hat,ro,allow_other,uid=1000,noatime"]
+    );
+    for bad in &["", "a,,b", "rw", "a b"] {
+        assert!(MountOptions::parse(&[bad]).is_err(), "{}", bad);
+    }
+}
+
+/// Mounts as root of a new user namespace, as in a rootless container. Needs FUSE, `unshare`
+/// and unprivileged user namespaces, so run it with `cargo test -- --ignored`.
+#[cfg(feature = "fuse")]
+#[test]
+#[ignore]
+fn mount_inside_user_namespace() {
+    use super::fuse::Fuse;
+    use crypto;
+    use hat::HatRc;
+    use libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const CHILD_ENV: &str = "HAT_TEST_IN_USER_NAMESPACE";
+    if env::var_os(CHILD_ENV).is_none() {
+        let status = process::Command::new("unshare")
+            .args(["--user", "--map-root-user", "--mount"])
+            .arg(env::current_exe().unwrap())
+            .args(["--ignored", "--exact", "vfs::tests::mount_inside_user_namespace"])
+            .env(CHILD_ENV, "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let dir = env::temp_dir().join(format!("hat-mount-userns-{}", process::id()));
+    let _ = std_fs::remove_dir_all(&dir);
+    std_fs::create_dir_all(dir.join("cache")).unwrap();
+    std_fs::create_dir_all(dir.join("mnt")).unwrap();
+    crypto::keys::Keeper::write_new_universal_key(&dir).unwrap();
+    let backend = Arc::new(MemoryBackend::new());
+    let mut hat = HatRc::open_repository(dir.clone(), backend, 4 * 1024 * 1024).unwrap();
+    let mut family = hat.open_family("home".to_string()).unwrap();
+    family
+        .snapshot_direct(
+            entry("file".to_string()),
+            false,
+            Some(FileIterator::from_bytes(b"hello".to_vec())),
+        )
+        .unwrap();
+    family.flush().unwrap();
+    hat.commit(&mut family, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let mnt = dir.join("mnt");
+    let mountpoint = mnt.clone();
+    let session = thread::spawn(move || Fuse::new(hat).mount(&mountpoint));
+
+    let file = mnt.join("home").join("1").join("file");
+    let started = Instant::now();
+    while !file.exists() {
+        if session.is_finished() {
+            panic!("mount failed: {:?}", session.join().unwrap());
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "mount did not appear");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(std_fs::read(&file).unwrap(), b"hello");
+    assert!(std_fs::write(mnt.join("home").join("new"), b"").is_err());
+
+    let target = CString::new(mnt.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) }, 0);
+    session.join().unwrap().unwrap();
+
+    std_fs::remove_dir_all(&dir).unwrap();
+}